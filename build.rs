@@ -0,0 +1,9 @@
+fn main() {
+    // The gRPC service's messages and server trait, generated from the shipped
+    // protobuf definitions
+    #[cfg(feature = "grpc")]
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/maple.proto"], &["proto"])
+        .expect("proto/maple.proto compiles; the grpc feature needs protoc installed");
+}
@@ -6,15 +6,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize tracing
     tracing_subscriber::fmt::init();
 
-    // Create config programmatically
-    let config = Config::new(
-        "127.0.0.1".to_string(),
-        8081, // Custom port
-        "https://enclave.trymaple.ai".to_string(),
-    )
-    .with_api_key("your-api-key-here".to_string())
-    .with_debug(true)
-    .with_cors(true);
+    // Create config programmatically, starting from the CLI defaults, and
+    // check it like the CLI does
+    let config = Config::default()
+        .with_port(8081) // Custom port
+        .with_api_key("your-api-key-here".to_string())
+        .with_debug(true)
+        .with_cors(true)
+        .validated()?;
 
     // Create the app
     let app = create_app(config.clone());
@@ -25,7 +24,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Maple proxy server running on http://{}", addr);
 
-    axum::serve(listener, app).await?;
+    // Connection info lets per-client rate limits see the caller's IP
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,60 @@
+use axum::http::StatusCode;
+use maple_proxy::{
+    create_app_with_hooks, Config, HookFuture, HookRejection, HookRequest, ProxyHook,
+};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Refuses chat and embedding requests for models outside a fixed list
+struct ModelPolicy {
+    allowed: Vec<&'static str>,
+}
+
+impl ProxyHook for ModelPolicy {
+    fn on_request<'a>(
+        &'a self,
+        request: &'a mut HookRequest,
+    ) -> HookFuture<'a, Result<(), HookRejection>> {
+        Box::pin(async move {
+            let model = serde_json::from_slice::<serde_json::Value>(&request.body)
+                .ok()
+                .and_then(|body| body.get("model")?.as_str().map(str::to_string));
+            match model {
+                Some(model) if !self.allowed.contains(&model.as_str()) => Err(HookRejection::new(
+                    StatusCode::FORBIDDEN,
+                    format!("'{}' is not available on this proxy", model),
+                )),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    let config = Config::new(
+        "127.0.0.1".to_string(),
+        8081,
+        "https://enclave.trymaple.ai".to_string(),
+    )
+    .with_api_key("your-api-key-here".to_string());
+
+    let policy = ModelPolicy {
+        allowed: vec!["llama3-3-70b"],
+    };
+    let app = create_app_with_hooks(config.clone(), vec![Arc::new(policy)]);
+
+    let addr = config.socket_addr()?;
+    let listener = TcpListener::bind(addr).await?;
+    println!("Maple proxy server with a model policy on http://{}", addr);
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await?;
+
+    Ok(())
+}
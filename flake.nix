@@ -40,6 +40,7 @@
           gcc
           clang
           libclang
+          protobuf
 
           # TypeScript / OpenClaw plugin
           nodejs_22
@@ -0,0 +1,117 @@
+use dashmap::DashMap;
+use serde_json::Value;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Completions shorter than this say more about latency than about speed
+const MIN_SAMPLE_TOKENS: u64 = 16;
+
+/// Weight of the newest completion in a model's average speed
+const SMOOTHING: f64 = 0.2;
+
+/// How many times longer than the observed speed predicts a request may take
+const HEADROOM: f64 = 2.0;
+
+/// Each model's average completion speed, in tokens per second from the
+/// request to the last token, so it includes prompt processing and latency
+#[derive(Default)]
+pub(crate) struct ModelSpeeds {
+    tokens_per_second: DashMap<String, f64>,
+}
+
+impl ModelSpeeds {
+    pub(crate) fn record(&self, model: &str, completion_tokens: u64, elapsed: Duration) {
+        if completion_tokens < MIN_SAMPLE_TOKENS || elapsed.is_zero() {
+            return;
+        }
+        let sample = completion_tokens as f64 / elapsed.as_secs_f64();
+        self.tokens_per_second
+            .entry(model.to_string())
+            .and_modify(|speed| *speed += SMOOTHING * (sample - *speed))
+            .or_insert(sample);
+    }
+
+    /// How long a chat completion request may take, from its token limit and
+    /// its model's speed, within `floor` and `ceiling`. `None` when the
+    /// request sets no limit or the model has not been timed yet.
+    pub(crate) fn timeout_for(
+        &self,
+        request_body: &[u8],
+        floor: Duration,
+        ceiling: Duration,
+    ) -> Option<Duration> {
+        let request: Value = serde_json::from_slice(request_body).ok()?;
+        let max_tokens = request["max_completion_tokens"]
+            .as_u64()
+            .or_else(|| request["max_tokens"].as_u64())?;
+        let speed = *self.tokens_per_second.get(request["model"].as_str()?)?;
+
+        let allowed =
+            Duration::try_from_secs_f64(max_tokens as f64 / speed * HEADROOM).unwrap_or(ceiling);
+        Some(allowed.max(floor).min(ceiling))
+    }
+}
+
+/// A chat completion being timed, whose speed is recorded once its usage is
+/// known
+pub(crate) struct SpeedSample {
+    speeds: Arc<ModelSpeeds>,
+    model: String,
+    started_at: Instant,
+}
+
+impl SpeedSample {
+    pub(crate) fn start(speeds: &Arc<ModelSpeeds>, model: String) -> Self {
+        Self {
+            speeds: Arc::clone(speeds),
+            model,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub(crate) fn record(&self, usage: &Value) {
+        if let Some(completion_tokens) = usage["completion_tokens"].as_u64() {
+            self.speeds
+                .record(&self.model, completion_tokens, self.started_at.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLOOR: Duration = Duration::from_secs(10);
+    const CEILING: Duration = Duration::from_secs(600);
+
+    #[test]
+    fn timeouts_follow_the_token_limit_and_observed_speed() {
+        let speeds = ModelSpeeds::default();
+        let request = br#"{"model":"llama3-3-70b","max_tokens":2000}"#;
+        assert_eq!(speeds.timeout_for(request, FLOOR, CEILING), None);
+
+        speeds.record("llama3-3-70b", 400, Duration::from_secs(20));
+        speeds.record("llama3-3-70b", 4, Duration::from_secs(20));
+        assert_eq!(
+            speeds.timeout_for(request, FLOOR, CEILING),
+            Some(Duration::from_secs(200))
+        );
+
+        let short = br#"{"model":"llama3-3-70b","max_completion_tokens":20}"#;
+        assert_eq!(speeds.timeout_for(short, FLOOR, CEILING), Some(FLOOR));
+        let long = br#"{"model":"llama3-3-70b","max_tokens":100000}"#;
+        assert_eq!(speeds.timeout_for(long, FLOOR, CEILING), Some(CEILING));
+        let unlimited = br#"{"model":"llama3-3-70b"}"#;
+        assert_eq!(speeds.timeout_for(unlimited, FLOOR, CEILING), None);
+    }
+
+    #[test]
+    fn speeds_are_smoothed() {
+        let speeds = ModelSpeeds::default();
+        speeds.record("m", 100, Duration::from_secs(1));
+        speeds.record("m", 200, Duration::from_secs(1));
+        assert_eq!(*speeds.tokens_per_second.get("m").unwrap(), 120.0);
+    }
+}
@@ -0,0 +1,1170 @@
+use crate::{
+    config::OpenAIError,
+    diagnose,
+    ip_bans::AuthRejected,
+    keys::{KeyQuota, KeyUpdateError},
+    models::{self, ModelAlias},
+    proxy::{ProxyError, ProxyState},
+    schedule::TimeWindow,
+    system_prompt::SystemPrompt,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{error, info};
+
+/// Backend names in the routing table. Models without a route use Maple.
+const MAPLE_BACKEND: &str = "maple";
+const UPSTREAM_BACKEND: &str = "openai-upstream";
+const MAX_MODEL_NAME_LEN: usize = 256;
+const MAX_KEY_NAME_LEN: usize = 256;
+/// Cache and client listings show at most this many entries, most recent first
+const MAX_LISTED_ENTRIES: usize = 1000;
+
+#[derive(Deserialize)]
+struct AliasTarget {
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct Route {
+    backend: String,
+}
+
+#[derive(Deserialize)]
+struct KeySchedule {
+    windows: Vec<TimeWindow>,
+}
+
+#[derive(Deserialize)]
+struct NewKey {
+    name: String,
+    #[serde(flatten)]
+    quota: KeyQuota,
+    #[serde(default)]
+    system_prompt: Option<SystemPrompt>,
+}
+
+/// Admin requests must carry `Authorization: Bearer <MAPLE_ADMIN_TOKEN>`
+pub(crate) async fn require_admin_token(
+    State(state): State<Arc<ProxyState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let authorized = state
+        .config()
+        .admin_token
+        .as_deref()
+        .is_some_and(|expected| {
+            request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), expected.as_bytes()))
+        });
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Extension(AuthRejected),
+            Json(OpenAIError::authentication_error("Invalid admin token.")),
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub(crate) async fn list_aliases(State(state): State<Arc<ProxyState>>) -> Json<Value> {
+    let tables = state.model_tables();
+    Json(json!({"object": "list", "data": tables.aliases}))
+}
+
+/// Creates or retargets an alias
+pub(crate) async fn put_alias(
+    State(state): State<Arc<ProxyState>>,
+    Path(alias): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>, ProxyError> {
+    let AliasTarget { model } = parse_body(&body)?;
+    if state.config().passthrough {
+        return Err(invalid_request(
+            "Aliases rewrite request bodies, which passthrough mode does not allow.",
+            "alias",
+        ));
+    }
+    validate_model_name("alias", &alias)?;
+    validate_model_name("model", &model)?;
+    if !models::is_model_allowed(&state.config().allowed_models, &model) {
+        return Err(invalid_request(
+            format!("'{}' is not in the allowed models.", model),
+            "model",
+        ));
+    }
+    if !models::is_model_listed(state.config(), &model) {
+        return Err(invalid_request(
+            format!("'{}' is not in the model list or is hidden.", model),
+            "model",
+        ));
+    }
+
+    if state
+        .config()
+        .model_pools
+        .iter()
+        .any(|pool| pool.name == alias)
+    {
+        return Err(invalid_request(
+            format!("'{}' is a model pool.", alias),
+            "alias",
+        ));
+    }
+
+    let entry = ModelAlias::new(alias, model);
+    state.update_model_tables(|tables| {
+        if entry.alias == entry.model {
+            return Err(invalid_request("An alias cannot point to itself.", "model"));
+        }
+        if tables
+            .aliases
+            .iter()
+            .any(|alias| alias.alias == entry.model)
+        {
+            return Err(invalid_request(
+                "Aliases resolve once, so they cannot point to other aliases.",
+                "model",
+            ));
+        }
+        if tables
+            .aliases
+            .iter()
+            .any(|alias| alias.model == entry.alias)
+        {
+            return Err(invalid_request(
+                format!("'{}' is the target of another alias.", entry.alias),
+                "alias",
+            ));
+        }
+
+        match tables
+            .aliases
+            .iter_mut()
+            .find(|alias| alias.alias == entry.alias)
+        {
+            Some(existing) => existing.model = entry.model.clone(),
+            None => tables.aliases.push(entry.clone()),
+        }
+        Ok(())
+    })?;
+
+    info!("Admin set model alias {}", entry);
+    Ok(Json(json!(entry)))
+}
+
+pub(crate) async fn delete_alias(
+    State(state): State<Arc<ProxyState>>,
+    Path(alias): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    state.update_model_tables(|tables| {
+        let before = tables.aliases.len();
+        tables.aliases.retain(|entry| entry.alias != alias);
+        if tables.aliases.len() == before {
+            return Err(not_found(format!("No alias named '{}'.", alias)));
+        }
+        Ok(())
+    })?;
+
+    info!("Admin removed model alias {}", alias);
+    Ok(Json(json!({"alias": alias, "deleted": true})))
+}
+
+pub(crate) async fn list_routes(State(state): State<Arc<ProxyState>>) -> Json<Value> {
+    let tables = state.model_tables();
+    let routes: Vec<Value> = tables
+        .upstream_models
+        .iter()
+        .map(|model| json!({"model": model, "backend": UPSTREAM_BACKEND}))
+        .collect();
+    Json(json!({"object": "list", "data": routes}))
+}
+
+/// Routes a model to the OpenAI-compatible upstream, or back to Maple
+pub(crate) async fn put_route(
+    State(state): State<Arc<ProxyState>>,
+    Path(model): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>, ProxyError> {
+    let Route { backend } = parse_body(&body)?;
+    validate_model_name("model", &model)?;
+    let to_upstream = match backend.as_str() {
+        UPSTREAM_BACKEND if state.config().openai_upstream_url.is_none() => {
+            return Err(invalid_request(
+                "No OpenAI-compatible upstream is configured.",
+                "backend",
+            ))
+        }
+        UPSTREAM_BACKEND => true,
+        MAPLE_BACKEND => false,
+        _ => {
+            return Err(invalid_request(
+                format!(
+                    "Unknown backend '{}'. Expected '{}' or '{}'.",
+                    backend, UPSTREAM_BACKEND, MAPLE_BACKEND
+                ),
+                "backend",
+            ))
+        }
+    };
+
+    state.update_model_tables(|tables| {
+        tables.upstream_models.retain(|routed| *routed != model);
+        if to_upstream {
+            tables.upstream_models.push(model.clone());
+        }
+        Ok(())
+    })?;
+
+    info!("Admin routed model {} to {}", model, backend);
+    Ok(Json(json!({"model": model, "backend": backend})))
+}
+
+pub(crate) async fn delete_route(
+    State(state): State<Arc<ProxyState>>,
+    Path(model): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    state.update_model_tables(|tables| {
+        let before = tables.upstream_models.len();
+        tables.upstream_models.retain(|routed| *routed != model);
+        if tables.upstream_models.len() == before {
+            return Err(not_found(format!("No route for '{}'.", model)));
+        }
+        Ok(())
+    })?;
+
+    info!("Admin routed model {} back to {}", model, MAPLE_BACKEND);
+    Ok(Json(json!({"model": model, "backend": MAPLE_BACKEND})))
+}
+
+/// The running configuration, with credentials reduced to whether they are set
+pub(crate) async fn show_config(State(state): State<Arc<ProxyState>>) -> Json<Value> {
+    Json(diagnose::config_summary(state.config()))
+}
+
+pub(crate) async fn list_keys(State(state): State<Arc<ProxyState>>) -> Json<Value> {
+    Json(json!({"object": "list", "data": state.virtual_keys().list()}))
+}
+
+/// Issues a virtual key. The key is only ever shown in this response.
+pub(crate) async fn create_key(
+    State(state): State<Arc<ProxyState>>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Value>), ProxyError> {
+    let NewKey {
+        name,
+        quota,
+        system_prompt,
+    } = parse_body(&body)?;
+    let valid_name = !name.trim().is_empty()
+        && name.len() <= MAX_KEY_NAME_LEN
+        && !name.chars().any(char::is_control);
+    if !valid_name {
+        return Err(invalid_request("'name' must be a non-empty label.", "name"));
+    }
+    if let Some(system_prompt) = &system_prompt {
+        validate_system_prompt(system_prompt)?;
+    }
+    validate_quota(&state, &quota)?;
+    if !state.config().has_api_key() {
+        return Err(invalid_request(
+            "Virtual keys stand in for MAPLE_API_KEY, which is not configured.",
+            "name",
+        ));
+    }
+
+    let created = state
+        .virtual_keys()
+        .create(name, quota, system_prompt)
+        .map_err(key_update_error)?;
+    info!(
+        "Admin issued virtual key {} ({})",
+        created["id"], created["name"]
+    );
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+pub(crate) async fn get_key(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    state
+        .virtual_keys()
+        .get(&id)
+        .map(Json)
+        .ok_or_else(|| key_update_error(KeyUpdateError::NotFound))
+}
+
+/// Replaces a key's quota; omitted or null limits are unlimited
+pub(crate) async fn put_key_quota(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>, ProxyError> {
+    let quota: KeyQuota = parse_body(&body)?;
+    validate_quota(&state, &quota)?;
+    let key = state
+        .virtual_keys()
+        .set_quota(&id, quota)
+        .map_err(key_update_error)?;
+    if let Some(alerts) = state.alerts() {
+        alerts.forget_key(&id);
+    }
+    info!("Admin set the quota of virtual key {}", id);
+    Ok(Json(key))
+}
+
+/// Sets the system prompt added to a key's chat completions
+pub(crate) async fn put_key_system_prompt(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>, ProxyError> {
+    let system_prompt: SystemPrompt = parse_body(&body)?;
+    validate_system_prompt(&system_prompt)?;
+    let key = state
+        .virtual_keys()
+        .set_system_prompt(&id, Some(system_prompt))
+        .map_err(key_update_error)?;
+    info!("Admin set the system prompt of virtual key {}", id);
+    Ok(Json(key))
+}
+
+pub(crate) async fn delete_key_system_prompt(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    let key = state
+        .virtual_keys()
+        .set_system_prompt(&id, None)
+        .map_err(key_update_error)?;
+    info!("Admin removed the system prompt of virtual key {}", id);
+    Ok(Json(key))
+}
+
+/// Limits a key to the hours in its schedule, in UTC
+pub(crate) async fn put_key_schedule(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>, ProxyError> {
+    let KeySchedule { windows } = parse_body(&body)?;
+    if windows.is_empty() {
+        return Err(invalid_request(
+            "'windows' must list at least one window; DELETE the schedule to lift it.",
+            "windows",
+        ));
+    }
+    let key = state
+        .virtual_keys()
+        .set_schedule(&id, windows)
+        .map_err(key_update_error)?;
+    info!("Admin set the schedule of virtual key {}", id);
+    Ok(Json(key))
+}
+
+pub(crate) async fn delete_key_schedule(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    let key = state
+        .virtual_keys()
+        .set_schedule(&id, Vec::new())
+        .map_err(key_update_error)?;
+    info!("Admin removed the schedule of virtual key {}", id);
+    Ok(Json(key))
+}
+
+pub(crate) async fn revoke_key(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    let key = state.virtual_keys().revoke(&id).map_err(key_update_error)?;
+    info!("Admin revoked virtual key {}", id);
+    Ok(Json(key))
+}
+
+/// Lists a cache's entries by hashed ID, without their keys or contents
+pub(crate) async fn list_cache_entries(
+    State(state): State<Arc<ProxyState>>,
+    Path(cache): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    let entries = state
+        .cache_entries(&cache)
+        .ok_or_else(|| cache_not_found(&cache))?;
+    Ok(Json(listing(entries)))
+}
+
+pub(crate) async fn clear_cache(
+    State(state): State<Arc<ProxyState>>,
+    Path(cache): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    let evicted = state
+        .evict_cache_entries(&cache, None)
+        .ok_or_else(|| cache_not_found(&cache))?;
+    info!("Admin cleared the {} cache ({} entries)", cache, evicted);
+    Ok(Json(json!({"cache": cache, "evicted": evicted})))
+}
+
+pub(crate) async fn evict_cache_entry(
+    State(state): State<Arc<ProxyState>>,
+    Path((cache, id)): Path<(String, String)>,
+) -> Result<Json<Value>, ProxyError> {
+    let evicted = state
+        .evict_cache_entries(&cache, Some(&id))
+        .ok_or_else(|| cache_not_found(&cache))?;
+    if evicted == 0 {
+        return Err(not_found(format!(
+            "No entry '{}' in the {} cache.",
+            id, cache
+        )));
+    }
+    info!("Admin evicted entry {} from the {} cache", id, cache);
+    Ok(Json(json!({"cache": cache, "evicted": evicted})))
+}
+
+/// Lists the pooled, attested backend clients
+pub(crate) async fn list_clients(State(state): State<Arc<ProxyState>>) -> Json<Value> {
+    Json(listing(state.list_clients()))
+}
+
+/// Drops a pooled client, so its next request attests again
+pub(crate) async fn evict_client(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    if !state.evict_client(&id) {
+        return Err(not_found(format!("No pooled client '{}'.", id)));
+    }
+    info!("Admin evicted pooled client {}", id);
+    Ok(Json(json!({"id": id, "evicted": true})))
+}
+
+/// Lists the keys quarantined for abuse, for review
+pub(crate) async fn list_quarantined_keys(
+    State(state): State<Arc<ProxyState>>,
+) -> Result<Json<Value>, ProxyError> {
+    let key_watch = state.key_watch().ok_or_else(quarantine_not_enabled)?;
+    Ok(Json(listing(key_watch.list())))
+}
+
+/// Lifts a key's quarantine once it has been reviewed
+pub(crate) async fn release_quarantined_key(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    let key_watch = state.key_watch().ok_or_else(quarantine_not_enabled)?;
+    if !key_watch.release(&id) {
+        return Err(not_found(format!("Key '{}' is not quarantined.", id)));
+    }
+    info!("Admin released key {} from quarantine", id);
+    Ok(Json(json!({"id": id, "released": true})))
+}
+
+/// Lists the IPs that probed a honeypot decoy and whether they are blocked
+pub(crate) async fn list_blocked_ips(
+    State(state): State<Arc<ProxyState>>,
+) -> Result<Json<Value>, ProxyError> {
+    let honeypot = state.honeypot().ok_or_else(honeypot_not_enabled)?;
+    Ok(Json(listing(honeypot.list())))
+}
+
+/// Takes an IP off the denylist, e.g. one caught by mistake
+pub(crate) async fn unblock_ip(
+    State(state): State<Arc<ProxyState>>,
+    Path(ip): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    let honeypot = state.honeypot().ok_or_else(honeypot_not_enabled)?;
+    if !honeypot.unblock(&ip) {
+        return Err(not_found(format!("IP '{}' is not on the denylist.", ip)));
+    }
+    info!("Admin unblocked IP {}", ip);
+    Ok(Json(json!({"ip": ip, "unblocked": true})))
+}
+
+/// IPs banned after repeated authentication failures, including ones whose
+/// ban has run out
+pub(crate) async fn list_ip_bans(
+    State(state): State<Arc<ProxyState>>,
+) -> Result<Json<Value>, ProxyError> {
+    let ip_bans = state.ip_bans().ok_or_else(ip_bans_not_enabled)?;
+    Ok(Json(listing(ip_bans.list())))
+}
+
+/// Lifts an IP's ban and forgets its failures
+pub(crate) async fn clear_ip_ban(
+    State(state): State<Arc<ProxyState>>,
+    Path(ip): Path<String>,
+) -> Result<Json<Value>, ProxyError> {
+    let ip_bans = state.ip_bans().ok_or_else(ip_bans_not_enabled)?;
+    if !ip_bans.clear(&ip) {
+        return Err(not_found(format!("IP '{}' has not been banned.", ip)));
+    }
+    info!("Admin cleared the ban on IP {}", ip);
+    Ok(Json(json!({"ip": ip, "cleared": true})))
+}
+
+/// Lists the keys in the client keys file with their usage
+pub(crate) async fn list_client_keys(
+    State(state): State<Arc<ProxyState>>,
+) -> Result<Json<Value>, ProxyError> {
+    let client_keys = state.client_keys().ok_or_else(client_keys_not_enabled)?;
+    Ok(Json(listing(client_keys.list())))
+}
+
+/// Loads the client keys file now rather than on its next check, and reports
+/// why it cannot be loaded
+pub(crate) async fn reload_client_keys(
+    State(state): State<Arc<ProxyState>>,
+) -> Result<Json<Value>, ProxyError> {
+    let client_keys = state.client_keys().ok_or_else(client_keys_not_enabled)?;
+    let reloaded = client_keys.reload().map_err(|load_error| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(OpenAIError::invalid_request_error(format!(
+                "{:#}",
+                load_error
+            ))),
+        )
+    })?;
+    info!("Admin reloaded the client keys file");
+    Ok(Json(
+        json!({"reloaded": reloaded, "keys": client_keys.list().len()}),
+    ))
+}
+
+fn client_keys_not_enabled() -> ProxyError {
+    not_found("Client keys are not enabled; set --client-keys-file.".to_string())
+}
+
+fn ip_bans_not_enabled() -> ProxyError {
+    not_found("IP bans are not enabled; set --ip-ban-auth-failures.".to_string())
+}
+
+fn honeypot_not_enabled() -> ProxyError {
+    not_found("The honeypot is not enabled; set --honeypot or --honeypot-path.".to_string())
+}
+
+fn quarantine_not_enabled() -> ProxyError {
+    not_found("Key quarantine is not enabled; set a --quarantine-max-* threshold.".to_string())
+}
+
+fn listing(mut entries: Vec<Value>) -> Value {
+    let total = entries.len();
+    entries.truncate(MAX_LISTED_ENTRIES);
+    json!({"object": "list", "total": total, "data": entries})
+}
+
+fn cache_not_found(cache: &str) -> ProxyError {
+    not_found(format!(
+        "No '{}' cache is enabled; the caches are responses, models, and embeddings.",
+        cache
+    ))
+}
+
+fn key_update_error(update_error: KeyUpdateError) -> ProxyError {
+    match update_error {
+        KeyUpdateError::NotFound => not_found("No virtual key with that id.".to_string()),
+        KeyUpdateError::Save(save_error) => {
+            error!("Failed to save the keys file: {}", save_error);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OpenAIError::server_error(
+                    "Failed to save the keys file; nothing was changed",
+                )),
+            )
+        }
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, ProxyError> {
+    serde_json::from_slice(body).map_err(|error| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OpenAIError::invalid_request_error(format!(
+                "Invalid request body: {}",
+                error
+            ))),
+        )
+    })
+}
+
+fn validate_system_prompt(system_prompt: &SystemPrompt) -> Result<(), ProxyError> {
+    if system_prompt.content.trim().is_empty() {
+        return Err(invalid_request("'content' must not be empty.", "content"));
+    }
+    Ok(())
+}
+
+/// Cost budgets are counted at the configured model prices, so they need some
+fn validate_quota(state: &ProxyState, quota: &KeyQuota) -> Result<(), ProxyError> {
+    for (param, max_cost) in [
+        ("max_cost_per_day", quota.max_cost_per_day),
+        ("max_cost_per_month", quota.max_cost_per_month),
+    ] {
+        if max_cost.is_some_and(|max_cost| !max_cost.is_finite() || max_cost < 0.0) {
+            return Err(invalid_request(
+                format!("'{}' must be a non-negative number of USD.", param),
+                param,
+            ));
+        }
+    }
+    if quota.has_cost_budget() && state.config().model_prices.is_empty() {
+        let param = match quota.max_cost_per_day {
+            Some(_) => "max_cost_per_day",
+            None => "max_cost_per_month",
+        };
+        return Err(invalid_request(
+            "Cost budgets are counted at model prices; set --model-price first.",
+            param,
+        ));
+    }
+    Ok(())
+}
+
+fn validate_model_name(param: &str, name: &str) -> Result<(), ProxyError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_MODEL_NAME_LEN
+        && name.trim() == name
+        && !name.chars().any(char::is_control);
+    if !valid {
+        return Err(invalid_request(
+            format!(
+                "'{}' must be a non-empty model name without surrounding spaces.",
+                param
+            ),
+            param,
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_request(message: impl Into<String>, param: &str) -> ProxyError {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(OpenAIError::invalid_request_error(message).with_param(param)),
+    )
+}
+
+fn not_found(message: String) -> ProxyError {
+    (
+        StatusCode::NOT_FOUND,
+        Json(OpenAIError::invalid_request_error(message)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, create_app};
+    use axum::{body::to_bytes, http::Method};
+    use tower::ServiceExt;
+
+    fn request(method: Method, uri: &str, token: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn send(app: &axum::Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), 64 * 1024).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    fn admin_config() -> Config {
+        Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_admin_token("admin-secret")
+        .with_model_alias("gpt-4", "qwen3-coder-480b")
+    }
+
+    #[tokio::test]
+    async fn admin_api_requires_the_token() {
+        let app = create_app(admin_config());
+
+        let (status, _) = send(&app, request(Method::GET, "/admin/aliases", "wrong", "")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let unconfigured = create_app(Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        ));
+        let (status, _) = send(
+            &unconfigured,
+            request(Method::GET, "/admin/aliases", "", ""),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn aliases_are_created_updated_and_deleted() {
+        let app = create_app(admin_config());
+        let token = "admin-secret";
+
+        let (status, _) = send(
+            &app,
+            request(
+                Method::PUT,
+                "/admin/aliases/fast",
+                token,
+                r#"{"model":"llama3-3-70b"}"#,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(
+            &app,
+            request(
+                Method::PUT,
+                "/admin/aliases/gpt-4",
+                token,
+                r#"{"model":"gemma4-31b"}"#,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, list) = send(&app, request(Method::GET, "/admin/aliases", token, "")).await;
+        assert_eq!(
+            list["data"],
+            json!([
+                {"alias": "gpt-4", "model": "gemma4-31b"},
+                {"alias": "fast", "model": "llama3-3-70b"}
+            ])
+        );
+
+        let (status, _) = send(
+            &app,
+            request(Method::DELETE, "/admin/aliases/fast", token, ""),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(
+            &app,
+            request(Method::DELETE, "/admin/aliases/fast", token, ""),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn invalid_changes_leave_the_tables_untouched() {
+        let app = create_app(admin_config().with_allowed_models(vec![
+            "qwen3-coder-480b".to_string(),
+            "llama3-3-70b".to_string(),
+        ]));
+        let token = "admin-secret";
+
+        for (uri, body) in [
+            ("/admin/aliases/fast", r#"{"model":"gemma4-31b"}"#),
+            ("/admin/aliases/fast", r#"{"model":"gpt-4"}"#),
+            ("/admin/aliases/fast", r#"{"model":"fast"}"#),
+            (
+                "/admin/aliases/qwen3-coder-480b",
+                r#"{"model":"llama3-3-70b"}"#,
+            ),
+            ("/admin/aliases/fast", r#"{"model":" llama3-3-70b"}"#),
+            ("/admin/aliases/fast", r#"{"target":"llama3-3-70b"}"#),
+            ("/admin/routes/llama3.2", r#"{"backend":"openai-upstream"}"#),
+            ("/admin/routes/llama3.2", r#"{"backend":"elsewhere"}"#),
+        ] {
+            let (status, _) = send(&app, request(Method::PUT, uri, token, body)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{} {}", uri, body);
+        }
+
+        let (_, list) = send(&app, request(Method::GET, "/admin/aliases", token, "")).await;
+        assert_eq!(
+            list["data"],
+            json!([{"alias": "gpt-4", "model": "qwen3-coder-480b"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn virtual_keys_are_issued_limited_and_revoked() {
+        let app = create_app(admin_config().with_api_key("default-key".to_string()));
+        let token = "admin-secret";
+
+        let (status, created) = send(
+            &app,
+            request(
+                Method::POST,
+                "/admin/keys",
+                token,
+                r#"{"name":"ci","max_tokens":1000}"#,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(created["key"].as_str().unwrap().starts_with("sk-maple-"));
+        assert_eq!(
+            created["quota"],
+            json!({"max_requests": null, "max_tokens": 1000})
+        );
+        let uri = format!("/admin/keys/{}", created["id"].as_str().unwrap());
+
+        let (status, key) = send(
+            &app,
+            request(
+                Method::PUT,
+                &format!("{}/quota", uri),
+                token,
+                r#"{"max_requests":5}"#,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(key["quota"], json!({"max_requests": 5, "max_tokens": null}));
+
+        let quota_uri = format!("{}/quota", uri);
+        let budget = r#"{"max_tokens_per_day":20000}"#;
+        let (status, key) = send(&app, request(Method::PUT, &quota_uri, token, budget)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(key["quota"]["max_tokens_per_day"], 20000);
+        assert_eq!(
+            key["budget_usage"]["day"],
+            json!({"tokens": 0, "cost": 0.0})
+        );
+        // Without model prices, there is nothing to count a cost budget in
+        let budget = r#"{"max_cost_per_month":25}"#;
+        let (status, error) = send(&app, request(Method::PUT, &quota_uri, token, budget)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"]["param"], "max_cost_per_month");
+
+        let prompt_uri = format!("{}/system_prompt", uri);
+        let body = r#"{"content":"Answer in French.","mode":"replace"}"#;
+        let (status, key) = send(&app, request(Method::PUT, &prompt_uri, token, body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            key["system_prompt"],
+            json!({"content": "Answer in French.", "mode": "replace"})
+        );
+        let (status, _) = send(
+            &app,
+            request(Method::PUT, &prompt_uri, token, r#"{"content":""}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, key) = send(&app, request(Method::DELETE, &prompt_uri, token, "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(key["system_prompt"].is_null());
+
+        let schedule_uri = format!("{}/schedule", uri);
+        let body = r#"{"windows":["mon-fri 00:00-06:00","Sat-Sun"]}"#;
+        let (status, key) = send(&app, request(Method::PUT, &schedule_uri, token, body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(key["schedule"], json!(["Mon-Fri 00:00-06:00", "Sat-Sun"]));
+        for body in [r#"{"windows":[]}"#, r#"{"windows":["9-17"]}"#] {
+            let (status, _) = send(&app, request(Method::PUT, &schedule_uri, token, body)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        let (status, key) = send(&app, request(Method::DELETE, &schedule_uri, token, "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(key["schedule"], json!([]));
+
+        let (status, key) = send(&app, request(Method::DELETE, &uri, token, "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(key["revoked_at"].is_u64());
+        let (_, list) = send(&app, request(Method::GET, "/admin/keys", token, "")).await;
+        assert_eq!(list["data"][0]["id"], created["id"]);
+        assert!(list["data"][0].get("key").is_none());
+
+        let (status, _) = send(
+            &app,
+            request(Method::GET, "/admin/keys/key_missing", token, ""),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(
+            &app,
+            request(Method::POST, "/admin/keys", token, r#"{"name":" "}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn caches_and_clients_are_listed_and_evicted() {
+        let app = create_app(admin_config());
+        let token = "admin-secret";
+
+        let (status, list) = send(
+            &app,
+            request(Method::GET, "/admin/caches/models", token, ""),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list, json!({"object": "list", "total": 0, "data": []}));
+        let (status, cleared) = send(
+            &app,
+            request(Method::DELETE, "/admin/caches/models", token, ""),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(cleared["evicted"], 0);
+
+        for (method, uri) in [
+            (Method::GET, "/admin/caches/responses"),
+            (Method::GET, "/admin/caches/elsewhere"),
+            (Method::DELETE, "/admin/caches/models/0123456789abcdef"),
+            (Method::DELETE, "/admin/clients/0123456789abcdef"),
+        ] {
+            let (status, _) = send(&app, request(method, uri, token, "")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+        }
+
+        let (status, clients) = send(&app, request(Method::GET, "/admin/clients", token, "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(clients["data"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn quarantined_keys_are_throttled_until_released() {
+        let config = admin_config()
+            .with_mock_backend(true)
+            .with_quarantine(Some(2), None, None)
+            .with_quarantine_requests_per_minute(1);
+        let app = create_app(config);
+        let chat = || {
+            request(
+                Method::POST,
+                "/v1/chat/completions",
+                "sk-busy-client",
+                r#"{"model":"llama3-3-70b","messages":[{"role":"user","content":"Hi"}]}"#,
+            )
+        };
+
+        // The third request trips the threshold, and the fourth uses up the
+        // quarantined key's allowance
+        for _ in 0..4 {
+            assert_eq!(send(&app, chat()).await.0, StatusCode::OK);
+        }
+        let (status, throttled) = send(&app, chat()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(throttled["error"]["code"], "key_quarantined");
+
+        let token = "admin-secret";
+        let (_, quarantined) =
+            send(&app, request(Method::GET, "/admin/quarantine", token, "")).await;
+        let key = &quarantined["data"][0];
+        assert_eq!(key["hint"], "sk-busy-...");
+        assert_eq!(key["reason"], "request_rate");
+        assert_eq!(key["throttled_requests"], 1);
+
+        let uri = format!("/admin/quarantine/{}", key["id"].as_str().unwrap());
+        let (status, _) = send(&app, request(Method::DELETE, &uri, token, "")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&app, request(Method::DELETE, &uri, token, "")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, chat()).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn ips_failing_authentication_are_banned_until_cleared() {
+        let app = create_app(admin_config().with_ip_bans(2, 60, 600));
+        let token = "admin-secret";
+        let from = |ip: [u8; 4], mut request: Request<Body>| {
+            let addr = std::net::SocketAddr::from((ip, 40000));
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(addr));
+            request
+        };
+        let (guesser, admin) = ([198, 51, 100, 9], [192, 0, 2, 1]);
+        let guess = || from(guesser, request(Method::GET, "/admin/keys", "wrong", ""));
+
+        assert_eq!(send(&app, guess()).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(&app, guess()).await.0, StatusCode::UNAUTHORIZED);
+        let (status, banned) = send(&app, guess()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(banned["error"]["code"], "ip_banned");
+
+        let list = || from(admin, request(Method::GET, "/admin/ip_bans", token, ""));
+        let (_, listed) = send(&app, list()).await;
+        assert_eq!(listed["total"], 1);
+        assert_eq!(listed["data"][0]["ip"], "198.51.100.9");
+        assert_eq!(listed["data"][0]["banned"], true);
+        assert_eq!(listed["data"][0]["refused_requests"], 1);
+
+        let uri = "/admin/ip_bans/198.51.100.9";
+        let clear = || from(admin, request(Method::DELETE, uri, token, ""));
+        assert_eq!(send(&app, clear()).await.0, StatusCode::OK);
+        assert_eq!(send(&app, clear()).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, guess()).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn client_keys_authorize_only_their_models() {
+        let dir_name = format!("maple-admin-client-keys-{}", std::process::id());
+        let dir = std::env::temp_dir().join(dir_name);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.json");
+        let keys = json!({"keys": [{
+            "name": "team-a",
+            "hash": crate::client_keys::hash_client_key("sk-team-a"),
+            "tier": "pro",
+            "allowed_models": ["llama3-3-70b"],
+        }]});
+        std::fs::write(&path, keys.to_string()).unwrap();
+        let config = admin_config()
+            .with_mock_backend(true)
+            .with_api_key("default-key".to_string())
+            .with_client_keys_file(&path);
+        let app = create_app(config);
+        let chat = |key: &str, model: &str| {
+            let body = json!({"model": model, "messages": [{"role": "user", "content": "Hi"}]});
+            request(Method::POST, "/v1/chat/completions", key, &body.to_string())
+        };
+
+        assert_eq!(
+            send(&app, chat("sk-team-a", "llama3-3-70b")).await.0,
+            StatusCode::OK
+        );
+        let (status, _) = send(&app, chat("sk-team-a", "gemma4-31b")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, rejected) = send(&app, chat("sk-team-b", "llama3-3-70b")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(rejected["error"]["code"], "invalid_api_key");
+
+        let token = "admin-secret";
+        let (_, listed) = send(&app, request(Method::GET, "/admin/client_keys", token, "")).await;
+        assert_eq!(listed["data"][0]["name"], "team-a");
+        assert_eq!(listed["data"][0]["tier"], "pro");
+        assert_eq!(listed["data"][0]["usage"]["requests"], 1);
+        assert!(listed["data"][0].get("hash").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ips_probing_decoys_are_blocked_until_unblocked() {
+        let app = create_app(admin_config().with_honeypot(true));
+        let token = "admin-secret";
+        let from = |ip: [u8; 4], mut request: Request<Body>| {
+            let addr = std::net::SocketAddr::from((ip, 40000));
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(addr));
+            request
+        };
+        let (scanner, admin) = ([198, 51, 100, 7], [192, 0, 2, 1]);
+        let probe = |uri: &str| from(scanner, request(Method::GET, uri, "", ""));
+
+        // Decoys look like any missing page, then everything is refused
+        assert_eq!(send(&app, probe("/.env")).await.0, StatusCode::NOT_FOUND);
+        let (status, blocked) = send(&app, probe("/health")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(blocked["error"]["code"], "ip_blocked");
+
+        let list = || from(admin, request(Method::GET, "/admin/blocked_ips", token, ""));
+        let (_, listed) = send(&app, list()).await;
+        assert_eq!(listed["total"], 1);
+        assert_eq!(listed["data"][0]["ip"], "198.51.100.7");
+        assert_eq!(listed["data"][0]["path"], "/.env");
+        assert_eq!(listed["data"][0]["blocked"], true);
+
+        let uri = "/admin/blocked_ips/198.51.100.7";
+        let unblock = || from(admin, request(Method::DELETE, uri, token, ""));
+        assert_eq!(send(&app, unblock()).await.0, StatusCode::OK);
+        assert_eq!(send(&app, unblock()).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, probe("/health")).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn keys_need_a_default_api_key_and_config_hides_secrets() {
+        let app = create_app(admin_config());
+        let token = "admin-secret";
+
+        let (status, _) = send(
+            &app,
+            request(Method::POST, "/admin/keys", token, r#"{"name":"ci"}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, config) = send(&app, request(Method::GET, "/admin/config", token, "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(config["admin_api"], true);
+        assert!(!config.to_string().contains(token));
+    }
+
+    #[tokio::test]
+    async fn routes_move_models_between_backends() {
+        let app = create_app(
+            admin_config()
+                .with_openai_upstream("http://localhost:11434/v1", vec!["llama3.1".to_string()]),
+        );
+        let token = "admin-secret";
+
+        let (status, _) = send(
+            &app,
+            request(
+                Method::PUT,
+                "/admin/routes/llama3.2",
+                token,
+                r#"{"backend":"openai-upstream"}"#,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, list) = send(&app, request(Method::GET, "/admin/routes", token, "")).await;
+        assert_eq!(
+            list["data"],
+            json!([
+                {"model": "llama3.1", "backend": "openai-upstream"},
+                {"model": "llama3.2", "backend": "openai-upstream"}
+            ])
+        );
+
+        let (status, _) = send(
+            &app,
+            request(
+                Method::PUT,
+                "/admin/routes/llama3.2",
+                token,
+                r#"{"backend":"maple"}"#,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, list) = send(&app, request(Method::GET, "/admin/routes", token, "")).await;
+        assert_eq!(
+            list["data"],
+            json!([{"model": "llama3.1", "backend": "openai-upstream"}])
+        );
+        let (status, _) = send(
+            &app,
+            request(Method::DELETE, "/admin/routes/llama3.2", token, ""),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    config::{Config, OpenAIError},
+    proxy::ProxyState,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+/// What shed requests are told to wait before retrying
+const RETRY_AFTER_SECS: u64 = 2;
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Shed {
+    /// The queue already held `--queue-depth` requests
+    QueueFull,
+    /// No slot opened within `--queue-timeout-secs`
+    TimedOut,
+}
+
+impl Shed {
+    pub(crate) fn reason(self) -> &'static str {
+        match self {
+            Shed::QueueFull => "queue_full",
+            Shed::TimedOut => "queue_timeout",
+        }
+    }
+}
+
+/// Caps the inference requests handled at once, queueing a bounded number of
+/// others in arrival order and shedding the rest
+pub(crate) struct AdmissionQueue {
+    slots: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    max_waiting: usize,
+    wait_timeout: Duration,
+}
+
+impl AdmissionQueue {
+    /// `None` without `--max-concurrent-requests`
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        let slots = usize::try_from(config.max_concurrent_requests?).unwrap_or(usize::MAX);
+        Some(Self {
+            slots: Arc::new(Semaphore::new(slots.min(Semaphore::MAX_PERMITS))),
+            waiting: AtomicUsize::new(0),
+            max_waiting: usize::try_from(config.queue_depth).unwrap_or(usize::MAX),
+            wait_timeout: Duration::from_secs(config.queue_timeout_secs),
+        })
+    }
+
+    /// Waits for a slot, which is held until the permit is dropped
+    pub(crate) async fn admit(&self) -> Result<OwnedSemaphorePermit, Shed> {
+        // The semaphore hands freed slots to waiters first, so this only
+        // succeeds when nobody is queued
+        if let Ok(slot) = Arc::clone(&self.slots).try_acquire_owned() {
+            return Ok(slot);
+        }
+        if self.waiting.fetch_add(1, Ordering::AcqRel) >= self.max_waiting {
+            self.waiting.fetch_sub(1, Ordering::AcqRel);
+            return Err(Shed::QueueFull);
+        }
+        let _waiting = Waiting(&self.waiting);
+        tokio::time::timeout(self.wait_timeout, Arc::clone(&self.slots).acquire_owned())
+            .await
+            .map_err(|_| Shed::TimedOut)
+            .map(|slot| slot.expect("the admission queue is never closed"))
+    }
+}
+
+/// Leaves the queue when a request is admitted, times out, or its client
+/// goes away
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Admits inference requests through the queue. A slot is held until the
+/// response body ends, so streams count for as long as they run.
+pub(crate) async fn queue_requests(
+    State(state): State<Arc<ProxyState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(queue) = state.admission_queue() else {
+        return next.run(request).await;
+    };
+    let slot = match queue.admit().await {
+        Ok(slot) => slot,
+        Err(shed) => {
+            debug!("Shed {} ({})", request.uri().path(), shed.reason());
+            state.metrics().record_shed(shed);
+            return shed_response(shed);
+        }
+    };
+
+    let (parts, body) = next.run(request).await.into_parts();
+    let mut stream = body.into_data_stream();
+    let body = Body::from_stream(async_stream::stream! {
+        let _slot = slot;
+        while let Some(chunk) = stream.next().await {
+            yield chunk;
+        }
+    });
+    Response::from_parts(parts, body)
+}
+
+fn shed_response(shed: Shed) -> Response {
+    let message = match shed {
+        Shed::QueueFull => "The server is handling too many requests. Please retry shortly.",
+        Shed::TimedOut => "The server is busy and no slot opened in time. Please retry shortly.",
+    };
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(OpenAIError::server_error(message).with_code("server_overloaded")),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(slots: u32, depth: u32) -> AdmissionQueue {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_max_concurrent_requests(slots)
+        .with_queue(depth, 1);
+        AdmissionQueue::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_wait_in_order_and_overflow_is_shed() {
+        let queue = Arc::new(queue(1, 1));
+        let first = queue.admit().await.unwrap();
+
+        let waiting_queue = Arc::clone(&queue);
+        let second = tokio::spawn(async move { waiting_queue.admit().await.map(drop) });
+        tokio::task::yield_now().await;
+        assert_eq!(queue.admit().await.err(), Some(Shed::QueueFull));
+
+        drop(first);
+        assert_eq!(second.await.unwrap(), Ok(()));
+        assert_eq!(queue.waiting.load(Ordering::Acquire), 0);
+    }
+
+    #[tokio::test]
+    async fn waits_end_at_the_deadline() {
+        let queue = queue(1, 4);
+        let _busy = queue.admit().await.unwrap();
+        assert_eq!(queue.admit().await.err(), Some(Shed::TimedOut));
+        assert_eq!(queue.waiting.load(Ordering::Acquire), 0);
+    }
+}
@@ -0,0 +1,428 @@
+use crate::{
+    config::Config,
+    diagnose::sanitize_url,
+    ids::{from_hex, random_hex, to_hex},
+    proxy::ProxyState,
+    release::USER_AGENT,
+};
+use anyhow::Context;
+use axum::{extract::State, Json};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ciborium::Value as Cbor;
+use clap::ValueEnum;
+use dashmap::DashMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::OnceCell;
+use tracing::warn;
+
+const DOCUMENT_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const NONCE_BYTES: usize = 16;
+
+/// Whether backends are attested before the proxy starts serving
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum StartupAttestation {
+    /// Attest on the first request that needs a backend
+    #[default]
+    Off,
+    /// Attest at startup and log the backends that fail, but serve anyway
+    Warn,
+    /// Attest at startup and exit unless at least one backend passes
+    Require,
+}
+
+/// A measurement an enclave's attestation document must report, as
+/// INDEX=HEX. PCR0 is the hash of the enclave image, its code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedPcr {
+    pub index: u64,
+    /// Lowercase hex
+    pub value: String,
+}
+
+impl FromStr for ExpectedPcr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected INDEX=HEX, got '{}'", value);
+        let (index, measurement) = value.split_once('=').ok_or_else(expected)?;
+        let index = index.trim().parse::<u64>().map_err(|_| expected())?;
+        let measurement = measurement.trim().to_ascii_lowercase();
+        if measurement.is_empty() || from_hex(&measurement).is_none() {
+            return Err(format!("the measurement must be hex in '{}'", value));
+        }
+        Ok(Self {
+            index,
+            value: measurement,
+        })
+    }
+}
+
+impl fmt::Display for ExpectedPcr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.index, self.value)
+    }
+}
+
+/// What an enclave's attestation document says about it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct AttestationDocument {
+    module_id: Option<String>,
+    /// The hash algorithm of the measurements, such as SHA384
+    digest: Option<String>,
+    /// When the enclave signed the document, in Unix milliseconds
+    timestamp: Option<u64>,
+    /// Platform configuration registers: the measurements of the enclave
+    /// image, kernel, and application, by index, in hex
+    pcrs: BTreeMap<u64, String>,
+}
+
+/// A backend's last successful attestation handshake
+struct AttestedBackend {
+    verified_at: SystemTime,
+    handshake: Duration,
+    /// Fetched the first time it is asked for after the handshake
+    document: OnceCell<AttestationDocument>,
+}
+
+/// When each backend last passed the OpenSecret attestation handshake, for
+/// `/v1/attestation`, with a summary of the document it attests with, and
+/// whether that reports the `--expected-pcr` measurements
+pub(crate) struct Attestations {
+    backends: DashMap<String, Arc<AttestedBackend>>,
+    expected_pcrs: Vec<ExpectedPcr>,
+    /// Why each backend's last handshake failed the pinned measurements
+    mismatches: DashMap<String, String>,
+    client: Option<reqwest::Client>,
+}
+
+impl Attestations {
+    pub(crate) fn new(config: &Config) -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(DOCUMENT_FETCH_TIMEOUT)
+            .build()
+            .map_err(|error| warn!("Attestation documents cannot be fetched: {}", error))
+            .ok();
+        Self {
+            backends: DashMap::new(),
+            expected_pcrs: config.expected_pcrs.clone(),
+            mismatches: DashMap::new(),
+            client,
+        }
+    }
+
+    /// Whether `--expected-pcr` pins any measurements
+    pub(crate) fn is_pinned(&self) -> bool {
+        !self.expected_pcrs.is_empty()
+    }
+
+    /// Notes a handshake with `backend_url` that passed, which took
+    /// `handshake`. With pinned measurements, the backend's attestation
+    /// document is fetched and must report them; if it does not, or cannot be
+    /// fetched, the reason is returned and the backend must not be used.
+    pub(crate) async fn check(&self, backend_url: &str, handshake: Duration) -> Result<(), String> {
+        let attested = Arc::new(AttestedBackend {
+            verified_at: SystemTime::now(),
+            handshake,
+            document: OnceCell::new(),
+        });
+        self.backends
+            .insert(backend_url.to_string(), Arc::clone(&attested));
+        if !self.is_pinned() {
+            return Ok(());
+        }
+
+        let document = attested
+            .document
+            .get_or_try_init(|| self.fetch_document(backend_url))
+            .await;
+        let checked = match document {
+            Ok(document) => self.check_pcrs(document),
+            Err(fetch_error) => Err(format!(
+                "its attestation document could not be fetched: {:#}",
+                fetch_error
+            )),
+        };
+        match &checked {
+            Ok(()) => {
+                self.mismatches.remove(backend_url);
+            }
+            Err(mismatch) => {
+                self.mismatches
+                    .insert(backend_url.to_string(), mismatch.clone());
+            }
+        }
+        checked
+    }
+
+    /// Each pinned PCR must have one of the values pinned for its index
+    fn check_pcrs(&self, document: &AttestationDocument) -> Result<(), String> {
+        let indexes: BTreeSet<u64> = self.expected_pcrs.iter().map(|pcr| pcr.index).collect();
+        for index in indexes {
+            let actual = document.pcrs.get(&index);
+            let pinned = actual.is_some_and(|actual| {
+                self.expected_pcrs
+                    .iter()
+                    .any(|pcr| pcr.index == index && pcr.value == *actual)
+            });
+            if !pinned {
+                return Err(format!(
+                    "PCR{} is {}, which is not pinned",
+                    index,
+                    actual.map_or("missing", String::as_str)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The backends whose last handshake failed the pinned measurements, and
+    /// why
+    pub(crate) fn mismatches(&self) -> Vec<(String, String)> {
+        let mut mismatches: Vec<_> = self
+            .mismatches
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        mismatches.sort();
+        mismatches
+    }
+
+    /// Each backend's attestation status. Attested sessions are renewed after
+    /// `session_ttl`.
+    async fn status<'a>(
+        &self,
+        backend_urls: impl Iterator<Item = &'a str>,
+        session_ttl: Duration,
+    ) -> Vec<Value> {
+        let mut statuses = Vec::new();
+        for backend_url in backend_urls {
+            let attested = self
+                .backends
+                .get(backend_url)
+                .map(|entry| Arc::clone(entry.value()));
+            let mut status = json!({
+                "backend_url": sanitize_url(backend_url),
+                "attested": attested.is_some(),
+            });
+            if let Some(attested) = attested {
+                status["verified_at"] = json!(unix_secs(attested.verified_at));
+                status["handshake_ms"] = json!(attested.handshake.as_millis() as u64);
+                status["expires_at"] = json!(unix_secs(attested.verified_at + session_ttl));
+                let document = attested
+                    .document
+                    .get_or_try_init(|| self.fetch_document(backend_url))
+                    .await;
+                match document {
+                    Ok(document) => status["document"] = json!(document),
+                    Err(fetch_error) => {
+                        status["document_error"] = json!(format!("{:#}", fetch_error))
+                    }
+                }
+            }
+            if self.is_pinned() {
+                let mismatch = self
+                    .mismatches
+                    .get(backend_url)
+                    .map(|entry| entry.value().clone());
+                let matched = status["attested"] == true && mismatch.is_none();
+                status["pinned_measurements_match"] = json!(matched);
+                if let Some(mismatch) = mismatch {
+                    status["mismatch"] = json!(mismatch);
+                }
+            }
+            statuses.push(status);
+        }
+        statuses
+    }
+
+    /// Asks the backend for an attestation document over a fresh nonce
+    async fn fetch_document(&self, backend_url: &str) -> anyhow::Result<AttestationDocument> {
+        let client = self
+            .client
+            .as_ref()
+            .context("Attestation documents cannot be fetched")?;
+        let nonce = random_hex::<NONCE_BYTES>();
+
+        let url = format!(
+            "{}/attestation/{}",
+            backend_url.trim_end_matches('/'),
+            nonce
+        );
+        let response: Value = client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let encoded = response["attestation_document"]
+            .as_str()
+            .context("The backend sent no attestation document")?;
+        parse_document(encoded, nonce.as_bytes())
+    }
+}
+
+/// Summarizes a base64 COSE_Sign1 attestation document, checking that it
+/// answers `nonce`. Its signature was checked by the handshake, not here.
+fn parse_document(encoded: &str, nonce: &[u8]) -> anyhow::Result<AttestationDocument> {
+    let bytes = BASE64
+        .decode(encoded.trim())
+        .context("The attestation document is not base64")?;
+    let cose: Cbor =
+        ciborium::from_reader(bytes.as_slice()).context("The attestation document is not CBOR")?;
+    let cose = match cose {
+        Cbor::Tag(_, inner) => *inner,
+        untagged => untagged,
+    };
+    let payload = cose
+        .as_array()
+        .and_then(|parts| parts.get(2))
+        .and_then(Cbor::as_bytes)
+        .context("The attestation document is not a COSE_Sign1 structure")?;
+    let payload: Cbor = ciborium::from_reader(payload.as_slice())
+        .context("The attestation document's payload is not CBOR")?;
+    let fields = payload
+        .as_map()
+        .context("The attestation document's payload is not a map")?;
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| key.as_text() == Some(name))
+            .map(|(_, value)| value)
+    };
+
+    if field("nonce").and_then(Cbor::as_bytes).map(Vec::as_slice) != Some(nonce) {
+        anyhow::bail!("The attestation document does not answer the nonce it was asked for");
+    }
+    let pcrs = field("pcrs")
+        .and_then(Cbor::as_map)
+        .into_iter()
+        .flatten()
+        .filter_map(|(index, measurement)| {
+            let index = u64::try_from(index.as_integer()?).ok()?;
+            Some((index, to_hex(measurement.as_bytes()?)))
+        })
+        .collect();
+    Ok(AttestationDocument {
+        module_id: field("module_id")
+            .and_then(Cbor::as_text)
+            .map(str::to_string),
+        digest: field("digest").and_then(Cbor::as_text).map(str::to_string),
+        timestamp: field("timestamp")
+            .and_then(Cbor::as_integer)
+            .and_then(|timestamp| u64::try_from(timestamp).ok()),
+        pcrs,
+    })
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Lets clients audit that the proxy talks to attested enclaves: when each
+/// backend last passed the attestation handshake, when the proxy attests
+/// again, and the measurements its attestation document reports
+pub(crate) async fn attestation_status(State(state): State<Arc<ProxyState>>) -> Json<Value> {
+    let backends = state
+        .attestations()
+        .status(state.config().backend_urls(), state.client_session_ttl())
+        .await;
+    Json(json!({"object": "maple.attestation", "backends": backends}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(nonce: &[u8]) -> String {
+        let payload = Cbor::Map(vec![
+            (
+                Cbor::Text("module_id".into()),
+                Cbor::Text("i-0abc-enc0123".into()),
+            ),
+            (Cbor::Text("digest".into()), Cbor::Text("SHA384".into())),
+            (
+                Cbor::Text("timestamp".into()),
+                Cbor::Integer(1_760_000_000_000u64.into()),
+            ),
+            (
+                Cbor::Text("pcrs".into()),
+                Cbor::Map(vec![
+                    (Cbor::Integer(0.into()), Cbor::Bytes(vec![0xab; 4])),
+                    (Cbor::Integer(8.into()), Cbor::Bytes(vec![0x01, 0x02])),
+                ]),
+            ),
+            (Cbor::Text("nonce".into()), Cbor::Bytes(nonce.to_vec())),
+        ]);
+        let mut payload_bytes = Vec::new();
+        ciborium::into_writer(&payload, &mut payload_bytes).unwrap();
+        let cose = Cbor::Tag(
+            18,
+            Box::new(Cbor::Array(vec![
+                Cbor::Bytes(Vec::new()),
+                Cbor::Map(Vec::new()),
+                Cbor::Bytes(payload_bytes),
+                Cbor::Bytes(vec![0; 96]),
+            ])),
+        );
+        let mut cose_bytes = Vec::new();
+        ciborium::into_writer(&cose, &mut cose_bytes).unwrap();
+        BASE64.encode(cose_bytes)
+    }
+
+    #[test]
+    fn documents_are_summarized_when_they_answer_the_nonce() {
+        let summary = parse_document(&document(b"nonce-1"), b"nonce-1").unwrap();
+        assert_eq!(summary.module_id.as_deref(), Some("i-0abc-enc0123"));
+        assert_eq!(summary.digest.as_deref(), Some("SHA384"));
+        assert_eq!(summary.timestamp, Some(1_760_000_000_000));
+        assert_eq!(summary.pcrs[&0], "abababab");
+        assert_eq!(summary.pcrs[&8], "0102");
+
+        assert!(parse_document(&document(b"nonce-1"), b"nonce-2").is_err());
+        assert!(parse_document("not base64!", b"nonce-1").is_err());
+    }
+
+    #[test]
+    fn pinned_pcrs_must_have_one_of_their_pinned_values() {
+        let pinned = |pcrs: &[&str]| {
+            let config = pcrs.iter().fold(
+                Config::new(
+                    "127.0.0.1".to_string(),
+                    0,
+                    "http://localhost:3000".to_string(),
+                ),
+                |config, pcr| config.with_expected_pcr(pcr.parse().unwrap()),
+            );
+            Attestations::new(&config)
+        };
+        let document = parse_document(&document(b"nonce-1"), b"nonce-1").unwrap();
+
+        let upgrading = pinned(&["0=ABABABAB", "0=cdcdcdcd", "8=0102"]);
+        assert!(upgrading.is_pinned());
+        assert_eq!(upgrading.check_pcrs(&document), Ok(()));
+        assert_eq!(
+            pinned(&["0=cdcdcdcd"]).check_pcrs(&document),
+            Err("PCR0 is abababab, which is not pinned".to_string())
+        );
+        assert_eq!(
+            pinned(&["3=00"]).check_pcrs(&document),
+            Err("PCR3 is missing, which is not pinned".to_string())
+        );
+
+        for invalid in ["abab", "x=abab", "0=", "0=xyz", "0=abc"] {
+            assert!(invalid.parse::<ExpectedPcr>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,549 @@
+use crate::{ids::unix_now, proxy::ProxyError, sse::SseParser, storage};
+use anyhow::Context;
+use axum::{
+    body::Body,
+    http::{HeaderMap, Method},
+    response::Response,
+};
+use clap::ValueEnum;
+use futures::StreamExt;
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::{
+    path::Path,
+    sync::{
+        mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{error, warn};
+
+/// Records waiting to be written; past this, new ones are dropped with a
+/// warning rather than holding up requests
+const AUDIT_QUEUE_LEN: usize = 1024;
+
+/// How often records past the retention period are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Prompt and response text past this many bytes is cut off
+const MAX_AUDIT_TEXT_BYTES: usize = 64 * 1024;
+
+/// Non-streaming responses past this many bytes are recorded without usage or
+/// text
+const MAX_AUDIT_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS audit_log (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        method TEXT NOT NULL,
+        path TEXT NOT NULL,
+        model TEXT,
+        api_key TEXT,
+        request_id TEXT,
+        status INTEGER NOT NULL,
+        streamed INTEGER NOT NULL,
+        completed INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        prompt_tokens INTEGER,
+        completion_tokens INTEGER,
+        request_text TEXT,
+        response_text TEXT
+    );
+    CREATE INDEX IF NOT EXISTS audit_log_timestamp ON audit_log (timestamp);
+";
+
+/// How much of a request's prompt and response the audit log keeps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AuditContent {
+    /// Only metadata: model, key, status, timing, and token usage
+    #[default]
+    Off,
+    /// Prompt and response text with email addresses, long numbers, and
+    /// secrets masked
+    Redacted,
+    /// Prompt and response text as sent
+    Full,
+}
+
+/// One request through the proxy, as stored in the `audit_log` table
+#[derive(Debug, Default, Clone, PartialEq)]
+struct AuditRecord {
+    timestamp: u64,
+    method: String,
+    path: String,
+    model: Option<String>,
+    /// The virtual key's ID, or a hint of the API key
+    api_key: Option<String>,
+    request_id: Option<String>,
+    status: u16,
+    streamed: bool,
+    /// Whether the client received the whole response
+    completed: bool,
+    duration_ms: u64,
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+    request_text: Option<String>,
+    response_text: Option<String>,
+}
+
+/// Writes audit records to a SQLite database on a thread of its own, so
+/// requests never wait on the disk
+pub(crate) struct AuditLog {
+    records: SyncSender<AuditRecord>,
+    content: AuditContent,
+}
+
+impl AuditLog {
+    /// Opens or creates the database. Records older than `retention` are
+    /// deleted now and then.
+    pub(crate) fn open(
+        path: &Path,
+        content: AuditContent,
+        retention: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        let connection = open_database(path)?;
+        let (records, received) = mpsc::sync_channel(AUDIT_QUEUE_LEN);
+        thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || write_records(connection, received, retention))
+            .context("Failed to start the audit log writer")?;
+        Ok(Self { records, content })
+    }
+
+    /// Starts the record of a request, which is written once its response has
+    /// been sent or abandoned
+    pub(crate) fn start(
+        self: &Arc<Self>,
+        method: &Method,
+        path: &str,
+        api_key: Option<String>,
+        body: &[u8],
+    ) -> PendingAudit {
+        let request: Option<Value> = serde_json::from_slice(body).ok();
+        let request_text = request
+            .as_ref()
+            .filter(|_| self.content != AuditContent::Off)
+            .and_then(|request| {
+                ["messages", "input", "prompt"]
+                    .iter()
+                    .find_map(|field| request.get(*field))
+            })
+            .map(|prompt| self.prompt_text(prompt.clone()));
+
+        PendingAudit {
+            log: Arc::clone(self),
+            started_at: Instant::now(),
+            record: AuditRecord {
+                timestamp: unix_now(),
+                method: method.to_string(),
+                path: path.to_string(),
+                model: request
+                    .as_ref()
+                    .and_then(|request| request.get("model"))
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                api_key,
+                request_text,
+                ..AuditRecord::default()
+            },
+            parser: SseParser::default(),
+            buffered: Some(Vec::new()),
+            response_text: String::new(),
+        }
+    }
+
+    /// The prompt as JSON, with its strings redacted one by one so the JSON
+    /// stays intact
+    fn prompt_text(&self, mut prompt: Value) -> String {
+        if self.content == AuditContent::Redacted {
+            redact_strings(&mut prompt);
+        }
+        truncate(&prompt.to_string(), MAX_AUDIT_TEXT_BYTES).to_string()
+    }
+
+    fn response_text(&self, text: &str) -> String {
+        let text = truncate(text, MAX_AUDIT_TEXT_BYTES);
+        match self.content {
+            AuditContent::Redacted => redact(text),
+            _ => text.to_string(),
+        }
+    }
+
+    fn write(&self, record: AuditRecord) {
+        match self.records.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(record)) => warn!(
+                "Audit log is behind; dropped the record of {} {}",
+                record.method, record.path
+            ),
+            Err(TrySendError::Disconnected(_)) => error!("Audit log writer has stopped"),
+        }
+    }
+}
+
+/// Checks that the audit database can be opened and has the expected table
+pub(crate) fn check_audit_db(path: &Path) -> anyhow::Result<()> {
+    open_database(path).map(drop)
+}
+
+fn open_database(path: &Path) -> anyhow::Result<Connection> {
+    storage::open_database(path, "audit", SCHEMA)
+}
+
+fn write_records(
+    connection: Connection,
+    received: mpsc::Receiver<AuditRecord>,
+    retention: Option<Duration>,
+) {
+    let mut last_pruned: Option<Instant> = None;
+    loop {
+        if let Some(retention) = retention {
+            if last_pruned.is_none_or(|pruned| pruned.elapsed() >= PRUNE_INTERVAL) {
+                if let Err(prune_error) = prune(&connection, retention) {
+                    error!("Failed to prune the audit log: {}", prune_error);
+                }
+                last_pruned = Some(Instant::now());
+            }
+        }
+
+        match received.recv_timeout(PRUNE_INTERVAL) {
+            Ok(record) => {
+                if let Err(insert_error) = insert(&connection, &record) {
+                    error!(
+                        "Failed to write the audit record of {} {}: {}",
+                        record.method, record.path, insert_error
+                    );
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+fn insert(connection: &Connection, record: &AuditRecord) -> rusqlite::Result<()> {
+    connection
+        .prepare_cached(
+            "INSERT INTO audit_log (timestamp, method, path, model, api_key, request_id, status,
+                streamed, completed, duration_ms, prompt_tokens, completion_tokens, request_text,
+                response_text)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        )?
+        .execute(params![
+            record.timestamp,
+            record.method,
+            record.path,
+            record.model,
+            record.api_key,
+            record.request_id,
+            record.status,
+            record.streamed,
+            record.completed,
+            record.duration_ms,
+            record.prompt_tokens,
+            record.completion_tokens,
+            record.request_text,
+            record.response_text,
+        ])
+        .map(drop)
+}
+
+fn prune(connection: &Connection, retention: Duration) -> rusqlite::Result<usize> {
+    let cutoff = unix_now().saturating_sub(retention.as_secs());
+    connection.execute(
+        "DELETE FROM audit_log WHERE timestamp < ?1",
+        params![cutoff],
+    )
+}
+
+/// A request whose record is written when this is dropped: once the response
+/// body has been sent, or as soon as the client goes away
+pub(crate) struct PendingAudit {
+    log: Arc<AuditLog>,
+    started_at: Instant,
+    record: AuditRecord,
+    parser: SseParser,
+    /// A non-streaming response body, until it grows too large to parse
+    buffered: Option<Vec<u8>>,
+    response_text: String,
+}
+
+impl PendingAudit {
+    /// Records the outcome of a request and, for responses, follows the body
+    /// as it is sent
+    pub(crate) fn finish(
+        mut self,
+        result: Result<Response, ProxyError>,
+    ) -> Result<Response, ProxyError> {
+        let response = match result {
+            Ok(response) => response,
+            Err(error) => {
+                self.record.status = error.0.as_u16();
+                self.record.completed = true;
+                return Err(error);
+            }
+        };
+
+        self.record.status = response.status().as_u16();
+        self.record.streamed = crate::proxy::is_event_stream(response.headers());
+        self.record.request_id = request_id(response.headers());
+        let (parts, body) = response.into_parts();
+        let mut stream = body.into_data_stream();
+        let body = Body::from_stream(async_stream::stream! {
+            let mut pending = self;
+            while let Some(chunk) = stream.next().await {
+                if let Ok(bytes) = &chunk {
+                    pending.observe(bytes);
+                }
+                yield chunk;
+            }
+            pending.record.completed = true;
+        });
+        Ok(Response::from_parts(parts, body))
+    }
+
+    fn observe(&mut self, bytes: &[u8]) {
+        if !self.record.streamed {
+            if let Some(body) = &mut self.buffered {
+                if body.len() + bytes.len() <= MAX_AUDIT_BODY_BYTES {
+                    body.extend_from_slice(bytes);
+                } else {
+                    self.buffered = None;
+                }
+            }
+            return;
+        }
+
+        for data in self.parser.push(bytes) {
+            if let Ok(chunk) = serde_json::from_str::<Value>(&data) {
+                self.observe_completion(&chunk, "delta");
+            }
+        }
+    }
+
+    /// Takes the usage and the text of each choice's `message` or `delta`
+    fn observe_completion(&mut self, completion: &Value, field: &str) {
+        let usage = &completion["usage"];
+        if let Some(prompt_tokens) = usage["prompt_tokens"].as_u64() {
+            self.record.prompt_tokens = Some(prompt_tokens);
+        }
+        if let Some(completion_tokens) = usage["completion_tokens"].as_u64() {
+            self.record.completion_tokens = Some(completion_tokens);
+        }
+        if self.log.content == AuditContent::Off {
+            return;
+        }
+        let choices = completion["choices"].as_array().into_iter().flatten();
+        for text in choices.filter_map(|choice| choice[field]["content"].as_str()) {
+            if self.response_text.len() < MAX_AUDIT_TEXT_BYTES {
+                self.response_text.push_str(text);
+            }
+        }
+    }
+}
+
+impl Drop for PendingAudit {
+    fn drop(&mut self) {
+        if let Some(body) = self.buffered.take().filter(|_| !self.record.streamed) {
+            if let Ok(completion) = serde_json::from_slice::<Value>(&body) {
+                self.observe_completion(&completion, "message");
+            }
+        }
+        self.record.duration_ms = self.started_at.elapsed().as_millis() as u64;
+        if !self.response_text.is_empty() {
+            self.record.response_text = Some(self.log.response_text(&self.response_text));
+        }
+        self.log.write(std::mem::take(&mut self.record));
+    }
+}
+
+fn request_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn truncate(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+fn redact_strings(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = redact(text),
+        Value::Array(items) => items.iter_mut().for_each(redact_strings),
+        Value::Object(fields) => fields.values_mut().for_each(redact_strings),
+        _ => {}
+    }
+}
+
+/// Masks email addresses, numbers of seven or more digits such as phone and
+/// card numbers, and strings that look like API keys or tokens
+fn redact(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    for word in text.split_inclusive(char::is_whitespace) {
+        let token = word.trim_end_matches(char::is_whitespace);
+        let core = token.trim_matches(|c: char| "\"'`()<>[]{},.;:!?".contains(c));
+        match mask_for(core) {
+            Some(mask) => {
+                let start = core.as_ptr() as usize - token.as_ptr() as usize;
+                redacted.push_str(&word[..start]);
+                redacted.push_str(mask);
+                redacted.push_str(&word[start + core.len()..]);
+            }
+            None => redacted.push_str(word),
+        }
+    }
+    redacted
+}
+
+fn mask_for(word: &str) -> Option<&'static str> {
+    let email = word
+        .split_once('@')
+        .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'));
+    let number = word.chars().filter(char::is_ascii_digit).count() >= 7
+        && word
+            .chars()
+            .all(|c| c.is_ascii_digit() || "+-().".contains(c));
+    if email {
+        Some("[email]")
+    } else if number {
+        Some("[number]")
+    } else if looks_like_secret(word) {
+        Some("[secret]")
+    } else {
+        None
+    }
+}
+
+fn looks_like_secret(token: &str) -> bool {
+    let key_like = token.len() >= 20
+        && ["sk-", "pk-", "ghp_", "xox", "AKIA", "eyJ"]
+            .iter()
+            .any(|prefix| token.starts_with(prefix));
+    let random_like = token.len() >= 32
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_+/=".contains(c))
+        && token.chars().any(|c| c.is_ascii_digit())
+        && token.chars().any(|c| c.is_ascii_alphabetic());
+    key_like || random_like
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, http::StatusCode};
+
+    #[test]
+    fn redacts_contact_details_and_secrets() {
+        assert_eq!(
+            redact("Mail jane.doe@example.com or call +1-555-123-4567, card 4111111111111111."),
+            "Mail [email] or call [number], card [number]."
+        );
+        assert_eq!(
+            redact("Use (sk-proj-abcdefghijklmnopqrstu) not 2024"),
+            "Use ([secret]) not 2024"
+        );
+        assert_eq!(redact("Line one\n  line two "), "Line one\n  line two ");
+    }
+
+    #[tokio::test]
+    async fn records_requests_with_usage_and_text() {
+        let dir = std::env::temp_dir().join(format!("maple-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.db");
+        let log = Arc::new(AuditLog::open(&path, AuditContent::Redacted, None).unwrap());
+
+        let request = br#"{"model":"m","messages":[{"role":"user","content":"I am a@b.co"}]}"#;
+        let pending = log.start(&Method::POST, "/v1/chat/completions", None, request);
+        let response = Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(Body::from(concat!(
+                "data: {\"choices\":[{\"delta\":{\"content\":\"Hi \"}}]}\n\n",
+                "data: {\"choices\":[{\"delta\":{\"content\":\"there\"}}],",
+                "\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":2}}\n\n",
+                "data: [DONE]\n\n"
+            )))
+            .unwrap();
+        let response = pending.finish(Ok(response)).unwrap();
+        to_bytes(response.into_body(), 1024).await.unwrap();
+
+        let pending = log.start(
+            &Method::POST,
+            "/v1/embeddings",
+            Some("key_1".to_string()),
+            b"",
+        );
+        let error = crate::config::OpenAIError::invalid_request_error("bad");
+        assert!(pending
+            .finish(Err((StatusCode::BAD_REQUEST, axum::Json(error))))
+            .is_err());
+        drop(log);
+
+        // The writer finishes the queue once the log is dropped
+        let connection = Connection::open(&path).unwrap();
+        let mut rows = Vec::new();
+        for _ in 0..100 {
+            let mut statement = connection
+                .prepare(
+                    "SELECT path, model, api_key, status, streamed, completed, prompt_tokens,
+                        completion_tokens, request_text, response_text FROM audit_log ORDER BY id",
+                )
+                .unwrap();
+            rows = statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, u16>(3)?,
+                        row.get::<_, bool>(4)?,
+                        row.get::<_, bool>(5)?,
+                        row.get::<_, Option<u64>>(6)?,
+                        row.get::<_, Option<u64>>(7)?,
+                        row.get::<_, Option<String>>(8)?,
+                        row.get::<_, Option<String>>(9)?,
+                    ))
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            if rows.len() == 2 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            rows[0],
+            (
+                "/v1/chat/completions".to_string(),
+                Some("m".to_string()),
+                None,
+                200,
+                true,
+                true,
+                Some(9),
+                Some(2),
+                Some(r#"[{"role":"user","content":"I am [email]"}]"#.to_string()),
+                Some("Hi there".to_string()),
+            )
+        );
+        assert_eq!(rows[1].0, "/v1/embeddings");
+        assert_eq!(rows[1].2.as_deref(), Some("key_1"));
+        assert_eq!(rows[1].3, 400);
+    }
+}
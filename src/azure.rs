@@ -0,0 +1,102 @@
+use crate::{
+    config::OpenAIError,
+    models,
+    proxy::{
+        proxy_inference_request, ProxyError, ProxyState, CHAT_COMPLETIONS_PATH, EMBEDDINGS_PATH,
+    },
+    validation::{self, ValidatedBody},
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::Response,
+    Json,
+};
+use std::sync::Arc;
+
+/// Azure `/openai/deployments/{deployment}/chat/completions`
+pub(crate) async fn azure_chat_completions(
+    State(state): State<Arc<ProxyState>>,
+    Path(deployment): Path<String>,
+    headers: HeaderMap,
+    ValidatedBody(body): ValidatedBody,
+) -> Result<Response, ProxyError> {
+    proxy_deployment_request(&state, CHAT_COMPLETIONS_PATH, &deployment, headers, body).await
+}
+
+/// Azure `/openai/deployments/{deployment}/embeddings`
+pub(crate) async fn azure_embeddings(
+    State(state): State<Arc<ProxyState>>,
+    Path(deployment): Path<String>,
+    headers: HeaderMap,
+    ValidatedBody(body): ValidatedBody,
+) -> Result<Response, ProxyError> {
+    proxy_deployment_request(&state, EMBEDDINGS_PATH, &deployment, headers, body).await
+}
+
+/// Serves a deployment request from the matching OpenAI endpoint. The
+/// `api-version` query parameter is accepted and ignored, and Azure's `api-key`
+/// header is read only with `--api-key-headers`, as on every other route.
+async fn proxy_deployment_request(
+    state: &ProxyState,
+    path: &'static str,
+    deployment: &str,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ProxyError> {
+    let model = deployment_model(&state.config().azure_deployments, deployment);
+    let body = models::with_request_model(&body, model).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(OpenAIError::invalid_request_error(
+                "The request body must be a JSON object.",
+            )),
+        )
+    })?;
+
+    validation::validate_request(path, &body)
+        .and_then(|()| validation::check_input_audio(path, &body, state.config().audio_max_mb))
+        .map_err(|error| (StatusCode::BAD_REQUEST, Json(error)))?;
+    proxy_inference_request(state, Method::POST, Uri::from_static(path), &headers, body).await
+}
+
+/// The model a deployment selects, since Azure request bodies name none
+pub(crate) fn deployment_model<'a>(
+    deployments: &'a [models::ModelAlias],
+    deployment: &'a str,
+) -> &'a str {
+    models::resolve_model_alias(deployments, deployment).unwrap_or(deployment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ModelAlias;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn deployments_map_to_models_or_name_themselves() {
+        let deployments = vec![ModelAlias::new("gpt-4o-prod", "llama3-3-70b")];
+
+        assert_eq!(
+            deployment_model(&deployments, "gpt-4o-prod"),
+            "llama3-3-70b"
+        );
+        assert_eq!(deployment_model(&deployments, "gemma4-31b"), "gemma4-31b");
+    }
+
+    #[test]
+    fn request_bodies_get_the_deployment_model() {
+        let body =
+            models::with_request_model(br#"{"messages":[],"model":"ignored"}"#, "llama3-3-70b")
+                .unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({"messages": [], "model": "llama3-3-70b"})
+        );
+        assert!(models::with_request_model(b"[]", "llama3-3-70b").is_none());
+        assert!(models::with_request_model(b"not json", "llama3-3-70b").is_none());
+    }
+}
@@ -0,0 +1,534 @@
+use crate::{
+    config::{Config, OpenAIError},
+    extension::EXTENSION_FIELD,
+    idempotency,
+    ids::{random_hex, unix_now},
+    proxy::{proxy_inference_request, ProxyError, ProxyState},
+    storage,
+    validation::ValidatedBody,
+    wire,
+};
+use anyhow::Context;
+use axum::{
+    body::{to_bytes, Bytes},
+    extract::{Path, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{json, Value};
+use std::{
+    path::Path as FsPath,
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, error, warn};
+
+/// Completions past this many bytes are stored as failed
+const MAX_RESULT_BYTES: usize = 32 * 1024 * 1024;
+
+const ID_PREFIX: &str = "resp_";
+const ID_BYTES: usize = 12;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS background_responses (
+        id TEXT PRIMARY KEY,
+        owner TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        completed_at INTEGER,
+        status_code INTEGER,
+        body TEXT
+    );
+    CREATE INDEX IF NOT EXISTS background_responses_completed_at
+        ON background_responses (completed_at);
+";
+
+/// A background completion, running or finished
+#[derive(Debug, Clone)]
+struct BackgroundResponse {
+    /// The client that created it, as `ProxyState::key_owner` names it
+    owner: String,
+    created_at: u64,
+    result: Option<BackgroundResult>,
+}
+
+/// What the backend, or the proxy, answered a background completion with
+#[derive(Debug, Clone)]
+struct BackgroundResult {
+    completed_at: u64,
+    status_code: u16,
+    body: Value,
+}
+
+impl BackgroundResponse {
+    /// The response object clients poll, modeled on OpenAI's background
+    /// responses: the completion is its `output` once it succeeds, and the
+    /// error is its `error` if it fails
+    fn to_value(&self, id: &str) -> Value {
+        let (status, output, error) = match &self.result {
+            None => ("in_progress", Value::Null, Value::Null),
+            Some(result) if (200..300).contains(&result.status_code) => {
+                ("completed", result.body.clone(), Value::Null)
+            }
+            Some(result) => {
+                let error = result.body.get("error").unwrap_or(&result.body).clone();
+                ("failed", Value::Null, error)
+            }
+        };
+        json!({
+            "id": id,
+            "object": "response",
+            "created_at": self.created_at,
+            "status": status,
+            "completed_at": self.result.as_ref().map(|result| result.completed_at),
+            "status_code": self.result.as_ref().map(|result| result.status_code),
+            "output": output,
+            "error": error,
+        })
+    }
+}
+
+enum Storage {
+    Memory(DashMap<String, BackgroundResponse>),
+    Sqlite(Mutex<Connection>),
+}
+
+/// Background completions and their results, in memory or, with
+/// `--background-db`, in SQLite. Finished results are kept for
+/// `--background-ttl-secs`.
+pub(crate) struct BackgroundStore {
+    storage: Storage,
+    ttl_secs: u64,
+}
+
+impl BackgroundStore {
+    /// Completions a previous run left unfinished in the database are marked
+    /// failed, since nothing will finish them
+    pub(crate) fn open(config: &Config) -> Self {
+        let storage = match &config.background_db {
+            Some(path) => match open_database(path).and_then(fail_interrupted) {
+                Ok(connection) => Storage::Sqlite(Mutex::new(connection)),
+                Err(open_error) => {
+                    error!("{:#}; background results are kept in memory", open_error);
+                    Storage::Memory(DashMap::new())
+                }
+            },
+            None => Storage::Memory(DashMap::new()),
+        };
+        Self {
+            storage,
+            ttl_secs: config.background_ttl_secs,
+        }
+    }
+
+    /// Records a new completion, and drops results past their TTL
+    fn start(&self, id: &str, response: &BackgroundResponse) -> rusqlite::Result<()> {
+        let expired_before = unix_now().saturating_sub(self.ttl_secs);
+        match &self.storage {
+            Storage::Memory(responses) => {
+                responses.retain(|_, response| {
+                    response
+                        .result
+                        .as_ref()
+                        .is_none_or(|result| result.completed_at >= expired_before)
+                });
+                responses.insert(id.to_string(), response.clone());
+                Ok(())
+            }
+            Storage::Sqlite(connection) => {
+                let connection = lock(connection);
+                connection.execute(
+                    "DELETE FROM background_responses WHERE completed_at < ?1",
+                    params![expired_before],
+                )?;
+                connection.execute(
+                    "INSERT INTO background_responses (id, owner, created_at) VALUES (?1, ?2, ?3)",
+                    params![id, response.owner, response.created_at],
+                )?;
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&self, id: &str, result: BackgroundResult) -> rusqlite::Result<()> {
+        match &self.storage {
+            Storage::Memory(responses) => {
+                if let Some(mut response) = responses.get_mut(id) {
+                    response.result = Some(result);
+                }
+                Ok(())
+            }
+            Storage::Sqlite(connection) => {
+                lock(connection).execute(
+                    "UPDATE background_responses SET completed_at = ?1, status_code = ?2, body = ?3
+                     WHERE id = ?4",
+                    params![
+                        result.completed_at,
+                        result.status_code,
+                        result.body.to_string(),
+                        id
+                    ],
+                )?;
+                Ok(())
+            }
+        }
+    }
+
+    /// A completion, unless its result has expired
+    fn get(&self, id: &str) -> rusqlite::Result<Option<BackgroundResponse>> {
+        let response = match &self.storage {
+            Storage::Memory(responses) => responses.get(id).map(|response| response.clone()),
+            Storage::Sqlite(connection) => lock(connection)
+                .query_row(
+                    "SELECT owner, created_at, completed_at, status_code, body
+                     FROM background_responses WHERE id = ?1",
+                    params![id],
+                    |row| {
+                        let completed_at: Option<u64> = row.get(2)?;
+                        let status_code: Option<u16> = row.get(3)?;
+                        let body: Option<String> = row.get(4)?;
+                        let result = completed_at.zip(status_code).map(|(completed_at, status)| {
+                            BackgroundResult {
+                                completed_at,
+                                status_code: status,
+                                body: body
+                                    .and_then(|body| serde_json::from_str(&body).ok())
+                                    .unwrap_or_default(),
+                            }
+                        });
+                        Ok(BackgroundResponse {
+                            owner: row.get(0)?,
+                            created_at: row.get(1)?,
+                            result,
+                        })
+                    },
+                )
+                .optional()?,
+        };
+        let expired_before = unix_now().saturating_sub(self.ttl_secs);
+        Ok(response.filter(|response| {
+            response
+                .result
+                .as_ref()
+                .is_none_or(|result| result.completed_at >= expired_before)
+        }))
+    }
+}
+
+fn lock(connection: &Mutex<Connection>) -> std::sync::MutexGuard<'_, Connection> {
+    connection
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Checks that the background database can be opened and has the expected
+/// table
+pub(crate) fn check_background_db(path: &FsPath) -> anyhow::Result<()> {
+    open_database(path).map(drop)
+}
+
+fn open_database(path: &FsPath) -> anyhow::Result<Connection> {
+    storage::open_database(path, "background", SCHEMA)
+}
+
+fn fail_interrupted(connection: Connection) -> anyhow::Result<Connection> {
+    let error = OpenAIError::server_error(
+        "The proxy restarted before the completion finished. Please send it again.",
+    );
+    let interrupted = connection
+        .execute(
+            "UPDATE background_responses SET completed_at = ?1, status_code = ?2, body = ?3
+             WHERE completed_at IS NULL",
+            params![
+                unix_now(),
+                StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                wire::to_value(&error).to_string()
+            ],
+        )
+        .context("Failed to update the background database")?;
+    if interrupted > 0 {
+        warn!(
+            "{} background completions were interrupted by a restart",
+            interrupted
+        );
+    }
+    Ok(connection)
+}
+
+/// `/v1/chat/completions`. With `maple.background`, the completion runs in a
+/// background task and the client gets a response ID at once, to poll
+/// `/v1/responses/{id}` with, instead of holding the connection open.
+pub(crate) async fn create_chat_completion(
+    State(state): State<Arc<ProxyState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    ValidatedBody(body): ValidatedBody,
+) -> Result<Response, ProxyError> {
+    idempotency::replay_or_send(&state, &headers, body, |body| {
+        chat_completion(&state, uri, &headers, body)
+    })
+    .await
+}
+
+async fn chat_completion(
+    state: &Arc<ProxyState>,
+    uri: Uri,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Response, ProxyError> {
+    let (background, body) = if state.config().passthrough {
+        (false, body)
+    } else {
+        take_background(body).map_err(bad_request)?
+    };
+    if !background {
+        return proxy_inference_request(state, Method::POST, uri, headers, body).await;
+    }
+
+    let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+    if request["stream"] == true {
+        return Err(bad_request(
+            OpenAIError::invalid_request_error(
+                "Background completions cannot stream; poll /v1/responses/{id} instead.",
+            )
+            .with_param("stream"),
+        ));
+    }
+    let owner = state.key_owner(headers)?;
+    let id = format!("{}{}", ID_PREFIX, random_hex::<ID_BYTES>());
+    let pending = BackgroundResponse {
+        owner,
+        created_at: unix_now(),
+        result: None,
+    };
+    state
+        .background()
+        .start(&id, &pending)
+        .map_err(storage_error)?;
+    tokio::spawn(run_in_background(
+        Arc::clone(state),
+        id.clone(),
+        uri,
+        headers.clone(),
+        body,
+    ));
+    Ok((StatusCode::ACCEPTED, Json(pending.to_value(&id))).into_response())
+}
+
+async fn run_in_background(
+    state: Arc<ProxyState>,
+    id: String,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) {
+    let (status, body) =
+        match proxy_inference_request(&state, Method::POST, uri, &headers, body).await {
+            Ok(response) => read_result(response).await,
+            Err((status, Json(error))) => (status, wire::to_value(&error)),
+        };
+    debug!("Background completion {} finished with {}", id, status);
+    let result = BackgroundResult {
+        completed_at: unix_now(),
+        status_code: status.as_u16(),
+        body,
+    };
+    if let Err(store_error) = state.background().finish(&id, result) {
+        error!(
+            "Failed to store background completion {}: {}",
+            id, store_error
+        );
+    }
+}
+
+async fn read_result(response: Response) -> (StatusCode, Value) {
+    let status = response.status();
+    match to_bytes(response.into_body(), MAX_RESULT_BYTES).await {
+        Ok(body) => {
+            let body = serde_json::from_slice(&body)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+            (status, body)
+        }
+        Err(read_error) => {
+            let error = OpenAIError::server_error(format!(
+                "The completion could not be read: {}",
+                read_error
+            ));
+            (StatusCode::BAD_GATEWAY, wire::to_value(&error))
+        }
+    }
+}
+
+/// `GET /v1/responses/{id}`: a background completion of the caller's key
+pub(crate) async fn get_response(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let response = state
+        .background()
+        .get(&id)
+        .map_err(storage_error)?
+        .filter(|response| response.owner == owner)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(
+                    OpenAIError::invalid_request_error(format!(
+                        "No response found with id '{}'.",
+                        id
+                    ))
+                    .with_param("id"),
+                ),
+            )
+        })?;
+    Ok(Json(response.to_value(&id)))
+}
+
+/// Removes `maple.background` from a JSON request body, returning whether it
+/// was set. Bodies without it are returned byte for byte.
+fn take_background(body: Bytes) -> Result<(bool, Bytes), OpenAIError> {
+    let Ok(mut request) = serde_json::from_slice::<Value>(&body) else {
+        return Ok((false, body));
+    };
+    let flag = request
+        .get_mut(EXTENSION_FIELD)
+        .and_then(Value::as_object_mut)
+        .and_then(|options| options.shift_remove("background"));
+    let background = match flag {
+        None => return Ok((false, body)),
+        Some(Value::Bool(background)) => background,
+        Some(Value::Null) => false,
+        Some(_) => {
+            return Err(
+                OpenAIError::invalid_request_error("`maple.background` must be a boolean.")
+                    .with_param("maple.background"),
+            )
+        }
+    };
+    let body = serde_json::to_vec(&request).map_or(body, Bytes::from);
+    Ok((background, body))
+}
+
+fn storage_error(storage_error: rusqlite::Error) -> ProxyError {
+    storage::storage_error("Background completion", storage_error)
+}
+
+fn bad_request(error: OpenAIError) -> ProxyError {
+    (StatusCode::BAD_REQUEST, Json(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, Router};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    fn post(path: &str, body: Value) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn poll(id: &str, api_key: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().uri(format!("/v1/responses/{}", id));
+        if let Some(api_key) = api_key {
+            request = request.header("authorization", format!("Bearer {}", api_key));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn chat(maple: Value) -> Value {
+        json!({
+            "model": "llama3-3-70b",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "maple": maple
+        })
+    }
+
+    #[tokio::test]
+    async fn background_completions_are_polled_for_their_result() {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_mock_backend(true);
+        let app = crate::create_app(config);
+
+        let (status, created) = send(
+            &app,
+            post("/v1/chat/completions", chat(json!({"background": true}))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(created["status"], "in_progress");
+        let id = created["id"].as_str().unwrap();
+        assert!(id.starts_with(ID_PREFIX));
+
+        let mut polled = created.clone();
+        for _ in 0..100 {
+            if polled["status"] != "in_progress" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            polled = send(&app, poll(id, None)).await.1;
+        }
+        assert_eq!(polled["status"], "completed");
+        assert_eq!(polled["output"]["object"], "chat.completion");
+        assert_eq!(
+            send(&app, poll(id, Some("another-key"))).await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        let mut streamed = chat(json!({"background": true}));
+        streamed["stream"] = json!(true);
+        let (status, error) = send(&app, post("/v1/chat/completions", streamed)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"]["param"], "stream");
+
+        let embeddings = json!({"model": "m", "input": "Hi", "maple": {"background": true}});
+        let (status, error) = send(&app, post("/v1/embeddings", embeddings)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"]["param"], "maple.background");
+    }
+
+    #[test]
+    fn completions_cut_off_by_a_restart_fail() {
+        let path = std::env::temp_dir().join(format!("maple-background-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config::default().with_background_db(&path);
+        let pending = BackgroundResponse {
+            owner: "owner".to_string(),
+            created_at: unix_now(),
+            result: None,
+        };
+        BackgroundStore::open(&config)
+            .start("resp_1", &pending)
+            .unwrap();
+
+        let response = BackgroundStore::open(&config)
+            .get("resp_1")
+            .unwrap()
+            .unwrap();
+        let response = response.to_value("resp_1");
+        assert_eq!(response["status"], "failed");
+        assert_eq!(response["status_code"], 500);
+        assert_eq!(response["error"]["type"], "server_error");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
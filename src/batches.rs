@@ -0,0 +1,1150 @@
+use crate::{
+    config::{Config, OpenAIError},
+    credentials::KEY_HEADERS,
+    ids::{random_hex, unix_now},
+    proxy::{
+        proxy_inference_request, ProxyError, ProxyState, CHAT_COMPLETIONS_PATH, EMBEDDINGS_PATH,
+        MODERATIONS_PATH,
+    },
+    storage::{self, open_append, write_atomically, write_json},
+    transcription::{form_boundary, parse_form},
+    validation::{missing_param, validate_request},
+    wire,
+};
+use anyhow::Context;
+use axum::{
+    body::{to_bytes, Bytes},
+    extract::{Path, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+/// The endpoints a batch's requests may be for
+const BATCH_ENDPOINTS: [&str; 3] = [CHAT_COMPLETIONS_PATH, EMBEDDINGS_PATH, MODERATIONS_PATH];
+
+/// OpenAI's limit on the requests in one batch
+const MAX_BATCH_REQUESTS: usize = 50_000;
+
+/// Validation errors reported for an input file, at most
+const MAX_REPORTED_ERRORS: usize = 100;
+
+const COMPLETION_WINDOW: &str = "24h";
+const COMPLETION_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// Backend responses past this many bytes fail their request
+const MAX_RESPONSE_BYTES: usize = 32 * 1024 * 1024;
+
+/// A running batch saves its request counts after this many requests finish
+const PROGRESS_SAVE_INTERVAL: u64 = 100;
+
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+const ID_BYTES: usize = 12;
+
+/// An uploaded input file, or a batch's output or error file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileObject {
+    id: String,
+    object: String,
+    bytes: u64,
+    created_at: u64,
+    filename: String,
+    purpose: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchStatus {
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Expired,
+    Cancelling,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct RequestCounts {
+    total: u64,
+    completed: u64,
+    failed: u64,
+}
+
+/// A batch as OpenAI's Batch API describes it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BatchObject {
+    id: String,
+    object: String,
+    endpoint: String,
+    errors: Option<Value>,
+    input_file_id: String,
+    completion_window: String,
+    status: BatchStatus,
+    output_file_id: Option<String>,
+    error_file_id: Option<String>,
+    created_at: u64,
+    in_progress_at: Option<u64>,
+    expires_at: u64,
+    finalizing_at: Option<u64>,
+    completed_at: Option<u64>,
+    failed_at: Option<u64>,
+    expired_at: Option<u64>,
+    cancelling_at: Option<u64>,
+    cancelled_at: Option<u64>,
+    request_counts: RequestCounts,
+    metadata: Option<Value>,
+}
+
+/// A file or batch as kept on disk, with the key it belongs to
+#[derive(Serialize, Deserialize)]
+struct Stored<T> {
+    owner: String,
+    #[serde(flatten)]
+    object: T,
+}
+
+#[derive(Deserialize)]
+struct NewBatch {
+    input_file_id: String,
+    endpoint: String,
+    completion_window: String,
+    #[serde(default)]
+    metadata: Option<Map<String, Value>>,
+}
+
+/// One line of an input file
+struct BatchRequest {
+    custom_id: String,
+    body: Bytes,
+}
+
+/// Creates the directories `--batch-dir` keeps files and batches in
+pub(crate) fn create_batch_dir(dir: &FsPath) -> anyhow::Result<()> {
+    for subdir in ["files", "batches"] {
+        let path = dir.join(subdir);
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create the batch directory {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Uploaded files, batch records, and the output of running batches, kept
+/// as files under `--batch-dir` so batches survive restarts
+pub(crate) struct BatchStore {
+    dir: PathBuf,
+    concurrency: usize,
+    max_retries: u32,
+    /// Held while a batch record is read, changed, and written back
+    records: Mutex<()>,
+    /// Wakes the batches running now when they are cancelled
+    running: DashMap<String, Arc<Notify>>,
+}
+
+impl BatchStore {
+    /// `None` without `--batch-dir`
+    pub(crate) fn open(config: &Config) -> Option<Self> {
+        let dir = config.batch_dir.clone()?;
+        if let Err(create_error) = create_batch_dir(&dir) {
+            error!("{:#}; the Batch API is unavailable", create_error);
+            return None;
+        }
+        Some(Self {
+            dir,
+            concurrency: config.batch_concurrency.max(1),
+            max_retries: config.batch_max_retries,
+            records: Mutex::default(),
+            running: DashMap::new(),
+        })
+    }
+
+    fn files_dir(&self) -> PathBuf {
+        self.dir.join("files")
+    }
+
+    fn batches_dir(&self) -> PathBuf {
+        self.dir.join("batches")
+    }
+
+    fn file_record_path(&self, id: &str) -> PathBuf {
+        self.files_dir().join(format!("{}.json", id))
+    }
+
+    fn file_data_path(&self, id: &str) -> PathBuf {
+        self.files_dir().join(format!("{}.data", id))
+    }
+
+    fn batch_path(&self, id: &str) -> PathBuf {
+        self.batches_dir().join(format!("{}.json", id))
+    }
+
+    /// Where a running batch appends its `output` or `error` lines
+    fn progress_path(&self, id: &str, kind: &str) -> PathBuf {
+        self.batches_dir().join(format!("{}.{}.jsonl", id, kind))
+    }
+
+    fn add_file(
+        &self,
+        owner: &str,
+        filename: &str,
+        purpose: &str,
+        contents: &[u8],
+    ) -> io::Result<FileObject> {
+        let file = FileObject {
+            id: format!("file-{}", random_hex::<ID_BYTES>()),
+            object: "file".to_string(),
+            bytes: contents.len() as u64,
+            created_at: unix_now(),
+            filename: filename.to_string(),
+            purpose: purpose.to_string(),
+        };
+        write_atomically(&self.file_data_path(&file.id), contents)?;
+        self.save_file(owner, &file)?;
+        Ok(file)
+    }
+
+    fn save_file(&self, owner: &str, file: &FileObject) -> io::Result<()> {
+        let stored = Stored {
+            owner: owner.to_string(),
+            object: file.clone(),
+        };
+        write_json(&self.file_record_path(&file.id), &stored)
+    }
+
+    /// A file of `owner`'s, or `None` for any other
+    fn file(&self, owner: &str, id: &str) -> Option<FileObject> {
+        if !is_valid_id(id) {
+            return None;
+        }
+        let stored: Stored<FileObject> = load(&self.file_record_path(id))?;
+        (stored.owner == owner).then_some(stored.object)
+    }
+
+    fn remove_file(&self, id: &str) -> io::Result<()> {
+        fs::remove_file(self.file_record_path(id))?;
+        match fs::remove_file(self.file_data_path(id)) {
+            Err(remove_error) if remove_error.kind() != io::ErrorKind::NotFound => {
+                Err(remove_error)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn batch(&self, id: &str) -> Option<Stored<BatchObject>> {
+        if !is_valid_id(id) {
+            return None;
+        }
+        load(&self.batch_path(id))
+    }
+
+    /// A batch of `owner`'s, or a 404 for any other
+    fn owned_batch(&self, owner: &str, id: &str) -> Result<BatchObject, ProxyError> {
+        self.batch(id)
+            .filter(|stored| stored.owner == owner)
+            .map(|stored| stored.object)
+            .ok_or_else(|| no_such("Batch", id))
+    }
+
+    fn save_batch(&self, batch: &Stored<BatchObject>) -> io::Result<()> {
+        write_json(&self.batch_path(&batch.object.id), batch)
+    }
+
+    /// Changes a batch record, returning it as changed
+    fn update_batch(
+        &self,
+        id: &str,
+        change: impl FnOnce(&mut BatchObject),
+    ) -> io::Result<BatchObject> {
+        let _records = self
+            .records
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut stored = self.batch(id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("batch {} has no record", id),
+            )
+        })?;
+        change(&mut stored.object);
+        self.save_batch(&stored)?;
+        Ok(stored.object)
+    }
+
+    /// Turns a finished batch's `output` or `error` lines into a file its
+    /// owner can download, or `None` when there were none
+    fn publish(&self, owner: &str, batch_id: &str, kind: &str) -> io::Result<Option<String>> {
+        let progress = self.progress_path(batch_id, kind);
+        let bytes = match fs::metadata(&progress) {
+            Ok(metadata) => metadata.len(),
+            Err(metadata_error) if metadata_error.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(metadata_error) => return Err(metadata_error),
+        };
+        if bytes == 0 {
+            fs::remove_file(&progress)?;
+            return Ok(None);
+        }
+        let file = FileObject {
+            id: format!("file-{}", random_hex::<ID_BYTES>()),
+            object: "file".to_string(),
+            bytes,
+            created_at: unix_now(),
+            filename: format!("{}_{}.jsonl", batch_id, kind),
+            purpose: "batch_output".to_string(),
+        };
+        fs::rename(&progress, self.file_data_path(&file.id))?;
+        self.save_file(owner, &file)?;
+        Ok(Some(file.id))
+    }
+}
+
+/// The records in `dir`, newest first
+fn records<T: DeserializeOwned>(dir: &FsPath) -> Vec<Stored<T>> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .filter_map(|path| load(&path))
+        .collect()
+}
+
+/// `POST /v1/files`: a multipart upload of a batch input file
+pub(crate) async fn upload_file(
+    State(state): State<Arc<ProxyState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let store = batch_store(&state)?;
+    let boundary = form_boundary(&headers).ok_or_else(|| {
+        bad_request(OpenAIError::invalid_request_error(
+            "Files must be uploaded as multipart/form-data.",
+        ))
+    })?;
+    let parts = parse_form(&body, &boundary).map_err(|reason| {
+        bad_request(OpenAIError::invalid_request_error(format!(
+            "We could not parse the multipart body of your request: {}.",
+            reason
+        )))
+    })?;
+    let field = |name: &str| parts.iter().find(|part| part.name == name);
+
+    let purpose = field("purpose")
+        .and_then(|part| part.text())
+        .ok_or_else(|| bad_request(missing_param("purpose")))?;
+    if purpose != "batch" {
+        return Err(bad_request(
+            OpenAIError::invalid_request_error("Only files with purpose 'batch' can be uploaded.")
+                .with_param("purpose"),
+        ));
+    }
+    let file = field("file")
+        .filter(|part| part.filename.is_some())
+        .ok_or_else(|| bad_request(missing_param("file")))?;
+    let filename = file.filename.as_deref().unwrap_or_default();
+    let file = store
+        .add_file(&owner, filename, purpose, &file.value)
+        .map_err(storage_error)?;
+    Ok(Json(wire::to_value(&file)))
+}
+
+pub(crate) async fn list_files(
+    State(state): State<Arc<ProxyState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let store = batch_store(&state)?;
+    let mut files: Vec<FileObject> = records(&store.files_dir())
+        .into_iter()
+        .filter(|stored: &Stored<FileObject>| stored.owner == owner)
+        .map(|stored| stored.object)
+        .collect();
+    files.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(Json(
+        json!({"object": "list", "data": files, "has_more": false}),
+    ))
+}
+
+pub(crate) async fn get_file(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let file = batch_store(&state)?
+        .file(&owner, &id)
+        .ok_or_else(|| no_such("File", &id))?;
+    Ok(Json(wire::to_value(&file)))
+}
+
+pub(crate) async fn file_content(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let store = batch_store(&state)?;
+    store
+        .file(&owner, &id)
+        .ok_or_else(|| no_such("File", &id))?;
+    let contents = fs::read(store.file_data_path(&id)).map_err(storage_error)?;
+    Ok(([(header::CONTENT_TYPE, "application/jsonl")], contents).into_response())
+}
+
+pub(crate) async fn delete_file(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let store = batch_store(&state)?;
+    store
+        .file(&owner, &id)
+        .ok_or_else(|| no_such("File", &id))?;
+    store.remove_file(&id).map_err(storage_error)?;
+    Ok(Json(json!({"id": id, "object": "file", "deleted": true})))
+}
+
+/// `POST /v1/batches`: checks every line of the input file, then runs the
+/// batch in the background. A file with invalid lines fails the batch, with
+/// the lines at fault in its `errors`.
+pub(crate) async fn create_batch(
+    State(state): State<Arc<ProxyState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let store = batch_store(&state)?;
+    let new_batch: NewBatch = serde_json::from_slice(&body).map_err(|parse_error| {
+        bad_request(OpenAIError::invalid_request_error(format!(
+            "We could not parse the JSON body of your request: {}.",
+            parse_error
+        )))
+    })?;
+    let Some(endpoint) = BATCH_ENDPOINTS
+        .into_iter()
+        .find(|endpoint| *endpoint == new_batch.endpoint)
+    else {
+        return Err(bad_request(
+            OpenAIError::invalid_request_error(format!(
+                "'endpoint' must be one of {}.",
+                BATCH_ENDPOINTS.join(", ")
+            ))
+            .with_param("endpoint"),
+        ));
+    };
+    if new_batch.completion_window != COMPLETION_WINDOW {
+        return Err(bad_request(
+            OpenAIError::invalid_request_error("'completion_window' must be '24h'.")
+                .with_param("completion_window"),
+        ));
+    }
+    let input = store
+        .file(&owner, &new_batch.input_file_id)
+        .filter(|file| file.purpose == "batch")
+        .ok_or_else(|| no_such("File", &new_batch.input_file_id))?;
+    let contents = fs::read(store.file_data_path(&input.id)).map_err(storage_error)?;
+
+    let created_at = unix_now();
+    let mut batch = BatchObject {
+        id: format!("batch_{}", random_hex::<ID_BYTES>()),
+        object: "batch".to_string(),
+        endpoint: endpoint.to_string(),
+        errors: None,
+        input_file_id: input.id,
+        completion_window: COMPLETION_WINDOW.to_string(),
+        status: BatchStatus::InProgress,
+        output_file_id: None,
+        error_file_id: None,
+        created_at,
+        in_progress_at: Some(created_at),
+        expires_at: created_at + COMPLETION_WINDOW_SECS,
+        finalizing_at: None,
+        completed_at: None,
+        failed_at: None,
+        expired_at: None,
+        cancelling_at: None,
+        cancelled_at: None,
+        request_counts: RequestCounts::default(),
+        metadata: new_batch.metadata.map(Value::Object),
+    };
+    match parse_requests(&contents, endpoint) {
+        Ok(requests) => batch.request_counts.total = requests.len() as u64,
+        Err(errors) => {
+            batch.status = BatchStatus::Failed;
+            batch.in_progress_at = None;
+            batch.failed_at = Some(created_at);
+            batch.errors = Some(json!({"object": "list", "data": errors}));
+        }
+    }
+    let stored = Stored {
+        owner,
+        object: batch,
+    };
+    store.save_batch(&stored).map_err(storage_error)?;
+    if stored.object.status == BatchStatus::InProgress {
+        start_batch(&state, stored.object.id.clone(), credentials(&headers));
+    }
+    Ok(Json(wire::to_value(&stored.object)))
+}
+
+pub(crate) async fn list_batches(
+    State(state): State<Arc<ProxyState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let store = batch_store(&state)?;
+    let mut batches: Vec<BatchObject> = records(&store.batches_dir())
+        .into_iter()
+        .filter(|stored: &Stored<BatchObject>| stored.owner == owner)
+        .map(|stored| stored.object)
+        .collect();
+    batches.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(Json(
+        json!({"object": "list", "data": batches, "has_more": false}),
+    ))
+}
+
+pub(crate) async fn get_batch(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let batch = batch_store(&state)?.owned_batch(&owner, &id)?;
+    Ok(Json(wire::to_value(&batch)))
+}
+
+/// Stops sending a batch's requests. It is `cancelling` until its runner
+/// stops, then `cancelled`, with the results so far in its files.
+pub(crate) async fn cancel_batch(
+    State(state): State<Arc<ProxyState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProxyError> {
+    let owner = state.key_owner(&headers)?;
+    let store = batch_store(&state)?;
+    store.owned_batch(&owner, &id)?;
+    let batch = store
+        .update_batch(&id, |batch| {
+            if batch.status == BatchStatus::InProgress {
+                batch.status = BatchStatus::Cancelling;
+                batch.cancelling_at = Some(unix_now());
+            }
+        })
+        .map_err(storage_error)?;
+    match batch.status {
+        BatchStatus::Cancelling => {
+            if let Some(cancel) = store.running.get(&id) {
+                cancel.notify_one();
+            }
+        }
+        BatchStatus::Cancelled => {}
+        status => {
+            return Err(bad_request(OpenAIError::invalid_request_error(format!(
+                "Batch {} is {} and cannot be cancelled.",
+                id,
+                wire::to_value(&status).as_str().unwrap_or_default()
+            ))));
+        }
+    }
+    Ok(Json(wire::to_value(&batch)))
+}
+
+/// Picks up the batches a previous run left unfinished. Client keys are never
+/// written to disk, so only batches created with the proxy's own API key can
+/// go on; the others fail.
+pub(crate) fn resume_batches(state: &Arc<ProxyState>) {
+    let Some(store) = state.batches() else {
+        return;
+    };
+    if tokio::runtime::Handle::try_current().is_err() {
+        warn!("Resuming batches needs a Tokio runtime");
+        return;
+    }
+    let keyless_owner = state.key_owner(&HeaderMap::new()).ok();
+    let unfinished = records::<BatchObject>(&store.batches_dir())
+        .into_iter()
+        .filter(|stored| {
+            matches!(
+                stored.object.status,
+                BatchStatus::InProgress | BatchStatus::Finalizing | BatchStatus::Cancelling
+            ) && !store.running.contains_key(&stored.object.id)
+        });
+    for stored in unfinished {
+        let id = stored.object.id;
+        if keyless_owner.as_ref() == Some(&stored.owner) {
+            info!("Resuming batch {}", id);
+            start_batch(state, id, HeaderMap::new());
+            continue;
+        }
+        let failed = store.update_batch(&id, |batch| {
+            fail(
+                batch,
+                "batch_interrupted",
+                "The proxy restarted while the batch ran, without the key that created it.",
+            )
+        });
+        if let Err(update_error) = failed {
+            error!("Failed to update batch {}: {}", id, update_error);
+        }
+    }
+}
+
+/// Runs a batch in the background, unless it already is
+fn start_batch(state: &Arc<ProxyState>, id: String, headers: HeaderMap) {
+    let Some(store) = state.batches().map(Arc::clone) else {
+        return;
+    };
+    let cancel = Arc::new(Notify::new());
+    match store.running.entry(id.clone()) {
+        Entry::Occupied(_) => return,
+        Entry::Vacant(entry) => {
+            entry.insert(Arc::clone(&cancel));
+        }
+    }
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        if let Err(run_error) = run_batch(&state, &store, &id, &headers, &cancel).await {
+            error!("Batch {} failed: {:#}", id, run_error);
+            let failed = store.update_batch(&id, |batch| {
+                fail(
+                    batch,
+                    "batch_failed",
+                    "The proxy could not run the batch; its log has the details.",
+                )
+            });
+            if let Err(update_error) = failed {
+                error!("Failed to update batch {}: {}", id, update_error);
+            }
+        }
+        store.running.remove(&id);
+    });
+}
+
+/// Sends a batch's unfinished requests, appending each result to its output
+/// or error lines, until all are done or the batch is cancelled or expires,
+/// then publishes the lines as files
+async fn run_batch(
+    state: &ProxyState,
+    store: &BatchStore,
+    id: &str,
+    headers: &HeaderMap,
+    cancel: &Notify,
+) -> anyhow::Result<()> {
+    let stored = store.batch(id).context("The batch record is gone")?;
+    let batch = stored.object;
+    let endpoint = BATCH_ENDPOINTS
+        .into_iter()
+        .find(|endpoint| *endpoint == batch.endpoint)
+        .context("The batch's endpoint is not supported")?;
+    let output_path = store.progress_path(id, "output");
+    let error_path = store.progress_path(id, "error");
+    let mut finished = finished_custom_ids(&output_path)?;
+    let failed = finished_custom_ids(&error_path)?;
+    let mut counts = RequestCounts {
+        total: batch.request_counts.total,
+        completed: finished.len() as u64,
+        failed: failed.len() as u64,
+    };
+    finished.extend(failed);
+
+    let mut outcome = match batch.status {
+        BatchStatus::Cancelling => BatchStatus::Cancelled,
+        _ => BatchStatus::Completed,
+    };
+    if batch.status == BatchStatus::InProgress {
+        let contents = fs::read(store.file_data_path(&batch.input_file_id))
+            .context("Failed to read the batch's input file")?;
+        let requests = parse_requests(&contents, endpoint)
+            .map_err(|_| anyhow::anyhow!("The batch's input file no longer validates"))?;
+        let mut output = open_append(&output_path)?;
+        let mut errors = open_append(&error_path)?;
+        let pending = requests
+            .into_iter()
+            .filter(|request| !finished.contains(&request.custom_id));
+        let mut results = futures::stream::iter(pending)
+            .map(|request| send_request(state, headers, endpoint, request, store.max_retries))
+            .buffer_unordered(store.concurrency);
+        let expiry = tokio::time::sleep(Duration::from_secs(
+            batch.expires_at.saturating_sub(unix_now()),
+        ));
+        tokio::pin!(expiry);
+
+        let mut unsaved = 0;
+        loop {
+            let (line, succeeded) = tokio::select! {
+                result = results.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                () = cancel.notified() => {
+                    outcome = BatchStatus::Cancelled;
+                    break;
+                }
+                () = &mut expiry => {
+                    outcome = BatchStatus::Expired;
+                    break;
+                }
+            };
+            if succeeded {
+                writeln!(output, "{}", line)?;
+                counts.completed += 1;
+            } else {
+                writeln!(errors, "{}", line)?;
+                counts.failed += 1;
+            }
+            unsaved += 1;
+            if unsaved == PROGRESS_SAVE_INTERVAL {
+                store.update_batch(id, |batch| batch.request_counts = counts)?;
+                unsaved = 0;
+            }
+        }
+    }
+
+    store.update_batch(id, |batch| {
+        if batch.status == BatchStatus::Cancelling {
+            outcome = BatchStatus::Cancelled;
+        }
+        batch.status = BatchStatus::Finalizing;
+        batch.finalizing_at = Some(unix_now());
+        batch.request_counts = counts;
+    })?;
+    let output_file_id = store.publish(&stored.owner, id, "output")?;
+    let error_file_id = store.publish(&stored.owner, id, "error")?;
+    store.update_batch(id, |batch| {
+        let now = Some(unix_now());
+        batch.status = outcome;
+        batch.output_file_id = output_file_id;
+        batch.error_file_id = error_file_id;
+        match outcome {
+            BatchStatus::Cancelled => batch.cancelled_at = now,
+            BatchStatus::Expired => batch.expired_at = now,
+            _ => batch.completed_at = now,
+        }
+    })?;
+    info!(
+        "Batch {} finished: {} requests completed, {} failed",
+        id, counts.completed, counts.failed
+    );
+    Ok(())
+}
+
+/// Sends one request, retrying 429s and 5xx with backoff, and returns its
+/// output line and whether it succeeded
+async fn send_request(
+    state: &ProxyState,
+    headers: &HeaderMap,
+    endpoint: &'static str,
+    request: BatchRequest,
+    max_retries: u32,
+) -> (Value, bool) {
+    let mut attempt = 0;
+    let (status, request_id, body) = loop {
+        let uri = Uri::from_static(endpoint);
+        let result =
+            proxy_inference_request(state, Method::POST, uri, headers, request.body.clone()).await;
+        let (status, request_id, body) = match result {
+            Ok(response) => read_response(response).await,
+            Err((status, Json(error))) => (status, None, wire::to_value(&error)),
+        };
+        let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+        if !retryable || attempt >= max_retries {
+            break (status, request_id, body);
+        }
+        tokio::time::sleep(retry_delay(attempt)).await;
+        attempt += 1;
+    };
+    let line = json!({
+        "id": format!("batch_req_{}", random_hex::<ID_BYTES>()),
+        "custom_id": request.custom_id,
+        "response": {"status_code": status.as_u16(), "request_id": request_id, "body": body},
+        "error": null
+    });
+    (line, status.is_success())
+}
+
+async fn read_response(response: Response) -> (StatusCode, Option<String>, Value) {
+    let status = response.status();
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    match to_bytes(response.into_body(), MAX_RESPONSE_BYTES).await {
+        Ok(body) => {
+            let body = serde_json::from_slice(&body)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+            (status, request_id, body)
+        }
+        Err(read_error) => {
+            let error = OpenAIError::server_error(format!(
+                "The backend's response could not be read: {}",
+                read_error
+            ));
+            (StatusCode::BAD_GATEWAY, request_id, wire::to_value(&error))
+        }
+    }
+}
+
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY
+        .saturating_mul(1 << attempt.min(6))
+        .min(RETRY_MAX_DELAY)
+}
+
+/// The requests of an input file, or what is wrong with its lines
+fn parse_requests(contents: &[u8], endpoint: &str) -> Result<Vec<BatchRequest>, Vec<Value>> {
+    let mut requests = Vec::new();
+    let mut errors = Vec::new();
+    let mut custom_ids = HashSet::new();
+    let lines = contents
+        .split(|byte| *byte == b'\n')
+        .enumerate()
+        .filter(|(_, line)| !line.trim_ascii().is_empty());
+    for (index, line) in lines {
+        match parse_request(line, endpoint, &mut custom_ids) {
+            Ok(request) => requests.push(request),
+            Err((code, message)) if errors.len() < MAX_REPORTED_ERRORS => {
+                errors.push(line_error(code, &message, Some(index + 1)));
+            }
+            Err(_) => {}
+        }
+    }
+    if requests.is_empty() && errors.is_empty() {
+        errors.push(line_error(
+            "empty_file",
+            "The input file has no requests.",
+            None,
+        ));
+    }
+    if requests.len() > MAX_BATCH_REQUESTS {
+        let message = format!("A batch can have at most {} requests.", MAX_BATCH_REQUESTS);
+        errors.push(line_error("too_many_requests", &message, None));
+    }
+    if errors.is_empty() {
+        Ok(requests)
+    } else {
+        Err(errors)
+    }
+}
+
+fn parse_request(
+    line: &[u8],
+    endpoint: &str,
+    custom_ids: &mut HashSet<String>,
+) -> Result<BatchRequest, (&'static str, String)> {
+    let Ok(Value::Object(mut request)) = serde_json::from_slice::<Value>(line) else {
+        return Err((
+            "invalid_json_line",
+            "This line is not a JSON object.".to_string(),
+        ));
+    };
+    let custom_id = match request.get("custom_id").and_then(Value::as_str) {
+        Some(custom_id) if !custom_id.is_empty() => custom_id.to_string(),
+        _ => {
+            let message = "'custom_id' must be a non-empty string.".to_string();
+            return Err(("missing_custom_id", message));
+        }
+    };
+    if request.get("method").and_then(Value::as_str) != Some("POST") {
+        return Err(("invalid_method", "'method' must be 'POST'.".to_string()));
+    }
+    if request.get("url").and_then(Value::as_str) != Some(endpoint) {
+        let message = format!("'url' must be the batch's endpoint, {}.", endpoint);
+        return Err(("mismatched_endpoint", message));
+    }
+    let Some(Value::Object(body)) = request.remove("body") else {
+        return Err(("invalid_body", "'body' must be a JSON object.".to_string()));
+    };
+    if body.get("stream") == Some(&Value::Bool(true)) {
+        return Err(("invalid_body", "Batch requests cannot stream.".to_string()));
+    }
+    let body = Bytes::from(Value::Object(body).to_string());
+    validate_request(endpoint, &body)
+        .map_err(|error| ("invalid_body", error.message().to_string()))?;
+    if !custom_ids.insert(custom_id.clone()) {
+        let message = format!("'custom_id' {} is used by an earlier line.", custom_id);
+        return Err(("duplicate_custom_id", message));
+    }
+    Ok(BatchRequest { custom_id, body })
+}
+
+fn line_error(code: &str, message: &str, line: Option<usize>) -> Value {
+    json!({"code": code, "message": message, "param": null, "line": line})
+}
+
+fn fail(batch: &mut BatchObject, code: &str, message: &str) {
+    batch.status = BatchStatus::Failed;
+    batch.failed_at = Some(unix_now());
+    batch.errors = Some(json!({"object": "list", "data": [line_error(code, message, None)]}));
+}
+
+/// The `custom_id`s of the lines a batch wrote before the proxy stopped. A
+/// line cut off part way is removed, so appending resumes on a fresh line.
+fn finished_custom_ids(path: &FsPath) -> io::Result<HashSet<String>> {
+    let mut contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(read_error) if read_error.kind() == io::ErrorKind::NotFound => {
+            return Ok(HashSet::new())
+        }
+        Err(read_error) => return Err(read_error),
+    };
+    let complete = contents
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |end| end + 1);
+    if complete < contents.len() {
+        contents.truncate(complete);
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(complete as u64)?;
+    }
+    Ok(contents
+        .split(|byte| *byte == b'\n')
+        .filter_map(|line| serde_json::from_slice::<Value>(line).ok())
+        .filter_map(|line| line["custom_id"].as_str().map(str::to_string))
+        .collect())
+}
+
+/// The client's credentials, for the batch's requests, including the key
+/// headers `--api-key-headers` accepts. They are kept in memory only.
+fn credentials(headers: &HeaderMap) -> HeaderMap {
+    std::iter::once(header::AUTHORIZATION)
+        .chain(KEY_HEADERS)
+        .filter_map(|name| Some((name.clone(), headers.get(&name)?.clone())))
+        .collect()
+}
+
+fn batch_store(state: &ProxyState) -> Result<&Arc<BatchStore>, ProxyError> {
+    state.batches().ok_or_else(|| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(OpenAIError::server_error("Batch storage is unavailable.")),
+        )
+    })
+}
+
+fn storage_error(io_error: io::Error) -> ProxyError {
+    storage::storage_error("Batch", io_error)
+}
+
+fn bad_request(error: OpenAIError) -> ProxyError {
+    (StatusCode::BAD_REQUEST, Json(error))
+}
+
+fn no_such(object: &str, id: &str) -> ProxyError {
+    (
+        StatusCode::NOT_FOUND,
+        Json(
+            OpenAIError::invalid_request_error(format!("No such {} object: {}", object, id))
+                .with_param("id"),
+        ),
+    )
+}
+
+/// IDs are file names, so only the characters the proxy's own IDs use are
+/// accepted
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+fn load<T: DeserializeOwned>(path: &FsPath) -> Option<T> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, Router};
+    use tower::ServiceExt;
+
+    fn batch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("maple-batches-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn app(dir: &FsPath) -> Router {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_mock_backend(true)
+        .with_batch_dir(dir);
+        crate::create_app(config)
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Bytes) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        (
+            status,
+            to_bytes(response.into_body(), usize::MAX).await.unwrap(),
+        )
+    }
+
+    async fn get(app: &Router, uri: &str, api_key: Option<&str>) -> (StatusCode, Bytes) {
+        let mut request = Request::builder().uri(uri);
+        if let Some(api_key) = api_key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", api_key));
+        }
+        send(app, request.body(Body::empty()).unwrap()).await
+    }
+
+    async fn upload(app: &Router, contents: &str) -> String {
+        let body = format!(
+            "--b\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nbatch\r\n\
+             --b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"input.jsonl\"\r\n\
+             Content-Type: application/jsonl\r\n\r\n{}\r\n--b--\r\n",
+            contents
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/v1/files")
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .body(Body::from(body))
+            .unwrap();
+        let (status, file) = send(app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let file: Value = serde_json::from_slice(&file).unwrap();
+        assert_eq!(file["purpose"], "batch");
+        file["id"].as_str().unwrap().to_string()
+    }
+
+    async fn create(app: &Router, input_file_id: &str) -> Value {
+        let body = json!({
+            "input_file_id": input_file_id,
+            "endpoint": "/v1/chat/completions",
+            "completion_window": "24h"
+        });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/v1/batches")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let (status, batch) = send(app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice(&batch).unwrap()
+    }
+
+    fn chat_line(custom_id: &str, url: &str) -> String {
+        json!({
+            "custom_id": custom_id,
+            "method": "POST",
+            "url": url,
+            "body": {"model": "llama3-3-70b", "messages": [{"role": "user", "content": "Hi"}]}
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn batches_run_their_requests_and_serve_the_results() {
+        let dir = batch_dir("run");
+        let app = app(&dir);
+        let lines: Vec<String> = (0..3)
+            .map(|index| chat_line(&format!("request-{}", index), CHAT_COMPLETIONS_PATH))
+            .collect();
+        let file_id = upload(&app, &lines.join("\n")).await;
+
+        let mut batch = create(&app, &file_id).await;
+        assert_eq!(batch["status"], "in_progress");
+        assert_eq!(batch["request_counts"]["total"], 3);
+        let batch_uri = format!("/v1/batches/{}", batch["id"].as_str().unwrap());
+        for _ in 0..100 {
+            if batch["status"] == "completed" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            batch = serde_json::from_slice(&get(&app, &batch_uri, None).await.1).unwrap();
+        }
+        assert_eq!(batch["status"], "completed");
+        assert_eq!(batch["request_counts"]["completed"], 3);
+        assert!(batch["error_file_id"].is_null());
+
+        let output_uri = format!(
+            "/v1/files/{}/content",
+            batch["output_file_id"].as_str().unwrap()
+        );
+        let (status, output) = get(&app, &output_uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let mut custom_ids: Vec<String> = String::from_utf8_lossy(&output)
+            .lines()
+            .map(|line| {
+                let line: Value = serde_json::from_str(line).unwrap();
+                assert_eq!(line["response"]["status_code"], 200);
+                line["custom_id"].as_str().unwrap().to_string()
+            })
+            .collect();
+        custom_ids.sort();
+        assert_eq!(custom_ids, ["request-0", "request-1", "request-2"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_lines_fail_the_batch_and_files_stay_private() {
+        let dir = batch_dir("invalid");
+        let app = app(&dir);
+        let input = [
+            chat_line("a", CHAT_COMPLETIONS_PATH),
+            chat_line("a", CHAT_COMPLETIONS_PATH),
+            chat_line("b", EMBEDDINGS_PATH),
+        ]
+        .join("\n");
+        let file_id = upload(&app, &input).await;
+
+        let batch = create(&app, &file_id).await;
+        assert_eq!(batch["status"], "failed");
+        let errors = &batch["errors"]["data"];
+        assert_eq!(errors[0]["code"], "duplicate_custom_id");
+        assert_eq!(errors[0]["line"], 2);
+        assert_eq!(errors[1]["code"], "mismatched_endpoint");
+        assert_eq!(errors[1]["line"], 3);
+
+        let file_uri = format!("/v1/files/{}", file_id);
+        assert_eq!(get(&app, &file_uri, None).await.0, StatusCode::OK);
+        assert_eq!(
+            get(&app, &file_uri, Some("another-key")).await.0,
+            StatusCode::NOT_FOUND
+        );
+        let batch_uri = format!("/v1/batches/{}", batch["id"].as_str().unwrap());
+        assert_eq!(
+            get(&app, &batch_uri, Some("another-key")).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get(&app, "/v1/files/..%2Fbatches", None).await.0,
+            StatusCode::NOT_FOUND
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
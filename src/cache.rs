@@ -0,0 +1,510 @@
+use crate::{diagnose::sanitize_url, ids::unix_now_ms};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderName, HeaderValue},
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+/// Larger completions are passed through without being cached
+const MAX_CACHED_BODY_BYTES: usize = 1024 * 1024;
+
+/// Hex digits of SHA-256 in the IDs the admin API lists entries under
+const ENTRY_ID_LEN: usize = 16;
+
+/// The caller's API key and the request body with object keys sorted, so
+/// requests that differ only in field order share an entry. Keying on the API
+/// key keeps one caller from reading completions paid for by another.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    api_key: String,
+    request: Vec<u8>,
+}
+
+impl CacheKey {
+    /// Returns `None` for requests that must not be cached: bodies that are
+    /// not JSON objects and streaming requests
+    pub(crate) fn for_chat_completion(api_key: &str, body: &[u8]) -> Option<Self> {
+        let request: Value = serde_json::from_slice(body).ok()?;
+        if !request.is_object() || request.get("stream").and_then(Value::as_bool) == Some(true) {
+            return None;
+        }
+
+        Some(Self {
+            api_key: api_key.to_string(),
+            request: serde_json::to_vec(&sorted(request)).ok()?,
+        })
+    }
+
+    /// The entry's ID in the admin API, which reveals neither the API key nor
+    /// the request
+    pub(crate) fn id(&self) -> String {
+        entry_id(&[self.api_key.as_bytes(), &self.request])
+    }
+
+    /// The entry's name in shared storage: its ID, to find it by, and the
+    /// whole hash, so entries for different callers never collide
+    pub(crate) fn storage_name(&self) -> String {
+        let digest = digest(&[self.api_key.as_bytes(), &self.request]);
+        format!("{}:{}", &digest[..ENTRY_ID_LEN], digest)
+    }
+
+    /// A model list request as answered by one backend
+    pub(crate) fn for_model_list(backend_url: &str, api_key: &str, path_and_query: &str) -> Self {
+        Self {
+            api_key: api_key.to_string(),
+            request: format!("GET {}{}", backend_url, path_and_query).into_bytes(),
+        }
+    }
+}
+
+/// A short hash of `parts` that tells cache and pool entries apart in the
+/// admin API without revealing what they hold
+pub(crate) fn entry_id(parts: &[&[u8]]) -> String {
+    let mut id = digest(parts);
+    id.truncate(ENTRY_ID_LEN);
+    id
+}
+
+fn digest(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// A strong entity tag for a response body, and for the `rewrite` state the
+/// body is transformed with before it reaches the client
+pub(crate) fn etag(body: &[u8], rewrite: &impl Hash) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    rewrite.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CachedResponse {
+    pub(crate) backend_url: String,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Bytes,
+    stored_at: Instant,
+}
+
+impl CachedResponse {
+    pub(crate) fn new(backend_url: String, headers: HeaderMap, body: Bytes) -> Self {
+        Self {
+            backend_url,
+            headers,
+            body,
+            stored_at: Instant::now(),
+        }
+    }
+
+    /// The response as bytes for shared storage: a JSON line with the backend
+    /// URL, when it was stored, and the headers, then the body. Header values
+    /// that are not visible ASCII are left out.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let age = self.stored_at.elapsed();
+        let stored_at_ms = unix_now_ms().saturating_sub(age.as_millis() as u64);
+        let headers: Vec<(&str, &str)> = self
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+            .collect();
+        let mut encoded = json!({
+            "backend_url": self.backend_url,
+            "stored_at_ms": stored_at_ms,
+            "headers": headers,
+        })
+        .to_string()
+        .into_bytes();
+        encoded.push(b'\n');
+        encoded.extend_from_slice(&self.body);
+        encoded
+    }
+
+    /// Reads a response written by [`CachedResponse::encode`], keeping its age
+    pub(crate) fn decode(encoded: &[u8]) -> Option<Self> {
+        let split = encoded.iter().position(|byte| *byte == b'\n')?;
+        let meta: Value = serde_json::from_slice(&encoded[..split]).ok()?;
+        let mut headers = HeaderMap::new();
+        for header in meta["headers"].as_array()? {
+            let name = HeaderName::from_bytes(header[0].as_str()?.as_bytes()).ok()?;
+            headers.append(name, HeaderValue::from_str(header[1].as_str()?).ok()?);
+        }
+
+        let stored_at_ms = meta["stored_at_ms"].as_u64()?;
+        let age = Duration::from_millis(unix_now_ms().saturating_sub(stored_at_ms));
+        let now = Instant::now();
+        Some(Self {
+            backend_url: meta["backend_url"].as_str()?.to_string(),
+            headers,
+            body: Bytes::copy_from_slice(&encoded[split + 1..]),
+            stored_at: now.checked_sub(age).unwrap_or(now),
+        })
+    }
+}
+
+/// Successful responses (non-streaming chat completions or model lists),
+/// reused for identical requests until they expire
+pub(crate) struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: DashMap<CacheKey, CachedResponse>,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            entries: DashMap::new(),
+        }
+    }
+
+    pub(crate) fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub(crate) fn get(&self, key: &CacheKey) -> Option<CachedResponse> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &CacheKey, now: Instant) -> Option<CachedResponse> {
+        if let Some(entry) = self.entries.get(key) {
+            if !self.is_expired(&entry, now) {
+                return Some(entry.clone());
+            }
+        }
+
+        self.entries
+            .remove_if(key, |_, entry| self.is_expired(entry, now));
+        None
+    }
+
+    pub(crate) fn insert(&self, key: CacheKey, response: CachedResponse) {
+        if self.max_entries == 0 || response.body.len() > MAX_CACHED_BODY_BYTES {
+            return;
+        }
+
+        if self.entries.len() >= self.max_entries && !self.entries.contains_key(&key) {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| !self.is_expired(entry, now));
+            self.evict_oldest_if_needed();
+        }
+        self.entries.insert(key, response);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.clear();
+    }
+
+    /// The unexpired entries, newest first, for the admin API
+    pub(crate) fn list(&self) -> Vec<Value> {
+        let now = Instant::now();
+        let mut entries: Vec<(Duration, Value)> = self
+            .entries
+            .iter()
+            .filter(|entry| !self.is_expired(entry.value(), now))
+            .map(|entry| {
+                let (key, response) = (entry.key(), entry.value());
+                let age = now.saturating_duration_since(response.stored_at);
+                let model = serde_json::from_slice::<Value>(&key.request)
+                    .ok()
+                    .and_then(|request| request["model"].as_str().map(str::to_string));
+                let listed = json!({
+                    "id": key.id(),
+                    "api_key_id": entry_id(&[key.api_key.as_bytes()]),
+                    "model": model,
+                    "backend_url": sanitize_url(&response.backend_url),
+                    "size_bytes": response.body.len(),
+                    "age_secs": age.as_secs(),
+                    "expires_in_secs": self.ttl.saturating_sub(age).as_secs(),
+                });
+                (age, listed)
+            })
+            .collect();
+        entries.sort_by_key(|(age, _)| *age);
+        entries.into_iter().map(|(_, listed)| listed).collect()
+    }
+
+    /// Evicts the entry with `id`, or every entry without one. Returns how
+    /// many were evicted.
+    pub(crate) fn evict(&self, id: Option<&str>) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|key, _| id.is_some_and(|id| key.id() != id));
+        before.saturating_sub(self.entries.len())
+    }
+
+    fn is_expired(&self, entry: &CachedResponse, now: Instant) -> bool {
+        now.saturating_duration_since(entry.stored_at) >= self.ttl
+    }
+
+    fn evict_oldest_if_needed(&self) {
+        while self.entries.len() >= self.max_entries {
+            let oldest_key = self
+                .entries
+                .iter()
+                .min_by_key(|entry| entry.value().stored_at)
+                .map(|entry| entry.key().clone());
+
+            let Some(oldest_key) = oldest_key else {
+                break;
+            };
+
+            self.entries.remove(&oldest_key);
+        }
+    }
+}
+
+/// Backend fetches in progress, so concurrent identical requests share one
+/// round trip instead of each missing the cache at once
+pub(crate) struct InFlightFetches<K> {
+    fetches: DashMap<K, broadcast::Sender<CachedResponse>>,
+}
+
+impl<K: Eq + Hash + Clone> Default for InFlightFetches<K> {
+    fn default() -> Self {
+        Self {
+            fetches: DashMap::new(),
+        }
+    }
+}
+
+/// A request's part in a fetch
+pub(crate) enum Fetch<'a, K: Eq + Hash + Clone> {
+    /// No identical fetch is running: make it, then share the result
+    Leader(FetchGuard<'a, K>),
+    /// Another request is fetching. The channel closes without a response if
+    /// that fetch fails or is abandoned.
+    Follower(broadcast::Receiver<CachedResponse>),
+}
+
+impl<K: Eq + Hash + Clone> InFlightFetches<K> {
+    pub(crate) fn join(&self, key: K) -> Fetch<'_, K> {
+        match self.fetches.entry(key.clone()) {
+            Entry::Occupied(entry) => Fetch::Follower(entry.get().subscribe()),
+            Entry::Vacant(entry) => {
+                let (sender, _) = broadcast::channel(1);
+                entry.insert(sender.clone());
+                Fetch::Leader(FetchGuard {
+                    fetches: self,
+                    key,
+                    sender,
+                })
+            }
+        }
+    }
+}
+
+/// Ends a fetch when dropped; requests arriving afterwards start a new one
+pub(crate) struct FetchGuard<'a, K: Eq + Hash + Clone> {
+    fetches: &'a InFlightFetches<K>,
+    key: K,
+    sender: broadcast::Sender<CachedResponse>,
+}
+
+impl<K: Eq + Hash + Clone> FetchGuard<'_, K> {
+    /// Hands the fetched response to every request waiting on it
+    pub(crate) fn complete(self, response: CachedResponse) {
+        let sender = self.sender.clone();
+        drop(self);
+        let _ = sender.send(response);
+    }
+}
+
+impl<K: Eq + Hash + Clone> Drop for FetchGuard<'_, K> {
+    fn drop(&mut self) {
+        self.fetches
+            .fetches
+            .remove_if(&self.key, |_, sender| sender.same_channel(&self.sender));
+    }
+}
+
+fn sorted(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut fields: Vec<_> = object.into_iter().collect();
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| (name, sorted(value)))
+                    .collect::<Map<_, _>>(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(sorted).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(api_key: &str, body: &str) -> CacheKey {
+        CacheKey::for_chat_completion(api_key, body.as_bytes()).unwrap()
+    }
+
+    fn response(body: &'static str) -> CachedResponse {
+        CachedResponse::new(
+            "https://backend".to_string(),
+            HeaderMap::new(),
+            Bytes::from_static(body.as_bytes()),
+        )
+    }
+
+    #[test]
+    fn keys_ignore_field_order_but_not_api_key_or_values() {
+        let request = r#"{"model":"llama3-3-70b","messages":[{"role":"user","content":"Hi"}],"temperature":0}"#;
+        let reordered = r#"{"temperature":0,"messages":[{"content":"Hi","role":"user"}],"model":"llama3-3-70b"}"#;
+
+        assert_eq!(key("key-a", request), key("key-a", reordered));
+        assert_ne!(key("key-a", request), key("key-b", request));
+        assert_ne!(
+            key("key-a", request),
+            key(
+                "key-a",
+                &request.replace("\"temperature\":0", "\"temperature\":1")
+            )
+        );
+    }
+
+    #[test]
+    fn streaming_and_non_object_requests_are_not_cached() {
+        assert!(CacheKey::for_chat_completion("key", br#"{"stream":true}"#).is_none());
+        assert!(CacheKey::for_chat_completion("key", b"[]").is_none());
+        assert!(CacheKey::for_chat_completion("key", b"not json").is_none());
+        assert!(CacheKey::for_chat_completion("key", br#"{"stream":false}"#).is_some());
+    }
+
+    #[test]
+    fn model_list_keys_are_per_backend_and_api_key() {
+        let key =
+            |backend_url, api_key| CacheKey::for_model_list(backend_url, api_key, "/v1/models");
+
+        assert_eq!(key("https://a", "key"), key("https://a", "key"));
+        assert_ne!(key("https://a", "key"), key("https://b", "key"));
+        assert_ne!(key("https://a", "key"), key("https://a", "other-key"));
+        assert_ne!(
+            key("https://a", "key"),
+            CacheKey::for_model_list("https://a", "key", "/v1/models?provider=tinfoil")
+        );
+    }
+
+    #[test]
+    fn etags_identify_bodies() {
+        assert_eq!(etag(b"models", &()), etag(b"models", &()));
+        assert_ne!(etag(b"models", &()), etag(b"other models", &()));
+        assert_ne!(etag(b"models", &1), etag(b"models", &2));
+        assert!(etag(b"models", &()).starts_with('"') && etag(b"models", &()).ends_with('"'));
+    }
+
+    #[test]
+    fn responses_are_encoded_for_shared_storage() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let stored = CachedResponse {
+            stored_at: Instant::now() - Duration::from_secs(30),
+            ..CachedResponse::new("https://a".to_string(), headers, Bytes::from("{\n}"))
+        };
+
+        let decoded = CachedResponse::decode(&stored.encode()).unwrap();
+        assert_eq!(decoded.backend_url, "https://a");
+        assert_eq!(decoded.headers["content-type"], "application/json");
+        assert_eq!(decoded.body, "{\n}");
+        assert_eq!(decoded.stored_at.elapsed().as_secs(), 30);
+        assert!(CachedResponse::decode(b"no metadata").is_none());
+
+        let key = key("key", "{}");
+        assert!(key.storage_name().starts_with(&format!("{}:", key.id())));
+    }
+
+    #[test]
+    fn entries_expire_after_ttl() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10);
+        let key = key("key", "{}");
+        cache.insert(key.clone(), response("cached"));
+
+        let now = Instant::now();
+        assert_eq!(cache.get_at(&key, now).unwrap().body, "cached");
+        assert!(cache.get_at(&key, now + Duration::from_secs(61)).is_none());
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn evicts_oldest_entry_at_capacity() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 2);
+        let now = Instant::now();
+        for index in 0..3 {
+            let response = CachedResponse {
+                stored_at: now + Duration::from_millis(index),
+                ..response("cached")
+            };
+            cache.insert(key("key", &format!("{{\"n\":{}}}", index)), response);
+        }
+
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get(&key("key", "{\"n\":0}")).is_none());
+        assert!(cache.get(&key("key", "{\"n\":2}")).is_some());
+    }
+
+    #[test]
+    fn entries_are_listed_and_evicted_by_id() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10);
+        cache.insert(key("secret-key", r#"{"model":"m"}"#), response("cached"));
+        cache.insert(key("secret-key", r#"{"model":"n"}"#), response("other"));
+
+        let listed = cache.list();
+        assert_eq!(listed.len(), 2);
+        let entry = listed.iter().find(|entry| entry["model"] == "m").unwrap();
+        assert_eq!(entry["size_bytes"], 6);
+        assert_eq!(entry["age_secs"], 0);
+        assert!(!entry.to_string().contains("secret-key"));
+
+        let id = entry["id"].as_str().unwrap();
+        assert_eq!(id, key("secret-key", r#"{"model":"m"}"#).id());
+        assert_eq!(cache.evict(Some(id)), 1);
+        assert_eq!(cache.evict(Some(id)), 0);
+        assert_eq!(cache.evict(None), 1);
+        assert!(cache.list().is_empty());
+    }
+
+    #[test]
+    fn concurrent_fetches_share_the_leaders_response() {
+        let fetches = InFlightFetches::default();
+        let Fetch::Leader(leader) = fetches.join("models") else {
+            panic!("the first fetch leads");
+        };
+        let Fetch::Follower(mut follower) = fetches.join("models") else {
+            panic!("an identical fetch follows");
+        };
+        assert!(matches!(fetches.join("other"), Fetch::Leader(_)));
+
+        leader.complete(response("list"));
+        assert_eq!(follower.try_recv().unwrap().body, "list");
+
+        let Fetch::Leader(abandoned) = fetches.join("models") else {
+            panic!("a completed fetch is not joined");
+        };
+        let Fetch::Follower(mut follower) = fetches.join("models") else {
+            panic!("an identical fetch follows");
+        };
+        drop(abandoned);
+        assert_eq!(
+            follower.try_recv().unwrap_err(),
+            broadcast::error::TryRecvError::Closed
+        );
+        assert!(matches!(fetches.join("models"), Fetch::Leader(_)));
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{
+    config::OpenAIError,
+    proxy::{CHAT_COMPLETIONS_PATH, EMBEDDINGS_PATH},
+};
+use axum::{body::Bytes, http::StatusCode};
+use dashmap::DashMap;
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+/// How long a backend is remembered as lacking a feature before requests that
+/// need it are tried again, which picks up backend upgrades
+const UNSUPPORTED_FEATURE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Phrases backends use when rejecting a parameter they do not know
+const REJECTION_PHRASES: &[&str] = &[
+    "not support",
+    "unsupported",
+    "unknown",
+    "unrecognized",
+    "not permitted",
+    "not allowed",
+    "not implemented",
+];
+
+/// A backend feature that requests may rely on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Feature {
+    /// `stream_options`, which asks for usage at the end of streams
+    StreamingUsage,
+    /// `tools` on chat completions
+    Tools,
+    Embeddings,
+    /// `continue_final_message`, which vLLM uses to carry on from a final
+    /// assistant message; only resumed streams send it
+    ContinueFinalMessage,
+}
+
+impl Feature {
+    pub(crate) fn description(self) -> &'static str {
+        match self {
+            Self::StreamingUsage => "stream_options",
+            Self::Tools => "tool calling",
+            Self::Embeddings => "embeddings",
+            Self::ContinueFinalMessage => "continue_final_message",
+        }
+    }
+
+    /// The request parameter that needs the feature, if any
+    fn param(self) -> Option<&'static str> {
+        match self {
+            Self::StreamingUsage => Some("stream_options"),
+            Self::Tools => Some("tools"),
+            Self::Embeddings => None,
+            Self::ContinueFinalMessage => Some("continue_final_message"),
+        }
+    }
+
+    /// Requests can do without streaming usage, which the proxy estimates
+    /// instead; the other features cannot be worked around
+    pub(crate) fn is_required(self) -> bool {
+        !matches!(self, Self::StreamingUsage)
+    }
+
+    /// The features a request to `path` relies on
+    pub(crate) fn required_by(path: &str, body: &[u8]) -> Vec<Feature> {
+        if path == EMBEDDINGS_PATH {
+            return vec![Self::Embeddings];
+        }
+        if path != CHAT_COMPLETIONS_PATH {
+            return Vec::new();
+        }
+        let Ok(request) = serde_json::from_slice::<Value>(body) else {
+            return Vec::new();
+        };
+
+        let mut features = Vec::new();
+        if request
+            .get("stream_options")
+            .is_some_and(|options| !options.is_null())
+        {
+            features.push(Self::StreamingUsage);
+        }
+        if request["tools"]
+            .as_array()
+            .is_some_and(|tools| !tools.is_empty())
+        {
+            features.push(Self::Tools);
+        }
+        features
+    }
+
+    /// Whether a backend's error response says it lacks this feature, as
+    /// opposed to rejecting how the client used it
+    fn is_rejected_by(self, status: StatusCode, body: &[u8]) -> bool {
+        let message = serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|error| {
+                error["error"]["message"]
+                    .as_str()
+                    .or_else(|| error["detail"].as_str())
+                    .or_else(|| error["message"].as_str())
+                    .map(str::to_lowercase)
+            })
+            .unwrap_or_else(|| String::from_utf8_lossy(body).to_lowercase());
+
+        match self.param() {
+            Some(param) => {
+                matches!(status.as_u16(), 400 | 422 | 501)
+                    && message.contains(param)
+                    && REJECTION_PHRASES
+                        .iter()
+                        .any(|phrase| message.contains(phrase))
+            }
+            // Unknown embedding models are 404s too, and name the model
+            None => matches!(status.as_u16(), 404 | 405 | 501) && !message.contains("model"),
+        }
+    }
+}
+
+/// Features each backend has been seen to lack, learned from its responses.
+/// Backends are assumed to support everything until they reject a request,
+/// except `continue_final_message`: backends that do not know it may ignore it
+/// and answer from the start, so only those in `--resume-backend-url` are sent
+/// it.
+#[derive(Default)]
+pub(crate) struct BackendCapabilities {
+    unsupported: DashMap<(String, Feature), Instant>,
+    continuing: HashSet<String>,
+}
+
+impl BackendCapabilities {
+    pub(crate) fn new(resume_backend_urls: &[String]) -> Self {
+        Self {
+            continuing: resume_backend_urls.iter().cloned().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Whether `backend_url` is known to continue a final assistant message,
+    /// which resuming a stream relies on
+    pub(crate) fn can_continue(&self, backend_url: &str) -> bool {
+        self.continuing.contains(backend_url)
+            && self.supports(backend_url, Feature::ContinueFinalMessage)
+    }
+
+    fn supports(&self, backend_url: &str, feature: Feature) -> bool {
+        let key = (backend_url.to_string(), feature);
+        let Some(detected_at) = self.unsupported.get(&key).map(|entry| *entry) else {
+            return true;
+        };
+        if detected_at.elapsed() < UNSUPPORTED_FEATURE_TTL {
+            return false;
+        }
+        self.unsupported
+            .remove_if(&key, |_, at| at.elapsed() >= UNSUPPORTED_FEATURE_TTL);
+        true
+    }
+
+    /// The first feature in `features` that requests cannot do without and
+    /// the backend lacks
+    pub(crate) fn missing(&self, backend_url: &str, features: &[Feature]) -> Option<Feature> {
+        features
+            .iter()
+            .copied()
+            .find(|feature| feature.is_required() && !self.supports(backend_url, *feature))
+    }
+
+    /// The request body to send to `backend_url`, without the parameters of
+    /// features it lacks but requests can do without
+    pub(crate) fn adapt_body(
+        &self,
+        backend_url: &str,
+        features: &[Feature],
+        body: &Bytes,
+    ) -> Bytes {
+        let dropped: Vec<&str> = features
+            .iter()
+            .filter(|feature| !feature.is_required() && !self.supports(backend_url, **feature))
+            .filter_map(|feature| feature.param())
+            .collect();
+        if dropped.is_empty() {
+            return body.clone();
+        }
+        let Ok(mut request) = serde_json::from_slice::<Value>(body) else {
+            return body.clone();
+        };
+        let Some(fields) = request.as_object_mut() else {
+            return body.clone();
+        };
+        for param in dropped {
+            fields.shift_remove(param);
+        }
+        serde_json::to_vec(&request)
+            .map(Bytes::from)
+            .unwrap_or_else(|_| body.clone())
+    }
+
+    /// Records which of `features` a backend lacks when its error response
+    /// says so, and returns it
+    pub(crate) fn learn(
+        &self,
+        backend_url: &str,
+        features: &[Feature],
+        status: StatusCode,
+        body: &[u8],
+    ) -> Option<Feature> {
+        let feature = features
+            .iter()
+            .copied()
+            .find(|feature| feature.is_rejected_by(status, body))?;
+        self.unsupported
+            .insert((backend_url.to_string(), feature), Instant::now());
+        Some(feature)
+    }
+}
+
+/// Statuses a backend may use to reject a feature it lacks
+pub(crate) fn may_reject_feature(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 400 | 404 | 405 | 422 | 501)
+}
+
+pub(crate) fn unsupported_feature_error(feature: Feature) -> OpenAIError {
+    let error = OpenAIError::invalid_request_error(format!(
+        "The Maple backend does not support {}.",
+        feature.description()
+    ))
+    .with_code("unsupported_feature");
+    match feature.param() {
+        Some(param) => error.with_param(param),
+        None => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKEND: &str = "https://enclave.example";
+
+    #[test]
+    fn detects_the_features_a_request_needs() {
+        let body =
+            br#"{"model":"m","stream":true,"stream_options":{"include_usage":true},"tools":[{}]}"#;
+        assert_eq!(
+            Feature::required_by(CHAT_COMPLETIONS_PATH, body),
+            [Feature::StreamingUsage, Feature::Tools]
+        );
+        assert!(Feature::required_by(CHAT_COMPLETIONS_PATH, br#"{"tools":[]}"#).is_empty());
+        assert_eq!(
+            Feature::required_by(EMBEDDINGS_PATH, b"{}"),
+            [Feature::Embeddings]
+        );
+    }
+
+    #[test]
+    fn learns_only_from_rejections_of_the_feature() {
+        let capabilities = BackendCapabilities::default();
+        let features = [Feature::StreamingUsage, Feature::Tools];
+        let invalid_tool = br#"{"error":{"message":"tools[0].function.name is required"}}"#;
+        let unsupported = br#"{"error":{"message":"Tools are not supported by this model"}}"#;
+
+        assert_eq!(
+            capabilities.learn(BACKEND, &features, StatusCode::BAD_REQUEST, invalid_tool),
+            None
+        );
+        assert_eq!(capabilities.missing(BACKEND, &features), None);
+        assert_eq!(
+            capabilities.learn(BACKEND, &features, StatusCode::BAD_REQUEST, unsupported),
+            Some(Feature::Tools)
+        );
+        assert_eq!(
+            capabilities.missing(BACKEND, &features),
+            Some(Feature::Tools)
+        );
+        assert_eq!(
+            capabilities.missing("https://other.example", &features),
+            None
+        );
+
+        let unknown_model = br#"{"error":{"message":"The model 'x' does not exist"}}"#;
+        let embeddings = [Feature::Embeddings];
+        assert_eq!(
+            capabilities.learn(BACKEND, &embeddings, StatusCode::NOT_FOUND, unknown_model),
+            None
+        );
+        assert_eq!(
+            capabilities.learn(BACKEND, &embeddings, StatusCode::NOT_FOUND, b"Not Found"),
+            Some(Feature::Embeddings)
+        );
+    }
+
+    #[test]
+    fn continues_only_on_listed_backends_that_have_not_rejected_it() {
+        let capabilities = BackendCapabilities::new(&[BACKEND.to_string()]);
+        assert!(capabilities.can_continue(BACKEND));
+        assert!(!capabilities.can_continue("https://other.example"));
+        assert!(!BackendCapabilities::default().can_continue(BACKEND));
+
+        let rejection = br#"{"error":{"message":"Unknown field: continue_final_message"}}"#;
+        let features = [Feature::ContinueFinalMessage];
+        assert_eq!(
+            capabilities.learn(BACKEND, &features, StatusCode::BAD_REQUEST, rejection),
+            Some(Feature::ContinueFinalMessage)
+        );
+        assert!(!capabilities.can_continue(BACKEND));
+    }
+
+    #[test]
+    fn drops_stream_options_for_backends_without_them() {
+        let capabilities = BackendCapabilities::default();
+        let body = Bytes::from_static(
+            br#"{"model":"m","stream":true,"stream_options":{"include_usage":true}}"#,
+        );
+        let features = Feature::required_by(CHAT_COMPLETIONS_PATH, &body);
+        assert_eq!(capabilities.adapt_body(BACKEND, &features, &body), body);
+
+        let rejection = br#"{"detail":"Unrecognized field: stream_options"}"#;
+        capabilities.learn(
+            BACKEND,
+            &features,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            rejection,
+        );
+        assert_eq!(capabilities.missing(BACKEND, &features), None);
+        assert_eq!(
+            capabilities.adapt_body(BACKEND, &features, &body),
+            Bytes::from_static(br#"{"model":"m","stream":true}"#)
+        );
+    }
+}
@@ -0,0 +1,379 @@
+pub use crate::extension::CacheDirective;
+use crate::extension::EXTENSION_FIELD;
+use axum::http::{header, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// Proxy options for a single request, sent in the body's `"maple"` object
+///
+/// ```
+/// use maple_proxy::client::{CacheDirective, MapleOptions};
+/// use serde_json::json;
+///
+/// let mut request = json!({"model": "llama3-3-70b", "messages": []});
+/// MapleOptions::new()
+///     .cache(CacheDirective::NoStore)
+///     .session_id("checkout-42")
+///     .apply_to(&mut request);
+/// assert_eq!(request["maple"]["cache"], "no-store");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MapleOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<CacheDirective>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    background: bool,
+}
+
+impl MapleOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tries this configured backend URL before the others
+    pub fn backend(mut self, backend_url: impl Into<String>) -> Self {
+        self.backend = Some(backend_url.into());
+        self
+    }
+
+    pub fn cache(mut self, directive: CacheDirective) -> Self {
+        self.cache = Some(directive);
+        self
+    }
+
+    /// Tags the proxy's log lines for the request
+    pub fn session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.session_id = Some(session_id.into());
+        self
+    }
+
+    /// Asks the proxy to report what it would send instead of sending it
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Asks the proxy to run a chat completion in the background and answer
+    /// with a response ID, to fetch the result with [`ProxyClient::response`]
+    pub fn background(mut self, background: bool) -> Self {
+        self.background = background;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sets the `"maple"` object of a JSON request body, or removes it when no
+    /// options are set. Bodies that are not JSON objects are left alone.
+    pub fn apply_to(&self, request: &mut Value) {
+        let Some(fields) = request.as_object_mut() else {
+            return;
+        };
+        if self.is_empty() {
+            fields.shift_remove(EXTENSION_FIELD);
+        } else {
+            let options = serde_json::to_value(self).expect("options serialize to JSON");
+            fields.insert(EXTENSION_FIELD.to_string(), options);
+        }
+    }
+}
+
+/// A `/v1/tokenize` request, counting either raw texts or chat messages
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenizeRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages: Option<Vec<Value>>,
+}
+
+impl TokenizeRequest {
+    pub fn input<I, S>(texts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            model: None,
+            input: Some(texts.into_iter().map(Into::into).collect()),
+            messages: None,
+        }
+    }
+
+    /// Counts messages the way chat completions do, including per-message
+    /// overhead
+    pub fn messages(messages: Vec<Value>) -> Self {
+        Self {
+            model: None,
+            input: None,
+            messages: Some(messages),
+        }
+    }
+
+    /// Echoed back in the response; tokenization does not depend on it
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TokenizeResponse {
+    pub model: Option<String>,
+    pub encoding: String,
+    /// The total across all texts or messages
+    pub count: u64,
+    pub data: Vec<TokenizedText>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TokenizedText {
+    pub index: usize,
+    pub count: u64,
+    pub tokens: Vec<u32>,
+}
+
+/// The response of `/version`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct VersionInfo {
+    pub service: String,
+    pub version: String,
+    /// The latest update check, when the proxy runs them
+    pub update_check: Option<Value>,
+}
+
+/// Why a call through [`ProxyClient`] failed
+#[derive(Debug)]
+pub enum ClientError {
+    /// The proxy could not be reached or the response could not be read
+    Request(reqwest::Error),
+    /// The proxy answered with an error status; `body` is usually an
+    /// OpenAI-style `{"error": {...}}` object
+    Api { status: StatusCode, body: Value },
+    /// The response was not the expected JSON
+    Decode(serde_json::Error),
+}
+
+impl ClientError {
+    /// The `error.message` of an API error, if it has one
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            Self::Api { body, .. } => body["error"]["message"].as_str(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request(error) => write!(f, "request to maple-proxy failed: {}", error),
+            Self::Api { status, .. } => match self.message() {
+                Some(message) => write!(f, "maple-proxy returned {}: {}", status, message),
+                None => write!(f, "maple-proxy returned {}", status),
+            },
+            Self::Decode(error) => write!(f, "unexpected response from maple-proxy: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Request(error) => Some(error),
+            Self::Api { .. } => None,
+            Self::Decode(error) => Some(error),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(error: reqwest::Error) -> Self {
+        Self::Request(error)
+    }
+}
+
+/// Calls a running maple-proxy's OpenAI-compatible and proxy-specific
+/// endpoints. Streaming responses are out of scope; use an OpenAI client
+/// library pointed at the proxy for those.
+#[derive(Debug, Clone)]
+pub struct ProxyClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl ProxyClient {
+    /// A client for the proxy at `base_url`, e.g. `http://localhost:8080`,
+    /// including any prefix the proxy is mounted under
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+        }
+    }
+
+    /// Sends `api_key` as a bearer token, for proxies without a default key
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Sends a non-streaming chat completion request with `options`
+    pub async fn chat_completion(
+        &self,
+        mut request: Value,
+        options: &MapleOptions,
+    ) -> Result<Value, ClientError> {
+        options.apply_to(&mut request);
+        self.post("/v1/chat/completions", &request).await
+    }
+
+    pub async fn embeddings(
+        &self,
+        mut request: Value,
+        options: &MapleOptions,
+    ) -> Result<Value, ClientError> {
+        options.apply_to(&mut request);
+        self.post("/v1/embeddings", &request).await
+    }
+
+    /// Counts tokens locally on the proxy, without calling the backend
+    pub async fn tokenize(
+        &self,
+        request: &TokenizeRequest,
+    ) -> Result<TokenizeResponse, ClientError> {
+        self.post("/v1/tokenize", request).await
+    }
+
+    /// A background chat completion, whose `status` is `in_progress` until
+    /// its `output` or `error` is in
+    pub async fn response(&self, id: &str) -> Result<Value, ClientError> {
+        let url = self.url(&format!("/v1/responses/{}", id));
+        self.send(self.http.get(url)).await
+    }
+
+    pub async fn version(&self) -> Result<VersionInfo, ClientError> {
+        self.send(self.http.get(self.url("/version"))).await
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<T, ClientError> {
+        let body = serde_json::to_vec(body).map_err(ClientError::Decode)?;
+        let request = self
+            .http
+            .post(self.url(path))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body);
+        self.send(request).await
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        mut request: reqwest::RequestBuilder,
+    ) -> Result<T, ClientError> {
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            let body = serde_json::from_slice(&body)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+            return Err(ClientError::Api { status, body });
+        }
+        serde_json::from_slice(&body).map_err(ClientError::Decode)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_app, Config};
+    use serde_json::json;
+
+    #[test]
+    fn options_round_trip_through_the_proxy_extension() {
+        let mut request = json!({"model": "m", "messages": []});
+        let options = MapleOptions::new()
+            .backend("http://secondary:3000")
+            .cache(CacheDirective::NoCache)
+            .dry_run(true)
+            .background(true);
+        options.apply_to(&mut request);
+        assert_eq!(
+            request["maple"],
+            json!({
+                "backend": "http://secondary:3000",
+                "cache": "no-cache",
+                "dry_run": true,
+                "background": true
+            })
+        );
+
+        let body = serde_json::to_vec(&request).unwrap();
+        let (extension, _) = crate::extension::take_extension(body.into()).unwrap();
+        assert_eq!(extension.backend.as_deref(), Some("http://secondary:3000"));
+        assert_eq!(extension.cache, Some(CacheDirective::NoCache));
+        assert!(extension.dry_run);
+        assert!(extension.background);
+
+        MapleOptions::new().apply_to(&mut request);
+        assert!(request.get("maple").is_none());
+    }
+
+    #[tokio::test]
+    async fn calls_a_running_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = create_app(Config::default().with_mock_backend(true));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = ProxyClient::new(format!("http://{}/", address));
+
+        let tokens = client
+            .tokenize(&TokenizeRequest::input(["Hello world"]).model("m"))
+            .await
+            .unwrap();
+        assert_eq!(tokens.model.as_deref(), Some("m"));
+        assert_eq!(tokens.data[0].tokens.len() as u64, tokens.count);
+
+        let request = json!({"model": "llama3-3-70b", "messages": [], "max_tokens": 2});
+        let completion = client
+            .chat_completion(request, &MapleOptions::new().session_id("s-1"))
+            .await
+            .unwrap();
+        assert_eq!(
+            completion["choices"][0]["message"]["content"],
+            "Lorem ipsum"
+        );
+
+        let error = client
+            .chat_completion(json!({"messages": []}), &MapleOptions::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ClientError::Api { status, .. } if status == 400));
+        assert_eq!(
+            error.message(),
+            Some("Missing required parameter: 'model'.")
+        );
+    }
+}
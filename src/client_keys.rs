@@ -0,0 +1,418 @@
+use crate::{
+    admin::constant_time_eq,
+    config::Config,
+    ids::{from_hex, random_bytes, random_hex, to_hex},
+    keys::{KeyQuota, KeyRejection, KeyUsage},
+    tasks,
+};
+use anyhow::Context;
+use clap::Args;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
+
+/// How often the keys file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+const HASH_SCHEME: &str = "sha256";
+const SALT_BYTES: usize = 16;
+const GENERATED_KEY_BYTES: usize = 24;
+
+/// A client key as written in the keys file. Unknown fields are refused, so a
+/// misspelled limit fails the load instead of leaving the key unlimited.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyDefinition {
+    name: String,
+    /// `sha256:<salt hex>:<hex of SHA-256(salt || key)>`, as printed by
+    /// `maple-proxy hash-key`
+    hash: String,
+    #[serde(default)]
+    tier: Option<String>,
+    /// Models the key may use; all when empty
+    #[serde(default)]
+    allowed_models: Vec<String>,
+    // The `KeyQuota` fields, listed here since `deny_unknown_fields` does not
+    // work with `#[serde(flatten)]`
+    #[serde(default)]
+    max_requests: Option<u64>,
+    #[serde(default)]
+    max_tokens: Option<u64>,
+    #[serde(default)]
+    max_tokens_per_day: Option<u64>,
+    #[serde(default)]
+    max_tokens_per_month: Option<u64>,
+    #[serde(default)]
+    max_cost_per_day: Option<f64>,
+    #[serde(default)]
+    max_cost_per_month: Option<f64>,
+}
+
+impl KeyDefinition {
+    fn budget(&self) -> KeyQuota {
+        KeyQuota {
+            max_requests: self.max_requests,
+            max_tokens: self.max_tokens,
+            max_tokens_per_day: self.max_tokens_per_day,
+            max_tokens_per_month: self.max_tokens_per_month,
+            max_cost_per_day: self.max_cost_per_day,
+            max_cost_per_month: self.max_cost_per_month,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    keys: Vec<KeyDefinition>,
+}
+
+/// A client key from the keys file, with its usage since the proxy started
+pub(crate) struct ClientKey {
+    pub(crate) name: String,
+    pub(crate) tier: Option<String>,
+    pub(crate) allowed_models: Vec<String>,
+    budget: KeyQuota,
+    salt: Vec<u8>,
+    digest: Vec<u8>,
+    usage: Arc<KeyUsage>,
+}
+
+impl ClientKey {
+    fn matches(&self, key: &str) -> bool {
+        constant_time_eq(&salted_digest(&self.salt, key), &self.digest)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "object": "client_key",
+            "tier": self.tier,
+            "allowed_models": self.allowed_models,
+            "budget": self.budget,
+            "usage": self.usage.to_json(),
+            "budget_usage": self.usage.periods_to_json(),
+        })
+    }
+}
+
+/// Client keys defined by hash in `--client-keys-file`, which is reloaded
+/// when it changes. Requests with one are sent upstream with the default
+/// Maple API key.
+pub(crate) struct ClientKeys {
+    path: PathBuf,
+    keys: RwLock<Arc<Vec<Arc<ClientKey>>>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl ClientKeys {
+    /// `None` without `--client-keys-file`. An unreadable file leaves no
+    /// client keys usable until it is fixed.
+    pub(crate) fn start(config: &Config) -> Option<Arc<Self>> {
+        let client_keys = Arc::new(Self {
+            path: config.client_keys_file.clone()?,
+            keys: RwLock::default(),
+            modified: Mutex::default(),
+        });
+        if let Err(load_error) = client_keys.reload() {
+            error!("{:#}; no client keys will be accepted", load_error);
+        }
+        tasks::spawn_or_warn(
+            tasks::reload_periodically(
+                Arc::downgrade(&client_keys),
+                RELOAD_INTERVAL,
+                Self::reload,
+                "keeping the client keys loaded before",
+            ),
+            "Reloading the client keys file needs a Tokio runtime",
+        );
+        Some(client_keys)
+    }
+
+    /// The client key `key` is. Every key is compared, in constant time, so
+    /// how long this takes does not tell which key or how much of one
+    /// matched.
+    pub(crate) fn find(&self, key: &str) -> Option<Arc<ClientKey>> {
+        // Keys are hashed against a snapshot, without holding the lock
+        let keys = {
+            let keys = self
+                .keys
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            Arc::clone(&keys)
+        };
+        keys.iter()
+            .fold(None, |found, client| {
+                if client.matches(key) {
+                    Some(client)
+                } else {
+                    found
+                }
+            })
+            .cloned()
+    }
+
+    /// The usage counters of a client key with budget left
+    pub(crate) fn authorize(&self, key: &str) -> Result<Arc<KeyUsage>, KeyRejection> {
+        let client = self.find(key).ok_or(KeyRejection::Invalid)?;
+        client.usage.check_quota(&client.budget)?;
+        Ok(Arc::clone(&client.usage))
+    }
+
+    pub(crate) fn list(&self) -> Vec<Value> {
+        let keys = self
+            .keys
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        keys.iter().map(|client| client.to_json()).collect()
+    }
+
+    /// Loads the file if it changed since it was last read. Keys keep their
+    /// usage across reloads by name. Returns whether it was loaded.
+    pub(crate) fn reload(&self) -> anyhow::Result<bool> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to read client keys file {}", self.path.display()))?;
+        // A file that fails to load is reported once, not until it is fixed
+        let previous = self
+            .modified
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .replace(modified);
+        if previous == Some(modified) {
+            return Ok(false);
+        }
+
+        let definitions = load(&self.path)?;
+        let mut keys = self
+            .keys
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut usages: HashMap<_, _> = keys
+            .iter()
+            .map(|client| (client.name.clone(), Arc::clone(&client.usage)))
+            .collect();
+        let reloaded = definitions
+            .into_iter()
+            .map(|definition| {
+                let (salt, digest) = parse_hash(&definition.hash)?;
+                let budget = definition.budget();
+                Ok(Arc::new(ClientKey {
+                    usage: usages.remove(&definition.name).unwrap_or_default(),
+                    name: definition.name,
+                    tier: definition.tier,
+                    allowed_models: definition.allowed_models,
+                    budget,
+                    salt,
+                    digest,
+                }))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if previous.is_some() {
+            info!(
+                "Reloaded {} client keys from {}",
+                reloaded.len(),
+                self.path.display()
+            );
+        }
+        *keys = Arc::new(reloaded);
+        Ok(true)
+    }
+}
+
+/// Checks that the client keys file can be loaded
+pub(crate) fn check_client_keys_file(path: &Path) -> anyhow::Result<()> {
+    for definition in load(path)? {
+        parse_hash(&definition.hash)?;
+    }
+    Ok(())
+}
+
+/// The key definitions in a TOML file, or in JSON for any other extension
+fn load(path: &Path) -> anyhow::Result<Vec<KeyDefinition>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read client keys file {}", path.display()))?;
+    let file: KeysFile = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&contents)
+            .with_context(|| format!("Invalid client keys file {}", path.display()))?,
+        _ => serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid client keys file {}", path.display()))?,
+    };
+    let mut names = HashSet::new();
+    for definition in &file.keys {
+        if !names.insert(definition.name.as_str()) {
+            anyhow::bail!(
+                "Client keys file {} names '{}' more than once",
+                path.display(),
+                definition.name
+            );
+        }
+    }
+    Ok(file.keys)
+}
+
+/// The salt and digest of a `sha256:<salt hex>:<digest hex>` hash
+fn parse_hash(hash: &str) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "Invalid client key hash '{}': expected sha256:<salt hex>:<digest hex>, as printed \
+             by `maple-proxy hash-key`",
+            hash
+        )
+    };
+    let mut parts = hash.split(':');
+    let (Some(HASH_SCHEME), Some(salt), Some(digest), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    let salt = from_hex(salt)
+        .filter(|salt| !salt.is_empty())
+        .ok_or_else(invalid)?;
+    let digest = from_hex(digest)
+        .filter(|digest| digest.len() == 32)
+        .ok_or_else(invalid)?;
+    Ok((salt, digest))
+}
+
+fn salted_digest(salt: &[u8], key: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(key.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// A fresh salted hash of `key` for the keys file
+pub(crate) fn hash_client_key(key: &str) -> String {
+    let salt = random_bytes::<SALT_BYTES>();
+    format!(
+        "{}:{}:{}",
+        HASH_SCHEME,
+        to_hex(&salt),
+        to_hex(&salted_digest(&salt, key))
+    )
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct HashKeyArgs {
+    /// Generate a new random key and print it along with its hash, instead
+    /// of hashing a key read from stdin
+    #[arg(long)]
+    pub generate: bool,
+}
+
+/// The salted hash for `--client-keys-file` of a key read from stdin or, with
+/// `--generate`, of a new key, which is printed first
+pub fn hash_key(args: &HashKeyArgs) -> anyhow::Result<String> {
+    if args.generate {
+        let key = format!("sk-{}", random_hex::<GENERATED_KEY_BYTES>());
+        return Ok(format!(
+            "key = \"{}\"\nhash = \"{}\"\n",
+            key,
+            hash_client_key(&key)
+        ));
+    }
+    let mut key = String::new();
+    io::stdin().read_to_string(&mut key)?;
+    let key = key.trim();
+    if key.is_empty() {
+        anyhow::bail!("Pipe the key to hash into stdin, or pass --generate");
+    }
+    Ok(format!("hash = \"{}\"\n", hash_client_key(key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_keys(path: &Path) -> ClientKeys {
+        ClientKeys {
+            path: path.to_path_buf(),
+            keys: RwLock::default(),
+            modified: Mutex::default(),
+        }
+    }
+
+    #[test]
+    fn hashed_keys_authorize_with_their_metadata() {
+        let dir = std::env::temp_dir().join(format!("maple-client-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.toml");
+        fs::write(
+            &path,
+            format!(
+                "[[keys]]\nname = \"ci\"\nhash = \"{}\"\ntier = \"free\"\n\
+                 allowed_models = [\"llama3-3-70b\"]\nmax_requests = 1\n",
+                hash_client_key("sk-ci-secret")
+            ),
+        )
+        .unwrap();
+        check_client_keys_file(&path).unwrap();
+
+        let client_keys = client_keys(&path);
+        assert!(client_keys.reload().unwrap());
+        assert!(!client_keys.reload().unwrap());
+        let client = client_keys.find("sk-ci-secret").unwrap();
+        assert_eq!(client.name, "ci");
+        assert_eq!(client.tier.as_deref(), Some("free"));
+        assert_eq!(client.allowed_models, vec!["llama3-3-70b".to_string()]);
+        assert!(client_keys.find("sk-ci-secreT").is_none());
+
+        client_keys
+            .authorize("sk-ci-secret")
+            .unwrap()
+            .record_request();
+        assert_eq!(
+            client_keys.authorize("sk-ci-secret").unwrap_err(),
+            KeyRejection::QuotaExceeded
+        );
+        assert_eq!(client_keys.list()[0]["usage"]["requests"], 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn misspelled_fields_fail_the_load() {
+        let dir =
+            std::env::temp_dir().join(format!("maple-client-keys-typo-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.json");
+        let hash = hash_client_key("sk-ci-secret");
+        fs::write(
+            &path,
+            json!({"keys": [{"name": "ci", "hash": hash, "max_request": 1}]}).to_string(),
+        )
+        .unwrap();
+
+        let error = check_client_keys_file(&path).unwrap_err();
+        assert!(format!("{:#}", error).contains("max_request"));
+        assert!(client_keys(&path).reload().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hashes_are_salted_and_checked() {
+        let (first, second) = (hash_client_key("sk-key"), hash_client_key("sk-key"));
+        assert_ne!(first, second);
+        let (salt, digest) = parse_hash(&first).unwrap();
+        assert_eq!(salted_digest(&salt, "sk-key"), digest);
+
+        for invalid in [
+            "sha256:abcd",
+            "md5:00:00",
+            "sha256::00",
+            "sha256:zz:00",
+            &first[1..],
+        ] {
+            assert!(parse_hash(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{
+    config::Config,
+    proxy::{write_sse_event, ByteStream},
+    sse::SseParser,
+};
+use axum::body::Bytes;
+use futures::StreamExt;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Delta fields whose text coalesced chunks join
+const TEXT_FIELDS: [&str; 3] = ["content", "reasoning_content", "reasoning"];
+
+/// How long and how much text streamed chat completion deltas are batched
+/// for, from `--coalesce-ms` and `--coalesce-bytes`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Coalescing {
+    interval: Duration,
+    max_bytes: usize,
+}
+
+impl Coalescing {
+    /// `None` without `--coalesce-ms`
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        Some(Self {
+            interval: Duration::from_millis(config.coalesce_ms?),
+            max_bytes: usize::try_from(config.coalesce_bytes).unwrap_or(usize::MAX),
+        })
+    }
+}
+
+/// Text deltas merged into one chunk that has not been sent yet
+struct Batch {
+    chunk: Value,
+    choice: u64,
+    bytes: usize,
+    send_at: Instant,
+}
+
+/// The choice a chunk adds text to, when that is all it does, so it can be
+/// merged with the chunks around it. Chunks that set a role, call tools,
+/// finish a choice, or carry usage or logprobs are sent as they are.
+fn text_delta_choice(chunk: &Value) -> Option<u64> {
+    let fields = chunk.as_object()?;
+    if fields.get("usage").is_some_and(|usage| !usage.is_null()) {
+        return None;
+    }
+    let [choice] = fields.get("choices")?.as_array()?.as_slice() else {
+        return None;
+    };
+    if !choice["finish_reason"].is_null() || !choice["logprobs"].is_null() {
+        return None;
+    }
+    let delta = choice["delta"].as_object()?;
+    let text_only = delta.iter().all(|(field, value)| {
+        value.is_null() || (value.is_string() && TEXT_FIELDS.contains(&field.as_str()))
+    });
+    text_only.then(|| choice["index"].as_u64().unwrap_or(0))
+}
+
+fn merge_text(batch: &mut Value, chunk: &Value) {
+    let delta = &chunk["choices"][0]["delta"];
+    for field in TEXT_FIELDS {
+        let Some(text) = delta[field].as_str() else {
+            continue;
+        };
+        let batched = &mut batch["choices"][0]["delta"][field];
+        match batched {
+            Value::String(batched) => batched.push_str(text),
+            _ => *batched = Value::from(text),
+        }
+    }
+}
+
+/// Re-emits a streamed chat completion with the text deltas that arrive
+/// within `--coalesce-ms` of each other merged into one chunk, sent early
+/// once it holds `--coalesce-bytes`. Backends that send a chunk per token
+/// otherwise send as many events, which slow clients and intermediaries
+/// struggle to keep up with.
+pub(crate) fn coalesce_stream(mut stream: ByteStream, coalescing: Coalescing) -> ByteStream {
+    Box::pin(async_stream::stream! {
+        let mut parser = SseParser::default();
+        let mut batch: Option<Batch> = None;
+        loop {
+            let send_at = batch.as_ref().map(|batch| batch.send_at);
+            let next = match send_at {
+                Some(send_at) => match tokio::time::timeout_at(send_at, stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        if let Some(batch) = batch.take() {
+                            let mut events = Vec::new();
+                            write_sse_event(&mut events, &batch.chunk.to_string());
+                            yield Ok(Bytes::from(events));
+                        }
+                        continue;
+                    }
+                },
+                None => stream.next().await,
+            };
+            let bytes = match next {
+                Some(Ok(bytes)) => bytes,
+                Some(Err(error)) => {
+                    if let Some(batch) = batch.take() {
+                        let mut events = Vec::new();
+                        write_sse_event(&mut events, &batch.chunk.to_string());
+                        yield Ok(Bytes::from(events));
+                    }
+                    yield Err(error);
+                    break;
+                }
+                None => break,
+            };
+
+            let mut events = Vec::new();
+            for data in parser.push(&bytes) {
+                let chunk = serde_json::from_str::<Value>(&data).ok();
+                let Some((chunk, choice)) =
+                    chunk.and_then(|chunk| text_delta_choice(&chunk).map(|choice| (chunk, choice)))
+                else {
+                    if let Some(batch) = batch.take() {
+                        write_sse_event(&mut events, &batch.chunk.to_string());
+                    }
+                    write_sse_event(&mut events, &data);
+                    continue;
+                };
+
+                if let Some(open) = batch.as_mut().filter(|open| open.choice == choice) {
+                    merge_text(&mut open.chunk, &chunk);
+                    open.bytes += data.len();
+                } else {
+                    if let Some(batch) = batch.take() {
+                        write_sse_event(&mut events, &batch.chunk.to_string());
+                    }
+                    batch = Some(Batch {
+                        chunk,
+                        choice,
+                        bytes: data.len(),
+                        send_at: Instant::now() + coalescing.interval,
+                    });
+                }
+                if let Some(full) = batch.take_if(|batch| batch.bytes >= coalescing.max_bytes) {
+                    write_sse_event(&mut events, &full.chunk.to_string());
+                }
+            }
+            if !events.is_empty() {
+                yield Ok(Bytes::from(events));
+            }
+        }
+
+        if let Some(batch) = batch.take() {
+            let mut events = Vec::new();
+            write_sse_event(&mut events, &batch.chunk.to_string());
+            yield Ok(Bytes::from(events));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn text_chunk(content: &str) -> String {
+        format!(
+            "data: {}\n\n",
+            serde_json::json!({
+                "id": "c1",
+                "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}]
+            })
+        )
+    }
+
+    #[tokio::test]
+    async fn text_deltas_merge_until_a_chunk_that_does_more() {
+        let mut body = String::from(
+            "data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\n\n",
+        );
+        for token in ["Hel", "lo", " wor", "ld"] {
+            body.push_str(&text_chunk(token));
+        }
+        body.push_str("data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n");
+        body.push_str("data: [DONE]\n\n");
+        let stream: ByteStream = Box::pin(futures::stream::iter(
+            body.into_bytes()
+                .chunks(7)
+                .map(|piece| Ok::<_, io::Error>(Bytes::copy_from_slice(piece)))
+                .collect::<Vec<_>>(),
+        ));
+        let coalescing = Coalescing {
+            interval: Duration::from_secs(5),
+            max_bytes: 1024,
+        };
+
+        let chunks: Vec<_> = coalesce_stream(stream, coalescing).collect().await;
+        let body: Vec<u8> = chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.unwrap())
+            .collect();
+        let events = SseParser::default().push(&body);
+        assert_eq!(events.len(), 4);
+        let merged: Value = serde_json::from_str(&events[1]).unwrap();
+        assert_eq!(merged["choices"][0]["delta"]["content"], "Hello world");
+        assert_eq!(events[3], "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn batches_are_sent_when_full_or_due() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let stream: ByteStream = Box::pin(receiver);
+        let coalescing = Coalescing {
+            interval: Duration::from_millis(20),
+            max_bytes: 150,
+        };
+        let mut coalesced = coalesce_stream(stream, coalescing);
+
+        for token in ["a", "b"] {
+            sender
+                .unbounded_send(Ok(Bytes::from(text_chunk(token))))
+                .unwrap();
+        }
+        // Nothing is sent until the batch is due
+        let first = coalesced.next().await.unwrap().unwrap();
+        let events = SseParser::default().push(&first);
+        let merged: Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(merged["choices"][0]["delta"]["content"], "ab");
+
+        let long = "x".repeat(200);
+        sender
+            .unbounded_send(Ok(Bytes::from(text_chunk(&long))))
+            .unwrap();
+        let full = tokio::time::timeout(Duration::from_millis(10), coalesced.next())
+            .await
+            .expect("a full batch is sent at once")
+            .unwrap()
+            .unwrap();
+        assert_eq!(SseParser::default().push(&full).len(), 1);
+    }
+}
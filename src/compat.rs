@@ -0,0 +1,309 @@
+use axum::body::Bytes;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+/// Chat completion response normalizations tailored to a client SDK
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CompatProfile {
+    /// The official `openai` Python package
+    OpenaiPython,
+    /// The `async-openai` Rust crate
+    AsyncOpenai,
+    /// Vercel's AI SDK (`@ai-sdk/openai` and `@ai-sdk/openai-compatible`)
+    VercelAiSdk,
+    /// LangChain's `ChatOpenAI`
+    Langchain,
+}
+
+impl CompatProfile {
+    pub(crate) fn normalizations(self) -> Normalizations {
+        match self {
+            Self::OpenaiPython => Normalizations {
+                null_fields: NullFields::Unchanged,
+                role_on_every_chunk: false,
+                usage_in_separate_chunk: true,
+            },
+            // Typed response structs deserialize most reliably when optional
+            // fields are present as explicit nulls.
+            Self::AsyncOpenai => Normalizations {
+                null_fields: NullFields::Explicit,
+                role_on_every_chunk: false,
+                usage_in_separate_chunk: true,
+            },
+            // The AI SDK's schemas treat absent fields as optional but reject
+            // nulls in several places, e.g. `tool_calls: null` in deltas.
+            Self::VercelAiSdk => Normalizations {
+                null_fields: NullFields::Omit,
+                role_on_every_chunk: false,
+                usage_in_separate_chunk: false,
+            },
+            // Message chunks are typed from each delta's role, so chunks
+            // without one can fall back to a generic chunk class.
+            Self::Langchain => Normalizations {
+                null_fields: NullFields::Unchanged,
+                role_on_every_chunk: true,
+                usage_in_separate_chunk: true,
+            },
+        }
+    }
+}
+
+/// How optional fields without a value are represented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NullFields {
+    Unchanged,
+    /// Add missing optional fields as `null`
+    Explicit,
+    /// Drop object keys whose value is `null`
+    Omit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Normalizations {
+    null_fields: NullFields,
+    /// Repeat `"role": "assistant"` in every streamed delta, not just the first
+    role_on_every_chunk: bool,
+    /// Move streamed usage off content chunks into a trailing chunk with empty
+    /// `choices`, as OpenAI does for `stream_options.include_usage`
+    usage_in_separate_chunk: bool,
+}
+
+const COMPLETION_OPTIONAL_FIELDS: &[&str] = &["system_fingerprint", "usage"];
+const CHOICE_OPTIONAL_FIELDS: &[&str] = &["finish_reason", "logprobs"];
+const MESSAGE_OPTIONAL_FIELDS: &[&str] = &["content", "refusal"];
+
+impl Normalizations {
+    /// Rewrites a non-streaming chat completion body, or returns `None` when it
+    /// is not a JSON object
+    pub(crate) fn normalize_completion(self, body: &[u8]) -> Option<Bytes> {
+        let mut completion: Value = serde_json::from_slice(body).ok()?;
+        if !completion.is_object() {
+            return None;
+        }
+        self.normalize_nulls(&mut completion);
+
+        serde_json::to_vec(&completion).ok().map(Bytes::from)
+    }
+
+    /// Rewrites one streamed chat completion chunk. Moving usage into its own
+    /// chunk can turn one chunk into two.
+    pub(crate) fn normalize_chunk(self, mut chunk: Value) -> Vec<Value> {
+        if !chunk.is_object() {
+            return vec![chunk];
+        }
+
+        if self.role_on_every_chunk {
+            for choice in choices_mut(&mut chunk) {
+                if let Some(delta) = choice.get_mut("delta").and_then(Value::as_object_mut) {
+                    delta
+                        .entry("role")
+                        .or_insert_with(|| Value::String("assistant".to_string()));
+                }
+            }
+        }
+
+        let mut chunks = vec![chunk];
+        if self.usage_in_separate_chunk {
+            if let Some(usage_chunk) = split_usage_chunk(&mut chunks[0]) {
+                chunks.push(usage_chunk);
+            }
+        }
+        for chunk in &mut chunks {
+            self.normalize_nulls(chunk);
+        }
+        chunks
+    }
+
+    fn normalize_nulls(self, completion: &mut Value) {
+        match self.null_fields {
+            NullFields::Unchanged => {}
+            NullFields::Explicit => {
+                insert_nulls(completion, COMPLETION_OPTIONAL_FIELDS);
+                for choice in choices_mut(completion) {
+                    insert_nulls(choice, CHOICE_OPTIONAL_FIELDS);
+                    if let Some(message) = choice.get_mut("message") {
+                        insert_nulls(message, MESSAGE_OPTIONAL_FIELDS);
+                    }
+                }
+            }
+            NullFields::Omit => remove_nulls(completion),
+        }
+    }
+}
+
+fn choices_mut(completion: &mut Value) -> impl Iterator<Item = &mut Value> {
+    completion
+        .get_mut("choices")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+}
+
+/// Detaches usage from a chunk that also carries choices and returns it as a
+/// standalone usage chunk
+fn split_usage_chunk(chunk: &mut Value) -> Option<Value> {
+    let has_choices = chunk
+        .get("choices")
+        .and_then(Value::as_array)
+        .is_some_and(|choices| !choices.is_empty());
+    let has_usage = chunk.get("usage").is_some_and(Value::is_object);
+    if !has_choices || !has_usage {
+        return None;
+    }
+
+    let usage = chunk["usage"].take();
+    let mut usage_chunk = chunk.clone();
+    usage_chunk["choices"] = json!([]);
+    usage_chunk["usage"] = usage;
+    Some(usage_chunk)
+}
+
+fn insert_nulls(object: &mut Value, fields: &[&str]) {
+    if let Some(object) = object.as_object_mut() {
+        for field in fields {
+            object.entry(*field).or_insert(Value::Null);
+        }
+    }
+}
+
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            object.retain(|_, value| !value.is_null());
+            object.values_mut().for_each(remove_nulls);
+        }
+        Value::Array(values) => values.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_chunk_with_usage() -> Value {
+        json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "llama3-3-70b",
+            "choices": [{
+                "index": 0,
+                "delta": {"content": "Hi", "tool_calls": null},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}
+        })
+    }
+
+    fn completion() -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "llama3-3-70b",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hi", "tool_calls": null},
+                "finish_reason": "stop"
+            }]
+        }))
+        .unwrap()
+    }
+
+    fn normalize_completion(profile: CompatProfile) -> Value {
+        let body = profile
+            .normalizations()
+            .normalize_completion(&completion())
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn openai_python_moves_usage_into_trailing_chunk() {
+        let chunks = CompatProfile::OpenaiPython
+            .normalizations()
+            .normalize_chunk(content_chunk_with_usage());
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0]["usage"], Value::Null);
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "Hi");
+        assert_eq!(chunks[1]["choices"], json!([]));
+        assert_eq!(chunks[1]["usage"]["total_tokens"], 4);
+        assert_eq!(chunks[1]["id"], "chatcmpl-1");
+        assert!(chunks[0]["choices"][0]["delta"].get("role").is_none());
+    }
+
+    #[test]
+    fn async_openai_fills_optional_fields_with_nulls() {
+        let completion = normalize_completion(CompatProfile::AsyncOpenai);
+
+        assert_eq!(completion["system_fingerprint"], Value::Null);
+        assert_eq!(completion["usage"], Value::Null);
+        assert_eq!(completion["choices"][0]["logprobs"], Value::Null);
+        assert_eq!(completion["choices"][0]["message"]["refusal"], Value::Null);
+        assert_eq!(completion["choices"][0]["message"]["content"], "Hi");
+
+        let chunks = CompatProfile::AsyncOpenai
+            .normalizations()
+            .normalize_chunk(json!({"choices": [{"index": 0, "delta": {}}]}));
+        assert_eq!(
+            chunks,
+            vec![json!({
+                "choices": [{"index": 0, "delta": {}, "finish_reason": null, "logprobs": null}],
+                "system_fingerprint": null,
+                "usage": null
+            })]
+        );
+    }
+
+    #[test]
+    fn vercel_ai_sdk_drops_nulls_and_keeps_usage_in_place() {
+        let completion = normalize_completion(CompatProfile::VercelAiSdk);
+        assert!(completion["choices"][0]["message"]
+            .get("tool_calls")
+            .is_none());
+
+        let chunks = CompatProfile::VercelAiSdk
+            .normalizations()
+            .normalize_chunk(content_chunk_with_usage());
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0]["choices"][0]["delta"].get("tool_calls").is_none());
+        assert_eq!(chunks[0]["usage"]["total_tokens"], 4);
+    }
+
+    #[test]
+    fn langchain_repeats_role_in_every_delta() {
+        let normalizations = CompatProfile::Langchain.normalizations();
+        let first = normalizations.normalize_chunk(json!({
+            "choices": [{"index": 0, "delta": {"role": "assistant", "content": ""}}]
+        }));
+        let next = normalizations.normalize_chunk(content_chunk_with_usage());
+
+        assert_eq!(first[0]["choices"][0]["delta"]["role"], "assistant");
+        assert_eq!(next[0]["choices"][0]["delta"]["role"], "assistant");
+        assert_eq!(next.len(), 2);
+    }
+
+    #[test]
+    fn non_object_bodies_are_left_alone() {
+        let normalizations = CompatProfile::AsyncOpenai.normalizations();
+
+        assert!(normalizations.normalize_completion(b"not json").is_none());
+        assert!(normalizations.normalize_completion(b"[1]").is_none());
+        assert_eq!(normalizations.normalize_chunk(json!(1)), vec![json!(1)]);
+    }
+
+    #[test]
+    fn profiles_parse_from_kebab_case_names() {
+        assert_eq!(
+            CompatProfile::from_str("vercel-ai-sdk", true).unwrap(),
+            CompatProfile::VercelAiSdk
+        );
+        assert_eq!(
+            CompatProfile::from_str("OpenAI-Python", true).unwrap(),
+            CompatProfile::OpenaiPython
+        );
+        assert!(CompatProfile::from_str("llamaindex", true).is_err());
+    }
+}
@@ -1,134 +1,2803 @@
-use clap::Parser;
+#[cfg(feature = "self-update")]
+use crate::update::SelfUpdateArgs;
+use crate::{
+    attestation::{ExpectedPcr, StartupAttestation},
+    audit::{self, AuditContent},
+    background, batches,
+    client_keys::{self, HashKeyArgs},
+    compat::CompatProfile,
+    connect::ConnectTimeouts,
+    dataset,
+    defaults::ModelDefaults,
+    diagnose::DiagnoseArgs,
+    forwarded::{ForwardedHeader, TrustedProxy},
+    geo::{self, CountryRateLimit},
+    honeypot,
+    ids::{IdFormat, MAX_SNOWFLAKE_WORKER_ID},
+    init::InitArgs,
+    key_pool::KeyRotation,
+    keys,
+    limits::{LimitAction, ModelTokenLimit},
+    models::{self, ModelAlias, ModelTables},
+    paths::PathAlias,
+    pipeline::{self, RoutePipeline},
+    pools::ModelPool,
+    pricing::ModelPrice,
+    proxy::CLIENT_CACHE_ENTRY_TTL,
+    redis_store::RedisStore,
+    release::ReleaseChannel,
+    schedule::ModelBlackout,
+    schema::SchemaValidation,
+    secrets,
+    serve::ConnectionLimits,
+    snippets::SnippetsArgs,
+    stream_recovery::StreamRecovery,
+    system_prompt::{SystemPrompt, SystemPromptMode},
+};
+use axum::http::{HeaderName, HeaderValue, Uri};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
 use serde::Serialize;
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 8080;
+pub const DEFAULT_BACKEND_URL: &str = "https://enclave.trymaple.ai";
 pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 300;
+pub const DEFAULT_IMAGE_TIMEOUT_SECS: u64 = 600;
 pub const DEFAULT_STREAM_IDLE_TIMEOUT_SECS: u64 = 300;
+pub const DEFAULT_API_KEY_COOLDOWN_SECS: u64 = 60;
+pub const DEFAULT_SESSION_REFRESH_SECS: u64 = 300;
+pub const DEFAULT_COALESCE_BYTES: u64 = 1024;
+pub const DEFAULT_RESPONSE_FORMAT_ATTEMPTS: u32 = 3;
+pub const DEFAULT_ADAPTIVE_TIMEOUT_MIN_SECS: u64 = 30;
+pub const DEFAULT_ADAPTIVE_TIMEOUT_MAX_SECS: u64 = 1800;
+pub const DEFAULT_RESPONSE_CACHE_MAX_ENTRIES: usize = 1000;
+pub const DEFAULT_MODELS_CACHE_TTL_SECS: u64 = 300;
+pub const DEFAULT_ID_PREFIX: &str = "chatcmpl-";
+pub const DEFAULT_DATASET_MAX_FILE_MB: u64 = 100;
+pub const DEFAULT_DATASET_MAX_FILES: usize = 5;
+pub const DEFAULT_QUARANTINE_REQUESTS_PER_MINUTE: u32 = 6;
+pub const DEFAULT_HONEYPOT_BLOCK_SECS: u64 = 3600;
+pub const DEFAULT_IP_BAN_WINDOW_SECS: u64 = 600;
+pub const DEFAULT_IP_BAN_SECS: u64 = 3600;
+pub const DEFAULT_HEADER_READ_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_CONNECTION_IDLE_TIMEOUT_SECS: u64 = 300;
+pub const DEFAULT_MAX_BODY_MB: u64 = 50;
+pub const DEFAULT_IMAGE_MAX_MB: u64 = 10;
+pub const DEFAULT_IMAGE_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+pub const DEFAULT_MAX_JSON_DEPTH: u32 = 64;
+pub const DEFAULT_AUDIO_MAX_MB: u64 = 20;
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+pub const DEFAULT_BATCH_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_BACKGROUND_TTL_SECS: u64 = 3600;
+pub const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 24 * 60 * 60;
+pub const DEFAULT_QUEUE_DEPTH: u32 = 100;
+pub const DEFAULT_QUEUE_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_ALERT_BACKEND_FAILURES: u32 = 5;
+pub const DEFAULT_JWT_KEY_CLAIM: &str = "sub";
+pub const DEFAULT_JWT_JWKS_REFRESH_SECS: u64 = 300;
+pub const DEFAULT_REDIS_KEY_PREFIX: &str = "maple-proxy:";
+pub const DEFAULT_DEMO_MODEL: &str = "llama3-3-70b";
+pub const DEFAULT_DEMO_RATE_LIMIT_PER_MINUTE: u32 = 10;
+pub const DEFAULT_MOCK_TOKENS_PER_SECOND: u32 = 20;
 
 #[derive(Parser, Debug, Clone)]
 #[command(name = "maple-proxy")]
 #[command(about = "Lightweight OpenAI-compatible proxy server for Maple/OpenSecret")]
 pub struct Config {
     /// Host to bind the server to
-    #[arg(long, env = "MAPLE_HOST", default_value = "127.0.0.1")]
+    #[arg(long, env = "MAPLE_HOST", default_value = DEFAULT_HOST)]
     pub host: String,
 
     /// Port to bind the server to
-    #[arg(short, long, env = "MAPLE_PORT", default_value = "8080")]
+    #[arg(short, long, env = "MAPLE_PORT", default_value_t = DEFAULT_PORT)]
     pub port: u16,
 
     /// OpenSecret/Maple backend URL
+    #[arg(long, env = "MAPLE_BACKEND_URL", default_value = DEFAULT_BACKEND_URL)]
+    pub backend_url: String,
+
+    /// Fallback backend URLs tried in order when the primary fails attestation or returns 5xx
+    #[arg(
+        long = "fallback-backend-url",
+        env = "MAPLE_FALLBACK_BACKEND_URLS",
+        value_name = "URL",
+        value_delimiter = ','
+    )]
+    pub fallback_backend_urls: Vec<String>,
+
+    /// Enclave measurement backends must attest to, as INDEX=HEX (repeatable; several values for
+    /// one PCR allow any of them). Backends that do not are refused.
+    #[arg(
+        long = "expected-pcr",
+        env = "MAPLE_EXPECTED_PCRS",
+        value_name = "INDEX=HEX",
+        value_delimiter = ','
+    )]
+    pub expected_pcrs: Vec<ExpectedPcr>,
+
+    /// Attest every backend with the default API key before serving, keeping the sessions for
+    /// the first requests: `warn` logs the backends that fail, `require` exits if none passes
     #[arg(
         long,
-        env = "MAPLE_BACKEND_URL",
-        default_value = "https://enclave.trymaple.ai"
+        env = "MAPLE_STARTUP_ATTESTATION",
+        value_enum,
+        default_value_t = StartupAttestation::Off
     )]
-    pub backend_url: String,
+    pub startup_attestation: StartupAttestation,
+
+    /// Re-attest pooled sessions in the background this many seconds before they expire, so
+    /// requests never wait on the handshake; only sessions used in the last 15 minutes are
+    /// refreshed, and 0 disables it
+    #[arg(
+        long,
+        env = "MAPLE_SESSION_REFRESH_SECS",
+        default_value_t = DEFAULT_SESSION_REFRESH_SECS
+    )]
+    pub session_refresh_secs: u64,
+
+    /// Default API key for Maple/OpenSecret (can be overridden by client Authorization header)
+    #[arg(long, env = "MAPLE_API_KEY")]
+    pub default_api_key: Option<String>,
+
+    /// File holding the default API key, such as a mounted Kubernetes or Docker secret, re-read
+    /// when it changes; `-` reads the key from stdin once
+    #[arg(
+        long = "api-key-file",
+        env = "MAPLE_API_KEY_FILE",
+        value_name = "PATH",
+        conflicts_with = "default_api_key"
+    )]
+    pub api_key_file: Option<PathBuf>,
+
+    /// Pool of default API keys to spread requests over, resting keys the backend answers with
+    /// a 429 or 401 and retrying with another
+    #[arg(
+        long = "api-keys",
+        env = "MAPLE_API_KEYS",
+        value_name = "KEY",
+        value_delimiter = ',',
+        conflicts_with_all = ["default_api_key", "api_key_file"]
+    )]
+    pub api_keys: Vec<String>,
+
+    /// How requests are spread over the --api-keys pool
+    #[arg(
+        long,
+        env = "MAPLE_API_KEY_ROTATION",
+        value_enum,
+        default_value_t = KeyRotation::RoundRobin
+    )]
+    pub api_key_rotation: KeyRotation,
+
+    /// Seconds a pooled API key rests after a 401, or a 429 without Retry-After
+    #[arg(
+        long,
+        env = "MAPLE_API_KEY_COOLDOWN_SECS",
+        default_value_t = DEFAULT_API_KEY_COOLDOWN_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub api_key_cooldown_secs: u64,
+
+    /// Also accept API keys in `api-key` (Azure) and `x-api-key` (Anthropic)
+    /// headers, everywhere but the admin API
+    #[arg(long, env = "MAPLE_API_KEY_HEADERS")]
+    pub api_key_headers: bool,
+
+    /// Also accept API keys in an `api_key` query parameter on the inference
+    /// endpoints, for test tools that cannot set headers
+    #[arg(long, env = "MAPLE_QUERY_API_KEY")]
+    pub query_api_key: bool,
+
+    /// Enable debug logging
+    #[arg(short, long, env = "MAPLE_DEBUG")]
+    pub debug: bool,
+
+    /// Enable CORS for web clients, for all origins unless --cors-origin is set
+    #[arg(long, env = "MAPLE_ENABLE_CORS")]
+    pub enable_cors: bool,
+
+    /// Browser origin allowed to call the proxy, e.g. https://app.example.com
+    /// (repeatable). Enables CORS for just these origins.
+    #[arg(
+        long = "cors-origin",
+        env = "MAPLE_CORS_ORIGINS",
+        value_name = "ORIGIN",
+        value_delimiter = ','
+    )]
+    pub cors_origins: Vec<String>,
+
+    /// Let browsers send cookies and HTTP authentication; requires --cors-origin
+    #[arg(long, env = "MAPLE_CORS_ALLOW_CREDENTIALS")]
+    pub cors_allow_credentials: bool,
+
+    /// How long browsers may cache preflight responses, in seconds
+    #[arg(long = "cors-max-age", env = "MAPLE_CORS_MAX_AGE", value_name = "SECS")]
+    pub cors_max_age_secs: Option<u64>,
+
+    /// Response header browser scripts may read, e.g. X-Maple-Cache (repeatable)
+    #[arg(
+        long = "cors-expose-header",
+        env = "MAPLE_CORS_EXPOSE_HEADERS",
+        value_name = "HEADER",
+        value_delimiter = ','
+    )]
+    pub cors_expose_headers: Vec<String>,
+
+    /// Timeout for backend request setup and non-streaming responses, in seconds
+    #[arg(
+        long,
+        env = "MAPLE_REQUEST_TIMEOUT_SECS",
+        default_value_t = DEFAULT_REQUEST_TIMEOUT_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub request_timeout_secs: u64,
+
+    /// Maximum time to wait between streaming response chunks, in seconds
+    #[arg(
+        long,
+        env = "MAPLE_STREAM_IDLE_TIMEOUT_SECS",
+        default_value_t = DEFAULT_STREAM_IDLE_TIMEOUT_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub stream_idle_timeout_secs: u64,
+
+    /// Timeout for image generation requests, in seconds, which the backend
+    /// answers only once every image is rendered
+    #[arg(
+        long,
+        env = "MAPLE_IMAGE_TIMEOUT_SECS",
+        default_value_t = DEFAULT_IMAGE_TIMEOUT_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub image_timeout_secs: u64,
+
+    /// What to do when a chat completion's backend stream fails, stalls, or ends early: cut
+    /// the client off (`off`), end with a `finish_reason: "error"` chunk (`finish`), or ask
+    /// the backend to continue from the text generated so far (`resume`)
+    #[arg(
+        long,
+        env = "MAPLE_STREAM_RECOVERY",
+        value_enum,
+        default_value_t = StreamRecovery::Off
+    )]
+    pub stream_recovery: StreamRecovery,
+
+    /// Backend URLs known to honor vLLM's `continue_final_message`, the only ones
+    /// `--stream-recovery resume` sends continuations to; streams from other backends
+    /// finish with an error chunk
+    #[arg(
+        long = "resume-backend-url",
+        env = "MAPLE_RESUME_BACKEND_URLS",
+        value_name = "URL",
+        value_delimiter = ','
+    )]
+    pub resume_backend_urls: Vec<String>,
+
+    /// Ask the backend to stream non-streaming chat completions and assemble the chunks into one
+    /// response, so a completion cut off by a timeout or a failed stream returns what was
+    /// generated, with `finish_reason: "error"`
+    #[arg(long, env = "MAPLE_AGGREGATE_STREAMS")]
+    pub aggregate_streams: bool,
+
+    /// Merge the text deltas of streamed chat completions that arrive within this many
+    /// milliseconds into one chunk, so backends that stream a chunk per token send far fewer
+    /// events
+    #[arg(
+        long,
+        env = "MAPLE_COALESCE_MS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub coalesce_ms: Option<u64>,
+
+    /// With --coalesce-ms, send merged text as soon as it reaches this many bytes
+    #[arg(
+        long,
+        env = "MAPLE_COALESCE_BYTES",
+        default_value_t = DEFAULT_COALESCE_BYTES,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub coalesce_bytes: u64,
+
+    /// Time chat completions out by their `max_tokens` and the model's observed
+    /// speed instead of --request-timeout-secs, once the model has been timed
+    #[arg(long, env = "MAPLE_ADAPTIVE_TIMEOUT")]
+    pub adaptive_timeout: bool,
+
+    /// Shortest adaptive timeout, in seconds
+    #[arg(
+        long,
+        env = "MAPLE_ADAPTIVE_TIMEOUT_MIN_SECS",
+        default_value_t = DEFAULT_ADAPTIVE_TIMEOUT_MIN_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub adaptive_timeout_min_secs: u64,
+
+    /// Longest adaptive timeout, in seconds
+    #[arg(
+        long,
+        env = "MAPLE_ADAPTIVE_TIMEOUT_MAX_SECS",
+        default_value_t = DEFAULT_ADAPTIVE_TIMEOUT_MAX_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub adaptive_timeout_max_secs: u64,
+
+    /// Limit for resolving a backend's host name, in milliseconds. Setting any
+    /// of the DNS, connect, or TLS timeouts checks each phase separately before
+    /// the attestation handshake with a new backend client.
+    #[arg(
+        long,
+        env = "MAPLE_DNS_TIMEOUT_MS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub dns_timeout_ms: Option<u64>,
+
+    /// Limit for opening a TCP connection to a backend, in milliseconds
+    #[arg(
+        long,
+        env = "MAPLE_CONNECT_TIMEOUT_MS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub connect_timeout_ms: Option<u64>,
+
+    /// Limit for the TLS handshake with an https backend, in milliseconds. The
+    /// certificate is checked against the Mozilla root store.
+    #[arg(
+        long,
+        env = "MAPLE_TLS_TIMEOUT_MS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub tls_timeout_ms: Option<u64>,
+
+    /// Close client connections this many seconds after they open, once their
+    /// in-flight requests finish, so long-lived clients reconnect and spread
+    /// across replicas behind a load balancer
+    #[arg(
+        long,
+        env = "MAPLE_MAX_CONNECTION_LIFETIME_SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_connection_lifetime_secs: Option<u64>,
+
+    /// Close client connections after they carry this many requests
+    #[arg(
+        long,
+        env = "MAPLE_MAX_CONNECTION_REQUESTS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_connection_requests: Option<u64>,
+
+    /// Drop client connections that take longer than this many seconds to send
+    /// their first request, or an HTTP/1.1 request's headers
+    #[arg(
+        long,
+        env = "MAPLE_HEADER_READ_TIMEOUT_SECS",
+        default_value_t = DEFAULT_HEADER_READ_TIMEOUT_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub header_read_timeout_secs: u64,
+
+    /// Close client connections that go this many seconds without a request
+    /// in flight; 0 disables it
+    #[arg(
+        long,
+        env = "MAPLE_CONNECTION_IDLE_TIMEOUT_SECS",
+        default_value_t = DEFAULT_CONNECTION_IDLE_TIMEOUT_SECS
+    )]
+    pub connection_idle_timeout_secs: u64,
+
+    /// Refuse client connections from an IP that already holds this many open
+    #[arg(
+        long,
+        env = "MAPLE_MAX_CONNECTIONS_PER_IP",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_connections_per_ip: Option<u32>,
+
+    /// Model alias applied to requests and the model list, as ALIAS=MODEL (repeatable)
+    #[arg(
+        long = "model-alias",
+        env = "MAPLE_MODEL_ALIASES",
+        value_name = "ALIAS=MODEL",
+        value_delimiter = ','
+    )]
+    pub model_aliases: Vec<ModelAlias>,
+
+    /// Model name spread over several models by weight, as
+    /// NAME=MODEL:WEIGHT,MODEL:WEIGHT (repeatable; `;`-separated in the
+    /// environment). Saturated picks spill over to the other models.
+    #[arg(
+        long = "model-pool",
+        env = "MAPLE_MODEL_POOLS",
+        value_name = "NAME=MODEL:WEIGHT,...",
+        value_delimiter = ';'
+    )]
+    pub model_pools: Vec<ModelPool>,
+
+    /// Sampling parameters filled into a model's chat completion requests
+    /// that leave them out, as MODEL=PARAM=VALUE,PARAM=VALUE with
+    /// temperature, max_tokens, top_p, or `|`-separated stop (repeatable;
+    /// `;`-separated in the environment)
+    #[arg(
+        long = "model-defaults",
+        env = "MAPLE_MODEL_DEFAULTS",
+        value_name = "MODEL=PARAM=VALUE,...",
+        value_delimiter = ';'
+    )]
+    pub model_defaults: Vec<ModelDefaults>,
+
+    /// System prompt added to every chat completion, before any virtual key's
+    /// own prompt
+    #[arg(long, env = "MAPLE_SYSTEM_PROMPT")]
+    pub system_prompt: Option<String>,
+
+    /// Whether the system prompt goes before the client's messages or replaces
+    /// the client's system and developer messages
+    #[arg(
+        long,
+        env = "MAPLE_SYSTEM_PROMPT_MODE",
+        value_enum,
+        default_value_t = SystemPromptMode::Prepend
+    )]
+    pub system_prompt_mode: SystemPromptMode,
+
+    /// Mint proxy-level chat completion and request IDs in this format
+    /// instead of keeping the backend's
+    #[arg(long, env = "MAPLE_ID_FORMAT", value_enum, default_value_t = IdFormat::Backend)]
+    pub id_format: IdFormat,
+
+    /// Prefix of minted chat completion IDs
+    #[arg(long, env = "MAPLE_ID_PREFIX", default_value = DEFAULT_ID_PREFIX)]
+    pub id_prefix: String,
+
+    /// Worker ID, 0 to 1023, in snowflake IDs; give each replica its own
+    #[arg(long, env = "MAPLE_SNOWFLAKE_WORKER_ID", default_value_t = 0)]
+    pub snowflake_worker_id: u16,
+
+    /// Cache successful non-streaming chat completions for this many seconds and
+    /// return them for identical requests with the same API key
+    #[arg(
+        long,
+        env = "MAPLE_RESPONSE_CACHE_TTL_SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub response_cache_ttl_secs: Option<u64>,
+
+    /// Maximum number of cached chat completions
+    #[arg(
+        long,
+        env = "MAPLE_RESPONSE_CACHE_MAX_ENTRIES",
+        default_value_t = DEFAULT_RESPONSE_CACHE_MAX_ENTRIES
+    )]
+    pub response_cache_max_entries: usize,
+
+    /// Seconds to cache each backend's `/v1/models` response; 0 disables the
+    /// cache
+    #[arg(
+        long,
+        env = "MAPLE_MODELS_CACHE_TTL_SECS",
+        default_value_t = DEFAULT_MODELS_CACHE_TTL_SECS
+    )]
+    pub models_cache_ttl_secs: u64,
+
+    /// Cache embedding vectors per model and input string, using at most this
+    /// many megabytes of memory
+    #[arg(
+        long,
+        env = "MAPLE_EMBEDDING_CACHE_MAX_MB",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub embedding_cache_max_mb: Option<u64>,
+
+    /// Largest request body accepted, in megabytes. Larger ones are refused
+    /// with a 413.
+    #[arg(
+        long,
+        env = "MAPLE_MAX_BODY_MB",
+        default_value_t = DEFAULT_MAX_BODY_MB,
+        value_parser = clap::value_parser!(u64).range(1..=1024)
+    )]
+    pub max_body_mb: u64,
+
+    /// Download the `http` and `https` image_url parts of chat completions and
+    /// send them to the backend inline, as base64 data URLs, for backends that
+    /// cannot fetch URLs from inside the enclave
+    #[arg(long, env = "MAPLE_FETCH_IMAGES")]
+    pub fetch_images: bool,
+
+    /// With --fetch-images, the largest image downloaded, in megabytes
+    #[arg(
+        long,
+        env = "MAPLE_IMAGE_MAX_MB",
+        default_value_t = DEFAULT_IMAGE_MAX_MB,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub image_max_mb: u64,
+
+    /// With --fetch-images, a media type downloaded images may have
+    /// (repeatable)
+    #[arg(
+        long = "image-type",
+        env = "MAPLE_IMAGE_TYPES",
+        value_name = "MEDIA_TYPE",
+        value_delimiter = ',',
+        default_values_t = DEFAULT_IMAGE_TYPES.map(String::from)
+    )]
+    pub image_types: Vec<String>,
+
+    /// With --fetch-images, also download images from loopback, private, and
+    /// link-local addresses, which are refused so clients cannot reach
+    /// internal services through the proxy
+    #[arg(long, env = "MAPLE_IMAGE_FETCH_PRIVATE")]
+    pub image_fetch_private: bool,
+
+    /// Deepest nesting of arrays and objects accepted in a JSON request body,
+    /// checked before it is parsed
+    #[arg(
+        long,
+        env = "MAPLE_MAX_JSON_DEPTH",
+        default_value_t = DEFAULT_MAX_JSON_DEPTH,
+        value_parser = clap::value_parser!(u32).range(1..=128)
+    )]
+    pub max_json_depth: u32,
+
+    /// Largest input_audio part accepted in a chat completion message, in
+    /// megabytes once decoded from base64
+    #[arg(
+        long,
+        env = "MAPLE_AUDIO_MAX_MB",
+        default_value_t = DEFAULT_AUDIO_MAX_MB,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub audio_max_mb: u64,
+
+    /// Memory, in megabytes, shared by embedding request bodies being received
+    /// and forwarded, which are buffered whole. Uploads wait for room instead
+    /// of all being buffered at once; those without a Content-Length reserve
+    /// what they have read and are refused when it runs out. Must fit
+    /// --max-body-mb.
+    #[arg(
+        long,
+        env = "MAPLE_EMBEDDING_UPLOAD_BUDGET_MB",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub embedding_upload_budget_mb: Option<u64>,
+
+    /// Memory, in megabytes, shared by response streams in flight. When it is
+    /// exceeded, the newest streams end early with an error event.
+    #[arg(
+        long,
+        env = "MAPLE_STREAM_MEMORY_BUDGET_MB",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub stream_memory_budget_mb: Option<u64>,
+
+    /// Inference requests handled at once. Others wait in a queue, in arrival
+    /// order, or are refused with a 503 when it is full.
+    #[arg(
+        long,
+        env = "MAPLE_MAX_CONCURRENT_REQUESTS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_concurrent_requests: Option<u32>,
+
+    /// Requests that may wait for --max-concurrent-requests; 0 refuses every
+    /// request over the limit at once
+    #[arg(long, env = "MAPLE_QUEUE_DEPTH", default_value_t = DEFAULT_QUEUE_DEPTH)]
+    pub queue_depth: u32,
+
+    /// How long a queued request waits before it is refused with a 503
+    #[arg(
+        long,
+        env = "MAPLE_QUEUE_TIMEOUT_SECS",
+        default_value_t = DEFAULT_QUEUE_TIMEOUT_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub queue_timeout_secs: u64,
+
+    /// Allow the server to keep running as root after startup
+    #[arg(long, env = "MAPLE_ALLOW_ROOT")]
+    pub allow_root: bool,
+
+    /// Unprivileged user to switch to after binding the listener (Unix only)
+    #[arg(long = "user", env = "MAPLE_USER", value_name = "USER")]
+    pub run_as_user: Option<String>,
+
+    /// Group to switch to after binding; defaults to the user's primary group (Unix only)
+    #[arg(long = "group", env = "MAPLE_GROUP", value_name = "GROUP")]
+    pub run_as_group: Option<String>,
+
+    /// Directory to chroot into after binding the listener (Unix only)
+    #[arg(long = "chroot", env = "MAPLE_CHROOT", value_name = "DIR")]
+    pub chroot_dir: Option<PathBuf>,
+
+    /// Only serve these models; other models are rejected and hidden from the model list
+    #[arg(
+        long = "allowed-model",
+        env = "MAPLE_ALLOWED_MODELS",
+        value_name = "MODEL",
+        value_delimiter = ','
+    )]
+    pub allowed_models: Vec<String>,
+
+    /// Serve exactly these models in `/v1/models`, in place of the backend's
+    /// list; other models are rejected
+    #[arg(
+        long = "model-list",
+        env = "MAPLE_MODEL_LIST",
+        value_name = "MODEL",
+        value_delimiter = ','
+    )]
+    pub model_list: Vec<String>,
+
+    /// Hide these models from `/v1/models` and reject requests for them
+    #[arg(
+        long = "hide-model",
+        env = "MAPLE_HIDDEN_MODELS",
+        value_name = "MODEL",
+        value_delimiter = ','
+    )]
+    pub hidden_models: Vec<String>,
+
+    /// Maximum inference requests per minute from a single client IP
+    #[arg(
+        long,
+        env = "MAPLE_RATE_LIMIT_PER_MINUTE",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub rate_limit_per_minute: Option<u32>,
+
+    /// Proxies, as IPs or CIDR ranges, whose forwarding header names the
+    /// client for rate limits, logs, and the country, network, and scanner
+    /// rules
+    #[arg(
+        long = "trusted-proxies",
+        env = "MAPLE_TRUSTED_PROXIES",
+        value_name = "CIDR",
+        value_delimiter = ','
+    )]
+    pub trusted_proxies: Vec<TrustedProxy>,
+
+    /// The header trusted proxies write the client's address to: xff
+    /// (X-Forwarded-For) or forwarded (RFC 7239). The other is ignored.
+    #[arg(
+        long,
+        env = "MAPLE_FORWARDED_HEADER",
+        value_enum,
+        default_value_t = ForwardedHeader::XForwardedFor
+    )]
+    pub forwarded_header: ForwardedHeader,
+
+    /// Quarantine an API key that sends more than this many inference
+    /// requests in a minute
+    #[arg(
+        long,
+        env = "MAPLE_QUARANTINE_MAX_REQUESTS_PER_MINUTE",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub quarantine_max_requests_per_minute: Option<u32>,
+
+    /// Quarantine an API key whose requests are refused with this many 401s in
+    /// a minute
+    #[arg(
+        long,
+        env = "MAPLE_QUARANTINE_MAX_AUTH_FAILURES",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub quarantine_max_auth_failures: Option<u32>,
+
+    /// Quarantine an API key whose requests are blocked with this many 403s in
+    /// a minute, e.g. by a policy hook
+    #[arg(
+        long,
+        env = "MAPLE_QUARANTINE_MAX_BLOCKED_REQUESTS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub quarantine_max_blocked_requests: Option<u32>,
+
+    /// Inference requests per minute a quarantined key is still allowed
+    #[arg(
+        long,
+        env = "MAPLE_QUARANTINE_REQUESTS_PER_MINUTE",
+        default_value_t = DEFAULT_QUARANTINE_REQUESTS_PER_MINUTE,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub quarantine_requests_per_minute: u32,
+
+    /// URL that is sent a JSON alert whenever a key is quarantined
+    #[arg(long, env = "MAPLE_QUARANTINE_WEBHOOK", value_name = "URL")]
+    pub quarantine_webhook: Option<String>,
+
+    /// URL that is sent a JSON alert when a virtual key uses up its quota or a
+    /// budget, and when a backend keeps failing or recovers
+    #[arg(long, env = "MAPLE_ALERT_WEBHOOK", value_name = "URL")]
+    pub alert_webhook: Option<String>,
+
+    /// Sign alerts with an HMAC-SHA256 under this secret, sent in the
+    /// X-Maple-Signature header
+    #[arg(long, env = "MAPLE_ALERT_WEBHOOK_SECRET", value_name = "SECRET")]
+    pub alert_webhook_secret: Option<String>,
+
+    /// Failed requests in a row after which a backend is reported failing
+    #[arg(
+        long,
+        env = "MAPLE_ALERT_BACKEND_FAILURES",
+        default_value_t = DEFAULT_ALERT_BACKEND_FAILURES,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub alert_backend_failures: u32,
+
+    /// Serve decoy paths that vulnerability scanners probe, such as /.env and
+    /// /wp-login.php, and block the IPs that request them
+    #[arg(long, env = "MAPLE_HONEYPOT")]
+    pub honeypot: bool,
+
+    /// Another decoy path (repeatable); a trailing `*` matches everything
+    /// starting with the rest. Enables the honeypot on its own.
+    #[arg(
+        long = "honeypot-path",
+        env = "MAPLE_HONEYPOT_PATHS",
+        value_name = "PATH",
+        value_delimiter = ','
+    )]
+    pub honeypot_paths: Vec<String>,
+
+    /// How long an IP that probed a decoy is blocked; 0 only flags it
+    #[arg(
+        long,
+        env = "MAPLE_HONEYPOT_BLOCK_SECS",
+        default_value_t = DEFAULT_HONEYPOT_BLOCK_SECS
+    )]
+    pub honeypot_block_secs: u64,
+
+    /// Ban a client IP whose requests fail authentication this many times
+    /// within --ip-ban-window-secs
+    #[arg(
+        long,
+        env = "MAPLE_IP_BAN_AUTH_FAILURES",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub ip_ban_auth_failures: Option<u32>,
+
+    /// How long authentication failures count toward a ban
+    #[arg(
+        long,
+        env = "MAPLE_IP_BAN_WINDOW_SECS",
+        default_value_t = DEFAULT_IP_BAN_WINDOW_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub ip_ban_window_secs: u64,
+
+    /// How long a banned IP's requests are refused
+    #[arg(
+        long,
+        env = "MAPLE_IP_BAN_SECS",
+        default_value_t = DEFAULT_IP_BAN_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub ip_ban_secs: u64,
+
+    /// MaxMind GeoIP2 or GeoLite2 Country (or City) database that client IPs
+    /// are located with for the country rules
+    #[arg(long, env = "MAPLE_GEOIP_DB", value_name = "PATH")]
+    pub geoip_db: Option<PathBuf>,
+
+    /// MaxMind GeoLite2 ASN database that client IPs are looked up in for the
+    /// network rules
+    #[arg(long, env = "MAPLE_ASN_DB", value_name = "PATH")]
+    pub asn_db: Option<PathBuf>,
+
+    /// Only serve clients located in this country, as a two-letter ISO code
+    /// (repeatable). Clients that cannot be located are refused.
+    #[arg(
+        long = "allow-country",
+        env = "MAPLE_ALLOW_COUNTRIES",
+        value_name = "CODE",
+        value_delimiter = ',',
+        value_parser = geo::parse_country
+    )]
+    pub allow_countries: Vec<String>,
+
+    /// Refuse clients located in this country, as a two-letter ISO code
+    /// (repeatable)
+    #[arg(
+        long = "deny-country",
+        env = "MAPLE_DENY_COUNTRIES",
+        value_name = "CODE",
+        value_delimiter = ',',
+        value_parser = geo::parse_country
+    )]
+    pub deny_countries: Vec<String>,
+
+    /// Only serve clients in this autonomous system (repeatable). Clients
+    /// whose network cannot be looked up are refused.
+    #[arg(
+        long = "allow-asn",
+        env = "MAPLE_ALLOW_ASNS",
+        value_name = "ASN",
+        value_delimiter = ','
+    )]
+    pub allow_asns: Vec<u32>,
+
+    /// Refuse clients in this autonomous system, such as a hosting provider
+    /// (repeatable)
+    #[arg(
+        long = "deny-asn",
+        env = "MAPLE_DENY_ASNS",
+        value_name = "ASN",
+        value_delimiter = ','
+    )]
+    pub deny_asns: Vec<u32>,
+
+    /// Inference requests per minute from each client IP in a country, as
+    /// COUNTRY=LIMIT (repeatable). Replaces --rate-limit-per-minute there.
+    #[arg(
+        long = "country-rate-limit",
+        env = "MAPLE_COUNTRY_RATE_LIMITS",
+        value_name = "COUNTRY=LIMIT",
+        value_delimiter = ','
+    )]
+    pub country_rate_limits: Vec<CountryRateLimit>,
+
+    /// Hours in UTC in which a model is refused, as MODEL=WINDOW such as
+    /// `gpt-4o=Mon-Fri 09:00-17:00` (repeatable). Clients are told when to
+    /// retry.
+    #[arg(
+        long = "model-blackout",
+        env = "MAPLE_MODEL_BLACKOUTS",
+        value_name = "MODEL=WINDOW",
+        value_delimiter = ','
+    )]
+    pub model_blackouts: Vec<ModelBlackout>,
+
+    /// What a model costs in USD per million prompt and completion tokens, as
+    /// MODEL=INPUT/OUTPUT (repeatable). Used to enforce cost ceilings.
+    #[arg(
+        long = "model-price",
+        env = "MAPLE_MODEL_PRICES",
+        value_name = "MODEL=INPUT/OUTPUT",
+        value_delimiter = ','
+    )]
+    pub model_prices: Vec<ModelPrice>,
+
+    /// Reject requests whose worst-case cost in USD exceeds this, unless the
+    /// X-Maple-Max-Cost header sets a lower ceiling
+    #[arg(long, env = "MAPLE_MAX_REQUEST_COST", value_name = "USD")]
+    pub max_request_cost: Option<f64>,
+
+    /// Report each non-streaming completion's and embedding's estimated cost
+    /// in USD in an X-Maple-Cost response header
+    #[arg(long, env = "MAPLE_COST_HEADER")]
+    pub cost_header: bool,
+
+    /// Highest `temperature` a chat completion may ask for, at most 2
+    #[arg(long, env = "MAPLE_MAX_TEMPERATURE")]
+    pub max_temperature: Option<f64>,
+
+    /// Most choices (`n`) a chat completion may ask for
+    #[arg(
+        long,
+        env = "MAPLE_MAX_N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_n: Option<u32>,
+
+    /// Most tokens a chat completion may generate; requests that set no limit
+    /// get this one
+    #[arg(
+        long,
+        env = "MAPLE_MAX_TOKENS_LIMIT",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_tokens_limit: Option<u64>,
+
+    /// --max-tokens-limit for one model, as MODEL=TOKENS (repeatable)
+    #[arg(
+        long = "model-max-tokens",
+        env = "MAPLE_MODEL_MAX_TOKENS",
+        value_name = "MODEL=TOKENS",
+        value_delimiter = ','
+    )]
+    pub model_max_tokens: Vec<ModelTokenLimit>,
+
+    /// Whether chat completion parameters over the limits above are lowered to
+    /// them or rejected with a 400
+    #[arg(
+        long,
+        env = "MAPLE_PARAM_LIMIT_ACTION",
+        value_enum,
+        default_value_t = LimitAction::Clamp
+    )]
+    pub param_limit_action: LimitAction,
+
+    /// Serve a browser chat playground at /playground
+    #[arg(long = "playground", env = "MAPLE_ENABLE_PLAYGROUND")]
+    pub enable_playground: bool,
+
+    /// Serve Prometheus metrics, broken down by client SDK, at /metrics
+    #[arg(long = "metrics", env = "MAPLE_ENABLE_METRICS")]
+    pub enable_metrics: bool,
+
+    /// Report the time spent attesting and waiting for the backend in a
+    /// Server-Timing response header
+    #[arg(long, env = "MAPLE_SERVER_TIMING")]
+    pub server_timing: bool,
+
+    /// Compress responses with gzip or Brotli when clients accept it, and
+    /// accept request bodies compressed with either
+    #[arg(long = "compression", env = "MAPLE_ENABLE_COMPRESSION")]
+    pub enable_compression: bool,
+
+    /// Log inference requests slower than this at WARN, timing streams to
+    /// their first token
+    #[arg(
+        long,
+        env = "MAPLE_SLOW_REQUEST_MS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub slow_request_ms: Option<u64>,
+
+    /// Serve Ollama-compatible /api/chat, /api/generate, and /api/tags endpoints
+    #[arg(long = "ollama-api", env = "MAPLE_ENABLE_OLLAMA_API")]
+    pub enable_ollama_api: bool,
+
+    /// Serve Azure OpenAI-style /openai/deployments/{deployment}/... endpoints
+    #[arg(long = "azure-api", env = "MAPLE_ENABLE_AZURE_API")]
+    pub enable_azure_api: bool,
+
+    /// Serve the maple.v1.Inference gRPC service on the same port (needs the
+    /// grpc feature)
+    #[arg(long = "grpc", env = "MAPLE_ENABLE_GRPC")]
+    pub enable_grpc: bool,
+
+    /// Azure deployment served by a Maple model, as DEPLOYMENT=MODEL (repeatable).
+    /// Unmapped deployment names are used as the model name.
+    #[arg(
+        long = "azure-deployment",
+        env = "MAPLE_AZURE_DEPLOYMENTS",
+        value_name = "DEPLOYMENT=MODEL",
+        value_delimiter = ','
+    )]
+    pub azure_deployments: Vec<ModelAlias>,
+
+    /// The gateway stages an inference route runs, in order, as
+    /// ROUTE=STAGE>STAGE or ROUTE=none (repeatable). Stages are metrics,
+    /// rate_limit, quarantine, schedule, hooks, and queue; routes not listed
+    /// run all of them in that order.
+    #[arg(
+        long = "pipeline",
+        env = "MAPLE_PIPELINES",
+        value_name = "ROUTE=STAGES",
+        value_delimiter = ','
+    )]
+    pub pipelines: Vec<RoutePipeline>,
+
+    /// A path served as another route, as FROM=TO (repeatable), e.g.
+    /// /chat/completions=/v1/chat/completions for clients that leave out /v1
+    #[arg(
+        long = "path-alias",
+        env = "MAPLE_PATH_ALIASES",
+        value_name = "FROM=TO",
+        value_delimiter = ','
+    )]
+    pub path_aliases: Vec<PathAlias>,
+
+    /// Serve OpenAI endpoints without their /v1 prefix, and any path with
+    /// trailing slashes, as the route it names
+    #[arg(long, env = "MAPLE_NORMALIZE_PATHS")]
+    pub normalize_paths: bool,
+
+    /// Keep API key fragments, query strings, and request details out of logs
+    #[arg(long, env = "MAPLE_REDACT_LOGS")]
+    pub redact_logs: bool,
+
+    /// Plain OpenAI-compatible server (e.g. local vLLM or Ollama) for models not served by Maple
+    #[arg(long, env = "MAPLE_OPENAI_UPSTREAM_URL", value_name = "URL")]
+    pub openai_upstream_url: Option<String>,
+
+    /// API key sent to the OpenAI-compatible upstream
+    #[arg(long, env = "MAPLE_OPENAI_UPSTREAM_API_KEY")]
+    pub openai_upstream_api_key: Option<String>,
+
+    /// Models routed to the OpenAI-compatible upstream instead of Maple
+    #[arg(
+        long = "openai-upstream-model",
+        env = "MAPLE_OPENAI_UPSTREAM_MODELS",
+        value_name = "MODEL",
+        value_delimiter = ','
+    )]
+    pub openai_upstream_models: Vec<String>,
+
+    /// Local OpenAI-compatible moderation classifier that serves /v1/moderations
+    /// instead of the Maple backend
+    #[arg(long, env = "MAPLE_MODERATION_URL", value_name = "URL")]
+    pub moderation_url: Option<String>,
+
+    /// Model for /v1/moderations requests that name none, such as the
+    /// backend's moderation model
+    #[arg(long, env = "MAPLE_MODERATION_MODEL", value_name = "MODEL")]
+    pub moderation_model: Option<String>,
+
+    /// Check requests and responses against the bundled OpenAI JSON schemas
+    #[arg(
+        long,
+        env = "MAPLE_SCHEMA_VALIDATION",
+        value_enum,
+        default_value_t = SchemaValidation::Off
+    )]
+    pub schema_validation: SchemaValidation,
+
+    /// Reject requests and responses with any field the bundled OpenAI schemas
+    /// do not list, vendor extensions included, to check that an application
+    /// stays portable across providers. Implies `--schema-validation enforce`.
+    #[arg(long, env = "MAPLE_STRICT_OPENAI")]
+    pub strict_openai: bool,
+
+    /// Client SDK whose response quirks to normalize for; clients can override
+    /// it per request with the X-Maple-Compat-Profile header
+    #[arg(long, env = "MAPLE_COMPAT_PROFILE", value_enum)]
+    pub compat_profile: Option<CompatProfile>,
+
+    /// Repair off-spec tool calls in chat completions: give streamed deltas a
+    /// missing `index`, cut arguments a backend repeats in full down to the new
+    /// part, and send `arguments` objects as strings
+    #[arg(long, env = "MAPLE_NORMALIZE_TOOL_CALLS")]
+    pub normalize_tool_calls: bool,
+
+    /// Check that non-streaming chat completions asking for a `json_object` or
+    /// `json_schema` response_format got one, and send the request again when
+    /// the backend's content is not valid JSON or does not match the schema
+    #[arg(long, env = "MAPLE_ENFORCE_RESPONSE_FORMAT")]
+    pub enforce_response_format: bool,
+
+    /// Repair non-streaming chat completions asking for a JSON response_format
+    /// whose content does not parse: drop surrounding prose and code fences,
+    /// close cut-off strings and brackets, or else send the request once more
+    #[arg(long, env = "MAPLE_REPAIR_JSON_OUTPUT")]
+    pub repair_json_output: bool,
+
+    /// With --enforce-response-format, how often a request is sent before a
+    /// mismatching completion is answered with a 502
+    #[arg(
+        long,
+        env = "MAPLE_RESPONSE_FORMAT_ATTEMPTS",
+        default_value_t = DEFAULT_RESPONSE_FORMAT_ATTEMPTS,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub response_format_attempts: u32,
+
+    /// Forward OpenAI request and response bodies byte for byte: no model
+    /// rewriting, usage estimates, compatibility fixes, or model list merging
+    #[arg(long, env = "MAPLE_PASSTHROUGH")]
+    pub passthrough: bool,
+
+    /// Public demo preset: anonymous access with the default key, strict rate
+    /// limits, a single allowed model, the playground, and log redaction
+    #[arg(long, env = "MAPLE_DEMO")]
+    pub demo: bool,
+
+    /// Serve canned model lists and lorem ipsum completions without
+    /// contacting Maple, for offline development. No API key is needed.
+    #[arg(long, env = "MAPLE_MOCK_BACKEND")]
+    pub mock_backend: bool,
+
+    /// Words per second streamed by the mock backend
+    #[arg(
+        long,
+        env = "MAPLE_MOCK_TOKENS_PER_SECOND",
+        default_value_t = DEFAULT_MOCK_TOKENS_PER_SECOND,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub mock_tokens_per_second: u32,
+
+    /// Check daily for newer releases and report them in the log, `/version`,
+    /// and the X-Maple-Update-Available header. Nothing is installed.
+    #[arg(long, env = "MAPLE_UPDATE_CHECK")]
+    pub update_check: bool,
+
+    /// Release channel the update check follows
+    #[arg(
+        long,
+        env = "MAPLE_UPDATE_CHANNEL",
+        value_enum,
+        default_value_t = ReleaseChannel::Stable
+    )]
+    pub update_channel: ReleaseChannel,
+
+    /// Also write the shutdown report to this file as JSON. The file is created
+    /// at startup, before any sandboxing takes effect.
+    #[arg(long, env = "MAPLE_SHUTDOWN_REPORT", value_name = "PATH")]
+    pub shutdown_report: Option<PathBuf>,
+
+    /// Bearer token for the /admin API, which edits model aliases and upstream
+    /// routing and manages virtual keys while running. The API is disabled
+    /// without it.
+    #[arg(long, env = "MAPLE_ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// JSON file the admin API saves alias and routing changes to. When it
+    /// exists, it replaces the configured aliases and upstream models on startup.
+    #[arg(long, env = "MAPLE_ROUTES_FILE", value_name = "PATH")]
+    pub routes_file: Option<PathBuf>,
+
+    /// JSON file virtual keys issued through the admin API are saved to. Without
+    /// it, virtual keys last until the proxy restarts.
+    #[arg(long, env = "MAPLE_KEYS_FILE", value_name = "PATH")]
+    pub keys_file: Option<PathBuf>,
+
+    /// TOML (.toml) or JSON file of client keys, each a salted hash from
+    /// `maple-proxy hash-key` with a name, optional tier, allowed models, and
+    /// budget. Clients must then present one of them, a virtual key, or an
+    /// accepted JWT, and are sent upstream with MAPLE_API_KEY. The file is
+    /// reloaded when it changes.
+    #[arg(long, env = "MAPLE_CLIENT_KEYS_FILE", value_name = "PATH")]
+    pub client_keys_file: Option<PathBuf>,
+
+    /// Accept bearer tokens that are JWTs signed by a key at this JWKS URL,
+    /// e.g. https://auth.example.com/.well-known/jwks.json. Each token is
+    /// charged to the virtual key its --jwt-key-claim claim names, and the
+    /// request is sent upstream with MAPLE_API_KEY.
+    #[arg(long, env = "MAPLE_JWT_JWKS_URL", value_name = "URL")]
+    pub jwt_jwks_url: Option<String>,
+
+    /// The `iss` claim accepted JWTs must have
+    #[arg(long, env = "MAPLE_JWT_ISSUER", value_name = "ISSUER")]
+    pub jwt_issuer: Option<String>,
+
+    /// The `aud` claim accepted JWTs must have
+    #[arg(long, env = "MAPLE_JWT_AUDIENCE", value_name = "AUDIENCE")]
+    pub jwt_audience: Option<String>,
+
+    /// The JWT claim whose value is the name of the virtual key a token is
+    /// charged to, such as a team or tenant claim
+    #[arg(
+        long,
+        env = "MAPLE_JWT_KEY_CLAIM",
+        value_name = "CLAIM",
+        default_value = DEFAULT_JWT_KEY_CLAIM
+    )]
+    pub jwt_key_claim: String,
+
+    /// Seconds between fetches of the JWKS. Tokens signed by a key it did
+    /// not have bring the next fetch forward.
+    #[arg(
+        long,
+        env = "MAPLE_JWT_JWKS_REFRESH_SECS",
+        default_value_t = DEFAULT_JWT_JWKS_REFRESH_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub jwt_jwks_refresh_secs: u64,
+
+    /// Redis that replicas share rate limit counters, virtual key usage, and
+    /// the response and model list caches through, e.g. redis://cache:6379/0
+    /// or rediss:// for TLS. When it is unreachable, each replica falls back
+    /// to its own.
+    #[arg(long, env = "MAPLE_REDIS_URL", value_name = "URL")]
+    pub redis_url: Option<String>,
+
+    /// Prefix of every Redis key the proxy uses, so deployments can share a
+    /// Redis
+    #[arg(
+        long,
+        env = "MAPLE_REDIS_KEY_PREFIX",
+        default_value = DEFAULT_REDIS_KEY_PREFIX
+    )]
+    pub redis_key_prefix: String,
+
+    /// Record each request through the OpenAI and Azure endpoints in this
+    /// SQLite database, for compliance review. The database is opened at
+    /// startup, before any sandboxing takes effect.
+    #[arg(long, env = "MAPLE_AUDIT_DB", value_name = "PATH")]
+    pub audit_db: Option<PathBuf>,
+
+    /// Whether audit records include prompt and response text, and whether it
+    /// is redacted first
+    #[arg(
+        long,
+        env = "MAPLE_AUDIT_CONTENT",
+        value_enum,
+        default_value_t = AuditContent::Off
+    )]
+    pub audit_content: AuditContent,
+
+    /// Delete audit records older than this many days
+    #[arg(
+        long,
+        env = "MAPLE_AUDIT_RETENTION_DAYS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub audit_retention_days: Option<u64>,
+
+    /// Append finished chat completions to this JSONL file in OpenAI's
+    /// fine-tuning format, for building training and evaluation datasets. The
+    /// file is opened at startup and rotated in its directory.
+    #[arg(long, env = "MAPLE_DATASET_FILE", value_name = "PATH")]
+    pub dataset_file: Option<PathBuf>,
+
+    /// Rotate the dataset file once it reaches this many MiB
+    #[arg(
+        long,
+        env = "MAPLE_DATASET_MAX_FILE_MB",
+        default_value_t = DEFAULT_DATASET_MAX_FILE_MB,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub dataset_max_file_mb: u64,
+
+    /// How many rotated dataset files to keep beside the current one
+    #[arg(long, env = "MAPLE_DATASET_MAX_FILES", default_value_t = DEFAULT_DATASET_MAX_FILES)]
+    pub dataset_max_files: usize,
+
+    /// Serve `/v1/files` and `/v1/batches`, keeping uploaded files, batch
+    /// progress, and results in this directory
+    #[arg(long, env = "MAPLE_BATCH_DIR", value_name = "DIR")]
+    pub batch_dir: Option<PathBuf>,
+
+    /// Requests of one batch sent to the backend at once
+    #[arg(long, env = "MAPLE_BATCH_CONCURRENCY", default_value_t = DEFAULT_BATCH_CONCURRENCY)]
+    pub batch_concurrency: usize,
+
+    /// How many times a batch request answered with a 429 or 5xx is retried
+    #[arg(long, env = "MAPLE_BATCH_MAX_RETRIES", default_value_t = DEFAULT_BATCH_MAX_RETRIES)]
+    pub batch_max_retries: u32,
+
+    /// Keep background chat completions in this SQLite database instead of
+    /// in memory, so results can be polled across restarts
+    #[arg(long, env = "MAPLE_BACKGROUND_DB", value_name = "PATH")]
+    pub background_db: Option<PathBuf>,
+
+    /// How many seconds a finished background completion can be polled for
+    #[arg(
+        long,
+        env = "MAPLE_BACKGROUND_TTL_SECS",
+        default_value_t = DEFAULT_BACKGROUND_TTL_SECS,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub background_ttl_secs: u64,
+
+    /// How many seconds a chat completion is replayed for requests repeating
+    /// its `Idempotency-Key`; 0 ignores the header
+    #[arg(
+        long,
+        env = "MAPLE_IDEMPOTENCY_TTL_SECS",
+        default_value_t = DEFAULT_IDEMPOTENCY_TTL_SECS
+    )]
+    pub idempotency_ttl_secs: u64,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Interactively write a config file (and optionally a systemd unit)
+    Init(InitArgs),
+
+    /// Print a sanitized diagnostics bundle to attach to bug reports
+    Diagnose(DiagnoseArgs),
+
+    /// Print client code for this proxy's address and models
+    Snippets(SnippetsArgs),
+
+    /// Print a salted hash of a key read from stdin for --client-keys-file
+    HashKey(HashKeyArgs),
+
+    /// Replace this binary with a verified GitHub release
+    #[cfg(feature = "self-update")]
+    SelfUpdate(SelfUpdateArgs),
+}
+
+/// The same settings the CLI starts with when no flags or environment
+/// variables are given
+impl Default for Config {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_HOST.to_string(),
+            DEFAULT_PORT,
+            DEFAULT_BACKEND_URL.to_string(),
+        )
+    }
+}
+
+impl Config {
+    pub fn socket_addr(&self) -> anyhow::Result<SocketAddr> {
+        let addr = format!("{}:{}", self.host, self.port);
+        addr.parse()
+            .map_err(|e| anyhow::anyhow!("Invalid socket address '{}': {}", addr, e))
+    }
+
+    /// Whether requests can fall back to a Maple API key, from MAPLE_API_KEY,
+    /// `--api-key-file`, or `--api-keys`
+    pub(crate) fn has_api_key(&self) -> bool {
+        self.default_api_key.is_some() || self.api_key_file.is_some() || !self.api_keys.is_empty()
+    }
+
+    pub fn load() -> Self {
+        // Load from .env file if it exists
+        let _ = dotenvy::dotenv();
+
+        let mut config = Config::parse();
+        if let Some(path) = &config.api_key_file {
+            match secrets::read_secret(path) {
+                Ok(api_key) => config.default_api_key = Some(api_key),
+                Err(read_error) => Config::command()
+                    .error(ErrorKind::Io, format!("{:#}", read_error))
+                    .exit(),
+            }
+        }
+        // Commands that check one key, like `diagnose`, use the pool's first
+        if config.default_api_key.is_none() {
+            config.default_api_key = config.api_keys.first().cloned();
+        }
+        if config.demo {
+            config.apply_demo_preset();
+        }
+        config
+    }
+
+    /// Checks settings that clap cannot validate on its own
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let api_key_file = self.api_key_file.as_deref();
+        if let Some(path) = api_key_file.filter(|path| *path != Path::new(secrets::STDIN_PATH)) {
+            secrets::read_secret(path)?;
+        }
+        for (index, api_key) in self.api_keys.iter().enumerate() {
+            if api_key.trim().is_empty() {
+                anyhow::bail!("--api-keys must not contain empty keys");
+            }
+            if self.api_keys[..index].contains(api_key) {
+                anyhow::bail!("--api-keys lists a key more than once");
+            }
+        }
+        if Duration::from_secs(self.session_refresh_secs) >= CLIENT_CACHE_ENTRY_TTL {
+            anyhow::bail!(
+                "--session-refresh-secs must be less than the {}s an attested session is kept",
+                CLIENT_CACHE_ENTRY_TTL.as_secs()
+            );
+        }
+        if self.startup_attestation != StartupAttestation::Off && !self.has_api_key() {
+            anyhow::bail!("--startup-attestation attests with MAPLE_API_KEY, which is not set");
+        }
+        if self.demo && !self.has_api_key() {
+            anyhow::bail!("Demo mode serves anonymous clients and requires MAPLE_API_KEY");
+        }
+        if self.openai_upstream_url.is_some() != !self.openai_upstream_models.is_empty() {
+            anyhow::bail!(
+                "--openai-upstream-url and --openai-upstream-model must be configured together"
+            );
+        }
+        if self.cors_allow_credentials && self.cors_origins.is_empty() {
+            anyhow::bail!(
+                "--cors-allow-credentials requires --cors-origin; browsers refuse credentials for any origin"
+            );
+        }
+        for origin in &self.cors_origins {
+            validate_cors_origin(origin)?;
+        }
+        for name in &self.cors_expose_headers {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| anyhow::anyhow!("Invalid --cors-expose-header '{}'", name))?;
+        }
+        for (index, pool) in self.model_pools.iter().enumerate() {
+            if self.model_pools[..index]
+                .iter()
+                .any(|other| other.name == pool.name)
+                || self
+                    .model_aliases
+                    .iter()
+                    .any(|alias| alias.alias == pool.name)
+            {
+                anyhow::bail!(
+                    "Model pool '{}' is defined more than once or shadows a model alias",
+                    pool.name
+                );
+            }
+            for member in &pool.members {
+                if !models::is_model_allowed(&self.allowed_models, &member.model) {
+                    anyhow::bail!(
+                        "Model pool '{}' uses '{}', which is not in --allowed-model",
+                        pool.name,
+                        member.model
+                    );
+                }
+                if !models::is_model_listed(self, &member.model) {
+                    anyhow::bail!(
+                        "Model pool '{}' uses '{}', which --model-list or --hide-model leaves out",
+                        pool.name,
+                        member.model
+                    );
+                }
+            }
+        }
+        for (index, defaults) in self.model_defaults.iter().enumerate() {
+            if self.model_defaults[..index]
+                .iter()
+                .any(|other| other.model == defaults.model)
+            {
+                anyhow::bail!(
+                    "Model defaults for '{}' are set more than once",
+                    defaults.model
+                );
+            }
+        }
+        if self
+            .system_prompt
+            .as_ref()
+            .is_some_and(|system_prompt| system_prompt.trim().is_empty())
+        {
+            anyhow::bail!("--system-prompt must not be empty");
+        }
+        if self.snowflake_worker_id > MAX_SNOWFLAKE_WORKER_ID {
+            anyhow::bail!(
+                "--snowflake-worker-id must be at most {}",
+                MAX_SNOWFLAKE_WORKER_ID
+            );
+        }
+        if self.id_prefix.chars().any(char::is_control) {
+            anyhow::bail!("--id-prefix must not contain control characters");
+        }
+        if self
+            .max_request_cost
+            .is_some_and(|cost| !cost.is_finite() || cost <= 0.0)
+        {
+            anyhow::bail!("--max-request-cost must be a positive number");
+        }
+        if self.max_request_cost.is_some() && self.model_prices.is_empty() {
+            anyhow::bail!("--max-request-cost requires --model-price");
+        }
+        if self.cost_header && self.model_prices.is_empty() {
+            anyhow::bail!("--cost-header requires --model-price");
+        }
+        if self
+            .max_temperature
+            .is_some_and(|temperature| !(0.0..=2.0).contains(&temperature))
+        {
+            anyhow::bail!("--max-temperature must be between 0 and 2");
+        }
+        for (index, limit) in self.model_max_tokens.iter().enumerate() {
+            if self.model_max_tokens[..index]
+                .iter()
+                .any(|other| other.model == limit.model)
+            {
+                anyhow::bail!(
+                    "--model-max-tokens for '{}' is set more than once",
+                    limit.model
+                );
+            }
+        }
+        if let Some(model) = self
+            .hidden_models
+            .iter()
+            .find(|model| self.model_list.contains(model))
+        {
+            anyhow::bail!("--hide-model '{}' is also in --model-list", model);
+        }
+        if self.passthrough {
+            let rewriting_options = [
+                ("--model-alias", !self.model_aliases.is_empty()),
+                ("--model-pool", !self.model_pools.is_empty()),
+                ("--model-defaults", !self.model_defaults.is_empty()),
+                ("--system-prompt", self.system_prompt.is_some()),
+                ("--id-format", self.id_format != IdFormat::Backend),
+                ("--max-temperature", self.max_temperature.is_some()),
+                ("--max-n", self.max_n.is_some()),
+                ("--max-tokens-limit", self.max_tokens_limit.is_some()),
+                ("--model-max-tokens", !self.model_max_tokens.is_empty()),
+                ("--allowed-model", !self.allowed_models.is_empty()),
+                ("--model-list", !self.model_list.is_empty()),
+                ("--hide-model", !self.hidden_models.is_empty()),
+                ("--compat-profile", self.compat_profile.is_some()),
+                ("--normalize-tool-calls", self.normalize_tool_calls),
+                ("--fetch-images", self.fetch_images),
+                ("--moderation-model", self.moderation_model.is_some()),
+                (
+                    "--embedding-cache-max-mb",
+                    self.embedding_cache_max_mb.is_some(),
+                ),
+            ];
+            if let Some((option, _)) = rewriting_options.iter().find(|(_, set)| *set) {
+                anyhow::bail!(
+                    "--passthrough forwards bodies unchanged, so it cannot be combined with {}",
+                    option
+                );
+            }
+        }
+        if let Some(media_type) = self
+            .image_types
+            .iter()
+            .find(|media_type| !media_type.trim().starts_with("image/"))
+        {
+            anyhow::bail!("--image-type '{}' is not an image media type", media_type);
+        }
+        if self.adaptive_timeout_min_secs > self.adaptive_timeout_max_secs {
+            anyhow::bail!(
+                "--adaptive-timeout-min-secs must not exceed --adaptive-timeout-max-secs"
+            );
+        }
+        if self
+            .embedding_upload_budget_mb
+            .is_some_and(|max_mb| max_mb < self.max_body_mb)
+        {
+            anyhow::bail!(
+                "--embedding-upload-budget-mb must be at least {} to fit the largest request body",
+                self.max_body_mb
+            );
+        }
+        if self.admin_token.as_deref().is_some_and(str::is_empty) {
+            anyhow::bail!("--admin-token must not be empty");
+        }
+        if let Some(path) = &self.routes_file {
+            ModelTables::load(path)?;
+        }
+        if let Some(path) = &self.keys_file {
+            keys::check_keys_file(path)?;
+        }
+        if let Some(path) = &self.client_keys_file {
+            client_keys::check_client_keys_file(path)?;
+            if !self.has_api_key() {
+                anyhow::bail!(
+                    "--client-keys-file requires MAPLE_API_KEY, which client key requests are \
+                     sent with"
+                );
+            }
+        }
+        match &self.jwt_jwks_url {
+            Some(url) => {
+                let parsed = reqwest::Url::parse(url);
+                if !parsed.is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+                    anyhow::bail!("--jwt-jwks-url must be an http:// or https:// URL");
+                }
+                if self.jwt_issuer.is_none() || self.jwt_audience.is_none() {
+                    anyhow::bail!("--jwt-jwks-url requires --jwt-issuer and --jwt-audience");
+                }
+                if !self.has_api_key() {
+                    anyhow::bail!(
+                        "--jwt-jwks-url requires MAPLE_API_KEY, which JWT requests are sent with"
+                    );
+                }
+                if self.jwt_key_claim.is_empty() {
+                    anyhow::bail!("--jwt-key-claim must not be empty");
+                }
+            }
+            None if self.jwt_issuer.is_some()
+                || self.jwt_audience.is_some()
+                || self.jwt_key_claim != DEFAULT_JWT_KEY_CLAIM
+                || self.jwt_jwks_refresh_secs != DEFAULT_JWT_JWKS_REFRESH_SECS =>
+            {
+                anyhow::bail!(
+                    "--jwt-issuer, --jwt-audience, --jwt-key-claim, and \
+                     --jwt-jwks-refresh-secs require --jwt-jwks-url"
+                );
+            }
+            None => {}
+        }
+        match &self.redis_url {
+            Some(url) => {
+                RedisStore::new(url, &self.redis_key_prefix)?;
+            }
+            None if self.redis_key_prefix != DEFAULT_REDIS_KEY_PREFIX => {
+                anyhow::bail!("--redis-key-prefix requires --redis-url");
+            }
+            None => {}
+        }
+        if self.audit_db.is_none()
+            && (self.audit_content != AuditContent::Off || self.audit_retention_days.is_some())
+        {
+            anyhow::bail!("--audit-content and --audit-retention-days require --audit-db");
+        }
+        if let Some(path) = &self.audit_db {
+            audit::check_audit_db(path)?;
+        }
+        if let Some(path) = &self.dataset_file {
+            dataset::check_dataset_file(path)?;
+        }
+        if let Some(dir) = &self.batch_dir {
+            batches::create_batch_dir(dir)?;
+        }
+        if self.batch_concurrency == 0 {
+            anyhow::bail!("--batch-concurrency must be at least 1");
+        }
+        if let Some(path) = &self.background_db {
+            background::check_background_db(path)?;
+        }
+        if self.enable_grpc && !cfg!(feature = "grpc") {
+            anyhow::bail!("--grpc requires maple-proxy built with the grpc feature");
+        }
+        if let Some(url) = &self.quarantine_webhook {
+            if !self.quarantine_enabled() {
+                anyhow::bail!("--quarantine-webhook requires a --quarantine-max-* threshold");
+            }
+            let parsed = reqwest::Url::parse(url);
+            if !parsed.is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+                anyhow::bail!("--quarantine-webhook must be an http:// or https:// URL");
+            }
+        }
+        if let Some(url) = &self.alert_webhook {
+            let parsed = reqwest::Url::parse(url);
+            if !parsed.is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+                anyhow::bail!("--alert-webhook must be an http:// or https:// URL");
+            }
+        } else if self.alert_webhook_secret.is_some() {
+            anyhow::bail!("--alert-webhook-secret requires --alert-webhook");
+        } else if self.alert_backend_failures != DEFAULT_ALERT_BACKEND_FAILURES {
+            anyhow::bail!("--alert-backend-failures requires --alert-webhook");
+        }
+        if self.alert_webhook_secret.as_deref() == Some("") {
+            anyhow::bail!("--alert-webhook-secret must not be empty");
+        }
+        for path in &self.honeypot_paths {
+            honeypot::check_decoy_path(path)?;
+        }
+        if !self.honeypot_enabled() && self.honeypot_block_secs != DEFAULT_HONEYPOT_BLOCK_SECS {
+            anyhow::bail!("--honeypot-block-secs requires --honeypot or --honeypot-path");
+        }
+        if self.ip_ban_auth_failures.is_none()
+            && (self.ip_ban_window_secs != DEFAULT_IP_BAN_WINDOW_SECS
+                || self.ip_ban_secs != DEFAULT_IP_BAN_SECS)
+        {
+            anyhow::bail!("--ip-ban-window-secs and --ip-ban-secs require --ip-ban-auth-failures");
+        }
+        let country_rules = self
+            .allow_countries
+            .iter()
+            .chain(&self.deny_countries)
+            .chain(self.country_rate_limits.iter().map(|limit| &limit.country));
+        for country in country_rules {
+            if geo::parse_country(country).as_ref() != Ok(country) {
+                anyhow::bail!(
+                    "'{}' is not an uppercase two-letter ISO country code",
+                    country
+                );
+            }
+        }
+        let has_country_rules = !self.allow_countries.is_empty()
+            || !self.deny_countries.is_empty()
+            || !self.country_rate_limits.is_empty();
+        if has_country_rules && self.geoip_db.is_none() {
+            anyhow::bail!(
+                "--allow-country, --deny-country, and --country-rate-limit require --geoip-db"
+            );
+        }
+        if (!self.allow_asns.is_empty() || !self.deny_asns.is_empty()) && self.asn_db.is_none() {
+            anyhow::bail!("--allow-asn and --deny-asn require --asn-db");
+        }
+        if self.max_concurrent_requests.is_none()
+            && (self.queue_depth != DEFAULT_QUEUE_DEPTH
+                || self.queue_timeout_secs != DEFAULT_QUEUE_TIMEOUT_SECS)
+        {
+            anyhow::bail!(
+                "--queue-depth and --queue-timeout-secs require --max-concurrent-requests"
+            );
+        }
+        if let Some(path) = &self.geoip_db {
+            geo::check_database(path, "--geoip-db")?;
+        }
+        if let Some(path) = &self.asn_db {
+            geo::check_database(path, "--asn-db")?;
+        }
+        for (index, route_pipeline) in self.pipelines.iter().enumerate() {
+            let route = &route_pipeline.route;
+            if !pipeline::INFERENCE_ROUTES.contains(&route.as_str()) {
+                anyhow::bail!(
+                    "--pipeline names '{}', which is not an inference route; expected one of {}",
+                    route,
+                    pipeline::INFERENCE_ROUTES.join(", ")
+                );
+            }
+            if self.pipelines[..index]
+                .iter()
+                .any(|other| &other.route == route)
+            {
+                anyhow::bail!("--pipeline configures '{}' more than once", route);
+            }
+        }
+        for (index, alias) in self.path_aliases.iter().enumerate() {
+            if self.path_aliases[..index]
+                .iter()
+                .any(|other| other.from == alias.from)
+            {
+                anyhow::bail!("--path-alias maps '{}' more than once", alias.from);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether to answer browsers' cross-origin requests
+    pub fn cors_enabled(&self) -> bool {
+        self.enable_cors || !self.cors_origins.is_empty()
+    }
+
+    fn apply_demo_preset(&mut self) {
+        if self.allowed_models.is_empty() {
+            self.allowed_models.push(DEFAULT_DEMO_MODEL.to_string());
+        }
+        self.rate_limit_per_minute
+            .get_or_insert(DEFAULT_DEMO_RATE_LIMIT_PER_MINUTE);
+        self.enable_playground = true;
+        self.redact_logs = true;
+        self.debug = false;
+    }
+
+    /// Create a new Config programmatically (for library usage)
+    pub fn new(host: String, port: u16, backend_url: String) -> Self {
+        Self {
+            host,
+            port,
+            backend_url,
+            fallback_backend_urls: Vec::new(),
+            expected_pcrs: Vec::new(),
+            startup_attestation: StartupAttestation::Off,
+            session_refresh_secs: DEFAULT_SESSION_REFRESH_SECS,
+            default_api_key: None,
+            api_key_file: None,
+            api_keys: Vec::new(),
+            api_key_rotation: KeyRotation::RoundRobin,
+            api_key_cooldown_secs: DEFAULT_API_KEY_COOLDOWN_SECS,
+            api_key_headers: false,
+            query_api_key: false,
+            debug: false,
+            enable_cors: false,
+            cors_origins: Vec::new(),
+            cors_allow_credentials: false,
+            cors_max_age_secs: None,
+            cors_expose_headers: Vec::new(),
+            request_timeout_secs: DEFAULT_REQUEST_TIMEOUT_SECS,
+            stream_idle_timeout_secs: DEFAULT_STREAM_IDLE_TIMEOUT_SECS,
+            image_timeout_secs: DEFAULT_IMAGE_TIMEOUT_SECS,
+            stream_recovery: StreamRecovery::Off,
+            resume_backend_urls: Vec::new(),
+            aggregate_streams: false,
+            coalesce_ms: None,
+            coalesce_bytes: DEFAULT_COALESCE_BYTES,
+            adaptive_timeout: false,
+            adaptive_timeout_min_secs: DEFAULT_ADAPTIVE_TIMEOUT_MIN_SECS,
+            adaptive_timeout_max_secs: DEFAULT_ADAPTIVE_TIMEOUT_MAX_SECS,
+            dns_timeout_ms: None,
+            connect_timeout_ms: None,
+            tls_timeout_ms: None,
+            max_connection_lifetime_secs: None,
+            max_connection_requests: None,
+            header_read_timeout_secs: DEFAULT_HEADER_READ_TIMEOUT_SECS,
+            connection_idle_timeout_secs: DEFAULT_CONNECTION_IDLE_TIMEOUT_SECS,
+            max_connections_per_ip: None,
+            model_aliases: Vec::new(),
+            model_pools: Vec::new(),
+            model_defaults: Vec::new(),
+            system_prompt: None,
+            system_prompt_mode: SystemPromptMode::Prepend,
+            id_format: IdFormat::Backend,
+            id_prefix: DEFAULT_ID_PREFIX.to_string(),
+            snowflake_worker_id: 0,
+            response_cache_ttl_secs: None,
+            response_cache_max_entries: DEFAULT_RESPONSE_CACHE_MAX_ENTRIES,
+            models_cache_ttl_secs: DEFAULT_MODELS_CACHE_TTL_SECS,
+            embedding_cache_max_mb: None,
+            max_body_mb: DEFAULT_MAX_BODY_MB,
+            fetch_images: false,
+            image_max_mb: DEFAULT_IMAGE_MAX_MB,
+            image_types: DEFAULT_IMAGE_TYPES.map(String::from).to_vec(),
+            image_fetch_private: false,
+            max_json_depth: DEFAULT_MAX_JSON_DEPTH,
+            audio_max_mb: DEFAULT_AUDIO_MAX_MB,
+            embedding_upload_budget_mb: None,
+            stream_memory_budget_mb: None,
+            max_concurrent_requests: None,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            queue_timeout_secs: DEFAULT_QUEUE_TIMEOUT_SECS,
+            allow_root: false,
+            run_as_user: None,
+            run_as_group: None,
+            chroot_dir: None,
+            allowed_models: Vec::new(),
+            model_list: Vec::new(),
+            hidden_models: Vec::new(),
+            rate_limit_per_minute: None,
+            trusted_proxies: Vec::new(),
+            forwarded_header: ForwardedHeader::XForwardedFor,
+            quarantine_max_requests_per_minute: None,
+            quarantine_max_auth_failures: None,
+            quarantine_max_blocked_requests: None,
+            quarantine_requests_per_minute: DEFAULT_QUARANTINE_REQUESTS_PER_MINUTE,
+            quarantine_webhook: None,
+            alert_webhook: None,
+            alert_webhook_secret: None,
+            alert_backend_failures: DEFAULT_ALERT_BACKEND_FAILURES,
+            honeypot: false,
+            honeypot_paths: Vec::new(),
+            honeypot_block_secs: DEFAULT_HONEYPOT_BLOCK_SECS,
+            ip_ban_auth_failures: None,
+            ip_ban_window_secs: DEFAULT_IP_BAN_WINDOW_SECS,
+            ip_ban_secs: DEFAULT_IP_BAN_SECS,
+            geoip_db: None,
+            asn_db: None,
+            allow_countries: Vec::new(),
+            deny_countries: Vec::new(),
+            allow_asns: Vec::new(),
+            deny_asns: Vec::new(),
+            country_rate_limits: Vec::new(),
+            model_blackouts: Vec::new(),
+            model_prices: Vec::new(),
+            max_request_cost: None,
+            cost_header: false,
+            max_temperature: None,
+            max_n: None,
+            max_tokens_limit: None,
+            model_max_tokens: Vec::new(),
+            param_limit_action: LimitAction::Clamp,
+            enable_playground: false,
+            enable_metrics: false,
+            server_timing: false,
+            enable_compression: false,
+            slow_request_ms: None,
+            enable_ollama_api: false,
+            enable_azure_api: false,
+            enable_grpc: false,
+            azure_deployments: Vec::new(),
+            pipelines: Vec::new(),
+            path_aliases: Vec::new(),
+            normalize_paths: false,
+            redact_logs: false,
+            openai_upstream_url: None,
+            openai_upstream_api_key: None,
+            openai_upstream_models: Vec::new(),
+            moderation_url: None,
+            moderation_model: None,
+            schema_validation: SchemaValidation::Off,
+            strict_openai: false,
+            compat_profile: None,
+            normalize_tool_calls: false,
+            enforce_response_format: false,
+            repair_json_output: false,
+            response_format_attempts: DEFAULT_RESPONSE_FORMAT_ATTEMPTS,
+            passthrough: false,
+            demo: false,
+            mock_backend: false,
+            mock_tokens_per_second: DEFAULT_MOCK_TOKENS_PER_SECOND,
+            update_check: false,
+            update_channel: ReleaseChannel::Stable,
+            shutdown_report: None,
+            admin_token: None,
+            routes_file: None,
+            keys_file: None,
+            client_keys_file: None,
+            jwt_jwks_url: None,
+            jwt_issuer: None,
+            jwt_audience: None,
+            jwt_key_claim: DEFAULT_JWT_KEY_CLAIM.to_string(),
+            jwt_jwks_refresh_secs: DEFAULT_JWT_JWKS_REFRESH_SECS,
+            redis_url: None,
+            redis_key_prefix: DEFAULT_REDIS_KEY_PREFIX.to_string(),
+            audit_db: None,
+            audit_content: AuditContent::Off,
+            audit_retention_days: None,
+            dataset_file: None,
+            dataset_max_file_mb: DEFAULT_DATASET_MAX_FILE_MB,
+            dataset_max_files: DEFAULT_DATASET_MAX_FILES,
+            batch_dir: None,
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
+            batch_max_retries: DEFAULT_BATCH_MAX_RETRIES,
+            background_db: None,
+            background_ttl_secs: DEFAULT_BACKGROUND_TTL_SECS,
+            idempotency_ttl_secs: DEFAULT_IDEMPOTENCY_TTL_SECS,
+            command: None,
+        }
+    }
+
+    /// Checks the configuration like the CLI does at startup, for library users
+    /// who build it in code, e.g.
+    /// `Config::default().with_port(8081).with_api_key(key).validated()?`
+    pub fn validated(self) -> anyhow::Result<Self> {
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// The primary backend URL followed by any fallbacks, in failover order
+    pub fn backend_urls(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.backend_url.as_str())
+            .chain(self.fallback_backend_urls.iter().map(String::as_str))
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    /// The largest request body accepted, in bytes
+    pub fn max_body_bytes(&self) -> usize {
+        usize::try_from(self.max_body_mb.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX)
+    }
+
+    pub fn stream_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.stream_idle_timeout_secs)
+    }
+
+    pub fn image_timeout(&self) -> Duration {
+        Duration::from_secs(self.image_timeout_secs)
+    }
+
+    /// The shortest and longest adaptive timeouts
+    pub(crate) fn adaptive_timeout_bounds(&self) -> (Duration, Duration) {
+        (
+            Duration::from_secs(self.adaptive_timeout_min_secs),
+            Duration::from_secs(self.adaptive_timeout_max_secs),
+        )
+    }
+
+    /// The schema validation in effect, which `--strict-openai` always enforces
+    pub(crate) fn effective_schema_validation(&self) -> SchemaValidation {
+        if self.strict_openai {
+            SchemaValidation::Enforce
+        } else {
+            self.schema_validation
+        }
+    }
+
+    /// Whether decoy paths are served, by default or configured
+    pub(crate) fn honeypot_enabled(&self) -> bool {
+        self.honeypot || !self.honeypot_paths.is_empty()
+    }
+
+    /// Whether any abuse threshold that quarantines keys is set
+    pub(crate) fn quarantine_enabled(&self) -> bool {
+        self.quarantine_max_requests_per_minute.is_some()
+            || self.quarantine_max_auth_failures.is_some()
+            || self.quarantine_max_blocked_requests.is_some()
+    }
+
+    /// The global system prompt, if one is configured
+    pub(crate) fn system_prompt(&self) -> Option<SystemPrompt> {
+        self.system_prompt.as_ref().map(|content| SystemPrompt {
+            content: content.clone(),
+            mode: self.system_prompt_mode,
+        })
+    }
+
+    /// The limits on client connections, for [`crate::serve`]
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_lifetime: self.max_connection_lifetime_secs.map(Duration::from_secs),
+            max_requests: self.max_connection_requests,
+            header_read_timeout: Some(Duration::from_secs(self.header_read_timeout_secs)),
+            idle_timeout: (self.connection_idle_timeout_secs > 0)
+                .then(|| Duration::from_secs(self.connection_idle_timeout_secs)),
+            max_per_ip: self.max_connections_per_ip,
+        }
+    }
+
+    pub(crate) fn connect_timeouts(&self) -> ConnectTimeouts {
+        ConnectTimeouts {
+            dns: self.dns_timeout_ms.map(Duration::from_millis),
+            connect: self.connect_timeout_ms.map(Duration::from_millis),
+            tls: self.tls_timeout_ms.map(Duration::from_millis),
+        }
+    }
+
+    /// Builder-style method to set the address to bind to
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    /// Builder-style method to set the port to bind to
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Builder-style method to set the primary backend URL
+    pub fn with_backend_url(mut self, backend_url: impl Into<String>) -> Self {
+        self.backend_url = backend_url.into();
+        self
+    }
+
+    /// Builder-style method to add a fallback backend URL
+    pub fn with_fallback_backend_url(mut self, backend_url: impl Into<String>) -> Self {
+        self.fallback_backend_urls.push(backend_url.into());
+        self
+    }
+
+    /// Builder-style method to attest backends before serving
+    pub fn with_startup_attestation(mut self, startup_attestation: StartupAttestation) -> Self {
+        self.startup_attestation = startup_attestation;
+        self
+    }
+
+    /// Builder-style method to set how long before expiry pooled sessions
+    /// are re-attested, or 0 to let them expire
+    pub fn with_session_refresh_secs(mut self, session_refresh_secs: u64) -> Self {
+        self.session_refresh_secs = session_refresh_secs;
+        self
+    }
+
+    /// Builder-style method to pin a measurement backends must attest to
+    pub fn with_expected_pcr(mut self, expected_pcr: ExpectedPcr) -> Self {
+        self.expected_pcrs.push(expected_pcr);
+        self
+    }
+
+    /// Builder-style method to set the API key
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        self.default_api_key = Some(api_key);
+        self
+    }
+
+    /// Builder-style method to spread requests over a pool of API keys
+    pub fn with_api_keys(
+        mut self,
+        api_keys: impl IntoIterator<Item = impl Into<String>>,
+        rotation: KeyRotation,
+    ) -> Self {
+        self.api_keys = api_keys.into_iter().map(Into::into).collect();
+        self.api_key_rotation = rotation;
+        self
+    }
+
+    /// Builder-style method to read the API key from a file, and again
+    /// whenever the file changes
+    pub fn with_api_key_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.api_key_file = Some(path.into());
+        self
+    }
+
+    /// Builder-style method to accept API keys in `api-key` and `x-api-key`
+    /// headers
+    pub fn with_api_key_headers(mut self, api_key_headers: bool) -> Self {
+        self.api_key_headers = api_key_headers;
+        self
+    }
+
+    /// Builder-style method to accept API keys in an `api_key` query parameter
+    pub fn with_query_api_key(mut self, query_api_key: bool) -> Self {
+        self.query_api_key = query_api_key;
+        self
+    }
+
+    /// Builder-style method to enable debug mode
+    pub fn with_debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Builder-style method to enable CORS
+    pub fn with_cors(mut self, enable_cors: bool) -> Self {
+        self.enable_cors = enable_cors;
+        self
+    }
+
+    /// Builder-style method to allow CORS from specific origins only
+    pub fn with_cors_origins(mut self, cors_origins: Vec<String>) -> Self {
+        self.cors_origins = cors_origins;
+        self
+    }
+
+    /// Builder-style method to let browsers send credentials to allowed origins
+    pub fn with_cors_allow_credentials(mut self, cors_allow_credentials: bool) -> Self {
+        self.cors_allow_credentials = cors_allow_credentials;
+        self
+    }
+
+    /// Builder-style method to set how long browsers cache preflight responses
+    pub fn with_cors_max_age_secs(mut self, cors_max_age_secs: u64) -> Self {
+        self.cors_max_age_secs = Some(cors_max_age_secs);
+        self
+    }
+
+    /// Builder-style method to expose response headers to browser scripts
+    pub fn with_cors_expose_headers(mut self, cors_expose_headers: Vec<String>) -> Self {
+        self.cors_expose_headers = cors_expose_headers;
+        self
+    }
+
+    /// Builder-style method to set the backend request timeout
+    pub fn with_request_timeout_secs(mut self, request_timeout_secs: u64) -> Self {
+        self.request_timeout_secs = request_timeout_secs;
+        self
+    }
+
+    /// Builder-style method to set the streaming idle timeout
+    pub fn with_stream_idle_timeout_secs(mut self, stream_idle_timeout_secs: u64) -> Self {
+        self.stream_idle_timeout_secs = stream_idle_timeout_secs;
+        self
+    }
+
+    /// Builder-style method to set the image generation timeout
+    pub fn with_image_timeout_secs(mut self, image_timeout_secs: u64) -> Self {
+        self.image_timeout_secs = image_timeout_secs;
+        self
+    }
+
+    /// Builder-style method to set what happens to chat completion streams the
+    /// backend cuts off
+    pub fn with_stream_recovery(mut self, stream_recovery: StreamRecovery) -> Self {
+        self.stream_recovery = stream_recovery;
+        self
+    }
+
+    /// Builder-style method to add a backend URL known to continue a final
+    /// assistant message, so its streams can be resumed
+    pub fn with_resume_backend_url(mut self, backend_url: impl Into<String>) -> Self {
+        self.resume_backend_urls.push(backend_url.into());
+        self
+    }
+
+    /// Builder-style method to answer non-streaming chat completions from
+    /// backend streams
+    pub fn with_aggregate_streams(mut self, aggregate_streams: bool) -> Self {
+        self.aggregate_streams = aggregate_streams;
+        self
+    }
+
+    /// Builder-style method to merge streamed text deltas for `interval_ms`, or
+    /// until they hold `max_bytes`
+    pub fn with_coalescing(mut self, interval_ms: u64, max_bytes: u64) -> Self {
+        self.coalesce_ms = Some(interval_ms);
+        self.coalesce_bytes = max_bytes;
+        self
+    }
+
+    /// Builder-style method to time chat completions out by their length and
+    /// the model's observed speed, within `min_secs` and `max_secs`
+    pub fn with_adaptive_timeout(mut self, min_secs: u64, max_secs: u64) -> Self {
+        self.adaptive_timeout = true;
+        self.adaptive_timeout_min_secs = min_secs;
+        self.adaptive_timeout_max_secs = max_secs;
+        self
+    }
+
+    /// Builder-style method to limit backend host name resolution
+    pub fn with_dns_timeout_ms(mut self, dns_timeout_ms: u64) -> Self {
+        self.dns_timeout_ms = Some(dns_timeout_ms);
+        self
+    }
+
+    /// Builder-style method to limit opening backend connections
+    pub fn with_connect_timeout_ms(mut self, connect_timeout_ms: u64) -> Self {
+        self.connect_timeout_ms = Some(connect_timeout_ms);
+        self
+    }
+
+    /// Builder-style method to limit TLS handshakes with backends
+    pub fn with_tls_timeout_ms(mut self, tls_timeout_ms: u64) -> Self {
+        self.tls_timeout_ms = Some(tls_timeout_ms);
+        self
+    }
+
+    /// Builder-style method to limit how long client connections stay open
+    pub fn with_max_connection_lifetime_secs(mut self, max_connection_lifetime_secs: u64) -> Self {
+        self.max_connection_lifetime_secs = Some(max_connection_lifetime_secs);
+        self
+    }
+
+    /// Builder-style method to limit the requests per client connection
+    pub fn with_max_connection_requests(mut self, max_connection_requests: u64) -> Self {
+        self.max_connection_requests = Some(max_connection_requests);
+        self
+    }
+
+    /// Builder-style method to limit how long clients may take to send headers
+    pub fn with_header_read_timeout_secs(mut self, header_read_timeout_secs: u64) -> Self {
+        self.header_read_timeout_secs = header_read_timeout_secs;
+        self
+    }
+
+    /// Builder-style method to close client connections left idle (0 disables
+    /// it)
+    pub fn with_connection_idle_timeout_secs(mut self, idle_timeout_secs: u64) -> Self {
+        self.connection_idle_timeout_secs = idle_timeout_secs;
+        self
+    }
+
+    /// Builder-style method to limit the open connections per client IP
+    pub fn with_max_connections_per_ip(mut self, max_connections_per_ip: u32) -> Self {
+        self.max_connections_per_ip = Some(max_connections_per_ip);
+        self
+    }
+
+    /// Builder-style method to enable the response cache
+    pub fn with_response_cache(mut self, ttl_secs: u64, max_entries: usize) -> Self {
+        self.response_cache_ttl_secs = Some(ttl_secs);
+        self.response_cache_max_entries = max_entries;
+        self
+    }
+
+    /// Builder-style method to set the `/v1/models` cache TTL (0 disables it)
+    pub fn with_models_cache_ttl_secs(mut self, models_cache_ttl_secs: u64) -> Self {
+        self.models_cache_ttl_secs = models_cache_ttl_secs;
+        self
+    }
+
+    /// Builder-style method to enable the embedding cache
+    pub fn with_embedding_cache(mut self, max_mb: u64) -> Self {
+        self.embedding_cache_max_mb = Some(max_mb);
+        self
+    }
+
+    /// Builder-style method to set the largest request body accepted
+    pub fn with_max_body_mb(mut self, max_mb: u64) -> Self {
+        self.max_body_mb = max_mb;
+        self
+    }
+
+    /// Builder-style method to inline remote images of up to `max_mb`
+    /// megabytes as data URLs
+    pub fn with_image_fetching(mut self, max_mb: u64) -> Self {
+        self.fetch_images = true;
+        self.image_max_mb = max_mb;
+        self
+    }
+
+    /// Builder-style method to set the deepest JSON nesting accepted
+    pub fn with_max_json_depth(mut self, max_depth: u32) -> Self {
+        self.max_json_depth = max_depth;
+        self
+    }
+
+    /// Builder-style method to set the largest input_audio part accepted
+    pub fn with_audio_max_mb(mut self, max_mb: u64) -> Self {
+        self.audio_max_mb = max_mb;
+        self
+    }
+
+    /// Builder-style method to bound the memory held by embedding uploads
+    pub fn with_embedding_upload_budget(mut self, max_mb: u64) -> Self {
+        self.embedding_upload_budget_mb = Some(max_mb);
+        self
+    }
+
+    /// Builder-style method to bound the memory held by response streams
+    pub fn with_stream_memory_budget(mut self, max_mb: u64) -> Self {
+        self.stream_memory_budget_mb = Some(max_mb);
+        self
+    }
+
+    /// Builder-style method to cap the inference requests handled at once
+    pub fn with_max_concurrent_requests(mut self, max_requests: u32) -> Self {
+        self.max_concurrent_requests = Some(max_requests);
+        self
+    }
+
+    /// Builder-style method to set how many requests may wait for a slot and
+    /// for how long
+    pub fn with_queue(mut self, depth: u32, timeout_secs: u64) -> Self {
+        self.queue_depth = depth;
+        self.queue_timeout_secs = timeout_secs;
+        self
+    }
+
+    /// Builder-style method to add a model alias
+    pub fn with_model_alias(mut self, alias: impl Into<String>, model: impl Into<String>) -> Self {
+        self.model_aliases.push(ModelAlias::new(alias, model));
+        self
+    }
+
+    /// Builder-style method to add a weighted model pool
+    pub fn with_model_pool(mut self, pool: ModelPool) -> Self {
+        self.model_pools.push(pool);
+        self
+    }
+
+    /// Builder-style method to add a model's default sampling parameters
+    pub fn with_model_defaults(mut self, defaults: ModelDefaults) -> Self {
+        self.model_defaults.push(defaults);
+        self
+    }
+
+    /// Builder-style method to set the system prompt added to chat completions
+    pub fn with_system_prompt(mut self, system_prompt: String, mode: SystemPromptMode) -> Self {
+        self.system_prompt = Some(system_prompt);
+        self.system_prompt_mode = mode;
+        self
+    }
+
+    /// Builder-style method to mint chat completion and request IDs
+    pub fn with_id_format(mut self, id_format: IdFormat, id_prefix: impl Into<String>) -> Self {
+        self.id_format = id_format;
+        self.id_prefix = id_prefix.into();
+        self
+    }
+
+    /// Builder-style method to set the worker ID in snowflake IDs
+    pub fn with_snowflake_worker_id(mut self, worker_id: u16) -> Self {
+        self.snowflake_worker_id = worker_id;
+        self
+    }
+
+    /// Builder-style method to restrict the models the proxy will serve
+    pub fn with_allowed_models(mut self, allowed_models: Vec<String>) -> Self {
+        self.allowed_models = allowed_models;
+        self
+    }
+
+    /// Builder-style method to replace the backend's model list
+    pub fn with_model_list(mut self, model_list: Vec<String>) -> Self {
+        self.model_list = model_list;
+        self
+    }
+
+    /// Builder-style method to hide models from clients
+    pub fn with_hidden_models(mut self, hidden_models: Vec<String>) -> Self {
+        self.hidden_models = hidden_models;
+        self
+    }
+
+    /// Builder-style method to set the per-client rate limit
+    pub fn with_rate_limit_per_minute(mut self, rate_limit_per_minute: u32) -> Self {
+        self.rate_limit_per_minute = Some(rate_limit_per_minute);
+        self
+    }
+
+    /// Builder-style method to trust forwarded client addresses from proxies
+    pub fn with_trusted_proxies(mut self, trusted_proxies: Vec<TrustedProxy>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    /// Builder-style method to choose the header trusted proxies write the
+    /// client's address to
+    pub fn with_forwarded_header(mut self, forwarded_header: ForwardedHeader) -> Self {
+        self.forwarded_header = forwarded_header;
+        self
+    }
+
+    /// Builder-style method to quarantine keys that trip any of the given
+    /// per-minute thresholds
+    pub fn with_quarantine(
+        mut self,
+        max_requests_per_minute: Option<u32>,
+        max_auth_failures: Option<u32>,
+        max_blocked_requests: Option<u32>,
+    ) -> Self {
+        self.quarantine_max_requests_per_minute = max_requests_per_minute;
+        self.quarantine_max_auth_failures = max_auth_failures;
+        self.quarantine_max_blocked_requests = max_blocked_requests;
+        self
+    }
+
+    /// Builder-style method to set how many requests a minute quarantined
+    /// keys are allowed
+    pub fn with_quarantine_requests_per_minute(mut self, requests_per_minute: u32) -> Self {
+        self.quarantine_requests_per_minute = requests_per_minute;
+        self
+    }
+
+    /// Builder-style method to send quarantine alerts to a webhook
+    pub fn with_quarantine_webhook(mut self, url: impl Into<String>) -> Self {
+        self.quarantine_webhook = Some(url.into());
+        self
+    }
+
+    /// Builder-style method to send operator alerts to a webhook
+    pub fn with_alert_webhook(mut self, url: impl Into<String>) -> Self {
+        self.alert_webhook = Some(url.into());
+        self
+    }
+
+    /// Builder-style method to sign alerts with an HMAC secret
+    pub fn with_alert_webhook_secret(mut self, secret: impl Into<String>) -> Self {
+        self.alert_webhook_secret = Some(secret.into());
+        self
+    }
+
+    /// Builder-style method to set how many failed requests in a row make a
+    /// backend worth an alert
+    pub fn with_alert_backend_failures(mut self, failures: u32) -> Self {
+        self.alert_backend_failures = failures;
+        self
+    }
+
+    /// Builder-style method to serve the default decoy paths
+    pub fn with_honeypot(mut self, honeypot: bool) -> Self {
+        self.honeypot = honeypot;
+        self
+    }
+
+    /// Builder-style method to add a decoy path
+    pub fn with_honeypot_path(mut self, path: impl Into<String>) -> Self {
+        self.honeypot_paths.push(path.into());
+        self
+    }
+
+    /// Builder-style method to set how long IPs that probe a decoy are
+    /// blocked, or 0 to only flag them
+    pub fn with_honeypot_block_secs(mut self, secs: u64) -> Self {
+        self.honeypot_block_secs = secs;
+        self
+    }
+
+    /// Builder-style method to ban IPs that fail authentication `max_failures`
+    /// times within `window_secs`, for `ban_secs`
+    pub fn with_ip_bans(mut self, max_failures: u32, window_secs: u64, ban_secs: u64) -> Self {
+        self.ip_ban_auth_failures = Some(max_failures);
+        self.ip_ban_window_secs = window_secs;
+        self.ip_ban_secs = ban_secs;
+        self
+    }
+
+    /// Builder-style method to locate clients with a MaxMind country database
+    pub fn with_geoip_db(mut self, path: impl Into<PathBuf>) -> Self {
+        self.geoip_db = Some(path.into());
+        self
+    }
+
+    /// Builder-style method to look up clients' networks in a MaxMind ASN
+    /// database
+    pub fn with_asn_db(mut self, path: impl Into<PathBuf>) -> Self {
+        self.asn_db = Some(path.into());
+        self
+    }
+
+    /// Builder-style method to serve clients located in a country
+    pub fn with_allowed_country(mut self, country: &str) -> Self {
+        self.allow_countries.push(country.to_ascii_uppercase());
+        self
+    }
+
+    /// Builder-style method to refuse clients located in a country
+    pub fn with_denied_country(mut self, country: &str) -> Self {
+        self.deny_countries.push(country.to_ascii_uppercase());
+        self
+    }
+
+    /// Builder-style method to serve clients in an autonomous system
+    pub fn with_allowed_asn(mut self, asn: u32) -> Self {
+        self.allow_asns.push(asn);
+        self
+    }
+
+    /// Builder-style method to refuse clients in an autonomous system
+    pub fn with_denied_asn(mut self, asn: u32) -> Self {
+        self.deny_asns.push(asn);
+        self
+    }
+
+    /// Builder-style method to add a per-country rate limit
+    pub fn with_country_rate_limit(mut self, limit: CountryRateLimit) -> Self {
+        self.country_rate_limits.push(limit);
+        self
+    }
+
+    /// Builder-style method to refuse a model during some hours
+    pub fn with_model_blackout(mut self, blackout: ModelBlackout) -> Self {
+        self.model_blackouts.push(blackout);
+        self
+    }
+
+    /// Builder-style method to add a model's price
+    pub fn with_model_price(mut self, price: ModelPrice) -> Self {
+        self.model_prices.push(price);
+        self
+    }
+
+    /// Builder-style method to set the default per-request cost ceiling
+    pub fn with_max_request_cost(mut self, max_request_cost: f64) -> Self {
+        self.max_request_cost = Some(max_request_cost);
+        self
+    }
+
+    /// Builder-style method to report estimated costs in a response header
+    pub fn with_cost_header(mut self, cost_header: bool) -> Self {
+        self.cost_header = cost_header;
+        self
+    }
+
+    /// Builder-style method to cap chat completions' `temperature`
+    pub fn with_max_temperature(mut self, max_temperature: f64) -> Self {
+        self.max_temperature = Some(max_temperature);
+        self
+    }
+
+    /// Builder-style method to cap chat completions' `n`
+    pub fn with_max_n(mut self, max_n: u32) -> Self {
+        self.max_n = Some(max_n);
+        self
+    }
 
-    /// Default API key for Maple/OpenSecret (can be overridden by client Authorization header)
-    #[arg(long, env = "MAPLE_API_KEY")]
-    pub default_api_key: Option<String>,
+    /// Builder-style method to cap the tokens chat completions may generate
+    pub fn with_max_tokens_limit(mut self, max_tokens_limit: u64) -> Self {
+        self.max_tokens_limit = Some(max_tokens_limit);
+        self
+    }
 
-    /// Enable debug logging
-    #[arg(short, long, env = "MAPLE_DEBUG")]
-    pub debug: bool,
+    /// Builder-style method to cap one model's generated tokens
+    pub fn with_model_max_tokens(mut self, limit: ModelTokenLimit) -> Self {
+        self.model_max_tokens.push(limit);
+        self
+    }
 
-    /// Enable CORS for all origins (useful for web clients)
-    #[arg(long, env = "MAPLE_ENABLE_CORS")]
-    pub enable_cors: bool,
+    /// Builder-style method to choose between clamping and rejecting
+    /// parameters over their limits
+    pub fn with_param_limit_action(mut self, param_limit_action: LimitAction) -> Self {
+        self.param_limit_action = param_limit_action;
+        self
+    }
 
-    /// Timeout for backend request setup and non-streaming responses, in seconds
-    #[arg(
-        long,
-        env = "MAPLE_REQUEST_TIMEOUT_SECS",
-        default_value_t = DEFAULT_REQUEST_TIMEOUT_SECS,
-        value_parser = clap::value_parser!(u64).range(1..)
-    )]
-    pub request_timeout_secs: u64,
+    /// Builder-style method to enable the browser playground
+    pub fn with_playground(mut self, enable_playground: bool) -> Self {
+        self.enable_playground = enable_playground;
+        self
+    }
 
-    /// Maximum time to wait between streaming response chunks, in seconds
-    #[arg(
-        long,
-        env = "MAPLE_STREAM_IDLE_TIMEOUT_SECS",
-        default_value_t = DEFAULT_STREAM_IDLE_TIMEOUT_SECS,
-        value_parser = clap::value_parser!(u64).range(1..)
-    )]
-    pub stream_idle_timeout_secs: u64,
-}
+    /// Builder-style method to enable the metrics endpoint
+    pub fn with_metrics(mut self, enable_metrics: bool) -> Self {
+        self.enable_metrics = enable_metrics;
+        self
+    }
 
-impl Config {
-    pub fn socket_addr(&self) -> anyhow::Result<SocketAddr> {
-        let addr = format!("{}:{}", self.host, self.port);
-        addr.parse()
-            .map_err(|e| anyhow::anyhow!("Invalid socket address '{}': {}", addr, e))
+    /// Builder-style method to report backend timing in a Server-Timing header
+    pub fn with_server_timing(mut self, server_timing: bool) -> Self {
+        self.server_timing = server_timing;
+        self
     }
 
-    pub fn load() -> Self {
-        // Load from .env file if it exists
-        let _ = dotenvy::dotenv();
+    /// Builder-style method to compress responses and accept compressed bodies
+    pub fn with_compression(mut self, enable_compression: bool) -> Self {
+        self.enable_compression = enable_compression;
+        self
+    }
 
-        Config::parse()
+    /// Builder-style method to log requests slower than `threshold_ms`
+    pub fn with_slow_request_ms(mut self, threshold_ms: u64) -> Self {
+        self.slow_request_ms = Some(threshold_ms);
+        self
     }
 
-    /// Create a new Config programmatically (for library usage)
-    pub fn new(host: String, port: u16, backend_url: String) -> Self {
-        Self {
-            host,
-            port,
-            backend_url,
-            default_api_key: None,
-            debug: false,
-            enable_cors: false,
-            request_timeout_secs: DEFAULT_REQUEST_TIMEOUT_SECS,
-            stream_idle_timeout_secs: DEFAULT_STREAM_IDLE_TIMEOUT_SECS,
-        }
+    /// Builder-style method to enable the Ollama-compatible endpoints
+    pub fn with_ollama_api(mut self, enable_ollama_api: bool) -> Self {
+        self.enable_ollama_api = enable_ollama_api;
+        self
     }
 
-    pub fn request_timeout(&self) -> Duration {
-        Duration::from_secs(self.request_timeout_secs)
+    /// Builder-style method to enable the Azure OpenAI-style endpoints
+    pub fn with_azure_api(mut self, enable_azure_api: bool) -> Self {
+        self.enable_azure_api = enable_azure_api;
+        self
     }
 
-    pub fn stream_idle_timeout(&self) -> Duration {
-        Duration::from_secs(self.stream_idle_timeout_secs)
+    /// Builder-style method to serve the gRPC service
+    pub fn with_grpc(mut self, enable_grpc: bool) -> Self {
+        self.enable_grpc = enable_grpc;
+        self
     }
 
-    /// Builder-style method to set the API key
-    pub fn with_api_key(mut self, api_key: String) -> Self {
-        self.default_api_key = Some(api_key);
+    /// Builder-style method to map an Azure deployment name to a model
+    pub fn with_azure_deployment(
+        mut self,
+        deployment: impl Into<String>,
+        model: impl Into<String>,
+    ) -> Self {
+        self.azure_deployments
+            .push(ModelAlias::new(deployment, model));
         self
     }
 
-    /// Builder-style method to enable debug mode
-    pub fn with_debug(mut self, debug: bool) -> Self {
-        self.debug = debug;
+    /// Builder-style method to choose the stages a route runs
+    pub fn with_pipeline(mut self, pipeline: RoutePipeline) -> Self {
+        self.pipelines.push(pipeline);
         self
     }
 
-    /// Builder-style method to enable CORS
-    pub fn with_cors(mut self, enable_cors: bool) -> Self {
-        self.enable_cors = enable_cors;
+    /// Builder-style method to serve a path as another route
+    pub fn with_path_alias(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.path_aliases.push(PathAlias::new(from, to));
         self
     }
 
-    /// Builder-style method to set the backend request timeout
-    pub fn with_request_timeout_secs(mut self, request_timeout_secs: u64) -> Self {
-        self.request_timeout_secs = request_timeout_secs;
+    /// Builder-style method to serve unversioned and trailing-slash paths
+    pub fn with_normalize_paths(mut self, normalize_paths: bool) -> Self {
+        self.normalize_paths = normalize_paths;
         self
     }
 
-    /// Builder-style method to set the streaming idle timeout
-    pub fn with_stream_idle_timeout_secs(mut self, stream_idle_timeout_secs: u64) -> Self {
-        self.stream_idle_timeout_secs = stream_idle_timeout_secs;
+    /// Builder-style method to enable log redaction
+    pub fn with_redacted_logs(mut self, redact_logs: bool) -> Self {
+        self.redact_logs = redact_logs;
+        self
+    }
+
+    /// Builder-style method to route models to a plain OpenAI-compatible upstream
+    pub fn with_openai_upstream(mut self, url: impl Into<String>, models: Vec<String>) -> Self {
+        self.openai_upstream_url = Some(url.into());
+        self.openai_upstream_models = models;
+        self
+    }
+
+    /// Builder-style method to set the OpenAI-compatible upstream's API key
+    pub fn with_openai_upstream_api_key(mut self, api_key: String) -> Self {
+        self.openai_upstream_api_key = Some(api_key);
+        self
+    }
+
+    /// Builder-style method to serve moderations from a local classifier
+    pub fn with_moderation_url(mut self, url: impl Into<String>) -> Self {
+        self.moderation_url = Some(url.into());
+        self
+    }
+
+    /// Builder-style method to set the model of moderations that name none
+    pub fn with_moderation_model(mut self, model: impl Into<String>) -> Self {
+        self.moderation_model = Some(model.into());
+        self
+    }
+
+    /// Builder-style method to set the OpenAI schema validation mode
+    pub fn with_schema_validation(mut self, schema_validation: SchemaValidation) -> Self {
+        self.schema_validation = schema_validation;
+        self
+    }
+
+    /// Builder-style method to reject anything outside the OpenAI schemas
+    pub fn with_strict_openai(mut self, strict_openai: bool) -> Self {
+        self.strict_openai = strict_openai;
+        self
+    }
+
+    /// Builder-style method to set the default client compatibility profile
+    pub fn with_compat_profile(mut self, compat_profile: CompatProfile) -> Self {
+        self.compat_profile = Some(compat_profile);
+        self
+    }
+
+    /// Builder-style method to repair off-spec tool calls in chat completions
+    pub fn with_normalize_tool_calls(mut self, normalize_tool_calls: bool) -> Self {
+        self.normalize_tool_calls = normalize_tool_calls;
+        self
+    }
+
+    /// Builder-style method to hold non-streaming chat completions to their
+    /// `response_format`, sending each request up to `attempts` times
+    pub fn with_response_format_enforcement(mut self, attempts: u32) -> Self {
+        self.enforce_response_format = true;
+        self.response_format_attempts = attempts;
+        self
+    }
+
+    /// Builder-style method to repair JSON completions that do not parse
+    pub fn with_json_repair(mut self, repair_json_output: bool) -> Self {
+        self.repair_json_output = repair_json_output;
+        self
+    }
+
+    /// Builder-style method to forward bodies without rewriting them
+    pub fn with_passthrough(mut self, passthrough: bool) -> Self {
+        self.passthrough = passthrough;
+        self
+    }
+
+    /// Builder-style method to enable daily update checks on a release channel
+    pub fn with_update_check(mut self, update_channel: ReleaseChannel) -> Self {
+        self.update_check = true;
+        self.update_channel = update_channel;
+        self
+    }
+
+    /// Builder-style method to write the shutdown report to a file
+    pub fn with_shutdown_report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.shutdown_report = Some(path.into());
+        self
+    }
+
+    /// Builder-style method to enable the admin API
+    pub fn with_admin_token(mut self, admin_token: impl Into<String>) -> Self {
+        self.admin_token = Some(admin_token.into());
+        self
+    }
+
+    /// Builder-style method to persist admin API changes to a file
+    pub fn with_routes_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.routes_file = Some(path.into());
+        self
+    }
+
+    /// Builder-style method to share limits, usage, and caches through Redis
+    pub fn with_redis(mut self, url: impl Into<String>, key_prefix: impl Into<String>) -> Self {
+        self.redis_url = Some(url.into());
+        self.redis_key_prefix = key_prefix.into();
+        self
+    }
+
+    /// Builder-style method to persist virtual keys to a file
+    pub fn with_keys_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.keys_file = Some(path.into());
+        self
+    }
+
+    /// Builder-style method to require clients to present a key from a
+    /// client keys file
+    pub fn with_client_keys_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.client_keys_file = Some(path.into());
+        self
+    }
+
+    /// Builder-style method to accept JWTs signed by a key at a JWKS URL,
+    /// charged to the virtual key named by their `key_claim` claim
+    pub fn with_jwt_auth(
+        mut self,
+        jwks_url: impl Into<String>,
+        issuer: impl Into<String>,
+        audience: impl Into<String>,
+        key_claim: impl Into<String>,
+    ) -> Self {
+        self.jwt_jwks_url = Some(jwks_url.into());
+        self.jwt_issuer = Some(issuer.into());
+        self.jwt_audience = Some(audience.into());
+        self.jwt_key_claim = key_claim.into();
+        self
+    }
+
+    /// Builder-style method to record requests in an audit database
+    pub fn with_audit_db(
+        mut self,
+        path: impl Into<PathBuf>,
+        content: AuditContent,
+        retention_days: Option<u64>,
+    ) -> Self {
+        self.audit_db = Some(path.into());
+        self.audit_content = content;
+        self.audit_retention_days = retention_days;
+        self
+    }
+
+    /// Builder-style method to record finished chat completions as a dataset
+    pub fn with_dataset_file(
+        mut self,
+        path: impl Into<PathBuf>,
+        max_file_mb: u64,
+        max_files: usize,
+    ) -> Self {
+        self.dataset_file = Some(path.into());
+        self.dataset_max_file_mb = max_file_mb;
+        self.dataset_max_files = max_files;
+        self
+    }
+
+    /// Builder-style method to serve the Batch API from files in `dir`
+    pub fn with_batch_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.batch_dir = Some(dir.into());
+        self
+    }
+
+    /// Builder-style method to keep background completions in a database
+    pub fn with_background_db(mut self, path: impl Into<PathBuf>) -> Self {
+        self.background_db = Some(path.into());
+        self
+    }
+
+    /// Builder-style method to set how long `Idempotency-Key` responses are
+    /// replayed, or 0 to ignore the header
+    pub fn with_idempotency_ttl_secs(mut self, idempotency_ttl_secs: u64) -> Self {
+        self.idempotency_ttl_secs = idempotency_ttl_secs;
+        self
+    }
+
+    /// Builder-style method to serve synthetic responses instead of Maple
+    pub fn with_mock_backend(mut self, mock_backend: bool) -> Self {
+        self.mock_backend = mock_backend;
+        self
+    }
+
+    /// Builder-style method to set how fast the mock backend streams
+    pub fn with_mock_tokens_per_second(mut self, mock_tokens_per_second: u32) -> Self {
+        self.mock_tokens_per_second = mock_tokens_per_second;
+        self
+    }
+
+    /// Builder-style method to apply the public demo preset
+    pub fn with_demo(mut self) -> Self {
+        self.demo = true;
+        self.apply_demo_preset();
         self
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub(crate) struct OpenAIError {
     error: OpenAIErrorDetails,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 struct OpenAIErrorDetails {
     message: String,
     #[serde(rename = "type")]
@@ -156,6 +2825,58 @@ impl OpenAIError {
     pub(crate) fn server_error(message: impl Into<String>) -> Self {
         Self::new(message, "server_error")
     }
+
+    pub(crate) fn invalid_request_error(message: impl Into<String>) -> Self {
+        Self::new(message, "invalid_request_error")
+    }
+
+    pub(crate) fn model_not_found(model: &str) -> Self {
+        Self::invalid_request_error(format!(
+            "The model `{}` does not exist or you do not have access to it.",
+            model
+        ))
+        .with_param("model")
+        .with_code("model_not_found")
+    }
+
+    pub(crate) fn rate_limit_error(message: impl Into<String>) -> Self {
+        Self::new(message, "rate_limit_error").with_code("rate_limit_exceeded")
+    }
+
+    pub(crate) fn insufficient_quota(message: impl Into<String>) -> Self {
+        Self::new(message, "insufficient_quota").with_code("insufficient_quota")
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        &self.error.message
+    }
+
+    pub(crate) fn with_param(mut self, param: impl Into<String>) -> Self {
+        self.error.param = Some(param.into());
+        self
+    }
+
+    pub(crate) fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.error.code = Some(code.into());
+        self
+    }
+}
+
+/// Browsers send `Origin` as `scheme://host[:port]`, so anything else would
+/// never match
+fn validate_cors_origin(origin: &str) -> anyhow::Result<()> {
+    let valid = HeaderValue::from_str(origin).is_ok()
+        && !origin.ends_with('/')
+        && origin.parse::<Uri>().is_ok_and(|uri| {
+            uri.scheme().is_some() && uri.authority().is_some() && uri.path() == "/"
+        });
+    if !valid {
+        anyhow::bail!(
+            "Invalid --cors-origin '{}': expected scheme://host[:port] without a path",
+            origin
+        );
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -163,6 +2884,38 @@ mod tests {
     use super::*;
     use clap::{error::ErrorKind, Parser};
 
+    #[test]
+    fn default_matches_the_cli_defaults() {
+        let parsed = Config::try_parse_from(["maple-proxy"]).unwrap();
+        assert_eq!(format!("{:?}", Config::default()), format!("{:?}", parsed));
+
+        let config = Config::default()
+            .with_port(8081)
+            .with_api_key("key".to_string())
+            .validated()
+            .unwrap();
+        assert_eq!(config.socket_addr().unwrap().port(), 8081);
+        assert!(Config::default().with_admin_token("").validated().is_err());
+        assert!(Config::default()
+            .with_embedding_upload_budget(10)
+            .validated()
+            .is_err());
+        assert!(Config::default()
+            .with_max_body_mb(10)
+            .with_embedding_upload_budget(10)
+            .validated()
+            .is_ok());
+        assert!(Config::default()
+            .with_adaptive_timeout(60, 30)
+            .validated()
+            .is_err());
+        assert!(Config::default()
+            .with_passthrough(true)
+            .with_compat_profile(CompatProfile::OpenaiPython)
+            .validated()
+            .is_err());
+    }
+
     #[test]
     fn config_new_uses_timeout_defaults() {
         let config = Config::new(
@@ -210,4 +2963,263 @@ mod tests {
             Config::try_parse_from(["maple-proxy", "--stream-idle-timeout-secs", "0"]).unwrap_err();
         assert_eq!(stream_idle_timeout_error.kind(), ErrorKind::ValueValidation);
     }
+
+    #[test]
+    fn backend_urls_list_primary_before_fallbacks() {
+        let config = Config::try_parse_from([
+            "maple-proxy",
+            "--backend-url",
+            "https://primary.example",
+            "--fallback-backend-url",
+            "https://secondary.example,https://tertiary.example",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            config.backend_urls().collect::<Vec<_>>(),
+            vec![
+                "https://primary.example",
+                "https://secondary.example",
+                "https://tertiary.example"
+            ]
+        );
+    }
+
+    #[test]
+    fn demo_preset_locks_down_without_overriding_explicit_choices() {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            8080,
+            "https://enclave.trymaple.ai".to_string(),
+        )
+        .with_debug(true)
+        .with_rate_limit_per_minute(3)
+        .with_demo();
+
+        assert_eq!(config.allowed_models, vec![DEFAULT_DEMO_MODEL.to_string()]);
+        assert_eq!(config.rate_limit_per_minute, Some(3));
+        assert!(config.enable_playground);
+        assert!(config.redact_logs);
+        assert!(!config.debug);
+        assert!(config.validate().is_err());
+        assert!(config
+            .with_api_key("demo-key".to_string())
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn hidden_models_cannot_be_listed_or_pooled() {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            8080,
+            "https://enclave.trymaple.ai".to_string(),
+        )
+        .with_hidden_models(vec!["internal-eval".to_string()]);
+
+        assert!(config
+            .clone()
+            .with_model_list(vec!["internal-eval".to_string()])
+            .validate()
+            .is_err());
+        assert!(config
+            .clone()
+            .with_model_pool("eval=internal-eval:1".parse().unwrap())
+            .validate()
+            .is_err());
+        assert!(config
+            .with_model_list(vec!["llama3-3-70b".to_string()])
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn openai_upstream_needs_url_and_models_together() {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            8080,
+            "https://enclave.trymaple.ai".to_string(),
+        );
+
+        assert!(config
+            .clone()
+            .with_openai_upstream("http://localhost:11434", Vec::new())
+            .validate()
+            .is_err());
+        assert!(config
+            .with_openai_upstream("http://localhost:11434", vec!["llama3.2".to_string()])
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn cors_settings_are_validated() {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            8080,
+            "https://enclave.trymaple.ai".to_string(),
+        );
+        assert!(!config.cors_enabled());
+
+        let origins = config
+            .clone()
+            .with_cors_origins(vec!["https://app.example.com".to_string()]);
+        assert!(origins.cors_enabled());
+        assert!(origins
+            .clone()
+            .with_cors_allow_credentials(true)
+            .with_cors_expose_headers(vec!["X-Maple-Cache".to_string()])
+            .validate()
+            .is_ok());
+
+        assert!(config
+            .clone()
+            .with_cors(true)
+            .with_cors_allow_credentials(true)
+            .validate()
+            .is_err());
+        for origin in [
+            "app.example.com",
+            "https://app.example.com/",
+            "https://a.com/app",
+        ] {
+            assert!(config
+                .clone()
+                .with_cors_origins(vec![origin.to_string()])
+                .validate()
+                .is_err());
+        }
+        assert!(origins
+            .with_cors_expose_headers(vec!["not a header".to_string()])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn model_aliases_parse_from_repeated_flags() {
+        let config = Config::try_parse_from([
+            "maple-proxy",
+            "--model-alias",
+            "gpt-4=qwen3-coder-480b",
+            "--model-alias",
+            "gpt-3.5-turbo=llama3-3-70b",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            config.model_aliases,
+            vec![
+                ModelAlias::new("gpt-4", "qwen3-coder-480b"),
+                ModelAlias::new("gpt-3.5-turbo", "llama3-3-70b"),
+            ]
+        );
+
+        let error = Config::try_parse_from(["maple-proxy", "--model-alias", "gpt-4"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn cost_ceilings_are_validated() {
+        let config = Config::try_parse_from([
+            "maple-proxy",
+            "--model-price",
+            "llama3-3-70b=0.9/0.9,gemma4-31b=0.2/0.4",
+            "--max-request-cost",
+            "0.05",
+        ])
+        .unwrap();
+        assert_eq!(config.model_prices.len(), 2);
+        assert_eq!(config.max_request_cost, Some(0.05));
+        assert!(config.validate().is_ok());
+
+        assert!(config
+            .clone()
+            .with_max_request_cost(0.0)
+            .validate()
+            .is_err());
+        assert!(config
+            .clone()
+            .with_max_request_cost(f64::INFINITY)
+            .validate()
+            .is_err());
+        assert!(
+            Config::try_parse_from(["maple-proxy", "--max-request-cost", "1"])
+                .unwrap()
+                .validate()
+                .is_err()
+        );
+        assert!(
+            Config::try_parse_from(["maple-proxy", "--model-price", "llama3-3-70b=1"]).is_err()
+        );
+    }
+
+    #[test]
+    fn model_pools_are_validated() {
+        let config = Config::try_parse_from([
+            "maple-proxy",
+            "--model-pool",
+            "fast=llama3-3-70b:70,gemma4-31b:30",
+        ])
+        .unwrap();
+        assert_eq!(config.model_pools[0].members.len(), 2);
+        assert!(config.validate().is_ok());
+
+        assert!(config
+            .clone()
+            .with_model_alias("fast", "llama3-3-70b")
+            .validate()
+            .is_err());
+        assert!(config
+            .clone()
+            .with_allowed_models(vec!["llama3-3-70b".to_string()])
+            .validate()
+            .is_err());
+        assert!(
+            Config::try_parse_from(["maple-proxy", "--model-pool", "fast=llama3-3-70b"]).is_err()
+        );
+    }
+
+    #[test]
+    fn param_limits_are_validated() {
+        let config = Config::try_parse_from([
+            "maple-proxy",
+            "--max-temperature",
+            "1.2",
+            "--model-max-tokens",
+            "llama3-3-70b=4096,gemma4-31b=2048",
+            "--param-limit-action",
+            "reject",
+        ])
+        .unwrap();
+        assert_eq!(config.model_max_tokens.len(), 2);
+        assert_eq!(config.param_limit_action, LimitAction::Reject);
+        assert!(config.validate().is_ok());
+
+        assert!(config.clone().with_max_temperature(2.5).validate().is_err());
+        assert!(config
+            .with_model_max_tokens("llama3-3-70b=1024".parse().unwrap())
+            .validate()
+            .is_err());
+    }
+    #[test]
+    fn pipelines_are_validated() {
+        let config = Config::try_parse_from([
+            "maple-proxy",
+            "--pipeline",
+            "/v1/tokenize=none,/v1/chat/completions=metrics>hooks>rate_limit",
+        ])
+        .unwrap();
+        assert_eq!(config.pipelines.len(), 2);
+        assert!(config.validate().is_ok());
+
+        assert!(config
+            .clone()
+            .with_pipeline("/v1/tokenize=metrics".parse().unwrap())
+            .validate()
+            .is_err());
+        assert!(config
+            .with_pipeline("/admin/keys=none".parse().unwrap())
+            .validate()
+            .is_err());
+    }
 }
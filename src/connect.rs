@@ -0,0 +1,258 @@
+use axum::http::Uri;
+use std::{
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+/// A stage of reaching a backend before any inference request is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum ConnectPhase {
+    Dns,
+    Connect,
+    Tls,
+    Attestation,
+}
+
+impl ConnectPhase {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Connect => "connect",
+            Self::Tls => "tls",
+            Self::Attestation => "attestation",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum PhaseOutcome {
+    Ok,
+    Timeout,
+    Error,
+}
+
+impl PhaseOutcome {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Timeout => "timeout",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Per-phase limits for reaching a backend. Phases without one are bounded
+/// by the request timeout.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConnectTimeouts {
+    pub(crate) dns: Option<Duration>,
+    pub(crate) connect: Option<Duration>,
+    pub(crate) tls: Option<Duration>,
+}
+
+impl ConnectTimeouts {
+    /// Whether any phase is limited, which is when backends are probed
+    pub(crate) fn any(&self) -> bool {
+        self.dns.is_some() || self.connect.is_some() || self.tls.is_some()
+    }
+}
+
+/// A phase that failed or ran out of time
+#[derive(Debug, Clone)]
+pub(crate) struct PhaseFailure {
+    pub(crate) phase: ConnectPhase,
+    pub(crate) outcome: PhaseOutcome,
+    pub(crate) limit: Duration,
+    detail: String,
+}
+
+impl fmt::Display for PhaseFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.outcome {
+            PhaseOutcome::Timeout => write!(
+                f,
+                "{} phase timed out after {} ms",
+                self.phase.as_str(),
+                self.limit.as_millis()
+            ),
+            _ => write!(f, "{} phase failed: {}", self.phase.as_str(), self.detail),
+        }
+    }
+}
+
+/// Resolves `backend_url`, opens a TCP connection to it, and for `https`
+/// backends with a TLS timeout completes a TLS handshake, each under its own
+/// limit, reporting every phase to `record`. The connection is then dropped;
+/// it only shows that the backend is reachable in time.
+pub(crate) async fn probe_backend(
+    backend_url: &str,
+    timeouts: ConnectTimeouts,
+    fallback: Duration,
+    record: &impl Fn(ConnectPhase, PhaseOutcome, Duration),
+) -> Result<(), PhaseFailure> {
+    let invalid = |detail: String| PhaseFailure {
+        phase: ConnectPhase::Dns,
+        outcome: PhaseOutcome::Error,
+        limit: fallback,
+        detail,
+    };
+    let uri: Uri = backend_url
+        .parse()
+        .map_err(|_| invalid(format!("invalid backend URL '{}'", backend_url)))?;
+    let host = uri
+        .host()
+        .ok_or_else(|| invalid(format!("backend URL '{}' has no host", backend_url)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let https = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let dns = timeouts.dns.unwrap_or(fallback);
+    let addrs: Vec<SocketAddr> = run_phase(ConnectPhase::Dns, dns, record, async {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+            .await?
+            .collect();
+        if addrs.is_empty() {
+            return Err(std::io::Error::other("no addresses found"));
+        }
+        Ok(addrs)
+    })
+    .await?;
+
+    let connect = timeouts.connect.unwrap_or(fallback);
+    let stream = run_phase(
+        ConnectPhase::Connect,
+        connect,
+        record,
+        TcpStream::connect(&addrs[..]),
+    )
+    .await?;
+
+    if let (true, Some(tls)) = (https, timeouts.tls) {
+        let server_name = ServerName::try_from(host).map_err(|error| PhaseFailure {
+            phase: ConnectPhase::Tls,
+            outcome: PhaseOutcome::Error,
+            limit: tls,
+            detail: error.to_string(),
+        })?;
+        run_phase(
+            ConnectPhase::Tls,
+            tls,
+            record,
+            tls_connector().connect(server_name, stream),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Runs one phase under its limit and records how it went
+async fn run_phase<T, E: fmt::Display>(
+    phase: ConnectPhase,
+    limit: Duration,
+    record: &impl Fn(ConnectPhase, PhaseOutcome, Duration),
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, PhaseFailure> {
+    let started_at = Instant::now();
+    let result = tokio::time::timeout(limit, future).await;
+    let elapsed = started_at.elapsed();
+
+    let (outcome, detail) = match result {
+        Ok(Ok(value)) => {
+            record(phase, PhaseOutcome::Ok, elapsed);
+            return Ok(value);
+        }
+        Ok(Err(error)) => (PhaseOutcome::Error, error.to_string()),
+        Err(_) => (PhaseOutcome::Timeout, String::new()),
+    };
+    record(phase, outcome, elapsed);
+    Err(PhaseFailure {
+        phase,
+        outcome,
+        limit,
+        detail,
+    })
+}
+
+/// Verifies backends against the Mozilla root certificates, like the
+/// `reqwest` client used for plain upstreams
+fn tls_connector() -> TlsConnector {
+    static CONNECTOR: OnceLock<TlsConnector> = OnceLock::new();
+    CONNECTOR
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("the ring provider supports the default protocol versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            TlsConnector::from(Arc::new(config))
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    fn timeouts(tls: Option<Duration>) -> ConnectTimeouts {
+        ConnectTimeouts {
+            dns: Some(Duration::from_secs(5)),
+            connect: Some(Duration::from_secs(5)),
+            tls,
+        }
+    }
+
+    #[tokio::test]
+    async fn probes_each_phase_and_reports_the_one_that_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let phases = Mutex::new(Vec::new());
+        let record = |phase: ConnectPhase, outcome: PhaseOutcome, _: Duration| {
+            phases.lock().unwrap().push((phase, outcome));
+        };
+
+        let url = format!("http://127.0.0.1:{}", port);
+        probe_backend(&url, timeouts(None), Duration::from_secs(5), &record)
+            .await
+            .unwrap();
+        assert_eq!(
+            phases.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                (ConnectPhase::Dns, PhaseOutcome::Ok),
+                (ConnectPhase::Connect, PhaseOutcome::Ok),
+            ]
+        );
+
+        // The listener accepts connections but never answers the TLS hello
+        let url = format!("https://127.0.0.1:{}", port);
+        let tls = Some(Duration::from_millis(50));
+        let failure = probe_backend(&url, timeouts(tls), Duration::from_secs(5), &record)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.phase, ConnectPhase::Tls);
+        assert_eq!(failure.outcome, PhaseOutcome::Timeout);
+        assert_eq!(failure.to_string(), "tls phase timed out after 50 ms");
+
+        drop(listener);
+        let url = format!("http://127.0.0.1:{}", port);
+        let failure = probe_backend(&url, timeouts(None), Duration::from_secs(5), &record)
+            .await
+            .unwrap_err();
+        assert_eq!(failure.phase, ConnectPhase::Connect);
+        assert_eq!(failure.outcome, PhaseOutcome::Error);
+    }
+}
@@ -0,0 +1,152 @@
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Uri},
+    middleware::Next,
+    response::Response,
+};
+
+/// Anthropic's and Azure OpenAI's key headers, which `--api-key-headers`
+/// accepts in place of `Authorization: Bearer`, in order of preference
+pub(crate) const KEY_HEADERS: [HeaderName; 2] = [
+    HeaderName::from_static("x-api-key"),
+    HeaderName::from_static("api-key"),
+];
+
+/// The query parameter `--query-api-key` reads
+const API_KEY_PARAM: &str = "api_key";
+
+/// The key in an `x-api-key` or else an `api-key` header
+pub(crate) fn header_key(headers: &HeaderMap) -> Option<String> {
+    KEY_HEADERS
+        .iter()
+        .filter_map(|name| headers.get(name)?.to_str().ok())
+        .map(str::trim)
+        .find(|key| !key.is_empty())
+        .map(str::to_string)
+}
+
+/// Moves an API key sent in the `api_key` query parameter into the
+/// `Authorization` header, unless the client sent one, and takes it out of the
+/// URI so it is not forwarded. Only the inference routes are given this, so
+/// admin tokens are never accepted in URLs.
+pub(crate) async fn accept_query_key(mut request: Request, next: Next) -> Response {
+    if let Some((uri, key)) = take_query_key(request.uri()) {
+        *request.uri_mut() = uri;
+        let bearer = HeaderValue::from_str(&format!("Bearer {}", key.trim()))
+            .ok()
+            .filter(|_| !request.headers().contains_key(header::AUTHORIZATION));
+        if let Some(mut bearer) = bearer {
+            bearer.set_sensitive(true);
+            request.headers_mut().insert(header::AUTHORIZATION, bearer);
+        }
+    }
+    next.run(request).await
+}
+
+/// `uri` without its `api_key` query parameters, for request logs
+pub(crate) fn without_query_key(uri: &Uri) -> Uri {
+    take_query_key(uri).map_or_else(|| uri.clone(), |(uri, _)| uri)
+}
+
+/// The URI without its `api_key` query parameters, and the first one's
+/// decoded value. The remaining parameters are encoded again.
+fn take_query_key(uri: &Uri) -> Option<(Uri, String)> {
+    let query = uri.query()?;
+    let mut key = None;
+    let mut rest = form_urlencoded::Serializer::new(String::new());
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        if name == API_KEY_PARAM {
+            key.get_or_insert(value);
+        } else {
+            rest.append_pair(&name, &value);
+        }
+    }
+    let key = key?;
+    let rest = rest.finish();
+    let path_and_query = if rest.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), rest)
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Some((Uri::from_parts(parts).ok()?, key.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use axum::{
+        body::{to_bytes, Body},
+        http::{self, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    #[test]
+    fn query_keys_are_decoded_and_taken_out_of_the_uri() {
+        let uri: Uri = "/v1/models?api_key=sk%2Btest%3D&limit=5".parse().unwrap();
+        let (uri, key) = take_query_key(&uri).unwrap();
+        assert_eq!(key, "sk+test=");
+        assert_eq!(uri, "/v1/models?limit=5");
+
+        let uri: Uri = "/v1/models?api_key=sk-test".parse().unwrap();
+        assert_eq!(take_query_key(&uri).unwrap().0, "/v1/models");
+        let uri: Uri = "/v1/models?limit=5".parse().unwrap();
+        assert!(take_query_key(&uri).is_none());
+        assert_eq!(without_query_key(&uri), uri);
+    }
+
+    #[tokio::test]
+    async fn query_keys_become_bearer_tokens() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|uri: Uri, headers: HeaderMap| async move {
+                    let authorization = &headers[header::AUTHORIZATION];
+                    format!("{} {}", uri, authorization.to_str().unwrap())
+                }),
+            )
+            .layer(middleware::from_fn(accept_query_key));
+
+        for (uri, authorization, expected) in [
+            ("/?api_key=sk%2Bquery&x=1", None, "/?x=1 Bearer sk+query"),
+            (
+                "/?api_key=sk-query",
+                Some("Bearer sk-header"),
+                "/ Bearer sk-header",
+            ),
+        ] {
+            let mut request = http::Request::get(uri);
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(String::from_utf8_lossy(&body), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_tokens_are_not_accepted_from_other_sources() {
+        let config = Config::default()
+            .with_api_key_headers(true)
+            .with_query_api_key(true)
+            .with_admin_token("admin-secret");
+        let app = crate::create_app(config);
+        let requests = [
+            http::Request::get("/admin/keys").header("x-api-key", "admin-secret"),
+            http::Request::get("/admin/keys").header("api-key", "admin-secret"),
+            http::Request::get("/admin/keys?api_key=admin-secret"),
+        ];
+        for request in requests {
+            let request = request.body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
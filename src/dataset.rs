@@ -0,0 +1,436 @@
+use crate::{proxy::ProxyError, sse::SseParser, storage};
+use anyhow::Context;
+use axum::{body::Body, http::StatusCode, response::Response};
+use futures::StreamExt;
+use serde_json::{json, Map, Value};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+};
+use tracing::{error, warn};
+
+/// Conversations waiting to be written; past this, new ones are dropped with
+/// a warning rather than holding up requests
+const DATASET_QUEUE_LEN: usize = 1024;
+
+/// Responses past this many bytes are not recorded
+const MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Replies with more tool calls than this are not recorded
+const MAX_TOOL_CALLS: usize = 128;
+
+/// Fields of a chat completion request that OpenAI's fine-tuning format keeps
+const RECORDED_FIELDS: [&str; 3] = ["messages", "tools", "parallel_tool_calls"];
+
+/// Appends completed chat completions to a JSONL file in OpenAI's
+/// fine-tuning format, on a thread of its own. The file is rotated once it
+/// reaches its size limit.
+pub(crate) struct DatasetRecorder {
+    conversations: SyncSender<Value>,
+}
+
+impl DatasetRecorder {
+    /// Opens or creates the file, keeping up to `max_files` rotated files
+    /// beside it as `<path>.1`, `<path>.2`, and so on
+    pub(crate) fn open(path: &Path, max_bytes: u64, max_files: usize) -> anyhow::Result<Self> {
+        let file = RotatingFile::open(path, max_bytes, max_files)?;
+        let (conversations, received) = mpsc::sync_channel(DATASET_QUEUE_LEN);
+        thread::Builder::new()
+            .name("dataset".to_string())
+            .spawn(move || write_conversations(file, received))
+            .context("Failed to start the dataset writer")?;
+        Ok(Self { conversations })
+    }
+
+    /// Starts recording a chat completion request. `None` when the body has
+    /// no messages to record.
+    pub(crate) fn start(&self, body: &[u8]) -> Option<PendingConversation> {
+        let request: Value = serde_json::from_slice(body).ok()?;
+        request.get("messages")?.as_array()?;
+        let conversation: Map<String, Value> = RECORDED_FIELDS
+            .iter()
+            .filter_map(|field| Some((field.to_string(), request.get(*field)?.clone())))
+            .collect();
+
+        Some(PendingConversation {
+            conversations: self.conversations.clone(),
+            conversation,
+            parser: SseParser::default(),
+            response: Vec::new(),
+            reply: Reply::default(),
+            too_large: false,
+        })
+    }
+}
+
+/// Checks that the dataset file can be opened for appending
+pub(crate) fn check_dataset_file(path: &Path) -> anyhow::Result<()> {
+    open_append(path).map(drop)
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    storage::open_append(path)
+        .with_context(|| format!("Failed to open the dataset file {}", path.display()))
+}
+
+fn write_conversations(mut file: RotatingFile, received: Receiver<Value>) {
+    for conversation in received {
+        let mut line = conversation.to_string();
+        line.push('\n');
+        if let Err(write_error) = file.append(line.as_bytes()) {
+            error!(
+                "Failed to write a conversation to {}: {}",
+                file.path.display(),
+                write_error
+            );
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: usize) -> anyhow::Result<Self> {
+        let file = open_append(path)?;
+        let len = file.metadata().map_or(0, |metadata| metadata.len());
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            len,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    /// Appends a line, first rotating the file if the line would take it
+    /// past its size limit. A line larger than the limit gets a file of its
+    /// own.
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `<path>.N` to `<path>.N+1`, dropping the oldest, and starts a
+    /// new file
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            let rotated = rotated_path(&self.path, index);
+            if rotated.exists() {
+                fs::rename(&rotated, rotated_path(&self.path, index + 1))?;
+            }
+        }
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = storage::open_append(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = OsString::from(path.as_os_str());
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+/// The assistant's reply in the first choice, built up from a completion's
+/// `message` or a stream's deltas
+#[derive(Debug, Default)]
+struct Reply {
+    content: String,
+    tool_calls: Vec<Value>,
+    finish_reason: Option<String>,
+    too_many_tool_calls: bool,
+}
+
+impl Reply {
+    fn observe(&mut self, completion: &Value, field: &str) {
+        let Some(choice) = completion["choices"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|choice| choice["index"].as_u64().unwrap_or(0) == 0)
+        else {
+            return;
+        };
+        let message = &choice[field];
+        if let Some(content) = message["content"].as_str() {
+            self.content.push_str(content);
+        }
+        let tool_calls = message["tool_calls"].as_array().into_iter().flatten();
+        for (position, call) in tool_calls.enumerate() {
+            let index = call["index"]
+                .as_u64()
+                .map_or(position, |index| index as usize);
+            self.observe_tool_call(index, call);
+        }
+        if let Some(finish_reason) = choice["finish_reason"].as_str() {
+            self.finish_reason = Some(finish_reason.to_string());
+        }
+    }
+
+    /// Streams send a tool call's ID and name once and its arguments in
+    /// pieces
+    fn observe_tool_call(&mut self, index: usize, call: &Value) {
+        if index >= MAX_TOOL_CALLS {
+            self.too_many_tool_calls = true;
+            return;
+        }
+        while self.tool_calls.len() <= index {
+            self.tool_calls.push(json!({
+                "id": "",
+                "type": "function",
+                "function": {"name": "", "arguments": ""},
+            }));
+        }
+        let tool_call = &mut self.tool_calls[index];
+        if let Some(id) = call["id"].as_str() {
+            tool_call["id"] = Value::from(id);
+        }
+        if let Some(name) = call["function"]["name"].as_str() {
+            tool_call["function"]["name"] = Value::from(name);
+        }
+        if let Some(arguments) = call["function"]["arguments"].as_str() {
+            let recorded = tool_call["function"]["arguments"]
+                .as_str()
+                .unwrap_or_default();
+            tool_call["function"]["arguments"] = Value::from(format!("{}{}", recorded, arguments));
+        }
+    }
+
+    /// The reply as an assistant message, if the model finished it
+    fn into_message(self) -> Option<Value> {
+        let finished = matches!(self.finish_reason.as_deref(), Some("stop" | "tool_calls"));
+        if !finished || self.too_many_tool_calls {
+            return None;
+        }
+        if self.tool_calls.is_empty() {
+            return (!self.content.is_empty())
+                .then(|| json!({"role": "assistant", "content": self.content}));
+        }
+        let content = (!self.content.is_empty()).then_some(self.content);
+        Some(json!({"role": "assistant", "content": content, "tool_calls": self.tool_calls}))
+    }
+}
+
+/// A chat completion that is written once its whole response has been sent.
+/// Failed, cut-off, and abandoned responses are not recorded.
+pub(crate) struct PendingConversation {
+    conversations: SyncSender<Value>,
+    conversation: Map<String, Value>,
+    parser: SseParser,
+    /// A non-streaming response body
+    response: Vec<u8>,
+    reply: Reply,
+    too_large: bool,
+}
+
+impl PendingConversation {
+    /// Follows a successful response's body as it is sent
+    pub(crate) fn finish(
+        self,
+        result: Result<Response, ProxyError>,
+    ) -> Result<Response, ProxyError> {
+        let response = match result {
+            Ok(response) if response.status() == StatusCode::OK => response,
+            other => return other,
+        };
+
+        let streamed = crate::proxy::is_event_stream(response.headers());
+        let (parts, body) = response.into_parts();
+        let mut stream = body.into_data_stream();
+        let body = Body::from_stream(async_stream::stream! {
+            let mut pending = self;
+            while let Some(chunk) = stream.next().await {
+                if let Ok(bytes) = &chunk {
+                    pending.observe(bytes, streamed);
+                }
+                yield chunk;
+            }
+            pending.complete(streamed);
+        });
+        Ok(Response::from_parts(parts, body))
+    }
+
+    fn observe(&mut self, bytes: &[u8], streamed: bool) {
+        if self.too_large {
+            return;
+        }
+        if streamed {
+            for data in self.parser.push(bytes) {
+                if let Ok(chunk) = serde_json::from_str::<Value>(&data) {
+                    self.reply.observe(&chunk, "delta");
+                }
+            }
+            self.too_large = self.reply.content.len() > MAX_RESPONSE_BYTES;
+        } else if self.response.len() + bytes.len() <= MAX_RESPONSE_BYTES {
+            self.response.extend_from_slice(bytes);
+        } else {
+            self.too_large = true;
+        }
+    }
+
+    fn complete(mut self, streamed: bool) {
+        if self.too_large {
+            return;
+        }
+        if !streamed {
+            let Ok(completion) = serde_json::from_slice::<Value>(&self.response) else {
+                return;
+            };
+            self.reply.observe(&completion, "message");
+        }
+        let Some(message) = self.reply.into_message() else {
+            return;
+        };
+        if let Some(Value::Array(messages)) = self.conversation.get_mut("messages") {
+            messages.push(message);
+        }
+
+        match self
+            .conversations
+            .try_send(Value::Object(self.conversation))
+        {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Dataset writer is behind; dropped a conversation"),
+            Err(TrySendError::Disconnected(_)) => error!("Dataset writer has stopped"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use std::time::Duration;
+
+    fn read_lines(path: &Path, expected: usize) -> Vec<Value> {
+        for _ in 0..100 {
+            let lines: Vec<Value> = fs::read_to_string(path)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect();
+            if lines.len() >= expected {
+                return lines;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} did not get {} lines", path.display(), expected);
+    }
+
+    #[tokio::test]
+    async fn records_finished_conversations_in_fine_tuning_format() {
+        let dir = std::env::temp_dir().join(format!("maple-dataset-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("conversations.jsonl");
+        let recorder = DatasetRecorder::open(&path, 1024 * 1024, 2).unwrap();
+
+        let request = br#"{"model":"m","stream":true,"messages":[{"role":"user","content":"Hi"}]}"#;
+        let pending = recorder.start(request).unwrap();
+        let response = Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(Body::from(concat!(
+                "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello \"}}]}\n\n",
+                "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"there\"},",
+                "\"finish_reason\":\"stop\"}]}\n\n",
+                "data: [DONE]\n\n"
+            )))
+            .unwrap();
+        let response = pending.finish(Ok(response)).unwrap();
+        to_bytes(response.into_body(), 1024).await.unwrap();
+
+        // Cut off at the token limit, so not recorded
+        let pending = recorder.start(br#"{"messages":[]}"#).unwrap();
+        let response = Response::new(Body::from(
+            r#"{"choices":[{"index":0,"message":{"content":"Hm"},"finish_reason":"length"}]}"#,
+        ));
+        let response = pending.finish(Ok(response)).unwrap();
+        to_bytes(response.into_body(), 1024).await.unwrap();
+
+        let pending = recorder
+            .start(br#"{"messages":[{"role":"user","content":"Weather?"}],"tools":[]}"#)
+            .unwrap();
+        let response = Response::new(Body::from(
+            r#"{"choices":[{"index":0,"finish_reason":"tool_calls","message":{"role":"assistant",
+            "content":null,"tool_calls":[{"id":"call_1","type":"function",
+            "function":{"name":"weather","arguments":"{}"}}]}}]}"#,
+        ));
+        let response = pending.finish(Ok(response)).unwrap();
+        to_bytes(response.into_body(), 1024).await.unwrap();
+
+        assert!(recorder.start(br#"{"input":"not a chat"}"#).is_none());
+
+        let lines = read_lines(&path, 2);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            lines[0],
+            json!({"messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello there"},
+            ]})
+        );
+        assert_eq!(lines[1]["tools"], json!([]));
+        assert_eq!(
+            lines[1]["messages"][1],
+            json!({"role": "assistant", "content": null, "tool_calls": [
+                {"id": "call_1", "type": "function",
+                    "function": {"name": "weather", "arguments": "{}"}},
+            ]})
+        );
+    }
+
+    #[test]
+    fn streamed_tool_calls_are_assembled() {
+        let mut reply = Reply::default();
+        for chunk in [
+            json!({"choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0, "id": "call_1",
+                "function": {"name": "weather", "arguments": "{\"city\":"}}]}}]}),
+            json!({"choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0,
+                "function": {"arguments": "\"Oslo\"}"}}]}}]}),
+            json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "tool_calls"}]}),
+        ] {
+            reply.observe(&chunk, "delta");
+        }
+        assert_eq!(
+            reply.into_message().unwrap()["tool_calls"][0]["function"],
+            json!({"name": "weather", "arguments": "{\"city\":\"Oslo\"}"})
+        );
+    }
+
+    #[test]
+    fn full_files_are_rotated() {
+        let dir = std::env::temp_dir().join(format!("maple-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("conversations.jsonl");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.append(line.as_bytes()).unwrap();
+        }
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap_or_default();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(rotated_path(&path, 1)), "third\n");
+        assert_eq!(read(rotated_path(&path, 2)), "second\n");
+        assert!(!rotated_path(&path, 3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
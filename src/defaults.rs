@@ -0,0 +1,188 @@
+use axum::body::Bytes;
+use serde_json::{json, Value};
+use std::{fmt, str::FromStr};
+
+/// Sampling parameters filled into a model's chat completion requests that
+/// leave them out, as
+/// `MODEL=temperature=0.2,max_tokens=1024,top_p=0.9,stop=###|END`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelDefaults {
+    pub model: String,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u64>,
+    pub top_p: Option<f64>,
+    /// Stop sequences, `|`-separated in the option
+    pub stop: Vec<String>,
+}
+
+impl FromStr for ModelDefaults {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected MODEL=PARAM=VALUE,PARAM=VALUE, got '{}'", value);
+        let (model, params) = value.split_once('=').ok_or_else(expected)?;
+        let model = model.trim();
+        if model.is_empty() {
+            return Err(expected());
+        }
+
+        let mut defaults = Self {
+            model: model.to_string(),
+            ..Self::default()
+        };
+        for param in params.split(',') {
+            let (name, setting) = param.split_once('=').ok_or_else(expected)?;
+            let invalid = || format!("invalid {} in '{}'", name.trim(), value);
+            let number = |range: std::ops::RangeInclusive<f64>| {
+                setting
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|number| range.contains(number))
+                    .ok_or_else(invalid)
+            };
+            match name.trim() {
+                "temperature" => defaults.temperature = Some(number(0.0..=2.0)?),
+                "top_p" => defaults.top_p = Some(number(0.0..=1.0)?),
+                "max_tokens" => {
+                    let max_tokens = setting.trim().parse::<u64>().ok().filter(|max| *max > 0);
+                    defaults.max_tokens = Some(max_tokens.ok_or_else(invalid)?);
+                }
+                "stop" => defaults.stop = setting.split('|').map(str::to_string).collect(),
+                other => {
+                    return Err(format!(
+                        "unknown parameter '{}' in '{}'; expected temperature, max_tokens, top_p, \
+                         or stop",
+                        other, value
+                    ))
+                }
+            }
+        }
+        Ok(defaults)
+    }
+}
+
+impl fmt::Display for ModelDefaults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut params = Vec::new();
+        if let Some(temperature) = self.temperature {
+            params.push(format!("temperature={}", temperature));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            params.push(format!("max_tokens={}", max_tokens));
+        }
+        if let Some(top_p) = self.top_p {
+            params.push(format!("top_p={}", top_p));
+        }
+        if !self.stop.is_empty() {
+            params.push(format!("stop={}", self.stop.join("|")));
+        }
+        write!(f, "{}={}", self.model, params.join(","))
+    }
+}
+
+impl ModelDefaults {
+    fn params(&self) -> Vec<(&'static str, Value)> {
+        let mut params = Vec::new();
+        if let Some(temperature) = self.temperature {
+            params.push(("temperature", json!(temperature)));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            params.push(("max_tokens", json!(max_tokens)));
+        }
+        if let Some(top_p) = self.top_p {
+            params.push(("top_p", json!(top_p)));
+        }
+        if !self.stop.is_empty() {
+            params.push(("stop", json!(self.stop)));
+        }
+        params
+    }
+}
+
+/// Fills in the defaults for a chat completion request's model that the
+/// request leaves out or sets to `null`. A request with
+/// `max_completion_tokens` keeps it instead of getting `max_tokens`.
+pub(crate) fn apply_model_defaults(all_defaults: &[ModelDefaults], body: Bytes) -> Bytes {
+    let Ok(Value::Object(mut request)) = serde_json::from_slice::<Value>(&body) else {
+        return body;
+    };
+    let Some(defaults) = request
+        .get("model")
+        .and_then(Value::as_str)
+        .and_then(|model| all_defaults.iter().find(|defaults| defaults.model == model))
+    else {
+        return body;
+    };
+
+    let mut changed = false;
+    for (name, value) in defaults.params() {
+        let limited = name == "max_tokens"
+            && request
+                .get("max_completion_tokens")
+                .is_some_and(|max| !max.is_null());
+        if limited || request.get(name).is_some_and(|set| !set.is_null()) {
+            continue;
+        }
+        request.insert(name.to_string(), value);
+        changed = true;
+    }
+    if !changed {
+        return body;
+    }
+    serde_json::to_vec(&request)
+        .map(Bytes::from)
+        .unwrap_or(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_defaults() {
+        let defaults: ModelDefaults = "llama3-3-70b=temperature=0.2,max_tokens=1024,stop=###|END"
+            .parse()
+            .unwrap();
+        assert_eq!(defaults.model, "llama3-3-70b");
+        assert_eq!(defaults.temperature, Some(0.2));
+        assert_eq!(defaults.max_tokens, Some(1024));
+        assert_eq!(defaults.top_p, None);
+        assert_eq!(defaults.stop, ["###", "END"]);
+        assert_eq!(
+            defaults.to_string(),
+            "llama3-3-70b=temperature=0.2,max_tokens=1024,stop=###|END"
+        );
+
+        for invalid in [
+            "m",
+            "=temperature=1",
+            "m=temperature=3",
+            "m=max_tokens=0",
+            "m=seed=1",
+        ] {
+            assert!(invalid.parse::<ModelDefaults>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn fills_in_only_what_the_request_leaves_out() {
+        let defaults = vec!["m=temperature=0.2,max_tokens=512,top_p=0.9"
+            .parse()
+            .unwrap()];
+        let body = Bytes::from_static(br#"{"model":"m","temperature":1,"top_p":null}"#);
+        let filled: Value = serde_json::from_slice(&apply_model_defaults(&defaults, body)).unwrap();
+        assert_eq!(
+            filled,
+            json!({"model": "m", "temperature": 1, "top_p": 0.9, "max_tokens": 512})
+        );
+
+        let limited = Bytes::from_static(br#"{"model":"m","max_completion_tokens":64}"#);
+        let filled: Value =
+            serde_json::from_slice(&apply_model_defaults(&defaults, limited)).unwrap();
+        assert!(filled.get("max_tokens").is_none());
+
+        let other = Bytes::from_static(br#"{"model":"other"}"#);
+        assert_eq!(apply_model_defaults(&defaults, other.clone()), other);
+    }
+}
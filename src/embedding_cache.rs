@@ -0,0 +1,463 @@
+use crate::{
+    cache::entry_id,
+    wire::{self, Usage},
+};
+use axum::body::Bytes;
+use dashmap::DashMap;
+use serde_json::{json, Value};
+use std::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+/// Once over budget, the least recently used vectors are evicted until the
+/// cache is back under this share of it, so eviction scans stay infrequent.
+const EVICTION_TARGET_PERCENT: usize = 90;
+
+/// One input string embedded by one model with one set of output options.
+/// The API key namespaces entries so callers never share cached vectors.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct EmbeddingKey {
+    api_key: String,
+    model: String,
+    options: String,
+    input: String,
+}
+
+impl EmbeddingKey {
+    fn size(&self) -> usize {
+        self.api_key.len() + self.model.len() + self.options.len() + self.input.len()
+    }
+
+    fn id(&self) -> String {
+        entry_id(&[
+            self.api_key.as_bytes(),
+            self.model.as_bytes(),
+            self.options.as_bytes(),
+            self.input.as_bytes(),
+        ])
+    }
+}
+
+struct StoredEmbedding {
+    embedding: Value,
+    size: usize,
+    last_used: Instant,
+}
+
+/// Embedding vectors by model and input string, bounded by an estimate of
+/// their memory use
+pub(crate) struct EmbeddingCache {
+    max_bytes: usize,
+    used_bytes: AtomicUsize,
+    entries: DashMap<EmbeddingKey, StoredEmbedding>,
+}
+
+impl EmbeddingCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            used_bytes: AtomicUsize::new(0),
+            entries: DashMap::new(),
+        }
+    }
+
+    /// Looks up every input of an embeddings request. Returns `None` for
+    /// requests the cache does not handle, such as token array inputs.
+    pub(crate) fn lookup(&self, api_key: &str, body: &[u8]) -> Option<EmbeddingLookup> {
+        let request: Value = serde_json::from_slice(body).ok()?;
+        let model = request.get("model")?.as_str()?.to_string();
+        let (inputs, single_input) = match request.get("input")? {
+            Value::String(input) => (vec![input.clone()], true),
+            Value::Array(items) if !items.is_empty() => {
+                let inputs = items
+                    .iter()
+                    .map(|item| item.as_str().map(str::to_string))
+                    .collect::<Option<Vec<_>>>()?;
+                (inputs, false)
+            }
+            _ => return None,
+        };
+        let options = json!({
+            "encoding_format": request.get("encoding_format"),
+            "dimensions": request.get("dimensions"),
+        })
+        .to_string();
+
+        let mut lookup = EmbeddingLookup {
+            request,
+            api_key: api_key.to_string(),
+            model,
+            options,
+            single_input,
+            cached: Vec::with_capacity(inputs.len()),
+            inputs,
+        };
+        lookup.cached = (0..lookup.inputs.len())
+            .map(|index| self.get(&lookup.key(index)))
+            .collect();
+        Some(lookup)
+    }
+
+    fn get(&self, key: &EmbeddingKey) -> Option<Value> {
+        let mut entry = self.entries.get_mut(key)?;
+        entry.last_used = Instant::now();
+        Some(entry.embedding.clone())
+    }
+
+    fn insert(&self, key: EmbeddingKey, embedding: Value) {
+        let size = key.size() + value_size(&embedding);
+        if size > self.max_bytes {
+            return;
+        }
+
+        let stored = StoredEmbedding {
+            embedding,
+            size,
+            last_used: Instant::now(),
+        };
+        if let Some(replaced) = self.entries.insert(key, stored) {
+            self.used_bytes.fetch_sub(replaced.size, Ordering::Relaxed);
+        }
+        if self.used_bytes.fetch_add(size, Ordering::Relaxed) + size > self.max_bytes {
+            self.evict_least_recently_used();
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.retain(|_, stored| {
+            self.used_bytes.fetch_sub(stored.size, Ordering::Relaxed);
+            false
+        });
+    }
+
+    /// The cached vectors, most recently used first, for the admin API
+    pub(crate) fn list(&self) -> Vec<Value> {
+        let now = Instant::now();
+        let mut entries: Vec<(Instant, Value)> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let (key, stored) = (entry.key(), entry.value());
+                let listed = json!({
+                    "id": key.id(),
+                    "api_key_id": entry_id(&[key.api_key.as_bytes()]),
+                    "model": key.model,
+                    "size_bytes": stored.size,
+                    "idle_secs": now.saturating_duration_since(stored.last_used).as_secs(),
+                });
+                (stored.last_used, listed)
+            })
+            .collect();
+        entries.sort_by_key(|(last_used, _)| std::cmp::Reverse(*last_used));
+        entries.into_iter().map(|(_, listed)| listed).collect()
+    }
+
+    /// Evicts the vector with `id`, or every vector without one. Returns how
+    /// many were evicted.
+    pub(crate) fn evict(&self, id: Option<&str>) -> usize {
+        let mut evicted = 0;
+        self.entries.retain(|key, stored| {
+            if id.is_some_and(|id| key.id() != id) {
+                return true;
+            }
+            self.used_bytes.fetch_sub(stored.size, Ordering::Relaxed);
+            evicted += 1;
+            false
+        });
+        evicted
+    }
+
+    fn evict_least_recently_used(&self) {
+        let target = self.max_bytes / 100 * EVICTION_TARGET_PERCENT;
+        let mut entries: Vec<(EmbeddingKey, Instant)> = self
+            .entries
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().last_used))
+            .collect();
+        entries.sort_by_key(|(_, last_used)| *last_used);
+
+        for (key, _) in entries {
+            if self.used_bytes.load(Ordering::Relaxed) <= target {
+                break;
+            }
+            if let Some((_, removed)) = self.entries.remove(&key) {
+                self.used_bytes.fetch_sub(removed.size, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// A rough, allocation-based estimate of a cached vector's memory footprint
+fn value_size(value: &Value) -> usize {
+    mem::size_of::<Value>()
+        + match value {
+            Value::String(string) => string.len(),
+            Value::Array(values) => values.iter().map(value_size).sum(),
+            Value::Object(object) => object
+                .iter()
+                .map(|(name, value)| name.len() + value_size(value))
+                .sum(),
+            _ => 0,
+        }
+}
+
+/// The cached and missing vectors for one embeddings request
+pub(crate) struct EmbeddingLookup {
+    request: Value,
+    api_key: String,
+    model: String,
+    options: String,
+    single_input: bool,
+    inputs: Vec<String>,
+    cached: Vec<Option<Value>>,
+}
+
+impl EmbeddingLookup {
+    fn key(&self, index: usize) -> EmbeddingKey {
+        EmbeddingKey {
+            api_key: self.api_key.clone(),
+            model: self.model.clone(),
+            options: self.options.clone(),
+            input: self.inputs[index].clone(),
+        }
+    }
+
+    fn missing(&self) -> Vec<usize> {
+        (0..self.cached.len())
+            .filter(|index| self.cached[*index].is_none())
+            .collect()
+    }
+
+    /// `hit` when every input was cached, `miss` when none were
+    pub(crate) fn cache_status(&self) -> &'static str {
+        match self.missing().len() {
+            0 => "hit",
+            missing if missing == self.inputs.len() => "miss",
+            _ => "partial",
+        }
+    }
+
+    /// The request to send upstream, asking only for the uncached inputs
+    pub(crate) fn backend_body(&self) -> Bytes {
+        let mut request = self.request.clone();
+        let missing: Vec<Value> = self
+            .missing()
+            .into_iter()
+            .map(|index| Value::String(self.inputs[index].clone()))
+            .collect();
+        request["input"] = if self.single_input {
+            missing.into_iter().next().unwrap_or_default()
+        } else {
+            Value::Array(missing)
+        };
+
+        Bytes::from(request.to_string())
+    }
+
+    /// Caches the vectors from the backend's response, if any, and assembles
+    /// the response for every requested input in the original order. Fields
+    /// the backend adds to the response or its items are kept. Returns `None`
+    /// when the backend response cannot be matched to the request.
+    pub(crate) fn complete(self, cache: &EmbeddingCache, backend: Option<&[u8]>) -> Option<Bytes> {
+        let missing = self.missing();
+        let mut items: Vec<Option<Value>> = self
+            .cached
+            .iter()
+            .enumerate()
+            .map(|(index, embedding)| {
+                let embedding = embedding.clone()?;
+                Some(json!({"object": "embedding", "index": index, "embedding": embedding}))
+            })
+            .collect();
+        let mut response = json!({
+            "object": "list",
+            "data": [],
+            "model": self.model,
+            "usage": wire::to_value(&Usage::embeddings(0)),
+        });
+
+        if let Some(body) = backend {
+            response = serde_json::from_slice(body).ok()?;
+            let data = response.get_mut("data")?.as_array_mut()?;
+            if data.len() != missing.len() {
+                return None;
+            }
+            for mut item in mem::take(data) {
+                let index = *missing.get(usize::try_from(item.get("index")?.as_u64()?).ok()?)?;
+                cache.insert(self.key(index), item.get("embedding")?.clone());
+                item["index"] = json!(index);
+                items[index] = Some(item);
+            }
+        }
+
+        response["data"] = Value::Array(items.into_iter().collect::<Option<Vec<_>>>()?);
+        serde_json::to_vec(&response).ok().map(Bytes::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend_response(embeddings: &[(usize, f64)]) -> Vec<u8> {
+        let data: Vec<Value> = embeddings
+            .iter()
+            .map(|(index, value)| {
+                json!({"object": "embedding", "index": index, "embedding": [value]})
+            })
+            .collect();
+        serde_json::to_vec(&json!({
+            "object": "list",
+            "data": data,
+            "model": "nomic-embed-text",
+            "usage": {"prompt_tokens": 2, "total_tokens": 2}
+        }))
+        .unwrap()
+    }
+
+    fn response_json(body: Bytes) -> Value {
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn only_uncached_inputs_are_sent_and_responses_keep_request_order() {
+        let cache = EmbeddingCache::new(1024 * 1024);
+        let first = cache
+            .lookup("key", br#"{"model":"nomic-embed-text","input":["a","b"]}"#)
+            .unwrap();
+        assert_eq!(first.cache_status(), "miss");
+        first
+            .complete(&cache, Some(&backend_response(&[(0, 0.1), (1, 0.2)])))
+            .unwrap();
+
+        let second = cache
+            .lookup(
+                "key",
+                br#"{"model":"nomic-embed-text","input":["c","b","a"]}"#,
+            )
+            .unwrap();
+        assert_eq!(second.cache_status(), "partial");
+        assert_eq!(
+            serde_json::from_slice::<Value>(&second.backend_body()).unwrap(),
+            json!({"model": "nomic-embed-text", "input": ["c"]})
+        );
+
+        let response = response_json(
+            second
+                .complete(&cache, Some(&backend_response(&[(0, 0.3)])))
+                .unwrap(),
+        );
+        assert_eq!(response["data"][0]["embedding"], json!([0.3]));
+        assert_eq!(response["data"][1]["embedding"], json!([0.2]));
+        assert_eq!(response["data"][2]["embedding"], json!([0.1]));
+        assert_eq!(response["data"][2]["index"], 2);
+        assert_eq!(response["usage"]["prompt_tokens"], 2);
+    }
+
+    #[test]
+    fn fully_cached_requests_need_no_backend() {
+        let cache = EmbeddingCache::new(1024 * 1024);
+        let request = br#"{"model":"nomic-embed-text","input":"a"}"#;
+        cache
+            .lookup("key", request)
+            .unwrap()
+            .complete(&cache, Some(&backend_response(&[(0, 0.1)])))
+            .unwrap();
+
+        let lookup = cache.lookup("key", request).unwrap();
+        assert_eq!(lookup.cache_status(), "hit");
+        let response = response_json(lookup.complete(&cache, None).unwrap());
+        assert_eq!(response["data"][0]["embedding"], json!([0.1]));
+        assert_eq!(response["usage"]["total_tokens"], 0);
+
+        assert_eq!(
+            cache.lookup("other-key", request).unwrap().cache_status(),
+            "miss"
+        );
+        assert_eq!(
+            cache
+                .lookup(
+                    "key",
+                    br#"{"model":"nomic-embed-text","input":"a","dimensions":8}"#
+                )
+                .unwrap()
+                .cache_status(),
+            "miss"
+        );
+    }
+
+    #[test]
+    fn backend_fields_are_kept() {
+        let cache = EmbeddingCache::new(1024 * 1024);
+        let request = br#"{"model":"nomic-embed-text","input":["a","b"]}"#;
+        cache
+            .lookup("key", br#"{"model":"nomic-embed-text","input":"a"}"#)
+            .unwrap()
+            .complete(&cache, Some(&backend_response(&[(0, 0.1)])))
+            .unwrap();
+
+        let backend = json!({
+            "object": "list",
+            "data": [{"object": "embedding", "index": 0, "embedding": [0.2], "norm": 1.0}],
+            "model": "nomic-embed-text-v1.5",
+            "usage": {"prompt_tokens": 1, "total_tokens": 1},
+            "provider": {"region": "us"}
+        });
+        let response = response_json(
+            cache
+                .lookup("key", request)
+                .unwrap()
+                .complete(&cache, Some(&serde_json::to_vec(&backend).unwrap()))
+                .unwrap(),
+        );
+        assert_eq!(response["provider"], json!({"region": "us"}));
+        assert_eq!(response["model"], "nomic-embed-text-v1.5");
+        assert_eq!(
+            response["data"],
+            json!([
+                {"object": "embedding", "index": 0, "embedding": [0.1]},
+                {"object": "embedding", "index": 1, "embedding": [0.2], "norm": 1.0}
+            ])
+        );
+    }
+
+    #[test]
+    fn token_inputs_and_mismatched_responses_are_not_cached() {
+        let cache = EmbeddingCache::new(1024 * 1024);
+        assert!(cache
+            .lookup("key", br#"{"model":"nomic-embed-text","input":[[1,2]]}"#)
+            .is_none());
+        assert!(cache.lookup("key", br#"{"input":"a"}"#).is_none());
+
+        let lookup = cache
+            .lookup("key", br#"{"model":"nomic-embed-text","input":["a","b"]}"#)
+            .unwrap();
+        assert!(lookup
+            .complete(&cache, Some(&backend_response(&[(0, 0.1)])))
+            .is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_vectors_over_budget() {
+        let key = |input: &str| EmbeddingKey {
+            api_key: "key".to_string(),
+            model: "nomic-embed-text".to_string(),
+            options: String::new(),
+            input: input.to_string(),
+        };
+        let entry_size = key("a").size() + value_size(&json!([0.1]));
+        let cache = EmbeddingCache::new(entry_size * 2);
+
+        cache.insert(key("a"), json!([0.1]));
+        cache.insert(key("b"), json!([0.1]));
+        cache.get(&key("a"));
+        cache.insert(key("c"), json!([0.1]));
+
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("c")).is_some());
+        assert!(cache.used_bytes.load(Ordering::Relaxed) <= entry_size * 2);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::OpenAIError;
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The request body field that holds proxy options
+pub(crate) const EXTENSION_FIELD: &str = "maple";
+
+/// Proxy options a client sets in a request's `"maple"` object, which is
+/// removed before the request is forwarded. Unknown options are ignored, so
+/// clients can send options newer proxies understand.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct MapleExtension {
+    /// A configured backend URL to try before the others
+    pub(crate) backend: Option<String>,
+    pub(crate) cache: Option<CacheDirective>,
+    /// Tags the request's log lines
+    pub(crate) session_id: Option<String>,
+    /// Checks the request and reports what would be sent instead of sending it
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+    /// Runs a chat completion in the background, to be polled for
+    #[serde(default)]
+    pub(crate) background: bool,
+}
+
+/// How a request uses the response and embedding caches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CacheDirective {
+    /// Skips cached responses and caches the fresh one, like
+    /// `Cache-Control: no-cache`
+    NoCache,
+    /// Neither reads nor writes the caches
+    NoStore,
+}
+
+impl MapleExtension {
+    pub(crate) fn skips_cache_lookup(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    pub(crate) fn skips_cache(&self) -> bool {
+        self.cache == Some(CacheDirective::NoStore)
+    }
+}
+
+/// Splits the `"maple"` object off a JSON request body. Bodies without one
+/// are returned as they are, byte for byte.
+pub(crate) fn take_extension(body: Bytes) -> Result<(MapleExtension, Bytes), OpenAIError> {
+    let has_extension = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .filter(|request| request.get(EXTENSION_FIELD).is_some());
+    let Some(mut request) = has_extension else {
+        return Ok((MapleExtension::default(), body));
+    };
+
+    let options = request
+        .as_object_mut()
+        .and_then(|fields| fields.shift_remove(EXTENSION_FIELD))
+        .filter(|options| !options.is_null());
+    let extension = match options {
+        Some(options) => serde_json::from_value(options).map_err(|error| {
+            OpenAIError::invalid_request_error(format!("Invalid `maple` options: {}", error))
+                .with_param(EXTENSION_FIELD)
+        })?,
+        None => MapleExtension::default(),
+    };
+    let body = serde_json::to_vec(&request)
+        .map(Bytes::from)
+        .unwrap_or(body);
+    Ok((extension, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_the_extension_and_keeps_other_fields() {
+        let body = Bytes::from_static(
+            br#"{"model":"m","maple":{"cache":"no-store","dry_run":true,"future":1},"n":2}"#,
+        );
+        let (extension, body) = take_extension(body).unwrap();
+
+        assert_eq!(body, Bytes::from_static(br#"{"model":"m","n":2}"#));
+        assert_eq!(extension.cache, Some(CacheDirective::NoStore));
+        assert!(extension.dry_run);
+        assert!(extension.skips_cache());
+    }
+
+    #[test]
+    fn leaves_bodies_without_an_extension_untouched() {
+        for body in [&b"{ \"model\": \"m\" }"[..], b"not json", b""] {
+            let (extension, forwarded) = take_extension(Bytes::from_static(body)).unwrap();
+            assert_eq!(forwarded, body);
+            assert!(!extension.dry_run && extension.cache.is_none());
+        }
+
+        let invalid = Bytes::from_static(br#"{"model":"m","maple":{"cache":"forever"}}"#);
+        assert!(take_extension(invalid).is_err());
+    }
+}
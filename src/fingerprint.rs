@@ -0,0 +1,165 @@
+use axum::http::{header, HeaderMap};
+
+const MAX_VERSION_LEN: usize = 32;
+
+/// User-Agent products in match order. Wrappers come before the SDKs they are
+/// built on, so LangChain is not reported as the OpenAI SDK it embeds.
+const USER_AGENT_PRODUCTS: &[(&str, &str)] = &[
+    ("langchain", "langchain"),
+    ("ai-sdk", "vercel-ai-sdk"),
+    ("async-openai", "async-openai"),
+    ("openai/python", "openai-python"),
+    ("openai/js", "openai-node"),
+    ("curl", "curl"),
+    ("python-requests", "python-requests"),
+    ("python-httpx", "python-httpx"),
+    ("go-http-client", "go"),
+    ("okhttp", "okhttp"),
+];
+
+/// The kind of client that sent a request, as far as its headers tell
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct ClientFingerprint {
+    pub(crate) name: &'static str,
+    pub(crate) version: Option<String>,
+}
+
+impl ClientFingerprint {
+    fn new(name: &'static str, version: Option<String>) -> Self {
+        Self { name, version }
+    }
+
+    /// Classifies a caller from its `User-Agent` and the `x-stainless-*`
+    /// headers the official OpenAI SDKs send
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_ascii_lowercase);
+
+        if let Some(user_agent) = &user_agent {
+            for (product, name) in USER_AGENT_PRODUCTS {
+                if let Some(rest) = find_product(user_agent, product) {
+                    return Self::new(name, version_after(rest));
+                }
+            }
+        }
+
+        // Browser builds of the OpenAI SDKs send a browser User-Agent
+        if let Some(lang) = header_str(headers, "x-stainless-lang") {
+            let name = match lang.to_ascii_lowercase().as_str() {
+                "python" => "openai-python",
+                "js" => "openai-node",
+                _ => "openai-sdk",
+            };
+            let version =
+                header_str(headers, "x-stainless-package-version").and_then(version_after);
+            return Self::new(name, version);
+        }
+
+        match user_agent {
+            Some(user_agent) if user_agent.starts_with("mozilla/") => Self::new("browser", None),
+            Some(_) => Self::new("other", None),
+            None => Self::new("unknown", None),
+        }
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Finds `product` at the start of a User-Agent token and returns what follows
+fn find_product<'a>(user_agent: &'a str, product: &str) -> Option<&'a str> {
+    user_agent.match_indices(product).find_map(|(index, _)| {
+        let at_token_start = user_agent[..index]
+            .chars()
+            .next_back()
+            .is_none_or(|previous| !previous.is_ascii_alphanumeric() && previous != '-');
+        at_token_start.then(|| &user_agent[index + product.len()..])
+    })
+}
+
+/// Reads a version from the text after a product name, e.g. `/1.2.3`,
+/// ` 1.2.3`, or `-openai/0.3.1`. Only short, label-safe versions are kept.
+fn version_after(rest: &str) -> Option<String> {
+    let token = rest
+        .trim_start_matches([' ', '/'])
+        .split_whitespace()
+        .next()?;
+    let version = token.rsplit('/').next()?;
+    let valid = version.len() <= MAX_VERSION_LEN
+        && version.starts_with(|c: char| c.is_ascii_digit())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'));
+
+    valid.then(|| version.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(headers: &[(&'static str, &'static str)]) -> ClientFingerprint {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, value.parse().unwrap());
+        }
+        ClientFingerprint::from_headers(&map)
+    }
+
+    #[test]
+    fn classifies_common_user_agents() {
+        for (user_agent, name, version) in [
+            ("OpenAI/Python 1.51.0", "openai-python", Some("1.51.0")),
+            ("OpenAI/JS 4.67.3", "openai-node", Some("4.67.3")),
+            ("curl/8.5.0", "curl", Some("8.5.0")),
+            ("python-httpx/0.27.0", "python-httpx", Some("0.27.0")),
+            (
+                "langchain-openai/0.2.1 OpenAI/Python 1.51.0",
+                "langchain",
+                Some("0.2.1"),
+            ),
+            (
+                "ai-sdk/openai/1.0.5 runtime/node",
+                "vercel-ai-sdk",
+                Some("1.0.5"),
+            ),
+            ("async-openai/0.24", "async-openai", Some("0.24")),
+            ("Mozilla/5.0 (X11; Linux x86_64)", "browser", None),
+            ("my-script", "other", None),
+        ] {
+            assert_eq!(
+                fingerprint(&[("user-agent", user_agent)]),
+                ClientFingerprint::new(name, version.map(str::to_string)),
+                "{}",
+                user_agent
+            );
+        }
+        assert_eq!(fingerprint(&[]).name, "unknown");
+    }
+
+    #[test]
+    fn stainless_headers_identify_sdks_behind_browser_user_agents() {
+        let client = fingerprint(&[
+            ("user-agent", "Mozilla/5.0"),
+            ("x-stainless-lang", "js"),
+            ("x-stainless-package-version", "4.67.3"),
+        ]);
+
+        assert_eq!(
+            client,
+            ClientFingerprint::new("openai-node", Some("4.67.3".to_string()))
+        );
+    }
+
+    #[test]
+    fn ignores_product_names_inside_other_tokens_and_unsafe_versions() {
+        assert_eq!(fingerprint(&[("user-agent", "notcurl/1.0")]).name, "other");
+        assert_eq!(
+            fingerprint(&[("user-agent", "curl/8.5\"injected")]),
+            ClientFingerprint::new("curl", None)
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use crate::proxy::ProxyState;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{Extensions, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use clap::ValueEnum;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The header trusted proxies write the client's address to. Only that one is
+/// read, since a client can send the other with any address it likes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`, which nginx and most load balancers append to
+    #[default]
+    #[value(name = "xff")]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded`, read from its `for=` parameters
+    Forwarded,
+}
+
+/// A proxy, or a network of them, trusted to report the client's address in
+/// `Forwarded` or `X-Forwarded-For`, as an IP or a CIDR range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl TrustedProxy {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix_len(network: IpAddr) -> u8 {
+        match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (network, prefix_len) = match value.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network = network
+            .parse::<IpAddr>()
+            .map_err(|_| format!("'{}' is not an IP address or CIDR range", value))?
+            .to_canonical();
+        let max_prefix_len = Self::max_prefix_len(network);
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| {
+                    format!(
+                        "'{}' needs a prefix length of at most {}",
+                        value, max_prefix_len
+                    )
+                })?,
+            None => max_prefix_len,
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for TrustedProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix_len == Self::max_prefix_len(self.network) {
+            return write!(f, "{}", self.network);
+        }
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// The address a request came from, as reported by trusted proxies, kept in
+/// the request's extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ClientIp(pub(crate) IpAddr);
+
+/// The request's client address, once [`resolve_client_ip`] has run
+pub(crate) fn client_ip(extensions: &Extensions) -> Option<IpAddr> {
+    extensions.get::<ClientIp>().map(|ClientIp(ip)| *ip)
+}
+
+/// Notes each request's client address for the rate limits, country and
+/// network rules, and the scanner denylist
+pub(crate) async fn resolve_client_ip(
+    State(state): State<Arc<ProxyState>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(peer) = peer {
+        let config = state.config();
+        let ip = resolve(
+            peer,
+            request.headers(),
+            &config.trusted_proxies,
+            config.forwarded_header,
+        );
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
+/// The client behind `peer`. When `peer` is a trusted proxy, the hops it
+/// reports are walked from the nearest, and the first one that is not itself
+/// trusted is the client. Only `header` is read; a hop that names no address
+/// ends the walk at the last one that did.
+pub(crate) fn resolve(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    trusted: &[TrustedProxy],
+    header: ForwardedHeader,
+) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|proxy| proxy.contains(ip));
+    let mut client = peer.to_canonical();
+    if !is_trusted(client) {
+        return client;
+    }
+    for hop in forwarded_hops(headers, header).into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop.to_canonical();
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// The addresses in `header`, client first
+fn forwarded_hops(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+    match header {
+        ForwardedHeader::Forwarded => values("forwarded")
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect(),
+        ForwardedHeader::XForwardedFor => values(X_FORWARDED_FOR)
+            .into_iter()
+            .map(parse_node)
+            .collect(),
+    }
+}
+
+/// An address as proxies write it: `192.0.2.60`, `192.0.2.60:4711`,
+/// `2001:db8::1`, or `"[2001:db8::1]:4711"`. Obfuscated and `unknown` nodes
+/// name no address.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| {
+            node.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        let range: TrustedProxy = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains("10.20.30.40".parse().unwrap()));
+        assert!(range.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!range.contains("11.0.0.1".parse().unwrap()));
+        assert_eq!(range.to_string(), "10.0.0.0/8");
+
+        let single: TrustedProxy = "fd00::1".parse().unwrap();
+        assert!(single.contains("fd00::1".parse().unwrap()));
+        assert!(!single.contains("fd00::2".parse().unwrap()));
+        assert_eq!(single.to_string(), "fd00::1");
+
+        for invalid in ["10.0.0.0/33", "proxy.internal", "fd00::/129", "10.0.0.0/"] {
+            assert!(invalid.parse::<TrustedProxy>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn forwarded_addresses_are_believed_only_from_trusted_proxies() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let balancer = "10.0.0.5".parse().unwrap();
+        let forwarded = headers(&[(X_FORWARDED_FOR, "1.2.3.4, 198.51.100.7, 10.0.0.9")]);
+        let xff = ForwardedHeader::XForwardedFor;
+
+        assert_eq!(
+            resolve(balancer, &forwarded, &trusted, xff),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+        let stranger = "203.0.113.9".parse().unwrap();
+        assert_eq!(resolve(stranger, &forwarded, &trusted, xff), stranger);
+        assert_eq!(resolve(balancer, &forwarded, &[], xff), balancer);
+        assert_eq!(
+            resolve(balancer, &HeaderMap::new(), &trusted, xff),
+            balancer
+        );
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let balancer = "10.0.0.5".parse().unwrap();
+        // The client sent its own Forwarded header; the balancer appended the
+        // address it saw to X-Forwarded-For
+        let spoofed = headers(&[
+            ("forwarded", "for=1.2.3.4"),
+            (X_FORWARDED_FOR, "198.51.100.7"),
+        ]);
+        assert_eq!(
+            resolve(balancer, &spoofed, &trusted, ForwardedHeader::XForwardedFor),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+
+        let unforwarded = headers(&[("forwarded", "for=1.2.3.4")]);
+        assert_eq!(
+            resolve(
+                balancer,
+                &unforwarded,
+                &trusted,
+                ForwardedHeader::XForwardedFor
+            ),
+            balancer
+        );
+    }
+
+    #[test]
+    fn forwarded_is_parsed() {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let balancer = "10.0.0.5".parse().unwrap();
+        let forwarded = headers(&[
+            (
+                "forwarded",
+                r#"for="[2001:db8::1]:4711";proto=https, for=10.1.1.1"#,
+            ),
+            (X_FORWARDED_FOR, "192.0.2.1"),
+        ]);
+        let header = ForwardedHeader::Forwarded;
+        assert_eq!(
+            resolve(balancer, &forwarded, &trusted, header),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        let hidden = headers(&[("forwarded", "for=198.51.100.7, for=_hidden, for=10.1.1.1")]);
+        assert_eq!(
+            resolve(balancer, &hidden, &trusted, header),
+            "10.1.1.1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use crate::{
+    config::{Config, OpenAIError},
+    forwarded::client_ip,
+    proxy::ProxyState,
+    rate_limit::RateLimiter,
+};
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Extensions, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::{collections::HashMap, fmt, net::IpAddr, path::Path, str::FromStr, sync::Arc};
+use tracing::{debug, error};
+
+/// A per-minute request limit for each client IP in a country, as
+/// COUNTRY=LIMIT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountryRateLimit {
+    /// ISO 3166-1 alpha-2 code, such as `US`
+    pub country: String,
+    pub per_minute: u32,
+}
+
+impl FromStr for CountryRateLimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected COUNTRY=LIMIT, got '{}'", value);
+        let (country, limit) = value.split_once('=').ok_or_else(expected)?;
+        let country = parse_country(country)?;
+        let per_minute = limit
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .ok_or_else(|| format!("the limit in '{}' must be a positive integer", value))?;
+        Ok(Self {
+            country,
+            per_minute,
+        })
+    }
+}
+
+impl fmt::Display for CountryRateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.country, self.per_minute)
+    }
+}
+
+/// Checks an ISO 3166-1 alpha-2 country code and uppercases it
+pub(crate) fn parse_country(country: &str) -> Result<String, String> {
+    let country = country.trim();
+    if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(format!(
+            "'{}' is not a two-letter ISO country code such as US",
+            country
+        ));
+    }
+    Ok(country.to_ascii_uppercase())
+}
+
+/// Checks that a MaxMind database can be opened
+pub(crate) fn check_database(path: &Path, flag: &str) -> anyhow::Result<()> {
+    open_database(path, flag).map(drop)
+}
+
+fn open_database(path: &Path, flag: &str) -> anyhow::Result<Reader<Vec<u8>>> {
+    Reader::open_readfile(path)
+        .with_context(|| format!("Failed to open the {} database {}", flag, path.display()))
+}
+
+/// Where a client connects from, as far as the databases know
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ClientLocation {
+    pub(crate) country: Option<String>,
+    pub(crate) asn: Option<u32>,
+}
+
+/// Why a client was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Refusal {
+    Country,
+    Network,
+}
+
+/// Allow and deny rules by country and autonomous system, and per-country
+/// rate limits, looked up in MaxMind databases
+pub(crate) struct GeoPolicy {
+    countries: Option<Reader<Vec<u8>>>,
+    asns: Option<Reader<Vec<u8>>>,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+    allow_asns: Vec<u32>,
+    deny_asns: Vec<u32>,
+    /// Each country's per-minute limit and its limiter
+    country_limiters: HashMap<String, (u32, RateLimiter)>,
+}
+
+impl GeoPolicy {
+    /// `None` without `--geoip-db` or `--asn-db`
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        if config.geoip_db.is_none() && config.asn_db.is_none() {
+            return None;
+        }
+        let open = |path: &Option<_>, flag| {
+            path.as_deref().and_then(|path| {
+                open_database(path, flag)
+                    .map_err(|open_error| error!("{:#}", open_error))
+                    .ok()
+            })
+        };
+        Some(Self {
+            countries: open(&config.geoip_db, "--geoip-db"),
+            asns: open(&config.asn_db, "--asn-db"),
+            allow_countries: config.allow_countries.clone(),
+            deny_countries: config.deny_countries.clone(),
+            allow_asns: config.allow_asns.clone(),
+            deny_asns: config.deny_asns.clone(),
+            country_limiters: config
+                .country_rate_limits
+                .iter()
+                .map(|limit| {
+                    let limiter = RateLimiter::per_minute(limit.per_minute);
+                    (limit.country.clone(), (limit.per_minute, limiter))
+                })
+                .collect(),
+        })
+    }
+
+    /// Looks up `ip`. Addresses that cannot be located, such as private
+    /// ones, have neither a country nor an ASN.
+    fn locate(&self, ip: IpAddr) -> ClientLocation {
+        let country = self.countries.as_ref().and_then(|reader| {
+            lookup::<geoip2::Country>(reader, ip)?
+                .country?
+                .iso_code
+                .map(str::to_string)
+        });
+        let asn = self
+            .asns
+            .as_ref()
+            .and_then(|reader| lookup::<geoip2::Asn>(reader, ip)?.autonomous_system_number);
+        ClientLocation { country, asn }
+    }
+
+    fn check(&self, location: &ClientLocation) -> Result<(), Refusal> {
+        let listed = |countries: &[String]| {
+            location
+                .country
+                .as_ref()
+                .is_some_and(|country| countries.contains(country))
+        };
+        if listed(&self.deny_countries)
+            || (!self.allow_countries.is_empty() && !listed(&self.allow_countries))
+        {
+            return Err(Refusal::Country);
+        }
+        if location
+            .asn
+            .is_some_and(|asn| self.deny_asns.contains(&asn))
+            || (!self.allow_asns.is_empty()
+                && !location
+                    .asn
+                    .is_some_and(|asn| self.allow_asns.contains(&asn)))
+        {
+            return Err(Refusal::Network);
+        }
+        Ok(())
+    }
+
+    /// The per-minute limit and rate limiter for the country a request comes
+    /// from, if it has its own limit
+    pub(crate) fn country_rate_limiter(
+        &self,
+        extensions: &Extensions,
+    ) -> Option<(u32, &RateLimiter)> {
+        let country = extensions.get::<ClientLocation>()?.country.as_deref()?;
+        let (limit, limiter) = self.country_limiters.get(country)?;
+        Some((*limit, limiter))
+    }
+}
+
+fn lookup<'a, T: serde::Deserialize<'a>>(reader: &'a Reader<Vec<u8>>, ip: IpAddr) -> Option<T> {
+    match reader.lookup(ip) {
+        Ok(found) => Some(found),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+        Err(lookup_error) => {
+            debug!("GeoIP lookup of {} failed: {}", ip, lookup_error);
+            None
+        }
+    }
+}
+
+/// Loopback, private, and link-local clients are on the operator's own
+/// network, which no database locates, so the rules leave them alone
+pub(crate) fn is_local(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            ip.is_loopback() || first_segment & 0xfe00 == 0xfc00 || first_segment & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// Refuses clients the country and network rules exclude, and notes where
+/// the others connect from for the per-country rate limits
+pub(crate) async fn enforce_geo_policy(
+    State(state): State<Arc<ProxyState>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (Some(policy), Some(ip)) = (state.geo_policy(), client_ip(request.extensions())) else {
+        return next.run(request).await;
+    };
+    if is_local(ip) {
+        return next.run(request).await;
+    }
+
+    let location = policy.locate(ip);
+    if let Err(refusal) = policy.check(&location) {
+        debug!(
+            "Refused {} ({:?}, AS{:?}): {:?}",
+            ip, location.country, location.asn, refusal
+        );
+        return refused_response(refusal);
+    }
+    request.extensions_mut().insert(location);
+    next.run(request).await
+}
+
+fn refused_response(refusal: Refusal) -> Response {
+    let error = match refusal {
+        Refusal::Country => OpenAIError::invalid_request_error(
+            "This service is not available in your country, region, or territory.",
+        )
+        .with_code("unsupported_country_region_territory"),
+        Refusal::Network => {
+            OpenAIError::invalid_request_error("This service is not available from your network.")
+                .with_code("network_not_allowed")
+        }
+    };
+    (StatusCode::FORBIDDEN, Json(error)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(config: Config) -> GeoPolicy {
+        GeoPolicy {
+            countries: None,
+            asns: None,
+            allow_countries: config.allow_countries,
+            deny_countries: config.deny_countries,
+            allow_asns: config.allow_asns,
+            deny_asns: config.deny_asns,
+            country_limiters: HashMap::new(),
+        }
+    }
+
+    fn config() -> Config {
+        Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+    }
+
+    fn location(country: Option<&str>, asn: Option<u32>) -> ClientLocation {
+        ClientLocation {
+            country: country.map(str::to_string),
+            asn,
+        }
+    }
+
+    #[test]
+    fn parses_country_rate_limits() {
+        let limit: CountryRateLimit = "de = 30".parse().unwrap();
+        assert_eq!(limit.country, "DE");
+        assert_eq!(limit.per_minute, 30);
+        assert_eq!(limit.to_string(), "DE=30");
+
+        for invalid in ["DE", "DEU=30", "D1=30", "DE=0", "DE=-1", "=30"] {
+            assert!(invalid.parse::<CountryRateLimit>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn deny_rules_win_and_allowlists_exclude_the_unknown() {
+        let mut allow_list = config();
+        allow_list.allow_countries = vec!["US".to_string(), "CA".to_string()];
+        allow_list.deny_asns = vec![64_496];
+        let allow_list = policy(allow_list);
+
+        assert_eq!(
+            allow_list.check(&location(Some("US"), Some(64_500))),
+            Ok(())
+        );
+        assert_eq!(
+            allow_list.check(&location(Some("FR"), None)),
+            Err(Refusal::Country)
+        );
+        assert_eq!(
+            allow_list.check(&location(None, None)),
+            Err(Refusal::Country)
+        );
+        assert_eq!(
+            allow_list.check(&location(Some("CA"), Some(64_496))),
+            Err(Refusal::Network)
+        );
+
+        let mut deny_only = config();
+        deny_only.deny_countries = vec!["FR".to_string()];
+        let deny_only = policy(deny_only);
+        assert_eq!(deny_only.check(&location(None, None)), Ok(()));
+        assert_eq!(
+            deny_only.check(&location(Some("FR"), None)),
+            Err(Refusal::Country)
+        );
+    }
+
+    #[test]
+    fn local_clients_are_not_checked() {
+        let local = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.1.20",
+            "::1",
+            "fd00::1",
+            "::ffff:10.0.0.1",
+        ];
+        for ip in local {
+            assert!(is_local(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["203.0.113.9", "2001:db8::1"] {
+            assert!(!is_local(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn country_limits_follow_the_located_country() {
+        let mut policy = policy(config());
+        policy
+            .country_limiters
+            .insert("BR".to_string(), (1, RateLimiter::per_minute(1)));
+        let mut extensions = Extensions::new();
+        assert!(policy.country_rate_limiter(&extensions).is_none());
+
+        extensions.insert(location(Some("BR"), None));
+        let (limit, limiter) = policy.country_rate_limiter(&extensions).unwrap();
+        assert_eq!(limit, 1);
+        assert!(limiter.check("203.0.113.9").is_ok());
+        assert!(limiter.check("203.0.113.9").is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use crate::{
+    proxy::{is_event_stream, CHAT_COMPLETIONS_PATH, EMBEDDINGS_PATH},
+    sse::SseParser,
+};
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Extensions, HeaderValue, Method, Request, StatusCode, Uri},
+    response::Response,
+    Router,
+};
+use futures::{Stream, StreamExt};
+use serde_json::{Map, Value};
+use std::pin::Pin;
+use tonic::{metadata::MetadataMap, Code, Status};
+use tower::ServiceExt;
+
+mod proto {
+    tonic::include_proto!("maple.v1");
+}
+
+use proto::{
+    inference_server::{Inference, InferenceServer},
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, ChatMessage, Choice,
+    ChunkChoice, Embedding, EmbeddingsRequest, EmbeddingsResponse, Usage,
+};
+
+/// Where the `maple.v1.Inference` service's methods are routed
+pub(crate) const ROUTE: &str = "/maple.v1.Inference/{*method}";
+
+type ChunkStream = Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk, Status>> + Send>>;
+
+/// The `maple.v1.Inference` gRPC service, which sends each call through the
+/// proxy's inference routes as the equivalent JSON request, so calls get the
+/// same authentication, pipeline stages, and handling as HTTP clients
+#[derive(Clone)]
+pub(crate) struct GrpcService {
+    inference: Router,
+}
+
+/// The service for `ROUTE`, accepting messages up to `max_message_bytes`
+pub(crate) fn service(inference: Router, max_message_bytes: usize) -> InferenceServer<GrpcService> {
+    InferenceServer::new(GrpcService { inference }).max_decoding_message_size(max_message_bytes)
+}
+
+impl GrpcService {
+    /// Sends a JSON body to an inference route, with the call's metadata as
+    /// its headers, returning the response when it succeeded
+    async fn send(
+        &self,
+        path: &'static str,
+        metadata: MetadataMap,
+        extensions: Extensions,
+        body: Value,
+    ) -> Result<Response, Status> {
+        let mut request = Request::new(Body::from(body.to_string()));
+        *request.method_mut() = Method::POST;
+        *request.uri_mut() = Uri::from_static(path);
+        // Connection details such as the client IP carry over from the call
+        *request.extensions_mut() = extensions;
+        let headers = request.headers_mut();
+        *headers = metadata.into_headers();
+        headers.remove(header::CONTENT_LENGTH);
+        headers.remove(header::TE);
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+
+        let response = match self.inference.clone().oneshot(request).await {
+            Ok(response) => response,
+            Err(infallible) => match infallible {},
+        };
+        if !response.status().is_success() {
+            return Err(error_status(response).await);
+        }
+        Ok(response)
+    }
+}
+
+#[tonic::async_trait]
+impl Inference for GrpcService {
+    async fn chat_completion(
+        &self,
+        request: tonic::Request<ChatCompletionRequest>,
+    ) -> Result<tonic::Response<ChatCompletionResponse>, Status> {
+        let (metadata, extensions, request) = request.into_parts();
+        let body = chat_completion_body(request, false)?;
+        let response = self
+            .send(CHAT_COMPLETIONS_PATH, metadata, extensions, body)
+            .await?;
+        let completion = response_json(response).await?;
+        Ok(tonic::Response::new(chat_completion_response(completion)))
+    }
+
+    type StreamChatCompletionStream = ChunkStream;
+
+    async fn stream_chat_completion(
+        &self,
+        request: tonic::Request<ChatCompletionRequest>,
+    ) -> Result<tonic::Response<ChunkStream>, Status> {
+        let (metadata, extensions, request) = request.into_parts();
+        let body = chat_completion_body(request, true)?;
+        let response = self
+            .send(CHAT_COMPLETIONS_PATH, metadata, extensions, body)
+            .await?;
+        if !is_event_stream(response.headers()) {
+            // The completion came back in one piece, e.g. from the response
+            // cache, so it is sent as a single chunk
+            let completion = response_json(response).await?;
+            let chunk = completion_chunk(&completion, "message");
+            let chunks: ChunkStream = Box::pin(futures::stream::iter([Ok(chunk)]));
+            return Ok(tonic::Response::new(chunks));
+        }
+        Ok(tonic::Response::new(chunk_stream(response)))
+    }
+
+    async fn embeddings(
+        &self,
+        request: tonic::Request<EmbeddingsRequest>,
+    ) -> Result<tonic::Response<EmbeddingsResponse>, Status> {
+        let (metadata, extensions, request) = request.into_parts();
+        let body = embeddings_body(request)?;
+        let response = self
+            .send(EMBEDDINGS_PATH, metadata, extensions, body)
+            .await?;
+        let embeddings = response_json(response).await?;
+        Ok(tonic::Response::new(embeddings_response(&embeddings)))
+    }
+}
+
+/// The fields of `extra_json`, which must be empty or a JSON object
+fn extra_fields(extra_json: &str) -> Result<Map<String, Value>, Status> {
+    if extra_json.trim().is_empty() {
+        return Ok(Map::new());
+    }
+    match serde_json::from_str(extra_json) {
+        Ok(Value::Object(fields)) => Ok(fields),
+        _ => Err(Status::invalid_argument("extra_json must be a JSON object")),
+    }
+}
+
+/// The `/v1/chat/completions` body for a call, the typed fields set over
+/// `extra_json`
+fn chat_completion_body(request: ChatCompletionRequest, stream: bool) -> Result<Value, Status> {
+    let mut body = extra_fields(&request.extra_json)?;
+    if !request.model.is_empty() {
+        body.insert("model".to_string(), Value::from(request.model));
+    }
+    if !request.messages.is_empty() {
+        let messages = request
+            .messages
+            .into_iter()
+            .map(|message| {
+                let mut fields = Map::new();
+                fields.insert("role".to_string(), Value::from(message.role));
+                fields.insert("content".to_string(), Value::from(message.content));
+                if let Some(name) = message.name {
+                    fields.insert("name".to_string(), Value::from(name));
+                }
+                Value::Object(fields)
+            })
+            .collect();
+        body.insert("messages".to_string(), Value::Array(messages));
+    }
+    if let Some(temperature) = request.temperature {
+        body.insert("temperature".to_string(), Value::from(temperature));
+    }
+    if let Some(top_p) = request.top_p {
+        body.insert("top_p".to_string(), Value::from(top_p));
+    }
+    if let Some(max_tokens) = request.max_tokens {
+        body.insert("max_tokens".to_string(), Value::from(max_tokens));
+    }
+    if !request.stop.is_empty() {
+        body.insert("stop".to_string(), Value::from(request.stop));
+    }
+    body.insert("stream".to_string(), Value::Bool(stream));
+    if stream {
+        // Token counts arrive on the last chunk
+        body.entry("stream_options")
+            .or_insert_with(|| serde_json::json!({ "include_usage": true }));
+    } else {
+        body.remove("stream_options");
+    }
+    Ok(Value::Object(body))
+}
+
+/// The `/v1/embeddings` body for a call. Embeddings are always requested as
+/// floats, which is how the response message carries them.
+fn embeddings_body(request: EmbeddingsRequest) -> Result<Value, Status> {
+    let mut body = extra_fields(&request.extra_json)?;
+    if !request.model.is_empty() {
+        body.insert("model".to_string(), Value::from(request.model));
+    }
+    if !request.input.is_empty() {
+        body.insert("input".to_string(), Value::from(request.input));
+    }
+    if let Some(dimensions) = request.dimensions {
+        body.insert("dimensions".to_string(), Value::from(dimensions));
+    }
+    body.insert("encoding_format".to_string(), Value::from("float"));
+    Ok(Value::Object(body))
+}
+
+async fn response_json(response: Response) -> Result<Value, Status> {
+    let body = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|error| Status::unavailable(format!("Reading the response failed: {}", error)))?;
+    serde_json::from_slice(&body)
+        .map_err(|error| Status::internal(format!("The response is not valid JSON: {}", error)))
+}
+
+/// Relays chat completion SSE chunks as messages, ending the stream with an
+/// error status when the backend reports an error partway through
+fn chunk_stream(response: Response) -> ChunkStream {
+    let mut events = response.into_body().into_data_stream();
+    Box::pin(async_stream::stream! {
+        let mut parser = SseParser::default();
+        while let Some(bytes) = events.next().await {
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    yield Err(Status::unavailable(format!("The stream failed: {}", error)));
+                    return;
+                }
+            };
+            for data in parser.push(&bytes) {
+                if data == "[DONE]" {
+                    return;
+                }
+                let Ok(chunk) = serde_json::from_str::<Value>(&data) else {
+                    continue;
+                };
+                if let Some(message) = chunk["error"]["message"].as_str() {
+                    yield Err(Status::internal(message));
+                    return;
+                }
+                yield Ok(completion_chunk(&chunk, "delta"));
+            }
+        }
+    })
+}
+
+/// The gRPC status for an error response, carrying its OpenAI error message
+async fn error_status(response: Response) -> Status {
+    let code = status_code(response.status());
+    let body = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap_or_default();
+    let message = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|error| error["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+    Status::new(code, message)
+}
+
+fn status_code(status: StatusCode) -> Code {
+    match status {
+        StatusCode::BAD_REQUEST
+        | StatusCode::PAYLOAD_TOO_LARGE
+        | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::PAYMENT_REQUIRED | StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT => Code::Aborted,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        _ => Code::Internal,
+    }
+}
+
+fn string(value: &Value) -> String {
+    value.as_str().unwrap_or_default().to_string()
+}
+
+fn count(value: &Value) -> u32 {
+    value
+        .as_u64()
+        .and_then(|count| u32::try_from(count).ok())
+        .unwrap_or_default()
+}
+
+fn usage(usage: &Value) -> Option<Usage> {
+    usage.is_object().then(|| Usage {
+        prompt_tokens: count(&usage["prompt_tokens"]),
+        completion_tokens: count(&usage["completion_tokens"]),
+        total_tokens: count(&usage["total_tokens"]),
+    })
+}
+
+fn chat_completion_response(completion: Value) -> ChatCompletionResponse {
+    let choices = completion["choices"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|choice| Choice {
+            index: count(&choice["index"]),
+            message: Some(ChatMessage {
+                role: string(&choice["message"]["role"]),
+                content: string(&choice["message"]["content"]),
+                name: choice["message"]["name"].as_str().map(str::to_string),
+            }),
+            finish_reason: string(&choice["finish_reason"]),
+        })
+        .collect();
+    ChatCompletionResponse {
+        id: string(&completion["id"]),
+        model: string(&completion["model"]),
+        created: completion["created"].as_i64().unwrap_or_default(),
+        choices,
+        usage: usage(&completion["usage"]),
+        json: completion.to_string(),
+    }
+}
+
+/// A chunk message for a streamed chunk, whose choices carry a `delta`, or for
+/// a whole completion, whose choices carry a `message`
+fn completion_chunk(chunk: &Value, delta_field: &str) -> ChatCompletionChunk {
+    let choices = chunk["choices"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|choice| ChunkChoice {
+            index: count(&choice["index"]),
+            role: string(&choice[delta_field]["role"]),
+            content: string(&choice[delta_field]["content"]),
+            finish_reason: string(&choice["finish_reason"]),
+        })
+        .collect();
+    ChatCompletionChunk {
+        id: string(&chunk["id"]),
+        model: string(&chunk["model"]),
+        created: chunk["created"].as_i64().unwrap_or_default(),
+        choices,
+        usage: usage(&chunk["usage"]),
+        json: chunk.to_string(),
+    }
+}
+
+fn embeddings_response(embeddings: &Value) -> EmbeddingsResponse {
+    let data = embeddings["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|embedding| Embedding {
+            index: count(&embedding["index"]),
+            embedding: embedding["embedding"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_f64)
+                .map(|value| value as f32)
+                .collect(),
+        })
+        .collect();
+    EmbeddingsResponse {
+        model: string(&embeddings["model"]),
+        data,
+        usage: usage(&embeddings["usage"]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn grpc_service() -> GrpcService {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_mock_backend(true)
+        .with_mock_tokens_per_second(1000);
+        GrpcService {
+            inference: crate::create_app(config),
+        }
+    }
+
+    fn chat_request(model: &str) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            model: model.to_string(),
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: "Hi".to_string(),
+                name: None,
+            }],
+            max_tokens: Some(3),
+            extra_json: r#"{"seed": 7, "max_tokens": 100}"#.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn typed_fields_are_set_over_extra_json() {
+        let body = chat_completion_body(chat_request("llama3-3-70b"), true).unwrap();
+        assert_eq!(body["seed"], 7);
+        assert_eq!(body["max_tokens"], 3);
+        assert_eq!(body["messages"][0]["content"], "Hi");
+        assert_eq!(body["stream_options"]["include_usage"], true);
+
+        let mut request = chat_request("llama3-3-70b");
+        request.extra_json = "[]".to_string();
+        let error = chat_completion_body(request, false).unwrap_err();
+        assert_eq!(error.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn calls_are_served_through_the_inference_routes() {
+        let service = grpc_service();
+        let request = tonic::Request::new(chat_request("llama3-3-70b"));
+        let completion = service.chat_completion(request).await.unwrap().into_inner();
+        let message = completion.choices[0].message.as_ref().unwrap();
+        assert_eq!(message.content, "Lorem ipsum dolor");
+        assert_eq!(completion.usage.unwrap().completion_tokens, 3);
+
+        let request = tonic::Request::new(chat_request("llama3-3-70b"));
+        let chunks: Vec<ChatCompletionChunk> = service
+            .stream_chat_completion(request)
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let content: String = chunks
+            .iter()
+            .flat_map(|chunk| &chunk.choices)
+            .map(|choice| choice.content.as_str())
+            .collect();
+        assert_eq!(content, "Lorem ipsum dolor");
+
+        let request = tonic::Request::new(EmbeddingsRequest {
+            model: "nomic-embed-text".to_string(),
+            input: vec!["a".to_string(), "b".to_string()],
+            ..Default::default()
+        });
+        let embeddings = service.embeddings(request).await.unwrap().into_inner();
+        assert_eq!(embeddings.data.len(), 2);
+        assert!(!embeddings.data[1].embedding.is_empty());
+
+        let mut request = chat_request("llama3-3-70b");
+        request.messages.clear();
+        let error = service
+            .chat_completion(tonic::Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), Code::InvalidArgument);
+        assert!(error.message().contains("messages"));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::{
+    config::{Config, OpenAIError},
+    forwarded::client_ip,
+    ids::unix_now,
+    proxy::ProxyState,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use serde_json::{json, Value};
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Paths nothing here serves but vulnerability scanners ask for, used as
+/// decoys by `--honeypot`. A trailing `*` matches anything that starts with
+/// the rest.
+pub(crate) const DEFAULT_DECOY_PATHS: [&str; 14] = [
+    "/.env*",
+    "/.git/*",
+    "/.aws/*",
+    "/.DS_Store",
+    "/wp-login.php",
+    "/wp-admin*",
+    "/xmlrpc.php",
+    "/phpmyadmin*",
+    "/vendor/phpunit/*",
+    "/cgi-bin/*",
+    "/server-status",
+    "/actuator/*",
+    "/boaform/*",
+    "/HNAP1*",
+];
+
+/// The proxy's own routes, which decoys must leave alone
+const SERVED_PATHS: [&str; 5] = ["/", "/health", "/version", "/metrics", "/playground"];
+const SERVED_PREFIXES: [&str; 4] = ["/v1/", "/api/", "/openai/", "/admin"];
+
+/// IPs come from scanners, so past this many listed IPs, expired blocks are
+/// dropped, and then further probes are only logged
+const MAX_LISTED_IPS: usize = 16 * 1024;
+
+/// An IP that asked for a decoy
+struct Listing {
+    path: String,
+    probes: u64,
+    first_seen: u64,
+    last_seen: u64,
+    blocked_until: Option<Instant>,
+    refused_requests: u64,
+}
+
+/// Decoy paths that flag the IPs probing them, and the denylist those IPs
+/// are blocked by
+pub(crate) struct Honeypot {
+    decoys: Vec<String>,
+    block_for: Option<Duration>,
+    listed: DashMap<IpAddr, Listing>,
+}
+
+impl Honeypot {
+    /// `None` without `--honeypot` or a `--honeypot-path`
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        if !config.honeypot_enabled() {
+            return None;
+        }
+        let defaults = DEFAULT_DECOY_PATHS
+            .iter()
+            .filter(|_| config.honeypot)
+            .map(|path| path.to_string());
+        Some(Self {
+            decoys: defaults
+                .chain(config.honeypot_paths.iter().cloned())
+                .collect(),
+            block_for: (config.honeypot_block_secs > 0)
+                .then(|| Duration::from_secs(config.honeypot_block_secs)),
+            listed: DashMap::new(),
+        })
+    }
+
+    fn is_decoy(&self, path: &str) -> bool {
+        self.decoys
+            .iter()
+            .any(|decoy| match decoy.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == decoy,
+            })
+    }
+
+    /// Flags `ip` for probing `path`, and blocks it unless blocking is off
+    fn record_probe(&self, ip: IpAddr, path: &str, now: Instant) {
+        if self.listed.len() >= MAX_LISTED_IPS && !self.listed.contains_key(&ip) {
+            self.listed
+                .retain(|_, listing| listing.blocked_until.is_some_and(|until| until > now));
+            if self.listed.len() >= MAX_LISTED_IPS {
+                warn!("{} probed decoy {}, but the denylist is full", ip, path);
+                return;
+            }
+        }
+
+        let seen = unix_now();
+        let mut listing = self.listed.entry(ip).or_insert_with(|| Listing {
+            path: path.to_string(),
+            probes: 0,
+            first_seen: seen,
+            last_seen: seen,
+            blocked_until: None,
+            refused_requests: 0,
+        });
+        listing.probes += 1;
+        listing.last_seen = seen;
+        if listing.probes == 1 {
+            match self.block_for {
+                Some(block_for) => warn!(
+                    "{} probed decoy {}; blocking it for {}s",
+                    ip,
+                    path,
+                    block_for.as_secs()
+                ),
+                None => warn!("{} probed decoy {}", ip, path),
+            }
+        }
+        if let Some(block_for) = self.block_for {
+            listing.blocked_until = Some(now + block_for);
+        }
+    }
+
+    /// Whether `ip` is blocked, counting the request it is refused
+    fn refuses(&self, ip: IpAddr, now: Instant) -> bool {
+        let Some(mut listing) = self.listed.get_mut(&ip) else {
+            return false;
+        };
+        let blocked = listing.blocked_until.is_some_and(|until| until > now);
+        if blocked {
+            listing.refused_requests += 1;
+        }
+        blocked
+    }
+
+    /// IPs that probed a decoy, most recent first
+    pub(crate) fn list(&self) -> Vec<Value> {
+        let now = Instant::now();
+        let mut ips: Vec<_> = self
+            .listed
+            .iter()
+            .map(|entry| {
+                let listing = entry.value();
+                let blocked_for = listing
+                    .blocked_until
+                    .map(|until| until.saturating_duration_since(now).as_secs())
+                    .filter(|&secs| secs > 0);
+                json!({
+                    "ip": entry.key().to_string(),
+                    "path": listing.path,
+                    "probes": listing.probes,
+                    "first_seen": listing.first_seen,
+                    "last_seen": listing.last_seen,
+                    "blocked": blocked_for.is_some(),
+                    "blocked_for_secs": blocked_for,
+                    "refused_requests": listing.refused_requests,
+                })
+            })
+            .collect();
+        ips.sort_by(|a, b| b["last_seen"].as_u64().cmp(&a["last_seen"].as_u64()));
+        ips
+    }
+
+    /// Takes `ip` off the denylist. Returns `false` if it was not listed.
+    pub(crate) fn unblock(&self, ip: &str) -> bool {
+        ip.parse::<IpAddr>()
+            .is_ok_and(|ip| self.listed.remove(&ip).is_some())
+    }
+}
+
+/// Rejects decoys that would shadow the proxy's own routes or never match
+pub(crate) fn check_decoy_path(path: &str) -> anyhow::Result<()> {
+    let prefix = path.strip_suffix('*');
+    let base = prefix.unwrap_or(path);
+    if !base.starts_with('/') || base.contains(['*', '{', '}']) {
+        anyhow::bail!(
+            "Invalid --honeypot-path '{}': expected a path such as /.env or /wp-admin*",
+            path
+        );
+    }
+    let shadows = |served: &str| match prefix {
+        Some(prefix) => served.starts_with(prefix),
+        None => served == base,
+    };
+    if SERVED_PATHS.into_iter().any(shadows)
+        || SERVED_PREFIXES
+            .into_iter()
+            .any(|served| base.starts_with(served) || shadows(served))
+    {
+        anyhow::bail!(
+            "--honeypot-path '{}' would catch requests the proxy serves",
+            path
+        );
+    }
+    Ok(())
+}
+
+/// Answers decoys with a plain 404, blocking whoever asked, and refuses
+/// every request from blocked IPs
+pub(crate) async fn catch_scanners(
+    State(state): State<Arc<ProxyState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (Some(honeypot), Some(ip)) = (state.honeypot(), client_ip(request.extensions())) else {
+        return next.run(request).await;
+    };
+    let now = Instant::now();
+    if honeypot.refuses(ip, now) {
+        return blocked_response();
+    }
+    if honeypot.is_decoy(request.uri().path()) {
+        honeypot.record_probe(ip, request.uri().path(), now);
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    next.run(request).await
+}
+
+fn blocked_response() -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(
+            OpenAIError::invalid_request_error(
+                "Requests from your IP address are blocked after it probed for vulnerabilities.",
+            )
+            .with_code("ip_blocked"),
+        ),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([203, 0, 113, last])
+    }
+
+    #[test]
+    fn decoys_match_exactly_or_by_prefix() {
+        let config = config()
+            .with_honeypot(true)
+            .with_honeypot_path("/backup.sql");
+        let honeypot = Honeypot::new(&config).unwrap();
+        for path in [
+            "/.env",
+            "/.env.production",
+            "/wp-admin/setup.php",
+            "/backup.sql",
+        ] {
+            assert!(honeypot.is_decoy(path), "{}", path);
+        }
+        for path in [
+            "/v1/models",
+            "/health",
+            "/wp-login.php.bak",
+            "/backup.sql.gz",
+        ] {
+            assert!(!honeypot.is_decoy(path), "{}", path);
+        }
+
+        let custom_only = Honeypot::new(&config().with_honeypot_path("/backup.sql")).unwrap();
+        assert!(!custom_only.is_decoy("/.env"));
+        assert!(Honeypot::new(&config()).is_none());
+    }
+
+    #[test]
+    fn probes_block_until_the_block_ends() {
+        let honeypot =
+            Honeypot::new(&config().with_honeypot(true).with_honeypot_block_secs(60)).unwrap();
+        let now = Instant::now();
+
+        honeypot.record_probe(ip(1), "/.env", now);
+        assert!(honeypot.refuses(ip(1), now));
+        assert!(!honeypot.refuses(ip(2), now));
+        assert!(!honeypot.refuses(ip(1), now + Duration::from_secs(60)));
+
+        let listed = honeypot.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["ip"], "203.0.113.1");
+        assert_eq!(listed[0]["path"], "/.env");
+        assert_eq!(listed[0]["refused_requests"], 1);
+
+        assert!(honeypot.unblock("203.0.113.1"));
+        assert!(!honeypot.unblock("203.0.113.1"));
+        assert!(!honeypot.unblock("not an ip"));
+        assert!(!honeypot.refuses(ip(1), now));
+    }
+
+    #[test]
+    fn probes_are_only_flagged_without_blocking() {
+        let honeypot =
+            Honeypot::new(&config().with_honeypot(true).with_honeypot_block_secs(0)).unwrap();
+        let now = Instant::now();
+        honeypot.record_probe(ip(3), "/xmlrpc.php", now);
+        honeypot.record_probe(ip(3), "/.git/config", now);
+
+        assert!(!honeypot.refuses(ip(3), now));
+        let listed = honeypot.list();
+        assert_eq!(listed[0]["probes"], 2);
+        assert_eq!(listed[0]["path"], "/xmlrpc.php");
+        assert_eq!(listed[0]["blocked"], false);
+    }
+
+    #[test]
+    fn decoys_cannot_shadow_served_routes() {
+        for path in DEFAULT_DECOY_PATHS {
+            assert!(check_decoy_path(path).is_ok(), "{}", path);
+        }
+        for path in ["/backup.sql", "/wp-admin*", "/old/*"] {
+            assert!(check_decoy_path(path).is_ok(), "{}", path);
+        }
+        for path in [
+            "/health",
+            "/v1/anything",
+            "/v*",
+            "/*",
+            "/admin*",
+            "backup.sql",
+            "/a*b",
+        ] {
+            assert!(check_decoy_path(path).is_err(), "{}", path);
+        }
+    }
+}
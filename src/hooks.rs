@@ -0,0 +1,238 @@
+use crate::{config::OpenAIError, proxy::ProxyState, validation::body_too_large};
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::State,
+    http::{HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use std::{future::Future, pin::Pin, sync::Arc};
+
+/// The future returned by [`ProxyHook`] methods
+pub type HookFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Custom logic run on every inference request, e.g. logging, prompt rewriting
+/// or policy checks. Register hooks with
+/// [`create_app_with_hooks`](crate::create_app_with_hooks); they run in
+/// registration order. Every method has a no-op default.
+pub trait ProxyHook: Send + Sync {
+    /// Runs before the request is handled. Changes to the headers or body are
+    /// what the proxy then sees; a rejection is returned to the client instead.
+    fn on_request<'a>(
+        &'a self,
+        request: &'a mut HookRequest,
+    ) -> HookFuture<'a, Result<(), HookRejection>> {
+        let _ = request;
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Runs once the response status and headers are known, before any of the
+    /// body is sent. Changes to the status or headers go to the client.
+    fn on_response<'a>(&'a self, response: &'a mut HookResponse) -> HookFuture<'a, ()> {
+        let _ = response;
+        Box::pin(async {})
+    }
+
+    /// Runs on each piece of the response body as it passes through, which for
+    /// streams is usually one or a few server-sent events
+    fn on_chunk<'a>(
+        &'a self,
+        response: &'a HookResponse,
+        chunk: &'a mut Bytes,
+    ) -> HookFuture<'a, ()> {
+        let _ = (response, chunk);
+        Box::pin(async {})
+    }
+}
+
+/// An inference request, as sent by the client
+#[derive(Debug, Clone)]
+pub struct HookRequest {
+    pub method: Method,
+    /// The request path, e.g. `/v1/chat/completions` or `/api/chat`
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// The response to an inference request
+#[derive(Debug, Clone)]
+pub struct HookResponse {
+    /// The path of the request this answers
+    pub path: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+}
+
+/// Refuses a request with an OpenAI-style error
+#[derive(Debug, Clone)]
+pub struct HookRejection {
+    status: StatusCode,
+    message: String,
+}
+
+impl HookRejection {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// Runs the registered hooks around an inference request
+pub(crate) async fn run_hooks(
+    State(state): State<Arc<ProxyState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let hooks = state.hooks();
+    if hooks.is_empty() {
+        return next.run(request).await;
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let body = match to_bytes(body, state.config().max_body_bytes()).await {
+        Ok(body) => body,
+        Err(_) => return body_too_large(state.config().max_body_mb).into_response(),
+    };
+    let mut hook_request = HookRequest {
+        method: parts.method.clone(),
+        path: parts.uri.path().to_string(),
+        headers: parts.headers.clone(),
+        body,
+    };
+    for hook in hooks {
+        if let Err(rejection) = hook.on_request(&mut hook_request).await {
+            return (
+                rejection.status,
+                Json(OpenAIError::invalid_request_error(rejection.message)),
+            )
+                .into_response();
+        }
+    }
+    parts.headers = hook_request.headers;
+    let response = next
+        .run(Request::from_parts(parts, Body::from(hook_request.body)))
+        .await;
+
+    let (mut parts, body) = response.into_parts();
+    let mut hook_response = HookResponse {
+        path: hook_request.path,
+        status: parts.status,
+        headers: parts.headers.clone(),
+    };
+    for hook in hooks {
+        hook.on_response(&mut hook_response).await;
+    }
+    parts.status = hook_response.status;
+    parts.headers = hook_response.headers.clone();
+
+    let hooks = hooks.to_vec();
+    let mut stream = body.into_data_stream();
+    let body = Body::from_stream(async_stream::stream! {
+        while let Some(chunk) = stream.next().await {
+            let mut chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(error) => {
+                    yield Err(error);
+                    return;
+                }
+            };
+            for hook in &hooks {
+                hook.on_chunk(&hook_response, &mut chunk).await;
+            }
+            yield Ok(chunk);
+        }
+    });
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, create_app_with_hooks};
+    use axum::http::HeaderValue;
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+
+    /// Rejects requests for one model and records what it saw
+    #[derive(Default)]
+    struct Policy {
+        chunks: Mutex<Vec<Bytes>>,
+    }
+
+    impl ProxyHook for Policy {
+        fn on_request<'a>(
+            &'a self,
+            request: &'a mut HookRequest,
+        ) -> HookFuture<'a, Result<(), HookRejection>> {
+            Box::pin(async move {
+                if request.body.windows(7).any(|window| window == b"blocked") {
+                    return Err(HookRejection::new(
+                        StatusCode::FORBIDDEN,
+                        "That model is not allowed here.",
+                    ));
+                }
+                Ok(())
+            })
+        }
+
+        fn on_response<'a>(&'a self, response: &'a mut HookResponse) -> HookFuture<'a, ()> {
+            Box::pin(async move {
+                response
+                    .headers
+                    .insert("x-policy", HeaderValue::from_static("checked"));
+            })
+        }
+
+        fn on_chunk<'a>(
+            &'a self,
+            _response: &'a HookResponse,
+            chunk: &'a mut Bytes,
+        ) -> HookFuture<'a, ()> {
+            Box::pin(async move {
+                self.chunks.lock().unwrap().push(chunk.clone());
+            })
+        }
+    }
+
+    fn tokenize(body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/v1/tokenize")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn hooks_can_reject_requests_and_see_responses() {
+        let policy = Arc::new(Policy::default());
+        let app = create_app_with_hooks(
+            Config::new(
+                "127.0.0.1".to_string(),
+                0,
+                "http://localhost:3000".to_string(),
+            ),
+            vec![Arc::clone(&policy) as Arc<dyn ProxyHook>],
+        );
+
+        let response = app
+            .clone()
+            .oneshot(tokenize(r#"{"model":"blocked","input":"hi"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app
+            .oneshot(tokenize(r#"{"input":"hello world"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-policy"], "checked");
+        let body = to_bytes(response.into_body(), 64 * 1024).await.unwrap();
+        assert_eq!(policy.chunks.lock().unwrap().concat(), body.to_vec());
+    }
+}
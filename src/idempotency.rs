@@ -0,0 +1,380 @@
+use crate::{
+    cache::entry_id,
+    config::OpenAIError,
+    proxy::{ProxyError, ProxyState},
+};
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::Response,
+    Json,
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::StreamExt;
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+const IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Set on responses replayed for a repeated key, as Stripe does
+const REPLAYED_HEADER: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+const MAX_KEY_LEN: usize = 255;
+
+/// Larger responses are sent without being kept, so a retry runs again
+const MAX_STORED_BODY_BYTES: usize = 1024 * 1024;
+
+/// Keys tracked at once; past this, requests with new keys run untracked
+const MAX_ENTRIES: usize = 10_000;
+
+/// A key's request, and its response once one has been sent in full
+struct Stored {
+    /// Tells a retry from a different request sent with the same key
+    fingerprint: String,
+    stored_at: Instant,
+    response: Option<StoredResponse>,
+}
+
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// Successful chat completions by `Idempotency-Key`, per API key, so a client
+/// retrying a request it never got the answer to is sent the first answer
+/// again instead of paying for a second one
+pub(crate) struct IdempotencyStore {
+    ttl: Duration,
+    entries: DashMap<(String, String), Stored>,
+}
+
+/// What a request with a key does
+enum Claim {
+    /// Sends the stored response again
+    Replay(Response),
+    /// Runs the request, recording its response unless too many keys are
+    /// tracked already
+    Send(Option<Recording>),
+}
+
+impl IdempotencyStore {
+    /// `None` when `--idempotency-ttl-secs` is 0
+    pub(crate) fn new(ttl_secs: u64) -> Option<Self> {
+        (ttl_secs > 0).then(|| Self {
+            ttl: Duration::from_secs(ttl_secs),
+            entries: DashMap::new(),
+        })
+    }
+
+    fn is_expired(&self, stored: &Stored, now: Instant) -> bool {
+        stored.response.is_some() && now.saturating_duration_since(stored.stored_at) >= self.ttl
+    }
+
+    fn claim(
+        self: &Arc<Self>,
+        owner: String,
+        key: String,
+        fingerprint: String,
+    ) -> Result<Claim, ProxyError> {
+        let now = Instant::now();
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries
+                .retain(|_, stored| !self.is_expired(stored, now));
+        }
+        let full = self.entries.len() >= MAX_ENTRIES;
+        let in_flight = Stored {
+            fingerprint: fingerprint.clone(),
+            stored_at: now,
+            response: None,
+        };
+        match self.entries.entry((owner, key)) {
+            Entry::Occupied(entry) if !self.is_expired(entry.get(), now) => {
+                let stored = entry.get();
+                if stored.fingerprint != fingerprint {
+                    return Err(key_error(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "This Idempotency-Key was already used with a different request body.",
+                        "idempotency_key_reused",
+                    ));
+                }
+                match &stored.response {
+                    Some(response) => Ok(Claim::Replay(response.replay())),
+                    None => Err(key_error(
+                        StatusCode::CONFLICT,
+                        "A request with this Idempotency-Key is still in progress; retry once \
+                         it finishes.",
+                        "idempotency_key_in_progress",
+                    )),
+                }
+            }
+            Entry::Occupied(mut entry) => {
+                entry.insert(in_flight);
+                let recording = Recording::new(self, entry.key(), fingerprint);
+                Ok(Claim::Send(Some(recording)))
+            }
+            Entry::Vacant(_) if full => Ok(Claim::Send(None)),
+            Entry::Vacant(entry) => {
+                let recording = Recording::new(self, entry.key(), fingerprint);
+                entry.insert(in_flight);
+                Ok(Claim::Send(Some(recording)))
+            }
+        }
+    }
+}
+
+impl StoredResponse {
+    fn replay(&self) -> Response {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+            .headers_mut()
+            .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+/// A response on its way to the client, kept once it has been sent in full.
+/// Dropped before then, it forgets the key, so the client can retry.
+struct Recording {
+    store: Arc<IdempotencyStore>,
+    key: (String, String),
+    fingerprint: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    finished: bool,
+}
+
+impl Recording {
+    fn new(store: &Arc<IdempotencyStore>, key: &(String, String), fingerprint: String) -> Self {
+        Self {
+            store: Arc::clone(store),
+            key: key.clone(),
+            fingerprint,
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Adds a chunk of the body, or returns `false` once it is too large to
+    /// keep
+    fn push(&mut self, chunk: &[u8]) -> bool {
+        if self.body.len() + chunk.len() > MAX_STORED_BODY_BYTES {
+            return false;
+        }
+        self.body.extend_from_slice(chunk);
+        true
+    }
+
+    fn finish(mut self) {
+        self.finished = true;
+        let response = StoredResponse {
+            status: self.status,
+            headers: std::mem::take(&mut self.headers),
+            body: Bytes::from(std::mem::take(&mut self.body)),
+        };
+        if let Some(mut stored) = self.store.entries.get_mut(&self.key) {
+            if stored.fingerprint == self.fingerprint && stored.response.is_none() {
+                stored.stored_at = Instant::now();
+                stored.response = Some(response);
+            }
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.store.entries.remove_if(&self.key, |_, stored| {
+                stored.response.is_none() && stored.fingerprint == self.fingerprint
+            });
+        }
+    }
+}
+
+/// Sends a chat completion, or, for a repeated `Idempotency-Key` from the same
+/// API key and with the same body, the response the first request got.
+/// Streams are recorded as they are relayed. Only successful responses are
+/// kept, so failed requests can be retried with the same key.
+pub(crate) async fn replay_or_send<F, Fut>(
+    state: &ProxyState,
+    headers: &HeaderMap,
+    body: Bytes,
+    send: F,
+) -> Result<Response, ProxyError>
+where
+    F: FnOnce(Bytes) -> Fut,
+    Fut: Future<Output = Result<Response, ProxyError>>,
+{
+    let (Some(store), Some(key)) = (state.idempotency(), headers.get(IDEMPOTENCY_KEY_HEADER))
+    else {
+        return send(body).await;
+    };
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LEN)
+        .ok_or_else(|| {
+            key_error(
+                StatusCode::BAD_REQUEST,
+                "Idempotency-Key must be 1 to 255 visible ASCII characters.",
+                "invalid_idempotency_key",
+            )
+        })?
+        .to_string();
+    let owner = state.key_owner(headers)?;
+    let fingerprint = entry_id(&[&body]);
+
+    let recording = match store.claim(owner, key, fingerprint)? {
+        Claim::Replay(response) => {
+            debug!("Replayed a response for a repeated Idempotency-Key");
+            return Ok(response);
+        }
+        Claim::Send(recording) => recording,
+    };
+    let response = send(body).await?;
+    match recording {
+        Some(recording) if response.status().is_success() => Ok(record(response, recording)),
+        _ => Ok(response),
+    }
+}
+
+/// The response, with its body recorded as the client receives it
+fn record(response: Response, mut recording: Recording) -> Response {
+    let (parts, body) = response.into_parts();
+    recording.status = parts.status;
+    recording.headers = parts.headers.clone();
+    let chunks = futures::stream::unfold(
+        (body.into_data_stream(), Some(recording)),
+        |(mut chunks, mut recording)| async move {
+            match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    if recording
+                        .as_mut()
+                        .is_some_and(|recording| !recording.push(&chunk))
+                    {
+                        recording = None;
+                    }
+                    Some((Ok(chunk), (chunks, recording)))
+                }
+                Some(Err(read_error)) => Some((Err(read_error), (chunks, None))),
+                None => {
+                    if let Some(recording) = recording {
+                        recording.finish();
+                    }
+                    None
+                }
+            }
+        },
+    );
+    Response::from_parts(parts, Body::from_stream(chunks))
+}
+
+fn key_error(status: StatusCode, message: &str, code: &str) -> ProxyError {
+    (
+        status,
+        Json(
+            OpenAIError::invalid_request_error(message)
+                .with_param("Idempotency-Key")
+                .with_code(code),
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use axum::{
+        body::to_bytes,
+        http::{Method, Request},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    async fn send(
+        app: &Router,
+        key: &str,
+        api_key: &str,
+        content: &str,
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        let body = serde_json::json!({
+            "model": "llama3-3-70b",
+            "messages": [{"role": "user", "content": content}]
+        });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/v1/chat/completions")
+            .header("content-type", "application/json")
+            .header("authorization", format!("Bearer {}", api_key))
+            .header(IDEMPOTENCY_KEY_HEADER, key)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        (parts.status, parts.headers, body)
+    }
+
+    #[tokio::test]
+    async fn repeated_keys_replay_the_first_response() {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_mock_backend(true);
+        let app = crate::create_app(config);
+
+        let (status, headers, first) = send(&app, "order-1", "sk-a", "Hi").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.get(REPLAYED_HEADER).is_none());
+        let (status, headers, replayed) = send(&app, "order-1", "sk-a", "Hi").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[REPLAYED_HEADER], "true");
+        assert_eq!(replayed, first);
+
+        let (status, _, _) = send(&app, "order-1", "sk-a", "Hello").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (_, headers, _) = send(&app, "order-1", "sk-b", "Hi").await;
+        assert!(headers.get(REPLAYED_HEADER).is_none());
+    }
+
+    #[test]
+    fn keys_in_flight_conflict_until_their_request_ends() {
+        let store = Arc::new(IdempotencyStore::new(60).unwrap());
+        let claim = |fingerprint: &str| {
+            store.claim(
+                "owner".to_string(),
+                "key".to_string(),
+                fingerprint.to_string(),
+            )
+        };
+
+        let Ok(Claim::Send(Some(recording))) = claim("a") else {
+            panic!("the first request is sent");
+        };
+        assert_eq!(claim("a").err().unwrap().0, StatusCode::CONFLICT);
+        assert_eq!(
+            claim("b").err().unwrap().0,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        // A request that fails or is abandoned frees its key
+        drop(recording);
+        let Ok(Claim::Send(Some(mut recording))) = claim("a") else {
+            panic!("the retry is sent");
+        };
+        assert!(recording.push(b"{}"));
+        recording.finish();
+        assert!(matches!(claim("a"), Ok(Claim::Replay(_))));
+    }
+}
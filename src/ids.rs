@@ -0,0 +1,207 @@
+use clap::ValueEnum;
+use serde_json::Value;
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Snowflake timestamps count milliseconds from 2024-01-01T00:00:00Z
+const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000;
+const SNOWFLAKE_SEQUENCE_BITS: u32 = 12;
+const SNOWFLAKE_WORKER_BITS: u32 = 10;
+
+/// The largest worker ID that fits a snowflake
+pub(crate) const MAX_SNOWFLAKE_WORKER_ID: u16 = (1 << SNOWFLAKE_WORKER_BITS) - 1;
+
+/// Crockford's base32, which ULIDs are written in
+const CROCKFORD_BASE32: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// How chat completion and request IDs are made
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum IdFormat {
+    /// Keep the IDs the backend issues
+    #[default]
+    Backend,
+    /// Time-ordered UUIDs, such as `0192a3b4-c5d6-7e8f-9a0b-1c2d3e4f5a6b`
+    Uuidv7,
+    /// Time-ordered 26-character ULIDs, such as `01JA5Z3K8XQ2M4N6P8R0T2V4W6`
+    Ulid,
+    /// 64-bit snowflakes in decimal: a millisecond timestamp, the worker ID,
+    /// and a sequence number
+    Snowflake,
+}
+
+/// Mints proxy-level IDs in one format
+pub(crate) struct IdGenerator {
+    format: IdFormat,
+    worker_id: u16,
+    /// The millisecond and sequence number of the last snowflake
+    last_snowflake: Mutex<(u64, u64)>,
+}
+
+impl IdGenerator {
+    /// `None` when the backend's IDs are kept
+    pub(crate) fn new(format: IdFormat, worker_id: u16) -> Option<Self> {
+        (format != IdFormat::Backend).then(|| Self {
+            format,
+            worker_id,
+            last_snowflake: Mutex::new((0, 0)),
+        })
+    }
+
+    pub(crate) fn mint(&self) -> String {
+        let now_ms = unix_now_ms();
+        match self.format {
+            IdFormat::Backend | IdFormat::Uuidv7 => uuidv7(now_ms, random_bytes()),
+            IdFormat::Ulid => ulid(now_ms, random_bytes()),
+            IdFormat::Snowflake => self.snowflake(now_ms).to_string(),
+        }
+    }
+
+    /// Snowflakes minted in the same millisecond take the next sequence
+    /// number; past the last one, they borrow the next millisecond so IDs
+    /// stay unique and ordered.
+    fn snowflake(&self, now_ms: u64) -> u64 {
+        let mut last = self
+            .last_snowflake
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (last_ms, last_sequence) = *last;
+        let elapsed_ms = now_ms.saturating_sub(SNOWFLAKE_EPOCH_MS);
+        let (ms, sequence) = if elapsed_ms > last_ms {
+            (elapsed_ms, 0)
+        } else if last_sequence + 1 < 1 << SNOWFLAKE_SEQUENCE_BITS {
+            (last_ms, last_sequence + 1)
+        } else {
+            (last_ms + 1, 0)
+        };
+        *last = (ms, sequence);
+
+        (ms << (SNOWFLAKE_WORKER_BITS + SNOWFLAKE_SEQUENCE_BITS))
+            | (u64::from(self.worker_id) << SNOWFLAKE_SEQUENCE_BITS)
+            | sequence
+    }
+}
+
+/// Replaces the `id` of a chat completion or chunk. JSON without one, such as
+/// an error event, is left alone.
+pub(crate) fn replace_completion_id(completion: &mut Value, id: &str) {
+    if let Some(existing) = completion.get_mut("id") {
+        *existing = Value::from(id);
+    }
+}
+
+/// Seconds since the Unix epoch
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Milliseconds since the Unix epoch
+pub(crate) fn unix_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    bytes
+}
+
+/// `N` random bytes in lowercase hex, for IDs and secrets
+pub(crate) fn random_hex<const N: usize>() -> String {
+    to_hex(&random_bytes::<N>())
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|start| u8::from_str_radix(&hex[start..start + 2], 16).ok())
+        .collect()
+}
+
+/// A UUIDv7 (RFC 9562): 48 bits of milliseconds, then version, variant, and
+/// random bits
+fn uuidv7(now_ms: u64, random: [u8; 10]) -> String {
+    let mut bytes = [0; 16];
+    bytes[..6].copy_from_slice(&now_ms.to_be_bytes()[2..]);
+    bytes[6..].copy_from_slice(&random);
+    bytes[6] = 0x70 | (bytes[6] & 0x0f);
+    bytes[8] = 0x80 | (bytes[8] & 0x3f);
+
+    let hex = to_hex(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// A ULID: 48 bits of milliseconds and 80 random bits in 26 base32 digits
+fn ulid(now_ms: u64, random: [u8; 10]) -> String {
+    let mut value = u128::from(now_ms & ((1 << 48) - 1));
+    for byte in random {
+        value = (value << 8) | u128::from(byte);
+    }
+    (0..26)
+        .map(|digit| {
+            let shift = 125 - 5 * digit;
+            char::from(CROCKFORD_BASE32[((value >> shift) & 0x1f) as usize])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANDOM: [u8; 10] = [0xff; 10];
+
+    #[test]
+    fn formats_uuidv7_and_ulid() {
+        let now_ms = 0x0192_a3b4_c5d6;
+        assert_eq!(
+            uuidv7(now_ms, RANDOM),
+            "0192a3b4-c5d6-7fff-bfff-ffffffffffff"
+        );
+        assert_eq!(ulid(now_ms, RANDOM), "01JAHV9HEPZZZZZZZZZZZZZZZZ");
+        assert_eq!(ulid(0, [0; 10]), "0".repeat(26));
+    }
+
+    #[test]
+    fn snowflakes_are_unique_and_ordered() {
+        let ids = IdGenerator::new(IdFormat::Snowflake, 3).unwrap();
+        let now_ms = SNOWFLAKE_EPOCH_MS + 1000;
+
+        let first = ids.snowflake(now_ms);
+        assert_eq!(first, (1000 << 22) | (3 << 12));
+        let mut last = first;
+        for _ in 0..5000 {
+            let next = ids.snowflake(now_ms);
+            assert!(next > last);
+            last = next;
+        }
+        assert_eq!(last >> 22, 1001);
+        assert_eq!((last >> 12) & 0x3ff, 3);
+    }
+
+    #[test]
+    fn backend_ids_need_no_generator() {
+        assert!(IdGenerator::new(IdFormat::Backend, 0).is_none());
+        let ids = IdGenerator::new(IdFormat::Uuidv7, 0).unwrap();
+        assert_ne!(ids.mint(), ids.mint());
+    }
+}
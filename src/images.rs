@@ -0,0 +1,306 @@
+use crate::{
+    config::{Config, OpenAIError},
+    geo,
+};
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header, redirect,
+};
+use serde_json::Value;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{debug, error};
+
+const IMAGE_FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_REDIRECTS: usize = 5;
+
+/// Addresses an image URL from a client must not reach: the proxy's own
+/// host and the networks it sits in
+fn is_private(ip: IpAddr) -> bool {
+    ip.to_canonical().is_unspecified() || geo::is_local(ip)
+}
+
+/// A URL whose host is a private IP address, which no resolver sees
+fn names_private_ip(url: &str) -> bool {
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+    host.and_then(|host| host.trim_matches(['[', ']']).parse().ok())
+        .is_some_and(is_private)
+}
+
+/// Resolves image hosts to their public addresses only, so a client cannot
+/// have the proxy fetch from internal services by naming them
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| !is_private(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Downloads the remote images of chat completion requests and sends them
+/// inline, as base64 data URLs, for backends that cannot fetch URLs from
+/// inside the enclave
+pub(crate) struct ImageFetcher {
+    client: reqwest::Client,
+    max_bytes: usize,
+    media_types: Vec<String>,
+    allow_private: bool,
+}
+
+impl ImageFetcher {
+    /// `None` without `--fetch-images`
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        if !config.fetch_images {
+            return None;
+        }
+        let allow_private = config.image_fetch_private;
+        let redirects = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if !allow_private && names_private_ip(attempt.url().as_str()) {
+                attempt.error("redirected to a private address")
+            } else {
+                attempt.follow()
+            }
+        });
+        let mut client = reqwest::Client::builder()
+            .timeout(IMAGE_FETCH_TIMEOUT)
+            .redirect(redirects);
+        if !allow_private {
+            client = client.dns_resolver(Arc::new(PublicResolver));
+        }
+        let client = client
+            .build()
+            .map_err(|build_error| error!("Failed to set up image fetching: {}", build_error))
+            .ok()?;
+        Some(Self {
+            client,
+            max_bytes: usize::try_from(config.image_max_mb.saturating_mul(1024 * 1024))
+                .unwrap_or(usize::MAX),
+            media_types: config
+                .image_types
+                .iter()
+                .map(|media_type| media_type.trim().to_ascii_lowercase())
+                .collect(),
+            allow_private,
+        })
+    }
+
+    /// The request with every `http` and `https` `image_url` part replaced by
+    /// the image as a data URL. An image that cannot be fetched, or is too
+    /// large or of the wrong type, rejects the request, naming the part.
+    pub(crate) async fn inline_images(&self, body: Bytes) -> Result<Bytes, OpenAIError> {
+        let Ok(mut request) = serde_json::from_slice::<Value>(&body) else {
+            return Ok(body);
+        };
+        let mut remote = Vec::new();
+        let messages = request["messages"].as_array().into_iter().flatten();
+        for (message_index, message) in messages.enumerate() {
+            let parts = message["content"].as_array().into_iter().flatten();
+            for (part_index, part) in parts.enumerate() {
+                let Some(url) = part["image_url"]["url"].as_str() else {
+                    continue;
+                };
+                if part["type"] == "image_url"
+                    && (url.starts_with("http://") || url.starts_with("https://"))
+                {
+                    remote.push((message_index, part_index, url.to_string()));
+                }
+            }
+        }
+        if remote.is_empty() {
+            return Ok(body);
+        }
+
+        let fetches = remote.iter().map(|(_, _, url)| self.fetch(url));
+        let images = futures::future::join_all(fetches).await;
+        for ((message_index, part_index, url), image) in remote.into_iter().zip(images) {
+            let data_url = image.map_err(|reason| {
+                OpenAIError::invalid_request_error(format!("Image {} {}", url, reason)).with_param(
+                    format!(
+                        "messages[{}].content[{}].image_url.url",
+                        message_index, part_index
+                    ),
+                )
+            })?;
+            request["messages"][message_index]["content"][part_index]["image_url"]["url"] =
+                Value::String(data_url);
+        }
+        Ok(serde_json::to_vec(&request).map_or(body, Bytes::from))
+    }
+
+    /// The image at `url` as a data URL, or why it was refused
+    async fn fetch(&self, url: &str) -> Result<String, String> {
+        if !self.allow_private && names_private_ip(url) {
+            return Err("is on a private network".to_string());
+        }
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|fetch_error| format!("could not be fetched: {}", fetch_error))?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "could not be fetched: the server answered {}",
+                response.status()
+            ));
+        }
+        let media_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|media_type| media_type.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if !self.media_types.contains(&media_type) {
+            return Err(format!(
+                "is of type '{}', not one of {}",
+                media_type,
+                self.media_types.join(", ")
+            ));
+        }
+        let too_large = || format!("is larger than {} bytes", self.max_bytes);
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.max_bytes as u64)
+        {
+            return Err(too_large());
+        }
+
+        let mut image = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|read_error| format!("could not be read: {}", read_error))?
+        {
+            image.extend_from_slice(&chunk);
+            if image.len() > self.max_bytes {
+                return Err(too_large());
+            }
+        }
+        debug!("Inlined a {} byte {} image", image.len(), media_type);
+        Ok(format!(
+            "data:{};base64,{}",
+            media_type,
+            BASE64.encode(image)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+
+    async fn image_server() -> SocketAddr {
+        let app = Router::new()
+            .route(
+                "/cat.png",
+                get(|| async { ([("content-type", "image/png")], &b"\x89PNG"[..]) }),
+            )
+            .route(
+                "/page",
+                get(|| async { ([("content-type", "text/html")], "<html></html>") }),
+            )
+            .route(
+                "/huge.png",
+                get(|| async { ([("content-type", "image/png")], vec![0u8; 2 * 1024 * 1024]) }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    fn fetcher(allow_private: bool) -> ImageFetcher {
+        let mut config = Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_image_fetching(1);
+        config.image_fetch_private = allow_private;
+        ImageFetcher::new(&config).unwrap()
+    }
+
+    fn request(url: &str) -> Bytes {
+        let request = serde_json::json!({
+            "model": "m",
+            "messages": [{
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image_url", "image_url": {"url": url, "detail": "low"}}
+                ]
+            }]
+        });
+        Bytes::from(request.to_string())
+    }
+
+    #[tokio::test]
+    async fn remote_images_are_inlined_within_the_limits() {
+        let addr = image_server().await;
+        let fetcher = fetcher(true);
+
+        let body = fetcher
+            .inline_images(request(&format!("http://{}/cat.png", addr)))
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let image = &body["messages"][0]["content"][1]["image_url"];
+        assert_eq!(image["url"], "data:image/png;base64,iVBORw==");
+        assert_eq!(image["detail"], "low");
+
+        for (path, reason) in [
+            ("page", "not one of image/png"),
+            ("huge.png", "larger than"),
+            ("missing.png", "404"),
+        ] {
+            let error = fetcher
+                .inline_images(request(&format!("http://{}/{}", addr, path)))
+                .await
+                .unwrap_err();
+            assert!(error.message().contains(reason), "{}", error.message());
+        }
+
+        let inline = request("data:image/png;base64,iVBORw==");
+        assert_eq!(fetcher.inline_images(inline.clone()).await.unwrap(), inline);
+    }
+
+    #[tokio::test]
+    async fn private_addresses_are_refused_by_default() {
+        let addr = image_server().await;
+        let fetcher = fetcher(false);
+
+        for url in [
+            format!("http://{}/cat.png", addr),
+            format!("http://localhost:{}/cat.png", addr.port()),
+        ] {
+            assert!(
+                fetcher.inline_images(request(&url)).await.is_err(),
+                "{}",
+                url
+            );
+        }
+        assert!(names_private_ip("http://[::1]/cat.png"));
+        assert!(names_private_ip("http://169.254.169.254/latest"));
+        assert!(!names_private_ip("https://example.com/cat.png"));
+    }
+}
@@ -0,0 +1,363 @@
+use crate::config::Config;
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_BACKEND_URL: &str = "https://enclave.trymaple.ai";
+const DEFAULT_PORT: u16 = 8080;
+const SYSTEMD_UNIT_FILE: &str = "maple-proxy.service";
+
+#[derive(Args, Debug, Clone)]
+pub struct InitArgs {
+    /// Config file to write, loaded from the working directory on startup
+    #[arg(short, long, value_name = "PATH", default_value = ".env")]
+    pub output: PathBuf,
+
+    /// Overwrite the config file if it already exists
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Where the wizard keeps the default API key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SecretStorage {
+    /// `MAPLE_API_KEY` in the config file, readable only by its owner
+    ConfigFile,
+    /// Not stored; clients send their own `Authorization` header
+    NotStored,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Answers {
+    backend_url: String,
+    api_key: Option<String>,
+    port: u16,
+    enable_cors: bool,
+    systemd_unit: bool,
+}
+
+/// Interactively asks for the essential settings, then writes a validated
+/// config file and, on request, a systemd unit next to it
+pub fn init(args: &InitArgs) -> anyhow::Result<()> {
+    if args.output.exists() && !args.force {
+        bail!(
+            "{} already exists; pass --force to overwrite it",
+            args.output.display()
+        );
+    }
+
+    let stdin = io::stdin();
+    let mut prompter = Prompter {
+        input: stdin.lock(),
+        output: io::stdout(),
+    };
+    let answers = ask(&mut prompter)?;
+    let config = answers.to_config();
+    config.validate()?;
+    config.socket_addr()?;
+
+    write_private_file(&args.output, &config_file(&answers))
+        .with_context(|| format!("failed to write {}", args.output.display()))?;
+    println!("Wrote {}", args.output.display());
+
+    if answers.systemd_unit {
+        let config_path = fs::canonicalize(&args.output)?;
+        let executable = std::env::current_exe()?;
+        let unit_path = config_path.with_file_name(SYSTEMD_UNIT_FILE);
+        fs::write(&unit_path, systemd_unit(&executable, &config_path))
+            .with_context(|| format!("failed to write {}", unit_path.display()))?;
+        println!("Wrote {}. To install it:", unit_path.display());
+        println!("  sudo cp {} /etc/systemd/system/", unit_path.display());
+        println!("  sudo systemctl enable --now maple-proxy");
+    } else {
+        println!("Start the proxy from this directory with: maple-proxy");
+    }
+    Ok(())
+}
+
+fn ask<R: BufRead, W: Write>(prompter: &mut Prompter<R, W>) -> anyhow::Result<Answers> {
+    prompter.say("Maple Proxy setup. Press Enter to accept the [default].")?;
+
+    let backend_url = prompter.ask_valid("Backend URL", Some(DEFAULT_BACKEND_URL), |value| {
+        validate_backend_url(value).map(|_| value.to_string())
+    })?;
+    let storage = prompter.ask_valid(
+        "Store a default API key? 1) in the config file  2) no, clients send their own",
+        Some("1"),
+        |value| match value {
+            "1" => Ok(SecretStorage::ConfigFile),
+            "2" => Ok(SecretStorage::NotStored),
+            _ => Err("enter 1 or 2".to_string()),
+        },
+    )?;
+    let api_key = match storage {
+        SecretStorage::ConfigFile => Some(prompter.ask_valid(
+            "Maple API key (input is visible)",
+            None,
+            |value| validate_value(value).map(|_| value.to_string()),
+        )?),
+        SecretStorage::NotStored => None,
+    };
+    let port = prompter.ask_valid("Port", Some(&DEFAULT_PORT.to_string()), |value| {
+        value
+            .parse::<u16>()
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| "enter a port between 1 and 65535".to_string())
+    })?;
+    let enable_cors =
+        prompter.confirm("Will browser apps call the proxy directly (CORS)?", false)?;
+    let systemd_unit = prompter.confirm("Write a systemd unit?", false)?;
+
+    Ok(Answers {
+        backend_url,
+        api_key,
+        port,
+        enable_cors,
+        systemd_unit,
+    })
+}
+
+impl Answers {
+    fn to_config(&self) -> Config {
+        let mut config = Config::new("127.0.0.1".to_string(), self.port, self.backend_url.clone())
+            .with_cors(self.enable_cors);
+        config.default_api_key = self.api_key.clone();
+        config
+    }
+}
+
+fn validate_backend_url(value: &str) -> Result<(), String> {
+    let uri: http::Uri = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a URL", value))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.authority().is_none() {
+        return Err("enter an http:// or https:// URL".to_string());
+    }
+    validate_value(value)
+}
+
+/// Config values are written unquoted, which both dotenv and systemd's
+/// `EnvironmentFile=` read the same way
+fn validate_value(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Err("a value is required".to_string());
+    }
+    if value
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '\\' | '#'))
+    {
+        return Err("spaces, quotes, backslashes, and '#' are not supported".to_string());
+    }
+    Ok(())
+}
+
+fn config_file(answers: &Answers) -> String {
+    let mut file = String::from("# Maple Proxy configuration, written by `maple-proxy init`\n");
+    file.push_str("MAPLE_HOST=127.0.0.1\n");
+    file.push_str(&format!("MAPLE_PORT={}\n", answers.port));
+    file.push_str(&format!("MAPLE_BACKEND_URL={}\n", answers.backend_url));
+    match &answers.api_key {
+        Some(api_key) => file.push_str(&format!("MAPLE_API_KEY={}\n", api_key)),
+        None => file.push_str("# Clients send their own Authorization: Bearer <key> header\n"),
+    }
+    file.push_str(&format!("MAPLE_ENABLE_CORS={}\n", answers.enable_cors));
+    file
+}
+
+fn systemd_unit(executable: &Path, config_path: &Path) -> String {
+    format!(
+        "[Unit]\n\
+         Description=Maple Proxy\n\
+         After=network-online.target\n\
+         Wants=network-online.target\n\
+         \n\
+         [Service]\n\
+         ExecStart={}\n\
+         EnvironmentFile={}\n\
+         DynamicUser=yes\n\
+         NoNewPrivileges=yes\n\
+         Restart=on-failure\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        executable.display(),
+        config_path.display()
+    )
+}
+
+/// The config file can hold the API key, so only its owner may read it
+fn write_private_file(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(contents.as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        options.open(path)?.write_all(contents.as_bytes())
+    }
+}
+
+struct Prompter<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompter<R, W> {
+    fn say(&mut self, message: &str) -> io::Result<()> {
+        writeln!(self.output, "{}", message)
+    }
+
+    fn ask(&mut self, question: &str, default: Option<&str>) -> anyhow::Result<String> {
+        match default {
+            Some(default) => write!(self.output, "{} [{}]: ", question, default)?,
+            None => write!(self.output, "{}: ", question)?,
+        }
+        self.output.flush()?;
+
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Err(anyhow!("setup cancelled: input ended"));
+        }
+        let answer = line.trim();
+        Ok(match default {
+            Some(default) if answer.is_empty() => default.to_string(),
+            _ => answer.to_string(),
+        })
+    }
+
+    /// Asks until `parse` accepts the answer
+    fn ask_valid<T>(
+        &mut self,
+        question: &str,
+        default: Option<&str>,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> anyhow::Result<T> {
+        loop {
+            let answer = self.ask(question, default)?;
+            match parse(&answer) {
+                Ok(value) => return Ok(value),
+                Err(error) => self.say(&format!("  {}", error))?,
+            }
+        }
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> anyhow::Result<bool> {
+        let default_answer = if default { "Y/n" } else { "y/N" };
+        self.ask_valid(question, Some(default_answer), |answer| {
+            match answer.to_ascii_lowercase().as_str() {
+                "y" | "yes" => Ok(true),
+                "n" | "no" => Ok(false),
+                _ if answer == default_answer => Ok(default),
+                _ => Err("enter y or n".to_string()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answers_for(input: &str) -> (anyhow::Result<Answers>, String) {
+        let mut prompter = Prompter {
+            input: input.as_bytes(),
+            output: Vec::new(),
+        };
+        let answers = ask(&mut prompter);
+        (answers, String::from_utf8(prompter.output).unwrap())
+    }
+
+    #[test]
+    fn defaults_need_only_an_api_key() {
+        let (answers, _) = answers_for("\n\nsk-test\n\n\n\n");
+
+        assert_eq!(
+            answers.unwrap(),
+            Answers {
+                backend_url: DEFAULT_BACKEND_URL.to_string(),
+                api_key: Some("sk-test".to_string()),
+                port: DEFAULT_PORT,
+                enable_cors: false,
+                systemd_unit: false,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_answers_are_asked_again() {
+        let (answers, output) =
+            answers_for("ftp://example.com\nhttp://localhost:3000\n3\n2\n0\n9090\nmaybe\ny\nn\n");
+        let answers = answers.unwrap();
+
+        assert_eq!(answers.backend_url, "http://localhost:3000");
+        assert_eq!(answers.api_key, None);
+        assert_eq!(answers.port, 9090);
+        assert!(answers.enable_cors);
+        assert!(output.contains("enter an http:// or https:// URL"));
+        assert!(output.contains("enter 1 or 2"));
+        assert!(output.contains("enter y or n"));
+    }
+
+    #[test]
+    fn ended_input_cancels_setup() {
+        assert!(answers_for("\n").0.is_err());
+    }
+
+    #[test]
+    fn config_file_round_trips_through_dotenv() {
+        let answers = Answers {
+            backend_url: "http://localhost:3000".to_string(),
+            api_key: Some("sk-test".to_string()),
+            port: 9090,
+            enable_cors: true,
+            systemd_unit: false,
+        };
+        let file = config_file(&answers);
+        let values: Vec<(String, String)> = dotenvy::from_read_iter(file.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert!(values.contains(&("MAPLE_API_KEY".to_string(), "sk-test".to_string())));
+        assert!(values.contains(&("MAPLE_PORT".to_string(), "9090".to_string())));
+        assert!(values.contains(&("MAPLE_ENABLE_CORS".to_string(), "true".to_string())));
+        assert!(answers.to_config().validate().is_ok());
+
+        let without_key = config_file(&Answers {
+            api_key: None,
+            ..answers
+        });
+        assert!(!without_key.contains("MAPLE_API_KEY"));
+    }
+
+    #[test]
+    fn api_keys_with_unsupported_characters_are_rejected() {
+        assert!(validate_value("sk-abc_123").is_ok());
+        assert!(validate_value("").is_err());
+        assert!(validate_value("sk abc").is_err());
+        assert!(validate_value("sk#abc").is_err());
+    }
+
+    #[test]
+    fn systemd_unit_loads_the_config_file() {
+        let unit = systemd_unit(
+            Path::new("/usr/local/bin/maple-proxy"),
+            Path::new("/etc/maple-proxy/.env"),
+        );
+
+        assert!(unit.contains("ExecStart=/usr/local/bin/maple-proxy\n"));
+        assert!(unit.contains("EnvironmentFile=/etc/maple-proxy/.env\n"));
+        assert!(unit.contains("[Install]\nWantedBy=multi-user.target\n"));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::{
+    config::{Config, OpenAIError},
+    forwarded::client_ip,
+    ids::unix_now,
+    proxy::ProxyState,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use serde_json::{json, Value};
+use std::{
+    cell::Cell,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+tokio::task_local! {
+    /// Whether the request `enforce_ip_bans` is running failed
+    /// authentication, for checks that return errors rather than responses
+    static AUTH_REJECTED: Cell<bool>;
+}
+
+/// Marks a 401 the proxy answered itself for a client's credentials. Only
+/// these count toward bans: a backend that rejects the proxy's own key says
+/// nothing about the client.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AuthRejected;
+
+/// Records that the request being served failed authentication, so
+/// `enforce_ip_bans` marks its response with [`AuthRejected`]. This also
+/// catches gRPC calls, whose failures are answered with a 200 and a
+/// `grpc-status`.
+pub(crate) fn note_auth_rejection() {
+    let _ = AUTH_REJECTED.try_with(|rejected| rejected.set(true));
+}
+
+/// Failing IPs come from clients, so past this many tracked IPs, those
+/// neither banned nor failing recently are dropped, and then further failures
+/// from new IPs are only logged
+const MAX_TRACKED_IPS: usize = 16 * 1024;
+
+/// An IP's recent authentication failures, and its ban if they added up
+struct Offender {
+    window_started_at: Instant,
+    failures: u32,
+    banned_until: Option<Instant>,
+    banned_at: u64,
+    bans: u64,
+    refused_requests: u64,
+}
+
+impl Offender {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Bans client IPs for `--ip-ban-secs` once they fail authentication
+/// `--ip-ban-auth-failures` times within `--ip-ban-window-secs`, like fail2ban
+pub(crate) struct IpBans {
+    max_failures: u32,
+    window: Duration,
+    ban_for: Duration,
+    offenders: DashMap<IpAddr, Offender>,
+}
+
+impl IpBans {
+    /// `None` without `--ip-ban-auth-failures`
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        Some(Self {
+            max_failures: config.ip_ban_auth_failures?,
+            window: Duration::from_secs(config.ip_ban_window_secs),
+            ban_for: Duration::from_secs(config.ip_ban_secs),
+            offenders: DashMap::new(),
+        })
+    }
+
+    /// How long `ip` is still banned for, counting the request it is refused
+    fn banned_for(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let mut offender = self.offenders.get_mut(&ip)?;
+        let until = offender.banned_until.filter(|until| *until > now)?;
+        offender.refused_requests += 1;
+        Some(until - now)
+    }
+
+    /// Counts a failed authentication from `ip`. Returns `true` when it
+    /// bans the IP.
+    fn record_failure(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.offenders.len() >= MAX_TRACKED_IPS && !self.offenders.contains_key(&ip) {
+            self.offenders.retain(|_, offender| {
+                offender.is_banned(now) || now - offender.window_started_at < self.window
+            });
+            if self.offenders.len() >= MAX_TRACKED_IPS {
+                warn!(
+                    "{} failed to authenticate, but too many IPs are tracked",
+                    ip
+                );
+                return false;
+            }
+        }
+
+        let mut offender = self.offenders.entry(ip).or_insert_with(|| Offender {
+            window_started_at: now,
+            failures: 0,
+            banned_until: None,
+            banned_at: 0,
+            bans: 0,
+            refused_requests: 0,
+        });
+        if now - offender.window_started_at >= self.window {
+            offender.window_started_at = now;
+            offender.failures = 0;
+        }
+        offender.failures += 1;
+        if offender.failures < self.max_failures || offender.is_banned(now) {
+            return false;
+        }
+        offender.failures = 0;
+        offender.banned_until = Some(now + self.ban_for);
+        offender.banned_at = unix_now();
+        offender.bans += 1;
+        warn!(
+            "{} failed to authenticate {} times; banning it for {}s",
+            ip,
+            self.max_failures,
+            self.ban_for.as_secs()
+        );
+        true
+    }
+
+    /// IPs banned now or before, most recently banned first
+    pub(crate) fn list(&self) -> Vec<Value> {
+        let now = Instant::now();
+        let mut ips: Vec<_> = self
+            .offenders
+            .iter()
+            .filter(|entry| entry.value().bans > 0)
+            .map(|entry| {
+                let offender = entry.value();
+                let banned_for = offender
+                    .banned_until
+                    .map(|until| until.saturating_duration_since(now).as_secs())
+                    .filter(|&secs| secs > 0);
+                json!({
+                    "ip": entry.key().to_string(),
+                    "banned": banned_for.is_some(),
+                    "banned_for_secs": banned_for,
+                    "banned_at": offender.banned_at,
+                    "bans": offender.bans,
+                    "refused_requests": offender.refused_requests,
+                })
+            })
+            .collect();
+        ips.sort_by(|a, b| b["banned_at"].as_u64().cmp(&a["banned_at"].as_u64()));
+        ips
+    }
+
+    /// Lifts `ip`'s ban and forgets its failures. Returns `false` if it was
+    /// never banned.
+    pub(crate) fn clear(&self, ip: &str) -> bool {
+        ip.parse::<IpAddr>().is_ok_and(|ip| {
+            self.offenders
+                .remove_if(&ip, |_, offender| offender.bans > 0)
+                .is_some()
+        })
+    }
+}
+
+/// Refuses banned IPs on every route, and counts the authentication failures
+/// of the others
+pub(crate) async fn enforce_ip_bans(
+    State(state): State<Arc<ProxyState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let (Some(ip_bans), Some(ip)) = (state.ip_bans(), client_ip(request.extensions())) else {
+        return next.run(request).await;
+    };
+    if let Some(banned_for) = ip_bans.banned_for(ip, Instant::now()) {
+        state.metrics().record_ip_ban_refusal();
+        return banned_response(banned_for);
+    }
+
+    let (mut response, rejected) = AUTH_REJECTED
+        .scope(Cell::new(false), async {
+            let response = next.run(request).await;
+            (response, AUTH_REJECTED.with(Cell::get))
+        })
+        .await;
+    if rejected {
+        response.extensions_mut().insert(AuthRejected);
+    }
+    let failed = response.extensions().get::<AuthRejected>().is_some();
+    if failed && ip_bans.record_failure(ip, Instant::now()) {
+        state.metrics().record_ip_ban();
+    }
+    response
+}
+
+fn banned_response(banned_for: Duration) -> Response {
+    let retry_after_secs = banned_for.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = (
+        StatusCode::FORBIDDEN,
+        Json(
+            OpenAIError::invalid_request_error(format!(
+                "Requests from your IP address are banned after repeated authentication \
+                 failures. Please retry after {} seconds.",
+                retry_after_secs
+            ))
+            .with_code("ip_banned"),
+        ),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip_bans() -> IpBans {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_ip_bans(3, 60, 600);
+        IpBans::new(&config).unwrap()
+    }
+
+    #[test]
+    fn bans_after_repeated_failures_within_the_window() {
+        let ip_bans = ip_bans();
+        let ip = IpAddr::from([203, 0, 113, 7]);
+        let now = Instant::now();
+
+        assert!(!ip_bans.record_failure(ip, now));
+        assert!(!ip_bans.record_failure(ip, now));
+        // The window ran out, so counting starts over
+        assert!(!ip_bans.record_failure(ip, now + Duration::from_secs(61)));
+        assert!(!ip_bans.record_failure(ip, now + Duration::from_secs(62)));
+        assert!(ip_bans.banned_for(ip, now).is_none());
+        assert!(ip_bans.record_failure(ip, now + Duration::from_secs(63)));
+
+        let later = now + Duration::from_secs(100);
+        assert_eq!(
+            ip_bans.banned_for(ip, later),
+            Some(Duration::from_secs(563))
+        );
+        assert!(ip_bans
+            .banned_for(ip, now + Duration::from_secs(663))
+            .is_none());
+        assert_eq!(ip_bans.list()[0]["refused_requests"], 1);
+
+        assert!(ip_bans.clear("203.0.113.7"));
+        assert!(!ip_bans.clear("203.0.113.7"));
+        assert!(ip_bans.list().is_empty());
+    }
+}
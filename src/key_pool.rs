@@ -0,0 +1,200 @@
+use crate::config::Config;
+use axum::http::{header, HeaderMap, StatusCode};
+use clap::ValueEnum;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// How requests are spread over the `--api-keys` pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum KeyRotation {
+    /// Take turns, skipping keys that are resting after a 429 or 401
+    #[default]
+    RoundRobin,
+    /// Use the first key that is not resting, keeping the rest as spares
+    OnRateLimit,
+}
+
+/// A backend API key in the pool, and when it may be used again after the
+/// backend rate limited or rejected it
+struct PooledKey {
+    key: String,
+    resting_until: Mutex<Option<Instant>>,
+}
+
+impl PooledKey {
+    fn resting_until(&self) -> Option<Instant> {
+        *self
+            .resting_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn is_resting(&self, now: Instant) -> bool {
+        self.resting_until().is_some_and(|until| until > now)
+    }
+}
+
+/// Backend API keys requests without a client key of their own are sent
+/// with, in place of the one MAPLE_API_KEY. A key the backend answers with a
+/// 429 or 401 rests, for its `Retry-After` or `--api-key-cooldown-secs`, and
+/// the request is retried with another, so a key can be revoked upstream
+/// while the others carry on.
+pub(crate) struct BackendKeyPool {
+    keys: Vec<PooledKey>,
+    rotation: KeyRotation,
+    cooldown: Duration,
+    next: AtomicUsize,
+}
+
+impl BackendKeyPool {
+    /// `None` without `--api-keys`
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        if config.api_keys.is_empty() {
+            return None;
+        }
+        Some(Self {
+            keys: config
+                .api_keys
+                .iter()
+                .map(|key| PooledKey {
+                    key: key.clone(),
+                    resting_until: Mutex::default(),
+                })
+                .collect(),
+            rotation: config.api_key_rotation,
+            cooldown: Duration::from_secs(config.api_key_cooldown_secs),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Every key in the pool, in `--api-keys` order
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &str> {
+        self.keys.iter().map(|pooled| pooled.key.as_str())
+    }
+
+    /// The key for the next request. When every key is resting, the one that
+    /// is done first.
+    pub(crate) fn pick(&self) -> String {
+        self.pick_at(Instant::now()).key.clone()
+    }
+
+    fn pick_at(&self, now: Instant) -> &PooledKey {
+        let start = match self.rotation {
+            KeyRotation::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            KeyRotation::OnRateLimit => 0,
+        };
+        (0..self.keys.len())
+            .map(|offset| &self.keys[(start + offset) % self.keys.len()])
+            .find(|pooled| !pooled.is_resting(now))
+            .or_else(|| self.keys.iter().min_by_key(|pooled| pooled.resting_until()))
+            .expect("the key pool is never empty")
+    }
+
+    /// Rests `key` if the backend rate limited or rejected it, and returns
+    /// another key to retry the request with, if one is not resting
+    pub(crate) fn rotate(
+        &self,
+        key: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<String> {
+        let now = Instant::now();
+        let (position, pooled) = self
+            .keys
+            .iter()
+            .enumerate()
+            .find(|(_, pooled)| pooled.key == key)?;
+        let rest_for = match status {
+            StatusCode::TOO_MANY_REQUESTS => retry_after(headers).unwrap_or(self.cooldown),
+            StatusCode::UNAUTHORIZED => self.cooldown,
+            _ => return None,
+        };
+        let rest_for = rest_for.max(Duration::from_secs(1));
+        *pooled
+            .resting_until
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(now + rest_for);
+        warn!(
+            "Backend answered pooled API key #{} with {}; resting it for {}s",
+            position + 1,
+            status,
+            rest_for.as_secs()
+        );
+
+        let next = self.pick_at(now);
+        (!next.is_resting(now)).then(|| next.key.clone())
+    }
+}
+
+/// A `Retry-After` given in seconds
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let secs = headers.get(header::RETRY_AFTER)?.to_str().ok()?;
+    secs.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn pool(rotation: KeyRotation) -> BackendKeyPool {
+        let config = Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+        .with_api_keys(["key-a", "key-b", "key-c"], rotation);
+        BackendKeyPool::new(&config).unwrap()
+    }
+
+    #[test]
+    fn round_robin_skips_resting_keys() {
+        let pool = pool(KeyRotation::RoundRobin);
+        let picks: Vec<_> = (0..4).map(|_| pool.pick()).collect();
+        assert_eq!(picks, ["key-a", "key-b", "key-c", "key-a"]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_static("30"));
+        let retry_with = pool.rotate("key-b", StatusCode::TOO_MANY_REQUESTS, &headers);
+        assert!(retry_with.is_some_and(|key| key != "key-b"));
+        let picks: Vec<_> = (0..4).map(|_| pool.pick()).collect();
+        assert!(!picks.contains(&"key-b".to_string()));
+
+        assert!(pool
+            .rotate("key-a", StatusCode::BAD_REQUEST, &HeaderMap::new())
+            .is_none());
+        assert!(pool
+            .rotate("sk-client", StatusCode::UNAUTHORIZED, &HeaderMap::new())
+            .is_none());
+    }
+
+    #[test]
+    fn on_rate_limit_keeps_spares_until_every_key_rests() {
+        let pool = pool(KeyRotation::OnRateLimit);
+        assert_eq!(pool.pick(), "key-a");
+        assert_eq!(pool.pick(), "key-a");
+
+        let none = HeaderMap::new();
+        assert_eq!(
+            pool.rotate("key-a", StatusCode::UNAUTHORIZED, &none),
+            Some("key-b".to_string())
+        );
+        assert_eq!(pool.pick(), "key-b");
+        assert_eq!(
+            pool.rotate("key-b", StatusCode::TOO_MANY_REQUESTS, &none),
+            Some("key-c".to_string())
+        );
+        assert_eq!(
+            pool.rotate("key-c", StatusCode::TOO_MANY_REQUESTS, &none),
+            None
+        );
+        // Every key rests, so the one that is done first is used
+        assert_eq!(pool.pick(), "key-a");
+    }
+}
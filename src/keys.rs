@@ -0,0 +1,810 @@
+use crate::{
+    ids::{random_hex, unix_now},
+    ollama::civil_from_days,
+    schedule::TimeWindow,
+    storage,
+    system_prompt::SystemPrompt,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+/// Virtual keys are told apart from Maple API keys by this prefix
+pub(crate) const VIRTUAL_KEY_PREFIX: &str = "sk-maple-";
+const KEY_BYTES: usize = 24;
+const ID_BYTES: usize = 6;
+const SECS_PER_DAY: u64 = 86_400;
+
+/// The counters in a key's usage ledger, in the order usage is synced
+pub(crate) const USAGE_FIELDS: [&str; 4] = [
+    "requests",
+    "prompt_tokens",
+    "completion_tokens",
+    "cost_nano_usd",
+];
+
+/// Costs are counted in billionths of a dollar, so they add up exactly
+const NANO_USD_PER_USD: f64 = 1e9;
+
+/// Limits on what a virtual key may use. Unset limits are unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct KeyQuota {
+    #[serde(default)]
+    pub(crate) max_requests: Option<u64>,
+    /// Prompt and completion tokens combined
+    #[serde(default)]
+    pub(crate) max_tokens: Option<u64>,
+    /// Tokens per UTC day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens_per_day: Option<u64>,
+    /// Tokens per UTC calendar month
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens_per_month: Option<u64>,
+    /// Estimated USD per UTC day, at the `--model-price` prices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_cost_per_day: Option<f64>,
+    /// Estimated USD per UTC calendar month
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_cost_per_month: Option<f64>,
+}
+
+impl KeyQuota {
+    /// Whether any limit is a cost, which needs model prices to count
+    pub(crate) fn has_cost_budget(&self) -> bool {
+        self.max_cost_per_day.is_some() || self.max_cost_per_month.is_some()
+    }
+}
+
+/// The period a budget covers, which starts over at midnight UTC or on the
+/// first of the month
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BudgetPeriod {
+    Day,
+    Month,
+}
+
+impl BudgetPeriod {
+    pub(crate) fn adjective(self) -> &'static str {
+        match self {
+            Self::Day => "daily",
+            Self::Month => "monthly",
+        }
+    }
+
+    /// Seconds until the period starts over
+    pub(crate) fn secs_until_reset(self) -> u64 {
+        self.resets_in(unix_now())
+    }
+
+    /// When the period that `now` falls in ends
+    pub(crate) fn resets_at(self, now: u64) -> u64 {
+        now + self.resets_in(now)
+    }
+
+    fn resets_in(self, now: u64) -> u64 {
+        let today = now / SECS_PER_DAY;
+        let next_start = match self {
+            Self::Day => today + 1,
+            Self::Month => {
+                let (_, _, day_of_month) = civil_from_days(today as i64);
+                let mut next_start = today - u64::from(day_of_month - 1) + 28;
+                while civil_from_days(next_start as i64).2 != 1 {
+                    next_start += 1;
+                }
+                next_start
+            }
+        };
+        next_start * SECS_PER_DAY - now
+    }
+}
+
+/// A saved virtual key. Only a hash of the key itself is kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct KeyRecord {
+    id: String,
+    name: String,
+    key_sha256: String,
+    /// The last characters of the key, to tell keys apart in listings
+    hint: String,
+    created_at: u64,
+    #[serde(default)]
+    revoked_at: Option<u64>,
+    #[serde(default)]
+    quota: KeyQuota,
+    /// Added to the key's chat completions, after any global system prompt
+    #[serde(default)]
+    system_prompt: Option<SystemPrompt>,
+    /// The hours the key may be used in. Without any, it may always be used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    schedule: Vec<TimeWindow>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct KeysFile {
+    keys: Vec<KeyRecord>,
+}
+
+/// Tokens and estimated cost in the current day and month, which budgets
+/// are checked against
+#[derive(Debug, Default)]
+struct PeriodUsage {
+    /// Days since the Unix epoch
+    day: u64,
+    /// Year and month
+    month: (i64, u32),
+    day_tokens: u64,
+    month_tokens: u64,
+    day_cost: f64,
+    month_cost: f64,
+}
+
+impl PeriodUsage {
+    /// Starts each period over once its boundary has passed
+    fn roll(&mut self, now: u64) {
+        let day = now / SECS_PER_DAY;
+        if day == self.day {
+            return;
+        }
+        self.day = day;
+        self.day_tokens = 0;
+        self.day_cost = 0.0;
+        let (year, month, _) = civil_from_days(day as i64);
+        if (year, month) != self.month {
+            self.month = (year, month);
+            self.month_tokens = 0;
+            self.month_cost = 0.0;
+        }
+    }
+
+    /// The first budget the usage has reached
+    fn exceeded(&self, quota: &KeyQuota) -> Option<BudgetPeriod> {
+        let reached_u64 = |used: u64, max: Option<u64>| max.is_some_and(|max| used >= max);
+        let reached_f64 = |used: f64, max: Option<f64>| max.is_some_and(|max| used >= max);
+        if reached_u64(self.day_tokens, quota.max_tokens_per_day)
+            || reached_f64(self.day_cost, quota.max_cost_per_day)
+        {
+            Some(BudgetPeriod::Day)
+        } else if reached_u64(self.month_tokens, quota.max_tokens_per_month)
+            || reached_f64(self.month_cost, quota.max_cost_per_month)
+        {
+            Some(BudgetPeriod::Month)
+        } else {
+            None
+        }
+    }
+}
+
+/// What a virtual key used since the proxy started or, with a shared usage
+/// ledger, across every replica
+#[derive(Debug, Default)]
+pub(crate) struct KeyUsage {
+    requests: AtomicU64,
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+    cost_nano_usd: AtomicU64,
+    /// Usage not yet added to the shared ledger, in `USAGE_FIELDS` order
+    unsynced: [AtomicU64; 4],
+    /// This replica's usage in the current budget periods
+    periods: Mutex<PeriodUsage>,
+}
+
+impl KeyUsage {
+    pub(crate) fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.unsynced[0].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds an OpenAI `usage` object to the key's token totals
+    pub(crate) fn record_usage(&self, usage: &Value) {
+        let mut recorded = 0;
+        for (field, total, unsynced) in [
+            ("prompt_tokens", &self.prompt_tokens, &self.unsynced[1]),
+            (
+                "completion_tokens",
+                &self.completion_tokens,
+                &self.unsynced[2],
+            ),
+        ] {
+            if let Some(tokens) = usage.get(field).and_then(Value::as_u64) {
+                total.fetch_add(tokens, Ordering::Relaxed);
+                unsynced.fetch_add(tokens, Ordering::Relaxed);
+                recorded += tokens;
+            }
+        }
+        if recorded > 0 {
+            self.update_periods(unix_now(), |periods| {
+                periods.day_tokens += recorded;
+                periods.month_tokens += recorded;
+            });
+        }
+    }
+
+    /// Adds a response's estimated cost in USD to the key's total and budget
+    /// periods
+    pub(crate) fn record_cost(&self, cost: f64) {
+        if cost > 0.0 {
+            let nano_usd = (cost * NANO_USD_PER_USD).round() as u64;
+            self.cost_nano_usd.fetch_add(nano_usd, Ordering::Relaxed);
+            self.unsynced[3].fetch_add(nano_usd, Ordering::Relaxed);
+            self.update_periods(unix_now(), |periods| {
+                periods.day_cost += cost;
+                periods.month_cost += cost;
+            });
+        }
+    }
+
+    fn update_periods(&self, now: u64, update: impl FnOnce(&mut PeriodUsage)) {
+        let mut periods = self
+            .periods
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        periods.roll(now);
+        update(&mut periods);
+    }
+
+    /// The first of the quota's budgets used up at `now`
+    fn exceeded_budget(&self, quota: &KeyQuota, now: u64) -> Option<BudgetPeriod> {
+        let mut periods = self
+            .periods
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        periods.roll(now);
+        periods.exceeded(quota)
+    }
+
+    /// Takes the usage recorded since the last sync with the shared ledger
+    pub(crate) fn take_unsynced(&self) -> [u64; 4] {
+        self.unsynced
+            .each_ref()
+            .map(|unsynced| unsynced.swap(0, Ordering::Relaxed))
+    }
+
+    /// Puts back usage the shared ledger did not take
+    pub(crate) fn restore_unsynced(&self, usage: [u64; 4]) {
+        for (unsynced, amount) in self.unsynced.iter().zip(usage) {
+            unsynced.fetch_add(amount, Ordering::Relaxed);
+        }
+    }
+
+    /// Replaces the totals with the shared ledger's, plus what was recorded
+    /// here since they were read
+    pub(crate) fn set_totals(&self, totals: [u64; 4]) {
+        let counters = [
+            &self.requests,
+            &self.prompt_tokens,
+            &self.completion_tokens,
+            &self.cost_nano_usd,
+        ];
+        for ((counter, unsynced), total) in counters.into_iter().zip(&self.unsynced).zip(totals) {
+            counter.store(total + unsynced.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the usage leaves room under `quota` for another request
+    pub(crate) fn check_quota(&self, quota: &KeyQuota) -> Result<(), KeyRejection> {
+        let requests = self.requests.load(Ordering::Relaxed);
+        if quota.max_requests.is_some_and(|max| requests >= max)
+            || quota.max_tokens.is_some_and(|max| self.tokens() >= max)
+        {
+            return Err(KeyRejection::QuotaExceeded);
+        }
+        match self.exceeded_budget(quota, unix_now()) {
+            Some(period) => Err(KeyRejection::BudgetExceeded(period)),
+            None => Ok(()),
+        }
+    }
+
+    fn tokens(&self) -> u64 {
+        self.prompt_tokens.load(Ordering::Relaxed) + self.completion_tokens.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "requests": self.requests.load(Ordering::Relaxed),
+            "prompt_tokens": self.prompt_tokens.load(Ordering::Relaxed),
+            "completion_tokens": self.completion_tokens.load(Ordering::Relaxed),
+            "cost": self.cost_nano_usd.load(Ordering::Relaxed) as f64 / NANO_USD_PER_USD,
+        })
+    }
+
+    pub(crate) fn periods_to_json(&self) -> Value {
+        let mut periods = self
+            .periods
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        periods.roll(unix_now());
+        json!({
+            "day": {"tokens": periods.day_tokens, "cost": periods.day_cost},
+            "month": {"tokens": periods.month_tokens, "cost": periods.month_cost},
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct KeyEntry {
+    record: KeyRecord,
+    usage: Arc<KeyUsage>,
+}
+
+impl KeyEntry {
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.record.id,
+            "object": "virtual_key",
+            "name": self.record.name,
+            "hint": format!("{}...{}", VIRTUAL_KEY_PREFIX, self.record.hint),
+            "created_at": self.record.created_at,
+            "revoked_at": self.record.revoked_at,
+            "quota": self.record.quota,
+            "system_prompt": self.record.system_prompt,
+            "schedule": self.record.schedule,
+            "usage": self.usage.to_json(),
+            "budget_usage": self.usage.periods_to_json(),
+        })
+    }
+}
+
+/// How a request names its virtual key: by presenting the key, or, with
+/// JWT authentication, by a token claim matching the key's name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum KeyRef {
+    Secret(String),
+    Name(String),
+}
+
+/// Why a virtual key cannot be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeyRejection {
+    /// Unknown or revoked
+    Invalid,
+    QuotaExceeded,
+    BudgetExceeded(BudgetPeriod),
+}
+
+#[derive(Debug)]
+pub(crate) enum KeyUpdateError {
+    NotFound,
+    Save(io::Error),
+}
+
+/// Proxy-issued API keys that stand in for the default Maple API key, each
+/// with its own quota and usage
+pub(crate) struct VirtualKeys {
+    path: Option<PathBuf>,
+    entries: RwLock<Vec<KeyEntry>>,
+}
+
+impl VirtualKeys {
+    /// Starts from the keys file if there is one. An unreadable file leaves no
+    /// keys usable rather than guessing at revocations.
+    pub(crate) fn open(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let records = match &path {
+            Some(path) => load(path)?.keys,
+            None => Vec::new(),
+        };
+        let entries = records
+            .into_iter()
+            .map(|record| KeyEntry {
+                record,
+                usage: Arc::default(),
+            })
+            .collect();
+        Ok(Self {
+            path,
+            entries: RwLock::new(entries),
+        })
+    }
+
+    pub(crate) fn empty() -> Self {
+        Self {
+            path: None,
+            entries: RwLock::default(),
+        }
+    }
+
+    /// The usage counters of an active key with quota left
+    pub(crate) fn authorize(&self, key: &KeyRef) -> Result<Arc<KeyUsage>, KeyRejection> {
+        let entries = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let entry = find(&entries, key)
+            .filter(|entry| entry.record.revoked_at.is_none())
+            .ok_or(KeyRejection::Invalid)?;
+
+        entry.usage.check_quota(&entry.record.quota)?;
+        Ok(Arc::clone(&entry.usage))
+    }
+
+    /// The ID of an issued key, revoked or not
+    pub(crate) fn id_of(&self, key: &KeyRef) -> Option<String> {
+        let entries = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        find(&entries, key).map(|entry| entry.record.id.clone())
+    }
+
+    /// The system prompt of an active key, if it has one
+    pub(crate) fn system_prompt(&self, key: &KeyRef) -> Option<SystemPrompt> {
+        let entries = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        find(&entries, key)
+            .filter(|entry| entry.record.revoked_at.is_none())
+            .and_then(|entry| entry.record.system_prompt.clone())
+    }
+
+    /// The hours an active key may be used in, if it is limited to some
+    pub(crate) fn schedule(&self, key: &KeyRef) -> Option<Vec<TimeWindow>> {
+        let entries = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        find(&entries, key)
+            .filter(|entry| entry.record.revoked_at.is_none())
+            .map(|entry| entry.record.schedule.clone())
+            .filter(|schedule| !schedule.is_empty())
+    }
+
+    pub(crate) fn list(&self) -> Vec<Value> {
+        let entries = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.iter().map(KeyEntry::to_json).collect()
+    }
+
+    /// Every key's ID and usage counters, for syncing with a shared ledger
+    pub(crate) fn usages(&self) -> Vec<(String, Arc<KeyUsage>)> {
+        let entries = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries
+            .iter()
+            .map(|entry| (entry.record.id.clone(), Arc::clone(&entry.usage)))
+            .collect()
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<Value> {
+        let entries = self
+            .entries
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries
+            .iter()
+            .find(|entry| entry.record.id == id)
+            .map(KeyEntry::to_json)
+    }
+
+    /// Issues a key. The returned JSON is the only place the key appears.
+    pub(crate) fn create(
+        &self,
+        name: String,
+        quota: KeyQuota,
+        system_prompt: Option<SystemPrompt>,
+    ) -> Result<Value, KeyUpdateError> {
+        let key = format!("{}{}", VIRTUAL_KEY_PREFIX, random_hex::<KEY_BYTES>());
+        let record = KeyRecord {
+            id: format!("key_{}", random_hex::<ID_BYTES>()),
+            name,
+            key_sha256: hash_key(&key),
+            hint: key[key.len() - 4..].to_string(),
+            created_at: unix_now(),
+            revoked_at: None,
+            quota,
+            system_prompt,
+            schedule: Vec::new(),
+        };
+        let entry = KeyEntry {
+            record,
+            usage: Arc::default(),
+        };
+        let mut created = entry.to_json();
+        created["key"] = json!(key);
+
+        self.update(|entries| {
+            entries.push(entry);
+            Ok(())
+        })?;
+        Ok(created)
+    }
+
+    pub(crate) fn set_quota(&self, id: &str, quota: KeyQuota) -> Result<Value, KeyUpdateError> {
+        self.update(|entries| {
+            let entry = find_mut(entries, id)?;
+            entry.record.quota = quota;
+            Ok(entry.to_json())
+        })
+    }
+
+    /// Sets or, with `None`, removes the key's system prompt
+    pub(crate) fn set_system_prompt(
+        &self,
+        id: &str,
+        system_prompt: Option<SystemPrompt>,
+    ) -> Result<Value, KeyUpdateError> {
+        self.update(|entries| {
+            let entry = find_mut(entries, id)?;
+            entry.record.system_prompt = system_prompt;
+            Ok(entry.to_json())
+        })
+    }
+
+    /// Limits the key to the hours in `schedule`, or with none, lifts the
+    /// limit
+    pub(crate) fn set_schedule(
+        &self,
+        id: &str,
+        schedule: Vec<TimeWindow>,
+    ) -> Result<Value, KeyUpdateError> {
+        self.update(|entries| {
+            let entry = find_mut(entries, id)?;
+            entry.record.schedule = schedule;
+            Ok(entry.to_json())
+        })
+    }
+
+    /// Revoked keys stay listed with their usage, but are rejected
+    pub(crate) fn revoke(&self, id: &str) -> Result<Value, KeyUpdateError> {
+        self.update(|entries| {
+            let entry = find_mut(entries, id)?;
+            entry.record.revoked_at.get_or_insert_with(unix_now);
+            Ok(entry.to_json())
+        })
+    }
+
+    /// Applies `update` to a copy of the keys and saves it before swapping it
+    /// in, so a failed save changes nothing
+    fn update<T>(
+        &self,
+        update: impl FnOnce(&mut Vec<KeyEntry>) -> Result<T, KeyUpdateError>,
+    ) -> Result<T, KeyUpdateError> {
+        let mut current = self
+            .entries
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut entries = current.clone();
+        let result = update(&mut entries)?;
+
+        if let Some(path) = &self.path {
+            let file = KeysFile {
+                keys: entries.iter().map(|entry| entry.record.clone()).collect(),
+            };
+            storage::write_json(path, &file).map_err(KeyUpdateError::Save)?;
+        }
+        *current = entries;
+        Ok(result)
+    }
+}
+
+/// The key a request names. Names are not unique, so by name only active
+/// keys are found.
+fn find<'a>(entries: &'a [KeyEntry], key: &KeyRef) -> Option<&'a KeyEntry> {
+    match key {
+        KeyRef::Secret(key) => {
+            let key_sha256 = hash_key(key);
+            entries
+                .iter()
+                .find(|entry| entry.record.key_sha256 == key_sha256)
+        }
+        KeyRef::Name(name) => entries
+            .iter()
+            .find(|entry| entry.record.name == *name && entry.record.revoked_at.is_none()),
+    }
+}
+
+fn find_mut<'a>(entries: &'a mut [KeyEntry], id: &str) -> Result<&'a mut KeyEntry, KeyUpdateError> {
+    entries
+        .iter_mut()
+        .find(|entry| entry.record.id == id)
+        .ok_or(KeyUpdateError::NotFound)
+}
+
+/// Checks that the keys file, if it exists, can be read
+pub(crate) fn check_keys_file(path: &Path) -> anyhow::Result<()> {
+    load(path).map(drop)
+}
+
+fn load(path: &Path) -> anyhow::Result<KeysFile> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(KeysFile::default()),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to read {}", path.display()))
+        }
+    };
+    serde_json::from_slice(&contents)
+        .with_context(|| format!("Invalid keys file {}", path.display()))
+}
+
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_of(created: &Value) -> &str {
+        created["key"].as_str().unwrap()
+    }
+
+    fn secret(key: &str) -> KeyRef {
+        KeyRef::Secret(key.to_string())
+    }
+
+    #[test]
+    fn issued_keys_authorize_until_revoked() {
+        let keys = VirtualKeys::empty();
+        let created = keys
+            .create("ci".to_string(), KeyQuota::default(), None)
+            .unwrap();
+        let key = key_of(&created);
+        let id = created["id"].as_str().unwrap();
+
+        assert!(key.starts_with(VIRTUAL_KEY_PREFIX));
+        assert_eq!(
+            created["hint"],
+            format!("sk-maple-...{}", &key[key.len() - 4..])
+        );
+        assert!(keys.authorize(&secret(key)).is_ok());
+        assert_eq!(
+            keys.authorize(&secret("sk-maple-unknown")).unwrap_err(),
+            KeyRejection::Invalid
+        );
+        assert!(keys.list()[0].get("key").is_none());
+
+        keys.revoke(id).unwrap();
+        assert_eq!(
+            keys.authorize(&secret(key)).unwrap_err(),
+            KeyRejection::Invalid
+        );
+        assert!(keys.get(id).unwrap()["revoked_at"].is_u64());
+        assert!(matches!(
+            keys.revoke("key_missing"),
+            Err(KeyUpdateError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn names_find_only_active_keys() {
+        let keys = VirtualKeys::empty();
+        let old = keys
+            .create("team-a".to_string(), KeyQuota::default(), None)
+            .unwrap();
+        keys.revoke(old["id"].as_str().unwrap()).unwrap();
+        let team = KeyRef::Name("team-a".to_string());
+        assert_eq!(keys.authorize(&team).unwrap_err(), KeyRejection::Invalid);
+
+        let current = keys
+            .create("team-a".to_string(), KeyQuota::default(), None)
+            .unwrap();
+        keys.authorize(&team).unwrap().record_request();
+        assert_eq!(keys.id_of(&team).as_deref(), current["id"].as_str());
+        assert_eq!(
+            keys.get(current["id"].as_str().unwrap()).unwrap()["usage"]["requests"],
+            1
+        );
+    }
+
+    #[test]
+    fn quotas_cap_requests_and_tokens() {
+        let keys = VirtualKeys::empty();
+        let quota = KeyQuota {
+            max_requests: Some(2),
+            ..KeyQuota::default()
+        };
+        let created = keys.create("batch".to_string(), quota, None).unwrap();
+        let (key, id) = (key_of(&created), created["id"].as_str().unwrap());
+
+        for _ in 0..2 {
+            keys.authorize(&secret(key)).unwrap().record_request();
+        }
+        assert_eq!(
+            keys.authorize(&secret(key)).unwrap_err(),
+            KeyRejection::QuotaExceeded
+        );
+
+        let quota = KeyQuota {
+            max_tokens: Some(100),
+            ..KeyQuota::default()
+        };
+        keys.set_quota(id, quota).unwrap();
+        let usage = keys.authorize(&secret(key)).unwrap();
+        usage.record_usage(&json!({"prompt_tokens": 60, "completion_tokens": 40}));
+        assert_eq!(
+            keys.authorize(&secret(key)).unwrap_err(),
+            KeyRejection::QuotaExceeded
+        );
+        assert_eq!(
+            keys.get(id).unwrap()["usage"],
+            json!({"requests": 2, "prompt_tokens": 60, "completion_tokens": 40, "cost": 0.0})
+        );
+    }
+
+    #[test]
+    fn budgets_start_over_each_day_and_month() {
+        // 2025-01-31T12:00:00Z
+        let now = 1_738_324_800;
+        let quota = KeyQuota {
+            max_tokens_per_day: Some(100),
+            max_cost_per_month: Some(1.0),
+            ..KeyQuota::default()
+        };
+        let usage = KeyUsage::default();
+        usage.update_periods(now, |periods| periods.day_tokens += 100);
+        assert_eq!(usage.exceeded_budget(&quota, now), Some(BudgetPeriod::Day));
+        assert_eq!(BudgetPeriod::Day.resets_in(now), 12 * 3_600);
+
+        let tomorrow = now + SECS_PER_DAY;
+        assert_eq!(usage.exceeded_budget(&quota, tomorrow), None);
+
+        let usage = KeyUsage::default();
+        usage.update_periods(now, |periods| periods.month_cost += 0.6);
+        usage.update_periods(now, |periods| periods.month_cost += 0.6);
+        assert_eq!(
+            usage.exceeded_budget(&quota, now),
+            Some(BudgetPeriod::Month)
+        );
+        assert_eq!(BudgetPeriod::Month.resets_in(now), 12 * 3_600);
+        // February starts the month over
+        assert_eq!(usage.exceeded_budget(&quota, tomorrow), None);
+        assert_eq!(
+            BudgetPeriod::Month.resets_in(tomorrow),
+            28 * SECS_PER_DAY - 12 * 3_600
+        );
+    }
+
+    #[test]
+    fn unsynced_usage_is_taken_and_totals_replaced() {
+        let usage = KeyUsage::default();
+        usage.record_request();
+        usage.record_usage(&json!({"prompt_tokens": 10, "completion_tokens": 5}));
+        usage.record_cost(0.000_002);
+        let taken = usage.take_unsynced();
+        assert_eq!(taken, [1, 10, 5, 2000]);
+        assert_eq!(usage.take_unsynced(), [0, 0, 0, 0]);
+
+        // Another replica's usage arrives while a request is recorded here
+        usage.record_request();
+        usage.set_totals([7, 100, 50, 1_500_000_000]);
+        assert_eq!(
+            usage.to_json(),
+            json!({"requests": 8, "prompt_tokens": 100, "completion_tokens": 50, "cost": 1.5})
+        );
+
+        usage.restore_unsynced(taken);
+        assert_eq!(usage.take_unsynced(), [2, 10, 5, 2000]);
+    }
+
+    #[test]
+    fn keys_persist_without_the_key_itself() {
+        let dir = std::env::temp_dir().join(format!("maple-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.json");
+
+        let keys = VirtualKeys::open(Some(path.clone())).unwrap();
+        let created = keys
+            .create("ci".to_string(), KeyQuota::default(), None)
+            .unwrap();
+        let key = key_of(&created);
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains(key));
+
+        let reopened = VirtualKeys::open(Some(path.clone())).unwrap();
+        assert!(reopened.authorize(&secret(key)).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
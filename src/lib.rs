@@ -1,60 +1,550 @@
+mod adaptive_timeout;
+mod admin;
+mod admission;
+mod alerts;
+mod attestation;
+mod audit;
+mod azure;
+mod background;
+mod batches;
+mod cache;
+mod capabilities;
+#[cfg(feature = "client")]
+pub mod client;
+mod client_keys;
+mod coalesce;
+mod compat;
 mod config;
+mod connect;
+mod credentials;
+mod dataset;
+mod defaults;
+mod diagnose;
+mod embedding_cache;
+mod extension;
+mod fingerprint;
+mod forwarded;
+mod geo;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod honeypot;
+mod hooks;
+mod idempotency;
+mod ids;
+mod images;
+mod init;
+mod ip_bans;
+mod jwt;
+mod key_pool;
+mod keys;
+mod limits;
+mod metrics;
+mod mock;
+mod models;
+mod moderations;
+mod ollama;
+mod paths;
+mod pipeline;
+mod pools;
+mod pricing;
 mod proxy;
+mod quarantine;
+mod rate_limit;
+mod redis_store;
+mod release;
+mod report;
+mod sandbox;
+mod schedule;
+mod schema;
+mod secrets;
+mod serve;
+mod snippets;
+mod speech;
+mod sse;
+mod storage;
+mod stream_aggregate;
+mod stream_memory;
+mod stream_recovery;
+mod structured;
+mod system_prompt;
+mod tasks;
+mod timing;
+mod tokenizer;
+mod tool_calls;
+mod transcription;
+#[cfg(feature = "self-update")]
+mod update;
+mod upstream;
+mod validation;
+mod wire;
 
-pub use config::Config;
-use proxy::{health_check, proxy_openai_request, ProxyState};
+use admin::{
+    clear_cache, clear_ip_ban, create_key, delete_alias, delete_key_schedule,
+    delete_key_system_prompt, delete_route, evict_cache_entry, evict_client, get_key, list_aliases,
+    list_blocked_ips, list_cache_entries, list_client_keys, list_clients, list_ip_bans, list_keys,
+    list_quarantined_keys, list_routes, put_alias, put_key_quota, put_key_schedule,
+    put_key_system_prompt, put_route, release_quarantined_key, reload_client_keys,
+    require_admin_token, revoke_key, show_config, unblock_ip,
+};
+use attestation::attestation_status;
+pub use attestation::{ExpectedPcr, StartupAttestation};
+pub use audit::AuditContent;
+use azure::{azure_chat_completions, azure_embeddings};
+use background::{create_chat_completion, get_response};
+use batches::{
+    cancel_batch, create_batch, delete_file, file_content, get_batch, get_file, list_batches,
+    list_files, upload_file,
+};
+pub use client_keys::{hash_key, HashKeyArgs};
+pub use compat::CompatProfile;
+pub use config::{Command, Config};
+use credentials::accept_query_key;
+pub use defaults::ModelDefaults;
+pub use diagnose::{diagnose, DiagnoseArgs};
+use forwarded::resolve_client_ip;
+pub use forwarded::{ForwardedHeader, TrustedProxy};
+use geo::enforce_geo_policy;
+pub use geo::CountryRateLimit;
+use honeypot::catch_scanners;
+pub use hooks::{HookFuture, HookRejection, HookRequest, HookResponse, ProxyHook};
+pub use ids::IdFormat;
+pub use init::{init, InitArgs};
+use ip_bans::enforce_ip_bans;
+pub use key_pool::KeyRotation;
+pub use limits::{LimitAction, ModelTokenLimit};
+pub use models::ModelAlias;
+use moderations::create_moderation;
+use ollama::{ollama_chat, ollama_generate, ollama_tags};
+pub use opensecret::Error as BackendError;
+pub use paths::PathAlias;
+pub use pipeline::{RoutePipeline, Stage};
+pub use pools::{ModelPool, PoolMember};
+pub use pricing::ModelPrice;
+use proxy::{
+    add_update_available_header, health_check, limit_embedding_uploads, playground,
+    prometheus_metrics, proxy_openai_request, version_info, ProxyState,
+};
+pub use proxy::{Backend, BackendBody};
+pub use release::ReleaseChannel;
+pub use report::{BusiestModel, RunStats, ShutdownReport};
+pub use sandbox::apply_process_sandbox;
+pub use schedule::{ModelBlackout, TimeWindow};
+pub use schema::SchemaValidation;
+pub use serve::{serve, ConnectionLimits};
+pub use snippets::{snippets, startup_snippet, SnippetLang, SnippetsArgs};
+use speech::create_speech;
+pub use stream_recovery::StreamRecovery;
+pub use system_prompt::SystemPromptMode;
+use tokenizer::tokenize_text;
+pub use tokenizer::{count_message_tokens, count_tokens, tokenize};
+use transcription::transcribe_audio;
+#[cfg(feature = "self-update")]
+pub use update::{self_update, Restart, SelfUpdateArgs};
+use validation::limit_request_size;
 
 use axum::{
-    extract::DefaultBodyLimit,
-    http::Method,
-    routing::{get, post},
+    extract::{ConnectInfo, DefaultBodyLimit},
+    http::{HeaderName, HeaderValue, Method, Request},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tower::ServiceBuilder;
 use tower_http::{
-    cors::{Any, CorsLayer},
-    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    cors::{AllowHeaders, Any, CorsLayer},
+    decompression::RequestDecompressionLayer,
+    trace::{DefaultOnResponse, MakeSpan, TraceLayer},
 };
-use tracing::Level;
-
-const MAX_PROXY_REQUEST_BODY_BYTES: usize = 50 * 1024 * 1024;
+use tracing::{Level, Span};
 
 /// Create the Axum application with the given configuration
 pub fn create_app(config: Config) -> Router {
+    create_app_with_stats(config).0
+}
+
+/// Like [`create_app`], also returning the totals behind the shutdown report
+pub fn create_app_with_stats(config: Config) -> (Router, Arc<RunStats>) {
+    let state = Arc::new(ProxyState::new(config.clone()));
+    let stats = Arc::clone(&state.stats);
+    (create_app_with_state(config, state), stats)
+}
+
+/// Like [`create_app_with_stats`], first attesting the backends as
+/// `--startup-attestation` asks, so the first requests use the attested
+/// sessions. Fails when it is `require` and no backend passes.
+pub async fn create_attested_app(config: Config) -> anyhow::Result<(Router, Arc<RunStats>)> {
     let state = Arc::new(ProxyState::new(config.clone()));
+    state.attest_on_startup().await?;
+    let stats = Arc::clone(&state.stats);
+    Ok((create_app_with_state(config, state), stats))
+}
+
+/// Like [`create_app`], running `hooks` on every inference request
+pub fn create_app_with_hooks(config: Config, hooks: Vec<Arc<dyn ProxyHook>>) -> Router {
+    let state = Arc::new(ProxyState::new(config.clone()).with_hooks(hooks));
+    create_app_with_state(config, state)
+}
+
+/// Like [`create_app`], sending inference requests for the primary and fallback
+/// backend URLs to `backend` instead of an attested OpenSecret client, e.g. a
+/// custom backend or a mock in integration tests
+pub fn create_app_with_backend(config: Config, backend: Arc<dyn Backend>) -> Router {
+    let state = Arc::new(ProxyState::new(config.clone()).with_backend(backend));
     create_app_with_state(config, state)
 }
 
+/// Like [`create_app`], serving every route under `prefix`, e.g. `/maple`, so
+/// the proxy can be mounted inside an existing Axum app. The proxy brings its
+/// own state, so the router merges into an app with any state type:
+///
+/// ```ignore
+/// let app = Router::new()
+///     .route("/", get(home))
+///     .merge(create_router_with_prefix(config, "/maple"))
+///     .with_state(app_state);
+/// ```
+///
+/// An empty prefix or `/` serves the routes at the root. Panics like
+/// [`Router::nest`] if `prefix` contains route parameters or wildcards.
+pub fn create_router_with_prefix<S>(config: Config, prefix: &str) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let state = Arc::new(ProxyState::new(config.clone()));
+    let router = router_with_state(config, state);
+
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return router;
+    }
+    let prefix = if prefix.starts_with('/') {
+        prefix.to_string()
+    } else {
+        format!("/{}", prefix)
+    };
+    Router::new().nest(&prefix, router)
+}
+
 pub(crate) fn create_app_with_state(config: Config, state: Arc<ProxyState>) -> Router {
-    let mut app = Router::new()
+    router_with_state(config, state)
+}
+
+fn router_with_state<S>(config: Config, state: Arc<ProxyState>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    state.start_session_refresh();
+    batches::resume_batches(&state);
+
+    // Embedding bodies can be large, so they are received within a shared budget
+    let embedding_uploads =
+        middleware::from_fn_with_state(Arc::clone(&state), limit_embedding_uploads);
+
+    // OpenAI-compatible endpoints
+    let mut inference = vec![
+        ("/v1/models", get(proxy_openai_request)),
+        ("/v1/chat/completions", post(create_chat_completion)),
+        (
+            "/v1/embeddings",
+            post(proxy_openai_request).layer(embedding_uploads.clone()),
+        ),
+        ("/v1/tokenize", post(tokenize_text)),
+        ("/v1/audio/transcriptions", post(transcribe_audio)),
+        ("/v1/audio/speech", post(create_speech)),
+        ("/v1/images/generations", post(proxy_openai_request)),
+        ("/v1/moderations", post(create_moderation)),
+        ("/v1/rerank", post(proxy_openai_request)),
+    ];
+
+    // Ollama-compatible endpoints share the rate limit and client metrics
+    if config.enable_ollama_api {
+        inference.extend([
+            ("/api/chat", post(ollama_chat)),
+            ("/api/generate", post(ollama_generate)),
+            ("/api/tags", get(ollama_tags)),
+        ]);
+    }
+
+    // Azure OpenAI-style endpoints, for clients configured with an Azure base URL
+    if config.enable_azure_api {
+        inference.extend([
+            (
+                "/openai/deployments/{deployment}/chat/completions",
+                post(azure_chat_completions),
+            ),
+            (
+                "/openai/deployments/{deployment}/embeddings",
+                post(azure_embeddings).layer(embedding_uploads),
+            ),
+        ]);
+    }
+
+    // Each route runs its pipeline's gateway stages before the handler
+    let mut inference = inference
+        .into_iter()
+        .fold(Router::new(), |router, (route, handler)| {
+            router.route(route, pipeline::staged(&state, route, handler))
+        });
+
+    // Keys in the query string are taken on the inference routes alone, so
+    // admin tokens and other secrets never belong in URLs
+    if config.query_api_key {
+        inference = inference.route_layer(middleware::from_fn(accept_query_key));
+    }
+
+    // Operator-facing endpoints, which report available updates
+    let mut operator = Router::new()
         // Health check endpoints
         .route("/health", get(health_check))
         .route("/", get(health_check))
-        // OpenAI-compatible endpoints
-        .route("/v1/models", get(proxy_openai_request))
-        .route("/v1/chat/completions", post(proxy_openai_request))
-        .route("/v1/embeddings", post(proxy_openai_request))
-        .with_state(state)
-        .layer(
-            ServiceBuilder::new()
-                .layer(DefaultBodyLimit::max(MAX_PROXY_REQUEST_BODY_BYTES))
-                .layer(
-                    TraceLayer::new_for_http()
-                        .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
-                        .on_response(DefaultOnResponse::new().level(Level::INFO)),
-                ),
+        .route("/version", get(version_info))
+        // Lets clients audit that the backends are attested enclaves
+        .route("/v1/attestation", get(attestation_status));
+    if config.enable_metrics {
+        operator = operator.route("/metrics", get(prometheus_metrics));
+    }
+    let operator = operator.route_layer(middleware::from_fn_with_state(
+        Arc::clone(&state),
+        add_update_available_header,
+    ));
+
+    // Polled for background chat completions, outside the inference pipeline
+    let mut app = Router::new()
+        .merge(operator)
+        .merge(inference.clone())
+        .route("/v1/responses/{id}", get(get_response));
+
+    if config.enable_playground {
+        app = app.route("/playground", get(playground));
+    }
+
+    // gRPC calls are sent through the same inference routes as HTTP requests
+    #[cfg(feature = "grpc")]
+    if config.enable_grpc {
+        let inference = inference.with_state(Arc::clone(&state));
+        app = app.route_service(
+            grpc::ROUTE,
+            grpc::service(inference, config.max_body_bytes()),
         );
+    }
+
+    // OpenAI's Batch API, run by the proxy itself against the backend
+    if config.batch_dir.is_some() {
+        app = app
+            .route("/v1/files", get(list_files).post(upload_file))
+            .route("/v1/files/{file_id}", get(get_file).delete(delete_file))
+            .route("/v1/files/{file_id}/content", get(file_content))
+            .route("/v1/batches", get(list_batches).post(create_batch))
+            .route("/v1/batches/{batch_id}", get(get_batch))
+            .route("/v1/batches/{batch_id}/cancel", post(cancel_batch));
+    }
+
+    // Admin endpoints for changing model aliases, routing, and virtual keys
+    // without a restart
+    if config.admin_token.is_some() {
+        let admin = Router::new()
+            .route("/admin/config", get(show_config))
+            .route("/admin/aliases", get(list_aliases))
+            .route(
+                "/admin/aliases/{alias}",
+                put(put_alias).delete(delete_alias),
+            )
+            .route("/admin/routes", get(list_routes))
+            .route("/admin/routes/{model}", put(put_route).delete(delete_route))
+            .route("/admin/keys", get(list_keys).post(create_key))
+            .route("/admin/keys/{id}", get(get_key).delete(revoke_key))
+            .route("/admin/keys/{id}/quota", put(put_key_quota))
+            .route(
+                "/admin/keys/{id}/system_prompt",
+                put(put_key_system_prompt).delete(delete_key_system_prompt),
+            )
+            .route(
+                "/admin/keys/{id}/schedule",
+                put(put_key_schedule).delete(delete_key_schedule),
+            )
+            .route(
+                "/admin/caches/{cache}",
+                get(list_cache_entries).delete(clear_cache),
+            )
+            .route("/admin/caches/{cache}/{id}", delete(evict_cache_entry))
+            .route("/admin/clients", get(list_clients))
+            .route("/admin/clients/{id}", delete(evict_client))
+            .route("/admin/quarantine", get(list_quarantined_keys))
+            .route("/admin/quarantine/{id}", delete(release_quarantined_key))
+            .route("/admin/blocked_ips", get(list_blocked_ips))
+            .route("/admin/blocked_ips/{ip}", delete(unblock_ip))
+            .route("/admin/ip_bans", get(list_ip_bans))
+            .route("/admin/ip_bans/{ip}", delete(clear_ip_ban))
+            .route("/admin/client_keys", get(list_client_keys))
+            .route("/admin/client_keys/reload", post(reload_client_keys))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::clone(&state),
+                require_admin_token,
+            ));
+        app = app.merge(admin);
+    }
+
+    // Bodies declared larger than --max-body-mb are refused before any of
+    // them is read
+    app = app.layer(middleware::from_fn_with_state(
+        Arc::clone(&state),
+        limit_request_size,
+    ));
+
+    // Country and network rules, which cover every route
+    if config.geoip_db.is_some() || config.asn_db.is_some() {
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            enforce_geo_policy,
+        ));
+    }
+
+    // Decoys for vulnerability scanners, and the denylist of IPs they catch,
+    // which covers every route
+    if config.honeypot_enabled() {
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            catch_scanners,
+        ));
+    }
+
+    // Bans for IPs that keep failing authentication, which cover every route
+    if config.ip_ban_auth_failures.is_some() {
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            enforce_ip_bans,
+        ));
+    }
+
+    // Outermost, so every layer and handler sees the client behind trusted
+    // proxies
+    app = app.layer(middleware::from_fn_with_state(
+        Arc::clone(&state),
+        resolve_client_ip,
+    ));
+
+    let mut app: Router = app.with_state(state).layer(
+        ServiceBuilder::new()
+            .layer(DefaultBodyLimit::max(config.max_body_bytes()))
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(RequestSpan {
+                        redact_logs: config.redact_logs,
+                        trusted_proxies: config.trusted_proxies.clone().into(),
+                        forwarded_header: config.forwarded_header,
+                        query_api_key: config.query_api_key,
+                    })
+                    .on_response(DefaultOnResponse::new().level(Level::INFO)),
+            ),
+    );
+
+    // gzip and Brotli, as Accept-Encoding and Content-Encoding ask. Streamed
+    // responses are sent uncompressed so every event reaches the client at once.
+    if config.enable_compression {
+        let unstreamed = DefaultPredicate::new()
+            .and(NotForContentType::const_new("application/x-ndjson"))
+            .and(NotForContentType::const_new("audio/"));
+        app = app
+            .layer(RequestDecompressionLayer::new())
+            .layer(CompressionLayer::new().compress_when(unstreamed));
+    }
 
     // Add CORS if enabled
-    if config.enable_cors {
-        app = app.layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-                .allow_headers(Any),
-        );
+    if config.cors_enabled() {
+        app = app.layer(cors_layer(&config));
+    }
+
+    // Aliased and unversioned paths are rewritten before anything is routed
+    paths::with_path_rewrites(&config, app)
+}
+
+/// Browsers reject wildcards alongside credentials, so credentials are only
+/// allowed for listed origins, and requested headers are then mirrored back
+fn cors_layer(config: &Config) -> CorsLayer {
+    let origins: Vec<HeaderValue> = config
+        .cors_origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect();
+    let allow_credentials = config.cors_allow_credentials && !origins.is_empty();
+
+    let mut cors = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_credentials(allow_credentials);
+    cors = if origins.is_empty() {
+        cors.allow_origin(Any)
+    } else {
+        cors.allow_origin(origins)
+    };
+    cors = if allow_credentials {
+        cors.allow_headers(AllowHeaders::mirror_request())
+    } else {
+        cors.allow_headers(Any)
+    };
+    if let Some(max_age_secs) = config.cors_max_age_secs {
+        cors = cors.max_age(Duration::from_secs(max_age_secs));
+    }
+
+    let expose_headers: Vec<HeaderName> = config
+        .cors_expose_headers
+        .iter()
+        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        .collect();
+    if !expose_headers.is_empty() {
+        cors = cors.expose_headers(expose_headers);
     }
+    cors
+}
+
+/// Request spans that name the client, resolved through trusted proxies, and
+/// omit it and query strings when log redaction is enabled. Keys sent in the
+/// query string are always left out.
+#[derive(Clone)]
+struct RequestSpan {
+    redact_logs: bool,
+    trusted_proxies: Arc<[TrustedProxy]>,
+    forwarded_header: ForwardedHeader,
+    query_api_key: bool,
+}
 
-    app
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        if self.redact_logs {
+            return tracing::info_span!(
+                "request",
+                method = %request.method(),
+                path = %request.uri().path(),
+            );
+        }
+        let client =
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| {
+                    forwarded::resolve(
+                        addr.ip(),
+                        request.headers(),
+                        &self.trusted_proxies,
+                        self.forwarded_header,
+                    )
+                });
+        // The route takes the query key out only after the span is made
+        let uri = if self.query_api_key {
+            credentials::without_query_key(request.uri())
+        } else {
+            request.uri().clone()
+        };
+        tracing::info_span!(
+            "request",
+            method = %request.method(),
+            uri = %uri,
+            version = ?request.version(),
+            client = client.map(tracing::field::display),
+        )
+    }
 }
@@ -0,0 +1,204 @@
+use crate::config::{Config, OpenAIError};
+use axum::body::Bytes;
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use std::{fmt, str::FromStr};
+
+/// What happens to a chat completion parameter over its configured limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LimitAction {
+    /// Lower the parameter to the limit and forward the request
+    #[default]
+    Clamp,
+    /// Reject the request with a 400 naming the parameter
+    Reject,
+}
+
+/// The most tokens one model's chat completions may generate, as
+/// MODEL=TOKENS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelTokenLimit {
+    pub model: String,
+    pub max_tokens: u64,
+}
+
+impl FromStr for ModelTokenLimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected MODEL=TOKENS, got '{}'", value);
+        let (model, max_tokens) = value.split_once('=').ok_or_else(expected)?;
+        let model = model.trim();
+        if model.is_empty() {
+            return Err(expected());
+        }
+        let max_tokens = max_tokens
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|max_tokens| *max_tokens > 0)
+            .ok_or_else(|| format!("the token limit must be a positive integer in '{}'", value))?;
+
+        Ok(Self {
+            model: model.to_string(),
+            max_tokens,
+        })
+    }
+}
+
+impl fmt::Display for ModelTokenLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.model, self.max_tokens)
+    }
+}
+
+/// Holds a chat completion request to the configured `temperature`, `n`, and
+/// token limits, clamping or rejecting what is over them. Requests that set
+/// no token limit get the model's. Values outside OpenAI's own ranges never
+/// get here, since request validation rejects them first.
+pub(crate) fn enforce_param_limits(config: &Config, body: Bytes) -> Result<Bytes, OpenAIError> {
+    if config.max_temperature.is_none()
+        && config.max_n.is_none()
+        && config.max_tokens_limit.is_none()
+        && config.model_max_tokens.is_empty()
+    {
+        return Ok(body);
+    }
+    let Ok(Value::Object(mut request)) = serde_json::from_slice::<Value>(&body) else {
+        return Ok(body);
+    };
+    let action = config.param_limit_action;
+
+    let mut changed = false;
+    if let Some(max) = config.max_temperature {
+        changed |= limit(&mut request, "temperature", json!(max), action)?;
+    }
+    if let Some(max) = config.max_n {
+        changed |= limit(&mut request, "n", json!(max), action)?;
+    }
+    let max_tokens = request
+        .get("model")
+        .and_then(Value::as_str)
+        .and_then(|model| {
+            config
+                .model_max_tokens
+                .iter()
+                .find(|limit| limit.model == model)
+        })
+        .map(|limit| limit.max_tokens)
+        .or(config.max_tokens_limit);
+    if let Some(max) = max_tokens {
+        let mut limited = false;
+        for name in ["max_tokens", "max_completion_tokens"] {
+            limited |= request.get(name).is_some_and(|value| !value.is_null());
+            changed |= limit(&mut request, name, json!(max), action)?;
+        }
+        if !limited {
+            request.insert("max_tokens".to_string(), json!(max));
+            changed = true;
+        }
+    }
+
+    if !changed {
+        return Ok(body);
+    }
+    Ok(serde_json::to_vec(&request)
+        .map(Bytes::from)
+        .unwrap_or(body))
+}
+
+/// Lowers parameter `name` to `max` when it is over it, or rejects it.
+/// Returns whether the request changed.
+fn limit(
+    request: &mut Map<String, Value>,
+    name: &str,
+    max: Value,
+    action: LimitAction,
+) -> Result<bool, OpenAIError> {
+    let Some(value) = request.get(name).and_then(Value::as_f64) else {
+        return Ok(false);
+    };
+    if max.as_f64().is_some_and(|max| value <= max) {
+        return Ok(false);
+    }
+    match action {
+        LimitAction::Clamp => {
+            request.insert(name.to_string(), max);
+            Ok(true)
+        }
+        LimitAction::Reject => Err(OpenAIError::invalid_request_error(format!(
+            "Invalid '{}': this proxy allows at most {}, but got {} instead.",
+            name, max, request[name]
+        ))
+        .with_param(name)
+        .with_code("invalid_value")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire;
+
+    fn limited_config(action: LimitAction) -> Config {
+        Config::default()
+            .with_max_temperature(1.0)
+            .with_max_n(2)
+            .with_max_tokens_limit(1024)
+            .with_model_max_tokens("small-model=256".parse().unwrap())
+            .with_param_limit_action(action)
+    }
+
+    fn enforce(config: &Config, body: &'static str) -> Result<Value, OpenAIError> {
+        let body = enforce_param_limits(config, Bytes::from_static(body.as_bytes()))?;
+        Ok(serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn parses_model_token_limits() {
+        let limit: ModelTokenLimit = "llama3-3-70b=4096".parse().unwrap();
+        assert_eq!(limit.max_tokens, 4096);
+        assert_eq!(limit.to_string(), "llama3-3-70b=4096");
+        for invalid in ["llama3-3-70b", "=10", "m=0", "m=-1"] {
+            assert!(invalid.parse::<ModelTokenLimit>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn clamps_parameters_to_their_limits() {
+        let config = limited_config(LimitAction::Clamp);
+
+        let request = enforce(
+            &config,
+            r#"{"model":"m","temperature":1.8,"n":8,"max_tokens":100000}"#,
+        )
+        .unwrap();
+        assert_eq!(request["temperature"], 1.0);
+        assert_eq!(request["n"], 2);
+        assert_eq!(request["max_tokens"], 1024);
+
+        let request = enforce(&config, r#"{"model":"small-model","temperature":0.5}"#).unwrap();
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["max_tokens"], 256);
+
+        let request = enforce(&config, r#"{"model":"m","max_completion_tokens":64}"#).unwrap();
+        assert_eq!(request["max_completion_tokens"], 64);
+        assert!(request.get("max_tokens").is_none());
+    }
+
+    #[test]
+    fn rejects_parameters_over_their_limits() {
+        let config = limited_config(LimitAction::Reject);
+
+        let error = enforce(&config, r#"{"model":"small-model","max_tokens":512}"#).unwrap_err();
+        let error = wire::to_value(&error)["error"].clone();
+        assert_eq!(error["param"], "max_tokens");
+        assert_eq!(
+            error["message"],
+            "Invalid 'max_tokens': this proxy allows at most 256, but got 512 instead."
+        );
+
+        let request = enforce(&config, r#"{"model":"m","n":2}"#).unwrap();
+        assert_eq!(request["max_tokens"], 1024);
+    }
+}
@@ -1,10 +1,43 @@
-use maple_proxy::{create_app, Config};
-use tracing::{info, Level};
+use maple_proxy::{
+    apply_process_sandbox, create_attested_app, diagnose, hash_key, init, serve, snippets,
+    startup_snippet, Command, Config, SchemaValidation, StartupAttestation, StreamRecovery,
+};
+#[cfg(feature = "self-update")]
+use maple_proxy::{self_update, Restart};
+use std::io::Write;
+use tracing::{info, warn, Level};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config = Config::load();
+    config.validate()?;
+
+    match &config.command {
+        Some(Command::Init(args)) => return init(args),
+        Some(Command::Diagnose(args)) => {
+            let bundle = diagnose(&config, args).await;
+            match &args.output {
+                Some(path) => {
+                    std::fs::write(path, bundle)?;
+                    eprintln!("Wrote diagnostics bundle to {}", path.display());
+                }
+                None => println!("{}", bundle),
+            }
+            return Ok(());
+        }
+        Some(Command::Snippets(args)) => {
+            print!("{}", snippets(&config, args).await);
+            return Ok(());
+        }
+        Some(Command::HashKey(args)) => {
+            print!("{}", hash_key(args)?);
+            return Ok(());
+        }
+        #[cfg(feature = "self-update")]
+        Some(Command::SelfUpdate(args)) => return self_update(args).await,
+        None => {}
+    }
 
     // Initialize tracing
     let filter = if config.debug {
@@ -21,29 +54,367 @@ async fn main() -> anyhow::Result<()> {
     info!("Starting Maple Proxy Server");
     info!("Version: {}", env!("CARGO_PKG_VERSION"));
     info!("Backend URL: {}", config.backend_url);
+    for backend_url in &config.fallback_backend_urls {
+        info!("Fallback backend URL: {}", backend_url);
+    }
     info!("Binding to: {}", config.socket_addr()?);
+    for expected_pcr in &config.expected_pcrs {
+        info!("Pinned enclave measurement: PCR{}", expected_pcr);
+    }
+    if config.startup_attestation != StartupAttestation::Off {
+        info!(
+            "Attesting backends before serving ({:?})",
+            config.startup_attestation
+        );
+    }
+    if config.session_refresh_secs > 0 {
+        info!(
+            "Attested sessions refresh {}s before they expire",
+            config.session_refresh_secs
+        );
+    }
 
-    if config.default_api_key.is_some() {
+    if let Some(path) = &config.api_key_file {
+        info!("Default API key read from {}", path.display());
+    } else if !config.api_keys.is_empty() {
+        info!(
+            "Default API key pool: {} keys, {:?} rotation, {}s cooldown",
+            config.api_keys.len(),
+            config.api_key_rotation,
+            config.api_key_cooldown_secs
+        );
+    } else if config.default_api_key.is_some() {
         info!("Default API key configured");
     } else {
         info!("No default API key - clients must provide Authorization header");
     }
+    if config.api_key_headers {
+        info!("API keys also accepted in api-key and x-api-key headers");
+    }
+    if config.query_api_key {
+        warn!("API keys also accepted in the api_key query parameter - meant for test tools");
+    }
+
+    if let Some(upstream_url) = &config.openai_upstream_url {
+        info!(
+            "OpenAI-compatible upstream: {} for {}",
+            upstream_url,
+            config.openai_upstream_models.join(", ")
+        );
+    }
+    if let Some(moderation_url) = &config.moderation_url {
+        info!("Moderation classifier: {}", moderation_url);
+    }
+    if let Some(moderation_model) = &config.moderation_model {
+        info!("Default moderation model: {}", moderation_model);
+    }
+    for alias in &config.model_aliases {
+        info!("Model alias: {} -> {}", alias.alias, alias.model);
+    }
+    for pool in &config.model_pools {
+        info!("Model pool: {}", pool);
+    }
+    for deployment in &config.azure_deployments {
+        info!(
+            "Azure deployment: {} -> {}",
+            deployment.alias, deployment.model
+        );
+    }
+    for alias in &config.path_aliases {
+        info!("Path alias: {} -> {}", alias.from, alias.to);
+    }
+    if config.normalize_paths {
+        info!("Serving OpenAI endpoints without /v1 and paths with trailing slashes");
+    }
 
     // Build the application
-    let app = create_app(config.clone());
+    let (app, stats) = create_attested_app(config.clone()).await?;
 
-    if config.enable_cors {
+    if !config.cors_origins.is_empty() {
+        info!("CORS enabled for: {}", config.cors_origins.join(", "));
+        if config.cors_allow_credentials {
+            info!("CORS credentials allowed");
+        }
+    } else if config.enable_cors {
         info!("CORS enabled for all origins");
     }
+    if config.demo {
+        info!("Demo mode enabled: anonymous access with the default API key");
+    }
+    if config.mock_backend {
+        warn!(
+            "Mock backend enabled: synthetic responses at {} words per second, Maple is never contacted",
+            config.mock_tokens_per_second
+        );
+    }
+    if !config.allowed_models.is_empty() {
+        info!("Allowed models: {}", config.allowed_models.join(", "));
+    }
+    if !config.model_list.is_empty() {
+        info!("Model list: {}", config.model_list.join(", "));
+    }
+    if !config.hidden_models.is_empty() {
+        info!("Hidden models: {}", config.hidden_models.join(", "));
+    }
+    if let Some(limit) = config.rate_limit_per_minute {
+        info!("Rate limit: {} requests per minute per client IP", limit);
+    }
+    if !config.trusted_proxies.is_empty() {
+        let proxies: Vec<String> = config
+            .trusted_proxies
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        info!(
+            "Trusting client addresses in {:?} from: {}",
+            config.forwarded_header,
+            proxies.join(", ")
+        );
+    }
+    info!(
+        "Request limits: {} MB bodies, JSON nested up to {} levels, {} MB audio parts",
+        config.max_body_mb, config.max_json_depth, config.audio_max_mb
+    );
+    if let Some(max_requests) = config.max_concurrent_requests {
+        info!(
+            "Admission queue: {} requests at once, up to {} more waiting for {}s",
+            max_requests, config.queue_depth, config.queue_timeout_secs
+        );
+    }
+    if config.stream_recovery != StreamRecovery::Off {
+        info!(
+            "Recovering cut-off chat completion streams: {:?}",
+            config.stream_recovery
+        );
+    }
+    if config.stream_recovery == StreamRecovery::Resume && config.resume_backend_urls.is_empty() {
+        warn!("No --resume-backend-url is set, so cut-off streams finish with an error instead");
+    }
+    if config.aggregate_streams {
+        info!("Non-streaming chat completions are streamed from the backend and assembled");
+    }
+    if let Some(interval_ms) = config.coalesce_ms {
+        info!(
+            "Coalescing streamed text deltas every {}ms or {} bytes",
+            interval_ms, config.coalesce_bytes
+        );
+    }
+    for price in &config.model_prices {
+        info!("Model price (USD per million tokens): {}", price);
+    }
+    if let Some(cost) = config.max_request_cost {
+        info!("Default cost ceiling: ${} per request", cost);
+    }
+    for (phase, timeout) in [
+        ("DNS", config.dns_timeout_ms),
+        ("Connect", config.connect_timeout_ms),
+        ("TLS", config.tls_timeout_ms),
+    ] {
+        if let Some(timeout) = timeout {
+            info!(
+                "{} timeout for new backend connections: {} ms",
+                phase, timeout
+            );
+        }
+    }
+    if let Some(lifetime) = config.max_connection_lifetime_secs {
+        info!("Client connections close after {} seconds", lifetime);
+    }
+    if let Some(requests) = config.max_connection_requests {
+        info!("Client connections close after {} requests", requests);
+    }
+    if config.connection_idle_timeout_secs > 0 {
+        info!(
+            "Client connections close after {} idle seconds",
+            config.connection_idle_timeout_secs
+        );
+    }
+    if let Some(max_connections) = config.max_connections_per_ip {
+        info!("Client IPs may hold {} connections open", max_connections);
+    }
+    if config.strict_openai {
+        info!("Strict OpenAI mode: rejecting fields outside the OpenAI schemas");
+    } else if config.schema_validation != SchemaValidation::Off {
+        info!("OpenAI schema validation: {:?}", config.schema_validation);
+    }
+    if let Some(profile) = config.compat_profile {
+        info!("Default compatibility profile: {:?}", profile);
+    }
+    if config.normalize_tool_calls {
+        info!("Repairing off-spec tool calls in chat completions");
+    }
+    if config.enforce_response_format {
+        info!(
+            "Enforcing response_format on non-streaming chat completions ({} attempts)",
+            config.response_format_attempts
+        );
+    }
+    if config.repair_json_output {
+        info!("Repairing JSON chat completions that do not parse");
+    }
+    if config.fetch_images {
+        info!(
+            "Inlining remote images up to {} MB as data URLs ({})",
+            config.image_max_mb,
+            config.image_types.join(", ")
+        );
+    }
+    if config.passthrough {
+        info!("Passthrough mode: OpenAI request and response bodies are forwarded unchanged");
+    }
+    if config.server_timing {
+        info!("Reporting attestation and backend time in Server-Timing headers");
+    }
+    if config.enable_compression {
+        info!("Compressing responses and accepting compressed request bodies (gzip, br)");
+    }
+    if let Some(threshold_ms) = config.slow_request_ms {
+        info!("Logging requests slower than {} ms", threshold_ms);
+    }
+    if let Some(path) = &config.routes_file {
+        info!("Model aliases and routes are saved to {}", path.display());
+    }
+    if let Some(path) = &config.keys_file {
+        info!("Virtual keys are saved to {}", path.display());
+    }
+    if let Some(path) = &config.client_keys_file {
+        info!("Requiring client keys from {}", path.display());
+    }
+    if let Some(url) = &config.jwt_jwks_url {
+        info!(
+            "Accepting JWTs signed by a key at {}, charged to the virtual key named by '{}'",
+            url, config.jwt_key_claim
+        );
+    }
+    if config.redis_url.is_some() {
+        info!(
+            "Sharing rate limits, key usage, and caches through Redis (key prefix {})",
+            config.redis_key_prefix
+        );
+    }
+    if config.honeypot_enabled() {
+        match config.honeypot_block_secs {
+            0 => info!("Honeypot: flagging IPs that probe decoy paths"),
+            secs => info!(
+                "Honeypot: blocking IPs that probe decoy paths for {}s",
+                secs
+            ),
+        }
+    }
+    if let Some(failures) = config.ip_ban_auth_failures {
+        info!(
+            "IP bans: {} authentication failures within {}s ban an IP for {}s",
+            failures, config.ip_ban_window_secs, config.ip_ban_secs
+        );
+    }
+    for pipeline in &config.pipelines {
+        info!("Pipeline: {}", pipeline);
+    }
+    for blackout in &config.model_blackouts {
+        info!(
+            "Refusing model {} during {} UTC",
+            blackout.model, blackout.window
+        );
+    }
+    if config.geoip_db.is_some() || config.asn_db.is_some() {
+        info!(
+            "Country and network rules: {} allowed and {} denied countries, {} allowed and {} \
+             denied networks, {} country rate limits",
+            config.allow_countries.len(),
+            config.deny_countries.len(),
+            config.allow_asns.len(),
+            config.deny_asns.len(),
+            config.country_rate_limits.len()
+        );
+    }
+    if let Some(path) = &config.audit_db {
+        info!(
+            "Auditing requests to {} (content: {:?})",
+            path.display(),
+            config.audit_content
+        );
+    }
+    if let Some(path) = &config.dataset_file {
+        info!("Recording finished chat completions to {}", path.display());
+    }
+    if let Some(dir) = &config.batch_dir {
+        info!(
+            "Batch API files in {} ({} requests at a time, {} retries)",
+            dir.display(),
+            config.batch_concurrency,
+            config.batch_max_retries
+        );
+    }
+    if let Some(path) = &config.background_db {
+        info!("Keeping background completions in {}", path.display());
+    }
+    if config.idempotency_ttl_secs > 0 {
+        info!(
+            "Replaying chat completions for a repeated Idempotency-Key for {}s",
+            config.idempotency_ttl_secs
+        );
+    }
+    if config.update_check {
+        info!(
+            "Daily update checks on the {:?} channel",
+            config.update_channel
+        );
+    }
+
+    // Captured before the sandbox can chroot away from the executable
+    #[cfg(feature = "self-update")]
+    let restart = Restart::capture()?;
 
     let listener = tokio::net::TcpListener::bind(config.socket_addr()?).await?;
+    // Opened now because the sandbox may make the path unreachable
+    let mut report_file = match &config.shutdown_report {
+        Some(path) => Some(std::fs::File::create(path)?),
+        None => None,
+    };
+    apply_process_sandbox(&config)?;
 
     info!("🚀 Maple Proxy Server started successfully!");
     info!("📋 Available endpoints:");
     info!("   GET  /health              - Health check");
+    info!("   GET  /version             - Running version and update status");
+    if config.enable_playground {
+        info!("   GET  /playground          - Browser chat playground");
+    }
+    if config.enable_metrics {
+        info!("   GET  /metrics             - Prometheus metrics");
+    }
     info!("   GET  /v1/models           - List available models");
     info!("   POST /v1/chat/completions - Create chat completions (streaming & non-streaming)");
     info!("   POST /v1/embeddings       - Create embeddings");
+    info!("   POST /v1/tokenize         - Count tokens locally");
+    info!("   POST /v1/audio/transcriptions - Transcribe audio (multipart, streaming)");
+    info!("   POST /v1/audio/speech     - Synthesize speech (streamed audio)");
+    info!("   POST /v1/images/generations - Generate images");
+    info!("   POST /v1/moderations      - Classify content");
+    info!("   POST /v1/rerank           - Rerank documents against a query");
+    info!("   GET  /v1/responses/{{id}}   - Poll a background chat completion");
+    if config.enable_ollama_api {
+        info!("   POST /api/chat            - Ollama chat");
+        info!("   POST /api/generate        - Ollama generate");
+        info!("   GET  /api/tags            - Ollama model list");
+    }
+    if config.batch_dir.is_some() {
+        info!("   POST /v1/files            - Upload batch input files");
+        info!("   POST /v1/batches          - Run batches of requests in the background");
+    }
+    if config.enable_azure_api {
+        info!("   POST /openai/deployments/{{deployment}}/chat/completions - Azure chat");
+        info!("   POST /openai/deployments/{{deployment}}/embeddings       - Azure embeddings");
+    }
+    if config.enable_grpc {
+        info!("   gRPC maple.v1.Inference   - Chat completions and embeddings over gRPC");
+    }
+    if config.admin_token.is_some() {
+        info!("   GET  /admin/aliases       - Model aliases (PUT/DELETE /admin/aliases/{{alias}})");
+        info!("   GET  /admin/routes        - Model routes (PUT/DELETE /admin/routes/{{model}})");
+        info!("   GET  /admin/keys          - Virtual keys and usage (POST to issue one)");
+        info!("   GET  /admin/config        - Running configuration, secrets redacted");
+    }
     info!("");
     info!("💡 Usage:");
     info!(
@@ -52,13 +423,79 @@ async fn main() -> anyhow::Result<()> {
     info!("   OpenAI-compatible clients can use this proxy as their base URL");
     info!("");
     info!("🔗 Example curl:");
-    info!("   curl http://{} \\", config.socket_addr()?);
-    info!("     -H \"Authorization: Bearer YOUR_MAPLE_API_KEY\" \\");
-    info!("     -H \"Content-Type: application/json\" \\");
-    info!("     -d '{{\"model\": \"gpt-4\", \"messages\": [{{\"role\": \"user\", \"content\": \"Hello!\"}}]}}'");
-    info!("     /v1/chat/completions");
+    for line in startup_snippet(&config).lines() {
+        info!("   {}", line);
+    }
+    info!("   Run `maple-proxy snippets --lang python|js|curl|rust` for more client code");
+
+    let limits = config.connection_limits();
+
+    // SIGINT and SIGTERM drain in-flight requests before exiting
+    #[cfg(not(feature = "self-update"))]
+    serve(listener, app, limits, shutdown_signal()).await?;
+
+    // SIGHUP also drains in-flight requests, then restarts into the (possibly
+    // updated) binary
+    #[cfg(feature = "self-update")]
+    let restart_requested = {
+        let (requested_tx, requested_rx) = tokio::sync::oneshot::channel();
+        serve(listener, app, limits, async move {
+            let restart = tokio::select! {
+                _ = Restart::requested() => true,
+                _ = shutdown_signal() => false,
+            };
+            let _ = requested_tx.send(restart);
+        })
+        .await?;
+        requested_rx.await.unwrap_or(false)
+    };
 
-    axum::serve(listener, app).await?;
+    let report = stats.report();
+    report.log();
+    if let Some(file) = &mut report_file {
+        if let Err(error) = writeln!(file, "{}", report.to_json()) {
+            warn!("Failed to write the shutdown report: {}", error);
+        }
+    }
+
+    #[cfg(feature = "self-update")]
+    if restart_requested {
+        info!("Restarting after in-flight requests finished");
+        restart.exec()?;
+    }
 
     Ok(())
 }
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            warn!("Cannot listen for Ctrl-C: {}", error);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(error) => {
+                warn!("Cannot listen for SIGTERM: {}", error);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down after in-flight requests finish");
+}
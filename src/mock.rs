@@ -0,0 +1,303 @@
+use crate::{
+    ids::unix_now,
+    proxy::{Backend, BackendBody},
+    wire::{self, Usage},
+};
+use axum::{
+    body::Bytes,
+    http::{header, Method, Request, StatusCode},
+};
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Stands in for the API key of clients that send none
+pub(crate) const MOCK_API_KEY: &str = "mock";
+
+/// The models `--mock-backend` lists
+const MOCK_MODELS: &[&str] = &[
+    "llama3-3-70b",
+    "gemma4-31b",
+    "qwen3-coder-480b",
+    "nomic-embed-text",
+];
+
+/// Completions without `max_tokens` stop after this many words
+const DEFAULT_COMPLETION_WORDS: u64 = 64;
+const MAX_COMPLETION_WORDS: u64 = 1024;
+const EMBEDDING_DIMENSIONS: usize = 8;
+
+const LOREM_IPSUM: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do \
+    eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis \
+    nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat.";
+
+/// Serves canned model lists, lorem ipsum chat completions, and made-up
+/// embeddings without contacting Maple, for developing against the proxy
+/// offline. Streams send one word per delta at `tokens_per_second`.
+pub(crate) struct MockBackend {
+    word_interval: Duration,
+    next_id: AtomicU64,
+}
+
+impl MockBackend {
+    pub(crate) fn new(tokens_per_second: u32) -> Self {
+        Self {
+            word_interval: Duration::from_secs(1) / tokens_per_second.max(1),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    fn respond(&self, request: Request<Bytes>) -> http::Response<BackendBody> {
+        let (parts, body) = request.into_parts();
+        let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+        match (&parts.method, parts.uri.path()) {
+            (&Method::GET, "/v1/models") => json_response(StatusCode::OK, model_list()),
+            (&Method::POST, "/v1/chat/completions") => {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                let id = format!("chatcmpl-mock-{}", id);
+                let words = completion_words(&request);
+                if request["stream"].as_bool().unwrap_or(false) {
+                    self.completion_stream(id, request, words)
+                } else {
+                    json_response(StatusCode::OK, completion(&id, &request, &words))
+                }
+            }
+            (&Method::POST, "/v1/embeddings") => {
+                json_response(StatusCode::OK, embeddings(&request))
+            }
+            (_, path) => json_response(
+                StatusCode::NOT_FOUND,
+                json!({
+                    "error": {
+                        "message": format!("The mock backend does not serve {}", path),
+                        "type": "invalid_request_error",
+                        "param": null,
+                        "code": null
+                    }
+                }),
+            ),
+        }
+    }
+
+    fn completion_stream(
+        &self,
+        id: String,
+        request: Value,
+        words: Vec<&'static str>,
+    ) -> http::Response<BackendBody> {
+        let word_interval = self.word_interval;
+        let model = request["model"].as_str().unwrap_or_default().to_string();
+        let created = unix_now();
+        let chunk = move |delta: Value, finish_reason: Value| {
+            let chunk = json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": model,
+                "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]
+            });
+            Ok::<_, opensecret::Error>(Bytes::from(format!("data: {}\n\n", chunk)))
+        };
+
+        let body: BackendBody = Box::pin(async_stream::stream! {
+            yield chunk(json!({"role": "assistant", "content": ""}), Value::Null);
+            for (index, word) in words.iter().enumerate() {
+                tokio::time::sleep(word_interval).await;
+                let content = if index == 0 { word.to_string() } else { format!(" {}", word) };
+                yield chunk(json!({"content": content}), Value::Null);
+            }
+            yield chunk(json!({}), json!("stop"));
+            yield Ok(Bytes::from_static(b"data: [DONE]\n\n"));
+        });
+        http::Response::builder()
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .body(body)
+            .expect("static headers are valid")
+    }
+}
+
+impl Backend for MockBackend {
+    fn send_inference_request(
+        &self,
+        request: Request<Bytes>,
+    ) -> BoxFuture<'_, Result<http::Response<BackendBody>, opensecret::Error>> {
+        let response = self.respond(request);
+        Box::pin(async { Ok(response) })
+    }
+}
+
+fn model_list() -> Value {
+    let data: Vec<Value> = MOCK_MODELS
+        .iter()
+        .map(|model| json!({"id": model, "object": "model", "created": 0, "owned_by": "mock"}))
+        .collect();
+    json!({"object": "list", "data": data})
+}
+
+fn completion_words(request: &Value) -> Vec<&'static str> {
+    let count = request["max_completion_tokens"]
+        .as_u64()
+        .or_else(|| request["max_tokens"].as_u64())
+        .unwrap_or(DEFAULT_COMPLETION_WORDS)
+        .min(MAX_COMPLETION_WORDS);
+    LOREM_IPSUM
+        .split_whitespace()
+        .cycle()
+        .take(count as usize)
+        .collect()
+}
+
+fn completion(id: &str, request: &Value, words: &[&str]) -> Value {
+    json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": unix_now(),
+        "model": request["model"],
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": words.join(" ")},
+            "finish_reason": "stop"
+        }],
+        "usage": wire::to_value(&Usage::completion(0, words.len() as u64))
+    })
+}
+
+/// Each input gets a small vector derived from its text, so equal inputs get
+/// equal embeddings
+fn embeddings(request: &Value) -> Value {
+    let inputs: Vec<String> = match &request["input"] {
+        Value::Array(inputs) => inputs.iter().map(Value::to_string).collect(),
+        input => vec![input.to_string()],
+    };
+    let data: Vec<Value> = inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let embedding: Vec<f64> = (0..EMBEDDING_DIMENSIONS)
+                .map(|dimension| {
+                    let sum = input
+                        .bytes()
+                        .skip(dimension)
+                        .step_by(EMBEDDING_DIMENSIONS)
+                        .map(u32::from)
+                        .sum::<u32>();
+                    f64::from(sum % 1000) / 1000.0
+                })
+                .collect();
+            json!({"object": "embedding", "index": index, "embedding": embedding})
+        })
+        .collect();
+    json!({
+        "object": "list",
+        "data": data,
+        "model": request["model"],
+        "usage": wire::to_value(&Usage::embeddings(0))
+    })
+}
+
+fn json_response(status: StatusCode, body: Value) -> http::Response<BackendBody> {
+    let body: BackendBody = Box::pin(futures::stream::once(async move {
+        Ok::<_, opensecret::Error>(Bytes::from(body.to_string()))
+    }));
+    http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .expect("static headers are valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn request(method: Method, path: &str, body: &str) -> Request<Bytes> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Bytes::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn body_text(response: http::Response<BackendBody>) -> String {
+        let chunks: Vec<Bytes> = response
+            .into_body()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn streams_one_word_per_delta() {
+        let backend = MockBackend::new(1000);
+        let body = r#"{"model":"llama3-3-70b","stream":true,"max_tokens":3}"#;
+        let response = backend
+            .send_inference_request(request(Method::POST, "/v1/chat/completions", body))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        let events: Vec<Value> = body_text(response)
+            .await
+            .split("\n\n")
+            .filter_map(|event| event.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let content: String = events
+            .iter()
+            .filter_map(|event| event["choices"][0]["delta"]["content"].as_str())
+            .collect();
+        assert_eq!(content, "Lorem ipsum dolor");
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[4]["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn serves_models_completions_and_embeddings() {
+        let backend = MockBackend::new(1000);
+
+        let response = backend
+            .send_inference_request(request(Method::GET, "/v1/models", ""))
+            .await
+            .unwrap();
+        let models: Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(models["data"][0]["id"], "llama3-3-70b");
+
+        let body = r#"{"model":"gemma4-31b","messages":[],"max_tokens":2}"#;
+        let response = backend
+            .send_inference_request(request(Method::POST, "/v1/chat/completions", body))
+            .await
+            .unwrap();
+        let completion: Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(completion["model"], "gemma4-31b");
+        assert_eq!(
+            completion["choices"][0]["message"]["content"],
+            "Lorem ipsum"
+        );
+
+        let body = r#"{"model":"nomic-embed-text","input":["a","b","a"]}"#;
+        let response = backend
+            .send_inference_request(request(Method::POST, "/v1/embeddings", body))
+            .await
+            .unwrap();
+        let embeddings: Value = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(embeddings["data"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            embeddings["data"][0]["embedding"],
+            embeddings["data"][2]["embedding"]
+        );
+        assert_ne!(
+            embeddings["data"][0]["embedding"],
+            embeddings["data"][1]["embedding"]
+        );
+    }
+}
@@ -0,0 +1,445 @@
+use crate::{
+    config::Config,
+    pools::ModelPool,
+    storage,
+    wire::{self, ModelObject},
+};
+use anyhow::Context;
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+/// Maps a client-facing model name (e.g. `gpt-4`) onto a Maple model
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ModelAlias {
+    pub alias: String,
+    pub model: String,
+}
+
+impl ModelAlias {
+    pub fn new(alias: impl Into<String>, model: impl Into<String>) -> Self {
+        Self {
+            alias: alias.into(),
+            model: model.into(),
+        }
+    }
+}
+
+impl FromStr for ModelAlias {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (alias, model) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected ALIAS=MODEL, got '{}'", value))?;
+        let (alias, model) = (alias.trim(), model.trim());
+
+        if alias.is_empty() || model.is_empty() {
+            return Err(format!("expected ALIAS=MODEL, got '{}'", value));
+        }
+
+        Ok(Self::new(alias, model))
+    }
+}
+
+impl fmt::Display for ModelAlias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.alias, self.model)
+    }
+}
+
+/// The alias and routing tables. They start out from the configuration or the
+/// routes file and can be replaced at runtime through the admin API.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct ModelTables {
+    #[serde(default)]
+    pub(crate) aliases: Vec<ModelAlias>,
+    /// Models routed to the OpenAI-compatible upstream instead of Maple
+    #[serde(default)]
+    pub(crate) upstream_models: Vec<String>,
+}
+
+impl ModelTables {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Self {
+            aliases: config.model_aliases.clone(),
+            upstream_models: config.openai_upstream_models.clone(),
+        }
+    }
+
+    /// Reads tables saved by the admin API, or `None` if there are none yet
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(error).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .with_context(|| format!("Invalid routes file {}", path.display()))
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        storage::write_json(path, self)
+    }
+}
+
+pub(crate) fn resolve_model_alias<'a>(aliases: &'a [ModelAlias], model: &str) -> Option<&'a str> {
+    aliases
+        .iter()
+        .find(|alias| alias.alias == model)
+        .map(|alias| alias.model.as_str())
+}
+
+/// Rewrites the `model` field of a JSON request body when it names an alias.
+///
+/// Returns `None` when the body is left untouched, so non-JSON or unaliased
+/// requests keep being forwarded byte for byte.
+pub(crate) fn rewrite_request_model(aliases: &[ModelAlias], body: &Bytes) -> Option<Bytes> {
+    if aliases.is_empty() {
+        return None;
+    }
+
+    let mut request: Value = serde_json::from_slice(body).ok()?;
+    let model = request.get("model")?.as_str()?;
+    let target = resolve_model_alias(aliases, model)?;
+    request["model"] = Value::String(target.to_string());
+
+    serde_json::to_vec(&request).ok().map(Bytes::from)
+}
+
+/// Whether `/v1/models` responses must be buffered and rewritten
+pub(crate) fn model_list_needs_rewrite(config: &Config, tables: &ModelTables) -> bool {
+    !tables.aliases.is_empty()
+        || !config.model_pools.is_empty()
+        || !config.allowed_models.is_empty()
+        || !config.model_list.is_empty()
+        || !config.hidden_models.is_empty()
+        || !tables.upstream_models.is_empty()
+}
+
+/// Applies OpenAI-compatible upstream models, `--model-list`, the model
+/// allowlist, hidden models, aliases, and model pools to a `/v1/models`
+/// response body
+pub(crate) fn rewrite_model_list(
+    config: &Config,
+    tables: &ModelTables,
+    body: &[u8],
+) -> Option<Bytes> {
+    let mut list: Value = serde_json::from_slice(body).ok()?;
+    let models = list.get_mut("data")?.as_array_mut()?;
+
+    for model in &tables.upstream_models {
+        if !models.iter().any(|entry| model_id(entry) == Some(model)) {
+            models.push(wire::to_value(&ModelObject::new(model, "openai-upstream")));
+        }
+    }
+    if !config.model_list.is_empty() {
+        *models = config
+            .model_list
+            .iter()
+            .map(|model| {
+                models
+                    .iter()
+                    .find(|entry| model_id(entry) == Some(model))
+                    .cloned()
+                    .unwrap_or_else(|| wire::to_value(&ModelObject::new(model, "maple")))
+            })
+            .collect();
+    }
+    models.retain(|entry| {
+        model_id(entry).is_some_and(|id| {
+            is_model_allowed(&config.allowed_models, id) && is_model_listed(config, id)
+        })
+    });
+    add_aliases_to_model_list(&tables.aliases, models);
+    add_pools_to_model_list(&config.model_pools, models);
+
+    serde_json::to_vec(&list).ok().map(Bytes::from)
+}
+
+/// Appends an entry for every alias whose target appears in the model list,
+/// copying the target's metadata under the alias id.
+fn add_aliases_to_model_list(aliases: &[ModelAlias], models: &mut Vec<Value>) {
+    for alias in aliases {
+        if models
+            .iter()
+            .any(|entry| model_id(entry) == Some(&alias.alias))
+        {
+            continue;
+        }
+        let Some(mut entry) = models
+            .iter()
+            .find(|entry| model_id(entry) == Some(&alias.model))
+            .cloned()
+        else {
+            continue;
+        };
+        entry["id"] = Value::String(alias.alias.clone());
+        models.push(entry);
+    }
+}
+
+/// Appends an entry for every pool with a member in the model list, copying the
+/// first listed member's metadata under the pool name
+fn add_pools_to_model_list(pools: &[ModelPool], models: &mut Vec<Value>) {
+    for pool in pools {
+        if models
+            .iter()
+            .any(|entry| model_id(entry) == Some(&pool.name))
+        {
+            continue;
+        }
+        let Some(mut entry) = pool.members.iter().find_map(|member| {
+            models
+                .iter()
+                .find(|entry| model_id(entry) == Some(&member.model))
+                .cloned()
+        }) else {
+            continue;
+        };
+        entry["id"] = Value::String(pool.name.clone());
+        models.push(entry);
+    }
+}
+
+/// An empty allowlist allows every model
+pub(crate) fn is_model_allowed(allowed_models: &[String], model: &str) -> bool {
+    allowed_models.is_empty() || allowed_models.iter().any(|allowed| allowed == model)
+}
+
+/// Whether `--model-list` and `--hide-model` leave `model` to be served, as
+/// if the backend listed it or did not
+pub(crate) fn is_model_listed(config: &Config, model: &str) -> bool {
+    is_model_allowed(&config.model_list, model)
+        && !config.hidden_models.iter().any(|hidden| hidden == model)
+}
+
+/// Sets the `model` field of a JSON object request body
+pub(crate) fn with_request_model(body: &[u8], model: &str) -> Option<Bytes> {
+    let mut request: Value = serde_json::from_slice(body).ok()?;
+    request
+        .as_object_mut()?
+        .insert("model".to_string(), Value::String(model.to_string()));
+
+    serde_json::to_vec(&request).ok().map(Bytes::from)
+}
+
+/// Reads the `model` field from a JSON request body
+pub(crate) fn request_model(body: &[u8]) -> Option<String> {
+    let request: Value = serde_json::from_slice(body).ok()?;
+    request.get("model")?.as_str().map(str::to_string)
+}
+
+/// Whether a `/v1/models` response body lists `model`, or `None` when the body
+/// is not a model list
+pub(crate) fn model_list_contains(body: &[u8], model: &str) -> Option<bool> {
+    let list: Value = serde_json::from_slice(body).ok()?;
+    let models = list.get("data")?.as_array()?;
+    Some(models.iter().any(|entry| model_id(entry) == Some(model)))
+}
+
+fn model_id(entry: &Value) -> Option<&str> {
+    entry.get("id").and_then(Value::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn aliases() -> Vec<ModelAlias> {
+        vec![
+            ModelAlias::new("gpt-4", "qwen3-coder-480b"),
+            ModelAlias::new("gpt-3.5-turbo", "llama3-3-70b"),
+        ]
+    }
+
+    #[test]
+    fn parses_alias_pairs() {
+        assert_eq!(
+            "gpt-4 = qwen3-coder-480b".parse::<ModelAlias>().unwrap(),
+            ModelAlias::new("gpt-4", "qwen3-coder-480b")
+        );
+        assert!("gpt-4".parse::<ModelAlias>().is_err());
+        assert!("=qwen3-coder-480b".parse::<ModelAlias>().is_err());
+    }
+
+    #[test]
+    fn rewrites_aliased_model_and_keeps_other_fields() {
+        let body = Bytes::from_static(
+            br#"{"model":"gpt-4","messages":[],"chat_template_kwargs":{"enable_thinking":false}}"#,
+        );
+
+        let rewritten = rewrite_request_model(&aliases(), &body).unwrap();
+
+        assert_eq!(
+            rewritten,
+            r#"{"model":"qwen3-coder-480b","messages":[],"chat_template_kwargs":{"enable_thinking":false}}"#
+        );
+    }
+
+    #[test]
+    fn leaves_unaliased_and_non_json_bodies_untouched() {
+        let unaliased = Bytes::from_static(br#"{"model":"llama3-3-70b"}"#);
+        let opaque = Bytes::from_static(b"\0\xffnot-json");
+
+        assert!(rewrite_request_model(&aliases(), &unaliased).is_none());
+        assert!(rewrite_request_model(&aliases(), &opaque).is_none());
+        assert!(rewrite_request_model(&[], &unaliased).is_none());
+    }
+
+    fn list_body() -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "object": "list",
+            "data": [
+                {"id": "qwen3-coder-480b", "object": "model", "owned_by": "maple"},
+                {"id": "nomic-embed-text", "object": "model", "owned_by": "maple"}
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn test_config() -> Config {
+        Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+    }
+
+    fn rewritten_list(config: &Config) -> Value {
+        let tables = ModelTables::from_config(config);
+        serde_json::from_slice(&rewrite_model_list(config, &tables, &list_body()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn model_list_gains_entries_for_available_targets() {
+        let mut config = test_config();
+        config.model_aliases = aliases();
+
+        let list = rewritten_list(&config);
+
+        assert_eq!(
+            list["data"],
+            json!([
+                {"id": "qwen3-coder-480b", "object": "model", "owned_by": "maple"},
+                {"id": "nomic-embed-text", "object": "model", "owned_by": "maple"},
+                {"id": "gpt-4", "object": "model", "owned_by": "maple"}
+            ])
+        );
+    }
+
+    #[test]
+    fn model_list_includes_pools_with_an_available_member() {
+        let config = test_config()
+            .with_model_pool("fast=gemma4-31b:1,qwen3-coder-480b:1".parse().unwrap())
+            .with_model_pool("gone=gemma4-31b:1".parse().unwrap());
+
+        let list = rewritten_list(&config);
+
+        assert_eq!(
+            list["data"][2],
+            json!({"id": "fast", "object": "model", "owned_by": "maple"})
+        );
+        assert_eq!(list["data"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn model_list_hides_models_outside_the_allowlist() {
+        let config = test_config()
+            .with_allowed_models(vec!["qwen3-coder-480b".to_string()])
+            .with_model_alias("embed", "nomic-embed-text");
+
+        let list = rewritten_list(&config);
+
+        assert_eq!(
+            list["data"],
+            json!([{"id": "qwen3-coder-480b", "object": "model", "owned_by": "maple"}])
+        );
+    }
+
+    #[test]
+    fn model_list_can_be_replaced_or_trimmed() {
+        let config = test_config()
+            .with_model_list(vec!["nomic-embed-text".to_string(), "approved".to_string()])
+            .with_model_alias("embed", "nomic-embed-text");
+
+        let list = rewritten_list(&config);
+
+        assert_eq!(
+            list["data"],
+            json!([
+                {"id": "nomic-embed-text", "object": "model", "owned_by": "maple"},
+                {"id": "approved", "object": "model", "owned_by": "maple", "created": 0},
+                {"id": "embed", "object": "model", "owned_by": "maple"}
+            ])
+        );
+        assert!(!is_model_listed(&config, "qwen3-coder-480b"));
+
+        let config = test_config()
+            .with_hidden_models(vec!["nomic-embed-text".to_string()])
+            .with_model_alias("embed", "nomic-embed-text");
+        let ids: Vec<String> = rewritten_list(&config)["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(model_id)
+            .map(str::to_string)
+            .collect();
+        assert_eq!(ids, vec!["qwen3-coder-480b"]);
+        assert!(!is_model_listed(&config, "nomic-embed-text"));
+    }
+
+    #[test]
+    fn model_list_includes_upstream_models() {
+        let config = test_config()
+            .with_openai_upstream("http://localhost:11434", vec!["llama3.2".to_string()])
+            .with_model_alias("local", "llama3.2");
+
+        let list = rewritten_list(&config);
+
+        let ids: Vec<&str> = list["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(model_id)
+            .collect();
+        assert_eq!(
+            ids,
+            vec!["qwen3-coder-480b", "nomic-embed-text", "llama3.2", "local"]
+        );
+        assert_eq!(list["data"][2]["owned_by"], "openai-upstream");
+    }
+
+    #[test]
+    fn reads_request_model() {
+        assert_eq!(
+            request_model(br#"{"model":"gpt-4","messages":[]}"#).as_deref(),
+            Some("gpt-4")
+        );
+        assert!(request_model(br#"{"messages":[]}"#).is_none());
+        assert!(request_model(b"not-json").is_none());
+    }
+
+    #[test]
+    fn tables_round_trip_through_the_routes_file() {
+        let path = std::env::temp_dir().join(format!("maple-routes-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(ModelTables::load(&path).unwrap(), None);
+
+        let tables = ModelTables {
+            aliases: aliases(),
+            upstream_models: vec!["llama3.2".to_string()],
+        };
+        tables.save(&path).unwrap();
+        assert_eq!(ModelTables::load(&path).unwrap(), Some(tables));
+
+        fs::write(&path, "{not json").unwrap();
+        assert!(ModelTables::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{
+    proxy::{proxy_inference_request, ProxyError, ProxyState},
+    validation::ValidatedBody,
+};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, Method, Uri},
+    response::Response,
+};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// `/v1/moderations`, for clients that gate content on `omni-moderation`
+/// style checks. Requests without a `model` get `--moderation-model`, and
+/// with `--moderation-url` they go to that classifier instead of the backend.
+pub(crate) async fn create_moderation(
+    State(state): State<Arc<ProxyState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    ValidatedBody(body): ValidatedBody,
+) -> Result<Response, ProxyError> {
+    let body = match &state.config().moderation_model {
+        Some(model) => with_default_model(body, model),
+        None => body,
+    };
+    proxy_inference_request(&state, Method::POST, uri, &headers, body).await
+}
+
+/// The request with `model` set when the client left it out
+fn with_default_model(body: Bytes, model: &str) -> Bytes {
+    let Ok(mut request) = serde_json::from_slice::<Value>(&body) else {
+        return body;
+    };
+    let Some(fields) = request.as_object_mut() else {
+        return body;
+    };
+    if fields.get("model").is_some_and(|model| !model.is_null()) {
+        return body;
+    }
+    fields.insert("model".to_string(), Value::from(model));
+    serde_json::to_vec(&request).map_or(body, Bytes::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_without_a_model_get_the_default() {
+        let body = with_default_model(Bytes::from(r#"{"input":"hi"}"#), "omni-moderation-latest");
+        let request: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(request["model"], "omni-moderation-latest");
+
+        let chosen = Bytes::from(r#"{"model":"text-moderation","input":"hi"}"#);
+        assert_eq!(
+            with_default_model(chosen.clone(), "omni-moderation-latest"),
+            chosen
+        );
+    }
+}
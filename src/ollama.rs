@@ -0,0 +1,888 @@
+use crate::{
+    extension::EXTENSION_FIELD,
+    models,
+    proxy::{
+        collect_response_body, forward_inference_request, is_event_stream,
+        stream_with_idle_timeout, ByteStream, ProxyError, ProxyState, CHAT_COMPLETIONS_PATH,
+        MODELS_PATH,
+    },
+    sse::SseParser,
+    validation::check_json_depth,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use opensecret::client::OpenSecretResponseBody;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Request fields that only mean something to Ollama itself, or to the proxy
+const OLLAMA_ONLY_FIELDS: &[&str] = &[
+    EXTENSION_FIELD,
+    "keep_alive",
+    "raw",
+    "template",
+    "context",
+    "suffix",
+    "think",
+];
+
+/// Options that tune the Ollama runtime or sampler and have no OpenAI
+/// counterpart
+const OLLAMA_ONLY_OPTIONS: &[&str] = &[
+    "num_ctx",
+    "num_batch",
+    "num_gpu",
+    "main_gpu",
+    "num_thread",
+    "num_keep",
+    "use_mmap",
+    "use_mlock",
+    "numa",
+    "low_vram",
+    "vocab_only",
+    "f16_kv",
+    "logits_all",
+    "mirostat",
+    "mirostat_eta",
+    "mirostat_tau",
+    "repeat_last_n",
+    "tfs_z",
+    "penalize_newline",
+];
+
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    model: String,
+    #[serde(default)]
+    messages: Vec<Message>,
+    #[serde(default = "default_stream")]
+    stream: bool,
+    format: Option<Value>,
+    #[serde(default)]
+    options: Options,
+    /// Vendor extensions such as `reasoning_effort`, forwarded as they are
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GenerateRequest {
+    model: String,
+    #[serde(default)]
+    prompt: String,
+    system: Option<String>,
+    #[serde(default)]
+    images: Vec<String>,
+    #[serde(default = "default_stream")]
+    stream: bool,
+    format: Option<Value>,
+    #[serde(default)]
+    options: Options,
+    /// Vendor extensions such as `reasoning_effort`, forwarded as they are
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    role: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    images: Vec<String>,
+}
+
+/// The Ollama runtime options that have an OpenAI equivalent
+#[derive(Debug, Default, Deserialize)]
+struct Options {
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    top_k: Option<u32>,
+    num_predict: Option<i64>,
+    stop: Option<Vec<String>>,
+    seed: Option<i64>,
+    presence_penalty: Option<f64>,
+    frequency_penalty: Option<f64>,
+    /// Other sampling parameters, e.g. `min_p`, forwarded as top-level
+    /// request fields
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+}
+
+/// Ollama streams unless told otherwise
+fn default_stream() -> bool {
+    true
+}
+
+/// `/api/chat` wraps generated text in a message; `/api/generate` returns it
+/// as a bare `response` string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Chat,
+    Generate,
+}
+
+/// Builds Ollama response objects for one request
+struct Reply {
+    endpoint: Endpoint,
+    model: String,
+    started_at: Instant,
+}
+
+impl Reply {
+    fn new(endpoint: Endpoint, model: String) -> Self {
+        Self {
+            endpoint,
+            model,
+            started_at: Instant::now(),
+        }
+    }
+
+    fn line(&self, content: &str, done: bool) -> Value {
+        let mut line = json!({
+            "model": self.model,
+            "created_at": rfc3339(SystemTime::now()),
+        });
+        match self.endpoint {
+            Endpoint::Chat => line["message"] = json!({"role": "assistant", "content": content}),
+            Endpoint::Generate => line["response"] = json!(content),
+        }
+        line["done"] = json!(done);
+        line
+    }
+
+    /// Adds a reasoning model's chain of thought, which Ollama calls
+    /// `thinking`
+    fn with_thinking(&self, mut line: Value, thinking: &str) -> Value {
+        match self.endpoint {
+            Endpoint::Chat => line["message"]["thinking"] = json!(thinking),
+            Endpoint::Generate => line["thinking"] = json!(thinking),
+        }
+        line
+    }
+
+    fn final_line(
+        &self,
+        content: &str,
+        finish_reason: Option<&str>,
+        usage: Option<&Value>,
+    ) -> Value {
+        let mut line = self.line(content, true);
+        line["done_reason"] = json!(finish_reason.unwrap_or("stop"));
+        line["total_duration"] = json!(self.started_at.elapsed().as_nanos() as u64);
+        for (ollama_field, openai_field) in [
+            ("prompt_eval_count", "prompt_tokens"),
+            ("eval_count", "completion_tokens"),
+        ] {
+            if let Some(count) = usage.and_then(|usage| usage.get(openai_field)) {
+                line[ollama_field] = count.clone();
+            }
+        }
+        line
+    }
+}
+
+/// Ollama `/api/chat`, served by the chat completions backend
+pub(crate) async fn ollama_chat(
+    State(state): State<Arc<ProxyState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(error) = check_json_depth(&body, state.config().max_json_depth) {
+        return error_response(StatusCode::BAD_REQUEST, error.message());
+    }
+    let request: ChatRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(error) => return error_response(StatusCode::BAD_REQUEST, error.to_string()),
+    };
+
+    let messages = request
+        .messages
+        .into_iter()
+        .map(|message| openai_message(&message.role, message.content, &message.images))
+        .collect();
+    let completion_request = chat_completion_request(
+        &request.model,
+        messages,
+        request.stream,
+        request.format.as_ref(),
+        &request.options,
+        &request.extra,
+    );
+
+    complete(
+        &state,
+        &headers,
+        Reply::new(Endpoint::Chat, request.model),
+        request.stream,
+        completion_request,
+    )
+    .await
+}
+
+/// Ollama `/api/generate`, served as a single-turn chat completion
+pub(crate) async fn ollama_generate(
+    State(state): State<Arc<ProxyState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(error) = check_json_depth(&body, state.config().max_json_depth) {
+        return error_response(StatusCode::BAD_REQUEST, error.message());
+    }
+    let request: GenerateRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(error) => return error_response(StatusCode::BAD_REQUEST, error.to_string()),
+    };
+
+    let mut messages = Vec::new();
+    if let Some(system) = request.system {
+        messages.push(json!({"role": "system", "content": system}));
+    }
+    messages.push(openai_message("user", request.prompt, &request.images));
+    let completion_request = chat_completion_request(
+        &request.model,
+        messages,
+        request.stream,
+        request.format.as_ref(),
+        &request.options,
+        &request.extra,
+    );
+
+    complete(
+        &state,
+        &headers,
+        Reply::new(Endpoint::Generate, request.model),
+        request.stream,
+        completion_request,
+    )
+    .await
+}
+
+/// Ollama `/api/tags`, listing the models the proxy serves
+pub(crate) async fn ollama_tags(
+    State(state): State<Arc<ProxyState>>,
+    headers: HeaderMap,
+) -> Response {
+    let models_uri = Uri::from_static(MODELS_PATH);
+    let body = Bytes::new();
+    let response = match forward_inference_request(
+        &state,
+        Method::GET,
+        models_uri,
+        &headers,
+        body,
+        None,
+    )
+    .await
+    {
+        Ok((_, response)) => response,
+        Err(error) => return proxy_error_response(error),
+    };
+
+    let config = state.config();
+    let (parts, body) = response.into_parts();
+    if !parts.status.is_success() {
+        return backend_error_response(parts.status, body, config.request_timeout()).await;
+    }
+    let mut body = match collect_response_body(body, config.request_timeout()).await {
+        Ok(body) => body,
+        Err(error) => return proxy_error_response(error),
+    };
+    let tables = state.model_tables();
+    if models::model_list_needs_rewrite(config, &tables) {
+        body = models::rewrite_model_list(config, &tables, &body).unwrap_or(body);
+    }
+
+    let list: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let models: Vec<Value> = list["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(tag_entry)
+        .collect();
+    Json(json!({ "models": models })).into_response()
+}
+
+fn tag_entry(model: &Value) -> Option<Value> {
+    let id = model["id"].as_str()?;
+    let created = model["created"].as_u64().unwrap_or_default();
+
+    Some(json!({
+        "name": id,
+        "model": id,
+        "modified_at": rfc3339(UNIX_EPOCH + Duration::from_secs(created)),
+        "size": 0,
+        "digest": "",
+        "details": {
+            "format": "",
+            "family": "",
+            "parameter_size": "",
+            "quantization_level": ""
+        }
+    }))
+}
+
+/// Sends a translated chat completion request and answers in Ollama's format
+async fn complete(
+    state: &ProxyState,
+    headers: &HeaderMap,
+    reply: Reply,
+    stream: bool,
+    completion_request: Value,
+) -> Response {
+    let mut headers = headers.clone();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    let uri = Uri::from_static(CHAT_COMPLETIONS_PATH);
+    let body = Bytes::from(completion_request.to_string());
+    let response =
+        match forward_inference_request(state, Method::POST, uri, &headers, body, None).await {
+            Ok((_, response)) => response,
+            Err(error) => return proxy_error_response(error),
+        };
+
+    let config = state.config();
+    let (parts, body) = response.into_parts();
+    if !parts.status.is_success() {
+        return backend_error_response(parts.status, body, config.request_timeout()).await;
+    }
+    if stream && is_event_stream(&parts.headers) {
+        let events = stream_with_idle_timeout(body, config.stream_idle_timeout());
+        return (
+            [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+            Body::from_stream(ndjson_stream(events, reply)),
+        )
+            .into_response();
+    }
+
+    let body = match collect_response_body(body, config.request_timeout()).await {
+        Ok(body) => body,
+        Err(error) => return proxy_error_response(error),
+    };
+    let completion: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let choice = &completion["choices"][0];
+    let mut line = reply.final_line(
+        choice["message"]["content"].as_str().unwrap_or_default(),
+        choice["finish_reason"].as_str(),
+        completion.get("usage"),
+    );
+    if let Some(thinking) = reasoning(&choice["message"]) {
+        line = reply.with_thinking(line, thinking);
+    }
+
+    if stream {
+        // The backend answered in one piece; send it as a single NDJSON line
+        let mut lines = Vec::new();
+        write_line(&mut lines, &line);
+        return ([(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)], lines).into_response();
+    }
+    Json(line).into_response()
+}
+
+/// Turns chat completion SSE chunks into Ollama NDJSON lines, ending with a
+/// `done` line that carries the finish reason and token counts, or with an
+/// `error` line when the stream reports an error
+fn ndjson_stream(mut events: ByteStream, reply: Reply) -> ByteStream {
+    Box::pin(async_stream::stream! {
+        let mut parser = SseParser::default();
+        let mut finish_reason = None;
+        let mut usage = None;
+        let mut failed = false;
+
+        while let Some(chunk) = events.next().await {
+            let bytes = match chunk {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    failed = true;
+                    yield Err(error);
+                    break;
+                }
+            };
+
+            let mut lines = Vec::new();
+            for data in parser.push(&bytes) {
+                let Ok(chunk) = serde_json::from_str::<Value>(&data) else {
+                    continue;
+                };
+                if let Some(message) = chunk["error"]["message"].as_str() {
+                    write_line(&mut lines, &json!({ "error": message }));
+                    failed = true;
+                    break;
+                }
+                if chunk["usage"].is_object() {
+                    usage = Some(chunk["usage"].clone());
+                }
+                let choice = &chunk["choices"][0];
+                if let Some(reason) = choice["finish_reason"].as_str() {
+                    finish_reason = Some(reason.to_string());
+                }
+                let content = choice["delta"]["content"].as_str().unwrap_or_default();
+                let thinking = reasoning(&choice["delta"]);
+                if !content.is_empty() || thinking.is_some() {
+                    let mut line = reply.line(content, false);
+                    if let Some(thinking) = thinking {
+                        line = reply.with_thinking(line, thinking);
+                    }
+                    write_line(&mut lines, &line);
+                }
+            }
+            if !lines.is_empty() {
+                yield Ok(Bytes::from(lines));
+            }
+            if failed {
+                break;
+            }
+        }
+
+        if !failed {
+            let mut lines = Vec::new();
+            write_line(
+                &mut lines,
+                &reply.final_line("", finish_reason.as_deref(), usage.as_ref()),
+            );
+            yield Ok(Bytes::from(lines));
+        }
+    })
+}
+
+/// The reasoning text of a message or delta, which backends send as
+/// `reasoning_content` or `reasoning`
+fn reasoning(message: &Value) -> Option<&str> {
+    message["reasoning_content"]
+        .as_str()
+        .or_else(|| message["reasoning"].as_str())
+        .filter(|reasoning| !reasoning.is_empty())
+}
+
+fn write_line(buffer: &mut Vec<u8>, line: &Value) {
+    buffer.extend_from_slice(line.to_string().as_bytes());
+    buffer.push(b'\n');
+}
+
+fn chat_completion_request(
+    model: &str,
+    messages: Vec<Value>,
+    stream: bool,
+    format: Option<&Value>,
+    options: &Options,
+    extra: &Map<String, Value>,
+) -> Value {
+    let mut request = json!({
+        "model": model_name(model),
+        "messages": messages,
+        "stream": stream,
+    });
+    if stream {
+        request["stream_options"] = json!({"include_usage": true});
+    }
+
+    let parameters = [
+        ("temperature", options.temperature.map(Value::from)),
+        ("top_p", options.top_p.map(Value::from)),
+        ("top_k", options.top_k.map(Value::from)),
+        // Negative values mean "no limit" to Ollama
+        (
+            "max_tokens",
+            options
+                .num_predict
+                .filter(|tokens| *tokens >= 0)
+                .map(Value::from),
+        ),
+        ("stop", options.stop.clone().map(Value::from)),
+        ("seed", options.seed.map(Value::from)),
+        (
+            "presence_penalty",
+            options.presence_penalty.map(Value::from),
+        ),
+        (
+            "frequency_penalty",
+            options.frequency_penalty.map(Value::from),
+        ),
+    ];
+    for (name, value) in parameters {
+        if let Some(value) = value {
+            request[name] = value;
+        }
+    }
+
+    match format {
+        Some(Value::String(format)) if format == "json" => {
+            request["response_format"] = json!({"type": "json_object"});
+        }
+        Some(schema @ Value::Object(_)) => {
+            request["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {"name": "response", "schema": schema}
+            });
+        }
+        _ => {}
+    }
+
+    // Unknown fields pass through, but never replace the translated ones
+    let extensions = extra
+        .iter()
+        .filter(|(name, _)| !OLLAMA_ONLY_FIELDS.contains(&name.as_str()))
+        .chain(
+            options
+                .extra
+                .iter()
+                .filter(|(name, _)| !OLLAMA_ONLY_OPTIONS.contains(&name.as_str())),
+        );
+    if let Some(fields) = request.as_object_mut() {
+        for (name, value) in extensions {
+            fields.entry(name.as_str()).or_insert_with(|| value.clone());
+        }
+    }
+
+    request
+}
+
+/// Ollama clients often add the default `:latest` tag to model names
+fn model_name(model: &str) -> &str {
+    model.strip_suffix(":latest").unwrap_or(model)
+}
+
+fn openai_message(role: &str, content: String, images: &[String]) -> Value {
+    if images.is_empty() {
+        return json!({"role": role, "content": content});
+    }
+
+    let mut parts = vec![json!({"type": "text", "text": content})];
+    parts.extend(
+        images
+            .iter()
+            .map(|image| json!({"type": "image_url", "image_url": {"url": image_data_url(image)}})),
+    );
+    json!({"role": role, "content": parts})
+}
+
+/// Ollama sends bare base64 images; OpenAI expects data URLs, so the media
+/// type is sniffed from the encoded magic bytes
+fn image_data_url(image: &str) -> String {
+    let media_type = if image.starts_with("/9j/") {
+        "image/jpeg"
+    } else if image.starts_with("R0lGOD") {
+        "image/gif"
+    } else if image.starts_with("UklGR") {
+        "image/webp"
+    } else {
+        "image/png"
+    };
+    format!("data:{};base64,{}", media_type, image)
+}
+
+fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
+
+fn proxy_error_response((status, Json(error)): ProxyError) -> Response {
+    error_response(status, error.message())
+}
+
+/// Relays a backend error status with its message in Ollama's error shape
+async fn backend_error_response(
+    status: StatusCode,
+    body: OpenSecretResponseBody,
+    timeout: Duration,
+) -> Response {
+    let body = match collect_response_body(body, timeout).await {
+        Ok(body) => body,
+        Err(error) => return proxy_error_response(error),
+    };
+    let message = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|error| error["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+
+    error_response(status, message)
+}
+
+/// Formats a timestamp as RFC 3339 in UTC, as Ollama does for `created_at`
+fn rfc3339(timestamp: SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let seconds_of_day = seconds % 86_400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3_600,
+        seconds_of_day % 3_600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_nanos()
+    )
+}
+
+/// Converts days since the Unix epoch into a proleptic Gregorian date, using
+/// Howard Hinnant's `civil_from_days` algorithm
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_request_maps_options_format_and_images() {
+        let request: ChatRequest = serde_json::from_value(json!({
+            "model": "llama3-3-70b:latest",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "What is this?", "images": ["iVBORw0KGgo="]}
+            ],
+            "format": "json",
+            "options": {"temperature": 0.2, "num_predict": 64, "stop": ["\n"], "mirostat": 1}
+        }))
+        .unwrap();
+        assert!(request.stream);
+
+        let messages = request
+            .messages
+            .into_iter()
+            .map(|message| openai_message(&message.role, message.content, &message.images))
+            .collect();
+        let completion_request = chat_completion_request(
+            &request.model,
+            messages,
+            request.stream,
+            request.format.as_ref(),
+            &request.options,
+            &request.extra,
+        );
+
+        assert_eq!(
+            completion_request,
+            json!({
+                "model": "llama3-3-70b",
+                "messages": [
+                    {"role": "system", "content": "Be brief."},
+                    {"role": "user", "content": [
+                        {"type": "text", "text": "What is this?"},
+                        {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}}
+                    ]}
+                ],
+                "stream": true,
+                "stream_options": {"include_usage": true},
+                "temperature": 0.2,
+                "max_tokens": 64,
+                "stop": ["\n"],
+                "response_format": {"type": "json_object"}
+            })
+        );
+    }
+
+    #[test]
+    fn vendor_extensions_are_forwarded() {
+        let request: GenerateRequest = serde_json::from_value(json!({
+            "model": "gpt-oss-120b",
+            "prompt": "Hi",
+            "stream": false,
+            "keep_alive": "5m",
+            "reasoning_effort": "high",
+            "extra_body": {"guided_choice": ["yes", "no"]},
+            "options": {"temperature": 0.5, "min_p": 0.05, "num_ctx": 8192}
+        }))
+        .unwrap();
+
+        let completion_request = chat_completion_request(
+            &request.model,
+            Vec::new(),
+            request.stream,
+            request.format.as_ref(),
+            &request.options,
+            &request.extra,
+        );
+
+        assert_eq!(
+            completion_request,
+            json!({
+                "model": "gpt-oss-120b",
+                "messages": [],
+                "stream": false,
+                "temperature": 0.5,
+                "reasoning_effort": "high",
+                "extra_body": {"guided_choice": ["yes", "no"]},
+                "min_p": 0.05
+            })
+        );
+
+        // Extensions cannot override what the proxy translated
+        let extra = json!({"model": "other", "stream": true});
+        let request = chat_completion_request(
+            "qwen",
+            Vec::new(),
+            false,
+            None,
+            &Options::default(),
+            extra.as_object().unwrap(),
+        );
+        assert_eq!(request["model"], "qwen");
+        assert_eq!(request["stream"], false);
+    }
+
+    #[test]
+    fn unlimited_num_predict_and_schema_formats() {
+        let options = Options {
+            num_predict: Some(-1),
+            ..Options::default()
+        };
+        let schema = json!({"type": "object"});
+
+        let request = chat_completion_request(
+            "qwen",
+            Vec::new(),
+            false,
+            Some(&schema),
+            &options,
+            &Map::new(),
+        );
+
+        assert!(request.get("max_tokens").is_none());
+        assert!(request.get("stream_options").is_none());
+        assert_eq!(request["response_format"]["type"], "json_schema");
+        assert_eq!(request["response_format"]["json_schema"]["schema"], schema);
+    }
+
+    #[test]
+    fn final_lines_carry_reason_and_token_counts() {
+        let chat = Reply::new(Endpoint::Chat, "llama3-3-70b".to_string());
+        let generate = Reply::new(Endpoint::Generate, "llama3-3-70b".to_string());
+        let usage = json!({"prompt_tokens": 12, "completion_tokens": 3});
+
+        let chat_line = chat.final_line("Hi", Some("length"), Some(&usage));
+        let generate_line = generate.final_line("Hi", None, None);
+
+        assert_eq!(
+            chat_line["message"],
+            json!({"role": "assistant", "content": "Hi"})
+        );
+        assert_eq!(chat_line["done"], true);
+        assert_eq!(chat_line["done_reason"], "length");
+        assert_eq!(chat_line["prompt_eval_count"], 12);
+        assert_eq!(chat_line["eval_count"], 3);
+        assert_eq!(generate_line["response"], "Hi");
+        assert_eq!(generate_line["done_reason"], "stop");
+        assert!(generate_line.get("eval_count").is_none());
+    }
+
+    #[tokio::test]
+    async fn sse_chunks_become_ndjson_lines() {
+        let events: ByteStream = Box::pin(futures::stream::iter([
+            Ok(Bytes::from_static(
+                b"data: {\"choices\":[{\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\n\n\
+                  data: {\"choices\":[{\"delta\":{\"reasoning_content\":\"Hmm.\"}}]}\n\n",
+            )),
+            Ok(Bytes::from_static(
+                b"data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"lo\"},\"finish_reason\":\"stop\"}]}\n\n",
+            )),
+            Ok(Bytes::from_static(
+                b"data: {\"choices\":[],\"usage\":{\"prompt_tokens\":5,\"completion_tokens\":2}}\n\ndata: [DONE]\n\n",
+            )),
+        ]));
+
+        let body: Vec<Bytes> = ndjson_stream(
+            events,
+            Reply::new(Endpoint::Generate, "llama3-3-70b".to_string()),
+        )
+        .map(Result::unwrap)
+        .collect()
+        .await;
+        let lines: Vec<Value> = body
+            .concat()
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["thinking"], "Hmm.");
+        assert_eq!(lines[0]["response"], "");
+        assert_eq!(lines[1]["response"], "Hel");
+        assert_eq!(lines[1]["done"], false);
+        assert_eq!(lines[2]["response"], "lo");
+        assert_eq!(lines[3]["done"], true);
+        assert_eq!(lines[3]["done_reason"], "stop");
+        assert_eq!(lines[3]["eval_count"], 2);
+    }
+
+    #[tokio::test]
+    async fn stream_errors_end_with_an_error_line() {
+        let events: ByteStream = Box::pin(futures::stream::iter([Ok(Bytes::from_static(
+            b"data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n\
+              data: {\"error\":{\"message\":\"Out of memory\"}}\n\n",
+        ))]));
+
+        let body: Vec<Bytes> = ndjson_stream(
+            events,
+            Reply::new(Endpoint::Chat, "llama3-3-70b".to_string()),
+        )
+        .map(Result::unwrap)
+        .collect()
+        .await;
+        let body = String::from_utf8(body.concat()).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], r#"{"error":"Out of memory"}"#);
+    }
+
+    #[test]
+    fn tag_entries_and_timestamps() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            "2023-11-14T22:13:20.000000000Z"
+        );
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00.000000000Z"
+        );
+
+        let entry = tag_entry(&json!({"id": "llama3-3-70b", "created": 1_700_000_000})).unwrap();
+        assert_eq!(entry["name"], "llama3-3-70b");
+        assert_eq!(entry["modified_at"], "2023-11-14T22:13:20.000000000Z");
+        assert!(tag_entry(&json!({"object": "model"})).is_none());
+    }
+
+    #[test]
+    fn image_media_types_are_sniffed() {
+        assert_eq!(
+            image_data_url("/9j/4AAQ"),
+            "data:image/jpeg;base64,/9j/4AAQ"
+        );
+        assert_eq!(image_data_url("iVBORw0K"), "data:image/png;base64,iVBORw0K");
+    }
+}
@@ -0,0 +1,198 @@
+use crate::Config;
+use axum::{extract::Request, http::Uri, Router};
+use std::{fmt, str::FromStr, sync::Arc};
+use tower::ServiceExt;
+use tracing::debug;
+
+/// The first path segments of OpenAI endpoints, which `--normalize-paths`
+/// serves without their `/v1` prefix
+const UNVERSIONED_SEGMENTS: &[&str] = &[
+    "models",
+    "chat",
+    "embeddings",
+    "tokenize",
+    "audio",
+    "images",
+    "moderations",
+    "rerank",
+    "files",
+    "batches",
+    "responses",
+];
+
+/// A request path served as another, as FROM=TO
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathAlias {
+    /// The path clients request, such as `/chat/completions`
+    pub from: String,
+    /// The route it is served by, such as `/v1/chat/completions`
+    pub to: String,
+}
+
+impl PathAlias {
+    pub fn new(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+}
+
+impl FromStr for PathAlias {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (from, to) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected FROM=TO, got '{}'", value))?;
+        let (from, to) = (from.trim(), to.trim());
+        if !from.starts_with('/') || !to.starts_with('/') {
+            return Err(format!(
+                "expected FROM=TO with both paths starting with '/', got '{}'",
+                value
+            ));
+        }
+        if to.contains('?') {
+            return Err(format!("'{}' must not give TO a query string", value));
+        }
+        Ok(Self::new(from, to))
+    }
+}
+
+impl fmt::Display for PathAlias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.from, self.to)
+    }
+}
+
+/// Rewrites request paths before they are routed
+#[derive(Clone)]
+struct PathRewrites {
+    aliases: Arc<[PathAlias]>,
+    normalize: bool,
+}
+
+impl PathRewrites {
+    /// The path a request for `path` is served as, or `None` to serve it as
+    /// it is
+    fn rewrite(&self, path: &str) -> Option<String> {
+        let trimmed = if self.normalize {
+            path.trim_end_matches('/')
+        } else {
+            path
+        };
+        let trimmed = if trimmed.is_empty() { "/" } else { trimmed };
+        if let Some(alias) = self.aliases.iter().find(|alias| alias.from == trimmed) {
+            return Some(alias.to.clone());
+        }
+        if !self.normalize {
+            return None;
+        }
+        let segment = trimmed.split('/').nth(1).unwrap_or_default();
+        if UNVERSIONED_SEGMENTS.contains(&segment) {
+            return Some(format!("/v1{}", trimmed));
+        }
+        (trimmed != path).then(|| trimmed.to_string())
+    }
+
+    fn rewrite_request(&self, mut request: Request) -> Request {
+        let uri = request.uri().clone();
+        let Some(path) = self.rewrite(uri.path()) else {
+            return request;
+        };
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = uri.clone().into_parts();
+        let Ok(path_and_query) = path_and_query.parse() else {
+            return request;
+        };
+        parts.path_and_query = Some(path_and_query);
+        if let Ok(rewritten) = Uri::from_parts(parts) {
+            debug!("Serving {} as {}", uri.path(), rewritten.path());
+            *request.uri_mut() = rewritten;
+        }
+        request
+    }
+}
+
+/// `router`, with request paths rewritten by `--path-alias` and
+/// `--normalize-paths` before they are routed, so the rewritten requests run
+/// through the same routes, middleware, and pipeline stages
+pub(crate) fn with_path_rewrites<S>(config: &Config, router: Router) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    if config.path_aliases.is_empty() && !config.normalize_paths {
+        return router.with_state(());
+    }
+    let rewrites = PathRewrites {
+        aliases: config.path_aliases.clone().into(),
+        normalize: config.normalize_paths,
+    };
+    let router = ServiceExt::<Request>::map_request(router, move |request: Request| {
+        rewrites.rewrite_request(request)
+    });
+    Router::new().fallback_service(router)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{to_bytes, Body},
+        http::{self, StatusCode},
+    };
+
+    #[test]
+    fn paths_are_aliased_and_normalized() {
+        let rewrites = PathRewrites {
+            aliases: vec![PathAlias::new("/openai/v1/models", "/v1/models")].into(),
+            normalize: true,
+        };
+        assert_eq!(
+            rewrites.rewrite("/chat/completions/").as_deref(),
+            Some("/v1/chat/completions")
+        );
+        assert_eq!(rewrites.rewrite("/models").as_deref(), Some("/v1/models"));
+        assert_eq!(
+            rewrites.rewrite("/v1/embeddings/").as_deref(),
+            Some("/v1/embeddings")
+        );
+        assert_eq!(
+            rewrites.rewrite("/openai/v1/models/").as_deref(),
+            Some("/v1/models")
+        );
+        assert_eq!(rewrites.rewrite("/health"), None);
+        assert_eq!(rewrites.rewrite("/"), None);
+        assert_eq!(rewrites.rewrite("/api/tags"), None);
+
+        let exact = PathRewrites {
+            normalize: false,
+            ..rewrites
+        };
+        assert_eq!(exact.rewrite("/models"), None);
+        assert_eq!(exact.rewrite("/openai/v1/models/"), None);
+
+        assert!("chat=/v1/chat/completions".parse::<PathAlias>().is_err());
+        assert!("/chat=/v1/chat?x=1".parse::<PathAlias>().is_err());
+    }
+
+    #[tokio::test]
+    async fn rewritten_requests_reach_the_routes() {
+        let config = Config::default()
+            .with_mock_backend(true)
+            .with_normalize_paths(true)
+            .with_path_alias("/llm/list", "/v1/models");
+        let app = crate::create_app(config);
+
+        for path in ["/models", "/v1/models/", "/llm/list?limit=5"] {
+            let request = http::Request::get(path).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert!(String::from_utf8_lossy(&body).contains("llama3-3-70b"));
+        }
+    }
+}
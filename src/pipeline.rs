@@ -0,0 +1,204 @@
+use crate::{
+    admission::queue_requests,
+    hooks::run_hooks,
+    proxy::{enforce_rate_limit, record_client_metrics, ProxyState},
+    quarantine::enforce_quarantine,
+    schedule::enforce_schedules,
+};
+use axum::{middleware, routing::MethodRouter};
+use std::{fmt, str::FromStr, sync::Arc};
+
+/// The inference routes a pipeline can be configured for
+pub(crate) const INFERENCE_ROUTES: &[&str] = &[
+    "/v1/models",
+    "/v1/chat/completions",
+    "/v1/embeddings",
+    "/v1/tokenize",
+    "/v1/audio/transcriptions",
+    "/v1/audio/speech",
+    "/v1/images/generations",
+    "/v1/moderations",
+    "/v1/rerank",
+    "/api/chat",
+    "/api/generate",
+    "/api/tags",
+    "/openai/deployments/{deployment}/chat/completions",
+    "/openai/deployments/{deployment}/embeddings",
+];
+
+/// A gateway stage an inference request passes through before its handler
+/// authenticates, validates, and forwards it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Counts the request under its client SDK
+    Metrics,
+    /// Applies `--rate-limit-per-minute` and the per-country limits
+    RateLimit,
+    /// Watches the presented key for abuse and throttles quarantined keys
+    Quarantine,
+    /// Refuses keys outside their schedules and models in a blackout
+    Schedule,
+    /// Runs the registered `ProxyHook`s
+    Hooks,
+    /// Waits for one of `--max-concurrent-requests` slots
+    Queue,
+}
+
+impl Stage {
+    /// The stages every route runs unless `--pipeline` says otherwise
+    pub const DEFAULT: [Stage; 6] = [
+        Stage::Metrics,
+        Stage::RateLimit,
+        Stage::Quarantine,
+        Stage::Schedule,
+        Stage::Hooks,
+        Stage::Queue,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Metrics => "metrics",
+            Stage::RateLimit => "rate_limit",
+            Stage::Quarantine => "quarantine",
+            Stage::Schedule => "schedule",
+            Stage::Hooks => "hooks",
+            Stage::Queue => "queue",
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        Stage::DEFAULT
+            .into_iter()
+            .find(|stage| stage.name() == value)
+            .ok_or_else(|| {
+                format!(
+                    "unknown stage '{}', expected metrics, rate_limit, quarantine, schedule, \
+                     hooks, or queue",
+                    value
+                )
+            })
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The stages a route runs, in order, as ROUTE=STAGE>STAGE, or ROUTE=none to
+/// run none
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutePipeline {
+    /// The route as it is served, such as `/v1/chat/completions`
+    pub route: String,
+    pub stages: Vec<Stage>,
+}
+
+impl FromStr for RoutePipeline {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (route, stages) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected ROUTE=STAGE>STAGE, got '{}'", value))?;
+        let route = route.trim();
+        if route.is_empty() {
+            return Err(format!("missing the route in '{}'", value));
+        }
+        let stages = match stages.trim() {
+            "none" => Vec::new(),
+            stages => stages
+                .split('>')
+                .map(str::parse)
+                .collect::<Result<Vec<Stage>, _>>()?,
+        };
+        for (index, stage) in stages.iter().enumerate() {
+            if stages[..index].contains(stage) {
+                return Err(format!("'{}' lists the {} stage twice", value, stage));
+            }
+        }
+        Ok(Self {
+            route: route.to_string(),
+            stages,
+        })
+    }
+}
+
+impl fmt::Display for RoutePipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.stages.is_empty() {
+            return write!(f, "{}=none", self.route);
+        }
+        let stages: Vec<&str> = self.stages.iter().map(|stage| stage.name()).collect();
+        write!(f, "{}={}", self.route, stages.join(">"))
+    }
+}
+
+/// Wraps a route's handler in the stages its pipeline names, the first
+/// stage outermost
+pub(crate) fn staged(
+    state: &Arc<ProxyState>,
+    route: &str,
+    mut handler: MethodRouter<Arc<ProxyState>>,
+) -> MethodRouter<Arc<ProxyState>> {
+    let stages = state
+        .config()
+        .pipelines
+        .iter()
+        .find(|pipeline| pipeline.route == route)
+        .map_or(&Stage::DEFAULT[..], |pipeline| &pipeline.stages);
+    // Each layer wraps the ones before it, so the last stage is layered first
+    for stage in stages.iter().rev() {
+        let state = Arc::clone(state);
+        handler = match stage {
+            Stage::Metrics => {
+                handler.layer(middleware::from_fn_with_state(state, record_client_metrics))
+            }
+            Stage::RateLimit => {
+                handler.layer(middleware::from_fn_with_state(state, enforce_rate_limit))
+            }
+            Stage::Quarantine => {
+                handler.layer(middleware::from_fn_with_state(state, enforce_quarantine))
+            }
+            Stage::Schedule => {
+                handler.layer(middleware::from_fn_with_state(state, enforce_schedules))
+            }
+            Stage::Hooks => handler.layer(middleware::from_fn_with_state(state, run_hooks)),
+            Stage::Queue => handler.layer(middleware::from_fn_with_state(state, queue_requests)),
+        };
+    }
+    handler
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_route_pipelines() {
+        let pipeline: RoutePipeline = "/v1/embeddings = hooks>rate_limit".parse().unwrap();
+        assert_eq!(pipeline.route, "/v1/embeddings");
+        assert_eq!(pipeline.stages, vec![Stage::Hooks, Stage::RateLimit]);
+        assert_eq!(pipeline.to_string(), "/v1/embeddings=hooks>rate_limit");
+
+        let bare: RoutePipeline = "/v1/tokenize=none".parse().unwrap();
+        assert!(bare.stages.is_empty());
+        assert_eq!(bare.to_string(), "/v1/tokenize=none");
+
+        for invalid in [
+            "/v1/tokenize",
+            "=metrics",
+            "/v1/tokenize=",
+            "/v1/tokenize=metrics>cache",
+            "/v1/tokenize=metrics>metrics",
+        ] {
+            assert!(invalid.parse::<RoutePipeline>().is_err(), "{}", invalid);
+        }
+    }
+}
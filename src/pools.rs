@@ -0,0 +1,175 @@
+use std::{fmt, str::FromStr, sync::Mutex};
+
+/// A model name that spreads requests over several Maple models by weight,
+/// e.g. `fast=llama3-3-70b:70,gemma4-31b:30`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelPool {
+    pub name: String,
+    pub members: Vec<PoolMember>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMember {
+    pub model: String,
+    pub weight: u32,
+}
+
+impl FromStr for ModelPool {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected NAME=MODEL:WEIGHT,MODEL:WEIGHT, got '{}'", value);
+        let (name, members) = value.split_once('=').ok_or_else(expected)?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(expected());
+        }
+
+        // Model names may contain ':' themselves, so the weight follows the last one
+        let members = members
+            .split(',')
+            .map(|member| {
+                let (model, weight) = member.trim().rsplit_once(':').ok_or_else(expected)?;
+                let weight = weight
+                    .trim()
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|weight| *weight > 0)
+                    .ok_or_else(|| {
+                        format!("pool weights must be positive integers in '{}'", value)
+                    })?;
+                let model = model.trim();
+                if model.is_empty() {
+                    return Err(expected());
+                }
+                Ok(PoolMember {
+                    model: model.to_string(),
+                    weight,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            name: name.to_string(),
+            members,
+        })
+    }
+}
+
+impl fmt::Display for ModelPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let members: Vec<String> = self
+            .members
+            .iter()
+            .map(|member| format!("{}:{}", member.model, member.weight))
+            .collect();
+        write!(f, "{}={}", self.name, members.join(","))
+    }
+}
+
+/// Picks pool members with smooth weighted round-robin, which follows the
+/// weights exactly over every cycle without bursts of the same model
+pub(crate) struct PoolScheduler {
+    pools: Vec<(ModelPool, Mutex<Vec<i64>>)>,
+}
+
+impl PoolScheduler {
+    pub(crate) fn new(pools: &[ModelPool]) -> Self {
+        Self {
+            pools: pools
+                .iter()
+                .map(|pool| (pool.clone(), Mutex::new(vec![0; pool.members.len()])))
+                .collect(),
+        }
+    }
+
+    /// The models to try for a pool, in order: the next weighted pick, then
+    /// the other members by weight to spill over to when the pick is
+    /// saturated. `None` if `name` is not a pool.
+    pub(crate) fn candidates(&self, name: &str) -> Option<Vec<&str>> {
+        let (pool, current) = self.pools.iter().find(|(pool, _)| pool.name == name)?;
+        let total: i64 = pool
+            .members
+            .iter()
+            .map(|member| i64::from(member.weight))
+            .sum();
+
+        let picked = {
+            let mut current = current
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            for (weight, member) in current.iter_mut().zip(&pool.members) {
+                *weight += i64::from(member.weight);
+            }
+            let picked = (0..current.len())
+                .max_by_key(|&index| (current[index], std::cmp::Reverse(index)))
+                .expect("pools have members");
+            current[picked] -= total;
+            picked
+        };
+
+        let mut spillover: Vec<&PoolMember> = pool
+            .members
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != picked)
+            .map(|(_, member)| member)
+            .collect();
+        spillover.sort_by_key(|member| std::cmp::Reverse(member.weight));
+
+        let mut candidates = vec![pool.members[picked].model.as_str()];
+        candidates.extend(spillover.iter().map(|member| member.model.as_str()));
+        Some(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pools() {
+        let pool: ModelPool = "fast = llama3-3-70b:70, llama3.2:3b:30".parse().unwrap();
+        assert_eq!(pool.name, "fast");
+        assert_eq!(
+            pool.members,
+            vec![
+                PoolMember {
+                    model: "llama3-3-70b".to_string(),
+                    weight: 70,
+                },
+                PoolMember {
+                    model: "llama3.2:3b".to_string(),
+                    weight: 30,
+                },
+            ]
+        );
+        assert_eq!(pool.to_string(), "fast=llama3-3-70b:70,llama3.2:3b:30");
+
+        for invalid in [
+            "fast",
+            "=llama3-3-70b:1",
+            "fast=llama3-3-70b",
+            "fast=llama3-3-70b:0",
+            "fast=:5",
+            "fast=llama3-3-70b:1,",
+        ] {
+            assert!(invalid.parse::<ModelPool>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn picks_follow_the_weights_and_spill_to_the_rest() {
+        let pool: ModelPool = "fast=a:3,b:1,c:2".parse().unwrap();
+        let scheduler = PoolScheduler::new(&[pool]);
+
+        let picks: Vec<&str> = (0..6)
+            .map(|_| scheduler.candidates("fast").unwrap()[0])
+            .collect();
+        assert_eq!(picks, ["a", "c", "a", "b", "c", "a"]);
+
+        let candidates = scheduler.candidates("fast").unwrap();
+        assert_eq!(candidates, ["a", "c", "b"]);
+        assert!(scheduler.candidates("a").is_none());
+    }
+}
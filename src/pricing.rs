@@ -0,0 +1,194 @@
+use crate::tokenizer;
+use serde_json::Value;
+use std::{fmt, str::FromStr};
+
+const TOKENS_PER_PRICE_UNIT: f64 = 1_000_000.0;
+
+/// What a model costs, in USD per million prompt and completion tokens, as
+/// MODEL=INPUT/OUTPUT
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelPrice {
+    pub model: String,
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl FromStr for ModelPrice {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected MODEL=INPUT/OUTPUT, got '{}'", value);
+        let (model, prices) = value.split_once('=').ok_or_else(expected)?;
+        let (input, output) = prices.split_once('/').ok_or_else(expected)?;
+        let price = |price: &str| {
+            price
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|price| price.is_finite() && *price >= 0.0)
+                .ok_or_else(|| format!("prices must be non-negative numbers in '{}'", value))
+        };
+
+        let model = model.trim();
+        if model.is_empty() {
+            return Err(expected());
+        }
+        Ok(Self {
+            model: model.to_string(),
+            input_per_million: price(input)?,
+            output_per_million: price(output)?,
+        })
+    }
+}
+
+impl fmt::Display for ModelPrice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={}/{}",
+            self.model, self.input_per_million, self.output_per_million
+        )
+    }
+}
+
+/// Why a request's cost could not be bounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CostError {
+    /// Chat completions without a token limit have no worst case
+    MissingMaxTokens,
+}
+
+/// The most a chat completion or embeddings request can cost in USD: its
+/// counted prompt tokens plus its full `max_tokens`. `None` for unpriced
+/// models and bodies that are not OpenAI requests.
+pub(crate) fn worst_case_cost(
+    prices: &[ModelPrice],
+    body: &[u8],
+) -> Result<Option<f64>, CostError> {
+    let Ok(request) = serde_json::from_slice::<Value>(body) else {
+        return Ok(None);
+    };
+    let Some(price) = request["model"]
+        .as_str()
+        .and_then(|model| prices.iter().find(|price| price.model == model))
+    else {
+        return Ok(None);
+    };
+    let Some(prompt_tokens) = tokenizer::count_prompt_tokens(&request) else {
+        return Ok(None);
+    };
+
+    let completion_tokens = if request.get("messages").is_some() {
+        request["max_completion_tokens"]
+            .as_u64()
+            .or_else(|| request["max_tokens"].as_u64())
+            .ok_or(CostError::MissingMaxTokens)?
+    } else {
+        0
+    };
+
+    Ok(Some(
+        (prompt_tokens as f64 * price.input_per_million
+            + completion_tokens as f64 * price.output_per_million)
+            / TOKENS_PER_PRICE_UNIT,
+    ))
+}
+
+/// The priced model a completion or embeddings response, or the stream chunk
+/// carrying its usage, was for, and what its `usage` cost in USD. `None` for
+/// unpriced models and responses without usage.
+pub(crate) fn usage_cost<'a>(prices: &'a [ModelPrice], response: &Value) -> Option<(&'a str, f64)> {
+    let usage = response.get("usage").filter(|usage| usage.is_object())?;
+    let price = response["model"]
+        .as_str()
+        .and_then(|model| prices.iter().find(|price| price.model == model))?;
+    let tokens = |field: &str| usage[field].as_u64().unwrap_or(0) as f64;
+    let cost = (tokens("prompt_tokens") * price.input_per_million
+        + tokens("completion_tokens") * price.output_per_million)
+        / TOKENS_PER_PRICE_UNIT;
+    Some((&price.model, cost))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prices() -> Vec<ModelPrice> {
+        vec!["llama3-3-70b=2/10".parse().unwrap()]
+    }
+
+    #[test]
+    fn parses_prices() {
+        let price: ModelPrice = "llama3-3-70b = 0.5/1.5".parse().unwrap();
+        assert_eq!(price.model, "llama3-3-70b");
+        assert_eq!(price.input_per_million, 0.5);
+        assert_eq!(price.output_per_million, 1.5);
+        assert_eq!(price.to_string(), "llama3-3-70b=0.5/1.5");
+
+        for invalid in [
+            "llama3-3-70b",
+            "llama3-3-70b=1",
+            "=1/2",
+            "m=-1/2",
+            "m=1/NaN",
+        ] {
+            assert!(invalid.parse::<ModelPrice>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn worst_case_covers_prompt_and_max_tokens() {
+        let prompt = r#"[{"role":"user","content":"Hello there"}]"#;
+        let prompt_tokens = tokenizer::count_message_tokens(
+            serde_json::from_str::<Value>(prompt)
+                .unwrap()
+                .as_array()
+                .unwrap(),
+        );
+        let body = format!(
+            r#"{{"model":"llama3-3-70b","messages":{},"max_tokens":1000}}"#,
+            prompt
+        );
+
+        let cost = worst_case_cost(&prices(), body.as_bytes())
+            .unwrap()
+            .unwrap();
+        let expected = (prompt_tokens as f64 * 2.0 + 1000.0 * 10.0) / 1_000_000.0;
+        assert!((cost - expected).abs() < 1e-12);
+
+        let body = format!(r#"{{"model":"llama3-3-70b","messages":{}}}"#, prompt);
+        assert_eq!(
+            worst_case_cost(&prices(), body.as_bytes()),
+            Err(CostError::MissingMaxTokens)
+        );
+    }
+
+    #[test]
+    fn embeddings_cost_their_input_and_unpriced_models_are_unbounded() {
+        let body = br#"{"model":"llama3-3-70b","input":["a b c"]}"#;
+        let cost = worst_case_cost(&prices(), body).unwrap().unwrap();
+        assert!(cost > 0.0 && cost < 1e-4);
+
+        let unpriced = br#"{"model":"gemma4-31b","messages":[]}"#;
+        assert_eq!(worst_case_cost(&prices(), unpriced), Ok(None));
+        assert_eq!(worst_case_cost(&prices(), b"not json"), Ok(None));
+    }
+
+    #[test]
+    fn usage_is_priced_by_the_response_model() {
+        let response = serde_json::json!({
+            "model": "llama3-3-70b",
+            "usage": {"prompt_tokens": 1000, "completion_tokens": 100},
+        });
+        let prices = prices();
+        let (model, cost) = usage_cost(&prices, &response).unwrap();
+        assert_eq!(model, "llama3-3-70b");
+        assert!((cost - 0.003).abs() < 1e-12);
+
+        let unpriced = serde_json::json!({"model": "gemma4-31b", "usage": response["usage"]});
+        assert_eq!(usage_cost(&prices, &unpriced), None);
+        // Stream chunks before the last carry no usage
+        let chunk = serde_json::json!({"model": "llama3-3-70b", "usage": null});
+        assert_eq!(usage_cost(&prices, &chunk), None);
+    }
+}
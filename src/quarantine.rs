@@ -0,0 +1,402 @@
+use crate::{
+    config::{Config, OpenAIError},
+    ids::unix_now,
+    proxy::ProxyState,
+    rate_limit::RateLimiter,
+};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{error, warn};
+
+/// Abuse signals are counted over windows of this length
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Keys are presented by clients, so past this many counted keys, windows
+/// that have ended are dropped before a new key is counted
+const MAX_WATCHED_KEYS: usize = 16 * 1024;
+
+/// Past this many quarantined keys, more are only logged
+const MAX_QUARANTINED_KEYS: usize = 4096;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a key was quarantined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum QuarantineReason {
+    RequestRate,
+    AuthFailures,
+    BlockedRequests,
+}
+
+impl QuarantineReason {
+    fn describe(self) -> &'static str {
+        match self {
+            Self::RequestRate => "too many requests per minute",
+            Self::AuthFailures => "repeated authentication failures",
+            Self::BlockedRequests => "repeated blocked requests",
+        }
+    }
+}
+
+/// An API key a client presented: a virtual key's ID, or a hash of any other
+/// key with its first characters as a hint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WatchedKey {
+    pub(crate) id: String,
+    pub(crate) hint: Option<String>,
+}
+
+/// What a key did in the current window
+struct Window {
+    started_at: Instant,
+    requests: u32,
+    auth_failures: u32,
+    blocked_requests: u32,
+}
+
+impl Window {
+    fn new(started_at: Instant) -> Self {
+        Self {
+            started_at,
+            requests: 0,
+            auth_failures: 0,
+            blocked_requests: 0,
+        }
+    }
+}
+
+struct Quarantine {
+    hint: Option<String>,
+    reason: QuarantineReason,
+    quarantined_at: u64,
+    throttled_requests: u64,
+}
+
+/// Watches the keys clients present for signs of abuse. Instead of being
+/// blocked, a key that trips a threshold is quarantined: its requests are
+/// throttled hard and an alert goes out until an admin releases it.
+pub(crate) struct KeyWatch {
+    max_requests: Option<u32>,
+    max_auth_failures: Option<u32>,
+    max_blocked_requests: Option<u32>,
+    windows: DashMap<String, Window>,
+    quarantined: DashMap<String, Quarantine>,
+    throttle: RateLimiter,
+    webhook: Option<(reqwest::Client, String)>,
+}
+
+impl KeyWatch {
+    /// `None` when no abuse threshold is configured
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        if !config.quarantine_enabled() {
+            return None;
+        }
+        let webhook = config.quarantine_webhook.as_ref().and_then(|url| {
+            reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .map_err(|build_error| {
+                    error!("Failed to set up the quarantine webhook: {}", build_error)
+                })
+                .ok()
+                .map(|client| (client, url.clone()))
+        });
+        Some(Self {
+            max_requests: config.quarantine_max_requests_per_minute,
+            max_auth_failures: config.quarantine_max_auth_failures,
+            max_blocked_requests: config.quarantine_max_blocked_requests,
+            windows: DashMap::new(),
+            quarantined: DashMap::new(),
+            throttle: RateLimiter::per_minute(config.quarantine_requests_per_minute),
+            webhook,
+        })
+    }
+
+    /// Counts a request, or returns how long a quarantined key must wait
+    pub(crate) fn admit(&self, key: &WatchedKey) -> Result<(), Duration> {
+        self.admit_at(key, Instant::now())
+    }
+
+    fn admit_at(&self, key: &WatchedKey, now: Instant) -> Result<(), Duration> {
+        if let Some(mut quarantine) = self.quarantined.get_mut(&key.id) {
+            return self.throttle.check(&key.id).inspect_err(|_| {
+                quarantine.throttled_requests += 1;
+            });
+        }
+
+        let requests = self.count(&key.id, now, |window| {
+            window.requests += 1;
+            window.requests
+        });
+        if self.max_requests.is_some_and(|max| requests > max) {
+            self.quarantine(key, QuarantineReason::RequestRate);
+        }
+        Ok(())
+    }
+
+    /// Counts a 401 response as an authentication failure and a 403 as a
+    /// blocked request
+    pub(crate) fn record_status(&self, key: &WatchedKey, status: StatusCode) {
+        self.record_status_at(key, status, Instant::now());
+    }
+
+    fn record_status_at(&self, key: &WatchedKey, status: StatusCode, now: Instant) {
+        if self.quarantined.contains_key(&key.id) {
+            return;
+        }
+        let tripped = match status {
+            StatusCode::UNAUTHORIZED => {
+                let failures = self.count(&key.id, now, |window| {
+                    window.auth_failures += 1;
+                    window.auth_failures
+                });
+                self.max_auth_failures
+                    .is_some_and(|max| failures >= max)
+                    .then_some(QuarantineReason::AuthFailures)
+            }
+            StatusCode::FORBIDDEN => {
+                let blocked = self.count(&key.id, now, |window| {
+                    window.blocked_requests += 1;
+                    window.blocked_requests
+                });
+                self.max_blocked_requests
+                    .is_some_and(|max| blocked >= max)
+                    .then_some(QuarantineReason::BlockedRequests)
+            }
+            _ => None,
+        };
+        if let Some(reason) = tripped {
+            self.quarantine(key, reason);
+        }
+    }
+
+    /// Updates the key's current window, starting a new one if it has ended
+    fn count(&self, id: &str, now: Instant, update: impl FnOnce(&mut Window) -> u32) -> u32 {
+        if self.windows.len() >= MAX_WATCHED_KEYS && !self.windows.contains_key(id) {
+            self.windows
+                .retain(|_, window| now.saturating_duration_since(window.started_at) < WINDOW);
+        }
+        let mut window = self
+            .windows
+            .entry(id.to_string())
+            .or_insert_with(|| Window::new(now));
+        if now.saturating_duration_since(window.started_at) >= WINDOW {
+            *window = Window::new(now);
+        }
+        update(&mut window)
+    }
+
+    fn quarantine(&self, key: &WatchedKey, reason: QuarantineReason) {
+        let key_label = key.hint.as_deref().unwrap_or(&key.id);
+        if self.quarantined.len() >= MAX_QUARANTINED_KEYS {
+            warn!(
+                "Key {} tripped quarantine ({}), but the quarantine is full",
+                key_label,
+                reason.describe()
+            );
+            return;
+        }
+        let quarantined_at = unix_now();
+        self.quarantined.insert(
+            key.id.clone(),
+            Quarantine {
+                hint: key.hint.clone(),
+                reason,
+                quarantined_at,
+                throttled_requests: 0,
+            },
+        );
+        self.windows.remove(&key.id);
+        let summary = format!("Quarantined key {}: {}", key_label, reason.describe());
+        warn!("{}", summary);
+
+        if let Some((client, url)) = &self.webhook {
+            let alert = json!({
+                "event": "key_quarantined",
+                "text": format!("maple-proxy: {}", summary),
+                "key": key.id,
+                "hint": key.hint,
+                "reason": reason,
+                "quarantined_at": quarantined_at,
+            });
+            let request = client.post(url).json(&alert);
+            tokio::spawn(async move {
+                let sent = request.send().await;
+                if let Err(send_error) = sent.and_then(|response| response.error_for_status()) {
+                    error!("Failed to send the quarantine alert: {}", send_error);
+                }
+            });
+        }
+    }
+
+    /// Quarantined keys, most recent first
+    pub(crate) fn list(&self) -> Vec<Value> {
+        let mut keys: Vec<_> = self
+            .quarantined
+            .iter()
+            .map(|entry| {
+                let quarantine = entry.value();
+                json!({
+                    "id": entry.key(),
+                    "hint": quarantine.hint,
+                    "reason": quarantine.reason,
+                    "quarantined_at": quarantine.quarantined_at,
+                    "throttled_requests": quarantine.throttled_requests,
+                })
+            })
+            .collect();
+        keys.sort_by(|a, b| {
+            b["quarantined_at"]
+                .as_u64()
+                .cmp(&a["quarantined_at"].as_u64())
+        });
+        keys
+    }
+
+    /// Lifts a key's quarantine and starts its count over
+    pub(crate) fn release(&self, id: &str) -> bool {
+        self.windows.remove(id);
+        self.quarantined.remove(id).is_some()
+    }
+}
+
+/// Throttles quarantined keys and watches the responses to every key for
+/// abuse signals
+pub(crate) async fn enforce_quarantine(
+    State(state): State<Arc<ProxyState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(key_watch) = state.key_watch() else {
+        return next.run(request).await;
+    };
+    let Some(key) = state.watched_key(request.headers()) else {
+        return next.run(request).await;
+    };
+    if let Err(retry_after) = key_watch.admit(&key) {
+        return quarantined_response(retry_after);
+    }
+
+    let response = next.run(request).await;
+    key_watch.record_status(&key, response.status());
+    response
+}
+
+fn quarantined_response(retry_after: Duration) -> Response {
+    let retry_after_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(
+            OpenAIError::rate_limit_error(format!(
+                "This API key is quarantined after unusual activity and its requests are \
+                 limited. Please retry after {} seconds.",
+                retry_after_secs
+            ))
+            .with_code("key_quarantined"),
+        ),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_watch(config: Config) -> KeyWatch {
+        KeyWatch::new(&config).unwrap()
+    }
+
+    fn key(id: &str) -> WatchedKey {
+        WatchedKey {
+            id: id.to_string(),
+            hint: None,
+        }
+    }
+
+    fn config() -> Config {
+        Config::new(
+            "127.0.0.1".to_string(),
+            0,
+            "http://localhost:3000".to_string(),
+        )
+    }
+
+    #[test]
+    fn request_rate_quarantines_and_throttles() {
+        let watch = key_watch(
+            config()
+                .with_quarantine(Some(3), None, None)
+                .with_quarantine_requests_per_minute(1),
+        );
+        let (busy, quiet) = (key("key_busy"), key("key_quiet"));
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            assert!(watch.admit_at(&busy, now).is_ok());
+        }
+        assert!(watch.admit_at(&quiet, now).is_ok());
+        let listed = watch.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["id"], "key_busy");
+        assert_eq!(listed[0]["reason"], "request_rate");
+
+        // One request a minute gets through, the rest wait
+        assert!(watch.admit(&busy).is_ok());
+        assert!(watch.admit(&busy).is_err());
+        assert_eq!(watch.list()[0]["throttled_requests"], 1);
+
+        assert!(watch.release("key_busy"));
+        assert!(!watch.release("key_busy"));
+        assert!(watch.admit_at(&busy, now).is_ok());
+        assert!(watch.list().is_empty());
+    }
+
+    #[test]
+    fn auth_failures_and_blocked_requests_count_per_window() {
+        let watch = key_watch(config().with_quarantine(None, Some(2), Some(3)));
+        let (guesser, blocked) = (key("guesser"), key("blocked"));
+        let now = Instant::now();
+
+        watch.record_status_at(&guesser, StatusCode::UNAUTHORIZED, now);
+        watch.record_status_at(&guesser, StatusCode::OK, now);
+        // A new window starts the count over
+        watch.record_status_at(&guesser, StatusCode::UNAUTHORIZED, now + WINDOW);
+        assert!(watch.list().is_empty());
+        watch.record_status_at(&guesser, StatusCode::UNAUTHORIZED, now + WINDOW);
+
+        for _ in 0..3 {
+            watch.record_status_at(&blocked, StatusCode::FORBIDDEN, now);
+        }
+        let reasons: Vec<_> = watch
+            .list()
+            .iter()
+            .map(|key| (key["id"].clone(), key["reason"].clone()))
+            .collect();
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons.contains(&(json!("guesser"), json!("auth_failures"))));
+        assert!(reasons.contains(&(json!("blocked"), json!("blocked_requests"))));
+    }
+
+    #[test]
+    fn no_thresholds_no_watch() {
+        assert!(KeyWatch::new(&config()).is_none());
+    }
+}
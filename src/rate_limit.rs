@@ -0,0 +1,95 @@
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+const RATE_LIMITER_MAX_CLIENTS: usize = 16 * 1024;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token bucket per client, refilled continuously so a client can burst up to
+/// the per-minute limit and then proceeds at the sustained rate.
+pub(crate) struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: DashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn per_minute(limit: u32) -> Self {
+        Self {
+            capacity: f64::from(limit),
+            refill_per_sec: f64::from(limit) / 60.0,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Takes a token for `client`, or returns how long until one is available
+    pub(crate) fn check(&self, client: &str) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        if self.buckets.len() >= RATE_LIMITER_MAX_CLIENTS && !self.buckets.contains_key(client) {
+            self.evict_refilled_buckets(now);
+        }
+
+        let mut bucket = self.buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+        bucket.tokens = self.refilled_tokens(&bucket, now);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.refill_per_sec,
+            ))
+        }
+    }
+
+    fn refilled_tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        (bucket.tokens + elapsed.as_secs_f64() * self.refill_per_sec).min(self.capacity)
+    }
+
+    /// Buckets that have refilled completely carry no state worth keeping
+    fn evict_refilled_buckets(&self, now: Instant) {
+        self.buckets
+            .retain(|_, bucket| self.refilled_tokens(bucket, now) < self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_burst_up_to_limit_then_reports_retry_delay() {
+        let limiter = RateLimiter::per_minute(2);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("10.0.0.1", now).is_ok());
+        assert!(limiter.check_at("10.0.0.1", now).is_ok());
+        let retry_after = limiter.check_at("10.0.0.1", now).unwrap_err();
+
+        assert_eq!(retry_after, Duration::from_secs(30));
+        assert!(limiter.check_at("10.0.0.2", now).is_ok());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::per_minute(1);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("client", now).is_ok());
+        assert!(limiter.check_at("client", now).is_err());
+        assert!(limiter
+            .check_at("client", now + Duration::from_secs(60))
+            .is_ok());
+    }
+}
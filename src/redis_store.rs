@@ -0,0 +1,272 @@
+use crate::{
+    cache::{CacheKey, CachedResponse},
+    ids::unix_now_ms,
+    keys::{VirtualKeys, USAGE_FIELDS},
+    tasks,
+};
+use anyhow::Context;
+use redis::{aio::ConnectionManager, FromRedisValue, Pipeline};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+/// Redis calls past this are given up on, so an unresponsive Redis slows
+/// requests by at most this much
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// After a failure, Redis is left alone this long and each replica goes it
+/// alone
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(5);
+
+/// How often virtual key usage is added to the shared ledger
+const USAGE_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rate limits count requests in windows of this length
+const RATE_WINDOW_MS: u64 = 60_000;
+
+/// Keys deleted per round trip when a cache is cleared
+const SCAN_BATCH: usize = 500;
+
+/// Rate limit counters, virtual key usage, and cached responses shared by
+/// every replica pointed at the same Redis. When Redis cannot be reached,
+/// callers fall back to what this replica knows on its own.
+pub(crate) struct RedisStore {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    prefix: String,
+    reachable: AtomicBool,
+    /// When Redis may be tried again after a failure
+    retry_at: Mutex<Option<Instant>>,
+}
+
+impl RedisStore {
+    /// Checks the URL; the connection is made on first use
+    pub(crate) fn new(url: &str, prefix: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url).context("Invalid --redis-url")?;
+        Ok(Self {
+            client,
+            connection: OnceCell::new(),
+            prefix: prefix.to_string(),
+            reachable: AtomicBool::new(true),
+            retry_at: Mutex::new(None),
+        })
+    }
+
+    /// Counts a request from `client` against a per-minute `limit` shared by
+    /// every replica, or returns how long until the next window. `None` when
+    /// Redis is unavailable.
+    pub(crate) async fn check_rate_limit(
+        &self,
+        client: &str,
+        limit: u32,
+    ) -> Option<Result<(), Duration>> {
+        let now_ms = unix_now_ms();
+        let key = format!("{}rate:{}:{}", self.prefix, client, now_ms / RATE_WINDOW_MS);
+        let mut pipeline = redis::pipe();
+        pipeline
+            .cmd("INCR")
+            .arg(&key)
+            .cmd("PEXPIRE")
+            .arg(&key)
+            .arg(2 * RATE_WINDOW_MS)
+            .ignore();
+        let (requests,): (u64,) = self.query(&pipeline).await?;
+        if requests <= u64::from(limit) {
+            return Some(Ok(()));
+        }
+        Some(Err(Duration::from_millis(
+            RATE_WINDOW_MS - now_ms % RATE_WINDOW_MS,
+        )))
+    }
+
+    /// Adds each key's new usage to the shared ledger and takes back the
+    /// totals, so quotas count every replica's requests
+    pub(crate) async fn sync_usage(&self, keys: &VirtualKeys) {
+        let usages = keys.usages();
+        if usages.is_empty() {
+            return;
+        }
+        let unsynced: Vec<[u64; 4]> = usages
+            .iter()
+            .map(|(_, usage)| usage.take_unsynced())
+            .collect();
+
+        let mut pipeline = redis::pipe();
+        for ((id, _), amounts) in usages.iter().zip(&unsynced) {
+            let key = format!("{}usage:{}", self.prefix, id);
+            for (field, amount) in USAGE_FIELDS.iter().zip(amounts) {
+                pipeline.cmd("HINCRBY").arg(&key).arg(*field).arg(*amount);
+            }
+        }
+        match self.query::<Vec<u64>>(&pipeline).await {
+            Some(totals) if totals.len() == usages.len() * USAGE_FIELDS.len() => {
+                let totals = totals.chunks_exact(USAGE_FIELDS.len());
+                for ((_, usage), totals) in usages.iter().zip(totals) {
+                    usage.set_totals(totals.try_into().expect("chunks have one total per field"));
+                }
+            }
+            _ => {
+                for ((_, usage), amounts) in usages.iter().zip(unsynced) {
+                    usage.restore_unsynced(amounts);
+                }
+            }
+        }
+    }
+
+    /// Starts syncing usage every second, until the keys are dropped
+    pub(crate) fn start_usage_sync(self: &Arc<Self>, keys: Weak<VirtualKeys>) {
+        let store = Arc::clone(self);
+        let sync = async move {
+            let mut interval = tokio::time::interval(USAGE_SYNC_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(keys) = keys.upgrade() else {
+                    return;
+                };
+                store.sync_usage(&keys).await;
+            }
+        };
+        tasks::spawn_or_warn(
+            sync,
+            "Sharing key usage needs a Tokio runtime and is disabled",
+        );
+    }
+
+    /// A response another replica, or this one, cached in the cache named
+    /// `cache`
+    pub(crate) async fn get_response(&self, cache: &str, key: &CacheKey) -> Option<CachedResponse> {
+        let mut pipeline = redis::pipe();
+        pipeline.cmd("GET").arg(self.response_key(cache, key));
+        let (encoded,): (Option<Vec<u8>>,) = self.query(&pipeline).await?;
+        CachedResponse::decode(&encoded?)
+    }
+
+    /// Shares a cached response with the other replicas in the background
+    pub(crate) fn put_response(
+        self: &Arc<Self>,
+        cache: &str,
+        key: &CacheKey,
+        response: &CachedResponse,
+        ttl: Duration,
+    ) {
+        let mut pipeline = redis::pipe();
+        pipeline
+            .cmd("SET")
+            .arg(self.response_key(cache, key))
+            .arg(response.encode())
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64)
+            .ignore();
+        self.spawn(|store| async move {
+            store.query::<()>(&pipeline).await;
+        });
+    }
+
+    /// Deletes the shared entry with the admin API `id` from a cache, or
+    /// all of its entries without one, in the background
+    pub(crate) fn evict_responses(self: &Arc<Self>, cache: &str, id: Option<&str>) {
+        // IDs are hex, so anything else would only add wildcards
+        if id.is_some_and(|id| !id.chars().all(|c| c.is_ascii_hexdigit())) {
+            return;
+        }
+        let pattern = format!("{}cache:{}:{}:*", self.prefix, cache, id.unwrap_or("*"));
+        self.spawn(|store| async move {
+            let mut cursor = 0u64;
+            loop {
+                let mut scan = redis::pipe();
+                scan.cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_BATCH);
+                let Some(((next_cursor, keys),)) =
+                    store.query::<((u64, Vec<String>),)>(&scan).await
+                else {
+                    return;
+                };
+                if !keys.is_empty() {
+                    let mut delete = redis::pipe();
+                    delete.cmd("DEL").arg(keys).ignore();
+                    store.query::<()>(&delete).await;
+                }
+                if next_cursor == 0 {
+                    return;
+                }
+                cursor = next_cursor;
+            }
+        });
+    }
+
+    fn response_key(&self, cache: &str, key: &CacheKey) -> String {
+        format!("{}cache:{}:{}", self.prefix, cache, key.storage_name())
+    }
+
+    fn spawn<F>(self: &Arc<Self>, task: impl FnOnce(Arc<Self>) -> F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(task(Arc::clone(self)));
+        }
+    }
+
+    /// Runs `pipeline`, or returns `None` if Redis fails, times out, or
+    /// failed moments ago
+    async fn query<T: FromRedisValue>(&self, pipeline: &Pipeline) -> Option<T> {
+        if self
+            .retry_at()
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return None;
+        }
+
+        let result = tokio::time::timeout(REDIS_TIMEOUT, async {
+            let connection = self
+                .connection
+                .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+                .await?;
+            pipeline.query_async(&mut connection.clone()).await
+        })
+        .await;
+        let failure = match result {
+            Ok(Ok(value)) => {
+                if !self.reachable.swap(true, Ordering::Relaxed) {
+                    info!("Redis is reachable again; limits, usage, and caches are shared");
+                }
+                return Some(value);
+            }
+            Ok(Err(redis_error)) => redis_error.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+
+        *self
+            .retry_at
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+            Some(Instant::now() + RETRY_AFTER_FAILURE);
+        if self.reachable.swap(false, Ordering::Relaxed) {
+            warn!(
+                "Redis is unavailable ({}); each replica uses its own limits, usage, and caches \
+                 until it is back",
+                failure
+            );
+        }
+        None
+    }
+
+    fn retry_at(&self) -> Option<Instant> {
+        *self
+            .retry_at
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
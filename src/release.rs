@@ -0,0 +1,340 @@
+use crate::tasks;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    cmp::Ordering,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, warn};
+
+pub(crate) const RELEASES_URL: &str =
+    "https://api.github.com/repos/OpenSecretCloud/maple-proxy/releases";
+pub(crate) const USER_AGENT: &str = concat!("maple-proxy/", env!("CARGO_PKG_VERSION"));
+
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const UPDATE_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Which releases count as updates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReleaseChannel {
+    /// Full releases only
+    #[default]
+    Stable,
+    /// Full releases and release candidates
+    Prerelease,
+}
+
+impl ReleaseChannel {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Stable => "stable",
+            Self::Prerelease => "prerelease",
+        }
+    }
+}
+
+/// A `vMAJOR.MINOR.PATCH[-PRERELEASE]` version, ordered like semver
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Version {
+    release: (u64, u64, u64),
+    pre_release: Vec<String>,
+}
+
+impl Version {
+    pub(crate) fn parse(version: &str) -> Option<Self> {
+        let version = version.strip_prefix('v').unwrap_or(version);
+        let (release, pre_release) = match version.split_once('-') {
+            Some((release, pre_release)) => (release, Some(pre_release)),
+            None => (version, None),
+        };
+
+        let mut parts = release.split('.').map(|part| part.parse::<u64>().ok());
+        let release = (parts.next()??, parts.next()??, parts.next()??);
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let pre_release = match pre_release {
+            Some(pre_release) if pre_release.split('.').any(str::is_empty) => return None,
+            Some(pre_release) => pre_release.split('.').map(str::to_string).collect(),
+            None => Vec::new(),
+        };
+        Some(Self {
+            release,
+            pre_release,
+        })
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.release.cmp(&other.release).then_with(|| {
+            match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => compare_pre_release(&self.pre_release, &other.pre_release),
+            }
+        })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Numeric identifiers compare numerically and sort before alphanumeric ones
+fn compare_pre_release(a: &[String], b: &[String]) -> Ordering {
+    for (a, b) in a.iter().zip(b) {
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// `None` when either side is not a release version
+pub(crate) fn is_newer(tag: &str, current: &str) -> Option<bool> {
+    Some(Version::parse(tag)? > Version::parse(current)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    html_url: String,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    draft: bool,
+}
+
+/// The highest published release on the channel
+fn newest_release(releases: Vec<Release>, channel: ReleaseChannel) -> Option<Release> {
+    releases
+        .into_iter()
+        .filter(|release| !release.draft)
+        .filter(|release| channel == ReleaseChannel::Prerelease || !release.prerelease)
+        .filter_map(|release| Version::parse(&release.tag_name).map(|version| (version, release)))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, release)| release)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AvailableRelease {
+    pub(crate) version: String,
+    pub(crate) url: String,
+}
+
+#[derive(Debug, Default)]
+struct UpdateStatus {
+    available: Option<AvailableRelease>,
+    checked_at: Option<SystemTime>,
+}
+
+/// Periodically looks for newer releases on a channel. It only reports them;
+/// installing one is left to the operator.
+pub(crate) struct UpdateNotifier {
+    channel: ReleaseChannel,
+    status: RwLock<UpdateStatus>,
+}
+
+impl UpdateNotifier {
+    pub(crate) fn new(channel: ReleaseChannel) -> Self {
+        Self {
+            channel,
+            status: RwLock::new(UpdateStatus::default()),
+        }
+    }
+
+    /// Starts daily checks, which stop once the notifier is dropped
+    pub(crate) fn start(channel: ReleaseChannel) -> Arc<Self> {
+        let notifier = Arc::new(Self::new(channel));
+        tasks::spawn_or_warn(
+            check_periodically(Arc::downgrade(&notifier)),
+            "Update checks need a Tokio runtime and are disabled",
+        );
+        notifier
+    }
+
+    /// The newer release found by the last successful check
+    pub(crate) fn available(&self) -> Option<AvailableRelease> {
+        self.status
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .available
+            .clone()
+    }
+
+    pub(crate) fn record(&self, available: Option<AvailableRelease>) {
+        if let Some(release) = &available {
+            warn!(
+                "maple-proxy {} is available on the {} channel (running {}): {}",
+                release.version,
+                self.channel.as_str(),
+                CURRENT_VERSION,
+                release.url
+            );
+        }
+
+        let mut status = self
+            .status
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        status.available = available;
+        status.checked_at = Some(SystemTime::now());
+    }
+
+    /// The check results reported by `/version`
+    pub(crate) fn to_json(&self) -> Value {
+        let status = self
+            .status
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        json!({
+            "channel": self.channel.as_str(),
+            "checked_at_unix": status.checked_at.and_then(|checked_at| {
+                checked_at.duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs())
+            }),
+            "update_available": status.available.is_some(),
+            "latest_version": status.available.as_ref().map(|release| &release.version),
+            "release_url": status.available.as_ref().map(|release| &release.url),
+        })
+    }
+}
+
+async fn check_periodically(notifier: Weak<UpdateNotifier>) {
+    let client = match reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(UPDATE_CHECK_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(error) => {
+            warn!("Update checks are disabled: {}", error);
+            return;
+        }
+    };
+
+    loop {
+        let Some(notifier) = notifier.upgrade() else {
+            return;
+        };
+        match fetch_newest_release(&client, notifier.channel).await {
+            Ok(release) => {
+                debug!("Update check found {:?}", release);
+                let newer = release
+                    .filter(|release| is_newer(&release.tag_name, CURRENT_VERSION) == Some(true));
+                notifier.record(newer.map(|release| AvailableRelease {
+                    version: release.tag_name,
+                    url: release.html_url,
+                }));
+            }
+            Err(error) => warn!("Update check failed: {}", error),
+        }
+        drop(notifier);
+
+        tokio::time::sleep(UPDATE_CHECK_INTERVAL).await;
+    }
+}
+
+async fn fetch_newest_release(
+    client: &reqwest::Client,
+    channel: ReleaseChannel,
+) -> anyhow::Result<Option<Release>> {
+    let response = client
+        .get(format!("{}?per_page=30", RELEASES_URL))
+        .send()
+        .await?
+        .error_for_status()?;
+    let releases: Vec<Release> = serde_json::from_slice(&response.bytes().await?)?;
+    Ok(newest_release(releases, channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(tag_name: &str, prerelease: bool, draft: bool) -> Release {
+        Release {
+            tag_name: tag_name.to_string(),
+            html_url: format!("https://github.com/releases/{}", tag_name),
+            prerelease,
+            draft,
+        }
+    }
+
+    #[test]
+    fn compares_release_versions() {
+        assert_eq!(is_newer("v0.3.0", "0.2.0"), Some(true));
+        assert_eq!(is_newer("v0.2.10", "0.2.9"), Some(true));
+        assert_eq!(is_newer("v0.2.0", "0.2.0"), Some(false));
+        assert_eq!(is_newer("v0.1.9", "0.2.0"), Some(false));
+        assert_eq!(is_newer("v0.3.0-rc.1", "0.2.0"), Some(true));
+        assert_eq!(is_newer("v0.3.0-rc.1", "0.3.0"), Some(false));
+        assert_eq!(is_newer("v0.3.0-rc.10", "0.3.0-rc.9"), Some(true));
+        assert_eq!(is_newer("v0.3.0-rc.1", "0.3.0-beta.2"), Some(true));
+        assert_eq!(is_newer("v0.3.0-", "0.2.0"), None);
+        assert_eq!(is_newer("nightly", "0.2.0"), None);
+    }
+
+    #[test]
+    fn channels_pick_the_newest_eligible_release() {
+        let releases = || {
+            vec![
+                release("v0.2.0", false, false),
+                release("v0.4.0", false, true),
+                release("v0.3.0-rc.1", true, false),
+                release("v0.2.1", false, false),
+                release("nightly", true, false),
+            ]
+        };
+
+        assert_eq!(
+            newest_release(releases(), ReleaseChannel::Stable)
+                .unwrap()
+                .tag_name,
+            "v0.2.1"
+        );
+        assert_eq!(
+            newest_release(releases(), ReleaseChannel::Prerelease)
+                .unwrap()
+                .tag_name,
+            "v0.3.0-rc.1"
+        );
+        assert!(newest_release(Vec::new(), ReleaseChannel::Stable).is_none());
+    }
+
+    #[test]
+    fn reports_the_last_check() {
+        let notifier = UpdateNotifier::new(ReleaseChannel::Stable);
+        assert_eq!(notifier.to_json()["checked_at_unix"], Value::Null);
+
+        let available = AvailableRelease {
+            version: "v9.0.0".to_string(),
+            url: "https://github.com/releases/v9.0.0".to_string(),
+        };
+        notifier.record(Some(available.clone()));
+
+        assert_eq!(notifier.available(), Some(available));
+        let status = notifier.to_json();
+        assert_eq!(status["update_available"], true);
+        assert_eq!(status["latest_version"], "v9.0.0");
+        assert!(status["checked_at_unix"].is_u64());
+
+        notifier.record(None);
+        assert!(notifier.available().is_none());
+        assert_eq!(notifier.to_json()["update_available"], false);
+    }
+}
@@ -0,0 +1,196 @@
+use axum::http::StatusCode;
+use dashmap::DashMap;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+use tracing::info;
+
+/// Model names come from request bodies, so only this many are tracked
+const MAX_TRACKED_MODELS: usize = 256;
+
+/// Totals for the life of the process, summarized on graceful shutdown
+pub struct RunStats {
+    started_at: Instant,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+    models: DashMap<String, u64>,
+}
+
+impl RunStats {
+    pub(crate) fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            prompt_tokens: AtomicU64::new(0),
+            completion_tokens: AtomicU64::new(0),
+            models: DashMap::new(),
+        }
+    }
+
+    /// Counts an inference request once it is answered; 4xx and 5xx
+    /// responses also count as errors
+    pub(crate) fn record_request(&self, status: StatusCode) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if status.is_client_error() || status.is_server_error() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a request a backend answered for `model`
+    pub(crate) fn record_model(&self, model: &str) {
+        if let Some(mut requests) = self.models.get_mut(model) {
+            *requests += 1;
+        } else if self.models.len() < MAX_TRACKED_MODELS {
+            *self.models.entry(model.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Adds an OpenAI `usage` object to the token totals
+    pub(crate) fn record_usage(&self, usage: &Value) {
+        for (field, total) in [
+            ("prompt_tokens", &self.prompt_tokens),
+            ("completion_tokens", &self.completion_tokens),
+        ] {
+            if let Some(tokens) = usage.get(field).and_then(Value::as_u64) {
+                total.fetch_add(tokens, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn report(&self) -> ShutdownReport {
+        let busiest_model = self
+            .models
+            .iter()
+            .max_by(|a, b| a.value().cmp(b.value()).then_with(|| b.key().cmp(a.key())))
+            .map(|entry| BusiestModel {
+                model: entry.key().clone(),
+                requests: *entry.value(),
+            });
+
+        ShutdownReport {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            prompt_tokens: self.prompt_tokens.load(Ordering::Relaxed),
+            completion_tokens: self.completion_tokens.load(Ordering::Relaxed),
+            busiest_model,
+        }
+    }
+}
+
+/// What the instance did during its run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShutdownReport {
+    pub uptime_secs: u64,
+    pub requests: u64,
+    pub errors: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub busiest_model: Option<BusiestModel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BusiestModel {
+    pub model: String,
+    pub requests: u64,
+}
+
+impl ShutdownReport {
+    pub fn log(&self) {
+        info!("📊 Shutdown report:");
+        info!("   Uptime:          {}", format_uptime(self.uptime_secs));
+        info!("   Requests served: {}", self.requests);
+        info!("   Errors:          {}", self.errors);
+        info!(
+            "   Tokens proxied:  {} ({} prompt, {} completion)",
+            self.prompt_tokens + self.completion_tokens,
+            self.prompt_tokens,
+            self.completion_tokens
+        );
+        match &self.busiest_model {
+            Some(busiest) => info!(
+                "   Busiest model:   {} ({} requests)",
+                busiest.model, busiest.requests
+            ),
+            None => info!("   Busiest model:   none"),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("report serializes")
+    }
+}
+
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes, seconds) =
+        (secs / 86_400, secs / 3_600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h {}m {}s", days, hours, minutes, seconds)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn summarizes_requests_tokens_and_models() {
+        let stats = RunStats::new();
+        stats.record_request(StatusCode::OK);
+        stats.record_request(StatusCode::NOT_FOUND);
+        stats.record_request(StatusCode::BAD_GATEWAY);
+        stats.record_model("llama3-3-70b");
+        stats.record_model("gemma4-31b");
+        stats.record_model("gemma4-31b");
+        stats.record_usage(&json!({"prompt_tokens": 10, "completion_tokens": 5}));
+        stats.record_usage(&json!({"prompt_tokens": 3, "total_tokens": 3}));
+        stats.record_usage(&Value::Null);
+
+        let report = stats.report();
+        assert_eq!(report.requests, 3);
+        assert_eq!(report.errors, 2);
+        assert_eq!(report.prompt_tokens, 13);
+        assert_eq!(report.completion_tokens, 5);
+        assert_eq!(
+            report.busiest_model,
+            Some(BusiestModel {
+                model: "gemma4-31b".to_string(),
+                requests: 2,
+            })
+        );
+
+        let json: Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["busiest_model"]["model"], "gemma4-31b");
+        assert!(RunStats::new().report().busiest_model.is_none());
+    }
+
+    #[test]
+    fn ties_go_to_the_first_model_alphabetically() {
+        let stats = RunStats::new();
+        stats.record_model("llama3-3-70b");
+        stats.record_model("gemma4-31b");
+
+        assert_eq!(stats.report().busiest_model.unwrap().model, "gemma4-31b");
+    }
+
+    #[test]
+    fn formats_uptime() {
+        assert_eq!(format_uptime(42), "42s");
+        assert_eq!(format_uptime(61), "1m 1s");
+        assert_eq!(format_uptime(3_600), "1h 0m 0s");
+        assert_eq!(format_uptime(90_061), "1d 1h 1m 1s");
+    }
+}
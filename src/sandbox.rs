@@ -0,0 +1,73 @@
+use crate::Config;
+
+/// Hardens the process once the listener is bound: optionally changes the
+/// root directory, drops to an unprivileged user/group, and refuses to keep
+/// running as root unless explicitly allowed.
+#[cfg(unix)]
+pub fn apply_process_sandbox(config: &Config) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use nix::unistd::{chdir, chroot, geteuid, setgid, setuid, Group, User};
+    use tracing::{info, warn};
+
+    // Resolve names up front; the user and group databases are usually
+    // unreachable once chrooted.
+    let user = match &config.run_as_user {
+        Some(name) => Some(
+            User::from_name(name)
+                .with_context(|| format!("Failed to look up user '{}'", name))?
+                .ok_or_else(|| anyhow::anyhow!("Unknown user '{}'", name))?,
+        ),
+        None => None,
+    };
+    let gid = match (&config.run_as_group, &user) {
+        (Some(name), _) => Some(
+            Group::from_name(name)
+                .with_context(|| format!("Failed to look up group '{}'", name))?
+                .ok_or_else(|| anyhow::anyhow!("Unknown group '{}'", name))?
+                .gid,
+        ),
+        (None, Some(user)) => Some(user.gid),
+        (None, None) => None,
+    };
+
+    if let Some(dir) = &config.chroot_dir {
+        chroot(dir).with_context(|| format!("Failed to chroot into {}", dir.display()))?;
+        chdir("/").context("Failed to change directory to the new root")?;
+        info!("Changed root directory to {}", dir.display());
+    }
+
+    if let Some(gid) = gid {
+        #[cfg(not(target_vendor = "apple"))]
+        nix::unistd::setgroups(&[gid]).context("Failed to drop supplementary groups")?;
+        setgid(gid).with_context(|| format!("Failed to switch to group {}", gid))?;
+        info!("Switched to group {}", gid);
+    }
+
+    if let Some(user) = &user {
+        setuid(user.uid).with_context(|| format!("Failed to switch to user '{}'", user.name))?;
+        info!("Dropped privileges to user '{}'", user.name);
+    }
+
+    if geteuid().is_root() {
+        if !config.allow_root {
+            anyhow::bail!(
+                "Refusing to run as root. Pass --user to drop privileges after binding, or --allow-root to override"
+            );
+        }
+        warn!("Running as root because --allow-root is set");
+    }
+
+    Ok(())
+}
+
+/// Privilege dropping and chroot are Unix-only; elsewhere they are rejected
+/// rather than silently ignored.
+#[cfg(not(unix))]
+pub fn apply_process_sandbox(config: &Config) -> anyhow::Result<()> {
+    if config.run_as_user.is_some() || config.run_as_group.is_some() || config.chroot_dir.is_some()
+    {
+        anyhow::bail!("--user, --group and --chroot are only supported on Unix");
+    }
+
+    Ok(())
+}
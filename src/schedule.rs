@@ -0,0 +1,404 @@
+use crate::{
+    azure, config::OpenAIError, ids::unix_now, models, proxy::ProxyState,
+    validation::body_too_large,
+};
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::State,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, sync::Arc};
+
+const DAY_NAMES: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const SECS_PER_WEEK: u64 = 7 * 86_400;
+
+/// Weekly recurring hours in UTC, as `[DAYS ]HH:MM-HH:MM` or `DAYS`, where
+/// DAYS is a day such as `Sat` or a range such as `Mon-Fri`. Hours ending at
+/// or before they start run past midnight, and days alone cover whole days.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    /// The days the window starts on, Monday being 0
+    first_day: u8,
+    last_day: u8,
+    /// Minutes after midnight
+    start: u16,
+    end: u16,
+}
+
+impl TimeWindow {
+    fn days(self) -> impl Iterator<Item = u8> {
+        let count = (self.last_day + 7 - self.first_day) % 7 + 1;
+        (0..count).map(move |offset| (self.first_day + offset) % 7)
+    }
+
+    /// The seconds of the week the window covers, from Monday 00:00. An
+    /// interval may run past the end of the week.
+    fn intervals(self) -> impl Iterator<Item = (u64, u64)> {
+        let end = if self.end <= self.start {
+            self.end + MINUTES_PER_DAY
+        } else {
+            self.end
+        };
+        self.days().map(move |day| {
+            let day_start = u64::from(day) * u64::from(MINUTES_PER_DAY);
+            (
+                (day_start + u64::from(self.start)) * 60,
+                (day_start + u64::from(end)) * 60,
+            )
+        })
+    }
+
+    /// Seconds until the window closes, if it is open `week_secs` into the
+    /// week
+    fn remaining(self, week_secs: u64) -> Option<u64> {
+        self.intervals().find_map(|(start, end)| {
+            [week_secs, week_secs + SECS_PER_WEEK]
+                .into_iter()
+                .find(|&secs| start <= secs && secs < end)
+                .map(|secs| end - secs)
+        })
+    }
+
+    fn is_whole_day(self) -> bool {
+        self.start == 0 && self.end == MINUTES_PER_DAY
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected DAYS, HH:MM-HH:MM, or DAYS HH:MM-HH:MM such as Mon-Fri 09:00-17:00, \
+                 got '{}'",
+                value
+            )
+        };
+        let value = value.trim();
+        let (days, hours) = match value.split_once(' ') {
+            Some((days, hours)) => (Some(days), Some(hours.trim())),
+            None if value.contains(':') => (None, Some(value)),
+            None => (Some(value), None),
+        };
+
+        let (first_day, last_day) = match days {
+            Some(days) => {
+                let (first, last) = days.split_once('-').unwrap_or((days, days));
+                (
+                    parse_day(first).ok_or_else(invalid)?,
+                    parse_day(last).ok_or_else(invalid)?,
+                )
+            }
+            None => (0, 6),
+        };
+        let (start, end) = match hours {
+            Some(hours) => {
+                let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+                let start = parse_time(start).filter(|&start| start < MINUTES_PER_DAY);
+                (
+                    start.ok_or_else(invalid)?,
+                    parse_time(end).ok_or_else(invalid)?,
+                )
+            }
+            None => (0, MINUTES_PER_DAY),
+        };
+        if start == end {
+            return Err(format!(
+                "'{}' starts and ends at the same time; list only the days for whole days",
+                value
+            ));
+        }
+        Ok(Self {
+            first_day,
+            last_day,
+            start,
+            end,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        window.to_string()
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let every_day = (self.last_day + 1) % 7 == self.first_day;
+        if !every_day || self.is_whole_day() {
+            f.write_str(DAY_NAMES[usize::from(self.first_day)])?;
+            if self.last_day != self.first_day {
+                write!(f, "-{}", DAY_NAMES[usize::from(self.last_day)])?;
+            }
+            if self.is_whole_day() {
+                return Ok(());
+            }
+            f.write_str(" ")?;
+        }
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+fn parse_day(day: &str) -> Option<u8> {
+    DAY_NAMES
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(day))
+        .map(|day| day as u8)
+}
+
+/// Minutes after midnight of `HH:MM`, up to 24:00
+fn parse_time(time: &str) -> Option<u16> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let hours: u16 = hours.parse().ok()?;
+    let minutes: u16 = minutes.parse().ok()?;
+    let time = hours * 60 + minutes;
+    (minutes < 60 && time <= MINUTES_PER_DAY).then_some(time)
+}
+
+/// Hours in which a model is refused, as MODEL=WINDOW
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelBlackout {
+    pub model: String,
+    pub window: TimeWindow,
+}
+
+impl FromStr for ModelBlackout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (model, window) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected MODEL=WINDOW, got '{}'", value))?;
+        let model = model.trim();
+        if model.is_empty() {
+            return Err(format!("expected MODEL=WINDOW, got '{}'", value));
+        }
+        Ok(Self {
+            model: model.to_string(),
+            window: window.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for ModelBlackout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.model, self.window)
+    }
+}
+
+/// Seconds into the UTC week, from Monday 00:00. The Unix epoch fell on a
+/// Thursday.
+fn week_secs(unix_secs: u64) -> u64 {
+    (unix_secs + 3 * 86_400) % SECS_PER_WEEK
+}
+
+/// Seconds until one of `windows` opens, or `None` if one is open or there
+/// are none
+pub(crate) fn secs_until_open(windows: &[TimeWindow], unix_secs: u64) -> Option<u64> {
+    let now = week_secs(unix_secs);
+    if windows.iter().any(|window| window.remaining(now).is_some()) {
+        return None;
+    }
+    windows
+        .iter()
+        .flat_map(|window| window.intervals())
+        .map(|(start, _)| (start + SECS_PER_WEEK - now) % SECS_PER_WEEK)
+        .min()
+}
+
+/// Seconds until none of `windows` is open, at most a week, or `None` if
+/// none is open now
+pub(crate) fn secs_until_closed(windows: &[TimeWindow], unix_secs: u64) -> Option<u64> {
+    let now = week_secs(unix_secs);
+    let mut elapsed = 0;
+    // Back-to-back and overlapping windows close when the last of them does
+    while let Some(remaining) = windows
+        .iter()
+        .filter_map(|window| window.remaining((now + elapsed) % SECS_PER_WEEK))
+        .max()
+    {
+        elapsed += remaining;
+        if elapsed >= SECS_PER_WEEK {
+            return Some(SECS_PER_WEEK);
+        }
+    }
+    (elapsed > 0).then_some(elapsed)
+}
+
+/// Refuses virtual keys outside their scheduled hours and models during
+/// their blackouts, telling clients when to retry
+pub(crate) async fn enforce_schedules(
+    State(state): State<Arc<ProxyState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let now = unix_now();
+    if let Some(schedule) = state.scheduled_key_hours(request.headers()) {
+        if let Some(retry_after) = secs_until_open(&schedule, now) {
+            return outside_schedule_response(
+                "This API key may only be used during its scheduled hours.".to_string(),
+                "outside_key_schedule",
+                retry_after,
+            );
+        }
+    }
+    let blackouts = &state.config().model_blackouts;
+    if blackouts.is_empty() {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let body = match to_bytes(body, state.config().max_body_bytes()).await {
+        Ok(body) => body,
+        Err(_) => return body_too_large(state.config().max_body_mb).into_response(),
+    };
+    if let Some(model) = requested_model(&state, parts.uri.path(), &body) {
+        let tables = state.model_tables();
+        let target = models::resolve_model_alias(&tables.aliases, &model);
+        let windows: Vec<TimeWindow> = blackouts
+            .iter()
+            .filter(|blackout| blackout.model == model || Some(blackout.model.as_str()) == target)
+            .map(|blackout| blackout.window)
+            .collect();
+        if let Some(retry_after) = secs_until_closed(&windows, now) {
+            return outside_schedule_response(
+                format!("The model `{}` is not available at this time.", model),
+                "model_unavailable_now",
+                retry_after,
+            );
+        }
+    }
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// The model a request names, or for Azure routes its deployment's model
+fn requested_model(state: &ProxyState, path: &str, body: &Bytes) -> Option<String> {
+    let deployment = path
+        .strip_prefix("/openai/deployments/")
+        .and_then(|rest| rest.split('/').next());
+    match deployment {
+        Some(deployment) => {
+            Some(azure::deployment_model(&state.config().azure_deployments, deployment).to_string())
+        }
+        None => models::request_model(body),
+    }
+}
+
+fn outside_schedule_response(message: String, code: &str, retry_after_secs: u64) -> Response {
+    let retry_after_secs = retry_after_secs.max(1);
+    let mut response = (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(
+            OpenAIError::rate_limit_error(format!(
+                "{} Please retry after {} seconds.",
+                message, retry_after_secs
+            ))
+            .with_code(code),
+        ),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Monday 2024-01-01 at `hh:mm` UTC, plus `days`
+    fn at(days: u64, hh: u64, mm: u64) -> u64 {
+        1_704_067_200 + days * 86_400 + hh * 3600 + mm * 60
+    }
+
+    fn windows(specs: &[&str]) -> Vec<TimeWindow> {
+        specs.iter().map(|spec| spec.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_and_prints_windows() {
+        for (spec, printed) in [
+            ("Mon-Fri 09:00-17:00", "Mon-Fri 09:00-17:00"),
+            ("00:00-06:00", "00:00-06:00"),
+            ("sat-sun", "Sat-Sun"),
+            ("Fri 22:00-02:00", "Fri 22:00-02:00"),
+            ("Mon-Sun", "Mon-Sun"),
+            ("Tue 18:00-24:00", "Tue 18:00-24:00"),
+        ] {
+            let window: TimeWindow = spec.parse().unwrap();
+            assert_eq!(window.to_string(), printed);
+        }
+        for invalid in [
+            "",
+            "Mon-Fry",
+            "9:00-17:00",
+            "09:00",
+            "24:00-01:00",
+            "10:00-10:00",
+        ] {
+            assert!(invalid.parse::<TimeWindow>().is_err(), "{}", invalid);
+        }
+
+        let blackout: ModelBlackout = "gpt-4o=Mon-Fri 09:00-17:00".parse().unwrap();
+        assert_eq!(blackout.model, "gpt-4o");
+        assert_eq!(blackout.to_string(), "gpt-4o=Mon-Fri 09:00-17:00");
+        assert!("=Mon".parse::<ModelBlackout>().is_err());
+    }
+
+    #[test]
+    fn keys_wait_for_their_next_window() {
+        let nightly = windows(&["00:00-06:00"]);
+        assert_eq!(secs_until_open(&nightly, at(0, 3, 0)), None);
+        assert_eq!(secs_until_open(&nightly, at(0, 6, 0)), Some(18 * 3600));
+        assert_eq!(secs_until_open(&[], at(0, 12, 0)), None);
+
+        // Friday night into Saturday, and over the weekend into Monday
+        let weekend = windows(&["Fri 22:00-02:00", "Sun 23:00-01:00"]);
+        assert_eq!(secs_until_open(&weekend, at(5, 1, 0)), None);
+        assert_eq!(secs_until_open(&weekend, at(7, 0, 30)), None);
+        assert_eq!(secs_until_open(&weekend, at(5, 2, 0)), Some(45 * 3600));
+        assert_eq!(
+            secs_until_open(&weekend, at(7, 1, 0)),
+            Some(4 * 86_400 + 21 * 3600)
+        );
+    }
+
+    #[test]
+    fn blackouts_last_until_every_window_closes() {
+        let peak = windows(&["Mon-Fri 09:00-12:00", "Mon-Fri 11:00-17:00"]);
+        assert_eq!(secs_until_closed(&peak, at(0, 10, 0)), Some(7 * 3600));
+        assert_eq!(secs_until_closed(&peak, at(0, 17, 0)), None);
+        assert_eq!(secs_until_closed(&peak, at(5, 10, 0)), None);
+        assert_eq!(secs_until_closed(&[], at(0, 10, 0)), None);
+
+        let always = windows(&["Mon-Sun"]);
+        assert_eq!(secs_until_closed(&always, at(2, 8, 0)), Some(SECS_PER_WEEK));
+    }
+}
@@ -0,0 +1,335 @@
+use clap::ValueEnum;
+use jsonschema::Validator;
+use serde_json::Value;
+use std::sync::LazyLock;
+
+const MAX_REPORTED_VIOLATIONS: usize = 10;
+
+/// How requests and responses are checked against the bundled OpenAI schemas
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SchemaValidation {
+    /// No validation
+    #[default]
+    Off,
+    /// Log deviations and forward everything unchanged
+    Log,
+    /// Reject invalid requests with 400 and invalid non-streaming responses with 502
+    Enforce,
+}
+
+/// The OpenAI request and response shapes the proxy ships schemas for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SchemaKind {
+    ChatCompletionRequest,
+    ChatCompletionResponse,
+    ChatCompletionChunk,
+    EmbeddingRequest,
+    EmbeddingResponse,
+    ModelList,
+}
+
+impl SchemaKind {
+    const ALL: [SchemaKind; 6] = [
+        SchemaKind::ChatCompletionRequest,
+        SchemaKind::ChatCompletionResponse,
+        SchemaKind::ChatCompletionChunk,
+        SchemaKind::EmbeddingRequest,
+        SchemaKind::EmbeddingResponse,
+        SchemaKind::ModelList,
+    ];
+
+    pub(crate) fn for_request(path: &str) -> Option<Self> {
+        match path {
+            "/v1/chat/completions" => Some(Self::ChatCompletionRequest),
+            "/v1/embeddings" => Some(Self::EmbeddingRequest),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn for_response(path: &str, streaming: bool) -> Option<Self> {
+        match (path, streaming) {
+            ("/v1/chat/completions", true) => Some(Self::ChatCompletionChunk),
+            ("/v1/chat/completions", false) => Some(Self::ChatCompletionResponse),
+            ("/v1/embeddings", false) => Some(Self::EmbeddingResponse),
+            ("/v1/models", false) => Some(Self::ModelList),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::ChatCompletionRequest => "chat completion request",
+            Self::ChatCompletionResponse => "chat completion response",
+            Self::ChatCompletionChunk => "chat completion chunk",
+            Self::EmbeddingRequest => "embedding request",
+            Self::EmbeddingResponse => "embedding response",
+            Self::ModelList => "model list response",
+        }
+    }
+
+    fn source(self) -> &'static str {
+        match self {
+            Self::ChatCompletionRequest => include_str!("schemas/chat_completion_request.json"),
+            Self::ChatCompletionResponse => include_str!("schemas/chat_completion_response.json"),
+            Self::ChatCompletionChunk => include_str!("schemas/chat_completion_chunk.json"),
+            Self::EmbeddingRequest => include_str!("schemas/embedding_request.json"),
+            Self::EmbeddingResponse => include_str!("schemas/embedding_response.json"),
+            Self::ModelList => include_str!("schemas/model_list.json"),
+        }
+    }
+
+    fn validator(self, strict: bool) -> &'static Validator {
+        static VALIDATORS: LazyLock<Vec<Validator>> = LazyLock::new(|| compile_all(false));
+        static STRICT_VALIDATORS: LazyLock<Vec<Validator>> = LazyLock::new(|| compile_all(true));
+
+        if strict {
+            &STRICT_VALIDATORS[self as usize]
+        } else {
+            &VALIDATORS[self as usize]
+        }
+    }
+}
+
+fn compile_all(strict: bool) -> Vec<Validator> {
+    SchemaKind::ALL
+        .iter()
+        .map(|kind| {
+            let mut schema: Value =
+                serde_json::from_str(kind.source()).expect("bundled schema is valid JSON");
+            if strict {
+                close_objects(&mut schema);
+            }
+            jsonschema::validator_for(&schema).expect("bundled schema compiles")
+        })
+        .collect()
+}
+
+/// Rejects fields the schema does not list in every object schema that lists
+/// its properties. `unevaluatedProperties` also sees the properties that
+/// `if`/`then` branches add, which `additionalProperties` would not.
+fn close_objects(schema: &mut Value) {
+    match schema {
+        Value::Object(fields) => {
+            let is_object = match fields.get("type") {
+                Some(Value::String(kind)) => kind == "object",
+                Some(Value::Array(kinds)) => kinds.iter().any(|kind| kind == "object"),
+                _ => false,
+            };
+            if is_object
+                && fields.contains_key("properties")
+                && !fields.contains_key("additionalProperties")
+            {
+                fields.insert("unevaluatedProperties".to_string(), Value::Bool(false));
+            }
+            fields.values_mut().for_each(close_objects);
+        }
+        Value::Array(items) => items.iter_mut().for_each(close_objects),
+        _ => {}
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SchemaViolation {
+    /// JSON pointer to the offending value; empty for the document itself
+    pub(crate) path: String,
+    pub(crate) message: String,
+}
+
+impl SchemaViolation {
+    /// The offending field in OpenAI `param` notation, e.g. `messages[0].role`
+    pub(crate) fn param(&self) -> Option<String> {
+        let mut param = String::new();
+        for segment in self.path.split('/').skip(1) {
+            if segment.parse::<usize>().is_ok() {
+                param.push_str(&format!("[{}]", segment));
+            } else {
+                if !param.is_empty() {
+                    param.push('.');
+                }
+                param.push_str(&segment.replace("~1", "/").replace("~0", "~"));
+            }
+        }
+
+        (!param.is_empty()).then_some(param)
+    }
+}
+
+/// Checks `instance` against `kind`'s schema; `strict` also rejects fields the
+/// schema does not list
+pub(crate) fn validate(kind: SchemaKind, strict: bool, instance: &Value) -> Vec<SchemaViolation> {
+    kind.validator(strict)
+        .iter_errors(instance)
+        .map(|error| SchemaViolation {
+            path: error.instance_path().as_str().to_string(),
+            message: error.to_string(),
+        })
+        .collect()
+}
+
+pub(crate) fn validate_bytes(kind: SchemaKind, strict: bool, body: &[u8]) -> Vec<SchemaViolation> {
+    match serde_json::from_slice(body) {
+        Ok(instance) => validate(kind, strict, &instance),
+        Err(error) => vec![SchemaViolation {
+            path: String::new(),
+            message: format!("body is not valid JSON: {}", error),
+        }],
+    }
+}
+
+/// A one-line compatibility report listing where a document deviates from spec
+pub(crate) fn report(kind: SchemaKind, violations: &[SchemaViolation]) -> String {
+    let mut details: Vec<String> = violations
+        .iter()
+        .take(MAX_REPORTED_VIOLATIONS)
+        .map(|violation| {
+            if violation.path.is_empty() {
+                violation.message.clone()
+            } else {
+                format!("{}: {}", violation.path, violation.message)
+            }
+        })
+        .collect();
+    if violations.len() > MAX_REPORTED_VIOLATIONS {
+        details.push(format!(
+            "and {} more",
+            violations.len() - MAX_REPORTED_VIOLATIONS
+        ));
+    }
+
+    format!(
+        "{} deviates from the OpenAI schema: {}",
+        kind.name(),
+        details.join("; ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn bundled_schemas_compile() {
+        for kind in SchemaKind::ALL {
+            kind.validator(false);
+            kind.validator(true);
+        }
+    }
+
+    #[test]
+    fn valid_chat_request_with_vendor_extensions_passes() {
+        let request = json!({
+            "model": "llama3-3-70b",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": [{"type": "text", "text": "Hi"}]}
+            ],
+            "temperature": 0.2,
+            "stream": true,
+            "chat_template_kwargs": {"enable_thinking": false}
+        });
+
+        assert!(validate(SchemaKind::ChatCompletionRequest, false, &request).is_empty());
+    }
+
+    #[test]
+    fn strict_validation_rejects_unlisted_fields() {
+        let portable = json!({
+            "model": "llama3-3-70b",
+            "messages": [{
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,"}}
+                ]
+            }],
+            "response_format": {"type": "json_schema", "json_schema": {"name": "answer"}},
+            "stream_options": {"include_usage": true}
+        });
+        assert!(validate(SchemaKind::ChatCompletionRequest, true, &portable).is_empty());
+
+        let mut extended = portable.clone();
+        extended["chat_template_kwargs"] = json!({"enable_thinking": false});
+        extended["messages"][0]["content"][1]["image_url"]["format"] = json!("png");
+        let violations = validate(SchemaKind::ChatCompletionRequest, true, &extended);
+        let reported = |field: &str| {
+            violations
+                .iter()
+                .any(|violation| violation.message.contains(field))
+        };
+        assert!(reported("chat_template_kwargs") && reported("'format'"));
+        assert!(validate(SchemaKind::ChatCompletionRequest, false, &extended).is_empty());
+
+        let usage = json!({
+            "prompt_tokens": 1,
+            "completion_tokens": 1,
+            "total_tokens": 2,
+            "completion_tokens_details": {"reasoning_tokens": 0}
+        });
+        let mut response = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "llama3-3-70b",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hi"},
+                "finish_reason": "stop"
+            }],
+            "usage": usage
+        });
+        assert!(validate(SchemaKind::ChatCompletionResponse, true, &response).is_empty());
+        response["choices"][0]["message"]["reasoning_content"] = json!("Hmm");
+        let violations = validate(SchemaKind::ChatCompletionResponse, true, &response);
+        assert!(violations
+            .iter()
+            .any(|violation| violation.param().as_deref() == Some("choices[0].message")));
+    }
+
+    #[test]
+    fn reports_offending_params() {
+        let request = json!({
+            "model": "llama3-3-70b",
+            "messages": [{"role": "bot", "content": "Hi"}],
+            "temperature": 3
+        });
+
+        let violations = validate(SchemaKind::ChatCompletionRequest, false, &request);
+        let params: Vec<_> = violations
+            .iter()
+            .filter_map(SchemaViolation::param)
+            .collect();
+
+        assert!(params.contains(&"messages[0].role".to_string()));
+        assert!(params.contains(&"temperature".to_string()));
+        assert!(report(SchemaKind::ChatCompletionRequest, &violations)
+            .starts_with("chat completion request deviates from the OpenAI schema: "));
+    }
+
+    #[test]
+    fn validates_stream_chunks_and_non_json_bodies() {
+        let chunk = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "llama3-3-70b",
+            "choices": [{"index": 0, "delta": {"content": "Hi"}, "finish_reason": null}]
+        });
+        let bad_chunk = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "llama3-3-70b",
+            "choices": [{"index": 0, "delta": {"tool_calls": [{"id": "call_1"}]}}]
+        });
+
+        assert!(validate(SchemaKind::ChatCompletionChunk, false, &chunk).is_empty());
+        assert_eq!(
+            validate(SchemaKind::ChatCompletionChunk, false, &bad_chunk)[0]
+                .param()
+                .as_deref(),
+            Some("choices[0].delta.tool_calls[0]")
+        );
+        assert!(!validate_bytes(SchemaKind::EmbeddingRequest, false, b"not json").is_empty());
+    }
+}
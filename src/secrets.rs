@@ -0,0 +1,155 @@
+use crate::{config::Config, tasks};
+use anyhow::Context;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
+
+/// How often the API key file is checked for a rotated key
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The `--api-key-file` that reads the key from stdin instead
+pub(crate) const STDIN_PATH: &str = "-";
+
+/// The Maple API key mounted at `--api-key-file`, as a Kubernetes or Docker
+/// secret is. The file is re-read when it changes, so a rotated key is used
+/// without a restart.
+pub(crate) struct SecretFile {
+    path: PathBuf,
+    secret: RwLock<Option<String>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl SecretFile {
+    /// `None` without `--api-key-file`, or when it is `-`: stdin is read once,
+    /// by `Config::load`
+    pub(crate) fn start(config: &Config) -> Option<Arc<Self>> {
+        let path = config.api_key_file.clone()?;
+        if path == Path::new(STDIN_PATH) {
+            return None;
+        }
+        let secret_file = Arc::new(Self {
+            path,
+            secret: RwLock::default(),
+            modified: Mutex::default(),
+        });
+        if let Err(read_error) = secret_file.reload() {
+            error!(
+                "{:#}; using the API key read at startup, if any",
+                read_error
+            );
+        }
+        tasks::spawn_or_warn(
+            tasks::reload_periodically(
+                Arc::downgrade(&secret_file),
+                RELOAD_INTERVAL,
+                Self::reload,
+                "keeping the API key read before",
+            ),
+            "Re-reading the API key file needs a Tokio runtime",
+        );
+        Some(secret_file)
+    }
+
+    /// The key last read from the file
+    pub(crate) fn current(&self) -> Option<String> {
+        self.secret
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Reads the file if it changed since it was last read, keeping the key
+    /// read before if it cannot be. Returns whether it was read.
+    fn reload(&self) -> anyhow::Result<bool> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to read API key file {}", self.path.display()))?;
+        // A file that fails to read is reported once, not until it is fixed
+        let previous = self
+            .modified
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .replace(modified);
+        if previous == Some(modified) {
+            return Ok(false);
+        }
+
+        let secret = read_secret(&self.path)?;
+        let mut current = self
+            .secret
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if previous.is_some() && current.as_ref() != Some(&secret) {
+            info!("Read a rotated API key from {}", self.path.display());
+        }
+        *current = Some(secret);
+        Ok(true)
+    }
+}
+
+/// The secret in a file, or in stdin for `-`, without the trailing newline
+/// secret files usually end with
+pub(crate) fn read_secret(path: &Path) -> anyhow::Result<String> {
+    let contents = if path == Path::new(STDIN_PATH) {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .context("Failed to read the API key from stdin")?;
+        contents
+    } else {
+        fs::read_to_string(path)
+            .with_context(|| format!("Failed to read API key file {}", path.display()))?
+    };
+    let secret = contents.trim();
+    if secret.is_empty() {
+        if path == Path::new(STDIN_PATH) {
+            anyhow::bail!("No API key was given on stdin");
+        }
+        anyhow::bail!("API key file {} is empty", path.display());
+    }
+    Ok(secret.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_keys_are_read_and_bad_files_keep_the_last_key() {
+        let dir = std::env::temp_dir().join(format!("maple-secrets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("api-key");
+        fs::write(&path, "sk-first\n").unwrap();
+
+        let secret_file = SecretFile {
+            path: path.clone(),
+            secret: RwLock::default(),
+            modified: Mutex::default(),
+        };
+        assert!(secret_file.reload().unwrap());
+        assert!(!secret_file.reload().unwrap());
+        assert_eq!(secret_file.current().as_deref(), Some("sk-first"));
+
+        let touch = |secs| {
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(secs))
+                .unwrap();
+        };
+        fs::write(&path, "sk-second").unwrap();
+        touch(10);
+        assert!(secret_file.reload().unwrap());
+        assert_eq!(secret_file.current().as_deref(), Some("sk-second"));
+
+        fs::write(&path, "\n").unwrap();
+        touch(20);
+        assert!(secret_file.reload().is_err());
+        assert!(!secret_file.reload().unwrap());
+        assert_eq!(secret_file.current().as_deref(), Some("sk-second"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,461 @@
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, HeaderValue, Request, Version},
+    response::Response,
+    Router,
+};
+use dashmap::DashMap;
+use futures::StreamExt;
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use std::{
+    convert::Infallible,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{watch, Notify},
+};
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+/// How long to wait before accepting again after an accept error, such as
+/// running out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Limits on client connections. The lifetime and request limits make
+/// long-lived clients reconnect now and then, so a load balancer can spread
+/// them across proxy replicas; the rest keep slow or idle clients from holding
+/// connections open. A connection over a limit finishes its in-flight
+/// requests and closes, with `Connection: close` on HTTP/1.1 and GOAWAY on
+/// HTTP/2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// How long a client connection may stay open
+    pub max_lifetime: Option<Duration>,
+    /// How many requests a client connection may carry
+    pub max_requests: Option<u64>,
+    /// How long a new connection may take to send its first request, and an
+    /// HTTP/1.1 client a request's headers, before the connection is dropped.
+    /// This also bounds the protocol sniffing and the HTTP/2 preface.
+    pub header_read_timeout: Option<Duration>,
+    /// How long a client connection may stay open without a request in
+    /// flight, which also bounds HTTP/2 clients that trickle a later
+    /// request's headers
+    pub idle_timeout: Option<Duration>,
+    /// How many connections one client IP may hold open at once; further
+    /// ones are closed as soon as they are accepted
+    pub max_per_ip: Option<u32>,
+}
+
+/// Serves `app` on `listener` over HTTP/1.1 and HTTP/2 with
+/// `ConnectInfo<SocketAddr>`, like `axum::serve`, enforcing `limits` on each
+/// connection. Once `shutdown` completes, stops accepting and returns when
+/// every connection has finished its in-flight requests.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    limits: ConnectionLimits,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    tokio::pin!(shutdown);
+    let open_per_ip = Arc::new(DashMap::new());
+
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    warn!("Failed to accept a client connection: {}", error);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let slot = match limits.max_per_ip {
+            Some(max_per_ip) => match IpSlot::claim(&open_per_ip, remote_addr.ip(), max_per_ip) {
+                Some(slot) => Some(slot),
+                None => {
+                    debug!(
+                        "Refusing a client connection from {}: it already has {} open",
+                        remote_addr, max_per_ip
+                    );
+                    continue;
+                }
+            },
+            None => None,
+        };
+        if let Err(error) = stream.set_nodelay(true) {
+            debug!(
+                "Cannot disable Nagle's algorithm for {}: {}",
+                remote_addr, error
+            );
+        }
+        tokio::spawn(serve_connection(
+            stream,
+            remote_addr,
+            app.clone(),
+            limits,
+            slot,
+            shutdown_rx.clone(),
+        ));
+    }
+
+    // Every connection holds a receiver, so the channel closes once they
+    // have all drained
+    drop(listener);
+    drop(shutdown_rx);
+    let _ = shutdown_tx.send(());
+    shutdown_tx.closed().await;
+    Ok(())
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    app: Router,
+    limits: ConnectionLimits,
+    _slot: Option<IpSlot>,
+    mut shutdown: watch::Receiver<()>,
+) {
+    let exhausted = Arc::new(Notify::new());
+    let activity = Arc::new(Activity::new());
+    let requests = Arc::new(AtomicU64::new(0));
+    let service = {
+        let requests = Arc::clone(&requests);
+        let exhausted = Arc::clone(&exhausted);
+        let activity = Arc::clone(&activity);
+        tower::service_fn(move |mut request: Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(remote_addr));
+            let served = requests.fetch_add(1, Ordering::Relaxed) + 1;
+            let last = limits.max_requests.is_some_and(|max| served >= max);
+            if last {
+                exhausted.notify_one();
+            }
+            // The response may be written before the connection hears about
+            // the limit, so HTTP/1 clients are told directly
+            let close = last && request.version() < Version::HTTP_2;
+            let app = app.clone();
+            let active = limits.idle_timeout.map(|_| activity.start());
+            async move {
+                let mut response = app.oneshot(request).await?;
+                if close {
+                    response
+                        .headers_mut()
+                        .insert(header::CONNECTION, HeaderValue::from_static("close"));
+                }
+                // A request stays active until its response body ends, so
+                // long streams never count as idle
+                if let Some(active) = active {
+                    let (parts, body) = response.into_parts();
+                    let mut stream = body.into_data_stream();
+                    let body = Body::from_stream(async_stream::stream! {
+                        let _active = active;
+                        while let Some(chunk) = stream.next().await {
+                            yield chunk;
+                        }
+                    });
+                    response = Response::from_parts(parts, body);
+                }
+                Ok::<_, Infallible>(response)
+            }
+        })
+    };
+
+    let mut builder = Builder::new(TokioExecutor::new());
+    if let Some(header_read_timeout) = limits.header_read_timeout {
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(header_read_timeout);
+    }
+    let connection =
+        builder.serve_connection(TokioIo::new(stream), TowerToHyperService::new(service));
+    tokio::pin!(connection);
+    let expired = async {
+        match limits.max_lifetime {
+            Some(max_lifetime) => tokio::time::sleep(max_lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(expired);
+    // Hyper's own header timeout only starts once it has detected HTTP/1, so
+    // a client that sends nothing, or stalls partway through the HTTP/2
+    // preface or its first request's headers, is dropped here
+    let first_request = async {
+        let Some(header_read_timeout) = limits.header_read_timeout else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep(header_read_timeout).await;
+        if requests.load(Ordering::Relaxed) > 0 {
+            std::future::pending().await
+        }
+    };
+    tokio::pin!(first_request);
+    let idle = async {
+        let Some(idle_timeout) = limits.idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let idle_for = activity.idle_for();
+            if idle_for >= idle_timeout {
+                return;
+            }
+            tokio::time::sleep(idle_timeout - idle_for).await;
+        }
+    };
+    tokio::pin!(idle);
+
+    let mut closing = false;
+    loop {
+        let reason = tokio::select! {
+            result = connection.as_mut() => {
+                if let Err(error) = result {
+                    debug!("Client connection from {} failed: {}", remote_addr, error);
+                }
+                return;
+            }
+            _ = &mut first_request => {
+                debug!(
+                    "Dropping the client connection from {}: it sent no request in time",
+                    remote_addr
+                );
+                return;
+            }
+            _ = &mut expired, if !closing => "it reached its maximum lifetime",
+            _ = &mut idle, if !closing => "it was idle too long",
+            _ = exhausted.notified(), if !closing => "it reached its maximum request count",
+            _ = shutdown.changed(), if !closing => "the proxy is shutting down",
+        };
+        debug!(
+            "Closing the client connection from {} because {}",
+            remote_addr, reason
+        );
+        connection.as_mut().graceful_shutdown();
+        closing = true;
+    }
+}
+
+/// When a connection last had a request in flight
+struct Activity {
+    in_flight: AtomicUsize,
+    last_active: Mutex<Instant>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn start(self: &Arc<Self>) -> ActiveRequest {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        ActiveRequest(Arc::clone(self))
+    }
+
+    /// How long the connection has gone without a request in flight
+    fn idle_for(&self) -> Duration {
+        if self.in_flight.load(Ordering::Acquire) > 0 {
+            return Duration::ZERO;
+        }
+        self.last_active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .elapsed()
+    }
+}
+
+/// A request in flight, until its response body ends or is dropped
+struct ActiveRequest(Arc<Activity>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        *self
+            .0
+            .last_active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// One of a client IP's `max_per_ip` connections, given back when the
+/// connection closes
+struct IpSlot {
+    open_per_ip: Arc<DashMap<IpAddr, u32>>,
+    ip: IpAddr,
+}
+
+impl IpSlot {
+    fn claim(open_per_ip: &Arc<DashMap<IpAddr, u32>>, ip: IpAddr, max_per_ip: u32) -> Option<Self> {
+        let mut open = open_per_ip.entry(ip).or_insert(0);
+        if *open >= max_per_ip {
+            return None;
+        }
+        *open += 1;
+        Some(Self {
+            open_per_ip: Arc::clone(open_per_ip),
+            ip,
+        })
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        self.open_per_ip.remove_if_mut(&self.ip, |_, open| {
+            *open -= 1;
+            *open == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    async fn start(limits: ConnectionLimits) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                assert!(peer.ip().is_loopback());
+                "ok"
+            }),
+        );
+        tokio::spawn(serve(listener, app, limits, std::future::pending()));
+        address
+    }
+
+    /// Reads one response to [`REQUEST`], lowercased
+    async fn read_response(stream: &mut TcpStream) -> String {
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\nok") {
+            let mut buffer = [0; 1024];
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "connection closed mid-response");
+            response.extend_from_slice(&buffer[..read]);
+        }
+        String::from_utf8(response).unwrap().to_lowercase()
+    }
+
+    async fn closed(stream: &mut TcpStream) -> bool {
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0; 1])).await;
+        matches!(read, Ok(Ok(0)))
+    }
+
+    #[tokio::test]
+    async fn closes_connections_after_their_request_limit() {
+        let address = start(ConnectionLimits {
+            max_requests: Some(2),
+            ..ConnectionLimits::default()
+        })
+        .await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+
+        stream.write_all(REQUEST).await.unwrap();
+        assert!(!read_response(&mut stream)
+            .await
+            .contains("connection: close"));
+        stream.write_all(REQUEST).await.unwrap();
+        assert!(read_response(&mut stream)
+            .await
+            .contains("connection: close"));
+        assert!(closed(&mut stream).await);
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections_after_their_lifetime() {
+        let address = start(ConnectionLimits {
+            max_lifetime: Some(Duration::from_millis(100)),
+            ..ConnectionLimits::default()
+        })
+        .await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+
+        stream.write_all(REQUEST).await.unwrap();
+        assert!(read_response(&mut stream).await.starts_with("http/1.1 200"));
+        assert!(closed(&mut stream).await);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_that_idle_or_send_headers_slowly() {
+        let address = start(ConnectionLimits {
+            header_read_timeout: Some(Duration::from_millis(100)),
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..ConnectionLimits::default()
+        })
+        .await;
+
+        let mut idle = TcpStream::connect(address).await.unwrap();
+        idle.write_all(REQUEST).await.unwrap();
+        assert!(read_response(&mut idle).await.starts_with("http/1.1 200"));
+        assert!(closed(&mut idle).await);
+
+        let mut slow = TcpStream::connect(address).await.unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\nHost: local")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), slow.read_to_end(&mut response));
+        assert!(read.await.is_ok_and(|read| read.is_ok()));
+        assert!(!String::from_utf8_lossy(&response).contains("200"));
+    }
+
+    #[tokio::test]
+    async fn drops_connections_that_never_send_a_request() {
+        let address = start(ConnectionLimits {
+            header_read_timeout: Some(Duration::from_millis(100)),
+            ..ConnectionLimits::default()
+        })
+        .await;
+
+        let mut silent = TcpStream::connect(address).await.unwrap();
+        assert!(closed(&mut silent).await);
+
+        let mut stalled = TcpStream::connect(address).await.unwrap();
+        stalled.write_all(b"PRI * HTTP/2.0\r\n").await.unwrap();
+        assert!(closed(&mut stalled).await);
+
+        let mut prompt = TcpStream::connect(address).await.unwrap();
+        prompt.write_all(REQUEST).await.unwrap();
+        assert!(read_response(&mut prompt).await.starts_with("http/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_over_the_per_ip_limit() {
+        let address = start(ConnectionLimits {
+            max_per_ip: Some(1),
+            ..ConnectionLimits::default()
+        })
+        .await;
+        let mut first = TcpStream::connect(address).await.unwrap();
+        first.write_all(REQUEST).await.unwrap();
+        assert!(read_response(&mut first).await.starts_with("http/1.1 200"));
+
+        let mut second = TcpStream::connect(address).await.unwrap();
+        assert!(closed(&mut second).await);
+        first.write_all(REQUEST).await.unwrap();
+        assert!(read_response(&mut first).await.starts_with("http/1.1 200"));
+    }
+}
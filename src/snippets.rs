@@ -0,0 +1,266 @@
+use crate::{config::Config, create_app};
+use axum::{
+    body::{to_bytes, Body},
+    http::{Request, StatusCode},
+};
+use clap::{Args, ValueEnum};
+use serde_json::Value;
+use tower::ServiceExt;
+
+/// Used when the model list is unavailable, e.g. without an API key
+const FALLBACK_MODEL: &str = "llama3-3-70b";
+const MAX_MODEL_LIST_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SnippetLang {
+    /// The official `openai` Python library
+    Python,
+    /// The official `openai` npm package
+    Js,
+    Curl,
+    /// `reqwest` and `serde_json` on Tokio
+    Rust,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SnippetsArgs {
+    /// Language to print the client code in
+    #[arg(long, value_enum)]
+    pub lang: SnippetLang,
+
+    /// Model to use in the code instead of the first available one
+    #[arg(long, value_name = "MODEL")]
+    pub model: Option<String>,
+
+    /// Don't fetch the model list from the backend
+    #[arg(long)]
+    pub offline: bool,
+}
+
+/// Client code for a chat completion against this proxy, using a model from
+/// the deployment's model list
+pub async fn snippets(config: &Config, args: &SnippetsArgs) -> String {
+    let models = if args.offline || config.default_api_key.is_none() {
+        None
+    } else {
+        fetch_model_ids(config).await
+    }
+    .unwrap_or_else(|| configured_model_ids(config));
+
+    let model = args
+        .model
+        .clone()
+        .unwrap_or_else(|| chat_model(&models).to_string());
+    snippet(args.lang, &base_url(config), &model, &models)
+}
+
+/// The curl example for the startup log, which must not wait on the backend
+pub fn startup_snippet(config: &Config) -> String {
+    let models = configured_model_ids(config);
+    snippet(
+        SnippetLang::Curl,
+        &base_url(config),
+        chat_model(&models),
+        &[],
+    )
+}
+
+/// The address clients should use; wildcard listen addresses are not
+/// connectable
+fn base_url(config: &Config) -> String {
+    let host = match config.host.as_str() {
+        "0.0.0.0" | "::" | "[::]" => "localhost".to_string(),
+        host if host.contains(':') && !host.starts_with('[') => format!("[{}]", host),
+        host => host.to_string(),
+    };
+    format!("http://{}:{}", host, config.port)
+}
+
+/// Models named in the configuration, for when the backend is not asked
+fn configured_model_ids(config: &Config) -> Vec<String> {
+    if !config.allowed_models.is_empty() {
+        return config.allowed_models.clone();
+    }
+    if !config.model_list.is_empty() {
+        return config.model_list.clone();
+    }
+    config
+        .model_aliases
+        .iter()
+        .map(|alias| alias.alias.clone())
+        .collect()
+}
+
+async fn fetch_model_ids(config: &Config) -> Option<Vec<String>> {
+    let request = Request::get("/v1/models")
+        .body(Body::empty())
+        .expect("static request is valid");
+    let response = match create_app(config.clone()).oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    };
+    if response.status() != StatusCode::OK {
+        return None;
+    }
+
+    let body = to_bytes(response.into_body(), MAX_MODEL_LIST_BYTES)
+        .await
+        .ok()?;
+    model_ids(&body)
+}
+
+fn model_ids(body: &[u8]) -> Option<Vec<String>> {
+    let list: Value = serde_json::from_slice(body).ok()?;
+    let ids: Vec<String> = list
+        .get("data")?
+        .as_array()?
+        .iter()
+        .filter_map(|model| model.get("id")?.as_str().map(str::to_string))
+        .collect();
+    (!ids.is_empty()).then_some(ids)
+}
+
+/// The first model that is not an embedding model
+fn chat_model(models: &[String]) -> &str {
+    models
+        .iter()
+        .find(|model| !model.contains("embed"))
+        .or(models.first())
+        .map_or(FALLBACK_MODEL, String::as_str)
+}
+
+fn snippet(lang: SnippetLang, base_url: &str, model: &str, models: &[String]) -> String {
+    let model = Value::String(model.to_string()).to_string();
+    let comment = match lang {
+        SnippetLang::Python | SnippetLang::Curl => "#",
+        SnippetLang::Js | SnippetLang::Rust => "//",
+    };
+    let mut snippet = String::new();
+    if !models.is_empty() {
+        snippet.push_str(&format!(
+            "{} Available models: {}\n",
+            comment,
+            models.join(", ")
+        ));
+    }
+
+    snippet.push_str(&match lang {
+        SnippetLang::Python => format!(
+            r#"from openai import OpenAI
+
+client = OpenAI(base_url="{base_url}/v1", api_key="YOUR_MAPLE_API_KEY")
+
+response = client.chat.completions.create(
+    model={model},
+    messages=[{{"role": "user", "content": "Hello!"}}],
+)
+print(response.choices[0].message.content)
+"#
+        ),
+        SnippetLang::Js => format!(
+            r#"import OpenAI from "openai";
+
+const client = new OpenAI({{
+  baseURL: "{base_url}/v1",
+  apiKey: "YOUR_MAPLE_API_KEY",
+}});
+
+const response = await client.chat.completions.create({{
+  model: {model},
+  messages: [{{ role: "user", content: "Hello!" }}],
+}});
+console.log(response.choices[0].message.content);
+"#
+        ),
+        SnippetLang::Curl => format!(
+            r#"curl {base_url}/v1/chat/completions \
+  -H "Authorization: Bearer YOUR_MAPLE_API_KEY" \
+  -H "Content-Type: application/json" \
+  -d '{{"model": {model}, "messages": [{{"role": "user", "content": "Hello!"}}]}}'
+"#
+        ),
+        SnippetLang::Rust => format!(
+            r#"// Cargo.toml: reqwest = {{ version = "0.12", features = ["json"] }}, serde_json = "1",
+// tokio = {{ version = "1", features = ["full"] }}
+#[tokio::main]
+async fn main() -> Result<(), reqwest::Error> {{
+    let response: serde_json::Value = reqwest::Client::new()
+        .post("{base_url}/v1/chat/completions")
+        .bearer_auth("YOUR_MAPLE_API_KEY")
+        .json(&serde_json::json!({{
+            "model": {model},
+            "messages": [{{"role": "user", "content": "Hello!"}}]
+        }}))
+        .send()
+        .await?
+        .json()
+        .await?;
+    println!("{{}}", response["choices"][0]["message"]["content"]);
+    Ok(())
+}}
+"#
+        ),
+    });
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(host: &str) -> Config {
+        Config::new(host.to_string(), 9090, "http://localhost:3000".to_string())
+    }
+
+    #[test]
+    fn base_url_is_connectable() {
+        assert_eq!(base_url(&config("127.0.0.1")), "http://127.0.0.1:9090");
+        assert_eq!(base_url(&config("0.0.0.0")), "http://localhost:9090");
+        assert_eq!(base_url(&config("::1")), "http://[::1]:9090");
+    }
+
+    #[test]
+    fn picks_a_chat_model_from_the_list() {
+        let models = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            chat_model(&models(&["nomic-embed-text", "gemma4-31b"])),
+            "gemma4-31b"
+        );
+        assert_eq!(
+            chat_model(&models(&["nomic-embed-text"])),
+            "nomic-embed-text"
+        );
+        assert_eq!(chat_model(&[]), FALLBACK_MODEL);
+        assert_eq!(
+            model_ids(br#"{"data":[{"id":"llama3-3-70b"},{"object":"model"}]}"#),
+            Some(models(&["llama3-3-70b"]))
+        );
+        assert_eq!(model_ids(br#"{"data":[]}"#), None);
+    }
+
+    #[test]
+    fn snippets_target_the_proxy_and_model() {
+        let models = vec!["gemma4-31b".to_string(), "nomic-embed-text".to_string()];
+        for lang in SnippetLang::value_variants() {
+            let snippet = snippet(*lang, "http://localhost:9090", "gemma4-31b", &models);
+
+            assert!(snippet.contains("http://localhost:9090/v1"), "{:?}", lang);
+            assert!(snippet.contains("\"gemma4-31b\""), "{:?}", lang);
+            assert!(
+                snippet.contains("Available models: gemma4-31b, nomic-embed-text"),
+                "{:?}",
+                lang
+            );
+        }
+    }
+
+    #[test]
+    fn startup_curl_posts_to_the_chat_completions_url() {
+        let config = config("0.0.0.0").with_allowed_models(vec!["gemma4-31b".to_string()]);
+        let snippet = startup_snippet(&config);
+
+        assert!(snippet.starts_with("curl http://localhost:9090/v1/chat/completions \\\n"));
+        assert!(snippet.contains(r#"-d '{"model": "gemma4-31b", "messages""#));
+    }
+}
@@ -0,0 +1,54 @@
+use crate::{
+    proxy::{proxy_inference_request, ProxyError, ProxyState},
+    validation::ValidatedBody,
+};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, Method, Uri},
+    response::Response,
+};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// The media type of each speech `response_format`, as OpenAI labels them
+fn media_type(response_format: &str) -> Option<&'static str> {
+    match response_format {
+        "mp3" => Some("audio/mpeg"),
+        "opus" => Some("audio/opus"),
+        "aac" => Some("audio/aac"),
+        "flac" => Some("audio/flac"),
+        "wav" => Some("audio/wav"),
+        "pcm" => Some("audio/pcm"),
+        _ => None,
+    }
+}
+
+/// `/v1/audio/speech`, text to speech. The audio is relayed chunk by chunk as
+/// the backend sends it, so long passages are never held in memory whole.
+/// Backends that label it `application/octet-stream`, or not at all, have the
+/// media type of the requested `response_format` (default `mp3`) filled in,
+/// unless `stream_format: "sse"` asked for audio events instead.
+pub(crate) async fn create_speech(
+    State(state): State<Arc<ProxyState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    ValidatedBody(body): ValidatedBody,
+) -> Result<Response, ProxyError> {
+    let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let audio_type = match request["stream_format"].as_str() {
+        Some("sse") => None,
+        _ => media_type(request["response_format"].as_str().unwrap_or("mp3")),
+    };
+    let mut response = proxy_inference_request(&state, Method::POST, uri, &headers, body).await?;
+
+    let unlabeled = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_none_or(|content_type| content_type == "application/octet-stream");
+    if let Some(audio_type) = audio_type.filter(|_| response.status().is_success() && unlabeled) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(audio_type));
+    }
+    Ok(response)
+}
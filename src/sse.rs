@@ -0,0 +1,78 @@
+/// Incrementally splits a Server-Sent Events byte stream into event payloads.
+///
+/// Only `data:` fields matter to OpenAI-compatible streams; multi-line data is
+/// joined with newlines and other fields are ignored.
+#[derive(Default)]
+pub(crate) struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    /// Feeds a chunk and returns the data payload of every event it completes
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some((end, separator_len)) = find_event_boundary(&self.buffer) {
+            let event: Vec<u8> = self.buffer.drain(..end + separator_len).collect();
+            if let Some(data) = event_data(&event[..end]) {
+                events.push(data);
+            }
+        }
+        events
+    }
+
+    /// Whether the chunks so far end partway through an event
+    pub(crate) fn mid_event(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+}
+
+fn find_event_boundary(buffer: &[u8]) -> Option<(usize, usize)> {
+    let lf = buffer.windows(2).position(|window| window == b"\n\n");
+    let crlf = buffer.windows(4).position(|window| window == b"\r\n\r\n");
+
+    match (lf, crlf) {
+        (Some(lf), Some(crlf)) if crlf < lf => Some((crlf, 4)),
+        (Some(lf), _) => Some((lf, 2)),
+        (None, Some(crlf)) => Some((crlf, 4)),
+        (None, None) => None,
+    }
+}
+
+fn event_data(event: &[u8]) -> Option<String> {
+    let event = String::from_utf8_lossy(event);
+    let data: Vec<&str> = event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+
+    (!data.is_empty()).then(|| data.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_events_across_chunk_boundaries() {
+        let mut parser = SseParser::default();
+
+        assert!(parser.push(b"data: {\"a\":").is_empty());
+        assert_eq!(
+            parser.push(b"1}\n\ndata: [DONE]\n\n"),
+            vec![r#"{"a":1}"#, "[DONE]"]
+        );
+    }
+
+    #[test]
+    fn handles_crlf_comments_and_multiline_data() {
+        let mut parser = SseParser::default();
+
+        assert_eq!(
+            parser.push(b": keep-alive\r\n\r\nevent: message\r\ndata: one\r\ndata:two\r\n\r\n"),
+            vec!["one\ntwo"]
+        );
+    }
+}
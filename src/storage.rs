@@ -0,0 +1,84 @@
+use crate::{config::OpenAIError, proxy::ProxyError};
+use anyhow::Context;
+use axum::{http::StatusCode, Json};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    path::Path,
+};
+use tracing::error;
+
+/// Replaces the file in one rename, so readers never see a partial write
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+/// Writes `value` as pretty-printed JSON with [`write_atomically`]
+pub(crate) fn write_json(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    let contents = serde_json::to_vec_pretty(value).map_err(io::Error::other)?;
+    write_atomically(path, &contents)
+}
+
+pub(crate) fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Opens the SQLite `database` at `path`, creating its tables from `schema`
+/// if they are missing
+pub(crate) fn open_database(
+    path: &Path,
+    database: &str,
+    schema: &str,
+) -> anyhow::Result<Connection> {
+    let connection = Connection::open(path).with_context(|| {
+        format!(
+            "Failed to open the {} database {}",
+            database,
+            path.display()
+        )
+    })?;
+    connection.execute_batch(schema).with_context(|| {
+        format!(
+            "Failed to set up the {} database {}",
+            database,
+            path.display()
+        )
+    })?;
+    Ok(connection)
+}
+
+/// The 500 for a request whose `store` failed to read or write, logging why
+pub(crate) fn storage_error(store: &str, storage_error: impl fmt::Display) -> ProxyError {
+    error!("{} storage failed: {}", store, storage_error);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(OpenAIError::server_error(format!(
+            "{} storage failed.",
+            store
+        ))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_writes_replace_the_file_and_leave_no_temporary_behind() {
+        let dir = std::env::temp_dir().join(format!("maple-storage-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tables.json");
+
+        write_atomically(&path, b"old").unwrap();
+        write_json(&path, &serde_json::json!({"new": true})).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\n  \"new\": true\n}");
+        assert!(!dir.join("tables.json.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+use crate::sse::SseParser;
+use axum::body::Bytes;
+use futures::StreamExt;
+use opensecret::client::OpenSecretResponseBody;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+/// The body that asks for a non-streaming chat completion as a stream, with
+/// usage, so `--aggregate-streams` can assemble it. `None` for requests that
+/// stream already or are not JSON objects.
+pub(crate) fn streaming_body(body: &[u8]) -> Option<Bytes> {
+    let mut request: Value = serde_json::from_slice(body).ok()?;
+    let fields = request.as_object_mut()?;
+    if fields.get("stream").and_then(Value::as_bool) == Some(true) {
+        return None;
+    }
+    fields.insert("stream".to_string(), Value::Bool(true));
+    fields.insert("stream_options".to_string(), json!({"include_usage": true}));
+    serde_json::to_vec(&request).ok().map(Bytes::from)
+}
+
+/// One choice's message as its deltas arrive
+#[derive(Default)]
+struct ChoiceParts {
+    message: Map<String, Value>,
+    /// Tool calls by their `index`, with the argument fragments joined
+    tool_calls: BTreeMap<u64, Value>,
+    finish_reason: Option<Value>,
+    logprobs: Vec<Value>,
+}
+
+impl ChoiceParts {
+    fn add_delta(&mut self, delta: &Map<String, Value>) {
+        for (field, value) in delta {
+            match (field.as_str(), value) {
+                ("tool_calls", Value::Array(calls)) => {
+                    for call in calls {
+                        self.add_tool_call(call);
+                    }
+                }
+                // Text fields, such as `content` and `reasoning_content`,
+                // arrive in pieces
+                (_, Value::String(piece)) if field != "role" => match self.message.get_mut(field) {
+                    Some(Value::String(text)) => text.push_str(piece),
+                    _ => {
+                        self.message.insert(field.clone(), value.clone());
+                    }
+                },
+                (_, Value::Null) => {}
+                _ => {
+                    self.message.insert(field.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    fn add_tool_call(&mut self, call: &Value) {
+        let index = call["index"].as_u64().unwrap_or(0);
+        let merged = self
+            .tool_calls
+            .entry(index)
+            .or_insert_with(|| json!({"function": {"name": "", "arguments": ""}}));
+        for field in ["id", "type"] {
+            if let Some(value) = call.get(field).filter(|value| !value.is_null()) {
+                merged[field] = value.clone();
+            }
+        }
+        for field in ["name", "arguments"] {
+            if let Some(piece) = call["function"][field].as_str() {
+                if let Some(Value::String(text)) = merged["function"].get_mut(field) {
+                    text.push_str(piece);
+                }
+            }
+        }
+    }
+
+    fn into_choice(mut self, index: u64, cut_off: bool) -> Value {
+        self.message
+            .entry("role")
+            .or_insert_with(|| Value::from("assistant"));
+        self.message.entry("content").or_insert(Value::Null);
+        if !self.tool_calls.is_empty() {
+            let tool_calls = self.tool_calls.into_values().collect();
+            self.message
+                .insert("tool_calls".to_string(), Value::Array(tool_calls));
+        }
+        let finish_reason = match self.finish_reason {
+            Some(finish_reason) if !cut_off => finish_reason,
+            _ => Value::from("error"),
+        };
+        let logprobs = if self.logprobs.is_empty() {
+            Value::Null
+        } else {
+            json!({"content": self.logprobs})
+        };
+        json!({
+            "index": index,
+            "message": self.message,
+            "logprobs": logprobs,
+            "finish_reason": finish_reason,
+        })
+    }
+}
+
+/// Assembles the chunks of a streamed chat completion into the
+/// `chat.completion` a non-streaming request would have been answered with
+#[derive(Default)]
+pub(crate) struct StreamAggregator {
+    /// The first chunk's `id`, `created`, `model`, and so on
+    header: Map<String, Value>,
+    choices: BTreeMap<u64, ChoiceParts>,
+    usage: Option<Value>,
+}
+
+impl StreamAggregator {
+    pub(crate) fn observe(&mut self, chunk: &Value) {
+        let Some(fields) = chunk.as_object() else {
+            return;
+        };
+        for (field, value) in fields {
+            match field.as_str() {
+                "choices" | "object" => {}
+                "usage" if value.is_object() => self.usage = Some(value.clone()),
+                _ if !value.is_null() => {
+                    self.header
+                        .entry(field.clone())
+                        .or_insert_with(|| value.clone());
+                }
+                _ => {}
+            }
+        }
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            let parts = self
+                .choices
+                .entry(choice["index"].as_u64().unwrap_or(0))
+                .or_default();
+            if let Some(delta) = choice["delta"].as_object() {
+                parts.add_delta(delta);
+            }
+            if let Some(logprobs) = choice["logprobs"]["content"].as_array() {
+                parts.logprobs.extend(logprobs.iter().cloned());
+            }
+            if let Some(finish_reason) =
+                choice.get("finish_reason").filter(|value| !value.is_null())
+            {
+                parts.finish_reason = Some(finish_reason.clone());
+            }
+        }
+    }
+
+    /// Whether no chunk arrived, leaving nothing to salvage
+    pub(crate) fn is_empty(&self) -> bool {
+        self.header.is_empty() && self.choices.is_empty()
+    }
+
+    /// The completion, with `finish_reason: "error"` for choices that did
+    /// not finish, or for every choice when the stream was `cut_off`
+    pub(crate) fn into_completion(self, cut_off: bool) -> Value {
+        let mut completion = self.header;
+        completion.insert("object".to_string(), Value::from("chat.completion"));
+        let choices = self
+            .choices
+            .into_iter()
+            .map(|(index, parts)| parts.into_choice(index, cut_off))
+            .collect();
+        completion.insert("choices".to_string(), Value::Array(choices));
+        if let Some(usage) = self.usage {
+            completion.insert("usage".to_string(), usage);
+        }
+        Value::Object(completion)
+    }
+}
+
+/// How a backend stream being assembled ended early
+pub(crate) enum StreamCutOff {
+    /// The whole completion took longer than the request timeout
+    Deadline,
+    /// No chunk arrived within the stream idle timeout
+    Idle,
+    /// The backend stream failed
+    Failed(String),
+}
+
+/// Reads a chat completion stream into a [`StreamAggregator`] until
+/// `[DONE]`, the end of the stream, or a cut-off, which is returned with
+/// whatever arrived before it
+pub(crate) async fn aggregate_stream(
+    mut stream: OpenSecretResponseBody,
+    deadline: Instant,
+    idle_timeout: Duration,
+) -> (StreamAggregator, Option<StreamCutOff>) {
+    let mut aggregator = StreamAggregator::default();
+    let mut parser = SseParser::default();
+    loop {
+        let wait = idle_timeout.min(deadline.saturating_duration_since(Instant::now()));
+        let bytes = match tokio::time::timeout(wait, stream.next()).await {
+            Ok(Some(Ok(bytes))) => bytes,
+            Ok(Some(Err(error))) => {
+                return (aggregator, Some(StreamCutOff::Failed(error.to_string())));
+            }
+            Ok(None) => return (aggregator, None),
+            Err(_) if Instant::now() >= deadline => {
+                return (aggregator, Some(StreamCutOff::Deadline));
+            }
+            Err(_) => return (aggregator, Some(StreamCutOff::Idle)),
+        };
+        for data in parser.push(&bytes) {
+            if data == "[DONE]" {
+                return (aggregator, None);
+            }
+            if let Ok(chunk) = serde_json::from_str::<Value>(&data) {
+                aggregator.observe(&chunk);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streaming_body_asks_for_usage_and_skips_streaming_requests() {
+        let body = streaming_body(br#"{"model":"m","messages":[]}"#).unwrap();
+        let request: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(request["stream"], true);
+        assert_eq!(request["stream_options"]["include_usage"], true);
+
+        assert!(streaming_body(br#"{"model":"m","stream":true}"#).is_none());
+        assert!(streaming_body(b"[]").is_none());
+    }
+
+    #[tokio::test]
+    async fn chunks_assemble_into_a_completion() {
+        let chunks = [
+            r#"{"id":"c1","object":"chat.completion.chunk","created":7,"model":"m","choices":[{"index":0,"delta":{"role":"assistant","content":""}}]}"#,
+            r#"{"id":"c1","object":"chat.completion.chunk","created":7,"model":"m","choices":[{"index":0,"delta":{"reasoning_content":"Think","content":"Hel"}}]}"#,
+            r#"{"id":"c1","object":"chat.completion.chunk","created":7,"model":"m","choices":[{"index":0,"delta":{"content":"lo","tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get","arguments":"{\"a\""}}]}}]}"#,
+            r#"{"id":"c1","object":"chat.completion.chunk","created":7,"model":"m","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":":1}"}}]},"finish_reason":"tool_calls"}]}"#,
+            r#"{"id":"c1","object":"chat.completion.chunk","created":7,"model":"m","choices":[],"usage":{"prompt_tokens":3,"completion_tokens":2,"total_tokens":5}}"#,
+            "[DONE]",
+        ];
+        let body: Vec<u8> = chunks
+            .iter()
+            .flat_map(|data| format!("data: {}\n\n", data).into_bytes())
+            .collect();
+        let stream: OpenSecretResponseBody =
+            Box::pin(futures::stream::iter([Ok::<_, opensecret::Error>(
+                Bytes::from(body),
+            )]));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (aggregator, cut_off) =
+            aggregate_stream(stream, deadline, Duration::from_secs(5)).await;
+        assert!(cut_off.is_none());
+        assert_eq!(
+            aggregator.into_completion(false),
+            json!({
+                "id": "c1",
+                "object": "chat.completion",
+                "created": 7,
+                "model": "m",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": "Hello",
+                        "reasoning_content": "Think",
+                        "tool_calls": [{
+                            "id": "call_1",
+                            "type": "function",
+                            "function": {"name": "get", "arguments": "{\"a\":1}"}
+                        }]
+                    },
+                    "logprobs": null,
+                    "finish_reason": "tool_calls"
+                }],
+                "usage": {"prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_streams_keep_what_arrived() {
+        let stream: OpenSecretResponseBody = Box::pin(futures::stream::iter([
+            Ok(Bytes::from_static(
+                b"data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Part\"}}]}\n\n",
+            )),
+            Err(opensecret::Error::Other("connection reset".to_string())),
+        ]));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (aggregator, cut_off) =
+            aggregate_stream(stream, deadline, Duration::from_secs(5)).await;
+        assert!(matches!(cut_off, Some(StreamCutOff::Failed(_))));
+        let completion = aggregator.into_completion(true);
+        assert_eq!(completion["choices"][0]["message"]["content"], "Part");
+        assert_eq!(completion["choices"][0]["finish_reason"], "error");
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{config::OpenAIError, proxy::ByteStream, wire};
+use axum::body::Bytes;
+use futures::StreamExt;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// Bytes held by active response streams, against a global budget. A stream
+/// is charged for the chunk it is passing on plus the unfinished event that
+/// the proxy's SSE parsers hold from earlier chunks. When the total exceeds
+/// the budget, the newest streams are shed until it fits, so streams that
+/// have been running longest get to finish.
+pub(crate) struct StreamMemory {
+    max_bytes: usize,
+    accounts: Mutex<Accounts>,
+}
+
+#[derive(Default)]
+struct Accounts {
+    next_id: u64,
+    total: usize,
+    /// Active streams by age, oldest first
+    streams: BTreeMap<u64, StreamAccount>,
+}
+
+struct StreamAccount {
+    held: usize,
+    shed: Arc<Notify>,
+}
+
+impl StreamMemory {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            accounts: Mutex::default(),
+        }
+    }
+
+    fn open(self: &Arc<Self>) -> StreamCharge {
+        let mut accounts = self.accounts();
+        let id = accounts.next_id;
+        accounts.next_id += 1;
+        let shed = Arc::new(Notify::new());
+        accounts.streams.insert(
+            id,
+            StreamAccount {
+                held: 0,
+                shed: Arc::clone(&shed),
+            },
+        );
+        StreamCharge {
+            memory: Arc::clone(self),
+            id,
+            shed,
+        }
+    }
+
+    /// Sets the bytes stream `id` holds, shedding the newest streams while the
+    /// total is over budget. Returns whether stream `id` is still active.
+    fn charge(&self, id: u64, held: usize) -> bool {
+        let mut accounts = self.accounts();
+        let Some(account) = accounts.streams.get_mut(&id) else {
+            return false;
+        };
+        let previous = std::mem::replace(&mut account.held, held);
+        accounts.total = accounts.total - previous + held;
+
+        while accounts.total > self.max_bytes {
+            let Some((_, newest)) = accounts.streams.pop_last() else {
+                break;
+            };
+            accounts.total -= newest.held;
+            newest.shed.notify_one();
+        }
+        accounts.streams.contains_key(&id)
+    }
+
+    fn release(&self, id: u64) {
+        let mut accounts = self.accounts();
+        if let Some(account) = accounts.streams.remove(&id) {
+            accounts.total -= account.held;
+        }
+    }
+
+    fn accounts(&self) -> MutexGuard<'_, Accounts> {
+        self.accounts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[cfg(test)]
+    fn total(&self) -> usize {
+        self.accounts().total
+    }
+}
+
+/// A stream's share of the budget, returned when the stream ends
+struct StreamCharge {
+    memory: Arc<StreamMemory>,
+    id: u64,
+    shed: Arc<Notify>,
+}
+
+impl StreamCharge {
+    fn set(&self, held: usize) -> bool {
+        self.memory.charge(self.id, held)
+    }
+}
+
+impl Drop for StreamCharge {
+    fn drop(&mut self) {
+        self.memory.release(self.id);
+    }
+}
+
+/// Charges an event stream to `memory` while it runs. A shed stream ends at
+/// once with an error event, even while it waits on the backend.
+pub(crate) fn account_stream(memory: &Arc<StreamMemory>, mut stream: ByteStream) -> ByteStream {
+    let charge = memory.open();
+    Box::pin(async_stream::stream! {
+        // Bytes of the event that earlier chunks started but did not finish
+        let mut pending = 0;
+        loop {
+            let next = tokio::select! {
+                biased;
+                _ = charge.shed.notified() => None,
+                chunk = stream.next() => Some(chunk),
+            };
+            let chunk = match next {
+                Some(Some(chunk)) => chunk,
+                Some(None) => break,
+                None => {
+                    warn!("Shed a response stream to stay within the streaming memory budget");
+                    yield Ok(shed_event(pending > 0));
+                    break;
+                }
+            };
+            if let Ok(bytes) = &chunk {
+                if !charge.set(pending + bytes.len()) {
+                    warn!("Shed a response stream to stay within the streaming memory budget");
+                    yield Ok(shed_event(pending > 0));
+                    break;
+                }
+                pending = unfinished_event_len(pending, bytes);
+            }
+            yield chunk;
+        }
+    })
+}
+
+/// The length of the event left unfinished after `chunk`, given `pending`
+/// bytes of one before it
+fn unfinished_event_len(pending: usize, chunk: &[u8]) -> usize {
+    let lf = chunk
+        .windows(2)
+        .rposition(|window| window == b"\n\n")
+        .map(|at| at + 2);
+    let crlf = chunk
+        .windows(4)
+        .rposition(|window| window == b"\r\n\r\n")
+        .map(|at| at + 4);
+    match lf.max(crlf) {
+        Some(end) => chunk.len() - end,
+        None => pending + chunk.len(),
+    }
+}
+
+/// The error event that ends a shed stream, first closing any event the
+/// client has only partly received
+fn shed_event(mid_event: bool) -> Bytes {
+    let error = OpenAIError::server_error(
+        "The proxy is streaming too many large responses right now. Please retry the request.",
+    )
+    .with_code("stream_memory_exceeded");
+    let separator = if mid_event { "\n\n" } else { "" };
+    Bytes::from(format!("{}data: {}\n\n", separator, wire::to_value(&error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use serde_json::Value;
+    use std::io;
+
+    type Sender = mpsc::UnboundedSender<Result<Bytes, io::Error>>;
+
+    fn open_stream(memory: &Arc<StreamMemory>) -> (Sender, ByteStream) {
+        let (sender, receiver) = mpsc::unbounded();
+        (sender, account_stream(memory, Box::pin(receiver)))
+    }
+
+    fn send(sender: &Sender, chunk: &'static [u8]) {
+        sender
+            .unbounded_send(Ok(Bytes::from_static(chunk)))
+            .unwrap();
+    }
+
+    fn error_code(chunk: &Bytes) -> Value {
+        let data = std::str::from_utf8(chunk)
+            .unwrap()
+            .trim()
+            .strip_prefix("data: ");
+        serde_json::from_str::<Value>(data.unwrap()).unwrap()["error"]["code"].clone()
+    }
+
+    #[test]
+    fn tracks_unfinished_events() {
+        assert_eq!(unfinished_event_len(0, b"data: 1\n\n"), 0);
+        assert_eq!(unfinished_event_len(0, b"data: 1\n\ndata: {"), 7);
+        assert_eq!(unfinished_event_len(7, b"\"a\":"), 11);
+        assert_eq!(unfinished_event_len(11, b"1}\r\n\r\n"), 0);
+    }
+
+    #[tokio::test]
+    async fn sheds_the_newest_streams_when_over_budget() {
+        let memory = Arc::new(StreamMemory::new(16));
+        let (oldest_sender, mut oldest) = open_stream(&memory);
+        let (middle_sender, mut middle) = open_stream(&memory);
+        let (newest_sender, mut newest) = open_stream(&memory);
+
+        send(&oldest_sender, b"data: 1\n\n");
+        assert_eq!(oldest.next().await.unwrap().unwrap(), "data: 1\n\n");
+        send(&middle_sender, b"data: 2");
+        assert_eq!(middle.next().await.unwrap().unwrap(), "data: 2");
+        assert_eq!(memory.total(), 16);
+
+        // The newest stream's own chunk would exceed the budget
+        send(&newest_sender, b"data: 3\n\n");
+        let shed = newest.next().await.unwrap().unwrap();
+        assert_eq!(error_code(&shed), "stream_memory_exceeded");
+        assert!(newest.next().await.is_none());
+
+        // An older stream growing sheds the middle one while it waits
+        send(&oldest_sender, b"data: 11\n\n");
+        assert_eq!(oldest.next().await.unwrap().unwrap(), "data: 11\n\n");
+        let shed = middle.next().await.unwrap().unwrap();
+        assert!(shed.starts_with(b"\n\ndata: "));
+        assert_eq!(error_code(&shed), "stream_memory_exceeded");
+        assert!(middle.next().await.is_none());
+
+        drop(oldest);
+        assert_eq!(memory.total(), 0);
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{metrics::Metrics, proxy::write_sse_event, sse::SseParser, tokenizer};
+use axum::body::Bytes;
+use clap::ValueEnum;
+use futures::{future::BoxFuture, StreamExt};
+use opensecret::client::OpenSecretResponseBody;
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tracing::warn;
+
+/// How often one streamed completion is resumed before it is finished with
+/// an error instead
+const MAX_RESUMES: usize = 2;
+
+/// What happens to a streamed chat completion whose backend stream fails,
+/// stalls, or ends before the completion finished
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum StreamRecovery {
+    /// Cut the client's stream off, as the backend did
+    #[default]
+    Off,
+    /// End the stream with a `finish_reason: "error"` chunk and `[DONE]`
+    Finish,
+    /// Ask the backend to continue from the text generated so far, and
+    /// finish with an error if it cannot
+    Resume,
+}
+
+/// Sends the continuation of a failed stream, answering its body when the
+/// backend accepted it
+pub(crate) type Resend =
+    Box<dyn FnMut(Bytes) -> BoxFuture<'static, Option<OpenSecretResponseBody>> + Send>;
+
+/// What the client has been sent of a streamed chat completion
+#[derive(Default)]
+struct Progress {
+    content: String,
+    last_chunk: Option<Value>,
+    finished: bool,
+    /// Completions with several choices or tool calls cannot be continued
+    /// from their text
+    resumable: bool,
+}
+
+impl Progress {
+    fn observe(&mut self, chunk: &Value) {
+        if let Some(choices) = chunk["choices"].as_array() {
+            for choice in choices {
+                let delta = &choice["delta"];
+                if choice["index"].as_u64().unwrap_or(0) != 0 || delta.get("tool_calls").is_some() {
+                    self.resumable = false;
+                }
+                if let Some(content) = delta["content"].as_str() {
+                    self.content.push_str(content);
+                }
+                if choice["finish_reason"].is_string() {
+                    self.finished = true;
+                }
+            }
+        }
+        if chunk.get("id").is_some() {
+            self.last_chunk = Some(chunk.clone());
+        }
+    }
+
+    /// The chunk that ends a stream that could not be recovered, in the
+    /// shape of the ones before it
+    fn error_chunk(&self) -> Value {
+        let mut chunk = self
+            .last_chunk
+            .clone()
+            .unwrap_or_else(|| serde_json::json!({"object": "chat.completion.chunk"}));
+        if let Some(fields) = chunk.as_object_mut() {
+            fields.remove("usage");
+        }
+        chunk["choices"] = serde_json::json!([{
+            "index": 0,
+            "delta": {},
+            "finish_reason": "error",
+        }]);
+        chunk
+    }
+
+    /// Makes a chunk of a resumed stream read as part of the original one
+    fn continue_chunk(&self, chunk: &mut Value) {
+        if let Some(last_chunk) = &self.last_chunk {
+            for field in ["id", "created"] {
+                if let Some(value) = last_chunk.get(field) {
+                    chunk[field] = value.clone();
+                }
+            }
+        }
+        if let Some(choices) = chunk["choices"].as_array_mut() {
+            for choice in choices {
+                if let Some(delta) = choice["delta"].as_object_mut() {
+                    delta.remove("role");
+                }
+            }
+        }
+    }
+}
+
+/// Watches a streamed chat completion for a backend stream that fails, goes
+/// idle for `idle_timeout`, or ends before a `finish_reason`. With `resend`,
+/// the request is sent again with the text generated so far as a final
+/// assistant message to continue, and the continuation streamed on as part
+/// of the same completion. Otherwise, or when that fails too, the stream ends
+/// with a `finish_reason: "error"` chunk and `[DONE]` instead of being cut.
+pub(crate) fn recover_stream(
+    mut stream: OpenSecretResponseBody,
+    request_body: Bytes,
+    idle_timeout: Duration,
+    mut resend: Option<Resend>,
+    metrics: Arc<Metrics>,
+) -> OpenSecretResponseBody {
+    Box::pin(async_stream::stream! {
+        let mut parser = SseParser::default();
+        let mut progress = Progress {
+            resumable: serde_json::from_slice::<Value>(&request_body)
+                .is_ok_and(|request| request["n"].as_u64().unwrap_or(1) == 1),
+            ..Progress::default()
+        };
+        let mut resumes = 0;
+        loop {
+            let failure = match tokio::time::timeout(idle_timeout, stream.next()).await {
+                Ok(Some(Ok(bytes))) => {
+                    let mut events = Vec::new();
+                    let mut done = false;
+                    for data in parser.push(&bytes) {
+                        if data == "[DONE]" {
+                            done = true;
+                        }
+                        match serde_json::from_str::<Value>(&data) {
+                            Ok(mut chunk) if chunk.is_object() => {
+                                if resumes > 0 {
+                                    progress.continue_chunk(&mut chunk);
+                                }
+                                progress.observe(&chunk);
+                                write_sse_event(&mut events, &chunk.to_string());
+                            }
+                            _ => write_sse_event(&mut events, &data),
+                        }
+                    }
+                    if !events.is_empty() {
+                        yield Ok(Bytes::from(events));
+                    }
+                    if done {
+                        return;
+                    }
+                    continue;
+                }
+                Ok(Some(Err(error))) => error.to_string(),
+                Ok(None) if progress.finished => return,
+                Ok(None) => "the stream ended before the completion finished".to_string(),
+                Err(_) => format!("no chunk arrived for {} seconds", idle_timeout.as_secs()),
+            };
+
+            let continuation = resend
+                .as_mut()
+                .filter(|_| progress.resumable && resumes < MAX_RESUMES)
+                .zip(resume_body(&request_body, &progress.content));
+            if let Some((resend, body)) = continuation {
+                warn!("Backend stream failed ({}); resuming the completion", failure);
+                if let Some(continued) = resend(body).await {
+                    metrics.record_stream_recovery("resumed");
+                    stream = continued;
+                    parser = SseParser::default();
+                    resumes += 1;
+                    continue;
+                }
+            }
+
+            warn!("Backend stream failed ({}); ending the completion with an error", failure);
+            metrics.record_stream_recovery("finished");
+            let mut events = Vec::new();
+            write_sse_event(&mut events, &progress.error_chunk().to_string());
+            write_sse_event(&mut events, "[DONE]");
+            yield Ok(Bytes::from(events));
+            return;
+        }
+    })
+}
+
+/// The request that continues `generated`: it is appended as a final
+/// assistant message the backend continues instead of answering, and the
+/// token limits shrink by what it used. `None` once they are used up.
+fn resume_body(request_body: &[u8], generated: &str) -> Option<Bytes> {
+    if generated.is_empty() {
+        return Some(Bytes::copy_from_slice(request_body));
+    }
+    let mut request: Value = serde_json::from_slice(request_body).ok()?;
+    request["messages"].as_array_mut()?.push(serde_json::json!({
+        "role": "assistant",
+        "content": generated,
+    }));
+    request["continue_final_message"] = Value::Bool(true);
+    request["add_generation_prompt"] = Value::Bool(false);
+
+    let used = tokenizer::count_tokens(generated) as u64;
+    for field in ["max_tokens", "max_completion_tokens"] {
+        if let Some(limit) = request.get(field).and_then(Value::as_u64) {
+            request[field] = limit.checked_sub(used).filter(|left| *left > 0)?.into();
+        }
+    }
+    serde_json::to_vec(&request).ok().map(Bytes::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(body: &[u8]) -> Vec<String> {
+        SseParser::default().push(body)
+    }
+
+    fn backend_stream(chunks: Vec<Result<&'static str, &'static str>>) -> OpenSecretResponseBody {
+        Box::pin(futures::stream::iter(chunks.into_iter().map(|chunk| {
+            chunk
+                .map(Bytes::from_static)
+                .map_err(|error| opensecret::Error::Other(error.to_string()))
+        })))
+    }
+
+    async fn collect(stream: OpenSecretResponseBody) -> Vec<u8> {
+        let chunks: Vec<_> = stream.collect().await;
+        chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.unwrap())
+            .collect()
+    }
+
+    const REQUEST: &str = r#"{"model":"m","stream":true,"max_tokens":100,"messages":[{"role":"user","content":"Hi"}]}"#;
+
+    #[tokio::test]
+    async fn failed_streams_finish_with_an_error_chunk() {
+        let stream = backend_stream(vec![
+            Ok("data: {\"id\":\"c1\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hel\"}}]}\n\n"),
+            Err("connection reset"),
+        ]);
+        let metrics = Arc::new(Metrics::default());
+        let recovered = recover_stream(
+            stream,
+            Bytes::from_static(REQUEST.as_bytes()),
+            Duration::from_secs(5),
+            None,
+            Arc::clone(&metrics),
+        );
+
+        let events = events(&collect(recovered).await);
+        assert_eq!(events.len(), 3);
+        let last: Value = serde_json::from_str(&events[1]).unwrap();
+        assert_eq!(last["id"], "c1");
+        assert_eq!(last["choices"][0]["finish_reason"], "error");
+        assert_eq!(events[2], "[DONE]");
+        assert!(metrics
+            .render()
+            .contains("maple_proxy_stream_recoveries_total{outcome=\"finished\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn resumed_streams_continue_from_the_generated_text() {
+        let stream = backend_stream(vec![
+            Ok("data: {\"id\":\"c1\",\"created\":1,\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"Hello\"}}]}\n\n"),
+            Ok("data: {\"id\":\"c1\",\"created\":1,\"choices\":[{\"index\":0,\"del"),
+        ]);
+        let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let resend: Resend = {
+            let sent = Arc::clone(&sent);
+            Box::new(move |body| {
+                sent.lock().unwrap().push(body);
+                Box::pin(async {
+                    Some(backend_stream(vec![
+                        Ok("data: {\"id\":\"c2\",\"created\":2,\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\" world\"},\"finish_reason\":\"stop\"}]}\n\n"),
+                        Ok("data: [DONE]\n\n"),
+                    ]))
+                })
+            })
+        };
+        let recovered = recover_stream(
+            stream,
+            Bytes::from_static(REQUEST.as_bytes()),
+            Duration::from_secs(5),
+            Some(resend),
+            Arc::new(Metrics::default()),
+        );
+
+        let events = events(&collect(recovered).await);
+        assert_eq!(events.len(), 3);
+        let continued: Value = serde_json::from_str(&events[1]).unwrap();
+        assert_eq!(continued["id"], "c1");
+        assert_eq!(continued["created"], 1);
+        assert_eq!(
+            continued["choices"][0]["delta"],
+            serde_json::json!({"content": " world"})
+        );
+
+        let sent = sent.lock().unwrap();
+        let resumed: Value = serde_json::from_slice(&sent[0]).unwrap();
+        assert_eq!(
+            resumed["messages"][1],
+            serde_json::json!({"role": "assistant", "content": "Hello"})
+        );
+        assert_eq!(resumed["continue_final_message"], true);
+        assert_eq!(resumed["add_generation_prompt"], false);
+        assert!(resumed["max_tokens"].as_u64().unwrap() < 100);
+    }
+}
@@ -0,0 +1,252 @@
+use axum::body::Bytes;
+use jsonschema::Validator;
+use serde_json::Value;
+use tracing::warn;
+
+/// The output a chat completion's `response_format` asks for, which
+/// `--enforce-response-format` holds the backend's answer to and
+/// `--repair-json-output` repairs it into
+pub(crate) enum ResponseFormat {
+    /// `json_object`: any JSON object
+    JsonObject,
+    /// `json_schema`: JSON that matches the schema
+    JsonSchema { name: String, validator: Validator },
+}
+
+impl ResponseFormat {
+    /// The format a chat completion request asks for. `None` for plain text,
+    /// and for schemas that do not compile, which are left to the backend.
+    pub(crate) fn for_request(body: &[u8]) -> Option<Self> {
+        let request: Value = serde_json::from_slice(body).ok()?;
+        let format = &request["response_format"];
+        match format["type"].as_str()? {
+            "json_object" => Some(Self::JsonObject),
+            "json_schema" => {
+                let json_schema = &format["json_schema"];
+                let name = json_schema["name"].as_str().unwrap_or_default().to_string();
+                let schema = json_schema
+                    .get("schema")
+                    .cloned()
+                    .unwrap_or(Value::Bool(true));
+                match jsonschema::validator_for(&schema) {
+                    Ok(validator) => Some(Self::JsonSchema { name, validator }),
+                    Err(error) => {
+                        warn!(
+                            "Not enforcing response_format schema '{}', which does not compile: {}",
+                            name, error
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Why a non-streaming chat completion's content does not match, or `None`
+    /// when every choice's does. Choices without text content, such as tool
+    /// calls and refusals, are not checked.
+    pub(crate) fn mismatch(&self, completion: &[u8]) -> Option<String> {
+        let completion: Value = serde_json::from_slice(completion).ok()?;
+        for choice in completion["choices"].as_array().into_iter().flatten() {
+            let Some(content) = choice["message"]["content"].as_str() else {
+                continue;
+            };
+            let index = choice["index"].as_u64().unwrap_or(0);
+            let output: Value = match serde_json::from_str(content) {
+                Ok(output) => output,
+                Err(error) => {
+                    return Some(format!("choice {} is not valid JSON: {}", index, error))
+                }
+            };
+            match self {
+                Self::JsonObject if !output.is_object() => {
+                    return Some(format!("choice {} is not a JSON object", index));
+                }
+                Self::JsonObject => {}
+                Self::JsonSchema { name, validator } => {
+                    if let Some(error) = validator.iter_errors(&output).next() {
+                        let path = error.instance_path().as_str().to_string();
+                        return Some(format!(
+                            "choice {} does not match schema '{}' at '{}': {}",
+                            index, name, path, error
+                        ));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// The completion with the content that does not parse as JSON repaired
+    /// where [`repair_json`] can, or `None` when nothing was repaired
+    pub(crate) fn repair(&self, completion: &[u8]) -> Option<Bytes> {
+        let mut completion: Value = serde_json::from_slice(completion).ok()?;
+        let mut repaired = false;
+        let choices = completion["choices"].as_array_mut().into_iter().flatten();
+        for choice in choices {
+            let Some(content) = choice["message"]["content"].as_str() else {
+                continue;
+            };
+            if serde_json::from_str::<Value>(content).is_ok() {
+                continue;
+            }
+            if let Some(json) = repair_json(content) {
+                choice["message"]["content"] = Value::String(json);
+                repaired = true;
+            }
+        }
+        if !repaired {
+            return None;
+        }
+        serde_json::to_vec(&completion).ok().map(Bytes::from)
+    }
+}
+
+/// Repairs JSON a model wrapped in prose or markdown fences, or broke off:
+/// text around the first object or array is dropped, trailing commas are
+/// removed, and an unterminated string, a dangling `:`, and unclosed brackets
+/// are completed. `None` when that does not make it parse.
+fn repair_json(text: &str) -> Option<String> {
+    let start = text.find(['{', '['])?;
+    let mut repaired = String::with_capacity(text.len() - start);
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text[start..].chars() {
+        if in_string {
+            repaired.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' => {
+                if closers.pop() != Some(c) {
+                    return None;
+                }
+                trim_trailing_comma(&mut repaired);
+            }
+            _ => {}
+        }
+        repaired.push(c);
+        if closers.is_empty() {
+            break;
+        }
+    }
+
+    if in_string {
+        if escaped {
+            repaired.pop();
+        }
+        repaired.push('"');
+    }
+    trim_trailing_comma(&mut repaired);
+    if repaired.ends_with(':') {
+        repaired.push_str("null");
+    }
+    repaired.extend(closers.into_iter().rev());
+    serde_json::from_str::<Value>(&repaired)
+        .is_ok()
+        .then_some(repaired)
+}
+
+fn trim_trailing_comma(json: &mut String) {
+    let end = json.trim_end().len();
+    if json[..end].ends_with(',') {
+        json.truncate(end - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn completion(content: &str) -> Vec<u8> {
+        json!({
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}}]
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn schemas_are_checked_against_the_message_content() {
+        let request = json!({
+            "model": "m",
+            "messages": [],
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "answer",
+                    "strict": true,
+                    "schema": {
+                        "type": "object",
+                        "properties": {"answer": {"type": "integer"}},
+                        "required": ["answer"]
+                    }
+                }
+            }
+        });
+        let format = ResponseFormat::for_request(request.to_string().as_bytes()).unwrap();
+
+        assert!(format.mismatch(&completion(r#"{"answer": 42}"#)).is_none());
+        let mismatch = format.mismatch(&completion(r#"{"answer": "42"}"#)).unwrap();
+        assert!(
+            mismatch.contains("schema 'answer' at '/answer'"),
+            "{}",
+            mismatch
+        );
+        let mismatch = format.mismatch(&completion("The answer is 42")).unwrap();
+        assert!(mismatch.contains("not valid JSON"), "{}", mismatch);
+    }
+
+    #[test]
+    fn json_objects_and_plain_text_requests() {
+        let json_object = br#"{"response_format": {"type": "json_object"}}"#;
+        let format = ResponseFormat::for_request(json_object).unwrap();
+        assert!(format.mismatch(&completion("{}")).is_none());
+        assert!(format.mismatch(&completion("[1, 2]")).is_some());
+
+        let tool_call = json!({
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": null}}]
+        });
+        assert!(format.mismatch(tool_call.to_string().as_bytes()).is_none());
+
+        assert!(ResponseFormat::for_request(br#"{"response_format": {"type": "text"}}"#).is_none());
+        assert!(ResponseFormat::for_request(br#"{"messages": []}"#).is_none());
+    }
+
+    #[test]
+    fn broken_json_is_repaired() {
+        let cases = [
+            ("```json\n{\"a\": 1}\n```", r#"{"a": 1}"#),
+            (
+                "Here you go: {\"a\": [1, 2,]} Hope that helps!",
+                r#"{"a": [1, 2]}"#,
+            ),
+            (r#"{"a": "cut of"#, r#"{"a": "cut of"}"#),
+            (r#"{"a": {"b": ["x", "y\"#, r#"{"a": {"b": ["x", "y"]}}"#),
+            (r#"{"a": 1, "b":"#, r#"{"a": 1, "b":null}"#),
+        ];
+        for (broken, repaired) in cases {
+            assert_eq!(repair_json(broken).as_deref(), Some(repaired), "{}", broken);
+        }
+        assert!(repair_json("no JSON here").is_none());
+        assert!(repair_json(r#"{"a": 1]"#).is_none());
+
+        let format = ResponseFormat::JsonObject;
+        let body = format.repair(&completion("{\"ok\": true")).unwrap();
+        assert!(format.mismatch(&body).is_none());
+        assert!(format.repair(&completion("{}")).is_none());
+    }
+}
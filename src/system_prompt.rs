@@ -0,0 +1,130 @@
+use axum::body::Bytes;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// How an operator's system prompt combines with the client's own system
+/// messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemPromptMode {
+    /// Put it before the client's messages, keeping the client's system
+    /// messages
+    #[default]
+    Prepend,
+    /// Drop the client's system and developer messages in its favor
+    Replace,
+}
+
+/// A system prompt the proxy adds to chat completion requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SystemPrompt {
+    pub(crate) content: String,
+    #[serde(default)]
+    pub(crate) mode: SystemPromptMode,
+}
+
+/// Puts `prompts` first in a chat completion request's messages, in order.
+/// When any of them replaces, the client's system and developer messages are
+/// dropped first. Bodies without a `messages` array are left alone.
+pub(crate) fn apply_system_prompts(prompts: &[&SystemPrompt], body: Bytes) -> Bytes {
+    if prompts.is_empty() {
+        return body;
+    }
+    let Ok(Value::Object(mut request)) = serde_json::from_slice::<Value>(&body) else {
+        return body;
+    };
+    let Some(Value::Array(messages)) = request.get_mut("messages") else {
+        return body;
+    };
+
+    if prompts
+        .iter()
+        .any(|prompt| prompt.mode == SystemPromptMode::Replace)
+    {
+        messages
+            .retain(|message| !matches!(message["role"].as_str(), Some("system" | "developer")));
+    }
+    let injected = prompts
+        .iter()
+        .map(|prompt| json!({"role": "system", "content": prompt.content}));
+    messages.splice(0..0, injected);
+
+    serde_json::to_vec(&request)
+        .map(Bytes::from)
+        .unwrap_or(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(content: &str, mode: SystemPromptMode) -> SystemPrompt {
+        SystemPrompt {
+            content: content.to_string(),
+            mode,
+        }
+    }
+
+    fn roles_and_contents(body: &Bytes) -> Vec<(String, String)> {
+        let request: Value = serde_json::from_slice(body).unwrap();
+        request["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| {
+                (
+                    message["role"].as_str().unwrap().to_string(),
+                    message["content"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    const REQUEST: &[u8] = br#"{"model":"m","messages":[
+        {"role":"system","content":"Talk like a pirate."},
+        {"role":"user","content":"Hi"}]}"#;
+
+    #[test]
+    fn prepends_prompts_in_order() {
+        let organization = prompt("Follow the policy.", SystemPromptMode::Prepend);
+        let team = prompt("Answer in French.", SystemPromptMode::Prepend);
+        let body = apply_system_prompts(&[&organization, &team], Bytes::from_static(REQUEST));
+
+        let messages = roles_and_contents(&body);
+        let contents: Vec<&str> = messages
+            .iter()
+            .map(|(_, content)| content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                "Follow the policy.",
+                "Answer in French.",
+                "Talk like a pirate.",
+                "Hi"
+            ]
+        );
+        assert_eq!(messages[0].0, "system");
+    }
+
+    #[test]
+    fn replacing_drops_the_clients_system_messages() {
+        let organization = prompt("Follow the policy.", SystemPromptMode::Replace);
+        let body = apply_system_prompts(&[&organization], Bytes::from_static(REQUEST));
+
+        assert_eq!(
+            roles_and_contents(&body),
+            [
+                ("system".to_string(), "Follow the policy.".to_string()),
+                ("user".to_string(), "Hi".to_string())
+            ]
+        );
+
+        let opaque = Bytes::from_static(br#"{"model":"m","input":"Hi"}"#);
+        assert_eq!(
+            apply_system_prompts(&[&organization], opaque.clone()),
+            opaque
+        );
+    }
+}
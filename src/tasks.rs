@@ -0,0 +1,32 @@
+use std::{future::Future, sync::Weak, time::Duration};
+use tracing::warn;
+
+/// Spawns `task` on the current Tokio runtime, or logs `warning` when there
+/// is none
+pub(crate) fn spawn_or_warn(task: impl Future<Output = ()> + Send + 'static, warning: &str) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => {
+            runtime.spawn(task);
+        }
+        Err(_) => warn!("{}", warning),
+    }
+}
+
+/// Calls `reload` every `interval` until `watched` is dropped. Failures are
+/// logged followed by `keeping`, which says what is used instead.
+pub(crate) async fn reload_periodically<T>(
+    watched: Weak<T>,
+    interval: Duration,
+    reload: fn(&T) -> anyhow::Result<bool>,
+    keeping: &'static str,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(watched) = watched.upgrade() else {
+            return;
+        };
+        if let Err(reload_error) = reload(&watched) {
+            warn!("{:#}; {}", reload_error, keeping);
+        }
+    }
+}
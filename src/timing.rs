@@ -0,0 +1,268 @@
+use crate::metrics::Metrics;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::Value;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Reports where a request's time went, with `--server-timing`
+pub(crate) const SERVER_TIMING_HEADER: HeaderName = HeaderName::from_static("server-timing");
+
+/// Where a backend request's time went until its response headers, kept in
+/// the backend response's extensions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct BackendTiming {
+    /// Getting an attested session, close to zero for a pooled one
+    pub(crate) session: Duration,
+    /// Waiting for the backend's response headers after that
+    pub(crate) response: Duration,
+}
+
+/// Adds a backend request's timing to its response headers, in milliseconds
+pub(crate) fn add_server_timing(headers: &mut HeaderMap, timing: BackendTiming) {
+    let value = format!(
+        "attest;desc=\"Attested session\";dur={:.1}, backend;desc=\"Backend response\";dur={:.1}",
+        millis(timing.session),
+        millis(timing.response)
+    );
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.append(SERVER_TIMING_HEADER, value);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// A request to warn about when it takes longer than `--slow-request-ms` to
+/// answer, or for a stream, to produce its first token
+pub(crate) struct SlowRequest {
+    pub(crate) threshold: Duration,
+    pub(crate) path: String,
+    pub(crate) model: Option<String>,
+    /// The virtual key's ID or a hint of the API key
+    pub(crate) key: Option<String>,
+    /// The backend's timing, unless the request never reached one
+    pub(crate) backend: Option<BackendTiming>,
+}
+
+impl SlowRequest {
+    /// Logs the request if `elapsed`, the time until `milestone` since the
+    /// request arrived, is over the threshold
+    pub(crate) fn check(&self, milestone: &str, elapsed: Duration) {
+        if let Some(message) = self.describe(milestone, elapsed) {
+            warn!("{}", message);
+        }
+    }
+
+    fn describe(&self, milestone: &str, elapsed: Duration) -> Option<String> {
+        if elapsed <= self.threshold {
+            return None;
+        }
+        let mut message = format!(
+            "Slow request: {} model={} key={} {} after {} ms",
+            self.path,
+            self.model.as_deref().unwrap_or("-"),
+            self.key.as_deref().unwrap_or("-"),
+            milestone,
+            elapsed.as_millis()
+        );
+        if let Some(backend) = self.backend {
+            message.push_str(&format!(
+                " (attested session {} ms, backend response {} ms)",
+                backend.session.as_millis(),
+                backend.response.as_millis()
+            ));
+        }
+        Some(message)
+    }
+}
+
+/// How a streamed chat completion's tokens arrived
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StreamTiming {
+    /// From receiving the request to the first token
+    pub(crate) first_token: Duration,
+    /// From the first token to the last
+    pub(crate) generation: Duration,
+    /// Gaps between consecutive chunks carrying tokens
+    pub(crate) chunk_gaps: u64,
+    pub(crate) chunk_gap_total: Duration,
+}
+
+/// Times a streamed chat completion's chunks as they pass through, recording
+/// them under the model the backend names once the stream ends
+pub(crate) struct StreamTimer {
+    metrics: Arc<Metrics>,
+    started_at: Instant,
+    /// Checked once the first token arrives, or the stream ends without one
+    slow: Option<SlowRequest>,
+    model: Option<String>,
+    first_token: Option<Instant>,
+    last_token: Option<Instant>,
+    chunk_gaps: u64,
+    chunk_gap_total: Duration,
+}
+
+impl StreamTimer {
+    /// `started_at` is when the proxy received the request
+    pub(crate) fn new(
+        metrics: Arc<Metrics>,
+        started_at: Instant,
+        slow: Option<SlowRequest>,
+    ) -> Self {
+        Self {
+            metrics,
+            started_at,
+            slow,
+            model: None,
+            first_token: None,
+            last_token: None,
+            chunk_gaps: 0,
+            chunk_gap_total: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn record(&mut self, event: &Value) {
+        self.record_at(event, Instant::now());
+    }
+
+    fn record_at(&mut self, event: &Value, now: Instant) {
+        if self.model.is_none() {
+            self.model = event["model"].as_str().map(str::to_string);
+        }
+        if !carries_tokens(event) {
+            return;
+        }
+        if let Some(last_token) = self.last_token {
+            self.chunk_gaps += 1;
+            self.chunk_gap_total += now.saturating_duration_since(last_token);
+        }
+        if self.first_token.is_none() {
+            self.first_token = Some(now);
+            if let Some(slow) = self.slow.take() {
+                slow.check(
+                    "first token",
+                    now.saturating_duration_since(self.started_at),
+                );
+            }
+        }
+        self.last_token = Some(now);
+    }
+
+    /// The stream's timing, unless it never produced a token
+    fn timing(&self) -> Option<StreamTiming> {
+        let (first_token, last_token) = (self.first_token?, self.last_token?);
+        Some(StreamTiming {
+            first_token: first_token.saturating_duration_since(self.started_at),
+            generation: last_token.saturating_duration_since(first_token),
+            chunk_gaps: self.chunk_gaps,
+            chunk_gap_total: self.chunk_gap_total,
+        })
+    }
+}
+
+impl Drop for StreamTimer {
+    /// Streams the client abandoned are recorded for as far as they got
+    fn drop(&mut self) {
+        if let Some(slow) = self.slow.take() {
+            slow.check("stream ended without tokens", self.started_at.elapsed());
+        }
+        if let (Some(model), Some(timing)) = (&self.model, self.timing()) {
+            self.metrics.record_stream(model, &timing);
+        }
+    }
+}
+
+/// Whether a chat completion chunk carries generated text, reasoning, or a
+/// tool call, as opposed to only a role, finish reason, or usage
+fn carries_tokens(event: &Value) -> bool {
+    let Some(choices) = event["choices"].as_array() else {
+        return false;
+    };
+    choices.iter().any(|choice| {
+        let delta = &choice["delta"];
+        ["content", "reasoning_content", "reasoning"]
+            .iter()
+            .any(|field| delta[field].as_str().is_some_and(|text| !text.is_empty()))
+            || delta["tool_calls"]
+                .as_array()
+                .is_some_and(|calls| !calls.is_empty())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chunk(delta: Value) -> Value {
+        json!({"model": "llama3-3-70b", "choices": [{"index": 0, "delta": delta}]})
+    }
+
+    #[test]
+    fn times_the_chunks_that_carry_tokens() {
+        let started_at = Instant::now();
+        let at = |millis| started_at + Duration::from_millis(millis);
+        let mut timer = StreamTimer::new(Arc::default(), started_at, None);
+
+        timer.record_at(&chunk(json!({"role": "assistant", "content": ""})), at(100));
+        assert_eq!(timer.timing(), None);
+        timer.record_at(&chunk(json!({"content": "Hel"})), at(250));
+        timer.record_at(&chunk(json!({"content": "lo"})), at(300));
+        timer.record_at(&chunk(json!({"tool_calls": [{"index": 0}]})), at(400));
+        timer.record_at(&chunk(json!({})), at(450));
+        timer.record_at(
+            &json!({"choices": [], "usage": {"completion_tokens": 3}}),
+            at(460),
+        );
+
+        assert_eq!(timer.model.as_deref(), Some("llama3-3-70b"));
+        assert_eq!(
+            timer.timing(),
+            Some(StreamTiming {
+                first_token: Duration::from_millis(250),
+                generation: Duration::from_millis(150),
+                chunk_gaps: 2,
+                chunk_gap_total: Duration::from_millis(150),
+            })
+        );
+    }
+
+    #[test]
+    fn server_timing_reports_milliseconds() {
+        let mut headers = HeaderMap::new();
+        let timing = BackendTiming {
+            session: Duration::from_micros(1_240),
+            response: Duration::from_millis(340),
+        };
+        add_server_timing(&mut headers, timing);
+        assert_eq!(
+            headers[SERVER_TIMING_HEADER],
+            "attest;desc=\"Attested session\";dur=1.2, backend;desc=\"Backend response\";dur=340.0"
+        );
+    }
+
+    #[test]
+    fn slow_requests_are_described_with_their_timing() {
+        let slow = SlowRequest {
+            threshold: Duration::from_secs(2),
+            path: "/v1/chat/completions".to_string(),
+            model: Some("llama3-3-70b".to_string()),
+            key: Some("key_1a2b".to_string()),
+            backend: Some(BackendTiming {
+                session: Duration::from_millis(800),
+                response: Duration::from_millis(1_900),
+            }),
+        };
+        assert_eq!(slow.describe("first token", Duration::from_secs(2)), None);
+        assert_eq!(
+            slow.describe("first token", Duration::from_millis(3_100))
+                .unwrap(),
+            "Slow request: /v1/chat/completions model=llama3-3-70b key=key_1a2b first token \
+             after 3100 ms (attested session 800 ms, backend response 1900 ms)"
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use crate::{
+    config::OpenAIError,
+    proxy::ProxyError,
+    wire::{self, Usage, UsageChunk},
+};
+use axum::{body::Bytes, http::StatusCode, Json};
+use serde_json::{json, Value};
+use std::sync::LazyLock;
+use tiktoken_rs::CoreBPE;
+
+/// Maple's models use their own tokenizers, so counts from this OpenAI
+/// encoding are close estimates rather than exact billing figures
+const ENCODING: &str = "cl100k_base";
+/// Every chat message is wrapped in formatting tokens
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_PER_NAME: usize = 1;
+/// The assistant reply is primed with `<|start|>assistant<|message|>`
+const REPLY_PRIMING_TOKENS: usize = 3;
+/// Tool definitions are rendered into the prompt with some framing
+const TOKENS_PER_TOOL: usize = 7;
+
+static BPE: LazyLock<CoreBPE> =
+    LazyLock::new(|| tiktoken_rs::cl100k_base().expect("bundled cl100k_base encoding loads"));
+
+/// Splits text into `cl100k_base` token ids. Special token markers in the text
+/// are encoded as ordinary text.
+pub fn tokenize(text: &str) -> Vec<u32> {
+    BPE.encode_ordinary(text)
+}
+
+pub fn count_tokens(text: &str) -> usize {
+    BPE.encode_ordinary(text).len()
+}
+
+/// Estimates the prompt tokens of OpenAI chat `messages`, including the
+/// per-message formatting overhead
+pub fn count_message_tokens(messages: &[Value]) -> usize {
+    messages
+        .iter()
+        .map(|message| {
+            let name_tokens = match message.get("name").and_then(Value::as_str) {
+                Some(name) => count_tokens(name) + TOKENS_PER_NAME,
+                None => 0,
+            };
+            TOKENS_PER_MESSAGE + count_tokens(&message_text(message)) + name_tokens
+        })
+        .sum::<usize>()
+        + REPLY_PRIMING_TOKENS
+}
+
+/// The text of a message that reaches the model: its role, text content
+/// parts, and tool call names and arguments
+fn message_text(message: &Value) -> String {
+    let mut text = String::new();
+    if let Some(role) = message.get("role").and_then(Value::as_str) {
+        text.push_str(role);
+    }
+    match message.get("content") {
+        Some(Value::String(content)) => text.push_str(content),
+        Some(Value::Array(parts)) => {
+            for part in parts {
+                if let Some(part_text) = part.get("text").and_then(Value::as_str) {
+                    text.push_str(part_text);
+                }
+            }
+        }
+        _ => {}
+    }
+    for call in message
+        .get("tool_calls")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        for field in ["name", "arguments"] {
+            if let Some(value) = call["function"].get(field).and_then(Value::as_str) {
+                text.push_str(value);
+            }
+        }
+    }
+    text
+}
+
+/// Estimates the prompt tokens taken by a request's `tools`, whose function
+/// definitions the backend renders into the prompt
+fn count_tool_tokens(tools: &[Value]) -> usize {
+    tools
+        .iter()
+        .map(|tool| TOKENS_PER_TOOL + count_tokens(&tool["function"].to_string()))
+        .sum()
+}
+
+/// `/v1/tokenize` counts tokens locally, without contacting a backend. The
+/// body holds either `input` (a string or array of strings) or chat
+/// `messages`, and optionally the `model` it is meant for.
+pub(crate) async fn tokenize_text(body: Bytes) -> Result<Json<Value>, ProxyError> {
+    let request: Value = serde_json::from_slice(&body)
+        .map_err(|_| invalid_request("The request body must be a JSON object.", None))?;
+    let texts = match (request.get("input"), request.get("messages")) {
+        (Some(input), None) => Texts::Input(input_texts(input).ok_or_else(|| {
+            invalid_request(
+                "'input' must be a string or an array of strings.",
+                Some("input"),
+            )
+        })?),
+        (None, Some(Value::Array(messages))) => Texts::Messages(messages.clone()),
+        (None, Some(_)) => {
+            return Err(invalid_request(
+                "'messages' must be an array.",
+                Some("messages"),
+            ))
+        }
+        _ => {
+            return Err(invalid_request(
+                "Provide exactly one of 'input' or 'messages'.",
+                None,
+            ))
+        }
+    };
+    let model = request.get("model").cloned().unwrap_or(Value::Null);
+
+    let response = tokio::task::spawn_blocking(move || texts.tokenize(model))
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(OpenAIError::server_error("Tokenization failed")),
+            )
+        })?;
+    Ok(Json(response))
+}
+
+enum Texts {
+    Input(Vec<String>),
+    Messages(Vec<Value>),
+}
+
+impl Texts {
+    fn tokenize(self, model: Value) -> Value {
+        let (data, count): (Vec<Value>, usize) = match self {
+            Texts::Input(inputs) => {
+                let data = token_data(inputs.iter().map(String::as_str));
+                let count = inputs.iter().map(|input| count_tokens(input)).sum();
+                (data, count)
+            }
+            Texts::Messages(messages) => {
+                let texts: Vec<String> = messages.iter().map(message_text).collect();
+                (
+                    token_data(texts.iter().map(String::as_str)),
+                    count_message_tokens(&messages),
+                )
+            }
+        };
+
+        json!({
+            "object": "tokenize",
+            "model": model,
+            "encoding": ENCODING,
+            "count": count,
+            "data": data,
+        })
+    }
+}
+
+fn token_data<'a>(texts: impl Iterator<Item = &'a str>) -> Vec<Value> {
+    texts
+        .enumerate()
+        .map(|(index, text)| {
+            let tokens = tokenize(text);
+            json!({"index": index, "count": tokens.len(), "tokens": tokens})
+        })
+        .collect()
+}
+
+/// Estimates the prompt tokens of a chat completion request, including its
+/// tools, or of an embeddings request's input
+pub(crate) fn count_prompt_tokens(request: &Value) -> Option<usize> {
+    if let Some(messages) = request.get("messages").and_then(Value::as_array) {
+        let tools = request
+            .get("tools")
+            .and_then(Value::as_array)
+            .map_or(0, |tools| count_tool_tokens(tools));
+        return Some(count_message_tokens(messages) + tools);
+    }
+
+    let inputs = input_texts(request.get("input")?)?;
+    Some(inputs.iter().map(|input| count_tokens(input)).sum())
+}
+
+fn input_texts(input: &Value) -> Option<Vec<String>> {
+    match input {
+        Value::String(input) => Some(vec![input.clone()]),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| item.as_str().map(str::to_string))
+            .collect(),
+        _ => None,
+    }
+}
+
+fn invalid_request(message: &str, param: Option<&str>) -> ProxyError {
+    let mut error = OpenAIError::invalid_request_error(message);
+    if let Some(param) = param {
+        error = error.with_param(param);
+    }
+    (StatusCode::BAD_REQUEST, Json(error))
+}
+
+/// Estimates usage for a streamed chat completion that asked for it with
+/// `stream_options.include_usage`, for backends that never send it
+pub(crate) struct StreamUsageEstimator {
+    prompt_tokens: usize,
+    completion: String,
+    last_chunk: Option<Value>,
+    finished: bool,
+}
+
+impl StreamUsageEstimator {
+    /// Returns `None` unless the request streams and asked for usage
+    pub(crate) fn for_request(body: &[u8]) -> Option<Self> {
+        let request: Value = serde_json::from_slice(body).ok()?;
+        let include_usage = request["stream"].as_bool() == Some(true)
+            && request["stream_options"]["include_usage"].as_bool() == Some(true);
+        if !include_usage {
+            return None;
+        }
+
+        if !request["messages"].is_array() {
+            return None;
+        }
+        Some(Self {
+            prompt_tokens: count_prompt_tokens(&request)?,
+            completion: String::new(),
+            last_chunk: None,
+            finished: false,
+        })
+    }
+
+    pub(crate) fn observe(&mut self, chunk: &Value) {
+        if chunk.get("usage").is_some_and(Value::is_object) {
+            self.finished = true;
+        }
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            let delta = &choice["delta"];
+            for field in ["content", "reasoning_content"] {
+                if let Some(text) = delta[field].as_str() {
+                    self.completion.push_str(text);
+                }
+            }
+            for call in delta["tool_calls"].as_array().into_iter().flatten() {
+                for field in ["name", "arguments"] {
+                    if let Some(text) = call["function"][field].as_str() {
+                        self.completion.push_str(text);
+                    }
+                }
+            }
+        }
+        self.last_chunk = Some(chunk.clone());
+    }
+
+    /// The final usage chunk, unless the backend already sent one or it was
+    /// already produced
+    pub(crate) fn usage_chunk(&mut self) -> Option<Value> {
+        if self.finished {
+            return None;
+        }
+        self.finished = true;
+
+        let completion_tokens = count_tokens(&self.completion);
+        let last_chunk = self.last_chunk.take().unwrap_or_default();
+        let usage = Usage::completion(self.prompt_tokens as u64, completion_tokens as u64);
+        Some(wire::to_value(&UsageChunk::after(&last_chunk, usage)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_cl100k_tokens() {
+        assert_eq!(tokenize("hello world"), vec![15339, 1917]);
+        assert_eq!(count_tokens("hello world"), 2);
+        assert_eq!(count_tokens(""), 0);
+        assert_eq!(
+            count_tokens("<|endoftext|>"),
+            tokenize("<|endoftext|>").len()
+        );
+    }
+
+    #[test]
+    fn message_counts_include_formatting_overhead() {
+        let messages = vec![json!({"role": "user", "content": "hello world"})];
+        assert_eq!(count_message_tokens(&messages), 3 + 1 + 2 + 3);
+
+        let named = vec![json!({
+            "role": "user",
+            "name": "alice",
+            "content": [{"type": "text", "text": "hello world"}]
+        })];
+        assert_eq!(
+            count_message_tokens(&named),
+            count_message_tokens(&messages) + count_tokens("alice") + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn tokenize_endpoint_counts_inputs_and_messages() {
+        let Json(inputs) = tokenize_text(Bytes::from_static(
+            br#"{"model":"llama3-3-70b","input":["hello world","hello"]}"#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(inputs["count"], 3);
+        assert_eq!(inputs["model"], "llama3-3-70b");
+        assert_eq!(inputs["data"][0]["tokens"], json!([15339, 1917]));
+        assert_eq!(inputs["data"][1]["count"], 1);
+
+        let Json(messages) = tokenize_text(Bytes::from_static(
+            br#"{"messages":[{"role":"user","content":"hello world"}]}"#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(messages["count"], 9);
+
+        for body in [
+            r#"{}"#,
+            r#"{"input":"a","messages":[]}"#,
+            r#"{"input":[1,2]}"#,
+            "not json",
+        ] {
+            let (status, _) = tokenize_text(Bytes::from(body)).await.unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+        }
+    }
+
+    #[test]
+    fn estimates_usage_only_when_requested_and_missing() {
+        let request = br#"{"stream":true,"stream_options":{"include_usage":true},"messages":[{"role":"user","content":"hello world"}]}"#;
+        assert!(StreamUsageEstimator::for_request(br#"{"stream":true,"messages":[]}"#).is_none());
+
+        let mut estimator = StreamUsageEstimator::for_request(request).unwrap();
+        estimator.observe(&json!({
+            "id": "chatcmpl-1",
+            "created": 1,
+            "model": "llama3-3-70b",
+            "choices": [{"index": 0, "delta": {"content": "hello"}}]
+        }));
+        estimator.observe(&json!({"choices": [{"index": 0, "delta": {"content": " world"}}]}));
+
+        let usage = estimator.usage_chunk().unwrap();
+        assert_eq!(usage["choices"], json!([]));
+        assert_eq!(
+            usage["usage"],
+            json!({"prompt_tokens": 9, "completion_tokens": 2, "total_tokens": 11})
+        );
+        assert!(estimator.usage_chunk().is_none());
+
+        let mut reported = StreamUsageEstimator::for_request(request).unwrap();
+        reported.observe(&json!({"choices": [], "usage": {"prompt_tokens": 5}}));
+        assert!(reported.usage_chunk().is_none());
+    }
+
+    #[test]
+    fn usage_estimates_include_tools_and_tool_calls() {
+        let function = json!({"name": "get_weather", "parameters": {"type": "object"}});
+        let request = json!({
+            "stream": true,
+            "stream_options": {"include_usage": true},
+            "messages": [{"role": "user", "content": "hello world"}],
+            "tools": [{"type": "function", "function": function}]
+        });
+
+        let body = serde_json::to_vec(&request).unwrap();
+        let mut estimator = StreamUsageEstimator::for_request(&body).unwrap();
+        estimator.observe(&json!({"choices": [{"index": 0, "delta": {"tool_calls": [
+            {"index": 0, "function": {"name": "get_weather", "arguments": "{\"city\""}}
+        ]}}]}));
+        estimator.observe(&json!({"choices": [{"index": 0, "delta": {"tool_calls": [
+            {"index": 0, "function": {"arguments": ":\"Paris\"}"}}
+        ]}}]}));
+
+        let usage = &estimator.usage_chunk().unwrap()["usage"];
+        assert_eq!(
+            usage["prompt_tokens"],
+            9 + TOKENS_PER_TOOL + count_tokens(&function.to_string())
+        );
+        assert_eq!(
+            usage["completion_tokens"],
+            count_tokens(r#"get_weather{"city":"Paris"}"#)
+        );
+    }
+}
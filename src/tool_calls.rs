@@ -0,0 +1,232 @@
+use axum::body::Bytes;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Calls per choice whose arguments are tracked; deltas with a higher
+/// `index` are only given a `type` and string arguments
+const MAX_TRACKED_CALLS: usize = 128;
+
+/// Makes a tool call spec-compliant where a backend gets it slightly wrong:
+/// `arguments` sent as a JSON object instead of a string, and `type` left out
+fn normalize_call(call: &mut Map<String, Value>) {
+    if let Some(function) = call.get_mut("function").and_then(Value::as_object_mut) {
+        if let Some(arguments) = function.get_mut("arguments") {
+            if arguments.is_object() || arguments.is_array() {
+                *arguments = Value::String(arguments.to_string());
+            }
+        }
+    }
+    if call.get("id").is_some_and(Value::is_string) {
+        call.entry("type")
+            .or_insert_with(|| Value::from("function"));
+    }
+}
+
+/// The `tool_calls` of each choice's `message` or `delta`, by choice index
+fn tool_calls_mut<'a>(
+    completion: &'a mut Value,
+    message_field: &'static str,
+) -> impl Iterator<Item = (u64, &'a mut Vec<Value>)> {
+    completion
+        .get_mut("choices")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(move |choice| {
+            let index = choice["index"].as_u64().unwrap_or(0);
+            let calls = choice
+                .get_mut(message_field)?
+                .get_mut("tool_calls")?
+                .as_array_mut()?;
+            Some((index, calls))
+        })
+}
+
+/// Rewrites a non-streaming chat completion's tool calls, or returns `None`
+/// when it is not a JSON object
+pub(crate) fn normalize_completion(body: &[u8]) -> Option<Bytes> {
+    let mut completion: Value = serde_json::from_slice(body).ok()?;
+    if !completion.is_object() {
+        return None;
+    }
+    for (_, calls) in tool_calls_mut(&mut completion, "message") {
+        for call in calls.iter_mut().filter_map(Value::as_object_mut) {
+            normalize_call(call);
+        }
+    }
+    serde_json::to_vec(&completion).ok().map(Bytes::from)
+}
+
+/// What one choice's stream has sent of a tool call so far
+#[derive(Default)]
+struct StreamedCall {
+    id: Option<String>,
+    arguments: String,
+}
+
+/// Fixes the tool call deltas of one chat completion stream so SDKs, which
+/// accumulate them by `index`, rebuild the calls the backend meant:
+/// - deltas without an `index` get one, a new call starting with each new
+///   `id` and other deltas continuing the latest call
+/// - `arguments` repeated in full each time, rather than in pieces, are cut
+///   down to the part not sent yet
+/// - `arguments` objects become strings and `type` is filled in
+#[derive(Default)]
+pub(crate) struct ToolCallDeltas {
+    /// The calls seen so far, by choice index
+    choices: HashMap<u64, Vec<StreamedCall>>,
+}
+
+impl ToolCallDeltas {
+    pub(crate) fn normalize_chunk(&mut self, chunk: &mut Value) {
+        for (choice, calls) in tool_calls_mut(chunk, "delta") {
+            let streamed = self.choices.entry(choice).or_default();
+            for call in calls.iter_mut().filter_map(Value::as_object_mut) {
+                normalize_call(call);
+                let id = call.get("id").and_then(Value::as_str).map(str::to_string);
+                let index = match call.get("index").and_then(Value::as_u64) {
+                    Some(index) => usize::try_from(index).unwrap_or(usize::MAX),
+                    None => {
+                        let continues_latest = streamed
+                            .last()
+                            .is_some_and(|latest| id.is_none() || latest.id == id);
+                        let index = streamed.len() - usize::from(continues_latest);
+                        call.insert("index".to_string(), Value::from(index));
+                        index
+                    }
+                };
+                if index >= MAX_TRACKED_CALLS {
+                    continue;
+                }
+                if streamed.len() <= index {
+                    streamed.resize_with(index + 1, StreamedCall::default);
+                }
+                let state = &mut streamed[index];
+                if id.is_some() {
+                    state.id = id;
+                }
+
+                let Some(arguments) = call
+                    .get_mut("function")
+                    .and_then(|function| function.get_mut("arguments"))
+                else {
+                    continue;
+                };
+                let Some(fragment) = arguments.as_str() else {
+                    continue;
+                };
+                let fragment = match unsent_arguments(&state.arguments, fragment) {
+                    Some(unsent) => unsent.to_string(),
+                    None => fragment.to_string(),
+                };
+                state.arguments.push_str(&fragment);
+                *arguments = Value::String(fragment);
+            }
+        }
+    }
+}
+
+/// The part of `fragment` not sent yet when it repeats everything `sent`
+/// before it, `None` when it is an ordinary piece. A repeat of complete JSON
+/// arguments, as some backends send with the finishing chunk, leaves nothing.
+fn unsent_arguments<'a>(sent: &str, fragment: &'a str) -> Option<&'a str> {
+    if sent.is_empty() {
+        return None;
+    }
+    let unsent = fragment.strip_prefix(sent)?;
+    if unsent.is_empty() && serde_json::from_str::<Value>(sent).is_err() {
+        return None;
+    }
+    Some(unsent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn delta(tool_calls: Value) -> Value {
+        json!({"id": "c1", "choices": [{"index": 0, "delta": {"tool_calls": tool_calls}}]})
+    }
+
+    fn normalize(deltas: &mut ToolCallDeltas, tool_calls: Value) -> Value {
+        let mut chunk = delta(tool_calls);
+        deltas.normalize_chunk(&mut chunk);
+        chunk["choices"][0]["delta"]["tool_calls"].take()
+    }
+
+    #[test]
+    fn compliant_deltas_are_left_alone() {
+        let mut deltas = ToolCallDeltas::default();
+        let chunks = [
+            json!([{"index": 0, "id": "call_1", "type": "function", "function": {"name": "get", "arguments": ""}}]),
+            json!([{"index": 0, "function": {"arguments": "{\""}}]),
+            json!([{"index": 0, "function": {"arguments": "{\""}}]),
+            json!([{"index": 1, "id": "call_2", "type": "function", "function": {"name": "put", "arguments": "{}"}}]),
+        ];
+        for tool_calls in chunks {
+            assert_eq!(normalize(&mut deltas, tool_calls.clone()), tool_calls);
+        }
+    }
+
+    #[test]
+    fn missing_indexes_follow_the_call_ids() {
+        let mut deltas = ToolCallDeltas::default();
+        let first = normalize(
+            &mut deltas,
+            json!([{"id": "call_1", "function": {"name": "get", "arguments": "{\"a\""}}]),
+        );
+        assert_eq!(first[0]["index"], 0);
+        assert_eq!(first[0]["type"], "function");
+        let continued = normalize(&mut deltas, json!([{"function": {"arguments": ":1}"}}]));
+        assert_eq!(continued[0]["index"], 0);
+        let second = normalize(
+            &mut deltas,
+            json!([{"id": "call_2", "function": {"name": "put", "arguments": "{}"}}]),
+        );
+        assert_eq!(second[0]["index"], 1);
+        let repeated_id = normalize(
+            &mut deltas,
+            json!([{"id": "call_2", "function": {"arguments": ""}}]),
+        );
+        assert_eq!(repeated_id[0]["index"], 1);
+    }
+
+    #[test]
+    fn repeated_arguments_are_cut_to_the_new_part() {
+        let mut deltas = ToolCallDeltas::default();
+        let pieces: Vec<_> = ["{\"city\"", "{\"city\":\"Oslo\"}", "{\"city\":\"Oslo\"}"]
+            .into_iter()
+            .map(|arguments| {
+                let calls = normalize(
+                    &mut deltas,
+                    json!([{"index": 0, "function": {"arguments": arguments}}]),
+                );
+                calls[0]["function"]["arguments"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(pieces, ["{\"city\"", ":\"Oslo\"}", ""]);
+    }
+
+    #[test]
+    fn completions_get_string_arguments() {
+        let body = json!({
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "tool_calls": [{"id": "call_1", "function": {"name": "get", "arguments": {"a": 1}}}]
+                }
+            }]
+        });
+        let normalized = normalize_completion(body.to_string().as_bytes()).unwrap();
+        let completion: Value = serde_json::from_slice(&normalized).unwrap();
+        let call = &completion["choices"][0]["message"]["tool_calls"][0];
+        assert_eq!(call["function"]["arguments"], "{\"a\":1}");
+        assert_eq!(call["type"], "function");
+        assert!(normalize_completion(b"[]").is_none());
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{
+    config::OpenAIError,
+    models,
+    proxy::{proxy_inference_request, ProxyError, ProxyState},
+    validation::{audio_too_large, invalid_value, missing_param},
+};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::Response,
+    Json,
+};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// The `response_format`s OpenAI accepts for transcriptions
+const RESPONSE_FORMATS: [&str; 5] = ["json", "text", "srt", "verbose_json", "vtt"];
+
+/// One part of a `multipart/form-data` body
+pub(crate) struct FormPart {
+    /// The part's header lines, as sent
+    headers: Bytes,
+    pub(crate) name: String,
+    pub(crate) filename: Option<String>,
+    pub(crate) value: Bytes,
+}
+
+impl FormPart {
+    pub(crate) fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.value).ok()
+    }
+}
+
+/// The boundary of a `multipart/form-data` request, or `None` for any other
+/// content type
+pub(crate) fn form_boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let (media_type, params) = content_type.split_once(';')?;
+    if !media_type
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    params
+        .split(';')
+        .find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("boundary")
+                .then(|| value.trim().trim_matches('"').to_string())
+        })
+        .filter(|boundary| !boundary.is_empty())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// A `Content-Disposition` parameter, such as `name` or `filename`
+fn disposition_param(disposition: &str, param: &str) -> Option<String> {
+    disposition.split(';').skip(1).find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case(param)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Splits a `multipart/form-data` body into its parts, or says why it cannot
+pub(crate) fn parse_form(body: &Bytes, boundary: &str) -> Result<Vec<FormPart>, String> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let next_delimiter = [b"\r\n".as_slice(), &delimiter].concat();
+    let mut position = find(body, &delimiter).ok_or("it has no parts")? + delimiter.len();
+    let mut parts = Vec::new();
+    loop {
+        let rest = &body[position..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !rest.starts_with(b"\r\n") {
+            return Err("a boundary is not followed by a line break".to_string());
+        }
+        let start = position + 2;
+        let length =
+            find(&body[start..], &next_delimiter).ok_or("its last part is unterminated")?;
+        let part = body.slice(start..start + length);
+        position = start + length + next_delimiter.len();
+
+        let header_length = find(&part, b"\r\n\r\n").ok_or("a part has no header")?;
+        let headers = part.slice(..header_length);
+        let disposition = String::from_utf8_lossy(&headers)
+            .split("\r\n")
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("content-disposition")
+                    .then(|| value.trim().to_string())
+            })
+            .ok_or("a part has no Content-Disposition header")?;
+        let name = disposition_param(&disposition, "name").ok_or("a part has no field name")?;
+        parts.push(FormPart {
+            headers,
+            filename: disposition_param(&disposition, "filename"),
+            name,
+            value: part.slice(header_length + 4..),
+        });
+    }
+}
+
+/// The form again, with the same boundary
+fn encode_form(parts: &[FormPart], boundary: &str) -> Bytes {
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(&part.headers);
+        body.extend_from_slice(b"\r\n\r\n");
+        body.extend_from_slice(&part.value);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    Bytes::from(body)
+}
+
+/// Checks the fields OpenAI requires or restricts: a `file` of at most
+/// `--audio-max-mb` megabytes, a `model`, and when they are sent a known
+/// `response_format`, an ISO-639-1 `language`, a `temperature` between 0 and
+/// 1, and a boolean `stream`. Other fields are forwarded unchecked.
+fn validate_form(parts: &[FormPart], audio_max_mb: u64) -> Result<(), OpenAIError> {
+    let field = |name: &str| parts.iter().find(|part| part.name == name);
+    let text = |name: &str| field(name).map(|part| part.text().unwrap_or_default());
+
+    let file = field("file").ok_or_else(|| missing_param("file"))?;
+    if file.filename.is_none() || file.value.is_empty() {
+        return Err(
+            OpenAIError::invalid_request_error("'file' must be an uploaded audio file.")
+                .with_param("file"),
+        );
+    }
+    let max_bytes = usize::try_from(audio_max_mb.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX);
+    if file.value.len() > max_bytes {
+        return Err(audio_too_large("file", audio_max_mb));
+    }
+    if text("model").is_none_or(str::is_empty) {
+        return Err(missing_param("model"));
+    }
+
+    if let Some(format) = text("response_format") {
+        if !RESPONSE_FORMATS.contains(&format) {
+            let expected = format!("one of {}", RESPONSE_FORMATS.join(", "));
+            return Err(invalid_value(
+                "response_format",
+                expected,
+                &Value::from(format),
+            ));
+        }
+    }
+    if let Some(language) = text("language") {
+        if language.len() != 2 || !language.chars().all(|c| c.is_ascii_lowercase()) {
+            let expected = "an ISO-639-1 code such as 'en'".to_string();
+            return Err(invalid_value("language", expected, &Value::from(language)));
+        }
+    }
+    if let Some(temperature) = text("temperature") {
+        if !temperature
+            .parse::<f64>()
+            .is_ok_and(|temperature| (0.0..=1.0).contains(&temperature))
+        {
+            let expected = "a number between 0 and 1".to_string();
+            return Err(invalid_value(
+                "temperature",
+                expected,
+                &Value::from(temperature),
+            ));
+        }
+    }
+    if let Some(stream) = text("stream") {
+        if !matches!(stream, "true" | "false") {
+            let expected = "a boolean".to_string();
+            return Err(invalid_value("stream", expected, &Value::from(stream)));
+        }
+    }
+    Ok(())
+}
+
+/// `/v1/audio/transcriptions`, Whisper-style speech to text. The multipart
+/// form is checked, its `model` alias resolved, and it is forwarded otherwise
+/// as sent, so every `response_format`, `srt` and `vtt` included, and
+/// `stream: true` transcript events come back from the backend unchanged.
+pub(crate) async fn transcribe_audio(
+    State(state): State<Arc<ProxyState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ProxyError> {
+    let bad_request = |error: OpenAIError| (StatusCode::BAD_REQUEST, Json(error));
+    let boundary = form_boundary(&headers).ok_or_else(|| {
+        bad_request(OpenAIError::invalid_request_error(
+            "Transcription requests must be sent as multipart/form-data.",
+        ))
+    })?;
+    let mut parts = parse_form(&body, &boundary).map_err(|reason| {
+        bad_request(OpenAIError::invalid_request_error(format!(
+            "We could not parse the multipart body of your request: {}.",
+            reason
+        )))
+    })?;
+    validate_form(&parts, state.config().audio_max_mb).map_err(bad_request)?;
+
+    let model_part = parts
+        .iter_mut()
+        .find(|part| part.name == "model")
+        .expect("validate_form requires a model");
+    let requested = model_part.text().unwrap_or_default().to_string();
+    let tables = state.model_tables();
+    let model = match models::resolve_model_alias(&tables.aliases, &requested) {
+        Some(model) if !state.config().passthrough => model.to_string(),
+        _ => requested.clone(),
+    };
+    let client_key = state.client_key(&headers);
+    let client_models = client_key
+        .as_ref()
+        .map_or(&[][..], |key| key.allowed_models.as_slice());
+    if !models::is_model_allowed(&state.config().allowed_models, &model)
+        || !models::is_model_allowed(client_models, &model)
+        || !models::is_model_listed(state.config(), &model)
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(OpenAIError::model_not_found(&model)),
+        ));
+    }
+
+    let body = if model == requested {
+        body
+    } else {
+        model_part.value = Bytes::from(model);
+        encode_form(&parts, &boundary)
+    };
+    proxy_inference_request(&state, Method::POST, uri, &headers, body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const BOUNDARY: &str = "form-boundary";
+
+    fn form(fields: &[(&str, &str)], file: Option<&[u8]>) -> Bytes {
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    BOUNDARY, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        if let Some(file) = file {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"file\"; \
+                     filename=\"speech.mp3\"\r\nContent-Type: audio/mpeg\r\n\r\n",
+                    BOUNDARY
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(file);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        Bytes::from(body)
+    }
+
+    fn rejection(fields: &[(&str, &str)], file: Option<&[u8]>) -> Value {
+        let body = form(fields, file);
+        let parts = parse_form(&body, BOUNDARY).unwrap();
+        let error = validate_form(&parts, 1).unwrap_err();
+        crate::wire::to_value(&error)["error"].clone()
+    }
+
+    #[test]
+    fn forms_parse_and_encode_back_to_the_same_bytes() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("multipart/form-data; boundary=\"form-boundary\""),
+        );
+        assert_eq!(form_boundary(&headers).as_deref(), Some(BOUNDARY));
+
+        let audio = b"ID3\x04\r\n--not-the-boundary\r\n\0\xff";
+        let body = form(
+            &[("model", "whisper-large-v3"), ("language", "en")],
+            Some(audio),
+        );
+        let parts = parse_form(&body, BOUNDARY).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].text(), Some("whisper-large-v3"));
+        assert_eq!(parts[2].name, "file");
+        assert_eq!(parts[2].filename.as_deref(), Some("speech.mp3"));
+        assert_eq!(&parts[2].value[..], audio);
+        assert!(validate_form(&parts, 1).is_ok());
+        assert_eq!(encode_form(&parts, BOUNDARY), body);
+
+        let cut_off = body.slice(..body.len() - 20);
+        assert!(parse_form(&cut_off, BOUNDARY).is_err());
+    }
+
+    #[test]
+    fn fields_are_checked_like_openai_does() {
+        let audio = Some(&b"ID3"[..]);
+        assert_eq!(rejection(&[("model", "m")], None)["param"], "file");
+        assert_eq!(rejection(&[], audio)["param"], "model");
+        for (field, value) in [
+            ("response_format", "docx"),
+            ("language", "English"),
+            ("temperature", "1.5"),
+            ("stream", "yes"),
+        ] {
+            let error = rejection(&[("model", "m"), (field, value)], audio);
+            assert_eq!(error["param"], field);
+            assert_eq!(error["code"], "invalid_value");
+        }
+        let large = vec![0; 1024 * 1024 + 1];
+        let error = rejection(&[("model", "m")], Some(&large));
+        assert_eq!(error["code"], "audio_too_large");
+    }
+}
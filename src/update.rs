@@ -0,0 +1,332 @@
+use crate::release::{is_newer, RELEASES_URL, USER_AGENT};
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const BINARY_NAME: &str = "maple-proxy";
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct SelfUpdateArgs {
+    /// Only report whether a newer release is available
+    #[arg(long)]
+    pub check: bool,
+
+    /// Install this release tag (e.g. v0.3.0) instead of the latest release
+    #[arg(long, value_name = "TAG")]
+    pub version: Option<String>,
+
+    /// Minisign public key; when set, the release archive must carry a valid
+    /// `.minisig` signature from it
+    #[arg(long, env = "MAPLE_UPDATE_PUBLIC_KEY", value_name = "KEY")]
+    pub public_key: Option<String>,
+
+    /// Running server to restart gracefully (with SIGHUP) after the update
+    #[arg(long, value_name = "PID")]
+    pub restart_pid: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset_url(&self, name: &str) -> Option<&str> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .map(|asset| asset.browser_download_url.as_str())
+    }
+}
+
+/// Replaces the running binary with a verified release from GitHub
+pub async fn self_update(args: &SelfUpdateArgs) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()?;
+
+    let release_url = match &args.version {
+        Some(tag) => format!("{}/tags/{}", RELEASES_URL, tag),
+        None => format!("{}/latest", RELEASES_URL),
+    };
+    let release: Release = serde_json::from_slice(&download(&client, &release_url).await?)
+        .context("unexpected GitHub release response")?;
+
+    let current = env!("CARGO_PKG_VERSION");
+    let newer = is_newer(&release.tag_name, current)
+        .ok_or_else(|| anyhow!("cannot compare release tag {}", release.tag_name))?;
+    if args.check {
+        if newer {
+            println!(
+                "maple-proxy {} is available (running {})",
+                release.tag_name, current
+            );
+        } else {
+            println!("maple-proxy {} is up to date", current);
+        }
+        return Ok(());
+    }
+    if !newer && args.version.is_none() {
+        println!("maple-proxy {} is up to date", current);
+        return Ok(());
+    }
+
+    let asset_name = platform_asset_name()?;
+    let archive_url = release
+        .asset_url(&asset_name)
+        .ok_or_else(|| anyhow!("release {} has no {}", release.tag_name, asset_name))?;
+    let checksum_name = format!("{}.sha256", asset_name);
+    let checksum_url = release
+        .asset_url(&checksum_name)
+        .ok_or_else(|| anyhow!("release {} has no {}", release.tag_name, checksum_name))?;
+
+    println!("Downloading {} {}", release.tag_name, asset_name);
+    let archive = download(&client, archive_url).await?;
+    let checksums = download(&client, checksum_url).await?;
+    verify_checksum(&archive, &String::from_utf8_lossy(&checksums))?;
+
+    if let Some(public_key) = &args.public_key {
+        let signature_name = format!("{}.minisig", asset_name);
+        let signature_url = release
+            .asset_url(&signature_name)
+            .ok_or_else(|| anyhow!("release {} has no {}", release.tag_name, signature_name))?;
+        let signature = download(&client, signature_url).await?;
+        verify_signature(&archive, &String::from_utf8_lossy(&signature), public_key)?;
+    }
+
+    let binary = extract_binary(&archive)?;
+    let executable = std::env::current_exe()?;
+    replace_executable(&executable, &binary)
+        .with_context(|| format!("failed to replace {}", executable.display()))?;
+    println!("Updated {} to {}", executable.display(), release.tag_name);
+
+    if let Some(pid) = args.restart_pid {
+        request_restart(pid)?;
+        println!("Asked process {} to restart", pid);
+    }
+    Ok(())
+}
+
+async fn download(client: &reqwest::Client, url: &str) -> anyhow::Result<Vec<u8>> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// The release archive built for this platform by the release workflow
+fn platform_asset_name() -> anyhow::Result<String> {
+    let platform = match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => "linux-x86_64",
+        ("linux", "aarch64") => "linux-aarch64",
+        ("macos", "aarch64") => "macos-aarch64",
+        (os, arch) => bail!("self-update is not available for {}-{}", os, arch),
+    };
+    Ok(format!("{}-{}.tar.gz", BINARY_NAME, platform))
+}
+
+/// Checks an archive against `sha256sum` output
+fn verify_checksum(archive: &[u8], checksums: &str) -> anyhow::Result<()> {
+    let expected = checksums
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("empty checksum file"))?;
+    let actual = format!("{:x}", Sha256::digest(archive));
+
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!("checksum mismatch: expected {}, got {}", expected, actual);
+    }
+    Ok(())
+}
+
+fn verify_signature(archive: &[u8], signature: &str, public_key: &str) -> anyhow::Result<()> {
+    let public_key = minisign_verify::PublicKey::from_base64(public_key.trim())
+        .map_err(|error| anyhow!("invalid update public key: {}", error))?;
+    let signature = minisign_verify::Signature::decode(signature)
+        .map_err(|error| anyhow!("invalid release signature: {}", error))?;
+
+    public_key
+        .verify(archive, &signature, false)
+        .map_err(|error| anyhow!("release signature verification failed: {}", error))
+}
+
+fn extract_binary(archive: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.file_name() == Some(OsStr::new(BINARY_NAME)) {
+            let mut binary = Vec::new();
+            entry.read_to_end(&mut binary)?;
+            return Ok(binary);
+        }
+    }
+    bail!("release archive does not contain {}", BINARY_NAME)
+}
+
+/// Writes the new binary next to the old one and renames it into place, so
+/// the executable is never left half-written
+#[cfg(unix)]
+fn replace_executable(executable: &Path, binary: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let staged = executable.with_file_name(format!(".{}.update", BINARY_NAME));
+    fs::write(&staged, binary)?;
+    fs::set_permissions(&staged, fs::Permissions::from_mode(0o755))?;
+    fs::rename(&staged, executable).inspect_err(|_| {
+        let _ = fs::remove_file(&staged);
+    })
+}
+
+#[cfg(not(unix))]
+fn replace_executable(_executable: &Path, _binary: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "self-update can only replace the binary on Unix",
+    ))
+}
+
+#[cfg(unix)]
+fn request_restart(pid: i32) -> anyhow::Result<()> {
+    use nix::{
+        sys::signal::{kill, Signal},
+        unistd::Pid,
+    };
+
+    kill(Pid::from_raw(pid), Signal::SIGHUP)
+        .with_context(|| format!("failed to signal process {}", pid))
+}
+
+#[cfg(not(unix))]
+fn request_restart(_pid: i32) -> anyhow::Result<()> {
+    bail!("graceful restart is only supported on Unix")
+}
+
+/// How the server was started, captured before the process sandbox changes
+/// its root or privileges, so it can re-execute itself after an update
+pub struct Restart {
+    executable: PathBuf,
+    args: Vec<OsString>,
+}
+
+impl Restart {
+    pub fn capture() -> io::Result<Self> {
+        Ok(Self {
+            executable: std::env::current_exe()?,
+            args: std::env::args_os().skip(1).collect(),
+        })
+    }
+
+    /// Resolves when a restart is requested with SIGHUP
+    pub async fn requested() {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            match signal(SignalKind::hangup()) {
+                Ok(mut hangup) => {
+                    hangup.recv().await;
+                    return;
+                }
+                Err(error) => tracing::warn!("Cannot listen for restart signals: {}", error),
+            }
+        }
+        std::future::pending::<()>().await
+    }
+
+    /// Replaces this process with a fresh start of the executable. Only
+    /// returns if that fails.
+    #[cfg(unix)]
+    pub fn exec(self) -> io::Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        Err(std::process::Command::new(self.executable)
+            .args(self.args)
+            .exec())
+    }
+
+    #[cfg(not(unix))]
+    pub fn exec(self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "restarting in place is only supported on Unix",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive_with(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::fast(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder.append_data(&mut header, name, contents).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn verifies_sha256sum_output() {
+        let archive = b"release archive";
+        let digest = format!("{:x}", Sha256::digest(archive));
+
+        assert!(verify_checksum(
+            archive,
+            &format!("{}  maple-proxy-linux-x86_64.tar.gz\n", digest)
+        )
+        .is_ok());
+        assert!(verify_checksum(b"tampered", &digest).is_err());
+        assert!(verify_checksum(archive, "").is_err());
+    }
+
+    #[test]
+    fn extracts_binary_from_release_archive() {
+        let archive = archive_with("maple-proxy", b"new binary");
+
+        assert_eq!(extract_binary(&archive).unwrap(), b"new binary");
+        assert!(extract_binary(&archive_with("README.md", b"docs")).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_public_keys() {
+        assert!(verify_signature(b"archive", "not a signature", "not a key").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaces_executable_atomically() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("maple-proxy-update-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let executable = dir.join(BINARY_NAME);
+        fs::write(&executable, b"old binary").unwrap();
+
+        replace_executable(&executable, b"new binary").unwrap();
+
+        assert_eq!(fs::read(&executable).unwrap(), b"new binary");
+        let mode = fs::metadata(&executable).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
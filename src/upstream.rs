@@ -0,0 +1,144 @@
+use crate::proxy::Backend;
+use axum::{
+    body::Bytes,
+    http::{header, HeaderValue, Request},
+};
+use futures::{future::BoxFuture, StreamExt};
+use opensecret::{client::OpenSecretResponseBody, Result as OpenSecretResult};
+
+/// A plain OpenAI-compatible server (vLLM, Ollama, LiteLLM, ...) reached over
+/// HTTP without attestation or transport encryption.
+pub(crate) struct OpenAIUpstream {
+    url: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+}
+
+impl OpenAIUpstream {
+    pub(crate) fn new(url: impl Into<String>, api_key: Option<String>) -> Self {
+        Self {
+            url: url.into(),
+            api_key,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Joins the proxied `/v1/...` path onto the upstream URL, which may be
+    /// given either as the server root or with its own `/v1` suffix.
+    fn request_url(&self, path_and_query: &str) -> String {
+        let base = self.url.trim_end_matches('/');
+        match base.strip_suffix("/v1") {
+            Some(root) => format!("{}{}", root, path_and_query),
+            None => format!("{}{}", base, path_and_query),
+        }
+    }
+
+    async fn send(
+        &self,
+        request: Request<Bytes>,
+    ) -> OpenSecretResult<http::Response<OpenSecretResponseBody>> {
+        let (parts, body) = request.into_parts();
+        let path_and_query = parts
+            .uri
+            .path_and_query()
+            .map(|path_and_query| path_and_query.as_str())
+            .unwrap_or("/");
+
+        let mut headers = parts.headers;
+        if let Some(api_key) = &self.api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {}", api_key)).map_err(|_| {
+                opensecret::Error::Configuration("Invalid upstream API key".to_string())
+            })?;
+            headers.insert(header::AUTHORIZATION, value);
+        }
+
+        let response = self
+            .client
+            .request(parts.method, self.request_url(path_and_query))
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+
+        let mut downstream = http::Response::builder().status(response.status());
+        if let Some(headers) = downstream.headers_mut() {
+            *headers = response.headers().clone();
+        }
+        let body: OpenSecretResponseBody = Box::pin(
+            response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(opensecret::Error::from)),
+        );
+
+        downstream
+            .body(body)
+            .map_err(|error| opensecret::Error::InvalidResponse(error.to_string()))
+    }
+}
+
+impl Backend for OpenAIUpstream {
+    fn send_inference_request(
+        &self,
+        request: Request<Bytes>,
+    ) -> BoxFuture<'_, OpenSecretResult<http::Response<OpenSecretResponseBody>>> {
+        Box::pin(self.send(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Router};
+
+    #[test]
+    fn request_url_accepts_root_or_v1_base() {
+        let root = OpenAIUpstream::new("http://localhost:11434/", None);
+        let versioned = OpenAIUpstream::new("http://localhost:8000/v1", None);
+
+        assert_eq!(
+            root.request_url("/v1/chat/completions"),
+            "http://localhost:11434/v1/chat/completions"
+        );
+        assert_eq!(
+            versioned.request_url("/v1/models?refresh=true"),
+            "http://localhost:8000/v1/models?refresh=true"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_request_with_upstream_key_and_streams_response() {
+        let app = Router::new().route(
+            "/v1/chat/completions",
+            post(|headers: HeaderMap, body: Bytes| async move {
+                let authorization = headers[header::AUTHORIZATION].to_str().unwrap().to_string();
+                (
+                    [("x-upstream", "yes")],
+                    format!("{}|{}", authorization, String::from_utf8_lossy(&body)),
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let upstream =
+            OpenAIUpstream::new(format!("http://{}/v1", addr), Some("local-key".to_string()));
+        let mut request = Request::new(Bytes::from_static(br#"{"model":"llama3.2"}"#));
+        *request.method_mut() = http::Method::POST;
+        *request.uri_mut() = "/v1/chat/completions".parse().unwrap();
+
+        let response = upstream.send_inference_request(request).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers()["x-upstream"], "yes");
+        let body: Vec<Bytes> = response
+            .into_body()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(body.concat(), br#"Bearer local-key|{"model":"llama3.2"}"#);
+    }
+}
@@ -0,0 +1,642 @@
+use crate::{
+    config::OpenAIError,
+    proxy::{
+        ProxyError, ProxyState, CHAT_COMPLETIONS_PATH, EMBEDDINGS_PATH, IMAGE_GENERATIONS_PATH,
+        MODERATIONS_PATH, RERANK_PATH, SPEECH_PATH,
+    },
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/// Sampling parameters and the ranges OpenAI accepts for them
+const NUMBER_RANGES: &[(&str, f64, f64)] = &[
+    ("temperature", 0.0, 2.0),
+    ("top_p", 0.0, 1.0),
+    ("presence_penalty", -2.0, 2.0),
+    ("frequency_penalty", -2.0, 2.0),
+];
+
+/// Chat completion parameters that must be positive integers
+const POSITIVE_INTEGERS: &[&str] = &["n", "max_tokens", "max_completion_tokens"];
+
+/// The `input_audio` formats OpenAI accepts in chat messages
+const AUDIO_FORMATS: [&str; 2] = ["wav", "mp3"];
+
+/// The audio formats and stream formats OpenAI accepts for speech
+const SPEECH_FORMATS: [&str; 6] = ["mp3", "opus", "aac", "flac", "wav", "pcm"];
+const SPEECH_STREAM_FORMATS: [&str; 2] = ["audio", "sse"];
+
+/// The image qualities and response formats OpenAI's image models accept
+const IMAGE_QUALITIES: [&str; 6] = ["auto", "standard", "hd", "low", "medium", "high"];
+const IMAGE_RESPONSE_FORMATS: [&str; 2] = ["url", "b64_json"];
+/// The most images one generation request may ask for
+const MAX_IMAGES: i64 = 10;
+
+/// An inference request body. Bodies over `--max-body-mb` are answered with
+/// an OpenAI-style 413, and ones nesting deeper than `--max-json-depth`,
+/// malformed chat completions and embeddings requests, and audio parts over
+/// `--audio-max-mb` with a 400 naming the offending `param`, instead of
+/// reaching the backend.
+pub(crate) struct ValidatedBody(pub(crate) Bytes);
+
+impl FromRequest<Arc<ProxyState>> for ValidatedBody {
+    type Rejection = ProxyError;
+
+    async fn from_request(
+        request: Request,
+        state: &Arc<ProxyState>,
+    ) -> Result<Self, Self::Rejection> {
+        let path = request.uri().path().to_string();
+        let is_post = request.method() == Method::POST;
+        let config = state.config();
+        let body =
+            Bytes::from_request(request, state)
+                .await
+                .map_err(|rejection| match rejection.status() {
+                    StatusCode::PAYLOAD_TOO_LARGE => body_too_large(config.max_body_mb),
+                    status => (
+                        status,
+                        Json(OpenAIError::invalid_request_error(rejection.body_text())),
+                    ),
+                })?;
+        check_json_depth(&body, config.max_json_depth)
+            .map_err(|error| (StatusCode::BAD_REQUEST, Json(error)))?;
+        if is_post {
+            validate_request(&path, &body)
+                .and_then(|()| check_input_audio(&path, &body, config.audio_max_mb))
+                .map_err(|error| (StatusCode::BAD_REQUEST, Json(error)))?;
+        }
+        Ok(Self(body))
+    }
+}
+
+/// Refuses requests whose `Content-Length` is over `--max-body-mb` before any
+/// of the body is read. Bodies without one are cut off at the same size as
+/// they are read.
+pub(crate) async fn limit_request_size(
+    State(state): State<Arc<ProxyState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let config = state.config();
+    let declared_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared_length.is_some_and(|length| length > config.max_body_bytes() as u64) {
+        return body_too_large(config.max_body_mb).into_response();
+    }
+    next.run(request).await
+}
+
+/// The OpenAI-style 413 for a body over `--max-body-mb`
+pub(crate) fn body_too_large(max_body_mb: u64) -> ProxyError {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(
+            OpenAIError::invalid_request_error(format!(
+                "The request body is too large. The limit is {} MB.",
+                max_body_mb
+            ))
+            .with_code("request_too_large"),
+        ),
+    )
+}
+
+/// Refuses JSON that nests arrays and objects more than `max_depth` levels
+/// deep. The bytes are scanned rather than parsed, so deeply nested input
+/// never reaches a recursive parser; brackets inside strings are skipped.
+pub(crate) fn check_json_depth(body: &[u8], max_depth: u32) -> Result<(), OpenAIError> {
+    let mut depth = 0u32;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in body {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Err(OpenAIError::invalid_request_error(format!(
+                        "The JSON body of your request nests arrays and objects more than {} \
+                         levels deep.",
+                        max_depth
+                    ))
+                    .with_code("json_too_deep"));
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks what backends would otherwise reject less clearly, or not at all:
+/// that the body is a JSON object with the required parameters, and that
+/// common parameters have the right type and range. Unknown parameters and
+/// other paths are not checked; `null` counts as absent.
+pub(crate) fn validate_request(path: &str, body: &[u8]) -> Result<(), OpenAIError> {
+    // Image generations and moderations fall back to a default model
+    let required: &[&str] = match path {
+        CHAT_COMPLETIONS_PATH => &["model", "messages"],
+        EMBEDDINGS_PATH | SPEECH_PATH => &["model", "input"],
+        IMAGE_GENERATIONS_PATH => &["prompt"],
+        MODERATIONS_PATH => &["input"],
+        RERANK_PATH => &["model", "query", "documents"],
+        _ => return Ok(()),
+    };
+    let Ok(Value::Object(request)) = serde_json::from_slice::<Value>(body) else {
+        return Err(OpenAIError::invalid_request_error(
+            "We could not parse the JSON body of your request. Expected a JSON object.",
+        ));
+    };
+    let param = |name: &str| request.get(name).filter(|value| !value.is_null());
+
+    for name in required {
+        if param(name).is_none() {
+            return Err(missing_param(name));
+        }
+    }
+    expect_type(&request, "model", "a string", Value::is_string)?;
+
+    if path == EMBEDDINGS_PATH {
+        expect_type(&request, "input", "a string or an array", |input| {
+            input.is_string() || input.is_array()
+        })?;
+        return expect_positive_integer(&request, "dimensions");
+    }
+    if path == SPEECH_PATH {
+        return validate_speech(&request);
+    }
+    if path == IMAGE_GENERATIONS_PATH {
+        return validate_image_generation(&request);
+    }
+    if path == MODERATIONS_PATH {
+        return validate_moderation_input(&request["input"]);
+    }
+    if path == RERANK_PATH {
+        return validate_rerank(&request);
+    }
+
+    expect_type(&request, "messages", "an array", Value::is_array)?;
+    let messages = request["messages"].as_array().map(Vec::as_slice);
+    for (index, message) in messages.unwrap_or_default().iter().enumerate() {
+        if !message.is_object() {
+            let param = format!("messages[{}]", index);
+            return Err(invalid_type(&param, "an object", message));
+        }
+    }
+    expect_type(&request, "stream", "a boolean", Value::is_boolean)?;
+    for (name, min, max) in NUMBER_RANGES {
+        expect_type(&request, name, "a number", Value::is_number)?;
+        let Some(value) = param(name).and_then(Value::as_f64) else {
+            continue;
+        };
+        if value < *min || value > *max {
+            return Err(invalid_value(
+                name,
+                format!("a value between {} and {}", min, max),
+                &request[*name],
+            ));
+        }
+    }
+    for name in POSITIVE_INTEGERS {
+        expect_positive_integer(&request, name)?;
+    }
+    Ok(())
+}
+
+/// Checks the `input_audio` parts of chat completion messages: the `format`
+/// must be one OpenAI accepts and the `data` base64 audio of that format, at
+/// most `max_mb` megabytes once decoded. Audio is otherwise forwarded as sent.
+pub(crate) fn check_input_audio(path: &str, body: &[u8], max_mb: u64) -> Result<(), OpenAIError> {
+    if path != CHAT_COMPLETIONS_PATH {
+        return Ok(());
+    }
+    let Ok(request) = serde_json::from_slice::<Value>(body) else {
+        return Ok(());
+    };
+    let max_bytes = usize::try_from(max_mb.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX);
+    let messages = request["messages"].as_array().into_iter().flatten();
+    for (message_index, message) in messages.enumerate() {
+        let parts = message["content"].as_array().into_iter().flatten();
+        for (part_index, part) in parts.enumerate() {
+            if part["type"] != "input_audio" {
+                continue;
+            }
+            let param = format!(
+                "messages[{}].content[{}].input_audio",
+                message_index, part_index
+            );
+            let audio = &part["input_audio"];
+            if !audio.is_object() {
+                return Err(invalid_type(&param, "an object", audio));
+            }
+
+            let format_param = format!("{}.format", param);
+            let format = match &audio["format"] {
+                Value::Null => return Err(missing_param(&format_param)),
+                Value::String(format) if AUDIO_FORMATS.contains(&format.as_str()) => format,
+                format => {
+                    let expected = format!("one of {}", AUDIO_FORMATS.join(", "));
+                    return Err(invalid_value(&format_param, expected, format));
+                }
+            };
+
+            let data_param = format!("{}.data", param);
+            let data = match &audio["data"] {
+                Value::Null => return Err(missing_param(&data_param)),
+                Value::String(data) => data,
+                data => return Err(invalid_type(&data_param, "a string", data)),
+            };
+            // Base64 encodes 3 bytes in 4 characters
+            if data.len() / 4 * 3 > max_bytes.saturating_add(2) {
+                return Err(audio_too_large(&data_param, max_mb));
+            }
+            let Ok(audio) = BASE64.decode(data.trim()) else {
+                return Err(OpenAIError::invalid_request_error(format!(
+                    "Invalid '{}': expected base64-encoded audio.",
+                    data_param
+                ))
+                .with_param(data_param)
+                .with_code("invalid_value"));
+            };
+            if audio.len() > max_bytes {
+                return Err(audio_too_large(&data_param, max_mb));
+            }
+            if !is_audio_format(&audio, format) {
+                return Err(OpenAIError::invalid_request_error(format!(
+                    "Invalid '{}': the audio is not in the {} format.",
+                    data_param, format
+                ))
+                .with_param(data_param)
+                .with_code("invalid_value"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `audio` starts the way a file of `format` does
+fn is_audio_format(audio: &[u8], format: &str) -> bool {
+    match format {
+        "wav" => audio.len() >= 12 && &audio[..4] == b"RIFF" && &audio[8..12] == b"WAVE",
+        // An ID3 tag, or straight into an MPEG audio frame
+        "mp3" => {
+            audio.starts_with(b"ID3")
+                || matches!(audio, [0xff, second, ..] if second & 0xe0 == 0xe0)
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn audio_too_large(param: &str, max_mb: u64) -> OpenAIError {
+    OpenAIError::invalid_request_error(format!(
+        "The audio in '{}' is too large. The limit is {} MB.",
+        param, max_mb
+    ))
+    .with_param(param)
+    .with_code("audio_too_large")
+}
+
+/// The speech parameters: `input` text, a `voice` named or given by `id`,
+/// and the output format and `speed` OpenAI accepts
+fn validate_speech(request: &Map<String, Value>) -> Result<(), OpenAIError> {
+    expect_type(request, "input", "a string", Value::is_string)?;
+    match request.get("voice").filter(|voice| !voice.is_null()) {
+        None => return Err(missing_param("voice")),
+        Some(voice) if !voice.is_string() && !voice["id"].is_string() => {
+            let expected = "a string or an object with an id";
+            return Err(invalid_type("voice", expected, voice));
+        }
+        Some(_) => {}
+    }
+    expect_one_of(request, "response_format", &SPEECH_FORMATS)?;
+    expect_one_of(request, "stream_format", &SPEECH_STREAM_FORMATS)?;
+    expect_type(request, "speed", "a number", Value::is_number)?;
+    match request.get("speed").and_then(Value::as_f64) {
+        Some(speed) if !(0.25..=4.0).contains(&speed) => Err(invalid_value(
+            "speed",
+            "a value between 0.25 and 4".to_string(),
+            &request["speed"],
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The image parameters: a `prompt` string, up to [`MAX_IMAGES`] images, a
+/// `size` such as `1024x1024` or `auto`, and a known `quality` and
+/// `response_format`
+fn validate_image_generation(request: &Map<String, Value>) -> Result<(), OpenAIError> {
+    expect_type(request, "prompt", "a string", Value::is_string)?;
+    expect_positive_integer(request, "n")?;
+    if request["n"].as_i64().is_some_and(|n| n > MAX_IMAGES) {
+        let expected = format!("a value <= {}", MAX_IMAGES);
+        return Err(invalid_value("n", expected, &request["n"]));
+    }
+    expect_type(request, "size", "a string", Value::is_string)?;
+    if let Some(size) = request.get("size").and_then(Value::as_str) {
+        let dimensions = size.split_once('x').filter(|(width, height)| {
+            [width, height]
+                .iter()
+                .all(|side| !side.is_empty() && side.bytes().all(|byte| byte.is_ascii_digit()))
+        });
+        if size != "auto" && dimensions.is_none() {
+            let expected = "a size such as '1024x1024', or 'auto'".to_string();
+            return Err(invalid_value("size", expected, &request["size"]));
+        }
+    }
+    expect_one_of(request, "quality", &IMAGE_QUALITIES)?;
+    expect_one_of(request, "response_format", &IMAGE_RESPONSE_FORMATS)
+}
+
+/// A moderation `input`: a string, an array of strings, or an array of
+/// `text` and `image_url` parts
+fn validate_moderation_input(input: &Value) -> Result<(), OpenAIError> {
+    let Some(items) = input.as_array() else {
+        if input.is_string() {
+            return Ok(());
+        }
+        return Err(invalid_type("input", "a string or an array", input));
+    };
+    for (index, item) in items.iter().enumerate() {
+        let is_part = matches!(item["type"].as_str(), Some("text" | "image_url"));
+        if !item.is_string() && !is_part {
+            let param = format!("input[{}]", index);
+            let expected = "a string or a text or image_url part";
+            return Err(invalid_type(&param, expected, item));
+        }
+    }
+    Ok(())
+}
+
+/// A Cohere or Jina-style rerank request: a `query` string, and `documents`
+/// given as strings or as objects with a `text` string
+fn validate_rerank(request: &Map<String, Value>) -> Result<(), OpenAIError> {
+    expect_type(request, "query", "a string", Value::is_string)?;
+    expect_type(request, "documents", "an array", Value::is_array)?;
+    let documents = request["documents"].as_array().map(Vec::as_slice);
+    let documents = documents.unwrap_or_default();
+    if documents.is_empty() {
+        let expected = "at least one document".to_string();
+        return Err(invalid_value("documents", expected, &request["documents"]));
+    }
+    for (index, document) in documents.iter().enumerate() {
+        if !document.is_string() && !document["text"].is_string() {
+            let param = format!("documents[{}]", index);
+            let expected = "a string or an object with a text string";
+            return Err(invalid_type(&param, expected, document));
+        }
+    }
+    expect_positive_integer(request, "top_n")?;
+    expect_type(request, "return_documents", "a boolean", Value::is_boolean)
+}
+
+fn expect_one_of(
+    request: &Map<String, Value>,
+    name: &str,
+    allowed: &[&str],
+) -> Result<(), OpenAIError> {
+    expect_type(request, name, "a string", Value::is_string)?;
+    match request.get(name).and_then(Value::as_str) {
+        Some(value) if !allowed.contains(&value) => {
+            let expected = format!("one of {}", allowed.join(", "));
+            Err(invalid_value(name, expected, &request[name]))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn expect_type(
+    request: &Map<String, Value>,
+    name: &str,
+    expected: &str,
+    matches: impl Fn(&Value) -> bool,
+) -> Result<(), OpenAIError> {
+    match request.get(name) {
+        Some(value) if !value.is_null() && !matches(value) => {
+            Err(invalid_type(name, expected, value))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn expect_positive_integer(request: &Map<String, Value>, name: &str) -> Result<(), OpenAIError> {
+    expect_type(request, name, "an integer", |value| {
+        value.is_i64() || value.is_u64()
+    })?;
+    match request.get(name).and_then(Value::as_i64) {
+        Some(value) if value < 1 => Err(invalid_value(
+            name,
+            "a value >= 1".to_string(),
+            &request[name],
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn missing_param(param: &str) -> OpenAIError {
+    OpenAIError::invalid_request_error(format!("Missing required parameter: '{}'.", param))
+        .with_param(param)
+        .with_code("missing_required_parameter")
+}
+
+fn invalid_type(param: &str, expected: &str, value: &Value) -> OpenAIError {
+    let actual = match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    };
+    OpenAIError::invalid_request_error(format!(
+        "Invalid type for '{}': expected {}, but got {} instead.",
+        param, expected, actual
+    ))
+    .with_param(param)
+    .with_code("invalid_type")
+}
+
+pub(crate) fn invalid_value(param: &str, expected: String, value: &Value) -> OpenAIError {
+    OpenAIError::invalid_request_error(format!(
+        "Invalid '{}': expected {}, but got {} instead.",
+        param, expected, value
+    ))
+    .with_param(param)
+    .with_code("invalid_value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire;
+
+    fn rejection(path: &str, body: &str) -> Value {
+        wire::to_value(&validate_request(path, body.as_bytes()).unwrap_err())["error"].clone()
+    }
+
+    #[test]
+    fn accepts_valid_and_unknown_parameters() {
+        let chat = r#"{"model":"m","messages":[{"role":"user","content":"Hi"}],
+            "temperature":0.7,"max_tokens":null,"n":1,"reasoning_effort":"high"}"#;
+        assert!(validate_request(CHAT_COMPLETIONS_PATH, chat.as_bytes()).is_ok());
+        let embeddings = r#"{"model":"m","input":["a"],"dimensions":8}"#;
+        assert!(validate_request(EMBEDDINGS_PATH, embeddings.as_bytes()).is_ok());
+        assert!(validate_request("/v1/audio/translations", b"opaque").is_ok());
+    }
+
+    #[test]
+    fn names_the_offending_param() {
+        let error = rejection(CHAT_COMPLETIONS_PATH, "{not json");
+        assert_eq!(error["param"], Value::Null);
+        assert!(error["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("We could not parse"));
+
+        let error = rejection(CHAT_COMPLETIONS_PATH, r#"{"model":"m"}"#);
+        assert_eq!(error["param"], "messages");
+        assert_eq!(error["code"], "missing_required_parameter");
+
+        let hot = r#"{"model":"m","messages":[],"temperature":3}"#;
+        let error = rejection(CHAT_COMPLETIONS_PATH, hot);
+        assert_eq!(error["param"], "temperature");
+        assert_eq!(error["code"], "invalid_value");
+        assert_eq!(
+            error["message"],
+            "Invalid 'temperature': expected a value between 0 and 2, but got 3 instead."
+        );
+
+        let error = rejection(CHAT_COMPLETIONS_PATH, r#"{"model":"m","messages":["Hi"]}"#);
+        assert_eq!(error["param"], "messages[0]");
+        assert_eq!(error["code"], "invalid_type");
+
+        let error = rejection(
+            CHAT_COMPLETIONS_PATH,
+            r#"{"model":"m","messages":[],"n":0}"#,
+        );
+        assert_eq!(error["param"], "n");
+
+        let error = rejection(EMBEDDINGS_PATH, r#"{"model":"m","input":{}}"#);
+        assert_eq!(error["param"], "input");
+
+        let speech = r#"{"model":"tts","input":"Hi","voice":{"id":"v1"},"speed":1.5}"#;
+        assert!(validate_request(SPEECH_PATH, speech.as_bytes()).is_ok());
+        let error = rejection(SPEECH_PATH, r#"{"model":"tts","input":"Hi"}"#);
+        assert_eq!(error["param"], "voice");
+        let ogg = r#"{"model":"tts","input":"Hi","voice":"alloy","response_format":"ogg"}"#;
+        assert_eq!(rejection(SPEECH_PATH, ogg)["param"], "response_format");
+        let fast = r#"{"model":"tts","input":"Hi","voice":"alloy","speed":5}"#;
+        assert_eq!(rejection(SPEECH_PATH, fast)["param"], "speed");
+
+        let image = r#"{"prompt":"A lighthouse","size":"1792x1024","n":2,"quality":"hd"}"#;
+        assert!(validate_request(IMAGE_GENERATIONS_PATH, image.as_bytes()).is_ok());
+        let error = rejection(IMAGE_GENERATIONS_PATH, r#"{"model":"m"}"#);
+        assert_eq!(error["param"], "prompt");
+        for (param, image) in [
+            ("n", r#"{"prompt":"p","n":11}"#),
+            ("size", r#"{"prompt":"p","size":"large"}"#),
+            ("quality", r#"{"prompt":"p","quality":"ultra"}"#),
+        ] {
+            assert_eq!(rejection(IMAGE_GENERATIONS_PATH, image)["param"], param);
+        }
+
+        let parts = r#"{"input":[{"type":"text","text":"Hi"},{"type":"image_url","image_url":{"url":"https://example.com/a.png"}}]}"#;
+        assert!(validate_request(MODERATIONS_PATH, parts.as_bytes()).is_ok());
+        let error = rejection(MODERATIONS_PATH, r#"{"input":["ok",{"type":"audio"}]}"#);
+        assert_eq!(error["param"], "input[1]");
+
+        let rerank =
+            r#"{"model":"bge-reranker","query":"q","documents":["a",{"text":"b"}],"top_n":1}"#;
+        assert!(validate_request(RERANK_PATH, rerank.as_bytes()).is_ok());
+        for (rerank, param) in [
+            (r#"{"model":"m","documents":["a"]}"#, "query"),
+            (r#"{"model":"m","query":"q","documents":[]}"#, "documents"),
+            (
+                r#"{"model":"m","query":"q","documents":["a",{"title":"b"}]}"#,
+                "documents[1]",
+            ),
+            (
+                r#"{"model":"m","query":"q","documents":["a"],"top_n":0}"#,
+                "top_n",
+            ),
+        ] {
+            assert_eq!(rejection(RERANK_PATH, rerank)["param"], param);
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited_outside_strings() {
+        let nested = format!("{}{}", "[".repeat(4), "]".repeat(4));
+        assert!(check_json_depth(nested.as_bytes(), 4).is_ok());
+        let error = check_json_depth(nested.as_bytes(), 3).unwrap_err();
+        assert_eq!(wire::to_value(&error)["error"]["code"], "json_too_deep");
+
+        let quoted = r#"{"content":"[[[[\"{{{{"}"#;
+        assert!(check_json_depth(quoted.as_bytes(), 1).is_ok());
+    }
+
+    #[test]
+    fn input_audio_is_checked_against_its_format_and_size() {
+        let request = |audio: Value| {
+            serde_json::json!({
+                "model": "m",
+                "messages": [{"role": "user", "content": [
+                    {"type": "text", "text": "Transcribe this"},
+                    {"type": "input_audio", "input_audio": audio}
+                ]}]
+            })
+            .to_string()
+        };
+        let check = |audio: Value, max_mb| {
+            check_input_audio(CHAT_COMPLETIONS_PATH, request(audio).as_bytes(), max_mb)
+                .map_err(|error| wire::to_value(&error)["error"].clone())
+        };
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        let wav_data = BASE64.encode(&wav);
+        assert!(check(serde_json::json!({"data": wav_data, "format": "wav"}), 1).is_ok());
+        let mp3_data = BASE64.encode(b"ID3\x04\0");
+        assert!(check(serde_json::json!({"data": mp3_data, "format": "mp3"}), 1).is_ok());
+
+        let error = check(serde_json::json!({"data": wav_data, "format": "flac"}), 1).unwrap_err();
+        assert_eq!(error["param"], "messages[0].content[1].input_audio.format");
+        let error = check(serde_json::json!({"data": wav_data, "format": "mp3"}), 1).unwrap_err();
+        assert_eq!(error["param"], "messages[0].content[1].input_audio.data");
+        let error = check(
+            serde_json::json!({"data": "not base64!", "format": "wav"}),
+            1,
+        )
+        .unwrap_err();
+        assert_eq!(error["code"], "invalid_value");
+        let error = check(serde_json::json!({"format": "wav"}), 1).unwrap_err();
+        assert_eq!(error["code"], "missing_required_parameter");
+
+        wav.resize(1024 * 1024 + 1, 0);
+        let large = serde_json::json!({"data": BASE64.encode(&wav), "format": "wav"});
+        assert_eq!(
+            check(large.clone(), 1).unwrap_err()["code"],
+            "audio_too_large"
+        );
+        assert!(check(large, 2).is_ok());
+    }
+}
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Token counts, as in the `usage` field of completions and embeddings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Usage {
+    pub(crate) prompt_tokens: u64,
+    /// Embeddings have no completion tokens and leave the field out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) completion_tokens: Option<u64>,
+    pub(crate) total_tokens: u64,
+}
+
+impl Usage {
+    pub(crate) fn completion(prompt_tokens: u64, completion_tokens: u64) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens: Some(completion_tokens),
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    pub(crate) fn embeddings(prompt_tokens: u64) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens: None,
+            total_tokens: prompt_tokens,
+        }
+    }
+}
+
+/// The closing chunk of a stream that carries only usage. `id`, `created`,
+/// and `model` repeat the backend's earlier chunks as they were.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct UsageChunk {
+    pub(crate) id: Value,
+    pub(crate) object: String,
+    pub(crate) created: Value,
+    pub(crate) model: Value,
+    pub(crate) choices: Vec<Value>,
+    pub(crate) usage: Usage,
+}
+
+impl UsageChunk {
+    /// A usage chunk following `last_chunk`
+    pub(crate) fn after(last_chunk: &Value, usage: Usage) -> Self {
+        Self {
+            id: last_chunk["id"].clone(),
+            object: "chat.completion.chunk".to_string(),
+            created: last_chunk["created"].clone(),
+            model: last_chunk["model"].clone(),
+            choices: Vec::new(),
+            usage,
+        }
+    }
+}
+
+/// An entry of a `/v1/models` list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ModelObject {
+    pub(crate) id: String,
+    pub(crate) object: String,
+    pub(crate) created: u64,
+    pub(crate) owned_by: String,
+}
+
+impl ModelObject {
+    pub(crate) fn new(id: impl Into<String>, owned_by: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            object: "model".to_string(),
+            created: 0,
+            owned_by: owned_by.into(),
+        }
+    }
+}
+
+/// Serializes an object the proxy writes itself. Backend bodies are forwarded
+/// as bytes, so these structs, not the `opensecret` crate's types, fix the
+/// JSON clients see from the proxy.
+pub(crate) fn to_value(object: &impl Serialize) -> Value {
+    serde_json::to_value(object).expect("wire objects serialize to JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OpenAIError;
+    use serde::de::DeserializeOwned;
+    use serde_json::json;
+
+    /// Checks that `object` serializes to exactly `expected` and reads back
+    /// unchanged
+    fn assert_wire_format<T>(object: &T, expected: Value)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        assert_eq!(to_value(object), expected);
+        assert_eq!(&serde_json::from_value::<T>(expected).unwrap(), object);
+    }
+
+    #[test]
+    fn usage_round_trips() {
+        assert_wire_format(
+            &Usage::completion(9, 2),
+            json!({"prompt_tokens": 9, "completion_tokens": 2, "total_tokens": 11}),
+        );
+        assert_wire_format(
+            &Usage::embeddings(4),
+            json!({"prompt_tokens": 4, "total_tokens": 4}),
+        );
+    }
+
+    #[test]
+    fn usage_chunks_round_trip() {
+        let last_chunk = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 1700000000,
+            "model": "llama3-3-70b",
+            "choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}]
+        });
+        assert_wire_format(
+            &UsageChunk::after(&last_chunk, Usage::completion(3, 1)),
+            json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion.chunk",
+                "created": 1700000000,
+                "model": "llama3-3-70b",
+                "choices": [],
+                "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}
+            }),
+        );
+    }
+
+    #[test]
+    fn model_objects_round_trip() {
+        assert_wire_format(
+            &ModelObject::new("gpt-4o-mini", "openai-upstream"),
+            json!({
+                "id": "gpt-4o-mini",
+                "object": "model",
+                "created": 0,
+                "owned_by": "openai-upstream"
+            }),
+        );
+    }
+
+    #[test]
+    fn errors_keep_the_openai_shape() {
+        let error = OpenAIError::model_not_found("gpt-5");
+        assert_eq!(
+            to_value(&error),
+            json!({
+                "error": {
+                    "message": "The model `gpt-5` does not exist or you do not have access to it.",
+                    "type": "invalid_request_error",
+                    "param": "model",
+                    "code": "model_not_found"
+                }
+            })
+        );
+        assert_eq!(
+            to_value(&OpenAIError::server_error("Backend unavailable"))["error"],
+            json!({
+                "message": "Backend unavailable",
+                "type": "server_error",
+                "param": null,
+                "code": null
+            })
+        );
+    }
+}
@@ -1,7 +1,18 @@
-use axum::http::StatusCode;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderValue, Method, Request, Response, StatusCode},
+    routing::get,
+    Router,
+};
 use axum_test::TestServer;
-use maple_proxy::{create_app, Config};
+use futures::future::BoxFuture;
+use maple_proxy::{
+    create_app, create_app_with_backend, create_router_with_prefix, Backend, BackendBody,
+    BackendError, Config,
+};
 use serde_json::{json, Value};
+use std::sync::Arc;
 
 #[tokio::test]
 async fn test_health_check_endpoint() {
@@ -45,6 +56,29 @@ async fn test_root_health_check() {
     assert_eq!(json["status"], "ok");
 }
 
+#[tokio::test]
+async fn attestation_status_lists_backends_before_their_first_handshake() {
+    let config = Config::new(
+        "127.0.0.1".to_string(),
+        0,
+        "http://localhost:3000".to_string(),
+    )
+    .with_fallback_backend_url("https://backup.example");
+    let server = TestServer::new(create_app(config)).unwrap();
+
+    let response = server.get("/v1/attestation").await;
+    response.assert_status(StatusCode::OK);
+    let json: Value = response.json();
+    assert_eq!(json["object"], "maple.attestation");
+    assert_eq!(
+        json["backends"],
+        json!([
+            {"backend_url": "http://localhost:3000", "attested": false},
+            {"backend_url": "https://backup.example", "attested": false},
+        ])
+    );
+}
+
 #[tokio::test]
 async fn chat_completion_accepts_large_payloads_above_axum_default() {
     let config = Config::new(
@@ -72,3 +106,227 @@ async fn chat_completion_accepts_large_payloads_above_axum_default() {
 
     response.assert_status(StatusCode::UNAUTHORIZED);
 }
+
+#[tokio::test]
+async fn cors_preflight_only_allows_configured_origins() {
+    let config = Config::new(
+        "127.0.0.1".to_string(),
+        0,
+        "http://localhost:3000".to_string(),
+    )
+    .with_cors_origins(vec!["https://app.example.com".to_string()])
+    .with_cors_allow_credentials(true)
+    .with_cors_max_age_secs(600)
+    .with_cors_expose_headers(vec!["X-Maple-Cache".to_string()]);
+    let server = TestServer::new(create_app(config)).unwrap();
+
+    let preflight = |origin: &'static str| {
+        server
+            .method(Method::OPTIONS, "/v1/chat/completions")
+            .add_header(header::ORIGIN, HeaderValue::from_static(origin))
+            .add_header(
+                header::ACCESS_CONTROL_REQUEST_METHOD,
+                HeaderValue::from_static("POST"),
+            )
+            .add_header(
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                HeaderValue::from_static("authorization,x-custom"),
+            )
+    };
+
+    let allowed = preflight("https://app.example.com").await;
+    assert_eq!(
+        allowed.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+        "https://app.example.com"
+    );
+    assert_eq!(
+        allowed.header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+        "true"
+    );
+    assert_eq!(
+        allowed.header(header::ACCESS_CONTROL_ALLOW_HEADERS),
+        "authorization,x-custom"
+    );
+    assert_eq!(allowed.header(header::ACCESS_CONTROL_MAX_AGE), "600");
+
+    let denied = preflight("https://evil.example.com").await;
+    assert!(denied
+        .headers()
+        .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        .is_none());
+
+    let response = server
+        .get("/health")
+        .add_header(
+            header::ORIGIN,
+            HeaderValue::from_static("https://app.example.com"),
+        )
+        .await;
+    assert_eq!(
+        response.header(header::ACCESS_CONTROL_EXPOSE_HEADERS),
+        "x-maple-cache"
+    );
+}
+
+#[tokio::test]
+async fn prefixed_router_merges_into_an_app_with_its_own_state() {
+    #[derive(Clone)]
+    struct AppState {
+        name: &'static str,
+    }
+
+    async fn name(State(state): State<AppState>) -> &'static str {
+        state.name
+    }
+
+    let config = Config::new(
+        "127.0.0.1".to_string(),
+        0,
+        "http://localhost:3000".to_string(),
+    );
+    let app = Router::new()
+        .route("/name", get(name))
+        .merge(create_router_with_prefix(config, "/maple/"))
+        .with_state(AppState { name: "host app" });
+    let server = TestServer::new(app).unwrap();
+
+    server.get("/name").await.assert_text("host app");
+    server
+        .get("/maple/health")
+        .await
+        .assert_status(StatusCode::OK);
+    server
+        .get("/health")
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+
+    let response = server
+        .post("/maple/v1/tokenize")
+        .json(&json!({"input": "hello world"}))
+        .await;
+    response.assert_status(StatusCode::OK);
+}
+
+const CANNED_COMPLETION: &str = r#"{"object":"chat.completion","choices":[{"index":0,"message":{"role":"assistant","content":"Hello from the mock"},"finish_reason":"stop"}]}"#;
+
+/// Answers every chat completion with the same message, without an enclave
+struct CannedBackend;
+
+impl Backend for CannedBackend {
+    fn send_inference_request(
+        &self,
+        request: Request<Bytes>,
+    ) -> BoxFuture<'_, Result<Response<BackendBody>, BackendError>> {
+        Box::pin(async move {
+            assert_eq!(request.uri().path(), "/v1/chat/completions");
+            assert!(request.headers().get(header::AUTHORIZATION).is_none());
+            let chunk = Ok::<_, BackendError>(Bytes::from_static(CANNED_COMPLETION.as_bytes()));
+            let body: BackendBody = Box::pin(futures::stream::iter([chunk]));
+            Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .unwrap())
+        })
+    }
+}
+
+#[tokio::test]
+async fn custom_backend_serves_inference_requests() {
+    let config = Config::new(
+        "127.0.0.1".to_string(),
+        0,
+        "http://localhost:3000".to_string(),
+    );
+    let server = TestServer::new(create_app_with_backend(config, Arc::new(CannedBackend))).unwrap();
+
+    let response = server
+        .post("/v1/chat/completions")
+        .add_header(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer test-key"),
+        )
+        .json(&json!({
+            "model": "llama3-3-70b",
+            "messages": [{"role": "user", "content": "Hi"}]
+        }))
+        .await;
+
+    response.assert_status(StatusCode::OK);
+    let json: Value = response.json();
+    assert_eq!(
+        json["choices"][0]["message"]["content"],
+        "Hello from the mock"
+    );
+}
+
+#[tokio::test]
+async fn mock_backend_streams_completions_without_an_api_key() {
+    let config = Config::default().with_mock_backend(true);
+    let server = TestServer::new(create_app(config)).unwrap();
+
+    let models: Value = server.get("/v1/models").await.json();
+    assert!(models["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|model| model["id"] == "llama3-3-70b"));
+
+    let response = server
+        .post("/v1/chat/completions")
+        .json(&json!({
+            "model": "llama3-3-70b",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "stream": true,
+            "max_tokens": 2
+        }))
+        .await;
+
+    response.assert_status(StatusCode::OK);
+    let body = response.text();
+    assert!(body.contains(r#""content":"Lorem""#));
+    assert!(body.contains(r#""content":" ipsum""#));
+    assert!(body.trim_end().ends_with("data: [DONE]"));
+}
+
+#[tokio::test]
+async fn compression_skips_streams_and_accepts_compressed_bodies() {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let config = Config::default()
+        .with_mock_backend(true)
+        .with_compression(true);
+    let server = TestServer::new(create_app(config)).unwrap();
+
+    let models = server
+        .get("/v1/models")
+        .add_header(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"))
+        .await;
+    models.assert_status(StatusCode::OK);
+    assert_eq!(models.header(header::CONTENT_ENCODING), "gzip");
+
+    let request = json!({
+        "model": "llama3-3-70b",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "stream": true,
+        "max_tokens": 2
+    });
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(request.to_string().as_bytes()).unwrap();
+    let stream = server
+        .post("/v1/chat/completions")
+        .add_header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        )
+        .add_header(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"))
+        .add_header(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, br"),
+        )
+        .bytes(Bytes::from(encoder.finish().unwrap()))
+        .await;
+    stream.assert_status(StatusCode::OK);
+    assert!(stream.headers().get(header::CONTENT_ENCODING).is_none());
+    assert!(stream.text().trim_end().ends_with("data: [DONE]"));
+}